@@ -0,0 +1,183 @@
+//! Admin endpoints of the ingest router, to check the route table without sending traffic:
+//! - `GET /admin/routes` lists the routes in the order they are tried
+//! - `POST /admin/routes/match` takes a JSON request with `path` and optionally `host`,
+//!   `method` and `headers`, and returns the route it would be matched to with its handler
+//!   and cells. The route is null if no route matches.
+use crate::config::Route;
+use crate::router::{MatchedRoute, Router};
+use http::header::HOST;
+use http::{Method, Request, Response, StatusCode};
+use hyper::body::Bytes;
+use serde::{Deserialize, Serialize};
+use shared::admin_service::{AdminEndpoints, AdminResponse, json_response};
+use std::collections::HashMap;
+
+pub struct RouterAdmin {
+    router: Router,
+}
+
+#[derive(Serialize)]
+struct RoutesResponse<'a> {
+    routes: &'a [Route],
+}
+
+/// Request matched by `POST /admin/routes/match`
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct MatchRequest {
+    host: Option<String>,
+    /// Path with an optional query
+    path: String,
+    method: Option<String>,
+    #[serde(default)]
+    headers: HashMap<String, String>,
+}
+
+#[derive(Serialize)]
+struct MatchResponse {
+    route: Option<MatchedRoute>,
+}
+
+impl MatchRequest {
+    /// Builds the parts of the request used for route matching.
+    fn to_request(&self) -> Result<Request<()>, http::Error> {
+        let mut request = Request::builder().uri(self.path.as_str());
+        if let Some(method) = &self.method {
+            request = request.method(method.as_str());
+        }
+        if let Some(host) = &self.host {
+            request = request.header(HOST, host.as_str());
+        }
+        for (name, value) in &self.headers {
+            request = request.header(name.as_str(), value.as_str());
+        }
+        request.body(())
+    }
+}
+
+impl RouterAdmin {
+    pub fn new(router: Router) -> Self {
+        Self { router }
+    }
+
+    fn match_route(&self, body: &[u8]) -> Response<Bytes> {
+        let request = serde_json::from_slice::<MatchRequest>(body)
+            .map_err(|e| e.to_string())
+            .and_then(|request| request.to_request().map_err(|e| e.to_string()));
+        match request {
+            Ok(request) => json_response(
+                StatusCode::OK,
+                &MatchResponse {
+                    route: self.router.match_request(&request),
+                },
+            ),
+            Err(e) => {
+                let mut response = Response::new(Bytes::from(e + "\n"));
+                *response.status_mut() = StatusCode::BAD_REQUEST;
+                response
+            }
+        }
+    }
+}
+
+impl AdminEndpoints for RouterAdmin {
+    fn call(&self, request: &Request<Bytes>) -> Option<AdminResponse> {
+        let response = match (request.method(), request.uri().path()) {
+            (&Method::GET, "/admin/routes") => json_response(
+                StatusCode::OK,
+                &RoutesResponse {
+                    routes: self.router.routes(),
+                },
+            ),
+            (&Method::POST, "/admin/routes/match") => self.match_route(request.body()),
+            _ => return None,
+        };
+        Some(Box::pin(async move { response }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::CellConfig;
+    use crate::router::HandlerDeps;
+    use crate::testutils::create_test_locator;
+    use url::Url;
+
+    async fn call(admin: &RouterAdmin, method: Method, uri: &str, body: &str) -> Response<Bytes> {
+        let request = Request::builder()
+            .method(method)
+            .uri(uri)
+            .body(Bytes::from(body.to_string()))
+            .unwrap();
+        admin.call(&request).unwrap().await
+    }
+
+    #[tokio::test]
+    async fn test_router_admin() {
+        let routes: Vec<Route> = serde_yaml::from_str(
+            r#"
+- match: {path: "/api/{project_id}/envelope/", method: POST}
+  action: {handler: envelope}
+  locality: us
+- match: {host: us.sentry.io}
+  action: {handler: health}
+  locality: us
+"#,
+        )
+        .unwrap();
+        let localities = HashMap::from([(
+            "us".to_string(),
+            vec![CellConfig {
+                id: "us1".to_string(),
+                sentry_url: Url::parse("http://sentry-us1:8080").unwrap(),
+                relay_url: Url::parse("http://relay-us1:8090").unwrap(),
+                protocol_version: None,
+                tls: None,
+                max_rps: None,
+            }],
+        )]);
+        let router = Router::new(
+            routes,
+            localities,
+            HandlerDeps::new(create_test_locator(HashMap::new()).await),
+        );
+        let admin = RouterAdmin::new(router);
+
+        let response = call(&admin, Method::GET, "/admin/routes", "").await;
+        let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(body["routes"].as_array().unwrap().len(), 2);
+        assert_eq!(body["routes"][0]["action"]["handler"], "envelope");
+        assert_eq!(body["routes"][0]["match"]["method"], "POST");
+
+        let response = call(
+            &admin,
+            Method::POST,
+            "/admin/routes/match",
+            r#"{"path": "/api/1/envelope/", "method": "POST", "host": "us.sentry.io"}"#,
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(body["route"]["index"], 0);
+        assert_eq!(body["route"]["handler"], "EnvelopeHandler");
+        assert_eq!(body["route"]["cells"], serde_json::json!(["us1"]));
+
+        // The envelope route only matches POST requests
+        let response = call(
+            &admin,
+            Method::POST,
+            "/admin/routes/match",
+            r#"{"path": "/api/1/envelope/"}"#,
+        )
+        .await;
+        let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(body["route"], serde_json::Value::Null);
+
+        let response = call(&admin, Method::POST, "/admin/routes/match", "{}").await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        let request = Request::get("/health").body(Bytes::new()).unwrap();
+        assert!(admin.call(&request).is_none());
+    }
+}
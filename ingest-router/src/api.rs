@@ -1,3 +1,12 @@
 pub mod any_cell_handler;
+pub mod envelope;
+pub mod key_extractor;
+pub mod minidump;
+pub mod ndjson_merge_handler;
+pub mod paginated_merge_handler;
 pub mod project_config;
+pub mod public_keys;
+pub mod quorum;
+pub mod relay_heartbeat;
+pub mod single_project;
 pub mod utils;
@@ -102,34 +102,12 @@ impl Handler for AnyCellHandler {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::config::CellConfig;
-    use crate::locality::Localities;
-    use std::collections::HashMap;
-    use url::Url;
-
-    fn create_test_cells() -> Cells {
-        let localities = HashMap::from([(
-            "us".to_string(),
-            vec![
-                CellConfig {
-                    id: "us1".to_string(),
-                    sentry_url: Url::parse("http://sentry-us1:8080").unwrap(),
-                    relay_url: Url::parse("http://relay-us1:8090").unwrap(),
-                },
-                CellConfig {
-                    id: "us2".to_string(),
-                    sentry_url: Url::parse("http://sentry-us2:8080").unwrap(),
-                    relay_url: Url::parse("http://relay-us2:8090").unwrap(),
-                },
-            ],
-        )]);
-        Localities::new(localities).get_cells("us").unwrap()
-    }
+    use crate::testutils::create_test_cells;
 
     #[tokio::test]
     async fn test_split_request_sends_to_all_cells() {
         let handler = AnyCellHandler::new("HealthCheck");
-        let cells = create_test_cells();
+        let cells = create_test_cells(&["us1", "us2"]);
 
         let request = Request::builder()
             .method("GET")
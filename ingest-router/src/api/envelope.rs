@@ -0,0 +1,153 @@
+use crate::api::key_extractor::{AuthHeader, KeyExtractor, QueryParam, is_compressed};
+use crate::api::single_project::SingleProjectHandler;
+use http::Uri;
+use hyper::header::HeaderMap;
+use locator::client::Locator;
+use serde::Deserialize;
+
+// Longest envelope header read for its DSN
+const MAX_HEADER_LEN: usize = 64 * 1024;
+
+/// Handler for envelopes, the main ingestion path of SDKs.
+///
+/// An envelope belongs to a single project, so the request is streamed unmodified to the
+/// relay of the cell of the project key, see `single_project`. Only the envelope header is
+/// read before the request is routed. The key is taken from, in order:
+/// - the `sentry_key` query parameter
+/// - the `X-Sentry-Auth` or `Authorization` header
+/// - the `dsn` of the envelope header, its first line. Only this line is parsed, the items
+///   are never read, and it is skipped if the body is compressed.
+///
+/// # Used for:
+///
+/// - `POST /api/{project_id}/envelope/` - Envelopes
+pub fn handler(locator: Locator) -> SingleProjectHandler {
+    let extractors: Vec<Box<dyn KeyExtractor>> = vec![
+        Box::new(QueryParam::new("sentry_key")),
+        Box::new(AuthHeader),
+        Box::new(EnvelopeHeader),
+    ];
+    SingleProjectHandler::new("EnvelopeHandler", "envelope", locator, extractors)
+        .pass_through_to_relay(MAX_HEADER_LEN)
+}
+
+/// The `dsn` of the envelope header, None if the body is compressed
+pub struct EnvelopeHeader;
+
+/// First line of an envelope
+#[derive(Deserialize)]
+struct Header {
+    dsn: Option<String>,
+}
+
+impl KeyExtractor for EnvelopeHeader {
+    fn extract(&self, _uri: &Uri, headers: &HeaderMap, body: &[u8]) -> Option<String> {
+        if is_compressed(headers) {
+            return None;
+        }
+        let prefix = &body[..body.len().min(MAX_HEADER_LEN)];
+        let line = match prefix.iter().position(|&b| b == b'\n') {
+            Some(end) => &prefix[..end],
+            // Envelopes without items have no newline
+            None if body.len() <= MAX_HEADER_LEN => prefix,
+            None => return None,
+        };
+        let header: Header = serde_json::from_slice(line).ok()?;
+        header.dsn.filter(|dsn| !dsn.is_empty())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::CellTarget;
+    use crate::handler::{BodyPrefix, ExecutionMode, Handler};
+    use crate::testutils::create_test_cells;
+    use crate::testutils::create_test_locator;
+    use hyper::Request;
+    use hyper::body::Bytes;
+    use hyper::header::CONTENT_ENCODING;
+    use std::collections::HashMap;
+
+    fn envelope(dsn: &str) -> Bytes {
+        Bytes::from(format!(
+            "{{\"event_id\":\"9ec79c33ec9942ab8353589fcb2e04dc\",\"dsn\":\"{dsn}\"}}\n{{\"type\":\"event\",\"length\":2}}\n{{}}\n"
+        ))
+    }
+
+    fn dsn_from_header(headers: &HeaderMap, body: &[u8]) -> Option<String> {
+        EnvelopeHeader.extract(&Uri::from_static("/api/1/envelope/"), headers, body)
+    }
+
+    #[test]
+    fn test_dsn_from_header() {
+        let dsn = format!("https://{}@o1.ingest.sentry.io/1", "a".repeat(32));
+        let headers = HeaderMap::new();
+        assert_eq!(
+            dsn_from_header(&headers, &envelope(&dsn)),
+            Some(dsn.clone())
+        );
+
+        // Envelopes without items
+        let body = format!("{{\"dsn\":\"{dsn}\"}}");
+        assert_eq!(
+            dsn_from_header(&headers, body.as_bytes()),
+            Some(dsn.clone())
+        );
+
+        // No DSN, or not a JSON header
+        assert_eq!(dsn_from_header(&headers, b"{}\n{}\n"), None);
+        assert_eq!(dsn_from_header(&headers, b"\x1f\x8b\x08\n"), None);
+
+        // Compressed bodies are not read
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_ENCODING, "gzip".parse().unwrap());
+        assert_eq!(dsn_from_header(&headers, &envelope(&dsn)), None);
+    }
+
+    #[tokio::test]
+    async fn test_route_head() {
+        let key = "a".repeat(32);
+        let locator = create_test_locator(HashMap::from([(key.clone(), "us2".into())])).await;
+        let handler = handler(locator);
+        let cells = create_test_cells(&["us1", "us2"]);
+        assert!(matches!(
+            handler.execution_mode(),
+            ExecutionMode::PassThrough(CellTarget::Relay)
+        ));
+
+        let head = |request: Request<()>, prefix: Bytes| {
+            let (mut parts, _) = request.into_parts();
+            parts.extensions.insert(BodyPrefix(prefix));
+            parts
+        };
+
+        // Routed to the cell of the DSN's key
+        let body = envelope(&format!("https://{key}@o1.ingest.sentry.io/1"));
+        let parts = head(
+            Request::post("/api/1/envelope/").body(()).unwrap(),
+            body.clone(),
+        );
+        assert_eq!(handler.route_head(&parts, &cells).await.unwrap(), "us2");
+
+        // The auth header takes precedence over the envelope header
+        let other = envelope(&format!("https://{}@o1.ingest.sentry.io/1", "f".repeat(32)));
+        let request = Request::post("/api/1/envelope/")
+            .header("x-sentry-auth", format!("Sentry sentry_key={key}"))
+            .body(())
+            .unwrap();
+        let parts = head(request, other);
+        assert_eq!(handler.route_head(&parts, &cells).await.unwrap(), "us2");
+
+        // Compressed envelopes need the key outside of the body
+        let request = Request::post("/api/1/envelope/")
+            .header(CONTENT_ENCODING, "gzip")
+            .body(())
+            .unwrap();
+        let parts = head(request, body);
+        assert_eq!(
+            handler.route_head(&parts, &cells).await,
+            Err(http::StatusCode::BAD_REQUEST)
+        );
+    }
+}
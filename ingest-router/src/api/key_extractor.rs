@@ -0,0 +1,216 @@
+//! Extraction of the project key of requests that belong to a single project.
+//!
+//! Endpoints carry the key in different places: the query, an auth header, a path segment,
+//! a JSON body, an envelope header or a form field. Each place is a `KeyExtractor`, and
+//! handlers try a list of them in order, see `single_project`. Routes of the
+//! `single_project` handler configure the list with `key_from`, so that endpoints can be
+//! onboarded with config plus an extractor instead of a new handler.
+use crate::api::envelope::EnvelopeHeader;
+use crate::api::minidump::FormField;
+use crate::config::KeySource;
+use http::Uri;
+use hyper::header::{AUTHORIZATION, CONTENT_ENCODING, HeaderMap};
+use serde_json::Value;
+
+/// Finds the project key, or a DSN, in a request
+pub trait KeyExtractor: Send + Sync {
+    fn extract(&self, uri: &Uri, headers: &HeaderMap, body: &[u8]) -> Option<String>;
+}
+
+/// The extractor of a configured key source
+pub fn from_config(source: &KeySource) -> Box<dyn KeyExtractor> {
+    match source {
+        KeySource::Query { name } => Box::new(QueryParam::new(name)),
+        KeySource::AuthHeader => Box::new(AuthHeader),
+        KeySource::PathSegment { index } => Box::new(PathSegment { index: *index }),
+        KeySource::JsonBody { pointer } => Box::new(JsonBody {
+            pointer: pointer.clone(),
+        }),
+        KeySource::EnvelopeHeader => Box::new(EnvelopeHeader),
+        KeySource::FormField { name } => Box::new(FormField::new(&[name.as_str()])),
+    }
+}
+
+/// The first key found by the extractors.
+pub fn extract_key(
+    extractors: &[Box<dyn KeyExtractor>],
+    uri: &Uri,
+    headers: &HeaderMap,
+    body: &[u8],
+) -> Option<String> {
+    extractors
+        .iter()
+        .find_map(|extractor| extractor.extract(uri, headers, body))
+}
+
+/// Whether the body is compressed, so that it cannot be read for a key.
+pub fn is_compressed(headers: &HeaderMap) -> bool {
+    headers
+        .get(CONTENT_ENCODING)
+        .is_some_and(|encoding| encoding != "identity")
+}
+
+/// A query parameter, such as `sentry_key`
+pub struct QueryParam {
+    name: String,
+}
+
+impl QueryParam {
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+        }
+    }
+}
+
+impl KeyExtractor for QueryParam {
+    fn extract(&self, uri: &Uri, _headers: &HeaderMap, _body: &[u8]) -> Option<String> {
+        url::form_urlencoded::parse(uri.query()?.as_bytes())
+            .find(|(name, value)| *name == self.name && !value.is_empty())
+            .map(|(_, value)| value.into_owned())
+    }
+}
+
+/// The `sentry_key` of a `Sentry sentry_key=..., sentry_version=7` auth header
+pub struct AuthHeader;
+
+impl KeyExtractor for AuthHeader {
+    fn extract(&self, _uri: &Uri, headers: &HeaderMap, _body: &[u8]) -> Option<String> {
+        let auth = headers
+            .get("x-sentry-auth")
+            .or_else(|| headers.get(AUTHORIZATION))?
+            .to_str()
+            .ok()?;
+        let params = auth.trim().strip_prefix("Sentry ")?;
+        params
+            .split(',')
+            .filter_map(|param| param.trim().split_once('='))
+            .find(|(name, value)| *name == "sentry_key" && !value.is_empty())
+            .map(|(_, value)| value.to_string())
+    }
+}
+
+/// A segment of the path, counted from 0 after the leading slash
+pub struct PathSegment {
+    index: usize,
+}
+
+impl KeyExtractor for PathSegment {
+    fn extract(&self, uri: &Uri, _headers: &HeaderMap, _body: &[u8]) -> Option<String> {
+        let segment = uri
+            .path()
+            .trim_start_matches('/')
+            .split('/')
+            .nth(self.index)?;
+        (!segment.is_empty()).then(|| segment.to_string())
+    }
+}
+
+/// A string in a JSON body, at a JSON pointer. The whole body is parsed, so this is meant
+/// for small bodies.
+pub struct JsonBody {
+    pointer: String,
+}
+
+impl KeyExtractor for JsonBody {
+    fn extract(&self, _uri: &Uri, headers: &HeaderMap, body: &[u8]) -> Option<String> {
+        if is_compressed(headers) {
+            return None;
+        }
+        let body: Value = serde_json::from_slice(body).ok()?;
+        let key = body.pointer(&self.pointer)?.as_str()?;
+        (!key.is_empty()).then(|| key.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn extract(
+        source: KeySource,
+        uri: &str,
+        headers: &[(&str, &str)],
+        body: &str,
+    ) -> Option<String> {
+        let uri: Uri = uri.parse().unwrap();
+        let headers = headers
+            .iter()
+            .map(|(name, value)| (name.parse().unwrap(), value.parse().unwrap()))
+            .collect();
+        from_config(&source).extract(&uri, &headers, body.as_bytes())
+    }
+
+    #[test]
+    fn test_query_and_auth_header() {
+        let query = || KeySource::Query {
+            name: "sentry_key".into(),
+        };
+        assert_eq!(
+            extract(query(), "/?sentry_key=abc&sentry_version=7", &[], ""),
+            Some("abc".into())
+        );
+        assert_eq!(extract(query(), "/?sentry_key=", &[], ""), None);
+        assert_eq!(extract(query(), "/", &[], ""), None);
+
+        let auth = [("x-sentry-auth", "Sentry sentry_version=7, sentry_key=abc")];
+        assert_eq!(
+            extract(KeySource::AuthHeader, "/", &auth, ""),
+            Some("abc".into())
+        );
+        let auth = [("authorization", "Bearer abc")];
+        assert_eq!(extract(KeySource::AuthHeader, "/", &auth, ""), None);
+    }
+
+    #[test]
+    fn test_path_segment() {
+        let segment = |index| KeySource::PathSegment { index };
+        assert_eq!(
+            extract(segment(2), "/api/1/abc/store/?x=1", &[], ""),
+            Some("abc".into())
+        );
+        assert_eq!(extract(segment(3), "/api/1/abc/", &[], ""), None);
+        assert_eq!(extract(segment(4), "/api/1/abc/", &[], ""), None);
+    }
+
+    #[test]
+    fn test_json_body() {
+        let json = || KeySource::JsonBody {
+            pointer: "/meta/dsn".into(),
+        };
+        let body = r#"{"meta": {"dsn": "https://abc@o1.ingest.sentry.io/1"}}"#;
+        assert_eq!(
+            extract(json(), "/", &[], body),
+            Some("https://abc@o1.ingest.sentry.io/1".into())
+        );
+        assert_eq!(extract(json(), "/", &[], r#"{"meta": {"dsn": 1}}"#), None);
+        assert_eq!(extract(json(), "/", &[], "not json"), None);
+        assert_eq!(
+            extract(json(), "/", &[("content-encoding", "gzip")], body),
+            None
+        );
+    }
+
+    #[test]
+    fn test_extract_key() {
+        let extractors = vec![
+            from_config(&KeySource::Query {
+                name: "sentry_key".into(),
+            }),
+            from_config(&KeySource::AuthHeader),
+        ];
+        let uri: Uri = "/?sentry_key=abc".parse().unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert("x-sentry-auth", "Sentry sentry_key=def".parse().unwrap());
+        assert_eq!(
+            extract_key(&extractors, &uri, &headers, b""),
+            Some("abc".into())
+        );
+        let uri: Uri = "/".parse().unwrap();
+        assert_eq!(
+            extract_key(&extractors, &uri, &headers, b""),
+            Some("def".into())
+        );
+        assert_eq!(extract_key(&extractors, &uri, &HeaderMap::new(), b""), None);
+    }
+}
@@ -0,0 +1,247 @@
+use crate::api::key_extractor::{AuthHeader, KeyExtractor, QueryParam};
+use crate::api::single_project::SingleProjectHandler;
+use http::Uri;
+use hyper::header::{CONTENT_TYPE, HeaderMap};
+use locator::client::Locator;
+
+// Form fields of a multipart upload that can hold the project key or the DSN
+const KEY_FIELDS: [&str; 2] = ["sentry_key", "dsn"];
+
+/// Handler for minidump and attachment uploads.
+///
+/// Uploads are multipart forms that belong to a single project, so the whole request is
+/// forwarded unmodified to the cell of the project key, see `single_project`. The key is
+/// taken from, in order:
+/// - the `sentry_key` query parameter, which crash reporters such as Crashpad append to
+///   the upload URL
+/// - the `X-Sentry-Auth` or `Authorization` header
+/// - a `sentry_key` or `dsn` form field. The body is only scanned for part boundaries and
+///   field names, file contents are skipped.
+///
+/// # Used for:
+///
+/// - `POST /api/{project_id}/minidump/` - Minidump uploads
+/// - `POST /api/{project_id}/events/{event_id}/attachments/` - Attachment uploads
+pub fn handler(locator: Locator) -> SingleProjectHandler {
+    let extractors: Vec<Box<dyn KeyExtractor>> = vec![
+        Box::new(QueryParam::new("sentry_key")),
+        Box::new(AuthHeader),
+        Box::new(FormField::new(&KEY_FIELDS)),
+    ];
+    SingleProjectHandler::new("MinidumpHandler", "upload", locator, extractors)
+}
+
+/// The first non-empty of the given fields of a multipart form
+pub struct FormField {
+    names: Vec<String>,
+}
+
+impl FormField {
+    pub fn new(names: &[&str]) -> Self {
+        Self {
+            names: names.iter().map(|name| name.to_string()).collect(),
+        }
+    }
+}
+
+impl KeyExtractor for FormField {
+    fn extract(&self, _uri: &Uri, headers: &HeaderMap, body: &[u8]) -> Option<String> {
+        let content_type = headers.get(CONTENT_TYPE)?.to_str().ok()?;
+        let boundary = multipart_boundary(content_type)?;
+        let delimiter = format!("--{boundary}");
+        let delimiter = delimiter.as_bytes();
+
+        // Parts start after a delimiter line and end before the next one
+        let mut rest = &body[find(body, delimiter)? + delimiter.len()..];
+        while !rest.starts_with(b"--") {
+            let end = find(rest, delimiter)?;
+            let part = &rest[..end];
+            rest = &rest[end + delimiter.len()..];
+
+            let part = part.strip_prefix(b"\r\n").unwrap_or(part);
+            let Some((part_headers, value)) = split_once(part, b"\r\n\r\n") else {
+                continue;
+            };
+            let Some(name) = field_name(part_headers) else {
+                continue;
+            };
+            if !self.names.contains(&name) {
+                continue;
+            }
+            let value = value.strip_suffix(b"\r\n").unwrap_or(value);
+            match std::str::from_utf8(value).map(str::trim) {
+                Ok(value) if !value.is_empty() => return Some(value.to_string()),
+                _ => continue,
+            }
+        }
+        None
+    }
+}
+
+/// The boundary parameter of a `multipart/form-data` content type.
+fn multipart_boundary(content_type: &str) -> Option<&str> {
+    let mut params = content_type.split(';');
+    let media_type = params.next()?.trim();
+    if !media_type.eq_ignore_ascii_case("multipart/form-data") {
+        return None;
+    }
+    params
+        .filter_map(|param| param.trim().split_once('='))
+        .find(|(name, _)| name.eq_ignore_ascii_case("boundary"))
+        .map(|(_, value)| value.trim_matches('"'))
+        .filter(|boundary| !boundary.is_empty())
+}
+
+/// The name of a form field, None for files and parts without a name.
+fn field_name(part_headers: &[u8]) -> Option<String> {
+    let part_headers = std::str::from_utf8(part_headers).ok()?;
+    let disposition = part_headers.split("\r\n").find_map(|line| {
+        let (name, value) = line.split_once(':')?;
+        name.trim()
+            .eq_ignore_ascii_case("content-disposition")
+            .then_some(value)
+    })?;
+
+    let mut name = None;
+    for param in disposition.split(';').skip(1) {
+        match param.trim().split_once('=') {
+            Some(("filename", _)) => return None,
+            Some(("name", value)) => name = Some(value.trim_matches('"').to_string()),
+            _ => {}
+        }
+    }
+    name
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+fn split_once<'a>(bytes: &'a [u8], separator: &[u8]) -> Option<(&'a [u8], &'a [u8])> {
+    let index = find(bytes, separator)?;
+    Some((&bytes[..index], &bytes[index + separator.len()..]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handler::Handler;
+    use crate::testutils::create_test_cells;
+    use crate::testutils::create_test_locator;
+    use hyper::Request;
+    use hyper::body::Bytes;
+    use std::collections::HashMap;
+
+    const BOUNDARY: &str = "X-BOUNDARY";
+
+    /// A multipart upload with a minidump and the given text fields.
+    fn form(fields: &[(&str, &str)]) -> Bytes {
+        let mut body = Vec::new();
+        body.extend_from_slice(
+            format!(
+                "--{BOUNDARY}\r\nContent-Disposition: form-data; name=\"upload_file_minidump\"; filename=\"crash.dmp\"\r\nContent-Type: application/octet-stream\r\n\r\n"
+            )
+            .as_bytes(),
+        );
+        // Binary contents that look like a key field
+        body.extend_from_slice(b"MDMP\x00\xff name=\"sentry_key\"\r\n\r\nnot-a-key\r\n");
+        for (name, value) in fields {
+            body.extend_from_slice(
+                format!(
+                    "--{BOUNDARY}\r\nContent-Disposition: form-data; name=\"{name}\"\r\n\r\n{value}\r\n"
+                )
+                .as_bytes(),
+            );
+        }
+        body.extend_from_slice(format!("--{BOUNDARY}--\r\n").as_bytes());
+        Bytes::from(body)
+    }
+
+    fn upload(uri: &str, body: Bytes) -> Request<Bytes> {
+        Request::builder()
+            .method("POST")
+            .uri(uri)
+            .header(
+                CONTENT_TYPE,
+                format!("multipart/form-data; boundary=\"{BOUNDARY}\""),
+            )
+            .body(body)
+            .unwrap()
+    }
+
+    fn key_from_form(headers: &HeaderMap, body: &[u8]) -> Option<String> {
+        FormField::new(&KEY_FIELDS).extract(&Uri::from_static("/api/1/minidump/"), headers, body)
+    }
+
+    #[test]
+    fn test_key_from_form() {
+        let key = "a".repeat(32);
+        let request = upload(
+            "/api/1/minidump/",
+            form(&[("sentry[release]", "1.0"), ("sentry_key", &key)]),
+        );
+        assert_eq!(
+            key_from_form(request.headers(), request.body()),
+            Some(key.clone())
+        );
+
+        let dsn = format!("https://{}@o1.ingest.sentry.io/1", "b".repeat(32));
+        let request = upload("/api/1/minidump/", form(&[("dsn", &dsn)]));
+        assert_eq!(key_from_form(request.headers(), request.body()), Some(dsn));
+
+        // The field in the minidump contents is not a form field
+        let request = upload("/api/1/minidump/", form(&[]));
+        assert_eq!(key_from_form(request.headers(), request.body()), None);
+
+        // Not a multipart form, or truncated
+        let request = Request::builder()
+            .header(CONTENT_TYPE, "application/octet-stream")
+            .body(form(&[("sentry_key", &key)]))
+            .unwrap();
+        assert_eq!(key_from_form(request.headers(), request.body()), None);
+        let body = form(&[("sentry_key", &key)]);
+        let request = upload("/api/1/minidump/", body.slice(..body.len() / 2));
+        assert_eq!(key_from_form(request.headers(), request.body()), None);
+    }
+
+    #[tokio::test]
+    async fn test_split_request() {
+        let key = "a".repeat(32);
+        let locator = create_test_locator(HashMap::from([(key.clone(), "us2".into())])).await;
+        let handler = handler(locator);
+        let cells = create_test_cells(&["us1", "us2"]);
+
+        // The body is forwarded unmodified to the cell of the key
+        let body = form(&[("sentry_key", &key.to_uppercase())]);
+        let (requests, _) = handler
+            .split_request(upload("/api/1/minidump/", body.clone()), &cells)
+            .await
+            .unwrap();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].0, "us2");
+        assert_eq!(requests[0].1.body(), &body);
+
+        // The query takes precedence over the form
+        let (requests, _) = handler
+            .split_request(
+                upload(
+                    &format!("/api/1/minidump/?sentry_key={key}"),
+                    form(&[("sentry_key", &"f".repeat(32))]),
+                ),
+                &cells,
+            )
+            .await
+            .unwrap();
+        assert_eq!(requests[0].0, "us2");
+
+        let (requests, metadata) = handler
+            .split_request(upload("/api/1/minidump/", form(&[])), &cells)
+            .await
+            .unwrap();
+        assert!(requests.is_empty());
+        let response = handler.merge_responses(Vec::new(), metadata).await;
+        assert_eq!(response.status(), http::StatusCode::BAD_REQUEST);
+    }
+}
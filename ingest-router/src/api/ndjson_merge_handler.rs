@@ -0,0 +1,180 @@
+use crate::api::utils::normalize_headers;
+use crate::errors::IngestRouterError;
+use crate::handler::{CellId, ExecutionMode, Handler, SplitMetadata};
+use crate::locality::Cells;
+use async_trait::async_trait;
+use http::StatusCode;
+use hyper::body::Bytes;
+use hyper::{Request, Response};
+use serde::de::IgnoredAny;
+use shared::http::make_error_response;
+use std::collections::HashMap;
+
+/// Handler for endpoints returning NDJSON (newline-delimited JSON).
+///
+/// The request is sent to all cells of the locality, and the lines of the successful
+/// responses are streamed back interleaved as they arrive, instead of buffering the
+/// responses and merging them at once. Suitable for endpoints where:
+/// - Every cell returns its part of the result as independent lines
+/// - The order of lines across cells does not matter
+///
+/// With `source_field`, every line that is a JSON object is annotated with the id of the
+/// cell it came from.
+pub struct NdjsonMergeHandler {
+    source_field: Option<String>,
+}
+
+impl NdjsonMergeHandler {
+    pub fn new(source_field: Option<String>) -> Self {
+        Self { source_field }
+    }
+}
+
+#[async_trait]
+impl Handler for NdjsonMergeHandler {
+    fn name(&self) -> &'static str {
+        "NdjsonMerge"
+    }
+
+    fn execution_mode(&self) -> ExecutionMode {
+        ExecutionMode::Streaming
+    }
+
+    async fn split_request(
+        &self,
+        request: Request<Bytes>,
+        cells: &Cells,
+    ) -> Result<(Vec<(CellId, Request<Bytes>)>, SplitMetadata), IngestRouterError> {
+        let (mut parts, body) = request.into_parts();
+        normalize_headers(&mut parts.headers, parts.version);
+
+        // Send the request to all cells
+        let cell_requests = cells
+            .cell_list()
+            .map(|cell_id| {
+                let req = Request::from_parts(parts.clone(), body.clone());
+                (cell_id.clone(), req)
+            })
+            .collect();
+
+        Ok((cell_requests, Box::new(())))
+    }
+
+    fn map_line(&self, cell_id: &str, line: Bytes) -> Bytes {
+        let Some(source_field) = &self.source_field else {
+            return line;
+        };
+
+        // Lines that are not objects, or already have the field, are forwarded unchanged
+        match serde_json::from_slice::<HashMap<String, IgnoredAny>>(&line) {
+            Ok(fields) if !fields.contains_key(source_field) => {}
+            _ => return line,
+        }
+
+        // Spliced in front of the existing fields, so that the line is otherwise unchanged
+        let Some(start) = line.iter().position(|byte| *byte == b'{') else {
+            return line;
+        };
+        let Ok(mut annotated) = serde_json::to_vec(&HashMap::from([(source_field, cell_id)]))
+        else {
+            return line;
+        };
+        // Without the closing brace
+        annotated.pop();
+        if !fields_empty(&line[start + 1..]) {
+            annotated.push(b',');
+        }
+        annotated.extend_from_slice(&line[start + 1..]);
+        Bytes::from(annotated)
+    }
+
+    async fn merge_responses(
+        &self,
+        responses: Vec<(CellId, Result<Response<Bytes>, IngestRouterError>)>,
+        _metadata: SplitMetadata,
+    ) -> Response<Bytes> {
+        // Only called if no cell succeeded, the failures were already logged while
+        // streaming. Client errors are passed on, since every cell rejects the same request.
+        for (_cell_id, result) in responses {
+            if let Ok(response) = result
+                && response.status().is_client_error()
+            {
+                let (mut parts, body) = response.into_parts();
+                normalize_headers(&mut parts.headers, parts.version);
+                return Response::from_parts(parts, body);
+            }
+        }
+
+        make_error_response(StatusCode::SERVICE_UNAVAILABLE)
+    }
+}
+
+/// Whether the rest of an object after its opening brace has no fields
+fn fields_empty(rest: &[u8]) -> bool {
+    rest.iter()
+        .find(|byte| !byte.is_ascii_whitespace())
+        .is_none_or(|byte| *byte == b'}')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_map_line() {
+        let handler = NdjsonMergeHandler::new(None);
+        let line = Bytes::from_static(br#"{"id": 1}"#);
+        assert_eq!(handler.map_line("us1", line.clone()), line);
+
+        let handler = NdjsonMergeHandler::new(Some("cell".into()));
+        assert_eq!(
+            handler.map_line("us1", Bytes::from_static(br#"{"id": 1, "name": "a"}"#)),
+            Bytes::from_static(br#"{"cell":"us1","id": 1, "name": "a"}"#)
+        );
+        assert_eq!(
+            handler.map_line("us1", Bytes::from_static(b" { }")),
+            Bytes::from_static(br#"{"cell":"us1" }"#)
+        );
+
+        // Forwarded unchanged
+        for line in [r#"{"cell": "us2"}"#, "[1, 2]", "not json"] {
+            let line = Bytes::from(line);
+            assert_eq!(handler.map_line("us1", line.clone()), line);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_merge_responses_all_failed() {
+        let handler = NdjsonMergeHandler::new(None);
+
+        let bad_request = Response::builder()
+            .status(StatusCode::BAD_REQUEST)
+            .body(Bytes::from_static(b"invalid query"))
+            .unwrap();
+        let merged = handler
+            .merge_responses(
+                vec![
+                    (
+                        "us1".to_string(),
+                        Err(IngestRouterError::UpstreamTimeout("us1".to_string())),
+                    ),
+                    ("us2".to_string(), Ok(bad_request)),
+                ],
+                Box::new(()),
+            )
+            .await;
+        assert_eq!(merged.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(merged.body().as_ref(), b"invalid query");
+
+        let merged = handler
+            .merge_responses(
+                vec![(
+                    "us1".to_string(),
+                    Err(IngestRouterError::UpstreamTimeout("us1".to_string())),
+                )],
+                Box::new(()),
+            )
+            .await;
+        assert_eq!(merged.status(), StatusCode::SERVICE_UNAVAILABLE);
+    }
+}
@@ -0,0 +1,547 @@
+use crate::api::utils::normalize_headers;
+use crate::errors::IngestRouterError;
+use crate::handler::{CellId, ExecutionMode, Handler, SplitMetadata};
+use crate::locality::Cells;
+use async_trait::async_trait;
+use base64::Engine as _;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use http::header::{CONTENT_TYPE, LINK};
+use http::{HeaderValue, StatusCode, Uri};
+use hyper::body::Bytes;
+use hyper::{Request, Response};
+use serde_json::Value;
+use shared::http::make_error_response;
+use std::collections::BTreeMap;
+use url::form_urlencoded;
+
+const CURSOR: &str = "cursor";
+
+/// Handler for list endpoints paginated with `Link` headers, such as the organization
+/// list of Sentry's API.
+///
+/// The request is sent to all cells of the locality and the JSON arrays of their responses
+/// are concatenated in the order of the cell ids. The `next` cursors of the cells are combined into a
+/// composite cursor, which holds the position of every cell, and the merged response links
+/// to the next page with it. A request with a composite cursor only goes to the cells that
+/// have more results, each with its own cursor. Cells that fail keep their position in the
+/// composite cursor, so their page is requested again with the next page instead of being
+/// skipped.
+///
+/// Only forward pagination is supported, the merged response has no `previous` link. With
+/// `source_field`, every object in the results is annotated with the id of its cell.
+pub struct PaginatedMergeHandler {
+    source_field: Option<String>,
+}
+
+impl PaginatedMergeHandler {
+    pub fn new(source_field: Option<String>) -> Self {
+        Self { source_field }
+    }
+}
+
+/// Position of every cell that has more results. A cell without a cursor is at its first
+/// page, cells that have no more results are left out.
+type Positions = BTreeMap<CellId, Option<String>>;
+
+fn encode_cursor(positions: &Positions) -> String {
+    // Serializing a map of strings cannot fail
+    URL_SAFE_NO_PAD.encode(serde_json::to_vec(positions).unwrap_or_default())
+}
+
+fn decode_cursor(cursor: &str) -> Option<Positions> {
+    let json = URL_SAFE_NO_PAD.decode(cursor).ok()?;
+    serde_json::from_slice(&json).ok()
+}
+
+enum Pagination {
+    /// The request's cursor is not a composite cursor of this handler
+    InvalidCursor,
+    Page {
+        path: String,
+        /// Query of the request without its cursor
+        query: Vec<(String, String)>,
+        /// Position every cell was requested at
+        positions: Positions,
+    },
+}
+
+/// Replaces the cursor in the query of the request.
+fn with_cursor(path: &str, query: &[(String, String)], cursor: Option<&str>) -> String {
+    let mut serializer = form_urlencoded::Serializer::new(String::new());
+    serializer.extend_pairs(query);
+    if let Some(cursor) = cursor {
+        serializer.append_pair(CURSOR, cursor);
+    }
+    match serializer.finish() {
+        query if query.is_empty() => path.to_string(),
+        query => format!("{path}?{query}"),
+    }
+}
+
+/// The cursor of the page after the response, None if there are no more results.
+///
+/// Entries of the `Link` header look like
+/// `<https://sentry.io/api/0/organizations/?cursor=100:1:0>; rel="next"; results="true"; cursor="100:1:0"`.
+fn next_cursor(response: &Response<Bytes>) -> Option<String> {
+    for link in response.headers().get_all(LINK) {
+        let Ok(link) = link.to_str() else {
+            continue;
+        };
+        for entry in split_links(link) {
+            let mut params = entry.split(';').map(str::trim);
+            let target = params.next().unwrap_or_default();
+            let mut rel = None;
+            let mut results = None;
+            let mut cursor = None;
+            for param in params {
+                let Some((name, value)) = param.split_once('=') else {
+                    continue;
+                };
+                let value = value.trim().trim_matches('"');
+                match name.trim() {
+                    "rel" => rel = Some(value),
+                    "results" => results = Some(value),
+                    "cursor" => cursor = Some(value.to_string()),
+                    _ => {}
+                }
+            }
+            if rel != Some("next") {
+                continue;
+            }
+            if results == Some("false") {
+                return None;
+            }
+            // Fall back to the cursor in the link's target
+            return cursor.or_else(|| {
+                let target = target.trim_start_matches('<').trim_end_matches('>');
+                let (_, query) = target.split_once('?')?;
+                form_urlencoded::parse(query.as_bytes())
+                    .find(|(name, _)| name == CURSOR)
+                    .map(|(_, value)| value.into_owned())
+            });
+        }
+    }
+    None
+}
+
+/// Splits a `Link` header into its entries, at commas outside of targets and quotes.
+fn split_links(link: &str) -> Vec<&str> {
+    let mut entries = Vec::new();
+    let mut start = 0;
+    let mut in_target = false;
+    let mut in_quotes = false;
+    for (i, c) in link.char_indices() {
+        match c {
+            '<' if !in_quotes => in_target = true,
+            '>' if !in_quotes => in_target = false,
+            '"' if !in_target => in_quotes = !in_quotes,
+            ',' if !in_target && !in_quotes => {
+                entries.push(link[start..i].trim());
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    entries.push(link[start..].trim());
+    entries.retain(|entry| !entry.is_empty());
+    entries
+}
+
+#[async_trait]
+impl Handler for PaginatedMergeHandler {
+    fn name(&self) -> &'static str {
+        "PaginatedMerge"
+    }
+
+    fn execution_mode(&self) -> ExecutionMode {
+        ExecutionMode::Parallel
+    }
+
+    async fn split_request(
+        &self,
+        request: Request<Bytes>,
+        cells: &Cells,
+    ) -> Result<(Vec<(CellId, Request<Bytes>)>, SplitMetadata), IngestRouterError> {
+        let (mut parts, body) = request.into_parts();
+        normalize_headers(&mut parts.headers, parts.version);
+
+        let path = parts.uri.path().to_string();
+        let mut cursor = None;
+        let query: Vec<(String, String)> =
+            form_urlencoded::parse(parts.uri.query().unwrap_or_default().as_bytes())
+                .into_owned()
+                .filter(|(name, value)| {
+                    if name == CURSOR {
+                        cursor = Some(value.clone());
+                    }
+                    name != CURSOR
+                })
+                .collect();
+
+        let positions: Positions = match cursor {
+            None => cells
+                .cell_list()
+                .map(|cell_id| (cell_id.clone(), None))
+                .collect(),
+            Some(cursor) => {
+                let Some(positions) = decode_cursor(&cursor) else {
+                    return Ok((Vec::new(), Box::new(Pagination::InvalidCursor)));
+                };
+                positions
+                    .into_iter()
+                    .filter(|(cell_id, _)| {
+                        let known = cells.contains_cell(cell_id);
+                        if !known {
+                            tracing::warn!(cell_id = %cell_id, "Cursor of an unknown cell dropped");
+                        }
+                        known
+                    })
+                    .collect()
+            }
+        };
+
+        let mut cell_requests = Vec::new();
+        for (cell_id, position) in &positions {
+            let mut parts = parts.clone();
+            parts.uri = Uri::try_from(with_cursor(&path, &query, position.as_deref()))
+                .map_err(|e| IngestRouterError::InternalError(e.to_string()))?;
+            cell_requests.push((cell_id.clone(), Request::from_parts(parts, body.clone())));
+        }
+
+        let pagination = Pagination::Page {
+            path,
+            query,
+            positions,
+        };
+        Ok((cell_requests, Box::new(pagination)))
+    }
+
+    async fn merge_responses(
+        &self,
+        responses: Vec<(CellId, Result<Response<Bytes>, IngestRouterError>)>,
+        metadata: SplitMetadata,
+    ) -> Response<Bytes> {
+        let Ok(pagination) = metadata.downcast::<Pagination>() else {
+            return make_error_response(StatusCode::INTERNAL_SERVER_ERROR);
+        };
+        let Pagination::Page {
+            path,
+            query,
+            positions,
+        } = *pagination
+        else {
+            return make_error_response(StatusCode::BAD_REQUEST);
+        };
+
+        let mut responses: BTreeMap<CellId, Result<Response<Bytes>, IngestRouterError>> =
+            responses.into_iter().collect();
+        let mut results = Vec::new();
+        let mut next = Positions::new();
+        let mut succeeded = false;
+        let mut client_error = None;
+
+        for (cell_id, position) in positions {
+            let items = match responses.remove(&cell_id) {
+                Some(Ok(response)) if response.status().is_success() => {
+                    match serde_json::from_slice::<Vec<Value>>(response.body()) {
+                        Ok(items) => Ok((items, next_cursor(&response))),
+                        Err(e) => Err(format!("invalid results: {e}")),
+                    }
+                }
+                Some(Ok(response)) => {
+                    let status = response.status();
+                    if status.is_client_error() && client_error.is_none() {
+                        client_error = Some(response);
+                    }
+                    Err(format!("status {status}"))
+                }
+                Some(Err(e)) => Err(e.to_string()),
+                None => Err("no response".to_string()),
+            };
+
+            match items {
+                Ok((items, cursor)) => {
+                    succeeded = true;
+                    results.extend(items.into_iter().map(|mut item| {
+                        if let (Some(source_field), Value::Object(fields)) =
+                            (&self.source_field, &mut item)
+                        {
+                            fields
+                                .entry(source_field.clone())
+                                .or_insert_with(|| Value::String(cell_id.clone()));
+                        }
+                        item
+                    }));
+                    if let Some(cursor) = cursor {
+                        next.insert(cell_id, Some(cursor));
+                    }
+                }
+                Err(error) => {
+                    tracing::warn!(cell_id = %cell_id, error = %error, "PaginatedMerge cell failed");
+                    // Requested again with the next page
+                    next.insert(cell_id, position);
+                }
+            }
+        }
+
+        if !succeeded {
+            // Every cell rejects the same request
+            if let Some(response) = client_error {
+                let (mut parts, body) = response.into_parts();
+                normalize_headers(&mut parts.headers, parts.version);
+                return Response::from_parts(parts, body);
+            }
+            // A page of cells that have no more results
+            if !next.is_empty() {
+                return make_error_response(StatusCode::SERVICE_UNAVAILABLE);
+            }
+        }
+
+        let Ok(body) = serde_json::to_vec(&results) else {
+            return make_error_response(StatusCode::INTERNAL_SERVER_ERROR);
+        };
+        let link = if next.is_empty() {
+            format!(
+                r#"<{}>; rel="next"; results="false""#,
+                with_cursor(&path, &query, None)
+            )
+        } else {
+            let cursor = encode_cursor(&next);
+            format!(
+                r#"<{}>; rel="next"; results="true"; cursor="{cursor}""#,
+                with_cursor(&path, &query, Some(&cursor))
+            )
+        };
+
+        let mut response = Response::new(Bytes::from(body));
+        let headers = response.headers_mut();
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+        if let Ok(link) = HeaderValue::try_from(link) {
+            headers.insert(LINK, link);
+        }
+        response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutils::create_test_cells;
+
+    fn request(uri: &str) -> Request<Bytes> {
+        Request::builder().uri(uri).body(Bytes::new()).unwrap()
+    }
+
+    fn response(body: &'static str, next: Option<&str>) -> Response<Bytes> {
+        let link = match next {
+            Some(cursor) => format!(
+                r#"<http://sentry/api/0/organizations/?cursor={cursor}>; rel="previous"; results="false"; cursor="0:0:1", <http://sentry/api/0/organizations/?cursor={cursor}>; rel="next"; results="true"; cursor="{cursor}""#
+            ),
+            None => r#"<http://sentry/api/0/organizations/?cursor=0:100:0>; rel="next"; results="false"; cursor="0:100:0""#.to_string(),
+        };
+        Response::builder()
+            .header(LINK, link)
+            .body(Bytes::from_static(body.as_bytes()))
+            .unwrap()
+    }
+
+    fn sorted_uris(requests: &[(CellId, Request<Bytes>)]) -> Vec<(String, String)> {
+        let mut uris: Vec<_> = requests
+            .iter()
+            .map(|(cell_id, request)| (cell_id.clone(), request.uri().to_string()))
+            .collect();
+        uris.sort();
+        uris
+    }
+
+    #[test]
+    fn test_next_cursor() {
+        assert_eq!(
+            next_cursor(&response("[]", Some("100:1:0"))),
+            Some("100:1:0".into())
+        );
+        assert_eq!(next_cursor(&response("[]", None)), None);
+
+        // Without a cursor parameter, the cursor of the target is used
+        let response = Response::builder()
+            .header(LINK, r#"<http://sentry/api/0/organizations/?per_page=10&cursor=100%3A1%3A0>; rel="next""#)
+            .body(Bytes::new())
+            .unwrap();
+        assert_eq!(next_cursor(&response), Some("100:1:0".into()));
+        assert_eq!(next_cursor(&Response::new(Bytes::new())), None);
+    }
+
+    #[tokio::test]
+    async fn test_pagination() {
+        let handler = PaginatedMergeHandler::new(Some("cell".into()));
+        let cells = create_test_cells(&["us1", "us2"]);
+
+        // The first page is requested from all cells
+        let (requests, metadata) = handler
+            .split_request(request("/api/0/organizations/?per_page=2"), &cells)
+            .await
+            .unwrap();
+        assert_eq!(
+            sorted_uris(&requests),
+            vec![
+                ("us1".into(), "/api/0/organizations/?per_page=2".into()),
+                ("us2".into(), "/api/0/organizations/?per_page=2".into()),
+            ]
+        );
+
+        let merged = handler
+            .merge_responses(
+                vec![
+                    ("us2".into(), Ok(response(r#"[{"id": 3}]"#, None))),
+                    (
+                        "us1".into(),
+                        Ok(response(r#"[{"id": 1}, {"id": 2}]"#, Some("2:1:0"))),
+                    ),
+                ],
+                metadata,
+            )
+            .await;
+        assert_eq!(merged.status(), StatusCode::OK);
+        let results: Value = serde_json::from_slice(merged.body()).unwrap();
+        assert_eq!(
+            results,
+            serde_json::json!([
+                {"id": 1, "cell": "us1"},
+                {"id": 2, "cell": "us1"},
+                {"id": 3, "cell": "us2"},
+            ])
+        );
+        let cursor = next_cursor(&merged).unwrap();
+        assert_eq!(
+            decode_cursor(&cursor).unwrap(),
+            Positions::from([("us1".into(), Some("2:1:0".into()))])
+        );
+
+        // Only the cell with more results is requested for the next page
+        let (requests, metadata) = handler
+            .split_request(
+                request(&format!("/api/0/organizations/?per_page=2&cursor={cursor}")),
+                &cells,
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            sorted_uris(&requests),
+            vec![(
+                "us1".into(),
+                "/api/0/organizations/?per_page=2&cursor=2%3A1%3A0".into()
+            )]
+        );
+
+        // A failed cell is requested again with the next page
+        let merged = handler
+            .merge_responses(
+                vec![(
+                    "us1".into(),
+                    Err(IngestRouterError::UpstreamTimeout("us1".into())),
+                )],
+                metadata,
+            )
+            .await;
+        assert_eq!(merged.status(), StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[tokio::test]
+    async fn test_failed_cells_keep_position() {
+        let handler = PaginatedMergeHandler::new(None);
+        let cells = create_test_cells(&["us1", "us2"]);
+
+        let (_, metadata) = handler
+            .split_request(request("/api/0/organizations/"), &cells)
+            .await
+            .unwrap();
+        let merged = handler
+            .merge_responses(
+                vec![
+                    ("us1".into(), Ok(response(r#"[{"id": 1}]"#, None))),
+                    (
+                        "us2".into(),
+                        Err(IngestRouterError::UpstreamTimeout("us2".into())),
+                    ),
+                ],
+                metadata,
+            )
+            .await;
+        assert_eq!(merged.status(), StatusCode::OK);
+        assert_eq!(merged.body().as_ref(), br#"[{"id":1}]"#);
+        // us2 starts over from its first page
+        let cursor = next_cursor(&merged).unwrap();
+        assert_eq!(
+            decode_cursor(&cursor).unwrap(),
+            Positions::from([("us2".into(), None)])
+        );
+
+        // The last page links to no further results
+        let (_, metadata) = handler
+            .split_request(
+                request(&format!("/api/0/organizations/?cursor={cursor}")),
+                &cells,
+            )
+            .await
+            .unwrap();
+        let merged = handler
+            .merge_responses(
+                vec![("us2".into(), Ok(response(r#"[{"id": 2}]"#, None)))],
+                metadata,
+            )
+            .await;
+        assert_eq!(merged.body().as_ref(), br#"[{"id":2}]"#);
+        assert_eq!(
+            merged.headers()[LINK],
+            r#"</api/0/organizations/>; rel="next"; results="false""#
+        );
+    }
+
+    #[tokio::test]
+    async fn test_invalid_cursor() {
+        let handler = PaginatedMergeHandler::new(None);
+        let cells = create_test_cells(&["us1", "us2"]);
+
+        for cursor in ["100:1:0", "bm90IGpzb24"] {
+            let (requests, metadata) = handler
+                .split_request(
+                    request(&format!("/api/0/organizations/?cursor={cursor}")),
+                    &cells,
+                )
+                .await
+                .unwrap();
+            assert!(requests.is_empty());
+            let merged = handler.merge_responses(Vec::new(), metadata).await;
+            assert_eq!(merged.status(), StatusCode::BAD_REQUEST, "{cursor}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_client_errors_are_passed() {
+        let handler = PaginatedMergeHandler::new(None);
+        let cells = create_test_cells(&["us1", "us2"]);
+
+        let (_, metadata) = handler
+            .split_request(request("/api/0/organizations/?query=invalid"), &cells)
+            .await
+            .unwrap();
+        let bad_request = Response::builder()
+            .status(StatusCode::BAD_REQUEST)
+            .body(Bytes::from_static(b"invalid query"))
+            .unwrap();
+        let merged = handler
+            .merge_responses(
+                vec![
+                    ("us1".into(), Ok(bad_request)),
+                    (
+                        "us2".into(),
+                        Err(IngestRouterError::UpstreamTimeout("us2".into())),
+                    ),
+                ],
+                metadata,
+            )
+            .await;
+        assert_eq!(merged.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(merged.body().as_ref(), b"invalid query");
+    }
+}
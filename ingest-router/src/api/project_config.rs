@@ -89,6 +89,15 @@
 //!    - Priority is determined by cell order in configuration (first = highest priority)
 //!    - Enables failover: if highest priority cell fails, next cell's global config is used
 //!
+//! 4. **Cross-locality keys** (optional, `cross_locality_routing`)
+//!    - Keys owned by a cell in another locality are added to pending by default
+//!    - With cross-locality routing enabled, they are forwarded to the owning cell instead,
+//!      provided that cell is configured under `localities`
+//!
+//! 5. **Deleted keys**
+//!    - Keys the locator reports as deleted are dropped from the response, relay does
+//!      not retry them. Keys the locator has not synced yet are added to pending
+//!
 //! ## Response Merging Strategy
 //!
 //! Responses from multiple upstreams are merged as follows:
@@ -96,10 +105,16 @@
 //! ### Configs (HashMap merge)
 //! - Merge all `configs` HashMaps from all upstreams
 //! - Configs are passed through unchanged from upstream
+//! - If more than one cell returns a config for the same key (split brain), the config of
+//!   the cell the key was routed to is kept, and the conflict is logged and counted in
+//!   `merge.conflicts`
 //!
 //! ### Pending (Array concatenation)
 //! - Concatenate all `pending` arrays from all upstream responses
-//! - Include keys from failed/timed-out upstreams
+//! - Include keys from failed/timed-out upstreams, and from cells that returned an
+//!   unreadable body. Keys a cell omitted from its response are left out, as the cell
+//!   has no config for them.
+//! - Keys with a config are removed from pending, and each key is listed once
 //! - Relay will retry these keys in a subsequent request
 //!
 //! ### Extra fields (Priority-based selection)
@@ -107,6 +122,14 @@
 //! - Priority determined by cell order in configuration (first = highest)
 //! - Forward compatibility: new fields are automatically preserved
 //!
+//! ### Protocol versions
+//! - Cells running older Sentry versions may declare the version of the protocol their
+//!   responses follow with `protocol_version`
+//! - Before version 3, `global` was returned without `global_status`, which is then set
+//!   to `"ready"`
+//! - Fields missing despite the declared version, or present before it, are still merged
+//!   but logged and counted in `response.schema_deviations`
+//!
 //! ## Error Handling
 //!
 //! ### Partial Failures (Graceful Degradation)
@@ -283,17 +306,34 @@ use crate::api::utils::{deserialize_body, normalize_headers, serialize_to_body};
 use crate::errors::IngestRouterError;
 use crate::handler::{CellId, ExecutionMode, Handler, SplitMetadata};
 use crate::locality::Cells;
+use crate::metrics_defs::{
+    CROSS_LOCALITY_KEYS, DUPLICATE_KEYS, MERGE_CONFLICTS, RESPONSE_SCHEMA_DEVIATIONS,
+};
+use crate::routing_drift::KeyDistribution;
+use crate::traffic_report::TrafficReport;
 use async_trait::async_trait;
 use http::StatusCode;
 use http::response::Parts;
 use hyper::body::Bytes;
 use hyper::header::{CONTENT_TYPE, HeaderValue};
 use hyper::{Request, Response};
-use locator::client::Locator;
+use locator::client::{ClientError, Locator};
+use locator::locator::LocatorError;
+use locator::types::CellAssignment;
 use serde::{Deserialize, Serialize};
 use serde_json::Value as JsonValue;
 use shared::http::make_error_response;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+
+/// Version of the relay project configs protocol implemented by the handler
+const PROTOCOL_VERSION: u32 = 3;
+
+// Most keys left out of the batch lookup that are looked up at the same time
+const MAX_CONCURRENT_KEY_LOOKUPS: usize = 16;
 
 /// Request format for the relay project configs endpoint.
 ///
@@ -317,6 +357,17 @@ pub struct ProjectConfigsRequest {
     pub extra_fields: HashMap<String, JsonValue>,
 }
 
+impl ProjectConfigsRequest {
+    /// Removes repeated public keys, keeping the first occurrence of each. Returns the
+    /// number of removed keys.
+    pub fn dedup_public_keys(&mut self) -> usize {
+        let len = self.public_keys.len();
+        let mut seen = HashSet::with_capacity(len);
+        self.public_keys.retain(|key| seen.insert(key.clone()));
+        len - self.public_keys.len()
+    }
+}
+
 /// Response format for the relay project configs endpoint.
 ///
 /// # Example
@@ -376,6 +427,10 @@ struct ProjectConfigsMetadata {
     cell_to_keys: HashMap<CellId, Vec<String>>,
     // keys that couldn't be assigned to any cell
     unassigned_keys: Vec<String>,
+    // declared protocol versions of the cells that are older than the current one
+    protocol_versions: HashMap<CellId, u32>,
+    // locality of the route
+    locality: String,
 }
 
 /// Handler for the Relay Project Configs endpoint
@@ -385,14 +440,240 @@ struct ProjectConfigsMetadata {
 /// pending keys.
 pub struct ProjectConfigsHandler {
     locator: Locator,
+    // Forward keys owned by another locality to their cell instead of marking them pending
+    cross_locality_routing: bool,
+    // Keys routed to each cell, for the routing drift
+    key_distribution: Option<Arc<KeyDistribution>>,
+    // Keys routed to each cell and returned pending, for the traffic report
+    traffic_report: Option<Arc<TrafficReport>>,
 }
 
 impl ProjectConfigsHandler {
-    pub fn new(locator: Locator) -> Self {
-        Self { locator }
+    pub fn new(locator: Locator, cross_locality_routing: bool) -> Self {
+        Self {
+            locator,
+            cross_locality_routing,
+            key_distribution: None,
+            traffic_report: None,
+        }
+    }
+
+    /// Records the keys routed to each cell in `distribution`
+    pub fn with_key_distribution(mut self, distribution: Arc<KeyDistribution>) -> Self {
+        self.key_distribution = Some(distribution);
+        self
+    }
+
+    /// Records the keys routed to each cell and the keys returned pending in `traffic_report`
+    pub fn with_traffic_report(mut self, traffic_report: Arc<TrafficReport>) -> Self {
+        self.traffic_report = Some(traffic_report);
+        self
+    }
+
+    /// Resolves the cell for a key that the locator placed in `owner_locality`, outside the
+    /// route's locality. Returns None if the owning cell is not configured.
+    async fn route_cross_locality(
+        &self,
+        public_key: &str,
+        owner_locality: &str,
+        cells: &Cells,
+    ) -> Option<CellId> {
+        let cell_id = match self.locator.lookup(public_key, None).await {
+            Ok(cell_id) => cell_id,
+            Err(e) => {
+                tracing::error!(
+                    public_key = %public_key,
+                    error = ?e,
+                    "Failed to route cross-locality public key"
+                );
+                return None;
+            }
+        };
+
+        if cells.locality_of(&cell_id) != Some(owner_locality) {
+            tracing::warn!(
+                public_key = %public_key,
+                cell_id = %cell_id,
+                locality = %owner_locality,
+                "Cross-locality cell is not configured"
+            );
+            return None;
+        }
+
+        metrics::counter!(
+            CROSS_LOCALITY_KEYS.name,
+            "handler" => self.name(),
+            "locality" => cells.locality().to_string(),
+            "target_locality" => owner_locality.to_string(),
+        )
+        .increment(1);
+
+        Some(cell_id)
+    }
+}
+
+impl ProjectConfigsHandler {
+    /// Adds a cell's config for a key to the merged response. If another cell already
+    /// returned a config for the key, the config of the cell the key was routed to wins.
+    fn merge_config(
+        &self,
+        merged: &mut ProjectConfigsResponse,
+        config_sources: &mut HashMap<String, CellId>,
+        cell_to_keys: &HashMap<CellId, Vec<String>>,
+        cell_id: &CellId,
+        public_key: String,
+        config: JsonValue,
+    ) {
+        let Some(existing_cell_id) = config_sources.get(&public_key) else {
+            config_sources.insert(public_key.clone(), cell_id.clone());
+            merged.project_configs.insert(public_key, config);
+            return;
+        };
+
+        let designated = cell_to_keys
+            .get(cell_id)
+            .is_some_and(|keys| keys.contains(&public_key));
+        let (kept, dropped) = if designated {
+            (cell_id, existing_cell_id)
+        } else {
+            (existing_cell_id, cell_id)
+        };
+
+        tracing::warn!(
+            public_key = %public_key,
+            kept_cell_id = %kept,
+            dropped_cell_id = %dropped,
+            "Project config returned by more than one cell"
+        );
+        metrics::counter!(
+            MERGE_CONFLICTS.name,
+            "handler" => self.name(),
+            "kept_cell_id" => kept.clone(),
+            "dropped_cell_id" => dropped.clone(),
+        )
+        .increment(1);
+
+        if designated {
+            config_sources.insert(public_key.clone(), cell_id.clone());
+            merged.project_configs.insert(public_key, config);
+        }
     }
 }
 
+/// Writes the request body sent to each cell. Only `publicKeys` differs between cells, so
+/// the remaining fields are serialized once and spliced into every body, instead of being
+/// cloned and serialized again per cell.
+struct SplitBodyWriter {
+    // Serialized members of the shared fields, prefixed by a comma if there are any
+    shared: Vec<u8>,
+}
+
+impl SplitBodyWriter {
+    fn new(extra_fields: &HashMap<String, JsonValue>) -> Result<Self, IngestRouterError> {
+        let object = serde_json::to_vec(extra_fields)
+            .map_err(|e| IngestRouterError::RequestBodyError(e.to_string()))?;
+
+        // Strip the braces of the serialized object
+        let members = &object[1..object.len() - 1];
+        let shared = if members.is_empty() {
+            Vec::new()
+        } else {
+            [b",", members].concat()
+        };
+
+        Ok(Self { shared })
+    }
+
+    /// Equivalent to serializing a `ProjectConfigsRequest` with the keys and shared fields.
+    fn write(&self, public_keys: &[String]) -> Result<Bytes, IngestRouterError> {
+        let keys_len: usize = public_keys.iter().map(|key| key.len() + 3).sum();
+        let mut buf = Vec::with_capacity(16 + keys_len + self.shared.len());
+
+        buf.extend_from_slice(br#"{"publicKeys":"#);
+        serde_json::to_writer(&mut buf, public_keys)
+            .map_err(|e| IngestRouterError::RequestBodyError(e.to_string()))?;
+        buf.extend_from_slice(&self.shared);
+        buf.push(b'}');
+
+        Ok(Bytes::from(buf))
+    }
+}
+
+/// Adapts a cell's response to the current protocol version, and reports fields that
+/// deviate from the version the cell declared.
+fn adapt_response(
+    handler: &'static str,
+    cell_id: &str,
+    protocol_version: u32,
+    response: &mut ProjectConfigsResponse,
+) {
+    let report = |field: &'static str| {
+        tracing::warn!(
+            cell_id = %cell_id,
+            protocol_version,
+            field,
+            "Project configs response deviates from the declared protocol version"
+        );
+        metrics::counter!(
+            RESPONSE_SCHEMA_DEVIATIONS.name,
+            "handler" => handler,
+            "cell_id" => cell_id.to_string(),
+            "protocol_version" => protocol_version.to_string(),
+            "field" => field,
+        )
+        .increment(1);
+    };
+
+    // Before version 3, the global config was returned without its status
+    if response.extra_fields.contains_key("global")
+        && !response.extra_fields.contains_key("global_status")
+    {
+        if protocol_version >= 3 {
+            report("global_status");
+        }
+        response
+            .extra_fields
+            .insert("global_status".to_string(), JsonValue::from("ready"));
+    }
+
+    // Before version 3, configs were computed synchronously and never pending
+    if protocol_version < 3 && !response.pending_keys.is_empty() {
+        report("pending");
+    }
+}
+
+/// Picks the cell for a key whose project spans multiple cells, splitting keys between the
+/// cells according to their weights. The choice is stable for a given key, so the same key
+/// is always routed to the same cell while the weights are unchanged.
+fn assign_cell(public_key: &str, assignments: &[CellAssignment]) -> Option<CellId> {
+    let total: u64 = assignments.iter().map(|a| u64::from(a.weight)).sum();
+    if total == 0 {
+        return assignments
+            .iter()
+            .find(|a| a.primary)
+            .or(assignments.first())
+            .map(|a| a.cell.clone());
+    }
+
+    // FNV-1a, a hash that is stable across processes and releases
+    let hash = public_key
+        .bytes()
+        .fold(0xcbf29ce484222325_u64, |hash, byte| {
+            (hash ^ u64::from(byte)).wrapping_mul(0x100000001b3)
+        });
+
+    let mut bucket = hash % total;
+    for assignment in assignments {
+        let weight = u64::from(assignment.weight);
+        if bucket < weight {
+            return Some(assignment.cell.clone());
+        }
+        bucket -= weight;
+    }
+
+    None
+}
+
 #[async_trait]
 impl Handler for ProjectConfigsHandler {
     fn name(&self) -> &'static str {
@@ -406,30 +687,102 @@ impl Handler for ProjectConfigsHandler {
     fn requires_relay_auth(&self) -> bool {
         true
     }
+
+    fn audit_keys(&self, request: &Request<Bytes>) -> Vec<String> {
+        serde_json::from_slice::<ProjectConfigsRequest>(request.body())
+            .map(|mut request| {
+                request.dedup_public_keys();
+                request.public_keys
+            })
+            .unwrap_or_default()
+    }
+
     async fn split_request(
         &self,
         request: Request<Bytes>,
         cells: &Cells,
     ) -> Result<(Vec<(CellId, Request<Bytes>)>, SplitMetadata), IngestRouterError> {
         let (mut parts, body) = request.into_parts();
-        let parsed: ProjectConfigsRequest = deserialize_body(body)?;
+        let mut parsed: ProjectConfigsRequest = deserialize_body(body)?;
         normalize_headers(&mut parts.headers, parts.version);
 
+        // Each key is looked up and sent upstream once, and appears once in the response
+        let duplicates = parsed.dedup_public_keys();
+        if duplicates > 0 {
+            tracing::debug!(duplicates, "Removed duplicate public keys from request");
+            metrics::counter!(DUPLICATE_KEYS.name, "handler" => self.name())
+                .increment(duplicates as u64);
+        }
+
         let public_keys = parsed.public_keys;
         let extra_fields = parsed.extra_fields;
 
-        // Route each public key to its owning cell using the locator service
+        // Route each public key to its owning cell using the locator service. All keys are
+        // looked up in one batch, the keys it leaves out are looked up one by one, a few at
+        // a time, to tell deleted, cross-locality and unknown keys apart.
+        let batch: Vec<&str> = public_keys.iter().map(String::as_str).collect();
+        let mut found = self
+            .locator
+            .lookup_many_multi(&batch, Some(cells.locality()))
+            .await
+            .unwrap_or_else(|e| {
+                tracing::warn!(error = ?e, "Failed to look up public keys in a batch");
+                HashMap::new()
+            });
+
+        let permits = Arc::new(Semaphore::new(MAX_CONCURRENT_KEY_LOOKUPS));
+        let mut lookups = JoinSet::new();
+        for public_key in public_keys.iter().filter(|key| !found.contains_key(*key)) {
+            let locator = self.locator.clone();
+            let locality = cells.locality().to_string();
+            let public_key = public_key.clone();
+            let permits = permits.clone();
+            lookups.spawn(async move {
+                let _permit = permits.acquire_owned().await;
+                let assignments = locator.lookup_multi(&public_key, Some(&locality)).await;
+                (public_key, assignments)
+            });
+        }
+        let mut looked_up = HashMap::new();
+        while let Some(lookup) = lookups.join_next().await {
+            match lookup {
+                Ok((public_key, assignments)) => {
+                    looked_up.insert(public_key, assignments);
+                }
+                Err(e) => tracing::error!(error = %e, "Public key lookup failed"),
+            }
+        }
+
         let mut cell_to_keys: HashMap<CellId, Vec<String>> = HashMap::new();
         let mut pending: Vec<String> = Vec::new();
 
         for public_key in public_keys {
-            match self
-                .locator
-                .lookup(&public_key, Some(cells.locality()))
-                .await
-            {
-                Ok(cell_id) => {
-                    cell_to_keys.entry(cell_id).or_default().push(public_key);
+            let assignments = match found.remove(&public_key) {
+                Some(assignments) => Ok(assignments),
+                None => match looked_up.remove(&public_key) {
+                    Some(assignments) => assignments,
+                    None => {
+                        pending.push(public_key);
+                        continue;
+                    }
+                },
+            };
+            match assignments {
+                Ok(assignments) => match assign_cell(&public_key, &assignments) {
+                    Some(cell_id) => cell_to_keys.entry(cell_id).or_default().push(public_key),
+                    None => pending.push(public_key),
+                },
+                Err(ClientError::LocatorError(LocatorError::LocalityMismatch {
+                    actual, ..
+                })) if self.cross_locality_routing => {
+                    match self.route_cross_locality(&public_key, &actual, cells).await {
+                        Some(cell_id) => cell_to_keys.entry(cell_id).or_default().push(public_key),
+                        None => pending.push(public_key),
+                    }
+                }
+                Err(ClientError::LocatorError(LocatorError::Deleted)) => {
+                    // The project was deleted, relay will not get a config for it
+                    tracing::debug!(public_key = %public_key, "Dropping deleted public key");
                 }
                 Err(e) => {
                     // Locator errors, add to pending
@@ -443,23 +796,37 @@ impl Handler for ProjectConfigsHandler {
             }
         }
 
+        if let Some(distribution) = &self.key_distribution {
+            for (cell_id, keys) in &cell_to_keys {
+                distribution.record(cell_id, keys.len());
+            }
+        }
+
+        let body_writer = SplitBodyWriter::new(&extra_fields)?;
         let cell_requests = cell_to_keys
             .iter()
             .map(|(cell_id, keys)| {
-                let project_configs_request = ProjectConfigsRequest {
-                    public_keys: keys.clone(),
-                    extra_fields: extra_fields.clone(),
-                };
-
-                let body = serialize_to_body(&project_configs_request)?;
+                let body = body_writer.write(keys)?;
                 let req = Request::from_parts(parts.clone(), body);
                 Ok((cell_id.into(), req))
             })
             .collect::<Result<_, IngestRouterError>>()?;
 
+        let protocol_versions = cell_to_keys
+            .keys()
+            .filter_map(|cell_id| {
+                cells
+                    .protocol_version(cell_id)
+                    .filter(|version| *version < PROTOCOL_VERSION)
+                    .map(|version| (cell_id.clone(), version))
+            })
+            .collect();
+
         let metadata = Box::new(ProjectConfigsMetadata {
             cell_to_keys,
             unassigned_keys: pending,
+            protocol_versions,
+            locality: cells.locality().to_string(),
         });
         Ok((cell_requests, metadata))
     }
@@ -475,6 +842,7 @@ impl Handler for ProjectConfigsHandler {
             .unwrap_or(Box::new(ProjectConfigsMetadata::default()));
 
         let mut merged = ProjectConfigsResponse::new();
+        let unassigned_keys = meta.unassigned_keys.len();
         merged.pending_keys.extend(meta.unassigned_keys);
 
         // Order the responses so successful ones come first
@@ -495,6 +863,8 @@ impl Handler for ProjectConfigsHandler {
 
         // Parts is populated from the first response.
         let mut parts: Option<Parts> = None;
+        // Cell each merged config came from
+        let mut config_sources: HashMap<String, CellId> = HashMap::new();
 
         for (cell_id, result) in sorted_responses {
             let successful_response = result.ok().filter(|r| r.status().is_success());
@@ -512,8 +882,24 @@ impl Handler for ProjectConfigsHandler {
                 parts = Some(p);
             }
 
-            if let Ok(parsed) = deserialize_body::<ProjectConfigsResponse>(body) {
-                merged.project_configs.extend(parsed.project_configs);
+            if let Ok(mut parsed) = deserialize_body::<ProjectConfigsResponse>(body) {
+                let protocol_version = meta
+                    .protocol_versions
+                    .get(&cell_id)
+                    .copied()
+                    .unwrap_or(PROTOCOL_VERSION);
+                adapt_response(self.name(), &cell_id, protocol_version, &mut parsed);
+
+                for (public_key, config) in parsed.project_configs {
+                    self.merge_config(
+                        &mut merged,
+                        &mut config_sources,
+                        &meta.cell_to_keys,
+                        &cell_id,
+                        public_key,
+                        config,
+                    );
+                }
                 merged.extra_fields.extend(parsed.extra_fields);
                 merged.pending_keys.extend(parsed.pending_keys);
             } else {
@@ -521,6 +907,41 @@ impl Handler for ProjectConfigsHandler {
                     cell_id = %cell_id,
                     "Failed to deserialize project configs response from cell"
                 );
+                if let Some(keys) = meta.cell_to_keys.get(&cell_id) {
+                    merged.pending_keys.extend(keys.clone());
+                }
+            }
+        }
+
+        let mut seen = HashSet::with_capacity(merged.pending_keys.len());
+        merged
+            .pending_keys
+            .retain(|key| !merged.project_configs.contains_key(key) && seen.insert(key.clone()));
+
+        if let Some(traffic_report) = &self.traffic_report {
+            let pending: HashSet<&str> = merged.pending_keys.iter().map(String::as_str).collect();
+            let now = Instant::now();
+            for (cell_id, keys) in &meta.cell_to_keys {
+                let pending_keys = keys
+                    .iter()
+                    .filter(|key| pending.contains(key.as_str()))
+                    .count();
+                traffic_report.record_keys(
+                    &meta.locality,
+                    Some(cell_id),
+                    keys.len(),
+                    pending_keys,
+                    now,
+                );
+            }
+            if unassigned_keys > 0 {
+                traffic_report.record_keys(
+                    &meta.locality,
+                    None,
+                    unassigned_keys,
+                    unassigned_keys,
+                    now,
+                );
             }
         }
 
@@ -545,6 +966,8 @@ mod tests {
     use crate::config::CellConfig;
     use crate::locality::Localities;
     use crate::testutils::create_test_locator;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
     use std::collections::HashMap;
     use url::Url;
 
@@ -578,11 +1001,17 @@ mod tests {
                     id: "us1".to_string(),
                     sentry_url: Url::parse("http://sentry-us1:8080").unwrap(),
                     relay_url: Url::parse("http://relay-us1:8090").unwrap(),
+                    protocol_version: None,
+                    tls: None,
+                    max_rps: None,
                 },
                 CellConfig {
                     id: "us2".to_string(),
                     sentry_url: Url::parse("http://sentry-us2:8080").unwrap(),
                     relay_url: Url::parse("http://relay-us2:8090").unwrap(),
+                    protocol_version: None,
+                    tls: None,
+                    max_rps: None,
                 },
             ],
         )]);
@@ -590,7 +1019,7 @@ mod tests {
         let localities_obj = Localities::new(localities);
         let cells = localities_obj.get_cells("us").unwrap();
 
-        let handler = ProjectConfigsHandler::new(locator);
+        let handler = ProjectConfigsHandler::new(locator, false);
 
         let mut extra = HashMap::new();
         extra.insert("global".to_string(), serde_json::json!(true));
@@ -619,7 +1048,7 @@ mod tests {
             ("key3".to_string(), "us1".to_string()),
         ]);
         let locator = create_test_locator(key_to_cell).await;
-        let handler = ProjectConfigsHandler::new(locator);
+        let handler = ProjectConfigsHandler::new(locator, false);
         let localities = HashMap::from([(
             "us".to_string(),
             vec![
@@ -627,11 +1056,17 @@ mod tests {
                     id: "us1".to_string(),
                     sentry_url: Url::parse("http://sentry-us1:8080").unwrap(),
                     relay_url: Url::parse("http://relay-us1:8090").unwrap(),
+                    protocol_version: None,
+                    tls: None,
+                    max_rps: None,
                 },
                 CellConfig {
                     id: "us2".to_string(),
                     sentry_url: Url::parse("http://sentry-us2:8080").unwrap(),
                     relay_url: Url::parse("http://relay-us2:8090").unwrap(),
+                    protocol_version: None,
+                    tls: None,
+                    max_rps: None,
                 },
             ],
         )]);
@@ -667,6 +1102,86 @@ mod tests {
         assert!(meta.unassigned_keys.is_empty());
     }
 
+    #[tokio::test]
+    async fn test_split_request_duplicate_keys() {
+        let key_to_cell = HashMap::from([("key1".to_string(), "us1".to_string())]);
+        let locator = create_test_locator(key_to_cell).await;
+        let localities = HashMap::from([(
+            "us".to_string(),
+            vec![CellConfig {
+                id: "us1".to_string(),
+                sentry_url: Url::parse("http://us1:8080").unwrap(),
+                relay_url: Url::parse("http://us1:8090").unwrap(),
+                protocol_version: None,
+                tls: None,
+                max_rps: None,
+            }],
+        )]);
+        let localities_obj = Localities::new(localities);
+        let cells = localities_obj.get_cells("us").unwrap();
+        let handler = ProjectConfigsHandler::new(locator, false);
+
+        let keys = ["key1", "unknown_key", "key1", "unknown_key", "key1"];
+        let request = build_request(ProjectConfigsRequest {
+            public_keys: keys.iter().map(|key| key.to_string()).collect(),
+            extra_fields: HashMap::new(),
+        });
+        assert_eq!(handler.audit_keys(&request), vec!["key1", "unknown_key"]);
+
+        let (cell_requests, metadata) = handler.split_request(request, &cells).await.unwrap();
+        let (_, us1_req) = cell_requests.into_iter().next().unwrap();
+        let us1_body: ProjectConfigsRequest = deserialize_body(us1_req.into_body()).unwrap();
+        assert_eq!(us1_body.public_keys, vec!["key1"]);
+
+        let meta = metadata.downcast::<ProjectConfigsMetadata>().unwrap();
+        assert_eq!(meta.unassigned_keys, vec!["unknown_key"]);
+    }
+
+    #[tokio::test]
+    async fn test_split_request_single_round_trip() {
+        use http_body_util::Full;
+        use shared::testutils::MockServer;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        // Remote locator answering every request with the cells of the keys
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counted = requests.clone();
+        let locator_api = MockServer::spawn(move |_| {
+            counted.fetch_add(1, Ordering::SeqCst);
+            let body = r#"{"cells": {"key1": "us1", "key2": "us2", "key3": "us1"}}"#;
+            let mut response = Response::new(Full::new(Bytes::from_static(body.as_bytes())));
+            response
+                .headers_mut()
+                .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+            response
+        })
+        .await;
+        let locator = Locator::new(locator::client::LocatorConfig {
+            locator_type: locator::client::LocatorType::Url {
+                url: locator_api.url(),
+                api_key: None,
+                datagram_addr: None,
+                cache: None,
+            },
+            data_type: locator::config::LocatorDataType::ProjectKey,
+            caller: None,
+        })
+        .await
+        .unwrap();
+
+        let cells = crate::testutils::create_test_cells(&["us1", "us2"]);
+        let handler = ProjectConfigsHandler::new(locator, false);
+
+        let request = build_request(ProjectConfigsRequest {
+            public_keys: vec!["key1".into(), "key2".into(), "key3".into()],
+            extra_fields: HashMap::new(),
+        });
+        let (cell_requests, _metadata) = handler.split_request(request, &cells).await.unwrap();
+
+        assert_eq!(cell_requests.len(), 2);
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+    }
+
     #[tokio::test]
     async fn test_split_request_unknown_key_goes_to_pending() {
         let key_to_cell = HashMap::from([("key1".to_string(), "us1".to_string())]);
@@ -677,13 +1192,16 @@ mod tests {
                 id: "us1".to_string(),
                 sentry_url: Url::parse("http://us1:8080").unwrap(),
                 relay_url: Url::parse("http://us1:8090").unwrap(),
+                protocol_version: None,
+                tls: None,
+                max_rps: None,
             }],
         )]);
 
         let localities_obj = Localities::new(localities);
         let cells = localities_obj.get_cells("us").unwrap();
 
-        let handler = ProjectConfigsHandler::new(locator);
+        let handler = ProjectConfigsHandler::new(locator, false);
 
         let request = build_request(ProjectConfigsRequest {
             public_keys: vec!["key1".to_string(), "unknown_key".to_string()],
@@ -703,10 +1221,200 @@ mod tests {
         assert_eq!(meta.unassigned_keys, Vec::from(["unknown_key".to_string()]));
     }
 
+    #[test]
+    fn test_assign_cell() {
+        let assignment = |cell: &str, weight| CellAssignment {
+            cell: cell.to_string(),
+            weight,
+            primary: cell == "us1",
+        };
+
+        assert_eq!(
+            assign_cell("key1", &[CellAssignment::single("us1".into())]),
+            Some("us1".into())
+        );
+
+        // Keys are split according to the weights
+        let assignments = [assignment("us1", 75), assignment("us2", 25)];
+        let keys: Vec<String> = (0..1000).map(|i| format!("key{i}")).collect();
+        let to_us2 = keys
+            .iter()
+            .filter(|key| assign_cell(key, &assignments) == Some("us2".into()))
+            .count();
+        assert!((150..350).contains(&to_us2), "{to_us2} keys routed to us2");
+
+        // Stable for a key
+        assert_eq!(
+            assign_cell("key1", &assignments),
+            assign_cell("key1", &assignments)
+        );
+
+        // Zero weights fall back to the primary cell
+        let assignments = [assignment("us2", 0), assignment("us1", 0)];
+        assert_eq!(assign_cell("key1", &assignments), Some("us1".into()));
+        assert_eq!(assign_cell("key1", &[]), None);
+    }
+
+    #[test]
+    fn test_split_body_writer() {
+        let keys = vec!["key1".to_string(), "key\"2".to_string()];
+
+        for extra_fields in [
+            HashMap::new(),
+            HashMap::from([
+                ("global".to_string(), serde_json::json!(true)),
+                ("noCache".to_string(), serde_json::json!({"nested": [1, 2]})),
+            ]),
+        ] {
+            let written = SplitBodyWriter::new(&extra_fields)
+                .unwrap()
+                .write(&keys)
+                .unwrap();
+            let serialized = serialize_to_body(&ProjectConfigsRequest {
+                public_keys: keys.clone(),
+                extra_fields,
+            })
+            .unwrap();
+            assert_eq!(
+                serde_json::from_slice::<JsonValue>(&written).unwrap(),
+                serde_json::from_slice::<JsonValue>(&serialized).unwrap()
+            );
+        }
+    }
+
+    /// Compares the split body writer with serializing a full request per cell.
+    /// Run with `cargo test --release -p ingest-router bench_split_bodies -- --ignored --nocapture`.
+    #[test]
+    #[ignore]
+    fn bench_split_bodies() {
+        const CELLS: usize = 10;
+        const ITERATIONS: u32 = 200;
+
+        let cell_keys: Vec<Vec<String>> = (0..CELLS)
+            .map(|cell| (0..1000).map(|i| format!("{cell:02}{i:030}")).collect())
+            .collect();
+        let extra_fields: HashMap<_, _> = (0..50)
+            .map(|i| {
+                (
+                    format!("field{i}"),
+                    serde_json::json!({"values": vec![i; 20]}),
+                )
+            })
+            .collect();
+
+        let start = std::time::Instant::now();
+        for _ in 0..ITERATIONS {
+            for keys in &cell_keys {
+                let request = ProjectConfigsRequest {
+                    public_keys: keys.clone(),
+                    extra_fields: extra_fields.clone(),
+                };
+                std::hint::black_box(serialize_to_body(&request).unwrap());
+            }
+        }
+        let per_request = start.elapsed() / ITERATIONS;
+
+        let start = std::time::Instant::now();
+        for _ in 0..ITERATIONS {
+            let writer = SplitBodyWriter::new(&extra_fields).unwrap();
+            for keys in &cell_keys {
+                std::hint::black_box(writer.write(keys).unwrap());
+            }
+        }
+        let per_request_writer = start.elapsed() / ITERATIONS;
+
+        println!(
+            "{CELLS} cells with 1000 keys: serialize per cell {per_request:?}, split body writer {per_request_writer:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_audit_keys() {
+        let locator = create_test_locator(HashMap::new()).await;
+        let handler = ProjectConfigsHandler::new(locator, false);
+
+        let request = build_request(ProjectConfigsRequest {
+            public_keys: vec!["key1".to_string(), "key2".to_string()],
+            extra_fields: HashMap::new(),
+        });
+        assert_eq!(handler.audit_keys(&request), vec!["key1", "key2"]);
+
+        // Invalid bodies have no keys
+        let request = Request::new(Bytes::from_static(b"not json"));
+        assert!(handler.audit_keys(&request).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_split_request_cross_locality() {
+        let key_to_cell = HashMap::from([
+            ("key1".to_string(), "us1".to_string()),
+            ("key2".to_string(), "de1".to_string()),
+        ]);
+        let localities = HashMap::from([
+            (
+                "us".to_string(),
+                vec![CellConfig {
+                    id: "us1".to_string(),
+                    sentry_url: Url::parse("http://us1:8080").unwrap(),
+                    relay_url: Url::parse("http://us1:8090").unwrap(),
+                    protocol_version: None,
+                    tls: None,
+                    max_rps: None,
+                }],
+            ),
+            (
+                "de".to_string(),
+                vec![CellConfig {
+                    id: "de1".to_string(),
+                    sentry_url: Url::parse("http://de1:8080").unwrap(),
+                    relay_url: Url::parse("http://de1:8090").unwrap(),
+                    protocol_version: None,
+                    tls: None,
+                    max_rps: None,
+                }],
+            ),
+        ]);
+        let localities_obj = Localities::new(localities);
+        let cells = localities_obj.get_cells("us").unwrap();
+
+        let request = || {
+            build_request(ProjectConfigsRequest {
+                public_keys: vec!["key1".to_string(), "key2".to_string()],
+                extra_fields: HashMap::new(),
+            })
+        };
+
+        // Disabled: the key owned by another locality goes to pending
+        let locator = create_test_locator(key_to_cell.clone()).await;
+        let handler = ProjectConfigsHandler::new(locator, false);
+        let (cell_requests, metadata) = handler.split_request(request(), &cells).await.unwrap();
+        assert_eq!(cell_requests.len(), 1);
+        assert_eq!(cell_requests[0].0, "us1");
+        let meta = metadata.downcast::<ProjectConfigsMetadata>().unwrap();
+        assert_eq!(meta.unassigned_keys, vec!["key2".to_string()]);
+
+        // Enabled: the key is forwarded to its owning cell
+        let locator = create_test_locator(key_to_cell).await;
+        let handler = ProjectConfigsHandler::new(locator, true);
+        let (cell_requests, metadata) = handler.split_request(request(), &cells).await.unwrap();
+        assert_eq!(cell_requests.len(), 2);
+        assert!(cell_requests.iter().any(|(id, _)| id == "de1"));
+        let meta = metadata.downcast::<ProjectConfigsMetadata>().unwrap();
+        assert!(meta.unassigned_keys.is_empty());
+        assert_eq!(
+            meta.cell_to_keys.get("de1").unwrap(),
+            &vec!["key2".to_string()]
+        );
+        assert_eq!(
+            cells.resolve_upstream("de1").unwrap().relay_url.as_str(),
+            "http://de1:8090/"
+        );
+    }
+
     #[tokio::test]
     async fn test_merge_results_successful_cells() {
         let locator = create_test_locator(HashMap::new()).await;
-        let handler = ProjectConfigsHandler::new(locator);
+        let handler = ProjectConfigsHandler::new(locator, false);
 
         // Create response from us1 with key1 and global config
         let response1_json = serde_json::json!({
@@ -737,6 +1445,8 @@ mod tests {
                 ("us2".to_string(), vec!["key2".to_string()]),
             ]),
             unassigned_keys: Vec::new(),
+            protocol_versions: HashMap::new(),
+            locality: "us".to_string(),
         });
         let merged = handler.merge_responses(results, metadata).await;
 
@@ -749,7 +1459,7 @@ mod tests {
     #[tokio::test]
     async fn test_merge_responses_with_pending() {
         let locator = create_test_locator(HashMap::new()).await;
-        let handler = ProjectConfigsHandler::new(locator);
+        let handler = ProjectConfigsHandler::new(locator, false);
 
         // Test pending keys from split phase (routing failures, unknown keys)
 
@@ -785,6 +1495,8 @@ mod tests {
                 "key_from_failed_cell1".to_string(),
                 "key_from_failed_cell2".to_string(),
             ],
+            protocol_versions: HashMap::new(),
+            locality: "us".to_string(),
         };
 
         let metadata: SplitMetadata = Box::new(pending_from_split);
@@ -815,4 +1527,258 @@ mod tests {
                 .contains(&"key_from_failed_cell2".to_string())
         );
     }
+
+    #[tokio::test]
+    async fn test_merge_responses_fuzz() {
+        let locator = create_test_locator(HashMap::new()).await;
+        let handler = ProjectConfigsHandler::new(locator, false);
+
+        for seed in 1..=1000u64 {
+            let mut rng = StdRng::seed_from_u64(seed);
+            let mut keys = (0..).map(|i| format!("key{i}"));
+
+            let cells: Vec<CellId> = (0..rng.random_range(1..4))
+                .map(|i| format!("cell{i}"))
+                .collect();
+            let cell_to_keys: HashMap<CellId, Vec<String>> = cells
+                .iter()
+                .map(|cell| {
+                    (
+                        cell.clone(),
+                        keys.by_ref().take(rng.random_range(0..5)).collect(),
+                    )
+                })
+                .collect();
+            let unassigned_keys: Vec<String> = keys.by_ref().take(rng.random_range(0..3)).collect();
+            let requested: Vec<String> = cell_to_keys
+                .values()
+                .flatten()
+                .chain(&unassigned_keys)
+                .cloned()
+                .collect();
+
+            let mut results: Vec<(CellId, Result<Response<Bytes>, IngestRouterError>)> = Vec::new();
+            // Keys that must be retried, as their cell failed
+            let mut retried: Vec<String> = unassigned_keys.clone();
+            for cell in &cells {
+                let outcome = rng.random_range(0..6);
+                if outcome < 3 {
+                    retried.extend(cell_to_keys[cell].iter().cloned());
+                }
+                let result = match outcome {
+                    0 => Err(IngestRouterError::UpstreamTimeout(cell.clone())),
+                    1 => Ok(Response::builder()
+                        .status(500)
+                        .body(Bytes::from_static(b"error"))
+                        .unwrap()),
+                    2 => Ok(Response::builder()
+                        .status(200)
+                        .body(Bytes::from_static(br#"{"configs": {"key0": "#))
+                        .unwrap()),
+                    _ => {
+                        // Valid, but possibly partial, pending or conflicting
+                        let mut configs = serde_json::Map::new();
+                        let mut pending = Vec::new();
+                        for key in &cell_to_keys[cell] {
+                            match rng.random_range(0..4) {
+                                0 => {}
+                                1 => pending.push(key.clone()),
+                                _ => {
+                                    configs.insert(key.clone(), serde_json::json!({"cell": cell}));
+                                }
+                            }
+                        }
+                        if !requested.is_empty() && rng.random_range(0..4) == 0 {
+                            // Config or pending key of a key routed elsewhere
+                            let key = requested[rng.random_range(0..requested.len())].clone();
+                            if rng.random_range(0..2) == 0 {
+                                configs.insert(key, serde_json::json!({"cell": cell}));
+                            } else {
+                                pending.push(key);
+                            }
+                        }
+                        Ok(build_response(
+                            serde_json::json!({"configs": configs, "pending": pending}),
+                        ))
+                    }
+                };
+                results.push((cell.clone(), result));
+            }
+
+            let metadata: SplitMetadata = Box::new(ProjectConfigsMetadata {
+                cell_to_keys,
+                unassigned_keys,
+                protocol_versions: HashMap::new(),
+                locality: "us".to_string(),
+            });
+            let merged = handler.merge_responses(results, metadata).await;
+            if !merged.status().is_success() {
+                continue;
+            }
+
+            let parsed: ProjectConfigsResponse = deserialize_body(merged.into_body())
+                .unwrap_or_else(|e| panic!("seed {seed}: invalid merged response: {e:?}"));
+            for key in &requested {
+                let count = usize::from(parsed.project_configs.contains_key(key))
+                    + parsed.pending_keys.iter().filter(|p| *p == key).count();
+                assert!(
+                    count <= 1,
+                    "seed {seed}: {key} is in both configs and pending, or listed twice"
+                );
+                if retried.contains(key) {
+                    assert_eq!(count, 1, "seed {seed}: {key} of a failed cell is missing");
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_merge_responses_protocol_versions() {
+        let key_to_cell = HashMap::from([
+            ("key1".to_string(), "us1".to_string()),
+            ("key2".to_string(), "us2".to_string()),
+        ]);
+        let locator = create_test_locator(key_to_cell).await;
+        let handler = ProjectConfigsHandler::new(locator, false);
+
+        // us1 runs an older Sentry version
+        let localities = Localities::new(HashMap::from([(
+            "us".to_string(),
+            vec![
+                CellConfig {
+                    id: "us1".to_string(),
+                    sentry_url: Url::parse("http://sentry-us1:8080").unwrap(),
+                    relay_url: Url::parse("http://relay-us1:8090").unwrap(),
+                    protocol_version: Some(2),
+                    tls: None,
+                    max_rps: None,
+                },
+                CellConfig {
+                    id: "us2".to_string(),
+                    sentry_url: Url::parse("http://sentry-us2:8080").unwrap(),
+                    relay_url: Url::parse("http://relay-us2:8090").unwrap(),
+                    protocol_version: None,
+                    tls: None,
+                    max_rps: None,
+                },
+            ],
+        )]));
+        let cells = localities.get_cells("us").unwrap();
+
+        let request = build_request(ProjectConfigsRequest {
+            public_keys: vec!["key1".to_string(), "key2".to_string()],
+            extra_fields: HashMap::from([("global".to_string(), serde_json::json!(true))]),
+        });
+        let (_cell_requests, metadata) = handler.split_request(request, &cells).await.unwrap();
+        let meta = metadata.downcast::<ProjectConfigsMetadata>().unwrap();
+        assert_eq!(
+            meta.protocol_versions,
+            HashMap::from([("us1".to_string(), 2)])
+        );
+
+        // The version 2 response has no global_status
+        let results = vec![
+            (
+                "us1".to_string(),
+                Ok(build_response(serde_json::json!({
+                    "configs": {"key1": {"slug": "project1"}},
+                    "global": {"version": 1}
+                }))),
+            ),
+            (
+                "us2".to_string(),
+                Ok(build_response(serde_json::json!({
+                    "configs": {"key2": {"slug": "project2"}}
+                }))),
+            ),
+        ];
+        let merged = handler.merge_responses(results, meta).await;
+        let parsed: ProjectConfigsResponse = deserialize_body(merged.into_body()).unwrap();
+
+        assert_eq!(parsed.project_configs.len(), 2);
+        assert_eq!(
+            parsed.extra_fields.get("global_status"),
+            Some(&serde_json::json!("ready"))
+        );
+    }
+
+    #[test]
+    fn test_adapt_response() {
+        let mut response: ProjectConfigsResponse = serde_json::from_value(serde_json::json!({
+            "configs": {},
+            "pending": ["key1"],
+            "global": {},
+            "global_status": "pending"
+        }))
+        .unwrap();
+
+        // Fields that are present are never changed
+        adapt_response("ProjectConfigsHandler", "us1", 2, &mut response);
+        assert_eq!(response.pending_keys, vec!["key1".to_string()]);
+        assert_eq!(
+            response.extra_fields.get("global_status"),
+            Some(&serde_json::json!("pending"))
+        );
+
+        // A missing global_status is filled in regardless of the declared version
+        response.extra_fields.remove("global_status");
+        adapt_response("ProjectConfigsHandler", "us1", 3, &mut response);
+        assert_eq!(
+            response.extra_fields.get("global_status"),
+            Some(&serde_json::json!("ready"))
+        );
+
+        // Without global, no status is added
+        let mut response = ProjectConfigsResponse::new();
+        adapt_response("ProjectConfigsHandler", "us1", 2, &mut response);
+        assert!(response.extra_fields.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_merge_responses_conflicting_configs() {
+        let locator = create_test_locator(HashMap::new()).await;
+        let handler = ProjectConfigsHandler::new(locator, false);
+
+        // Both cells return key2, which was routed to us2
+        let results = || {
+            vec![
+                (
+                    "us1".to_string(),
+                    Ok(build_response(serde_json::json!({
+                        "configs": {
+                            "key1": {"slug": "project1"},
+                            "key2": {"slug": "stale"}
+                        }
+                    }))),
+                ),
+                (
+                    "us2".to_string(),
+                    Ok(build_response(serde_json::json!({
+                        "configs": {"key2": {"slug": "project2"}}
+                    }))),
+                ),
+            ]
+        };
+
+        // Regardless of which cell responds first
+        for results in [results(), results().into_iter().rev().collect()] {
+            let metadata: SplitMetadata = Box::new(ProjectConfigsMetadata {
+                cell_to_keys: HashMap::from([
+                    ("us1".to_string(), vec!["key1".to_string()]),
+                    ("us2".to_string(), vec!["key2".to_string()]),
+                ]),
+                unassigned_keys: Vec::new(),
+                protocol_versions: HashMap::new(),
+                locality: "us".to_string(),
+            });
+            let merged = handler.merge_responses(results, metadata).await;
+            let parsed: ProjectConfigsResponse = deserialize_body(merged.into_body()).unwrap();
+
+            assert_eq!(parsed.project_configs.len(), 2);
+            assert_eq!(
+                parsed.project_configs.get("key2"),
+                Some(&serde_json::json!({"slug": "project2"}))
+            );
+        }
+    }
 }
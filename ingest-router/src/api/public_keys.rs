@@ -0,0 +1,373 @@
+use crate::api::quorum::QuorumPolicy;
+use crate::api::utils::{deserialize_body, normalize_headers, serialize_to_body};
+use crate::errors::IngestRouterError;
+use crate::handler::{CellId, ExecutionMode, Handler, SplitMetadata};
+use crate::locality::Cells;
+use async_trait::async_trait;
+use http::StatusCode;
+use http::response::Parts;
+use hyper::body::Bytes;
+use hyper::header::{CONTENT_TYPE, HeaderValue};
+use hyper::{Request, Response};
+use serde::{Deserialize, Serialize};
+use serde_json::Value as JsonValue;
+use shared::http::make_error_response;
+use std::collections::HashMap;
+
+/// Request format for the relay public keys endpoint.
+///
+/// # Example
+/// ```json
+/// {"relay_ids": ["relay1", "relay2"]}
+/// ```
+#[derive(Debug, Deserialize)]
+struct PublicKeysRequest {
+    #[serde(alias = "relayIds", default)]
+    relay_ids: Vec<String>,
+}
+
+/// Response format for the relay public keys endpoint. Relays unknown to a cell are null.
+///
+/// # Example
+/// ```json
+/// {
+///   "public_keys": {"relay1": "key1", "relay2": null},
+///   "relays": {"relay1": {"publicKey": "key1", "internal": false}, "relay2": null}
+/// }
+/// ```
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct PublicKeysResponse {
+    #[serde(default)]
+    public_keys: HashMap<String, Option<JsonValue>>,
+    #[serde(default)]
+    relays: HashMap<String, Option<JsonValue>>,
+    /// Other fields, taken from the first successful cell
+    #[serde(flatten)]
+    extra_fields: HashMap<String, JsonValue>,
+}
+
+impl PublicKeysResponse {
+    fn is_resolved(&self, relay_id: &str) -> bool {
+        [&self.public_keys, &self.relays]
+            .iter()
+            .any(|values| values.get(relay_id).is_some_and(Option::is_some))
+    }
+}
+
+struct PublicKeysMetadata {
+    relay_ids: Vec<String>,
+    quorum: usize,
+}
+
+/// Handler for the relay public keys endpoint.
+///
+/// The lookup is broadcast to every cell of the locality in parallel and the relays found
+/// by any cell are merged. It succeeds once the quorum policy is met: a quorum of cells
+/// responded and, unless disabled, every requested relay was found. Relays that no cell
+/// knows are only reported as unknown if every cell responded.
+pub struct PublicKeysHandler {
+    policy: QuorumPolicy,
+}
+
+impl PublicKeysHandler {
+    pub fn new(policy: QuorumPolicy) -> Self {
+        Self { policy }
+    }
+}
+
+/// Merges the values of a cell, keeping known relays over unknown ones.
+fn merge_values(
+    merged: &mut HashMap<String, Option<JsonValue>>,
+    values: HashMap<String, Option<JsonValue>>,
+) {
+    for (relay_id, value) in values {
+        let entry = merged.entry(relay_id).or_default();
+        if entry.is_none() {
+            *entry = value;
+        }
+    }
+}
+
+#[async_trait]
+impl Handler for PublicKeysHandler {
+    fn name(&self) -> &'static str {
+        "PublicKeys"
+    }
+
+    fn execution_mode(&self) -> ExecutionMode {
+        ExecutionMode::Parallel
+    }
+
+    async fn split_request(
+        &self,
+        request: Request<Bytes>,
+        cells: &Cells,
+    ) -> Result<(Vec<(CellId, Request<Bytes>)>, SplitMetadata), IngestRouterError> {
+        let (mut parts, body) = request.into_parts();
+        normalize_headers(&mut parts.headers, parts.version);
+
+        // Cells reject malformed requests themselves, the lookup then has no IDs to resolve
+        let relay_ids = deserialize_body::<PublicKeysRequest>(body.clone())
+            .map(|request| request.relay_ids)
+            .unwrap_or_default();
+
+        let cell_requests: Vec<_> = cells
+            .cell_list()
+            .map(|cell_id| {
+                let req = Request::from_parts(parts.clone(), body.clone());
+                (cell_id.clone(), req)
+            })
+            .collect();
+
+        let metadata = PublicKeysMetadata {
+            relay_ids,
+            quorum: self.policy.required(cell_requests.len()),
+        };
+
+        Ok((cell_requests, Box::new(metadata)))
+    }
+
+    async fn merge_responses(
+        &self,
+        responses: Vec<(CellId, Result<Response<Bytes>, IngestRouterError>)>,
+        metadata: SplitMetadata,
+    ) -> Response<Bytes> {
+        let cell_count = responses.len();
+        let metadata = metadata
+            .downcast::<PublicKeysMetadata>()
+            .map(|m| *m)
+            .unwrap_or(PublicKeysMetadata {
+                relay_ids: Vec::new(),
+                quorum: self.policy.required(cell_count),
+            });
+
+        let mut merged = PublicKeysResponse::default();
+        let mut succeeded = 0;
+        // Parts is populated from the first successful response
+        let mut parts: Option<Parts> = None;
+
+        for (cell_id, result) in responses {
+            let response = match result {
+                Ok(response) if response.status().is_success() => response,
+                Ok(response) => {
+                    tracing::warn!(
+                        cell_id = %cell_id,
+                        status = %response.status(),
+                        "Public keys lookup failed with non-success status"
+                    );
+                    continue;
+                }
+                Err(e) => {
+                    tracing::warn!(
+                        cell_id = %cell_id,
+                        error = %e,
+                        "Public keys request failed"
+                    );
+                    continue;
+                }
+            };
+
+            let (p, body) = response.into_parts();
+            let Ok(parsed) = deserialize_body::<PublicKeysResponse>(body) else {
+                tracing::error!(
+                    cell_id = %cell_id,
+                    "Failed to deserialize public keys response from cell"
+                );
+                continue;
+            };
+
+            succeeded += 1;
+            parts.get_or_insert(p);
+            merge_values(&mut merged.public_keys, parsed.public_keys);
+            merge_values(&mut merged.relays, parsed.relays);
+            for (key, value) in parsed.extra_fields {
+                merged.extra_fields.entry(key).or_insert(value);
+            }
+        }
+
+        // A relay no responding cell knows may be known to a failed cell
+        let unresolved = if succeeded < cell_count {
+            metadata
+                .relay_ids
+                .iter()
+                .filter(|relay_id| !merged.is_resolved(relay_id))
+                .count()
+        } else {
+            0
+        };
+
+        if !self
+            .policy
+            .is_met(self.name(), metadata.quorum, succeeded, unresolved)
+        {
+            return make_error_response(StatusCode::SERVICE_UNAVAILABLE);
+        }
+
+        match (parts, serialize_to_body(&merged)) {
+            (Some(mut p), Ok(body)) => {
+                normalize_headers(&mut p.headers, p.version);
+                p.headers
+                    .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+                Response::from_parts(p, body)
+            }
+            _ => make_error_response(StatusCode::SERVICE_UNAVAILABLE),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutils::create_test_cells;
+
+    fn request() -> Request<Bytes> {
+        Request::builder()
+            .method("POST")
+            .uri("/api/0/relays/publickeys/")
+            .body(Bytes::from(r#"{"relay_ids":["relay1","relay2"]}"#))
+            .unwrap()
+    }
+
+    fn response(body: serde_json::Value) -> Result<Response<Bytes>, IngestRouterError> {
+        Ok(Response::builder()
+            .status(StatusCode::OK)
+            .body(Bytes::from(body.to_string()))
+            .unwrap())
+    }
+
+    fn timeout(cell_id: &str) -> Result<Response<Bytes>, IngestRouterError> {
+        Err(IngestRouterError::UpstreamTimeout(cell_id.to_string()))
+    }
+
+    fn relay1_only() -> Result<Response<Bytes>, IngestRouterError> {
+        response(serde_json::json!({
+            "public_keys": {"relay1": "key1", "relay2": null},
+            "relays": {"relay1": {"publicKey": "key1", "internal": false}, "relay2": null},
+        }))
+    }
+
+    fn relay2_only() -> Result<Response<Bytes>, IngestRouterError> {
+        response(serde_json::json!({
+            "public_keys": {"relay1": null, "relay2": "key2"},
+            "relays": {"relay1": null, "relay2": {"publicKey": "key2", "internal": true}},
+        }))
+    }
+
+    async fn merge(
+        handler: &PublicKeysHandler,
+        responses: Vec<(&str, Result<Response<Bytes>, IngestRouterError>)>,
+    ) -> Response<Bytes> {
+        let (_, metadata) = handler
+            .split_request(request(), &create_test_cells(&["us1", "us2", "us3"]))
+            .await
+            .unwrap();
+        let responses = responses
+            .into_iter()
+            .map(|(cell_id, result)| (cell_id.to_string(), result))
+            .collect();
+        handler.merge_responses(responses, metadata).await
+    }
+
+    #[tokio::test]
+    async fn test_split_request_broadcasts_to_all_cells() {
+        let handler = PublicKeysHandler::new(QuorumPolicy::new(None));
+        let (cell_requests, metadata) = handler
+            .split_request(request(), &create_test_cells(&["us1", "us2", "us3"]))
+            .await
+            .unwrap();
+
+        let cell_ids: Vec<_> = cell_requests.iter().map(|(id, _)| id.as_str()).collect();
+        assert_eq!(cell_ids, vec!["us1", "us2", "us3"]);
+
+        let metadata = metadata.downcast::<PublicKeysMetadata>().unwrap();
+        assert_eq!(metadata.relay_ids, vec!["relay1", "relay2"]);
+        assert_eq!(metadata.quorum, 2);
+    }
+
+    #[tokio::test]
+    async fn test_merge_responses() {
+        let handler = PublicKeysHandler::new(QuorumPolicy::new(None).require_all_resolved(true));
+
+        // Relays found by different cells are merged
+        let merged = merge(
+            &handler,
+            vec![
+                ("us1", relay1_only()),
+                ("us2", timeout("us2")),
+                ("us3", relay2_only()),
+            ],
+        )
+        .await;
+        assert_eq!(merged.status(), StatusCode::OK);
+        let body: serde_json::Value = serde_json::from_slice(merged.body()).unwrap();
+        assert_eq!(
+            body,
+            serde_json::json!({
+                "public_keys": {"relay1": "key1", "relay2": "key2"},
+                "relays": {
+                    "relay1": {"publicKey": "key1", "internal": false},
+                    "relay2": {"publicKey": "key2", "internal": true},
+                },
+            })
+        );
+
+        // A relay unknown to every cell is not an error
+        let merged = merge(
+            &handler,
+            vec![
+                ("us1", relay1_only()),
+                ("us2", relay1_only()),
+                ("us3", relay1_only()),
+            ],
+        )
+        .await;
+        assert_eq!(merged.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_merge_responses_quorum() {
+        let handler = PublicKeysHandler::new(QuorumPolicy::new(None).require_all_resolved(true));
+
+        // The quorum is reached, but relay2 may be known to the failed cell
+        let merged = merge(
+            &handler,
+            vec![
+                ("us1", relay1_only()),
+                ("us2", timeout("us2")),
+                ("us3", relay1_only()),
+            ],
+        )
+        .await;
+        assert_eq!(merged.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+        // All relays are resolved, but the quorum is not reached
+        let merged = merge(
+            &handler,
+            vec![
+                (
+                    "us1",
+                    response(
+                        serde_json::json!({"public_keys": {"relay1": "key1", "relay2": "key2"}}),
+                    ),
+                ),
+                ("us2", timeout("us2")),
+                ("us3", timeout("us3")),
+            ],
+        )
+        .await;
+        assert_eq!(merged.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+        // Unresolved relays are allowed when not required
+        let handler = PublicKeysHandler::new(QuorumPolicy::new(None));
+        let merged = merge(
+            &handler,
+            vec![
+                ("us1", relay1_only()),
+                ("us2", timeout("us2")),
+                ("us3", relay1_only()),
+            ],
+        )
+        .await;
+        assert_eq!(merged.status(), StatusCode::OK);
+    }
+}
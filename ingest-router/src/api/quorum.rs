@@ -0,0 +1,96 @@
+//! Quorum policy shared by the handlers broadcasting requests to every cell of a locality.
+//!
+//! A broadcast succeeds once enough cells responded successfully. Handlers that look up
+//! IDs can additionally require every requested ID to be resolved by one of them, so that
+//! an ID known only to a failed cell is not reported as unknown.
+use crate::metrics_defs::QUORUM_FAILURES;
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct QuorumPolicy {
+    quorum: Option<usize>,
+    require_all_resolved: bool,
+}
+
+impl QuorumPolicy {
+    /// Creates a policy requiring `quorum` successful cells, or a majority of cells if not set.
+    pub fn new(quorum: Option<usize>) -> Self {
+        Self {
+            quorum,
+            require_all_resolved: false,
+        }
+    }
+
+    /// Also require every requested ID to be resolved
+    pub fn require_all_resolved(mut self, require_all_resolved: bool) -> Self {
+        self.require_all_resolved = require_all_resolved;
+        self
+    }
+
+    /// Number of successful cells required out of `cell_count`
+    pub fn required(&self, cell_count: usize) -> usize {
+        match self.quorum {
+            Some(quorum) => quorum.min(cell_count),
+            None => cell_count / 2 + 1,
+        }
+    }
+
+    /// Whether a merge of `succeeded` successful cells with `unresolved` unresolved IDs is
+    /// successful. Failures are logged and counted in `merge.quorum_failures`.
+    pub fn is_met(
+        &self,
+        handler: &str,
+        required: usize,
+        succeeded: usize,
+        unresolved: usize,
+    ) -> bool {
+        let reason = if succeeded < required {
+            "quorum"
+        } else if self.require_all_resolved && unresolved > 0 {
+            "unresolved"
+        } else {
+            return true;
+        };
+
+        tracing::warn!(
+            handler,
+            succeeded,
+            required,
+            unresolved,
+            reason,
+            "Broadcast merge did not reach quorum"
+        );
+        metrics::counter!(
+            QUORUM_FAILURES.name,
+            "handler" => handler.to_string(),
+            "reason" => reason,
+        )
+        .increment(1);
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_required() {
+        // Defaults to a majority
+        assert_eq!(QuorumPolicy::new(None).required(3), 2);
+        assert_eq!(QuorumPolicy::new(None).required(4), 3);
+        // Capped at the number of cells
+        assert_eq!(QuorumPolicy::new(Some(2)).required(3), 2);
+        assert_eq!(QuorumPolicy::new(Some(5)).required(3), 3);
+    }
+
+    #[test]
+    fn test_is_met() {
+        let policy = QuorumPolicy::new(None);
+        assert!(policy.is_met("test", 2, 2, 1));
+        assert!(!policy.is_met("test", 2, 1, 0));
+
+        let policy = policy.require_all_resolved(true);
+        assert!(policy.is_met("test", 2, 3, 0));
+        assert!(!policy.is_met("test", 2, 3, 1));
+    }
+}
@@ -0,0 +1,237 @@
+use crate::api::quorum::QuorumPolicy;
+use crate::api::utils::normalize_headers;
+use crate::errors::IngestRouterError;
+use crate::handler::{CellId, ExecutionMode, Handler, ResponseReceivedAt, SplitMetadata};
+use crate::locality::Cells;
+use crate::metrics_defs::HEARTBEAT_ACK_LAG;
+use async_trait::async_trait;
+use http::StatusCode;
+use hyper::body::Bytes;
+use hyper::{Request, Response};
+use shared::http::make_error_response;
+use std::time::Instant;
+
+/// Handler for relay heartbeat and liveness endpoints.
+///
+/// Unlike `AnyCellHandler`, the heartbeat is broadcast to every cell of the locality in
+/// parallel so that all cells keep a consistent view of which relays are alive. The request
+/// succeeds once a quorum of cells has acknowledged it, so a single slow or unavailable cell
+/// does not fail the heartbeat.
+///
+/// The time each cell took to acknowledge the heartbeat is recorded as `heartbeat.ack_lag`.
+pub struct RelayHeartbeatHandler {
+    policy: QuorumPolicy,
+}
+
+struct HeartbeatMetadata {
+    sent_at: Instant,
+    quorum: usize,
+}
+
+impl RelayHeartbeatHandler {
+    /// Creates a handler requiring `quorum` acknowledgments, or a majority of cells if not set.
+    pub fn new(quorum: Option<usize>) -> Self {
+        Self {
+            policy: QuorumPolicy::new(quorum),
+        }
+    }
+}
+
+#[async_trait]
+impl Handler for RelayHeartbeatHandler {
+    fn name(&self) -> &'static str {
+        "RelayHeartbeat"
+    }
+
+    fn execution_mode(&self) -> ExecutionMode {
+        ExecutionMode::Parallel
+    }
+
+    async fn split_request(
+        &self,
+        request: Request<Bytes>,
+        cells: &Cells,
+    ) -> Result<(Vec<(CellId, Request<Bytes>)>, SplitMetadata), IngestRouterError> {
+        let (mut parts, body) = request.into_parts();
+        normalize_headers(&mut parts.headers, parts.version);
+
+        let cell_requests: Vec<_> = cells
+            .cell_list()
+            .map(|cell_id| {
+                let req = Request::from_parts(parts.clone(), body.clone());
+                (cell_id.clone(), req)
+            })
+            .collect();
+
+        let metadata = HeartbeatMetadata {
+            sent_at: Instant::now(),
+            quorum: self.policy.required(cell_requests.len()),
+        };
+
+        Ok((cell_requests, Box::new(metadata)))
+    }
+
+    async fn merge_responses(
+        &self,
+        responses: Vec<(CellId, Result<Response<Bytes>, IngestRouterError>)>,
+        metadata: SplitMetadata,
+    ) -> Response<Bytes> {
+        let metadata = metadata
+            .downcast::<HeartbeatMetadata>()
+            .map(|m| *m)
+            .unwrap_or(HeartbeatMetadata {
+                sent_at: Instant::now(),
+                quorum: self.policy.required(responses.len()),
+            });
+
+        let mut acknowledged = 0;
+        let mut first_success = None;
+
+        for (cell_id, result) in responses {
+            match result {
+                Ok(response) if response.status().is_success() => {
+                    acknowledged += 1;
+
+                    if let Some(ResponseReceivedAt(received_at)) =
+                        response.extensions().get::<ResponseReceivedAt>()
+                    {
+                        metrics::histogram!(
+                            HEARTBEAT_ACK_LAG.name,
+                            "cell_id" => cell_id.clone(),
+                        )
+                        .record(
+                            received_at
+                                .saturating_duration_since(metadata.sent_at)
+                                .as_secs_f64(),
+                        );
+                    }
+
+                    first_success.get_or_insert(response);
+                }
+                Ok(response) => {
+                    tracing::warn!(
+                        cell_id = %cell_id,
+                        status = %response.status(),
+                        "Relay heartbeat failed with non-success status"
+                    );
+                }
+                Err(e) => {
+                    tracing::warn!(
+                        cell_id = %cell_id,
+                        error = %e,
+                        "Relay heartbeat request failed"
+                    );
+                }
+            }
+        }
+
+        let quorum_met = self
+            .policy
+            .is_met(self.name(), metadata.quorum, acknowledged, 0);
+        match first_success {
+            Some(response) if quorum_met => {
+                let (mut parts, body) = response.into_parts();
+                normalize_headers(&mut parts.headers, parts.version);
+                Response::from_parts(parts, body)
+            }
+            _ => make_error_response(StatusCode::SERVICE_UNAVAILABLE),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutils::create_test_cells;
+
+    fn ok_response() -> Result<Response<Bytes>, IngestRouterError> {
+        let mut response = Response::builder()
+            .status(StatusCode::OK)
+            .body(Bytes::from(r#"{"is_healthy":true}"#))
+            .unwrap();
+        response
+            .extensions_mut()
+            .insert(ResponseReceivedAt(Instant::now()));
+        Ok(response)
+    }
+
+    fn timeout(cell_id: &str) -> Result<Response<Bytes>, IngestRouterError> {
+        Err(IngestRouterError::UpstreamTimeout(cell_id.to_string()))
+    }
+
+    #[tokio::test]
+    async fn test_split_request_broadcasts_to_all_cells() {
+        let handler = RelayHeartbeatHandler::new(None);
+        let cells = create_test_cells(&["us1", "us2", "us3"]);
+
+        let request = Request::builder()
+            .method("GET")
+            .uri("/api/0/relays/live/")
+            .body(Bytes::new())
+            .unwrap();
+
+        let (cell_requests, metadata) = handler.split_request(request, &cells).await.unwrap();
+
+        let cell_ids: Vec<_> = cell_requests.iter().map(|(id, _)| id.as_str()).collect();
+        assert_eq!(cell_ids, vec!["us1", "us2", "us3"]);
+
+        // Default quorum is a majority of cells
+        let metadata = metadata.downcast::<HeartbeatMetadata>().unwrap();
+        assert_eq!(metadata.quorum, 2);
+    }
+
+    #[tokio::test]
+    async fn test_merge_responses_quorum() {
+        let handler = RelayHeartbeatHandler::new(None);
+        let cells = create_test_cells(&["us1", "us2", "us3"]);
+        let request = || {
+            Request::builder()
+                .uri("/api/0/relays/live/")
+                .body(Bytes::new())
+                .unwrap()
+        };
+
+        // 2 of 3 cells acknowledged
+        let (_, metadata) = handler.split_request(request(), &cells).await.unwrap();
+        let responses = vec![
+            ("us1".to_string(), ok_response()),
+            ("us2".to_string(), timeout("us2")),
+            ("us3".to_string(), ok_response()),
+        ];
+        let merged = handler.merge_responses(responses, metadata).await;
+        assert_eq!(merged.status(), StatusCode::OK);
+
+        // 1 of 3 cells acknowledged
+        let (_, metadata) = handler.split_request(request(), &cells).await.unwrap();
+        let responses = vec![
+            ("us1".to_string(), ok_response()),
+            ("us2".to_string(), timeout("us2")),
+            ("us3".to_string(), timeout("us3")),
+        ];
+        let merged = handler.merge_responses(responses, metadata).await;
+        assert_eq!(merged.status(), StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[tokio::test]
+    async fn test_configured_quorum() {
+        let cells = create_test_cells(&["us1", "us2", "us3"]);
+        let request = Request::builder()
+            .uri("/api/0/relays/live/")
+            .body(Bytes::new())
+            .unwrap();
+
+        let handler = RelayHeartbeatHandler::new(Some(1));
+        let (_, metadata) = handler.split_request(request, &cells).await.unwrap();
+        let responses = vec![
+            ("us1".to_string(), timeout("us1")),
+            ("us2".to_string(), timeout("us2")),
+            ("us3".to_string(), ok_response()),
+        ];
+        let merged = handler.merge_responses(responses, metadata).await;
+        assert_eq!(merged.status(), StatusCode::OK);
+
+        // Quorum larger than the locality requires every cell
+        let handler = RelayHeartbeatHandler::new(Some(5));
+        assert_eq!(handler.policy.required(3), 3);
+    }
+}
@@ -0,0 +1,341 @@
+//! Routing of requests that belong to a single project, such as envelopes and uploads.
+//!
+//! Such requests are forwarded unmodified to the cell of their project key, which handlers
+//! find in the request with a list of key extractors, see `key_extractor`. Requests that
+//! cannot be routed are answered without reaching a cell:
+//! - 400 if the request has no key
+//! - 403 if the key has no cell in the route's locality, the key is unknown or deleted
+//! - 503 if the locator cannot tell, for example while it is not synced yet
+//!
+//! The response of the cell is passed on as is, including its rejections.
+//!
+//! Routes of the `proxy_to_cell` handler take the key from the request head only, and pass
+//! requests through to the cell's relay or sentry URL without buffering them, see
+//! `ExecutionMode::PassThrough`. They are rejected the same way. Envelopes are passed
+//! through to the relay too, with only the start of their body read for its key, see
+//! `BodyPrefix`.
+use crate::api::key_extractor::{self, KeyExtractor};
+use crate::api::utils::normalize_headers;
+use crate::config::{CellTarget, KeySource};
+use crate::errors::IngestRouterError;
+use crate::handler::{BodyPrefix, CellId, ExecutionMode, Handler, SplitMetadata};
+use crate::locality::Cells;
+use async_trait::async_trait;
+use http::request::Parts;
+use http::{HeaderMap, StatusCode, Uri};
+use hyper::body::Bytes;
+use hyper::{Request, Response};
+use locator::client::{ClientError, Locator};
+use locator::locator::LocatorError;
+use locator::project_key;
+use shared::http::make_error_response;
+
+/// Why a request could not be routed to a cell, answered in `merge_responses`
+#[derive(Clone, Copy)]
+enum Unrouted {
+    MissingKey,
+    UnknownKey,
+    LocatorError,
+}
+
+impl Unrouted {
+    fn status(self) -> StatusCode {
+        match self {
+            Unrouted::MissingKey => StatusCode::BAD_REQUEST,
+            Unrouted::UnknownKey => StatusCode::FORBIDDEN,
+            Unrouted::LocatorError => StatusCode::SERVICE_UNAVAILABLE,
+        }
+    }
+}
+
+/// Handler for requests that belong to a single project, which are forwarded to the cell
+/// of the first key found by its extractors.
+///
+/// # Used for:
+///
+/// - Envelopes and uploads, see `envelope` and `minidump`
+/// - Routes of the `single_project` handler, with the extractors of their `key_from`
+/// - Routes of the `proxy_to_cell` handler, which are passed through to the cell
+pub struct SingleProjectHandler {
+    name: &'static str,
+    // Describes the requests in logs, e.g. "envelope"
+    kind: &'static str,
+    locator: Locator,
+    extractors: Vec<Box<dyn KeyExtractor>>,
+    // URL of the cell requests are passed through to, None if they are buffered
+    pass_through: Option<CellTarget>,
+    // Longest start of the body read for the key of passed through requests
+    body_prefix_limit: Option<usize>,
+}
+
+impl SingleProjectHandler {
+    pub fn new(
+        name: &'static str,
+        kind: &'static str,
+        locator: Locator,
+        extractors: Vec<Box<dyn KeyExtractor>>,
+    ) -> Self {
+        Self {
+            name,
+            kind,
+            locator,
+            extractors,
+            pass_through: None,
+            body_prefix_limit: None,
+        }
+    }
+
+    /// Passes requests through to the relay of the cell instead of buffering them. The
+    /// extractors only see the start of the body, up to its first newline or past
+    /// `body_prefix_limit` bytes.
+    pub fn pass_through_to_relay(self, body_prefix_limit: usize) -> Self {
+        Self {
+            pass_through: Some(CellTarget::Relay),
+            body_prefix_limit: Some(body_prefix_limit),
+            ..self
+        }
+    }
+
+    /// Handler of a `single_project` route
+    pub fn from_config(locator: Locator, key_from: &[KeySource]) -> Self {
+        let extractors = key_from.iter().map(key_extractor::from_config).collect();
+        Self::new("SingleProjectHandler", "request", locator, extractors)
+    }
+
+    /// Handler of a `proxy_to_cell` route, passing requests through to the `target` URL
+    /// of the cell
+    pub fn proxy_to_cell(locator: Locator, key_from: &[KeySource], target: CellTarget) -> Self {
+        let extractors = key_from.iter().map(key_extractor::from_config).collect();
+        Self {
+            pass_through: Some(target),
+            ..Self::new(
+                "ProxyToCellHandler",
+                "pass-through request",
+                locator,
+                extractors,
+            )
+        }
+    }
+
+    /// The normalized public key of the request, from its key or DSN.
+    fn project_key(&self, uri: &Uri, headers: &HeaderMap, body: &[u8]) -> Option<String> {
+        let key = key_extractor::extract_key(&self.extractors, uri, headers, body)?;
+        Some(project_key::normalize(&key).into_owned())
+    }
+
+    /// The cell of the key in the route's locality.
+    async fn find_cell(&self, key: Option<String>, cells: &Cells) -> Result<CellId, Unrouted> {
+        let Some(key) = key else {
+            tracing::debug!(kind = self.kind, "Request without a project key");
+            return Err(Unrouted::MissingKey);
+        };
+
+        match self.locator.lookup(&key, Some(cells.locality())).await {
+            Ok(cell_id) => Ok(cell_id),
+            Err(ClientError::LocatorError(
+                LocatorError::NoCell
+                | LocatorError::Deleted
+                | LocatorError::LocalityMismatch { .. },
+            )) => {
+                tracing::debug!(kind = self.kind, public_key = %key, "Key without a cell");
+                Err(Unrouted::UnknownKey)
+            }
+            Err(e) => {
+                tracing::error!(
+                    kind = self.kind,
+                    public_key = %key,
+                    error = ?e,
+                    "Failed to route request"
+                );
+                Err(Unrouted::LocatorError)
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl Handler for SingleProjectHandler {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn execution_mode(&self) -> ExecutionMode {
+        match self.pass_through {
+            Some(target) => ExecutionMode::PassThrough(target),
+            None => ExecutionMode::Failover,
+        }
+    }
+
+    fn audit_keys(&self, request: &Request<Bytes>) -> Vec<String> {
+        self.project_key(request.uri(), request.headers(), request.body())
+            .into_iter()
+            .collect()
+    }
+
+    fn body_prefix_limit(&self) -> Option<usize> {
+        self.body_prefix_limit
+    }
+
+    async fn route_head(&self, parts: &Parts, cells: &Cells) -> Result<CellId, StatusCode> {
+        let prefix = parts
+            .extensions
+            .get::<BodyPrefix>()
+            .map(|BodyPrefix(prefix)| prefix.as_ref())
+            .unwrap_or_default();
+        let key = self.project_key(&parts.uri, &parts.headers, prefix);
+        self.find_cell(key, cells).await.map_err(Unrouted::status)
+    }
+
+    /// Routes the request to the cell of its key. Without a cell, no request is sent and
+    /// the metadata holds the reason.
+    async fn split_request(
+        &self,
+        request: Request<Bytes>,
+        cells: &Cells,
+    ) -> Result<(Vec<(CellId, Request<Bytes>)>, SplitMetadata), IngestRouterError> {
+        let key = self.project_key(request.uri(), request.headers(), request.body());
+        let cell_id = match self.find_cell(key, cells).await {
+            Ok(cell_id) => cell_id,
+            Err(unrouted) => return Ok((Vec::new(), Box::new(unrouted))),
+        };
+
+        let (mut parts, body) = request.into_parts();
+        normalize_headers(&mut parts.headers, parts.version);
+        let request = Request::from_parts(parts, body);
+        Ok((vec![(cell_id, request)], Box::new(())))
+    }
+
+    /// The response of the cell, or the rejection of a request that was not routed.
+    async fn merge_responses(
+        &self,
+        responses: Vec<(CellId, Result<Response<Bytes>, IngestRouterError>)>,
+        metadata: SplitMetadata,
+    ) -> Response<Bytes> {
+        if let Ok(unrouted) = metadata.downcast::<Unrouted>() {
+            return make_error_response(unrouted.status());
+        }
+
+        for (cell_id, result) in responses {
+            match result {
+                Ok(response) => {
+                    let (mut parts, body) = response.into_parts();
+                    normalize_headers(&mut parts.headers, parts.version);
+                    return Response::from_parts(parts, body);
+                }
+                Err(e) => {
+                    tracing::warn!(
+                        kind = self.kind,
+                        cell_id = %cell_id,
+                        error = %e,
+                        "Request to cell failed"
+                    );
+                }
+            }
+        }
+
+        make_error_response(StatusCode::SERVICE_UNAVAILABLE)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutils::create_test_cells;
+    use crate::testutils::create_test_locator;
+    use std::collections::HashMap;
+
+    /// Handler taking the key from the `sentry_key` query parameter
+    fn query_handler(locator: Locator) -> SingleProjectHandler {
+        let key_from = [KeySource::Query {
+            name: "sentry_key".into(),
+        }];
+        SingleProjectHandler::from_config(locator, &key_from)
+    }
+
+    fn request(key: Option<&str>, body: Bytes) -> Request<Bytes> {
+        let uri = match key {
+            Some(key) => format!("/api/1/envelope/?sentry_key={key}"),
+            None => "/api/1/envelope/".to_string(),
+        };
+        Request::post(uri).body(body).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_split_request() {
+        let key = "a".repeat(32);
+        let locator = create_test_locator(HashMap::from([
+            (key.clone(), "us2".into()),
+            ("c".repeat(32), "de1".into()),
+        ]))
+        .await;
+        let handler = query_handler(locator);
+        let cells = create_test_cells(&["us1", "us2"]);
+
+        // Keys are normalized, the body is forwarded unmodified
+        let body = Bytes::from_static(b"\x00body");
+        let dsn = format!("https://{}@o1.ingest.sentry.io/1", key.to_uppercase());
+        let query = url::form_urlencoded::Serializer::new(String::new())
+            .append_pair("sentry_key", &dsn)
+            .finish();
+        let req = Request::post(format!("/api/1/envelope/?{query}"))
+            .body(body.clone())
+            .unwrap();
+        let (requests, _) = handler.split_request(req, &cells).await.unwrap();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].0, "us2");
+        assert_eq!(requests[0].1.body(), &body);
+        assert_eq!(requests[0].1.uri().path(), "/api/1/envelope/");
+
+        for (key, status) in [
+            (None, StatusCode::BAD_REQUEST),
+            (Some("c".repeat(32)), StatusCode::FORBIDDEN),
+            // Unknown keys may not be synced yet, the test locator only loaded its backup
+            (Some("f".repeat(32)), StatusCode::SERVICE_UNAVAILABLE),
+        ] {
+            let request = request(key.as_deref(), body.clone());
+            let (requests, metadata) = handler.split_request(request, &cells).await.unwrap();
+            assert!(requests.is_empty());
+            let response = handler.merge_responses(Vec::new(), metadata).await;
+            assert_eq!(response.status(), status);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_audit_keys() {
+        let locator = create_test_locator(HashMap::new()).await;
+        let handler = query_handler(locator);
+        let key = "a".repeat(32);
+        assert_eq!(
+            handler.audit_keys(&request(Some(&key.to_uppercase()), Bytes::new())),
+            vec![key]
+        );
+        assert!(handler.audit_keys(&request(None, Bytes::new())).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_merge_responses() {
+        let locator = create_test_locator(HashMap::new()).await;
+        let handler = query_handler(locator);
+
+        // Rejections of the cell are forwarded
+        let rejected = Response::builder()
+            .status(StatusCode::PAYLOAD_TOO_LARGE)
+            .body(Bytes::new())
+            .unwrap();
+        let merged = handler
+            .merge_responses(vec![("us1".into(), Ok(rejected))], Box::new(()))
+            .await;
+        assert_eq!(merged.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+        let merged = handler
+            .merge_responses(
+                vec![(
+                    "us1".into(),
+                    Err(IngestRouterError::UpstreamTimeout("us1".into())),
+                )],
+                Box::new(()),
+            )
+            .await;
+        assert_eq!(merged.status(), StatusCode::SERVICE_UNAVAILABLE);
+    }
+}
@@ -0,0 +1,339 @@
+//! Audit trail of routing decisions.
+//!
+//! When enabled, one JSON line is written per request recording the matched handler and
+//! locality, the keys involved in the request, the cells the request was sent to and the
+//! outcome. This allows proving where customer data was routed without logging the keys
+//! themselves: keys are stored as SHA-256 hashes.
+//!
+//! Records are written by a dedicated thread, so that a slow disk or stdout never blocks
+//! the request path. Records are dropped when too many are waiting to be written.
+use crate::config::AuditLog;
+use crate::handler::CellId;
+use crate::metrics_defs::AUDIT_DROPPED;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+
+// Most records waiting to be written before new ones are dropped
+const QUEUE_CAPACITY: usize = 10_000;
+
+/// How the request was handled
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    /// Sent to the upstream cells, the status is the merged response status
+    Routed,
+    /// No route matched the request
+    NoRoute,
+    /// Rejected because the route does not accept the request's content type
+    UnsupportedContentType,
+    /// Rejected because the memory budget for buffered bodies was exceeded
+    BudgetExceeded,
+    /// Rejected because the route was at its limit of concurrent requests
+    RouteBudgetExceeded,
+    /// The request body could not be read
+    InvalidBody,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AuditRecord<'a> {
+    pub timestamp: DateTime<Utc>,
+    pub method: &'a str,
+    pub path: &'a str,
+    pub handler: &'a str,
+    pub locality: Option<&'a str>,
+    /// SHA-256 hashes of the keys involved in the request
+    pub keys: Vec<String>,
+    pub cells: &'a [CellId],
+    pub status: u16,
+    pub outcome: Outcome,
+}
+
+/// Hashes a key so that it can be correlated across records without being stored.
+pub fn hash_key(key: &str) -> String {
+    Sha256::digest(key.as_bytes())
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+#[derive(Clone)]
+pub struct AuditLogger {
+    queue: SyncSender<Message>,
+}
+
+enum Message {
+    Line(Vec<u8>),
+    /// Answered once every record queued before it is written
+    Flush(mpsc::Sender<()>),
+}
+
+enum Sink {
+    Stdout,
+    File(RotatingFile),
+}
+
+impl Sink {
+    fn write_line(&mut self, line: &[u8]) -> io::Result<()> {
+        match self {
+            Sink::Stdout => io::stdout().lock().write_all(line),
+            Sink::File(file) => file.write_line(line),
+        }
+    }
+}
+
+impl AuditLogger {
+    pub fn new(config: AuditLog) -> io::Result<Self> {
+        Self::with_capacity(config, QUEUE_CAPACITY)
+    }
+
+    fn with_capacity(config: AuditLog, capacity: usize) -> io::Result<Self> {
+        let sink = match config {
+            AuditLog::Stdout => Sink::Stdout,
+            AuditLog::File {
+                path,
+                max_file_bytes,
+                max_files,
+            } => Sink::File(RotatingFile::open(path.into(), max_file_bytes, max_files)?),
+        };
+
+        let (queue, received) = mpsc::sync_channel(capacity);
+        std::thread::Builder::new()
+            .name("audit-log".into())
+            .spawn(move || write_records(sink, received))?;
+
+        Ok(Self { queue })
+    }
+
+    /// Queues the record to be written as a single JSON line. Failures are logged and
+    /// otherwise ignored, so that a broken audit sink never fails the request.
+    pub fn log(&self, record: &AuditRecord) {
+        let mut line = match serde_json::to_vec(record) {
+            Ok(line) => line,
+            Err(e) => {
+                tracing::error!(error = %e, "Failed to serialize audit record");
+                return;
+            }
+        };
+        line.push(b'\n');
+
+        match self.queue.try_send(Message::Line(line)) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => {
+                metrics::counter!(AUDIT_DROPPED.name).increment(1);
+            }
+            Err(TrySendError::Disconnected(_)) => {
+                tracing::error!("Audit log writer stopped, dropping record");
+            }
+        }
+    }
+
+    /// Blocks until every record logged so far is written.
+    pub fn flush(&self) {
+        let (written_tx, written) = mpsc::channel();
+        if self.queue.send(Message::Flush(written_tx)).is_ok() {
+            let _ = written.recv();
+        }
+    }
+}
+
+/// Writes the queued records until every logger is dropped.
+fn write_records(mut sink: Sink, received: Receiver<Message>) {
+    for message in received {
+        match message {
+            Message::Line(line) => {
+                if let Err(e) = sink.write_line(&line) {
+                    tracing::error!(error = %e, "Failed to write audit record");
+                }
+            }
+            Message::Flush(written) => {
+                let _ = written.send(());
+            }
+        }
+    }
+}
+
+/// File that is rotated once it reaches `max_file_bytes`. Rotated files are kept as
+/// `<path>.1` (most recent) up to `<path>.<max_files>`.
+struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    written: u64,
+    max_file_bytes: u64,
+    max_files: usize,
+}
+
+impl RotatingFile {
+    fn open(path: PathBuf, max_file_bytes: u64, max_files: usize) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let written = file.metadata()?.len();
+
+        Ok(Self {
+            path,
+            file,
+            written,
+            max_file_bytes,
+            max_files,
+        })
+    }
+
+    fn write_line(&mut self, line: &[u8]) -> io::Result<()> {
+        if self.written > 0 && self.written + line.len() as u64 > self.max_file_bytes {
+            self.rotate()?;
+        }
+
+        self.file.write_all(line)?;
+        self.written += line.len() as u64;
+        Ok(())
+    }
+
+    fn rotated_path(&self, index: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{index}"));
+        path.into()
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+
+        if self.max_files == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            // The oldest file is overwritten
+            for index in (1..self.max_files).rev() {
+                let from = self.rotated_path(index);
+                if from.exists() {
+                    fs::rename(&from, self.rotated_path(index + 1))?;
+                }
+            }
+            fs::rename(&self.path, self.rotated_path(1))?;
+        }
+
+        self.file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        self.written = 0;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(cells: &[CellId]) -> AuditRecord<'_> {
+        AuditRecord {
+            timestamp: DateTime::from_timestamp(1_700_000_000, 0).unwrap(),
+            method: "POST",
+            path: "/api/0/relays/projectconfigs/",
+            handler: "ProjectConfigsHandler",
+            locality: Some("us"),
+            keys: vec![hash_key("aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa")],
+            cells,
+            status: 200,
+            outcome: Outcome::Routed,
+        }
+    }
+
+    #[test]
+    fn test_hash_key() {
+        assert_eq!(
+            hash_key("abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+
+    #[test]
+    fn test_file_sink() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("audit.jsonl");
+
+        let logger = AuditLogger::new(AuditLog::File {
+            path: path.to_str().unwrap().to_string(),
+            max_file_bytes: 1024 * 1024,
+            max_files: 2,
+        })
+        .unwrap();
+
+        let cells = vec!["us1".to_string()];
+        logger.log(&record(&cells));
+        logger.flush();
+
+        let contents = fs::read_to_string(&path).unwrap();
+        let lines: Vec<_> = contents.lines().collect();
+        assert_eq!(lines.len(), 1);
+
+        let value: serde_json::Value = serde_json::from_str(lines[0]).unwrap();
+        assert_eq!(value["handler"], "ProjectConfigsHandler");
+        assert_eq!(value["locality"], "us");
+        assert_eq!(value["cells"], serde_json::json!(["us1"]));
+        assert_eq!(value["status"], 200);
+        assert_eq!(value["outcome"], "routed");
+        assert_eq!(
+            value["keys"][0],
+            hash_key("aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa")
+        );
+        assert_eq!(value["timestamp"], "2023-11-14T22:13:20Z");
+    }
+
+    #[test]
+    fn test_rotation() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("audit.jsonl");
+
+        // Every record exceeds the limit, so each write after the first rotates
+        let logger = AuditLogger::new(AuditLog::File {
+            path: path.to_str().unwrap().to_string(),
+            max_file_bytes: 10,
+            max_files: 2,
+        })
+        .unwrap();
+
+        let cells = vec![];
+        for _ in 0..4 {
+            logger.log(&record(&cells));
+        }
+        logger.flush();
+
+        let line_count = |path: PathBuf| fs::read_to_string(path).unwrap().lines().count();
+        assert_eq!(line_count(path.clone()), 1);
+        assert_eq!(line_count(dir.path().join("audit.jsonl.1")), 1);
+        assert_eq!(line_count(dir.path().join("audit.jsonl.2")), 1);
+        assert!(!dir.path().join("audit.jsonl.3").exists());
+    }
+
+    #[test]
+    fn test_full_queue() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("audit.jsonl");
+        let config = AuditLog::File {
+            path: path.to_str().unwrap().to_string(),
+            max_file_bytes: 1024 * 1024,
+            max_files: 2,
+        };
+
+        // Records over the capacity are dropped, as are records logged once the writer is gone
+        let (queue, received) = mpsc::sync_channel(1);
+        let logger = AuditLogger { queue };
+        let cells = vec![];
+        logger.log(&record(&cells));
+        logger.log(&record(&cells));
+        assert_eq!(received.try_iter().count(), 1);
+        drop(received);
+        logger.log(&record(&cells));
+        logger.flush();
+
+        // Records queued while the writer is busy are all written
+        let logger = AuditLogger::with_capacity(config, 100).unwrap();
+        for _ in 0..50 {
+            logger.log(&record(&cells));
+        }
+        logger.flush();
+        assert_eq!(fs::read_to_string(&path).unwrap().lines().count(), 50);
+    }
+}
@@ -0,0 +1,286 @@
+//! Synthetic canary requests.
+//!
+//! Periodically sends project configs and public keys requests for dedicated test keys
+//! through the router, exercising the same split, fan-out and merge path as relay traffic.
+//! Every request results in a `canary.result` and `canary.duration` metric, so that a cell
+//! which stopped serving configs is noticed before customer traffic is affected.
+//!
+//! Canary requests originate in synapse, so they skip the inbound signature verification
+//! and are signed with synapse's own credentials instead.
+use crate::api::project_config::ProjectConfigsResponse;
+use crate::config::{Canary as CanaryConfig, CanaryTarget};
+use crate::executor::Executor;
+use crate::metrics_defs::{CANARY_DURATION, CANARY_RESULT};
+use crate::router::{ResolvedRoute, Router};
+use http_body_util::BodyExt;
+use hyper::body::Bytes;
+use hyper::header::{CONTENT_TYPE, HOST};
+use hyper::{Method, Request, Response};
+use std::time::{Duration, Instant};
+use tokio::time::MissedTickBehavior;
+
+const PROJECT_CONFIGS_PATH: &str = "/api/0/relays/projectconfigs/?version=3";
+const PUBLIC_KEYS_PATH: &str = "/api/0/relays/publickeys/";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Check {
+    ProjectConfigs,
+    PublicKeys,
+}
+
+impl Check {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Check::ProjectConfigs => "project_configs",
+            Check::PublicKeys => "public_keys",
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum CheckResult {
+    Success,
+    /// No route matches the canary request
+    NoRoute,
+    /// The merged response has a non-success status
+    UpstreamError,
+    /// Some of the test keys are pending or missing from the merged response
+    MissingKeys(Vec<String>),
+}
+
+impl CheckResult {
+    fn as_str(&self) -> &'static str {
+        match self {
+            CheckResult::Success => "success",
+            CheckResult::NoRoute => "no_route",
+            CheckResult::UpstreamError => "upstream_error",
+            CheckResult::MissingKeys(_) => "missing_keys",
+        }
+    }
+}
+
+pub struct Canary {
+    router: Router,
+    executor: Executor,
+    interval: Duration,
+    targets: Vec<CanaryTarget>,
+}
+
+impl Canary {
+    pub(crate) fn new(config: CanaryConfig, router: Router, executor: Executor) -> Self {
+        Self {
+            router,
+            executor,
+            interval: Duration::from_secs(config.interval_secs),
+            targets: config.targets,
+        }
+    }
+
+    /// Checks every target once per interval, forever.
+    pub async fn run(self) {
+        let mut interval = tokio::time::interval(self.interval);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+
+        loop {
+            interval.tick().await;
+            for target in &self.targets {
+                self.check(Check::ProjectConfigs, target).await;
+                if !target.relay_ids.is_empty() {
+                    self.check(Check::PublicKeys, target).await;
+                }
+            }
+        }
+    }
+
+    async fn check(&self, check: Check, target: &CanaryTarget) {
+        let start = Instant::now();
+
+        let request = build_request(check, target);
+        let result = match self.router.resolve(&request) {
+            Some(ResolvedRoute { handler, cells, .. }) => {
+                let mut request = request;
+                self.executor.sign_request(&mut request);
+                let (parts, body) = self
+                    .executor
+                    .dispatch(handler, request, cells)
+                    .await
+                    .into_parts();
+                match body.collect().await {
+                    Ok(collected) => evaluate(
+                        check,
+                        target,
+                        &Response::from_parts(parts, collected.to_bytes()),
+                    ),
+                    Err(_) => CheckResult::UpstreamError,
+                }
+            }
+            None => CheckResult::NoRoute,
+        };
+
+        metrics::histogram!(
+            CANARY_DURATION.name,
+            "check" => check.as_str(),
+            "host" => target.host.clone(),
+        )
+        .record(start.elapsed().as_secs_f64());
+        metrics::counter!(
+            CANARY_RESULT.name,
+            "check" => check.as_str(),
+            "host" => target.host.clone(),
+            "result" => result.as_str(),
+        )
+        .increment(1);
+
+        match result {
+            CheckResult::Success => {}
+            CheckResult::MissingKeys(keys) => tracing::warn!(
+                check = check.as_str(),
+                host = %target.host,
+                missing_keys = ?keys,
+                "Canary request returned incomplete configs"
+            ),
+            result => tracing::warn!(
+                check = check.as_str(),
+                host = %target.host,
+                result = result.as_str(),
+                "Canary request failed"
+            ),
+        }
+    }
+}
+
+fn build_request(check: Check, target: &CanaryTarget) -> Request<Bytes> {
+    let (path, body) = match check {
+        Check::ProjectConfigs => (
+            PROJECT_CONFIGS_PATH,
+            serde_json::json!({ "publicKeys": target.public_keys }),
+        ),
+        Check::PublicKeys => (
+            PUBLIC_KEYS_PATH,
+            serde_json::json!({ "relay_ids": target.relay_ids }),
+        ),
+    };
+
+    let mut request = Request::new(Bytes::from(body.to_string()));
+    *request.method_mut() = Method::POST;
+    if let Ok(uri) = path.parse() {
+        *request.uri_mut() = uri;
+    }
+    if let Ok(host) = target.host.parse() {
+        request.headers_mut().insert(HOST, host);
+    }
+    request
+        .headers_mut()
+        .insert(CONTENT_TYPE, "application/json".parse().unwrap());
+    request
+}
+
+fn evaluate(check: Check, target: &CanaryTarget, response: &Response<Bytes>) -> CheckResult {
+    if !response.status().is_success() {
+        return CheckResult::UpstreamError;
+    }
+
+    match check {
+        Check::ProjectConfigs => {
+            let Ok(parsed) = serde_json::from_slice::<ProjectConfigsResponse>(response.body())
+            else {
+                return CheckResult::UpstreamError;
+            };
+            let missing: Vec<String> = target
+                .public_keys
+                .iter()
+                .filter(|key| !parsed.project_configs.contains_key(*key))
+                .cloned()
+                .collect();
+            if missing.is_empty() {
+                CheckResult::Success
+            } else {
+                CheckResult::MissingKeys(missing)
+            }
+        }
+        Check::PublicKeys => CheckResult::Success,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::StatusCode;
+
+    fn target() -> CanaryTarget {
+        CanaryTarget {
+            host: "us.sentry.io".to_string(),
+            public_keys: vec!["key1".to_string(), "key2".to_string()],
+            relay_ids: vec!["relay1".to_string()],
+        }
+    }
+
+    fn response(status: StatusCode, body: &'static str) -> Response<Bytes> {
+        let mut response = Response::new(Bytes::from_static(body.as_bytes()));
+        *response.status_mut() = status;
+        response
+    }
+
+    #[test]
+    fn test_build_request() {
+        let request = build_request(Check::ProjectConfigs, &target());
+        assert_eq!(request.method(), Method::POST);
+        assert_eq!(request.uri().path(), "/api/0/relays/projectconfigs/");
+        assert_eq!(request.headers().get(HOST).unwrap(), "us.sentry.io");
+        let body: serde_json::Value = serde_json::from_slice(request.body()).unwrap();
+        assert_eq!(body, serde_json::json!({"publicKeys": ["key1", "key2"]}));
+
+        let request = build_request(Check::PublicKeys, &target());
+        assert_eq!(request.uri().path(), "/api/0/relays/publickeys/");
+        let body: serde_json::Value = serde_json::from_slice(request.body()).unwrap();
+        assert_eq!(body, serde_json::json!({"relay_ids": ["relay1"]}));
+    }
+
+    #[test]
+    fn test_evaluate() {
+        let target = target();
+
+        assert_eq!(
+            evaluate(
+                Check::ProjectConfigs,
+                &target,
+                &response(StatusCode::OK, r#"{"configs": {"key1": {}, "key2": {}}}"#)
+            ),
+            CheckResult::Success
+        );
+
+        // Keys of a failed cell are returned as pending
+        assert_eq!(
+            evaluate(
+                Check::ProjectConfigs,
+                &target,
+                &response(
+                    StatusCode::OK,
+                    r#"{"configs": {"key1": {}}, "pending": ["key2"]}"#
+                )
+            ),
+            CheckResult::MissingKeys(vec!["key2".to_string()])
+        );
+
+        assert_eq!(
+            evaluate(
+                Check::ProjectConfigs,
+                &target,
+                &response(StatusCode::OK, "not json")
+            ),
+            CheckResult::UpstreamError
+        );
+        assert_eq!(
+            evaluate(
+                Check::PublicKeys,
+                &target,
+                &response(StatusCode::BAD_GATEWAY, "")
+            ),
+            CheckResult::UpstreamError
+        );
+        assert_eq!(
+            evaluate(Check::PublicKeys, &target, &response(StatusCode::OK, "{}")),
+            CheckResult::Success
+        );
+    }
+}
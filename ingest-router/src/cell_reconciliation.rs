@@ -0,0 +1,251 @@
+//! Reconciliation of the configured cells with the cells known to the locator.
+//!
+//! The cells under `localities` are maintained by hand, while the locator learns the cells
+//! from the control plane, so the two can diverge: a cell added to the control plane but
+//! not configured cannot be reached, and a configured cell that was decommissioned is still
+//! sent every request fanned out to its locality. Every `interval_secs`, the configured
+//! cells are compared to the locator's cell catalog. Cells missing from either side are
+//! logged and counted in `cells.unreconciled`. With `exclude_unknown`, configured cells
+//! missing from the catalog are left out of the requests sent to all cells of a locality,
+//! unless that would leave the locality without cells, as a catalog missing every
+//! configured cell of a locality is more likely wrong than the config. The comparison is skipped while the locator has no
+//! catalog or cannot be reached, keeping the previously excluded cells.
+use crate::config::CellReconciliation as CellReconciliationConfig;
+use crate::handler::CellId;
+use crate::locality::Localities;
+use crate::metrics_defs::{CELLS_INELIGIBLE, CELLS_UNRECONCILED};
+use locator::client::Locator;
+use locator::types::CatalogCell;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::time::Duration;
+use tokio::time::MissedTickBehavior;
+
+/// Differences between the configured cells and the catalog
+#[derive(Debug, Default, PartialEq)]
+struct Differences {
+    /// Configured cells missing from the catalog
+    missing_from_catalog: BTreeSet<CellId>,
+    /// Catalog cells that are not configured
+    missing_from_config: BTreeSet<CellId>,
+    /// Cells configured in another locality than the one of the catalog, with the
+    /// configured and the catalog locality
+    locality_mismatches: Vec<(CellId, String, String)>,
+}
+
+pub struct CellReconciliation {
+    locator: Locator,
+    localities: Localities,
+    // Locality of every configured cell
+    configured: HashMap<CellId, String>,
+    interval: Duration,
+    exclude_unknown: bool,
+}
+
+impl CellReconciliation {
+    pub fn new(config: CellReconciliationConfig, locator: Locator, localities: Localities) -> Self {
+        let configured = localities
+            .configured_cells()
+            .map(|(locality, cell_id)| (cell_id.to_string(), locality.to_string()))
+            .collect();
+        Self {
+            locator,
+            localities,
+            configured,
+            interval: Duration::from_secs(config.interval_secs),
+            exclude_unknown: config.exclude_unknown,
+        }
+    }
+
+    /// Compares the cells once per interval, forever.
+    pub async fn run(self) {
+        let mut interval = tokio::time::interval(self.interval);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            match self.locator.cell_catalog().await {
+                Ok(Some(catalog)) => self.reconcile(&catalog),
+                Ok(None) => {
+                    tracing::debug!("The locator has no cell catalog to reconcile the cells with")
+                }
+                Err(error) => {
+                    tracing::warn!(%error, "Failed to get the cell catalog from the locator")
+                }
+            }
+        }
+    }
+
+    /// Reports the differences with the catalog, and updates the ineligible cells.
+    fn reconcile(&self, catalog: &[CatalogCell]) {
+        let differences = compare(&self.configured, catalog);
+        metrics::gauge!(CELLS_UNRECONCILED.name, "missing_from" => "catalog")
+            .set(differences.missing_from_catalog.len() as f64);
+        metrics::gauge!(CELLS_UNRECONCILED.name, "missing_from" => "config")
+            .set(differences.missing_from_config.len() as f64);
+        if !differences.missing_from_catalog.is_empty() {
+            tracing::warn!(
+                cells = ?differences.missing_from_catalog,
+                "Configured cells are missing from the locator's cell catalog"
+            );
+        }
+        if !differences.missing_from_config.is_empty() {
+            tracing::warn!(
+                cells = ?differences.missing_from_config,
+                "Cells of the locator's cell catalog are not configured"
+            );
+        }
+        if !differences.locality_mismatches.is_empty() {
+            tracing::warn!(
+                mismatches = ?differences.locality_mismatches,
+                "Cells are configured in another locality than in the locator's cell catalog"
+            );
+        }
+
+        if !self.exclude_unknown {
+            return;
+        }
+        let ineligible = self.ineligible(&differences.missing_from_catalog);
+        metrics::gauge!(CELLS_INELIGIBLE.name).set(ineligible.len() as f64);
+        self.localities.set_ineligible(ineligible);
+    }
+
+    /// Configured cells missing from the catalog, except in the localities where no
+    /// configured cell is in the catalog.
+    fn ineligible(&self, missing_from_catalog: &BTreeSet<CellId>) -> HashSet<CellId> {
+        // Configured and missing cells of every locality
+        let mut localities: HashMap<&str, (usize, Vec<&CellId>)> = HashMap::new();
+        for (cell_id, locality) in &self.configured {
+            let (configured, missing) = localities.entry(locality).or_default();
+            *configured += 1;
+            if missing_from_catalog.contains(cell_id) {
+                missing.push(cell_id);
+            }
+        }
+
+        let mut ineligible = HashSet::new();
+        for (locality, (configured, missing)) in localities {
+            if missing.len() < configured {
+                ineligible.extend(missing.into_iter().cloned());
+            } else {
+                tracing::error!(
+                    locality,
+                    "No configured cell of the locality is in the locator's cell catalog, none are excluded"
+                );
+            }
+        }
+        ineligible
+    }
+}
+
+fn compare(configured: &HashMap<CellId, String>, catalog: &[CatalogCell]) -> Differences {
+    let mut differences = Differences::default();
+    let catalog: HashMap<&str, &str> = catalog
+        .iter()
+        .map(|cell| (cell.id.as_str(), cell.locality.as_str()))
+        .collect();
+    for (cell_id, locality) in configured {
+        match catalog.get(cell_id.as_str()) {
+            None => {
+                differences.missing_from_catalog.insert(cell_id.clone());
+            }
+            Some(&catalog_locality) if catalog_locality != locality => {
+                differences.locality_mismatches.push((
+                    cell_id.clone(),
+                    locality.clone(),
+                    catalog_locality.to_string(),
+                ));
+            }
+            Some(_) => {}
+        }
+    }
+    differences.locality_mismatches.sort();
+    differences.missing_from_config = catalog
+        .keys()
+        .filter(|cell_id| !configured.contains_key(**cell_id))
+        .map(|cell_id| cell_id.to_string())
+        .collect();
+    differences
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::CellConfig;
+    use crate::testutils::create_test_locator;
+    use url::Url;
+
+    fn cell_config(id: &str) -> CellConfig {
+        CellConfig {
+            id: id.to_string(),
+            sentry_url: Url::parse("http://sentry.example.com").unwrap(),
+            relay_url: Url::parse("http://relay.example.com").unwrap(),
+            protocol_version: None,
+            tls: None,
+            max_rps: None,
+        }
+    }
+
+    fn catalog_cell(id: &str, locality: &str) -> CatalogCell {
+        CatalogCell {
+            id: id.to_string(),
+            locality: locality.to_string(),
+            sentry_url: None,
+            relay_url: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_reconcile() {
+        let cells = HashMap::from([
+            (
+                "us".to_string(),
+                vec![cell_config("us1"), cell_config("us2")],
+            ),
+            ("de".to_string(), vec![cell_config("de1")]),
+        ]);
+        let localities = Localities::new(cells);
+        let reconciliation = CellReconciliation::new(
+            CellReconciliationConfig {
+                interval_secs: 300,
+                exclude_unknown: true,
+            },
+            create_test_locator(HashMap::new()).await,
+            localities.clone(),
+        );
+
+        let catalog = [
+            catalog_cell("us1", "us"),
+            catalog_cell("de1", "us"),
+            catalog_cell("us3", "us"),
+        ];
+        assert_eq!(
+            compare(&reconciliation.configured, &catalog),
+            Differences {
+                missing_from_catalog: BTreeSet::from(["us2".to_string()]),
+                missing_from_config: BTreeSet::from(["us3".to_string()]),
+                locality_mismatches: vec![("de1".to_string(), "de".to_string(), "us".to_string())],
+            }
+        );
+
+        let us_cells = localities.get_cells("us").unwrap();
+        reconciliation.reconcile(&catalog);
+        assert_eq!(us_cells.cell_list().collect::<Vec<_>>(), vec!["us1"]);
+
+        // Cells are eligible again once they are in the catalog
+        reconciliation.reconcile(&[
+            catalog_cell("us1", "us"),
+            catalog_cell("us2", "us"),
+            catalog_cell("de1", "de"),
+        ]);
+        assert_eq!(us_cells.cell_list().collect::<Vec<_>>(), vec!["us1", "us2"]);
+
+        // A catalog without any configured cell excludes none
+        reconciliation.reconcile(&[catalog_cell("us3", "us")]);
+        assert_eq!(us_cells.cell_list().collect::<Vec<_>>(), vec!["us1", "us2"]);
+
+        // Nor does a catalog without any configured cell of a locality, in that locality
+        let de_cells = localities.get_cells("de").unwrap();
+        reconciliation.reconcile(&[catalog_cell("us1", "us")]);
+        assert_eq!(us_cells.cell_list().collect::<Vec<_>>(), vec!["us1"]);
+        assert_eq!(de_cells.cell_list().collect::<Vec<_>>(), vec!["de1"]);
+    }
+}
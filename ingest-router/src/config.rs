@@ -1,8 +1,14 @@
 use crate::auth::RelayInfo;
-use locator::client::{LocatorConfig as ClientLocatorConfig, LocatorType as ClientLocatorType};
-use locator::config::{BackupRouteStore, ControlPlane, LocatorDataType};
-use serde::Deserialize;
+use locator::client::{
+    LocatorConfig as ClientLocatorConfig, LocatorType as ClientLocatorType, ShardTopology,
+};
+use locator::config::{BackupRouteStore, ClientCache, ControlPlane, LocatorDataType};
+use serde::{Deserialize, Serialize};
+use shared::admin_service::BearerTokens;
+pub use shared::http::HeaderMatch;
+pub use shared::tls::ListenerTls;
 use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
 use thiserror::Error;
 use url::Url;
 
@@ -28,10 +34,49 @@ pub enum ValidationError {
 
     #[error("Invalid timeout configuration: {0}")]
     InvalidTimeouts(String),
+
+    #[error("Quorum must be > 0")]
+    InvalidQuorum,
+
+    #[error("Invalid canary configuration: {0}")]
+    InvalidCanary(String),
+
+    #[error("Invalid routing drift configuration: {0}")]
+    InvalidRoutingDrift(String),
+
+    #[error("Invalid panic breaker configuration: {0}")]
+    InvalidPanicBreaker(String),
+
+    #[error("Invalid cell reconciliation configuration: {0}")]
+    InvalidCellReconciliation(String),
+
+    #[error("Invalid traffic report configuration: {0}")]
+    InvalidTrafficReport(String),
+
+    #[error("Cell max_rps must be > 0: {0}")]
+    InvalidMaxRps(String),
+
+    #[error("Route max_concurrent_requests must be > 0")]
+    InvalidMaxConcurrentRequests,
+
+    #[error("Invalid header name in forward_headers: {0}")]
+    InvalidForwardHeader(String),
+
+    #[error("Invalid header name in match headers: {0}")]
+    InvalidMatchHeader(String),
+
+    #[error("Match headers cannot capture route parameters: {0}")]
+    MatchHeaderParam(String),
+
+    #[error("Invalid key sources: {0}")]
+    InvalidKeySources(String),
+
+    #[error("Invalid admin auth: {0}")]
+    AdminAuth(String),
 }
 
 /// HTTP methods supported for route matching
-#[derive(Clone, Debug, Deserialize, PartialEq, Eq)]
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
 #[serde(rename_all = "UPPERCASE")]
 pub enum HttpMethod {
     Get,
@@ -51,7 +96,7 @@ impl PartialEq<hyper::Method> for HttpMethod {
     }
 }
 
-#[derive(Clone, Debug, Deserialize, PartialEq, Eq, Hash)]
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq, Hash)]
 #[serde(tag = "handler", rename_all = "snake_case")]
 pub enum HandlerAction {
     /// Merges project configs from multiple relay instances
@@ -60,7 +105,81 @@ pub enum HandlerAction {
     Health,
     RegisterChallenge,
     RegisterResponse,
+    /// Broadcasts public key lookups to all cells of the locality
     PublicKeys,
+    /// Broadcasts relay heartbeats to all cells of the locality
+    RelayHeartbeat,
+    /// Forwards envelopes unmodified to the cell of their project key
+    Envelope,
+    /// Forwards minidump and attachment uploads unmodified to the cell of their project key
+    Minidump,
+    /// Forwards requests unmodified to the cell of their project key, taken from the first
+    /// of `key_from` that has one
+    SingleProject {
+        key_from: Vec<KeySource>,
+    },
+    /// Sends the request to all cells of the locality and streams back the lines of their
+    /// NDJSON responses, interleaved as they arrive
+    NdjsonMerge {
+        /// If set, the id of the cell a line came from is added to every line that is a
+        /// JSON object, under this key
+        #[serde(default)]
+        source_field: Option<String>,
+    },
+    /// Sends the request to all cells of the locality and concatenates the JSON arrays of
+    /// their responses, paginated with a composite cursor of the cells' cursors
+    PaginatedMerge {
+        /// If set, the id of the cell an object came from is added to every object of the
+        /// results, under this key
+        #[serde(default)]
+        source_field: Option<String>,
+    },
+    /// Streams requests to the cell of their project key, taken from the first of
+    /// `key_from` that has one, and streams the cell's response back. Neither body is
+    /// buffered, so the key can only come from the path, query or headers.
+    ProxyToCell {
+        key_from: Vec<KeySource>,
+        /// URL of the cell the requests are sent to. Default: relay
+        #[serde(default)]
+        target: CellTarget,
+    },
+}
+
+/// URL of a cell that requests are sent to
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum CellTarget {
+    #[default]
+    Relay,
+    Sentry,
+}
+
+/// Where the project key, or DSN, of a request is taken from
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq, Hash)]
+#[serde(tag = "source", rename_all = "snake_case")]
+pub enum KeySource {
+    /// A query parameter, such as `sentry_key`
+    Query { name: String },
+    /// The `sentry_key` of the `X-Sentry-Auth` or `Authorization` header
+    AuthHeader,
+    /// A segment of the path, counted from 0 after the leading slash
+    PathSegment { index: usize },
+    /// A string in a JSON body, as a JSON pointer such as `/dsn`
+    JsonBody { pointer: String },
+    /// The `dsn` of the envelope header, the first line of an envelope
+    EnvelopeHeader,
+    /// A field of a multipart form
+    FormField { name: String },
+}
+
+impl KeySource {
+    /// Whether the key is taken from the request body
+    pub fn reads_body(&self) -> bool {
+        matches!(
+            self,
+            KeySource::JsonBody { .. } | KeySource::EnvelopeHeader | KeySource::FormField { .. }
+        )
+    }
 }
 
 // Timeout configuration for relay project configs handler
@@ -81,6 +200,21 @@ pub struct RelayTimeouts {
     /// Aggressively cuts off slow upstreams once we have good data.
     /// Default: 5 seconds
     pub task_subsequent_timeout_secs: u64,
+
+    /// Keep successful responses arriving after the subsequent deadline for this long, and
+    /// use them for the next identical request to the same cell (seconds).
+    /// Default: None (late responses are discarded)
+    pub late_response_ttl_secs: Option<u64>,
+
+    /// Attempts of a cell request that fails to reach the cell, such as on a connection
+    /// reset, including the first one. Retries are only started before the task deadline.
+    /// Default: 1 (failed requests are not retried)
+    pub cell_request_attempts: u32,
+
+    /// Delay before the first retry of a cell request (milliseconds), doubled for every
+    /// further retry, with up to half of it randomized away.
+    /// Default: 100 milliseconds
+    pub cell_retry_backoff_ms: u64,
 }
 
 impl Default for RelayTimeouts {
@@ -89,6 +223,9 @@ impl Default for RelayTimeouts {
             http_timeout_secs: 15,
             task_initial_timeout_secs: 20,
             task_subsequent_timeout_secs: 5,
+            late_response_ttl_secs: None,
+            cell_request_attempts: 1,
+            cell_retry_backoff_ms: 100,
         }
     }
 }
@@ -110,10 +247,290 @@ impl RelayTimeouts {
             ));
         }
 
+        if self.late_response_ttl_secs == Some(0) {
+            return Err(ValidationError::InvalidTimeouts(
+                "late_response_ttl_secs must be > 0".to_string(),
+            ));
+        }
+
+        if self.cell_request_attempts == 0 {
+            return Err(ValidationError::InvalidTimeouts(
+                "cell_request_attempts must be > 0".to_string(),
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+/// Configuration for the relay heartbeat handler
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct RelayHeartbeat {
+    /// Number of cells that must acknowledge a heartbeat for it to succeed.
+    /// Capped at the number of cells in the locality.
+    /// Default: a majority of the locality's cells
+    pub quorum: Option<usize>,
+}
+
+impl RelayHeartbeat {
+    /// Validates the heartbeat configuration
+    pub fn validate(&self) -> Result<(), ValidationError> {
+        if self.quorum == Some(0) {
+            return Err(ValidationError::InvalidQuorum);
+        }
+        Ok(())
+    }
+}
+
+/// Configuration for the public keys handler
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct PublicKeys {
+    /// Number of cells that must respond for a lookup to succeed.
+    /// Capped at the number of cells in the locality.
+    /// Default: a majority of the locality's cells
+    pub quorum: Option<usize>,
+    /// Fail lookups that reached the quorum if a requested relay has no key while some
+    /// cells failed, since a failed cell may know it.
+    /// Default: false
+    pub require_all_resolved: bool,
+}
+
+impl PublicKeys {
+    /// Validates the public keys configuration
+    pub fn validate(&self) -> Result<(), ValidationError> {
+        if self.quorum == Some(0) {
+            return Err(ValidationError::InvalidQuorum);
+        }
+        Ok(())
+    }
+}
+
+fn default_audit_max_file_bytes() -> u64 {
+    100 * 1024 * 1024
+}
+
+fn default_audit_max_files() -> usize {
+    5
+}
+
+/// Destination of the routing decision audit trail
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum AuditLog {
+    Stdout,
+    File {
+        path: String,
+        /// Size at which the file is rotated.
+        /// Default: 100 MiB
+        #[serde(default = "default_audit_max_file_bytes")]
+        max_file_bytes: u64,
+        /// Number of rotated files to keep.
+        /// Default: 5
+        #[serde(default = "default_audit_max_files")]
+        max_files: usize,
+    },
+}
+
+fn default_canary_interval_secs() -> u64 {
+    60
+}
+
+/// Synthetic canary requests
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct Canary {
+    /// Time between two rounds of canary requests (seconds).
+    /// Default: 60 seconds
+    #[serde(default = "default_canary_interval_secs")]
+    pub interval_secs: u64,
+    /// Each target is checked once per round
+    pub targets: Vec<CanaryTarget>,
+}
+
+impl Canary {
+    /// Validates the canary configuration
+    pub fn validate(&self) -> Result<(), ValidationError> {
+        if self.interval_secs == 0 {
+            return Err(ValidationError::InvalidCanary(
+                "interval_secs must be > 0".into(),
+            ));
+        }
+        for target in &self.targets {
+            if target.public_keys.is_empty() {
+                return Err(ValidationError::InvalidCanary(format!(
+                    "target {} has no public keys",
+                    target.host
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+fn default_drift_window_secs() -> u64 {
+    300
+}
+
+fn default_drift_threshold() -> f64 {
+    0.2
+}
+
+fn default_drift_min_keys() -> u64 {
+    1000
+}
+
+/// Comparison of the distribution of routed keys across cells between windows
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct RoutingDrift {
+    /// Length of the compared windows (seconds).
+    /// Default: 300 seconds
+    #[serde(default = "default_drift_window_secs")]
+    pub window_secs: u64,
+    /// Drift, the share of keys that moved to another cell, from which a shift is logged.
+    /// Default: 0.2
+    #[serde(default = "default_drift_threshold")]
+    pub threshold: f64,
+    /// Windows with fewer routed keys are not compared.
+    /// Default: 1000
+    #[serde(default = "default_drift_min_keys")]
+    pub min_keys: u64,
+}
+
+impl RoutingDrift {
+    /// Validates the routing drift configuration
+    pub fn validate(&self) -> Result<(), ValidationError> {
+        if self.window_secs == 0 {
+            return Err(ValidationError::InvalidRoutingDrift(
+                "window_secs must be > 0".into(),
+            ));
+        }
+        if !(self.threshold > 0.0 && self.threshold <= 1.0) {
+            return Err(ValidationError::InvalidRoutingDrift(
+                "threshold must be > 0 and <= 1".into(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+fn default_panic_threshold() -> u32 {
+    3
+}
+
+fn default_panic_window_secs() -> u64 {
+    60
+}
+
+fn default_panic_open_secs() -> u64 {
+    30
+}
+
+/// Stops sending requests to a cell whose request tasks keep panicking
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct PanicBreaker {
+    /// Panics of a cell's request tasks within the window that open the breaker.
+    /// Default: 3
+    #[serde(default = "default_panic_threshold")]
+    pub threshold: u32,
+    /// Window in which panics are counted (seconds).
+    /// Default: 60 seconds
+    #[serde(default = "default_panic_window_secs")]
+    pub window_secs: u64,
+    /// How long no requests are sent to the cell once the breaker opens (seconds).
+    /// Default: 30 seconds
+    #[serde(default = "default_panic_open_secs")]
+    pub open_secs: u64,
+}
+
+impl PanicBreaker {
+    /// Validates the panic breaker configuration
+    pub fn validate(&self) -> Result<(), ValidationError> {
+        if self.threshold == 0 {
+            return Err(ValidationError::InvalidPanicBreaker(
+                "threshold must be > 0".into(),
+            ));
+        }
+        if self.window_secs == 0 || self.open_secs == 0 {
+            return Err(ValidationError::InvalidPanicBreaker(
+                "window_secs and open_secs must be > 0".into(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+fn default_reconciliation_interval_secs() -> u64 {
+    300
+}
+
+/// Periodic comparison of the configured cells with the cell catalog of the locator
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct CellReconciliation {
+    /// Interval between comparisons (seconds).
+    /// Default: 300 seconds
+    #[serde(default = "default_reconciliation_interval_secs")]
+    pub interval_secs: u64,
+    /// Leave the configured cells missing from the catalog out of the requests sent to
+    /// all cells of a locality. Requests routed to them by key are still sent.
+    #[serde(default)]
+    pub exclude_unknown: bool,
+}
+
+impl CellReconciliation {
+    /// Validates the cell reconciliation configuration
+    pub fn validate(&self) -> Result<(), ValidationError> {
+        if self.interval_secs == 0 {
+            return Err(ValidationError::InvalidCellReconciliation(
+                "interval_secs must be > 0".into(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+fn default_drain_timeout_secs() -> u64 {
+    30
+}
+
+fn default_traffic_window_secs() -> u64 {
+    300
+}
+
+/// Requests, keys, errors and pending keys of each locality and cell over a sliding window,
+/// reported on the admin listener
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct TrafficReport {
+    /// Length of the window (seconds).
+    /// Default: 300 seconds
+    #[serde(default = "default_traffic_window_secs")]
+    pub window_secs: u64,
+}
+
+impl TrafficReport {
+    /// Validates the traffic report configuration
+    pub fn validate(&self) -> Result<(), ValidationError> {
+        if self.window_secs == 0 {
+            return Err(ValidationError::InvalidTrafficReport(
+                "window_secs must be > 0".into(),
+            ));
+        }
         Ok(())
     }
 }
 
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct CanaryTarget {
+    /// Host the requests are sent to, which selects the route and therefore the locality
+    pub host: String,
+    /// Dedicated test project keys. To check every cell, the keys must be owned by all
+    /// cells of the locality between them.
+    pub public_keys: Vec<String>,
+    /// Relay ids requested from the public keys endpoint. That check is skipped if empty.
+    #[serde(default)]
+    pub relay_ids: Vec<String>,
+}
+
 /// Cell/upstream configuration
 /// Note: The cell id is the HashMap key in Config.localities
 #[derive(Clone, Debug, Deserialize, PartialEq)]
@@ -124,6 +541,35 @@ pub struct CellConfig {
     pub sentry_url: Url,
     /// URL of the Relay upstream server
     pub relay_url: Url,
+    /// Version of the relay protocol the cell's responses follow, if it is older than the
+    /// one the handlers implement. Responses are adapted to the current version, and
+    /// deviations from the declared version are reported.
+    #[serde(default)]
+    pub protocol_version: Option<u32>,
+    /// TLS settings for reaching the cell over HTTPS. Public CA roots are trusted if not set.
+    #[serde(default)]
+    pub tls: Option<CellTls>,
+    /// Maximum requests per second sent to the cell, with bursts of up to one second of
+    /// requests. Requests over the limit are not sent, and are rejected with 429 if no other
+    /// cell is involved. Unlimited if not set.
+    #[serde(default)]
+    pub max_rps: Option<u32>,
+}
+
+/// TLS settings of a cell, for cells behind an internal PKI
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct CellTls {
+    /// PEM bundle of the CA certificates trusted for the cell, instead of the public roots
+    pub ca_file: Option<PathBuf>,
+    /// PEM certificate chain presented to the cell. Requires `client_key_file`.
+    pub client_cert_file: Option<PathBuf>,
+    /// PEM private key of the client certificate
+    pub client_key_file: Option<PathBuf>,
+    /// Accept any server certificate. Only allowed outside of production, see the
+    /// `SENTRY_ENVIRONMENT` environment variable.
+    /// Default: false
+    pub insecure_skip_verify: bool,
 }
 
 /// Locator configuration
@@ -131,7 +577,18 @@ pub struct CellConfig {
 #[serde(tag = "type")]
 pub enum LocatorType {
     #[serde(rename = "url")]
-    Url { url: String },
+    Url {
+        url: String,
+        /// API key of this service, if the locator API requires one
+        api_key: Option<String>,
+        /// Datagram listener of a locator on the same host, which lookups are sent to
+        /// instead of the API
+        #[serde(default)]
+        datagram_addr: Option<String>,
+        /// Cache of the lookups in this service. Disabled if not set.
+        #[serde(default)]
+        cache: Option<ClientCache>,
+    },
     #[serde(rename = "in_process")]
     InProcess {
         control_plane: ControlPlane,
@@ -139,6 +596,13 @@ pub enum LocatorType {
         localities: Option<Vec<String>>,
         locality_to_default_cell: Option<HashMap<String, String>>,
     },
+    /// Locators each holding one shard of the keyspace
+    #[serde(rename = "sharded")]
+    Sharded {
+        shards: ShardTopology,
+        /// API key of this service, if the locator API requires one
+        api_key: Option<String>,
+    },
 }
 
 #[derive(Clone, Debug, Deserialize, PartialEq)]
@@ -158,14 +622,28 @@ impl Locator {
                     localities,
                     locality_to_default_cell,
                 } => ClientLocatorType::InProcess {
-                    control_plane_url: control_plane.url,
+                    control_plane,
                     backup_route_store_type: backup_route_store.r#type,
                     localities,
                     locality_to_default_cell,
                 },
-                LocatorType::Url { url } => ClientLocatorType::Url { url },
+                LocatorType::Url {
+                    url,
+                    api_key,
+                    datagram_addr,
+                    cache,
+                } => ClientLocatorType::Url {
+                    url,
+                    api_key,
+                    datagram_addr,
+                    cache,
+                },
+                LocatorType::Sharded { shards, api_key } => {
+                    ClientLocatorType::Sharded { shards, api_key }
+                }
             },
             data_type: LocatorDataType::ProjectKey,
+            caller: Some("ingest-router".into()),
         }
     }
 }
@@ -190,6 +668,47 @@ pub struct Config {
     pub relay_timeouts: RelayTimeouts,
     /// Trusted downstream relay public keys, keyed by relay id
     pub relay_keys: HashMap<String, RelayInfo>,
+    /// Forward keys owned by a cell in another locality to that cell instead of
+    /// returning them as pending. Requires the owning cell to be configured under
+    /// `localities`.
+    #[serde(default)]
+    pub cross_locality_routing: bool,
+    /// Relay heartbeat handler configuration
+    #[serde(default)]
+    pub relay_heartbeat: RelayHeartbeat,
+    /// Public keys handler configuration
+    #[serde(default)]
+    pub public_keys: PublicKeys,
+    /// Ceiling for request body bytes buffered across all in-flight requests. Once
+    /// reached, new requests are rejected with 503. Unlimited if not set.
+    #[serde(default)]
+    pub max_buffered_body_bytes: Option<u64>,
+    /// Writes one JSON line per request describing where it was routed. Disabled if not set.
+    #[serde(default)]
+    pub audit_log: Option<AuditLog>,
+    /// Synthetic requests periodically sent through the router to every cell. Disabled if
+    /// not set.
+    #[serde(default)]
+    pub canary: Option<Canary>,
+    /// Logs shifts of the distribution of routed keys across cells. Disabled if not set.
+    #[serde(default)]
+    pub routing_drift: Option<RoutingDrift>,
+    /// Stops sending requests to cells whose request tasks keep panicking. Disabled if not
+    /// set, panics are still reported.
+    #[serde(default)]
+    pub panic_breaker: Option<PanicBreaker>,
+    /// Logs the configured cells missing from the locator's cell catalog and the other
+    /// way around. Disabled if not set.
+    #[serde(default)]
+    pub cell_reconciliation: Option<CellReconciliation>,
+    /// Reports the traffic of each locality and cell on the admin listener. Disabled if not
+    /// set.
+    #[serde(default)]
+    pub traffic_report: Option<TrafficReport>,
+    /// Seconds given to open connections to finish on SIGTERM or Ctrl-C.
+    /// Default: 30 seconds
+    #[serde(default = "default_drain_timeout_secs")]
+    pub drain_timeout_secs: u64,
 }
 
 impl Config {
@@ -200,6 +719,23 @@ impl Config {
         self.admin_listener.validate()?;
 
         self.relay_timeouts.validate()?;
+        self.relay_heartbeat.validate()?;
+        self.public_keys.validate()?;
+        if let Some(canary) = &self.canary {
+            canary.validate()?;
+        }
+        if let Some(routing_drift) = &self.routing_drift {
+            routing_drift.validate()?;
+        }
+        if let Some(panic_breaker) = &self.panic_breaker {
+            panic_breaker.validate()?;
+        }
+        if let Some(cell_reconciliation) = &self.cell_reconciliation {
+            cell_reconciliation.validate()?;
+        }
+        if let Some(traffic_report) = &self.traffic_report {
+            traffic_report.validate()?;
+        }
 
         // Validate localities and cells
         for (locality, cells) in &self.localities {
@@ -217,6 +753,9 @@ impl Config {
                 if !seen_ids.insert(&cell.id) {
                     return Err(ValidationError::DuplicateUpstream(cell.id.clone()));
                 }
+                if cell.max_rps == Some(0) {
+                    return Err(ValidationError::InvalidMaxRps(cell.id.clone()));
+                }
             }
         }
 
@@ -228,12 +767,65 @@ impl Config {
             if valid_localities.is_empty() || !valid_localities.contains(&r.locality) {
                 return Err(ValidationError::UnknownLocality(r.locality.clone()));
             }
+            if r.max_concurrent_requests == Some(0) {
+                return Err(ValidationError::InvalidMaxConcurrentRequests);
+            }
+            for name in r.forward_headers.iter().flatten() {
+                if http::HeaderName::from_bytes(name.as_bytes()).is_err() {
+                    return Err(ValidationError::InvalidForwardHeader(name.clone()));
+                }
+            }
+            for header in &r.r#match.headers {
+                if http::HeaderName::from_bytes(header.name.as_bytes()).is_err() {
+                    return Err(ValidationError::InvalidMatchHeader(header.name.clone()));
+                }
+                // Routes have no parameters, the cell is found from the request's key
+                if let Some(param) = &header.param {
+                    return Err(ValidationError::MatchHeaderParam(param.clone()));
+                }
+            }
+            match &r.action {
+                HandlerAction::SingleProject { key_from } => validate_key_sources(key_from)?,
+                HandlerAction::ProxyToCell { key_from, .. } => {
+                    validate_key_sources(key_from)?;
+                    if let Some(source) = key_from.iter().find(|source| source.reads_body()) {
+                        return Err(ValidationError::InvalidKeySources(format!(
+                            "proxy_to_cell does not read the body: {source:?}"
+                        )));
+                    }
+                }
+                _ => {}
+            }
         }
 
         Ok(())
     }
 }
 
+fn validate_key_sources(key_from: &[KeySource]) -> Result<(), ValidationError> {
+    if key_from.is_empty() {
+        return Err(ValidationError::InvalidKeySources(
+            "key_from must not be empty".to_string(),
+        ));
+    }
+    for source in key_from {
+        match source {
+            KeySource::JsonBody { pointer } if !pointer.starts_with('/') => {
+                return Err(ValidationError::InvalidKeySources(format!(
+                    "JSON pointer must start with '/': {pointer}"
+                )));
+            }
+            KeySource::Query { name } | KeySource::FormField { name } if name.is_empty() => {
+                return Err(ValidationError::InvalidKeySources(
+                    "name must not be empty".to_string(),
+                ));
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
 /// Network listener configuration
 #[derive(Clone, Debug, Deserialize, PartialEq)]
 pub struct Listener {
@@ -241,6 +833,9 @@ pub struct Listener {
     pub host: String,
     /// Port number to listen on
     pub port: u16,
+    /// Terminates TLS on the listener. Plaintext HTTP is served if not set.
+    #[serde(default)]
+    pub tls: Option<ListenerTls>,
 }
 
 impl Default for Listener {
@@ -248,6 +843,7 @@ impl Default for Listener {
         Listener {
             host: "0.0.0.0".into(),
             port: 3000,
+            tls: None,
         }
     }
 }
@@ -268,6 +864,10 @@ pub struct AdminListener {
     pub host: String,
     /// Port number to listen on
     pub port: u16,
+    /// Authentication of the requests to all admin endpoints except the health and
+    /// readiness probes. Requests are not authenticated if not set.
+    #[serde(default)]
+    pub auth: Option<AdminAuth>,
 }
 
 impl Default for AdminListener {
@@ -275,6 +875,7 @@ impl Default for AdminListener {
         AdminListener {
             host: "0.0.0.0".into(),
             port: 3001,
+            auth: None,
         }
     }
 }
@@ -285,12 +886,36 @@ impl AdminListener {
         if self.port == 0 {
             return Err(ValidationError::InvalidPort);
         }
+        if let Some(auth) = &self.auth {
+            auth.bearer_tokens()?;
+        }
         Ok(())
     }
 }
 
-/// Routing rule configuration
+/// Authentication of admin requests, like the `auth` of the proxy admin listener
 #[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct AdminAuth {
+    /// Requests must send one of these tokens as `Authorization: Bearer <token>`, others
+    /// are rejected with 401. Several tokens allow rotating them.
+    pub bearer_tokens: Vec<String>,
+}
+
+impl AdminAuth {
+    /// The tokens checked by the admin service
+    pub fn bearer_tokens(&self) -> Result<BearerTokens, ValidationError> {
+        if self.bearer_tokens.is_empty() {
+            return Err(ValidationError::AdminAuth(
+                "auth requires bearer_tokens".to_string(),
+            ));
+        }
+        BearerTokens::new(self.bearer_tokens.clone()).map_err(ValidationError::AdminAuth)
+    }
+}
+
+/// Routing rule configuration
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
 pub struct Route {
     /// Conditions for matching incoming requests
     pub r#match: Match,
@@ -298,17 +923,33 @@ pub struct Route {
     pub action: HandlerAction,
     // Locality that the route applies to
     pub locality: String,
+    /// Accepted request content types (e.g. `application/json`), any if empty.
+    /// Requests with other or no content types are rejected with 415.
+    #[serde(default)]
+    pub content_types: Vec<String>,
+    /// Requests of this route handled concurrently, further requests are rejected with 503.
+    /// Unlimited if not set.
+    #[serde(default)]
+    pub max_concurrent_requests: Option<usize>,
+    /// Request headers forwarded to cells. The content, auth and tracing headers are
+    /// always forwarded. All headers are forwarded if not set.
+    #[serde(default)]
+    pub forward_headers: Option<Vec<String>>,
 }
 
 /// Request matching criteria
-#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
 pub struct Match {
     /// Optional hostname to match (e.g., "us.sentry.io")
     pub host: Option<String>,
-    /// Optional path to match (e.g., "/api/0/relays/projectconfigs/")
+    /// Optional path to match (e.g., "/api/0/relays/projectconfigs/"). Segments written as
+    /// `{name}` match any non-empty segment (e.g., "/api/{project_id}/minidump/").
     pub path: Option<String>,
     /// Optional HTTP method to match
     pub method: Option<HttpMethod>,
+    /// Optional request headers that must all be present (e.g., "X-Sentry-Relay-Id")
+    #[serde(default)]
+    pub headers: Vec<HeaderMatch>,
 }
 
 #[cfg(test)]
@@ -335,10 +976,13 @@ localities:
         - id: us2
           sentry_url: "http://10.0.0.2:8080"
           relay_url: "http://10.0.0.2:8090"
+          max_rps: 500
     de:
         - id: de1
           sentry_url: "http://10.0.0.3:8080"
-          relay_url: "http://10.0.0.3:8090"
+          relay_url: "https://10.0.0.3:8090"
+          tls:
+            ca_file: /etc/synapse/ca.pem
 routes:
     - match:
         host: us.sentry.io
@@ -365,6 +1009,16 @@ relay_keys:
         assert_eq!(config.localities.get("de").unwrap().len(), 1);
         assert_eq!(config.localities.get("us").unwrap()[0].id, "us1");
         assert_eq!(config.localities.get("us").unwrap()[1].id, "us2");
+        assert_eq!(config.localities.get("us").unwrap()[0].tls, None);
+        assert_eq!(config.localities.get("us").unwrap()[0].max_rps, None);
+        assert_eq!(config.localities.get("us").unwrap()[1].max_rps, Some(500));
+        assert_eq!(
+            config.localities.get("de").unwrap()[0].tls,
+            Some(CellTls {
+                ca_file: Some(PathBuf::from("/etc/synapse/ca.pem")),
+                ..Default::default()
+            })
+        );
         assert_eq!(config.routes.len(), 2);
         assert_eq!(config.routes[0].r#match.method, Some(HttpMethod::Post));
         assert_eq!(config.routes[1].r#match.host, None);
@@ -376,10 +1030,12 @@ relay_keys:
             listener: Listener {
                 host: "0.0.0.0".to_string(),
                 port: 3000,
+                tls: None,
             },
             admin_listener: AdminListener {
                 host: "127.0.0.1".to_string(),
                 port: 3001,
+                auth: None,
             },
             localities: HashMap::from([(
                 "us".to_string(),
@@ -387,22 +1043,43 @@ relay_keys:
                     id: "us1".to_string(),
                     sentry_url: Url::parse("http://127.0.0.1:8080").unwrap(),
                     relay_url: Url::parse("http://127.0.0.1:8090").unwrap(),
+                    protocol_version: None,
+                    tls: None,
+                    max_rps: None,
                 }],
             )]),
             relay_timeouts: RelayTimeouts::default(),
             relay_keys: HashMap::new(),
+            cross_locality_routing: false,
+            relay_heartbeat: RelayHeartbeat::default(),
+            public_keys: PublicKeys::default(),
+            max_buffered_body_bytes: None,
+            audit_log: None,
+            canary: None,
+            routing_drift: None,
+            panic_breaker: None,
+            cell_reconciliation: None,
+            traffic_report: None,
+            drain_timeout_secs: 30,
             routes: vec![Route {
                 r#match: Match {
                     path: Some("/api/".to_string()),
                     host: None,
                     method: None,
+                    headers: vec![],
                 },
                 action: HandlerAction::RelayProjectConfigs,
                 locality: "us".to_string(),
+                content_types: vec![],
+                max_concurrent_requests: None,
+                forward_headers: None,
             }],
             locator: Locator {
                 r#type: LocatorType::Url {
                     url: "http://locator:3000".to_string(),
+                    api_key: None,
+                    datagram_addr: None,
+                    cache: None,
                 },
             },
         };
@@ -415,12 +1092,64 @@ relay_keys:
             ValidationError::InvalidPort
         ));
 
+        // Test admin auth without tokens or with an empty one
+        for bearer_tokens in [vec![], vec!["".to_string()]] {
+            let mut config = base_config.clone();
+            config.admin_listener.auth = Some(AdminAuth { bearer_tokens });
+            assert!(matches!(
+                config.validate().unwrap_err(),
+                ValidationError::AdminAuth(_)
+            ));
+        }
+
+        // Test zero cell request attempts
+        let mut config = base_config.clone();
+        config.relay_timeouts.cell_request_attempts = 0;
+        assert!(matches!(
+            config.validate().unwrap_err(),
+            ValidationError::InvalidTimeouts(_)
+        ));
+
+        // Test zero heartbeat quorum
+        let mut config = base_config.clone();
+        config.relay_heartbeat.quorum = Some(0);
+        assert!(matches!(
+            config.validate().unwrap_err(),
+            ValidationError::InvalidQuorum
+        ));
+
+        // Test zero public keys quorum
+        let mut config = base_config.clone();
+        config.public_keys.quorum = Some(0);
+        assert!(matches!(
+            config.validate().unwrap_err(),
+            ValidationError::InvalidQuorum
+        ));
+
+        // Test canary without public keys
+        let mut config = base_config.clone();
+        config.canary = Some(Canary {
+            interval_secs: 60,
+            targets: vec![CanaryTarget {
+                host: "us.sentry.io".to_string(),
+                public_keys: Vec::new(),
+                relay_ids: Vec::new(),
+            }],
+        });
+        assert!(matches!(
+            config.validate().unwrap_err(),
+            ValidationError::InvalidCanary(_)
+        ));
+
         // Test empty cell id
         let mut config = base_config.clone();
         config.localities.get_mut("us").unwrap().push(CellConfig {
             id: "".to_string(),
             sentry_url: Url::parse("http://10.0.0.2:8080").unwrap(),
             relay_url: Url::parse("http://10.0.0.2:8090").unwrap(),
+            protocol_version: None,
+            tls: None,
+            max_rps: None,
         });
         assert!(matches!(
             config.validate().unwrap_err(),
@@ -433,12 +1162,23 @@ relay_keys:
             id: "us1".to_string(),
             sentry_url: Url::parse("http://10.0.0.2:8080").unwrap(),
             relay_url: Url::parse("http://10.0.0.2:8090").unwrap(),
+            protocol_version: None,
+            tls: None,
+            max_rps: None,
         });
         assert!(matches!(
             config.validate().unwrap_err(),
             ValidationError::DuplicateUpstream(_)
         ));
 
+        // Test cell allowing no requests
+        let mut config = base_config.clone();
+        config.localities.get_mut("us").unwrap()[0].max_rps = Some(0);
+        assert!(matches!(
+            config.validate().unwrap_err(),
+            ValidationError::InvalidMaxRps(_)
+        ));
+
         // Test unknown locality in action
         let mut config = base_config.clone();
         config.routes[0].locality = "invalid".to_string();
@@ -447,6 +1187,97 @@ relay_keys:
             ValidationError::UnknownLocality(_)
         ));
 
+        // Test route allowing no concurrent requests
+        let mut config = base_config.clone();
+        config.routes[0].max_concurrent_requests = Some(0);
+        assert!(matches!(
+            config.validate().unwrap_err(),
+            ValidationError::InvalidMaxConcurrentRequests
+        ));
+
+        // Test invalid header name in the allow-list
+        let mut config = base_config.clone();
+        config.routes[0].forward_headers = Some(vec!["x sentry".to_string()]);
+        assert!(matches!(
+            config.validate().unwrap_err(),
+            ValidationError::InvalidForwardHeader(_)
+        ));
+
+        // Test invalid header name in the route match
+        let mut config = base_config.clone();
+        config.routes[0].r#match.headers = vec![HeaderMatch {
+            name: "x sentry".to_string(),
+            value: None,
+            param: None,
+        }];
+        assert!(matches!(
+            config.validate().unwrap_err(),
+            ValidationError::InvalidMatchHeader(_)
+        ));
+        config.routes[0].r#match.headers[0].name = "x-sentry".to_string();
+        config.routes[0].r#match.headers[0].param = Some("organization".to_string());
+        assert!(matches!(
+            config.validate().unwrap_err(),
+            ValidationError::MatchHeaderParam(_)
+        ));
+
+        // Test routes without key sources, or with an invalid JSON pointer
+        for key_from in [
+            vec![],
+            vec![KeySource::JsonBody {
+                pointer: "dsn".to_string(),
+            }],
+        ] {
+            let mut config = base_config.clone();
+            config.routes[0].action = HandlerAction::SingleProject { key_from };
+            assert!(matches!(
+                config.validate().unwrap_err(),
+                ValidationError::InvalidKeySources(_)
+            ));
+        }
+
+        // Test pass-through routes taking the key from the body
+        let mut config = base_config.clone();
+        config.routes[0].action = HandlerAction::ProxyToCell {
+            key_from: vec![KeySource::AuthHeader, KeySource::EnvelopeHeader],
+            target: CellTarget::Relay,
+        };
+        assert!(matches!(
+            config.validate().unwrap_err(),
+            ValidationError::InvalidKeySources(_)
+        ));
+
+        // Test panic breaker that never opens
+        let mut config = base_config.clone();
+        config.panic_breaker = Some(PanicBreaker {
+            threshold: 0,
+            window_secs: 60,
+            open_secs: 30,
+        });
+        assert!(matches!(
+            config.validate().unwrap_err(),
+            ValidationError::InvalidPanicBreaker(_)
+        ));
+
+        // Test cell reconciliation that never runs
+        let mut config = base_config.clone();
+        config.cell_reconciliation = Some(CellReconciliation {
+            interval_secs: 0,
+            exclude_unknown: false,
+        });
+        assert!(matches!(
+            config.validate().unwrap_err(),
+            ValidationError::InvalidCellReconciliation(_)
+        ));
+
+        // Test traffic report without a window
+        let mut config = base_config.clone();
+        config.traffic_report = Some(TrafficReport { window_secs: 0 });
+        assert!(matches!(
+            config.validate().unwrap_err(),
+            ValidationError::InvalidTrafficReport(_)
+        ));
+
         // Test locality with no cells
         let mut config = base_config.clone();
         config.localities.insert("locality".to_string(), Vec::new());
@@ -461,6 +1292,8 @@ relay_keys:
             http_timeout_secs: 20,
             task_initial_timeout_secs: 15, // Less than HTTP timeout
             task_subsequent_timeout_secs: 5,
+            late_response_ttl_secs: None,
+            ..RelayTimeouts::default()
         };
         assert!(matches!(
             config.validate().unwrap_err(),
@@ -473,11 +1306,21 @@ relay_keys:
             http_timeout_secs: 15,
             task_initial_timeout_secs: 20,
             task_subsequent_timeout_secs: 0, // Zero timeout
+            late_response_ttl_secs: None,
+            ..RelayTimeouts::default()
         };
         assert!(matches!(
             config.validate().unwrap_err(),
             ValidationError::InvalidTimeouts(_)
         ));
+
+        // Test invalid timeouts: late_response_ttl = 0
+        let mut config = base_config.clone();
+        config.relay_timeouts.late_response_ttl_secs = Some(0);
+        assert!(matches!(
+            config.validate().unwrap_err(),
+            ValidationError::InvalidTimeouts(_)
+        ));
     }
 
     #[test]
@@ -551,5 +1394,87 @@ handler: relay_project_configs
         )
         .unwrap();
         assert_eq!(action, HandlerAction::RelayProjectConfigs);
+
+        let action: HandlerAction = serde_yaml::from_str("handler: ndjson_merge").unwrap();
+        assert_eq!(action, HandlerAction::NdjsonMerge { source_field: None });
+        let action: HandlerAction =
+            serde_yaml::from_str("{handler: ndjson_merge, source_field: cell}").unwrap();
+        assert_eq!(
+            action,
+            HandlerAction::NdjsonMerge {
+                source_field: Some("cell".into())
+            }
+        );
+        let action: HandlerAction =
+            serde_yaml::from_str("{handler: paginated_merge, source_field: cell}").unwrap();
+        assert_eq!(
+            action,
+            HandlerAction::PaginatedMerge {
+                source_field: Some("cell".into())
+            }
+        );
+        let action: HandlerAction = serde_yaml::from_str(
+            r#"
+handler: single_project
+key_from:
+  - source: query
+    name: sentry_key
+  - source: auth_header
+  - source: path_segment
+    index: 2
+  - source: json_body
+    pointer: /dsn
+"#,
+        )
+        .unwrap();
+        assert_eq!(
+            action,
+            HandlerAction::SingleProject {
+                key_from: vec![
+                    KeySource::Query {
+                        name: "sentry_key".into()
+                    },
+                    KeySource::AuthHeader,
+                    KeySource::PathSegment { index: 2 },
+                    KeySource::JsonBody {
+                        pointer: "/dsn".into()
+                    },
+                ]
+            }
+        );
+    }
+
+    #[test]
+    fn test_sharded_locator_deserialization() {
+        let locator: Locator = serde_yaml::from_str(
+            r#"
+type: sharded
+shards:
+  urls: ["http://locator-0:3000", "http://locator-1:3000"]
+"#,
+        )
+        .unwrap();
+        assert_eq!(
+            locator.r#type,
+            LocatorType::Sharded {
+                shards: ShardTopology::Urls(vec![
+                    "http://locator-0:3000".into(),
+                    "http://locator-1:3000".into()
+                ]),
+                api_key: None,
+            }
+        );
+
+        let locator: Locator = serde_yaml::from_str(
+            "{type: sharded, shards: {discovery_url: \"http://locator:3000\"}, api_key: key}",
+        )
+        .unwrap();
+        assert_eq!(
+            locator.r#type,
+            LocatorType::Sharded {
+                shards: ShardTopology::DiscoveryUrl("http://locator:3000".into()),
+                api_key: Some("key".into()),
+            }
+        );
     }
 }
@@ -21,6 +21,16 @@ pub enum IngestRouterError {
     #[error("Upstream timeout for {0}")]
     UpstreamTimeout(String),
 
+    #[error("Request task panicked for {0}")]
+    CellTaskPanicked(String),
+
+    #[error("Panic breaker open for {0}")]
+    PanicBreakerOpen(String),
+
+    /// The cell exceeded its max_rps, with the wait until it accepts a request again
+    #[error("Rate limit exceeded for {0}")]
+    RateLimited(String, std::time::Duration),
+
     #[error("Response serialization error: {0}")]
     ResponseSerializationError(String),
 
@@ -57,4 +67,13 @@ pub enum IngestRouterError {
 
     #[error("Relay signer configuration error: {0}")]
     RelaySignerError(#[from] crate::auth::SigningError),
+
+    #[error("Cell TLS configuration error: {0}")]
+    TlsConfigError(#[from] crate::tls::TlsError),
+
+    #[error("Listener TLS configuration error: {0}")]
+    ListenerTlsError(#[from] shared::tls::TlsError),
+
+    #[error("Invalid configuration: {0}")]
+    InvalidConfig(#[from] crate::config::ValidationError),
 }
@@ -1,81 +1,291 @@
-use crate::auth::{RelaySigner, RelayVerifier};
-use crate::config::RelayTimeouts;
+use crate::api::utils::normalize_headers;
+use crate::auth::{RELAY_ID_HEADER, RelaySigner, RelayVerifier};
+use crate::config::{self, CellTarget, RelayTimeouts};
 use crate::errors::IngestRouterError;
-use crate::handler::{CellId, ExecutionMode, Handler};
-use crate::http::send_to_upstream;
+use crate::handler::{BodyPrefix, CellId, ExecutionMode, Handler, ResponseReceivedAt, RoutedCells};
+use crate::http::{
+    RequestBody, ResponseBody, full_body, send_to_upstream, send_to_upstream_streaming,
+};
+use crate::late_responses::{self, LateResponses, OriginRelay};
 use crate::locality::Cells;
-use crate::metrics_defs::UPSTREAM_REQUEST_DURATION;
+use crate::metrics_defs::{
+    CELL_RETRIES, LATE_RESPONSES, PANIC_BREAKER_REJECTED, UPSTREAM_REQUEST_DURATION,
+};
+use crate::panic_breaker::{self, PanicBreaker};
+use crate::rate_limit::{self, CellRateLimits};
+use crate::streaming::{self, LINE_BUFFER, MergedLines};
+use crate::tls::{CellClients, HttpClient};
+use crate::traffic_report::TrafficReport;
 use http::StatusCode;
-use http_body_util::Full;
-use hyper::body::Bytes;
+use http_body_util::BodyExt;
+use hyper::body::{Bytes, Incoming};
+use hyper::header::{HeaderValue, RETRY_AFTER};
 use hyper::{Request, Response};
-use hyper_util::client::legacy::Client;
-use hyper_util::client::legacy::connect::HttpConnector;
-use hyper_util::rt::TokioExecutor;
 use shared::http::make_error_response;
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
 use std::sync::atomic::{AtomicU64, Ordering};
 use std::time::Instant;
-use tokio::task::JoinSet;
+use tokio::sync::mpsc;
+use tokio::task::{self, JoinError, JoinSet};
 use tokio::time::{Duration, sleep};
 
 // Counter for 1% metric sampling.
 static UPSTREAM_REQUEST_COUNT: AtomicU64 = AtomicU64::new(0);
 
+/// Result of a cell request, with its key in the late responses if they are kept
+type ParallelResult = (
+    CellId,
+    Option<late_responses::Key>,
+    Result<Response<Bytes>, IngestRouterError>,
+);
+
 #[derive(Clone)]
 pub struct Executor {
-    client: Client<HttpConnector, Full<Bytes>>,
+    clients: Arc<CellClients>,
     timeouts: RelayTimeouts,
     verifier: Arc<RelayVerifier>,
     signer: Arc<RelaySigner>,
+    late_responses: Option<Arc<LateResponses>>,
+    panic_breaker: Option<Arc<PanicBreaker>>,
+    rate_limits: Arc<CellRateLimits>,
+    traffic_report: Option<Arc<TrafficReport>>,
 }
 
 impl Executor {
     pub fn new(timeouts: RelayTimeouts, verifier: RelayVerifier, signer: RelaySigner) -> Self {
-        let client = Client::builder(TokioExecutor::new()).build(HttpConnector::new());
+        let late_responses = timeouts
+            .late_response_ttl_secs
+            .map(|ttl_secs| Arc::new(LateResponses::new(Duration::from_secs(ttl_secs))));
         Self {
-            client,
+            clients: Arc::new(CellClients::default()),
             timeouts,
             verifier: Arc::new(verifier),
             signer: Arc::new(signer),
+            late_responses,
+            panic_breaker: None,
+            rate_limits: Arc::new(CellRateLimits::default()),
+            traffic_report: None,
         }
     }
 
+    /// Sends requests to the cells with their own clients, for cells with TLS settings
+    pub fn with_cell_clients(mut self, clients: CellClients) -> Self {
+        self.clients = Arc::new(clients);
+        self
+    }
+
+    /// Stops sending requests to cells whose request tasks keep panicking
+    pub fn with_panic_breaker(mut self, config: config::PanicBreaker) -> Self {
+        self.panic_breaker = Some(Arc::new(PanicBreaker::new(config)));
+        self
+    }
+
+    /// Limits the requests sent to the cells with `max_rps`
+    pub fn with_rate_limits(mut self, rate_limits: CellRateLimits) -> Self {
+        self.rate_limits = Arc::new(rate_limits);
+        self
+    }
+
+    /// Records the requests sent to each cell and whether they failed
+    pub fn with_traffic_report(mut self, traffic_report: Arc<TrafficReport>) -> Self {
+        self.traffic_report = Some(traffic_report);
+        self
+    }
+
     // Verifies, splits, executes, and merges the responses using the provided handler.
     pub async fn execute(
         &self,
         handler: Arc<dyn Handler>,
         request: Request<Bytes>,
         cells: Cells,
-    ) -> Response<Bytes> {
+    ) -> Response<ResponseBody> {
         if handler.requires_relay_auth()
             && let Err(err) = self
                 .verifier
                 .verify_request(request.headers(), request.body())
         {
             tracing::warn!(error = %err, handler = handler.name(), "relay signature verification failed");
-            return make_error_response(StatusCode::UNAUTHORIZED);
+            return make_error_response(StatusCode::UNAUTHORIZED).map(full_body);
         }
 
+        self.dispatch(handler, request, cells).await
+    }
+
+    // Splits, executes, and merges the responses without verifying the request. Used for
+    // requests originating in synapse itself, such as the canary.
+    pub(crate) async fn dispatch(
+        &self,
+        handler: Arc<dyn Handler>,
+        mut request: Request<Bytes>,
+        cells: Cells,
+    ) -> Response<ResponseBody> {
+        if let ExecutionMode::PassThrough(_) = handler.execution_mode() {
+            // The whole body is buffered already
+            if handler.body_prefix_limit().is_some() {
+                let prefix = BodyPrefix(request.body().clone());
+                request.extensions_mut().insert(prefix);
+            }
+            return self
+                .pass_through(handler, request.map(full_body), cells)
+                .await;
+        }
+
+        // Replaced by synapse's own id when the split requests are signed
+        let request_relay_id = request.headers().get(&RELAY_ID_HEADER).cloned();
         let (mut split_requests, metadata) = match handler.split_request(request, &cells).await {
             Ok(result) => result,
-            Err(_e) => return make_error_response(StatusCode::INTERNAL_SERVER_ERROR),
+            Err(_e) => {
+                return make_error_response(StatusCode::INTERNAL_SERVER_ERROR).map(full_body);
+            }
         };
 
         if handler.requires_relay_auth() {
+            let origin = request_relay_id.map(OriginRelay);
             for (_cell_id, request) in split_requests.iter_mut() {
-                let body = request.body().clone();
-                self.signer.sign_request(request.headers_mut(), &body);
+                if let Some(origin) = &origin {
+                    request.extensions_mut().insert(origin.clone());
+                }
+                self.sign_request(request);
             }
         }
 
-        let results = match handler.execution_mode() {
-            ExecutionMode::Parallel => self.execute_parallel(split_requests, cells).await,
+        let routed_cells: Vec<String> = split_requests
+            .iter()
+            .map(|(cell_id, _)| cell_id.clone())
+            .collect();
+
+        // Failover only takes a token of the cells it tries
+        let mut rate_limited = Vec::new();
+        if !matches!(handler.execution_mode(), ExecutionMode::Failover) {
+            split_requests.retain(|(cell_id, _)| {
+                match self.rate_limits.try_acquire(cell_id, Instant::now()) {
+                    Ok(()) => true,
+                    Err(wait) => {
+                        rate_limited.push((
+                            cell_id.clone(),
+                            Err(IngestRouterError::RateLimited(cell_id.clone(), wait)),
+                        ));
+                        false
+                    }
+                }
+            });
+        }
+
+        let locality = cells.locality().to_string();
+        let mut results = match handler.execution_mode() {
+            // Pass-through requests are passed through before being split, see `dispatch`
+            ExecutionMode::Parallel | ExecutionMode::PassThrough(_) => {
+                self.execute_parallel(split_requests, cells).await
+            }
             ExecutionMode::Failover => self.execute_failover(split_requests, cells).await,
+            ExecutionMode::Streaming => {
+                match self
+                    .execute_streaming(handler.clone(), split_requests, cells)
+                    .await
+                {
+                    Ok(mut response) => {
+                        if let Some(traffic_report) = &self.traffic_report {
+                            for cell_id in routed_cells.iter().map(String::as_str) {
+                                traffic_report.record_request(
+                                    &locality,
+                                    cell_id,
+                                    false,
+                                    Instant::now(),
+                                );
+                            }
+                        }
+                        response.extensions_mut().insert(RoutedCells(routed_cells));
+                        return response;
+                    }
+                    Err(failures) => failures,
+                }
+            }
         };
+        results.extend(rate_limited);
+        if let Some(traffic_report) = &self.traffic_report {
+            for (cell_id, result) in &results {
+                let failed = !result
+                    .as_ref()
+                    .is_ok_and(|response| response.status().is_success());
+                traffic_report.record_request(&locality, cell_id, failed, Instant::now());
+            }
+        }
 
-        handler.merge_responses(results, metadata).await
+        let mut response = match rate_limited_wait(&results) {
+            Some(wait) => rate_limited_response(wait),
+            None => handler
+                .merge_responses(results, metadata)
+                .await
+                .map(full_body),
+        };
+        response.extensions_mut().insert(RoutedCells(routed_cells));
+        response
+    }
+
+    /// Passes the request through to the cell found from its head, with both bodies
+    /// streamed, see `ExecutionMode::PassThrough`. The cell's response headers must arrive
+    /// within the HTTP timeout, which includes sending the request body.
+    pub async fn pass_through(
+        &self,
+        handler: Arc<dyn Handler>,
+        request: Request<RequestBody>,
+        cells: Cells,
+    ) -> Response<ResponseBody> {
+        let ExecutionMode::PassThrough(target) = handler.execution_mode() else {
+            return make_error_response(StatusCode::INTERNAL_SERVER_ERROR).map(full_body);
+        };
+        let (parts, body) = request.into_parts();
+        let cell_id = match handler.route_head(&parts, &cells).await {
+            Ok(cell_id) => cell_id,
+            Err(status) => return make_error_response(status).map(full_body),
+        };
+
+        let result = match self.rate_limits.try_acquire(&cell_id, Instant::now()) {
+            Ok(()) => {
+                send_to_cell_pass_through(
+                    self.clients.get(&cell_id),
+                    &cell_id,
+                    Request::from_parts(parts, body),
+                    &cells,
+                    target,
+                    self.timeouts.http_timeout_secs,
+                )
+                .await
+            }
+            Err(wait) => Err(IngestRouterError::RateLimited(cell_id.clone(), wait)),
+        };
+        if let Some(traffic_report) = &self.traffic_report {
+            let failed = !result
+                .as_ref()
+                .is_ok_and(|response| response.status().is_success());
+            traffic_report.record_request(cells.locality(), &cell_id, failed, Instant::now());
+        }
+
+        let mut response = match result {
+            Ok(response) => response.map(|body| {
+                body.map_err(|e| IngestRouterError::ResponseBodyError(e.to_string()))
+                    .boxed()
+            }),
+            Err(IngestRouterError::RateLimited(_, wait)) => rate_limited_response(wait),
+            Err(e) => {
+                tracing::warn!(
+                    cell_id = %cell_id,
+                    error = %e,
+                    "{} request failed",
+                    handler.name()
+                );
+                make_error_response(StatusCode::SERVICE_UNAVAILABLE).map(full_body)
+            }
+        };
+        response.extensions_mut().insert(RoutedCells(vec![cell_id]));
+        response
+    }
+
+    /// Signs the request with synapse's own relay credentials
+    pub(crate) fn sign_request(&self, request: &mut Request<Bytes>) {
+        let body = request.body().clone();
+        self.signer.sign_request(request.headers_mut(), &body);
     }
 
     /// Execute split requests in parallel against their cell upstreams
@@ -85,38 +295,96 @@ impl Executor {
         cells: Cells,
     ) -> Vec<(CellId, Result<Response<Bytes>, IngestRouterError>)> {
         let mut join_set = JoinSet::new();
+        // Failed requests are not retried after the first result is due
+        let deadline =
+            Instant::now() + Duration::from_secs(self.timeouts.task_initial_timeout_secs);
 
         let mut pending_cells = HashSet::new();
+        let mut task_cells = HashMap::new();
+        let mut results = Vec::new();
 
         // Spawn requests for each cell
         for (cell_id, request) in requests {
+            let key = self
+                .late_responses
+                .as_ref()
+                .map(|_| LateResponses::key(&cell_id, &request));
+            // A response to an identical earlier request arrived too late for it
+            if let Some(late_responses) = &self.late_responses
+                && let Some(key) = &key
+                && let Some(response) = late_responses.take(key, Instant::now())
+            {
+                metrics::counter!(
+                    LATE_RESPONSES.name,
+                    "cell_id" => cell_id.clone(),
+                    "outcome" => "used",
+                )
+                .increment(1);
+                results.push((cell_id, Ok(response)));
+                continue;
+            }
+            if let Some(breaker) = &self.panic_breaker
+                && breaker.is_open(&cell_id, Instant::now())
+            {
+                metrics::counter!(PANIC_BREAKER_REJECTED.name, "cell_id" => cell_id.clone())
+                    .increment(1);
+                results.push((
+                    cell_id.clone(),
+                    Err(IngestRouterError::PanicBreakerOpen(cell_id)),
+                ));
+                continue;
+            }
+
             let cells = cells.clone();
-            let client = self.client.clone();
-            let timeout_secs = self.timeouts.http_timeout_secs;
+            let client = self.clients.get(&cell_id).clone();
+            let timeouts = self.timeouts.clone();
 
             pending_cells.insert(cell_id.clone());
-            join_set.spawn(async move {
-                let result = send_to_cell(&client, &cell_id, request, &cells, timeout_secs).await;
-                (cell_id, result)
+            let task_cell_id = cell_id.clone();
+            let handle = join_set.spawn(async move {
+                let result = send_to_cell_with_retries(
+                    &client, &cell_id, request, &cells, &timeouts, deadline,
+                )
+                .await;
+                (cell_id, key, result)
             });
+            task_cells.insert(handle.id(), task_cell_id);
         }
 
-        let mut results = Vec::new();
+        if join_set.is_empty() {
+            return results;
+        }
 
-        // Use the longer initial timeout for the first result
-        let initial_timeout = sleep(Duration::from_secs(self.timeouts.task_initial_timeout_secs));
+        // Use the longer initial timeout for the first result, unless a late response
+        // already is one
+        if results.is_empty() {
+            let initial_timeout =
+                sleep(Duration::from_secs(self.timeouts.task_initial_timeout_secs));
 
-        tokio::select! {
-            _ = initial_timeout => {},
-            join_result = join_set.join_next() => {
-                match join_result {
-                    Some(Ok((cell_id, result))) => {
-                        pending_cells.remove(&cell_id);
-                        results.push((cell_id, result));
+            tokio::select! {
+                _ = initial_timeout => {},
+                join_result = join_set.join_next_with_id() => {
+                    match join_result {
+                        Some(Ok((_, (cell_id, _key, result)))) => {
+                            pending_cells.remove(&cell_id);
+                            results.push((cell_id, result));
+                        }
+                        Some(Err(e)) => {
+                            if let Some(cell_id) = report_task_failure(
+                                e,
+                                &task_cells,
+                                self.panic_breaker.as_deref(),
+                            ) {
+                                pending_cells.remove(&cell_id);
+                                results.push((
+                                    cell_id.clone(),
+                                    Err(IngestRouterError::CellTaskPanicked(cell_id)),
+                                ));
+                            }
+                        }
+                        // The join set is empty -- this should never happen
+                        None => return results,
                     }
-                    Some(Err(e)) => tracing::error!("Task panicked: {}", e),
-                    // The join set is empty -- this should never happen
-                    None => return results,
                 }
             }
         }
@@ -132,13 +400,25 @@ impl Executor {
                 _ = &mut timeout => {
                     break;
                 },
-                join_result = join_set.join_next() => {
+                join_result = join_set.join_next_with_id() => {
                     match join_result {
-                        Some(Ok((cell_id, result))) => {
+                        Some(Ok((_, (cell_id, _key, result)))) => {
                             pending_cells.remove(&cell_id);
                             results.push((cell_id, result));
                         },
-                        Some(Err(e)) => tracing::error!("Task panicked: {}", e),
+                        Some(Err(e)) => {
+                            if let Some(cell_id) = report_task_failure(
+                                e,
+                                &task_cells,
+                                self.panic_breaker.as_deref(),
+                            ) {
+                                pending_cells.remove(&cell_id);
+                                results.push((
+                                    cell_id.clone(),
+                                    Err(IngestRouterError::CellTaskPanicked(cell_id)),
+                                ));
+                            }
+                        }
                         // No more tasks
                         None => break,
                     }
@@ -146,6 +426,18 @@ impl Executor {
             }
         }
 
+        // Dropping the join set would abort the remaining requests
+        if let Some(late_responses) = &self.late_responses
+            && !join_set.is_empty()
+        {
+            tokio::spawn(keep_late_responses(
+                join_set,
+                task_cells,
+                late_responses.clone(),
+                self.panic_breaker.clone(),
+            ));
+        }
+
         // Add all remaining pending cells to results
         for cell_id in pending_cells.drain() {
             results.push((
@@ -157,6 +449,87 @@ impl Executor {
         results
     }
 
+    /// Execute split requests in parallel and stream the lines of all successful responses
+    /// as they arrive. Responds as soon as the first cell responds with a success status,
+    /// the headers are taken from that cell's response. Returns the results of all cells
+    /// if none succeeds.
+    async fn execute_streaming(
+        &self,
+        handler: Arc<dyn Handler>,
+        requests: Vec<(CellId, Request<Bytes>)>,
+        cells: Cells,
+    ) -> Result<Response<ResponseBody>, Vec<(CellId, Result<Response<Bytes>, IngestRouterError>)>>
+    {
+        let (lines_tx, lines_rx) = mpsc::channel(LINE_BUFFER);
+        // Success with the response headers, or the failed result, of each cell
+        let (status_tx, mut status_rx) = mpsc::unbounded_channel();
+
+        // Not in a join set, the tasks keep streaming after this returns
+        for (cell_id, request) in requests {
+            let cells = cells.clone();
+            let client = self.clients.get(&cell_id).clone();
+            let timeout_secs = self.timeouts.http_timeout_secs;
+            let handler = handler.clone();
+            let lines_tx = lines_tx.clone();
+            let status_tx = status_tx.clone();
+
+            tokio::spawn(async move {
+                let result =
+                    send_to_cell_streaming(&client, &cell_id, request, &cells, timeout_secs).await;
+                match result {
+                    Ok(response) if response.status().is_success() => {
+                        let (parts, body) = response.into_parts();
+                        let _ = status_tx.send((cell_id.clone(), Ok(parts)));
+                        let idle_timeout = Duration::from_secs(timeout_secs);
+                        streaming::forward_lines(handler, cell_id, body, lines_tx, idle_timeout)
+                            .await;
+                    }
+                    Ok(response) => {
+                        tracing::warn!(
+                            cell_id = %cell_id,
+                            status = %response.status(),
+                            "{} failed with non-success status",
+                            handler.name()
+                        );
+                        let (parts, body) = response.into_parts();
+                        let result = body
+                            .collect()
+                            .await
+                            .map(|collected| Response::from_parts(parts, collected.to_bytes()))
+                            .map_err(|e| IngestRouterError::ResponseBodyError(e.to_string()));
+                        let _ = status_tx.send((cell_id, Err(result)));
+                    }
+                    Err(e) => {
+                        tracing::warn!(
+                            cell_id = %cell_id,
+                            error = %e,
+                            "{} request failed",
+                            handler.name()
+                        );
+                        let _ = status_tx.send((cell_id, Err(Err(e))));
+                    }
+                }
+            });
+        }
+        // The channels close once all tasks are done
+        drop(lines_tx);
+        drop(status_tx);
+
+        let mut failures = Vec::new();
+        while let Some((cell_id, status)) = status_rx.recv().await {
+            match status {
+                Ok(mut parts) => {
+                    normalize_headers(&mut parts.headers, parts.version);
+                    let body = MergedLines::new(lines_rx).boxed();
+                    return Ok(Response::from_parts(parts, body));
+                }
+                Err(result) => failures.push((cell_id, result)),
+            }
+        }
+
+        Err(failures)
+    }
+
     /// Execute requests sequentially in priority order, stopping on first success
     /// If no success, returns all failures
     async fn execute_failover(
@@ -167,8 +540,17 @@ impl Executor {
         let mut failures = Vec::new();
 
         for (cell_id, request) in requests {
+            if let Err(wait) = self.rate_limits.try_acquire(&cell_id, Instant::now()) {
+                tracing::debug!(cell_id = %cell_id, "Failover: cell rate limited, trying next cell");
+                failures.push((
+                    cell_id.clone(),
+                    Err(IngestRouterError::RateLimited(cell_id, wait)),
+                ));
+                continue;
+            }
+
             let result = send_to_cell(
-                &self.client,
+                self.clients.get(&cell_id),
                 &cell_id,
                 request,
                 &cells,
@@ -203,33 +585,220 @@ impl Executor {
     }
 }
 
+/// The shortest wait until a cell accepts a request again, if every cell of the request was
+/// rate limited.
+fn rate_limited_wait(
+    results: &[(CellId, Result<Response<Bytes>, IngestRouterError>)],
+) -> Option<Duration> {
+    let mut shortest: Option<Duration> = None;
+    for (_, result) in results {
+        let Err(IngestRouterError::RateLimited(_, wait)) = result else {
+            return None;
+        };
+        shortest = Some(shortest.unwrap_or(*wait).min(*wait));
+    }
+    shortest
+}
+
+/// Rejection of a request whose cells are all rate limited, see `rate_limit`.
+fn rate_limited_response(wait: Duration) -> Response<ResponseBody> {
+    let mut response = make_error_response(StatusCode::TOO_MANY_REQUESTS).map(full_body);
+    response.headers_mut().insert(
+        RETRY_AFTER,
+        HeaderValue::from(rate_limit::retry_after_secs(wait)),
+    );
+    response
+}
+
+/// Keeps the successful responses of requests that are still running after their deadline.
+async fn keep_late_responses(
+    mut join_set: JoinSet<ParallelResult>,
+    task_cells: HashMap<task::Id, CellId>,
+    late_responses: Arc<LateResponses>,
+    panic_breaker: Option<Arc<PanicBreaker>>,
+) {
+    while let Some(join_result) = join_set.join_next_with_id().await {
+        let (cell_id, key, response) = match join_result {
+            Ok((_, (cell_id, Some(key), Ok(response)))) => (cell_id, key, response),
+            Ok(_) => continue,
+            Err(e) => {
+                report_task_failure(e, &task_cells, panic_breaker.as_deref());
+                continue;
+            }
+        };
+        if !response.status().is_success() {
+            continue;
+        }
+
+        let outcome = if late_responses.insert(key, response, Instant::now()) {
+            "cached"
+        } else {
+            "dropped"
+        };
+        metrics::counter!(
+            LATE_RESPONSES.name,
+            "cell_id" => cell_id,
+            "outcome" => outcome,
+        )
+        .increment(1);
+    }
+}
+
+/// Reports a cell request task that did not complete, see `panic_breaker`. Returns the cell
+/// of a task that panicked.
+fn report_task_failure(
+    error: JoinError,
+    task_cells: &HashMap<task::Id, CellId>,
+    breaker: Option<&PanicBreaker>,
+) -> Option<CellId> {
+    let cell_id = task_cells.get(&error.id())?.clone();
+    match error.try_into_panic() {
+        Ok(payload) => {
+            panic_breaker::report(&cell_id, payload.as_ref(), breaker);
+            Some(cell_id)
+        }
+        Err(error) => {
+            tracing::error!(cell_id, %error, "Cell request task did not complete");
+            None
+        }
+    }
+}
+
+/// Sends a request to a cell, retrying it with a jittered exponential backoff while it fails
+/// to reach the cell, up to `cell_request_attempts` times. No retry is started that would
+/// not begin before `deadline`.
+async fn send_to_cell_with_retries(
+    client: &HttpClient,
+    cell_id: &str,
+    request: Request<Bytes>,
+    cells: &Cells,
+    timeouts: &RelayTimeouts,
+    deadline: Instant,
+) -> Result<Response<Bytes>, IngestRouterError> {
+    let mut attempt = 1;
+    loop {
+        if attempt == timeouts.cell_request_attempts {
+            return send_to_cell(client, cell_id, request, cells, timeouts.http_timeout_secs).await;
+        }
+
+        let mut retried = Request::new(request.body().clone());
+        *retried.method_mut() = request.method().clone();
+        *retried.uri_mut() = request.uri().clone();
+        *retried.version_mut() = request.version();
+        *retried.headers_mut() = request.headers().clone();
+
+        let result =
+            send_to_cell(client, cell_id, retried, cells, timeouts.http_timeout_secs).await;
+        let Err(IngestRouterError::UpstreamRequestFailed(_, error)) = &result else {
+            return result;
+        };
+
+        let delay = retry_delay(timeouts.cell_retry_backoff_ms, attempt);
+        if Instant::now() + delay >= deadline {
+            return result;
+        }
+        tracing::debug!(cell_id, attempt, %error, "Retrying failed cell request");
+        metrics::counter!(CELL_RETRIES.name, "cell_id" => cell_id.to_string()).increment(1);
+        sleep(delay).await;
+        attempt += 1;
+    }
+}
+
+/// Delay before the given retry (1-indexed): exponential backoff from `backoff_ms`, with up
+/// to half of the delay randomized away.
+fn retry_delay(backoff_ms: u64, retry: u32) -> Duration {
+    let backoff = backoff_ms.saturating_mul(2_u64.saturating_pow(retry - 1));
+    let random = getrandom::u64().unwrap_or(0) as f64 / u64::MAX as f64;
+    Duration::from_millis((backoff as f64 * (1.0 - 0.5 * random)) as u64)
+}
+
+/// Send a request to a specific cell's upstream, without reading the response body.
+async fn send_to_cell_streaming(
+    client: &HttpClient,
+    cell_id: &str,
+    request: Request<Bytes>,
+    cells: &Cells,
+    timeout_secs: u64,
+) -> Result<Response<Incoming>, IngestRouterError> {
+    let upstream = cells
+        .resolve_upstream(cell_id)
+        .ok_or_else(|| IngestRouterError::InternalError(format!("Unknown cell: {}", cell_id)))?;
+
+    let (parts, body) = request.into_parts();
+    let request = Request::from_parts(parts, full_body(body));
+
+    let start = Instant::now();
+    let result =
+        send_to_upstream_streaming(client, &upstream.relay_url, request, timeout_secs).await;
+    record_upstream_duration(cell_id, &result, start);
+    result
+}
+
+/// Send a request to the relay or sentry URL of a cell, without reading either body.
+async fn send_to_cell_pass_through(
+    client: &HttpClient,
+    cell_id: &str,
+    request: Request<RequestBody>,
+    cells: &Cells,
+    target: CellTarget,
+    timeout_secs: u64,
+) -> Result<Response<Incoming>, IngestRouterError> {
+    let upstream = cells
+        .resolve_upstream(cell_id)
+        .ok_or_else(|| IngestRouterError::InternalError(format!("Unknown cell: {}", cell_id)))?;
+    let url = match target {
+        CellTarget::Relay => &upstream.relay_url,
+        CellTarget::Sentry => &upstream.sentry_url,
+    };
+
+    send_to_upstream_streaming(client, url, request, timeout_secs).await
+}
+
 /// Send a request to a specific cell's upstream.
 async fn send_to_cell(
-    client: &Client<HttpConnector, Full<Bytes>>,
+    client: &HttpClient,
     cell_id: &str,
     request: Request<Bytes>,
     cells: &Cells,
     timeout_secs: u64,
 ) -> Result<Response<Bytes>, IngestRouterError> {
-    // Look up the upstream for this cell
+    // Look up the upstream for this cell. Cross-locality requests target cells outside
+    // the route's locality.
     let upstream = cells
-        .get_upstream(cell_id)
+        .resolve_upstream(cell_id)
         .ok_or_else(|| IngestRouterError::InternalError(format!("Unknown cell: {}", cell_id)))?;
 
-    // Wrap Bytes in Full for the HTTP client
+    // Wrap Bytes in a boxed body for the HTTP client
     let (parts, body) = request.into_parts();
-    let request = Request::from_parts(parts, Full::new(body));
+    let request = Request::from_parts(parts, full_body(body));
 
     // Send to upstream (using relay_url) - returns Response<Bytes>
     let start = Instant::now();
-    let result = send_to_upstream(client, &upstream.relay_url, request, timeout_secs).await;
+    let result = send_to_upstream(client, &upstream.relay_url, request, timeout_secs)
+        .await
+        .map(|mut response| {
+            response
+                .extensions_mut()
+                .insert(ResponseReceivedAt(Instant::now()));
+            response
+        });
 
-    // Record duration metric with status (1% sample)
+    record_upstream_duration(cell_id, &result, start);
+    result
+}
+
+/// Records the duration of a cell request with its status (1% sample). Streamed responses
+/// are timed until their headers arrive.
+fn record_upstream_duration<B>(
+    cell_id: &str,
+    result: &Result<Response<B>, IngestRouterError>,
+    start: Instant,
+) {
     if UPSTREAM_REQUEST_COUNT
         .fetch_add(1, Ordering::Relaxed)
         .is_multiple_of(100)
     {
-        let status = match &result {
+        let status = match result {
             Ok(response) => response.status().as_u16().to_string(),
             Err(IngestRouterError::UpstreamTimeout(_)) => "timeout".to_string(),
             Err(_) => "error".to_string(),
@@ -241,8 +810,6 @@ async fn send_to_cell(
         )
         .record(start.elapsed().as_secs_f64());
     }
-
-    result
 }
 
 #[cfg(test)]
@@ -251,6 +818,8 @@ mod tests {
     use crate::handler::SplitMetadata;
     use crate::testutils::make_signing_keypair;
     use async_trait::async_trait;
+    use http_body_util::Full;
+    use shared::testutils::MockServer;
 
     /// Minimal handler that requires relay auth; its split is never reached because verification
     /// rejects the request first.
@@ -301,6 +870,9 @@ mod tests {
                 id: "us1".to_string(),
                 sentry_url: Url::parse("http://localhost:8080").unwrap(),
                 relay_url: Url::parse("http://localhost:8090").unwrap(),
+                protocol_version: None,
+                tls: None,
+                max_rps: None,
             }],
         )]))
         .get_cells("us")
@@ -327,4 +899,380 @@ mod tests {
 
         assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
     }
+
+    /// Resets the first `resets` connections, then answers every request with 200 "ok"
+    async fn start_flaky_test_server(resets: usize) -> MockServer {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let connections = AtomicUsize::new(0);
+        MockServer::spawn_tcp(move |mut stream, _| {
+            let reset = connections.fetch_add(1, Ordering::SeqCst) < resets;
+            async move {
+                let mut buf = [0; 4096];
+                let _ = stream.read(&mut buf).await;
+                if !reset {
+                    let _ = stream
+                        .write_all(
+                            b"HTTP/1.1 200 OK\r\ncontent-length: 2\r\nconnection: close\r\n\r\nok",
+                        )
+                        .await;
+                }
+            }
+        })
+        .await
+    }
+
+    /// Cells whose relays are the given servers
+    fn local_cells(relays: &[(&str, &MockServer)]) -> Cells {
+        use crate::config::CellConfig;
+        use crate::locality::Localities;
+        use std::collections::HashMap;
+        use url::Url;
+
+        let cells = relays
+            .iter()
+            .map(|(id, relay)| CellConfig {
+                id: id.to_string(),
+                sentry_url: Url::parse("http://localhost:8080").unwrap(),
+                relay_url: Url::parse(&relay.url()).unwrap(),
+                protocol_version: None,
+                tls: None,
+                max_rps: None,
+            })
+            .collect();
+        Localities::new(HashMap::from([("us".to_string(), cells)]))
+            .get_cells("us")
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_execute_streaming() {
+        use crate::api::ndjson_merge_handler::NdjsonMergeHandler;
+
+        let us1 = MockServer::status(StatusCode::OK, "{\"id\":1}\n{\"id\":2}\n").await;
+        let us2 = MockServer::status(StatusCode::OK, "{\"id\":3}").await;
+        let failing = MockServer::status(StatusCode::INTERNAL_SERVER_ERROR, "").await;
+
+        let (signer, verifier) = make_signing_keypair();
+        let executor = Executor::new(RelayTimeouts::default(), verifier, signer);
+        let handler: Arc<dyn Handler> = Arc::new(NdjsonMergeHandler::new(Some("cell".into())));
+        let request = || {
+            Request::builder()
+                .uri("/api/0/stream/")
+                .body(Bytes::new())
+                .unwrap()
+        };
+
+        // The lines of all successful cells are streamed, the failed cell is skipped
+        let response = executor
+            .execute(
+                handler.clone(),
+                request(),
+                local_cells(&[("us1", &us1), ("us2", &us2), ("us3", &failing)]),
+            )
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let mut lines: Vec<&str> = std::str::from_utf8(&body).unwrap().lines().collect();
+        lines.sort();
+        assert_eq!(
+            lines,
+            vec![
+                r#"{"cell":"us1","id":1}"#,
+                r#"{"cell":"us1","id":2}"#,
+                r#"{"cell":"us2","id":3}"#,
+            ]
+        );
+
+        // No cell succeeded
+        let response = executor
+            .execute(handler, request(), local_cells(&[("us3", &failing)]))
+            .await;
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[tokio::test]
+    async fn test_execute_parallel_late_responses() {
+        let fast = MockServer::status(StatusCode::OK, "fast").await;
+        let slow = MockServer::spawn_async(|_| async {
+            sleep(Duration::from_millis(1500)).await;
+            Response::new(Full::new(Bytes::from_static(b"slow")))
+        })
+        .await;
+
+        let (signer, verifier) = make_signing_keypair();
+        let timeouts = RelayTimeouts {
+            task_subsequent_timeout_secs: 1,
+            late_response_ttl_secs: Some(60),
+            ..Default::default()
+        };
+        let executor = Executor::new(timeouts, verifier, signer);
+        let cells = local_cells(&[("us1", &fast), ("us2", &slow)]);
+        let requests = || {
+            ["us1", "us2"]
+                .map(|cell_id| {
+                    let request = Request::builder()
+                        .method("POST")
+                        .uri("/api/0/relays/projectconfigs/")
+                        .body(Bytes::from_static(b"{\"publicKeys\":[\"abc\"]}"))
+                        .unwrap();
+                    (cell_id.to_string(), request)
+                })
+                .to_vec()
+        };
+        let bodies = |results: Vec<(CellId, Result<Response<Bytes>, IngestRouterError>)>| {
+            let mut bodies: Vec<(CellId, Option<Bytes>)> = results
+                .into_iter()
+                .map(|(cell_id, result)| (cell_id, result.ok().map(|r| r.into_body())))
+                .collect();
+            bodies.sort();
+            bodies
+        };
+
+        // The slow cell misses the deadline, its response is kept once it arrives
+        let results = executor.execute_parallel(requests(), cells.clone()).await;
+        assert_eq!(
+            bodies(results),
+            vec![
+                ("us1".into(), Some(Bytes::from("fast"))),
+                ("us2".into(), None)
+            ]
+        );
+        let late_responses = executor.late_responses.clone().unwrap();
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while late_responses.is_empty() {
+                sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+
+        // The identical request takes the late response instead of waiting on the cell
+        let start = Instant::now();
+        let results = executor.execute_parallel(requests(), cells.clone()).await;
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert_eq!(
+            bodies(results),
+            vec![
+                ("us1".into(), Some(Bytes::from("fast"))),
+                ("us2".into(), Some(Bytes::from("slow")))
+            ]
+        );
+
+        // Late responses are used once
+        let results = executor.execute_parallel(requests(), cells).await;
+        assert_eq!(
+            bodies(results),
+            vec![
+                ("us1".into(), Some(Bytes::from("fast"))),
+                ("us2".into(), None)
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_execute_parallel_retries() {
+        let (signer, verifier) = make_signing_keypair();
+        let timeouts = RelayTimeouts {
+            cell_request_attempts: 2,
+            cell_retry_backoff_ms: 10,
+            ..Default::default()
+        };
+        let executor = Executor::new(timeouts, verifier, signer);
+        let request = || {
+            let request = Request::builder()
+                .method("POST")
+                .uri("/api/0/relays/projectconfigs/")
+                .body(Bytes::from_static(b"{\"publicKeys\":[\"abc\"]}"))
+                .unwrap();
+            vec![("us1".to_string(), request)]
+        };
+
+        // The reset connection is retried
+        let flaky = start_flaky_test_server(1).await;
+        let cells = local_cells(&[("us1", &flaky)]);
+        let results = executor.execute_parallel(request(), cells).await;
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].1.as_ref().unwrap().body(), "ok");
+
+        // Until the attempts are used up
+        let flaky = start_flaky_test_server(2).await;
+        let cells = local_cells(&[("us1", &flaky)]);
+        let results = executor.execute_parallel(request(), cells).await;
+        assert!(matches!(
+            results[0].1,
+            Err(IngestRouterError::UpstreamRequestFailed(_, _))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_rate_limits() {
+        use crate::api::any_cell_handler::AnyCellHandler;
+        use crate::api::ndjson_merge_handler::NdjsonMergeHandler;
+
+        let us1 = MockServer::status(StatusCode::OK, "{\"cell\":\"us1\"}").await;
+        let us2 = MockServer::status(StatusCode::OK, "{\"cell\":\"us2\"}").await;
+        let cells = local_cells(&[("us1", &us1), ("us2", &us2)]);
+        let mut localities = HashMap::from([("us".to_string(), Vec::new())]);
+        for id in ["us1", "us2"] {
+            localities.get_mut("us").unwrap().push(config::CellConfig {
+                id: id.to_string(),
+                sentry_url: url::Url::parse("http://localhost:8080").unwrap(),
+                relay_url: url::Url::parse("http://localhost:8090").unwrap(),
+                protocol_version: None,
+                tls: None,
+                max_rps: Some(1),
+            });
+        }
+
+        let (signer, verifier) = make_signing_keypair();
+        let executor = Executor::new(RelayTimeouts::default(), verifier, signer)
+            .with_rate_limits(CellRateLimits::from_config(&localities));
+        let request = || Request::new(Bytes::new());
+
+        // Failover moves on to the next cell while the first one is limited
+        let handler: Arc<dyn Handler> = Arc::new(AnyCellHandler::new("HealthCheck"));
+        for expected in ["us1", "us2"] {
+            let response = executor
+                .execute(handler.clone(), request(), cells.clone())
+                .await;
+            assert_eq!(response.status(), StatusCode::OK);
+            let body = response.into_body().collect().await.unwrap().to_bytes();
+            assert_eq!(body, format!("{{\"cell\":\"{expected}\"}}"));
+        }
+
+        // The request is rejected once all of its cells are limited
+        let handler: Arc<dyn Handler> = Arc::new(NdjsonMergeHandler::new(None));
+        let response = executor.execute(handler, request(), cells).await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()[RETRY_AFTER], "1");
+    }
+
+    #[test]
+    fn test_rate_limited_wait() {
+        let limited = |cell_id: &str, millis| {
+            (
+                cell_id.to_string(),
+                Err(IngestRouterError::RateLimited(
+                    cell_id.to_string(),
+                    Duration::from_millis(millis),
+                )),
+            )
+        };
+        assert_eq!(rate_limited_wait(&[]), None);
+        assert_eq!(
+            rate_limited_wait(&[limited("us1", 300), limited("us2", 200)]),
+            Some(Duration::from_millis(200))
+        );
+        assert_eq!(
+            rate_limited_wait(&[
+                limited("us1", 300),
+                ("us2".to_string(), Ok(Response::new(Bytes::new())))
+            ]),
+            None
+        );
+    }
+
+    /// Answers every request with its path and body
+    async fn start_echo_server() -> MockServer {
+        MockServer::spawn(|request| {
+            let echo = format!(
+                "{} {}",
+                request.uri().path(),
+                String::from_utf8_lossy(request.body())
+            );
+            Response::new(Full::new(Bytes::from(echo)))
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn test_pass_through() {
+        use crate::api::single_project::SingleProjectHandler;
+        use crate::config::{CellConfig, KeySource};
+        use crate::locality::Localities;
+        use locator::client::{Locator, LocatorConfig, LocatorType};
+        use locator::config::LocatorDataType;
+        use std::collections::HashMap;
+        use url::Url;
+
+        let echo = start_echo_server().await;
+        let cells = Localities::new(HashMap::from([(
+            "us".to_string(),
+            vec![CellConfig {
+                id: "us1".to_string(),
+                sentry_url: Url::parse(&echo.url()).unwrap(),
+                relay_url: Url::parse("http://127.0.0.1:1").unwrap(),
+                protocol_version: None,
+                tls: None,
+                max_rps: None,
+            }],
+        )]))
+        .get_cells("us")
+        .unwrap();
+        // Remote locator whose mappings are synced, so that unknown keys have no cell
+        let locator_api =
+            MockServer::locator(HashMap::from([("a".repeat(32), "us1".to_string())])).await;
+        let locator = Locator::new(LocatorConfig {
+            locator_type: LocatorType::Url {
+                url: locator_api.url(),
+                api_key: None,
+                datagram_addr: None,
+                cache: None,
+            },
+            data_type: LocatorDataType::ProjectKey,
+            caller: None,
+        })
+        .await
+        .unwrap();
+
+        let (signer, verifier) = make_signing_keypair();
+        let executor = Executor::new(RelayTimeouts::default(), verifier, signer);
+        let key_from = [KeySource::Query {
+            name: "sentry_key".into(),
+        }];
+        let handler: Arc<dyn Handler> = Arc::new(SingleProjectHandler::proxy_to_cell(
+            locator.clone(),
+            &key_from,
+            CellTarget::Sentry,
+        ));
+        let request = |key: &str| {
+            Request::builder()
+                .uri(format!("/api/1/upload/?sentry_key={key}"))
+                .body(full_body(Bytes::from_static(b"chunk")))
+                .unwrap()
+        };
+
+        // Sent to the sentry URL of the key's cell
+        let response = executor
+            .pass_through(handler.clone(), request(&"a".repeat(32)), cells.clone())
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.extensions().get::<RoutedCells>().unwrap().0,
+            vec!["us1".to_string()]
+        );
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(body, "/api/1/upload/ chunk");
+
+        // Rejected without a cell
+        let response = executor
+            .pass_through(handler.clone(), request(&"b".repeat(32)), cells.clone())
+            .await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        // The relay URL of the cell is unreachable
+        let handler: Arc<dyn Handler> = Arc::new(SingleProjectHandler::proxy_to_cell(
+            locator,
+            &key_from,
+            CellTarget::Relay,
+        ));
+        let buffered = Request::builder()
+            .uri(format!("/api/1/upload/?sentry_key={}", "a".repeat(32)))
+            .body(Bytes::new())
+            .unwrap();
+        let response = executor.execute(handler, buffered, cells).await;
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    }
 }
@@ -1,19 +1,52 @@
+use crate::config::CellTarget;
 use crate::errors::IngestRouterError;
 use crate::locality::Cells;
 use async_trait::async_trait;
+use http::StatusCode;
+use http::request::Parts;
 use hyper::body::Bytes;
 use hyper::{Request, Response};
 use std::any::Any;
+use std::time::Instant;
 
 pub type CellId = String;
 pub type SplitMetadata = Box<dyn Any + Send>;
 
+/// Time at which a cell's response was received. The executor inserts this into the
+/// extensions of every upstream response before they are merged.
+#[derive(Clone, Copy, Debug)]
+pub struct ResponseReceivedAt(pub Instant);
+
+/// Media type of the request, lowercased and without parameters (e.g. `application/json`).
+/// The router inserts this into the request extensions if the request has a content type,
+/// and it is always one of the route's accepted content types if the route restricts them.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RequestContentType(pub String);
+
+/// Cells a request was sent to. The executor inserts this into the extensions of the
+/// merged response.
+#[derive(Clone, Debug, Default)]
+pub struct RoutedCells(pub Vec<CellId>);
+
+/// Start of the body of a request passed through to a cell, up to its first newline. The
+/// router inserts this into the request extensions if the handler has a
+/// `body_prefix_limit`, before `route_head` is called.
+#[derive(Clone, Debug)]
+pub struct BodyPrefix(pub Bytes);
+
 pub enum ExecutionMode {
     // Requests are fanned out and executed in parallel across cells
     Parallel,
     // Requests are executed sequentially across cells in priority order
     // Subsequent requests are skipped if an earlier request succeeds
     Failover,
+    // Requests are fanned out in parallel and the line-delimited response bodies are
+    // interleaved into one streamed response as lines arrive, see `Handler::map_line`
+    Streaming,
+    // The request is sent to the URL of the cell found by `Handler::route_head` with its
+    // body streamed, and the cell's response is streamed back unmodified. Requests are
+    // neither split nor merged.
+    PassThrough(CellTarget),
 }
 
 /// Handler for endpoints that split requests across cells and merge results
@@ -33,6 +66,12 @@ pub trait Handler: Send + Sync {
         false
     }
 
+    /// Keys involved in the request (e.g. project keys), recorded in the audit log.
+    /// Only called if the audit log is enabled.
+    fn audit_keys(&self, _request: &Request<Bytes>) -> Vec<String> {
+        Vec::new()
+    }
+
     /// Split one request into multiple per-cell requests
     ///
     /// This method routes the request data to appropriate cells and builds
@@ -43,10 +82,30 @@ pub trait Handler: Send + Sync {
         cells: &Cells,
     ) -> Result<(Vec<(CellId, Request<Bytes>)>, SplitMetadata), IngestRouterError>;
 
+    /// Cell a request is passed through to, found from its head only, or the status the
+    /// request is rejected with. Only called in pass-through mode.
+    async fn route_head(&self, _parts: &Parts, _cells: &Cells) -> Result<CellId, StatusCode> {
+        Err(StatusCode::INTERNAL_SERVER_ERROR)
+    }
+
+    /// Longest start of the body, up to its first newline, that `route_head` reads from
+    /// the `BodyPrefix` extension. None if requests are routed from their head only. Only
+    /// called in pass-through mode.
+    fn body_prefix_limit(&self) -> Option<usize> {
+        None
+    }
+
+    /// Transform one line of a cell's streamed response before it is forwarded, without
+    /// the trailing newline. Only called in streaming mode.
+    fn map_line(&self, _cell_id: &str, line: Bytes) -> Bytes {
+        line
+    }
+
     /// Merge results from multiple cells into a single response
     ///
     /// This method combines responses from successful cells, handles failures,
-    /// and incorporates metadata from the split phase.
+    /// and incorporates metadata from the split phase. In streaming mode, it is only
+    /// called if no cell responded with a success status.
     async fn merge_responses(
         &self,
         responses: Vec<(CellId, Result<Response<Bytes>, IngestRouterError>)>,
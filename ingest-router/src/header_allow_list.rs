@@ -0,0 +1,104 @@
+//! Per-route allow-lists of the request headers forwarded to cells.
+//!
+//! Relays send many headers cells have no use for, and edges in front of synapse add
+//! internal ones that should not leak into cells. Routes with `forward_headers` only
+//! forward the listed headers, plus the ones cells always need: the content, auth and
+//! tracing headers. Routes without it forward all headers.
+use http::HeaderName;
+use http::header::{AUTHORIZATION, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE, HOST};
+use hyper::header::HeaderMap;
+use std::collections::HashSet;
+use std::sync::Arc;
+
+/// Forwarded by every allow-list
+const REQUIRED: &[&str] = &[
+    "x-sentry-auth",
+    "x-sentry-relay-id",
+    "x-sentry-relay-signature",
+    "sentry-trace",
+    "baggage",
+    "traceparent",
+    "tracestate",
+    "x-request-id",
+];
+
+#[derive(Clone, Debug)]
+pub struct HeaderAllowList {
+    names: Arc<HashSet<HeaderName>>,
+}
+
+impl HeaderAllowList {
+    /// Allow-list of the given header names, plus the required ones. Names that are not
+    /// valid header names are ignored, they are rejected when the config is validated.
+    pub fn new(names: &[String]) -> Self {
+        let required = [
+            AUTHORIZATION,
+            CONTENT_ENCODING,
+            CONTENT_LENGTH,
+            CONTENT_TYPE,
+            HOST,
+        ]
+        .into_iter()
+        .chain(REQUIRED.iter().map(|name| HeaderName::from_static(name)));
+        let configured = names
+            .iter()
+            .filter_map(|name| HeaderName::from_bytes(name.as_bytes()).ok());
+
+        Self {
+            names: Arc::new(required.chain(configured).collect()),
+        }
+    }
+
+    /// Removes all headers that are not allowed
+    pub fn apply(&self, headers: &mut HeaderMap) {
+        let removed: Vec<HeaderName> = headers
+            .keys()
+            .filter(|name| !self.names.contains(*name))
+            .cloned()
+            .collect();
+        for name in removed {
+            headers.remove(name);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http::HeaderValue;
+
+    #[test]
+    fn test_apply() {
+        let allow_list = HeaderAllowList::new(&["X-Sentry-Relay-Version".to_string()]);
+
+        let mut headers = HeaderMap::new();
+        for name in [
+            "x-sentry-relay-version",
+            "x-sentry-relay-id",
+            "sentry-trace",
+            "content-type",
+            "host",
+            "x-forwarded-for",
+            "x-edge-internal",
+        ] {
+            headers.insert(name, HeaderValue::from_static("value"));
+        }
+        headers.append("cookie", HeaderValue::from_static("a=1"));
+        headers.append("cookie", HeaderValue::from_static("b=2"));
+
+        allow_list.apply(&mut headers);
+
+        let mut remaining: Vec<_> = headers.keys().map(|name| name.as_str()).collect();
+        remaining.sort();
+        assert_eq!(
+            remaining,
+            [
+                "content-type",
+                "host",
+                "sentry-trace",
+                "x-sentry-relay-id",
+                "x-sentry-relay-version",
+            ]
+        );
+    }
+}
@@ -1,13 +1,104 @@
-use http_body_util::BodyExt;
-use hyper::body::Bytes;
+use http_body_util::combinators::BoxBody;
+use http_body_util::{BodyExt, Full};
+use hyper::body::{Body, Bytes, Frame, Incoming, SizeHint};
 use hyper::{Request, Response};
 use hyper_util::client::legacy::Client;
 use shared::http::{add_via_header, filter_hop_by_hop};
+use std::collections::VecDeque;
+use std::pin::Pin;
+use std::task::{Context, Poll};
 use std::time::Duration;
 use tokio::time::timeout;
 
 use crate::errors::IngestRouterError;
 
+/// Body of responses returned to clients, either buffered or streamed
+pub type ResponseBody = BoxBody<Bytes, IngestRouterError>;
+
+/// Body of requests sent to cells, either buffered or streamed from the client
+pub type RequestBody = BoxBody<Bytes, IngestRouterError>;
+
+/// Wraps a buffered body into a `ResponseBody`, or a `RequestBody`
+pub fn full_body(bytes: Bytes) -> ResponseBody {
+    Full::new(bytes).map_err(|never| match never {}).boxed()
+}
+
+/// Reads the start of a streamed body, up to its first newline or past `limit` bytes,
+/// without consuming it. Returns the bytes read and the whole body, which starts with the
+/// frames read.
+pub async fn read_prefix(
+    mut body: RequestBody,
+    limit: usize,
+) -> Result<(Bytes, RequestBody), IngestRouterError> {
+    let mut prefix = Vec::new();
+    let mut frames = VecDeque::new();
+    while prefix.len() <= limit {
+        let Some(frame) = body.frame().await else {
+            break;
+        };
+        let frame = frame?;
+        let newline = match frame.data_ref() {
+            Some(data) => {
+                prefix.extend_from_slice(data);
+                data.contains(&b'\n')
+            }
+            // Trailers end the body
+            None => true,
+        };
+        frames.push_back(frame);
+        if newline {
+            break;
+        }
+    }
+
+    let body = ReadAhead {
+        read: prefix.len() as u64,
+        frames,
+        rest: body,
+    };
+    Ok((Bytes::from(prefix), body.boxed()))
+}
+
+/// Frames read ahead from a body, followed by the rest of the body
+struct ReadAhead {
+    // Data bytes in `frames` when they were read
+    read: u64,
+    frames: VecDeque<Frame<Bytes>>,
+    rest: RequestBody,
+}
+
+impl Body for ReadAhead {
+    type Data = Bytes;
+    type Error = IngestRouterError;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, IngestRouterError>>> {
+        if let Some(frame) = self.frames.pop_front() {
+            if let Some(data) = frame.data_ref() {
+                self.read -= data.len() as u64;
+            }
+            return Poll::Ready(Some(Ok(frame)));
+        }
+        Pin::new(&mut self.rest).poll_frame(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.frames.is_empty() && self.rest.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        let rest = self.rest.size_hint();
+        let mut hint = SizeHint::new();
+        hint.set_lower(rest.lower() + self.read);
+        if let Some(upper) = rest.upper() {
+            hint.set_upper(upper + self.read);
+        }
+        hint
+    }
+}
+
 /// Send a request to a single upstream with configurable timeout
 ///
 /// This function handles the complete request/response cycle including:
@@ -27,12 +118,43 @@ use crate::errors::IngestRouterError;
 /// **Important**: This function is NOT suitable for:
 /// - Server-Sent Events (SSE)
 /// - Long-lived streaming connections
+///
+/// Use `send_to_upstream_streaming` for those.
 pub async fn send_to_upstream<C, B>(
     client: &Client<C, B>,
     upstream_url: &url::Url,
     request: Request<B>,
     timeout_secs: u64,
 ) -> Result<Response<Bytes>, IngestRouterError>
+where
+    C: hyper_util::client::legacy::connect::Connect + Clone + Send + Sync + 'static,
+    B: hyper::body::Body + Send + Unpin + 'static,
+    B::Data: Send,
+    B::Error: std::error::Error + Send + Sync + 'static,
+{
+    let response = send_to_upstream_streaming(client, upstream_url, request, timeout_secs).await?;
+
+    // Collect response body bytes
+    let (parts, body) = response.into_parts();
+    let body_bytes = body
+        .collect()
+        .await
+        .map(|collected| collected.to_bytes())
+        .map_err(|e| IngestRouterError::ResponseBodyError(e.to_string()))?;
+
+    Ok(Response::from_parts(parts, body_bytes))
+}
+
+/// Send a request to a single upstream, returning as soon as the response headers arrive
+///
+/// Same as `send_to_upstream`, except that the response body is left to the caller, for
+/// streamed responses. The timeout only applies until the response headers are received.
+pub async fn send_to_upstream_streaming<C, B>(
+    client: &Client<C, B>,
+    upstream_url: &url::Url,
+    request: Request<B>,
+    timeout_secs: u64,
+) -> Result<Response<Incoming>, IngestRouterError>
 where
     C: hyper_util::client::legacy::connect::Connect + Clone + Send + Sync + 'static,
     B: hyper::body::Body + Send + Unpin + 'static,
@@ -93,91 +215,43 @@ where
         IngestRouterError::UpstreamRequestFailed(upstream_identifier.to_string(), e.to_string())
     })?;
 
-    // Collect response body bytes and filter hop-by-hop headers
+    // Filter hop-by-hop headers
     let (mut parts, body) = response.into_parts();
     let response_version = parts.version;
     filter_hop_by_hop(&mut parts.headers, response_version);
     add_via_header(&mut parts.headers, response_version);
 
-    let body_bytes = body
-        .collect()
-        .await
-        .map(|collected| collected.to_bytes())
-        .map_err(|e| IngestRouterError::ResponseBodyError(e.to_string()))?;
-
-    Ok(Response::from_parts(parts, body_bytes))
+    Ok(Response::from_parts(parts, body))
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use http_body_util::Full;
-    use hyper::service::service_fn;
     use hyper_util::client::legacy::connect::HttpConnector;
     use hyper_util::rt::TokioExecutor;
-    use std::convert::Infallible;
-    use tokio::net::TcpListener;
-
-    // Simple echo server that returns the request body
-    async fn echo_handler(
-        req: Request<hyper::body::Incoming>,
-    ) -> Result<Response<Full<Bytes>>, Infallible> {
-        let (parts, body) = req.into_parts();
-
-        // Collect the request body
-        let body_bytes = body
-            .collect()
-            .await
-            .map(|collected| collected.to_bytes())
-            .unwrap_or_else(|_| Bytes::new());
-
-        // Echo back the request body with original headers
-        let mut response = Response::new(Full::new(body_bytes));
-        *response.headers_mut() = parts.headers;
-
-        Ok(response)
-    }
-
-    async fn start_test_server() -> u16 {
-        let listener = TcpListener::bind("127.0.0.1:0")
-            .await
-            .expect("Failed to bind to address");
-
-        let port = listener.local_addr().unwrap().port();
-
-        tokio::spawn(async move {
-            loop {
-                let (stream, _) = listener.accept().await.unwrap();
-                let io = hyper_util::rt::TokioIo::new(stream);
-
-                tokio::spawn(async move {
-                    if let Err(err) =
-                        hyper_util::server::conn::auto::Builder::new(TokioExecutor::new())
-                            .serve_connection(io, service_fn(echo_handler))
-                            .await
-                    {
-                        eprintln!("Error serving connection: {:?}", err);
-                    }
-                });
-            }
-        });
-
-        // Give the server a moment to start
-        tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
-
-        port
+    use shared::testutils::MockServer;
+
+    /// Server answering with the body and headers of the request
+    async fn start_test_server() -> MockServer {
+        MockServer::spawn(|request| {
+            let (parts, body) = request.into_parts();
+            let mut response = Response::new(Full::new(body));
+            *response.headers_mut() = parts.headers;
+            response
+        })
+        .await
     }
 
     #[tokio::test]
     async fn test_send_to_upstream_success() {
-        let port = start_test_server().await;
+        let upstream = start_test_server().await;
 
         let conn = HttpConnector::new();
         let client: Client<HttpConnector, Full<Bytes>> =
             Client::builder(TokioExecutor::new()).build(conn);
 
-        let upstream_url =
-            url::Url::parse(&format!("http://127.0.0.1:{}", port)).expect("Failed to parse URL");
+        let upstream_url = url::Url::parse(&upstream.url()).expect("Failed to parse URL");
 
         let content = b"hello world";
         let request = Request::builder()
@@ -204,6 +278,43 @@ mod tests {
         assert!(!response.headers().contains_key("connection"));
     }
 
+    #[tokio::test]
+    async fn test_read_prefix() {
+        // Body of one data frame per chunk
+        let chunked = |chunks: &[&'static [u8]]| {
+            ReadAhead {
+                read: chunks.iter().map(|chunk| chunk.len() as u64).sum(),
+                frames: chunks
+                    .iter()
+                    .map(|chunk| Frame::data(Bytes::from_static(chunk)))
+                    .collect(),
+                rest: full_body(Bytes::new()),
+            }
+            .boxed()
+        };
+
+        // Only the frames up to the first newline are read
+        let (prefix, body) = read_prefix(chunked(&[b"{\"dsn\"", b":1}\n{}", b"\n{}\n"]), 100)
+            .await
+            .unwrap();
+        assert_eq!(prefix.as_ref(), b"{\"dsn\":1}\n{}");
+        let body = body.collect().await.unwrap().to_bytes();
+        assert_eq!(body.as_ref(), b"{\"dsn\":1}\n{}\n{}\n");
+
+        // Or past the limit, or until the end of the body
+        let (prefix, body) = read_prefix(chunked(&[b"ab", b"cd", b"ef"]), 3)
+            .await
+            .unwrap();
+        assert_eq!(prefix.as_ref(), b"abcd");
+        let body = body.collect().await.unwrap().to_bytes();
+        assert_eq!(body.as_ref(), b"abcdef");
+        let (prefix, body) = read_prefix(full_body(Bytes::from_static(b"abc")), 100)
+            .await
+            .unwrap();
+        assert_eq!(prefix.as_ref(), b"abc");
+        assert_eq!(body.size_hint().exact(), Some(3));
+    }
+
     #[tokio::test]
     async fn test_send_to_upstream_timeout() {
         let conn = HttpConnector::new();
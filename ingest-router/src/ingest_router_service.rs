@@ -1,18 +1,28 @@
+use crate::audit::{AuditLogger, AuditRecord, Outcome, hash_key};
 use crate::auth;
+use crate::canary::Canary;
 use crate::config;
 use crate::errors::IngestRouterError;
 use crate::executor;
+use crate::handler::{BodyPrefix, ExecutionMode, RoutedCells};
+use crate::http::{ResponseBody, full_body, read_prefix};
+use crate::memory_budget::{CollectError, MemoryBudget};
 use crate::metrics_defs::{REQUEST_DURATION, REQUESTS_INFLIGHT};
-use crate::router;
-use http_body_util::{BodyExt, Full};
+use crate::rate_limit::CellRateLimits;
+use crate::router::{self, ContentTypeCheck, ResolvedRoute};
+use crate::tls::CellClients;
+use crate::traffic_report::TrafficReport;
+use http_body_util::BodyExt;
 use hyper::StatusCode;
 use hyper::body::Bytes;
 use hyper::service::Service;
 use hyper::{Request, Response};
-use shared::http::make_error_response;
+use shared::http::{RequestId, make_error_response};
 use std::pin::Pin;
+use std::sync::Arc;
 use std::sync::atomic::{AtomicU64, Ordering};
 use std::time::Instant;
+use tracing::Instrument;
 
 // Counter for 1% metric sampling.
 static REQUEST_COUNT: AtomicU64 = AtomicU64::new(0);
@@ -23,6 +33,8 @@ static INFLIGHT: AtomicU64 = AtomicU64::new(0);
 pub struct IngestRouterService {
     router: router::Router,
     executor: executor::Executor,
+    memory_budget: MemoryBudget,
+    audit: Option<AuditLogger>,
 }
 
 impl IngestRouterService {
@@ -31,9 +43,45 @@ impl IngestRouterService {
         timeouts: config::RelayTimeouts,
         verifier: auth::RelayVerifier,
         signer: auth::RelaySigner,
+        max_buffered_body_bytes: Option<u64>,
+        audit: Option<AuditLogger>,
     ) -> Self {
         let executor = executor::Executor::new(timeouts, verifier, signer);
-        Self { router, executor }
+        Self {
+            router,
+            executor,
+            memory_budget: MemoryBudget::new(max_buffered_body_bytes),
+            audit,
+        }
+    }
+
+    /// Sends requests to the cells with their own clients, for cells with TLS settings
+    pub fn with_cell_clients(mut self, clients: CellClients) -> Self {
+        self.executor = self.executor.with_cell_clients(clients);
+        self
+    }
+
+    /// Stops sending requests to cells whose request tasks keep panicking
+    pub fn with_panic_breaker(mut self, config: config::PanicBreaker) -> Self {
+        self.executor = self.executor.with_panic_breaker(config);
+        self
+    }
+
+    /// Limits the requests sent to the cells with `max_rps`, see `rate_limit`
+    pub fn with_rate_limits(mut self, rate_limits: CellRateLimits) -> Self {
+        self.executor = self.executor.with_rate_limits(rate_limits);
+        self
+    }
+
+    /// Records the requests sent to each cell in the traffic report
+    pub fn with_traffic_report(mut self, traffic_report: Arc<TrafficReport>) -> Self {
+        self.executor = self.executor.with_traffic_report(traffic_report);
+        self
+    }
+
+    /// Canary sending its requests through this service's router and executor
+    pub fn canary(&self, config: config::Canary) -> Canary {
+        Canary::new(config, self.router.clone(), self.executor.clone())
     }
 }
 
@@ -43,41 +91,188 @@ where
     B::Error: std::error::Error + Send + Sync + 'static,
     B: Unpin,
 {
-    type Response = Response<Full<Bytes>>;
+    type Response = Response<ResponseBody>;
     type Error = IngestRouterError;
     type Future =
         Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send + 'static>>;
 
-    fn call(&self, req: Request<B>) -> Self::Future {
+    fn call(&self, mut req: Request<B>) -> Self::Future {
         let start = Instant::now();
         INFLIGHT.fetch_add(1, Ordering::Relaxed);
 
+        let request_id = RequestId::ensure(req.headers_mut());
+        let span = request_id.span();
+
         let resolved = self.router.resolve(&req);
-        let (parts, body) = req.into_parts();
+        let (mut parts, body) = req.into_parts();
         let executor = self.executor.clone();
-
-        Box::pin(async move {
-            let (response, handler_name): (Response<Full<Bytes>>, &str) = match resolved {
-                Some((handler, cells)) => {
-                    let handler_name = handler.name();
-                    match body.collect().await {
-                        Ok(c) => {
-                            let request = Request::from_parts(parts, c.to_bytes());
-                            let response = executor.execute(handler, request, cells).await;
-                            (response.map(Full::new), handler_name)
+        let memory_budget = self.memory_budget.clone();
+        let audit = self.audit.clone();
+
+        // Only needed to describe the request in the audit log
+        let request_info = audit
+            .as_ref()
+            .map(|_| (parts.method.clone(), parts.uri.path().to_string()));
+
+        let future = async move {
+            let locality = resolved
+                .as_ref()
+                .map(|resolved| resolved.cells.locality().to_string());
+            let mut audit_keys = Vec::new();
+            // Held until the request completes
+            let route_permit = resolved
+                .as_ref()
+                .and_then(|resolved| resolved.budget.as_ref())
+                .map(|budget| budget.try_acquire());
+
+            let (mut response, handler_name, outcome): (Response<ResponseBody>, &str, Outcome) =
+                match resolved {
+                    // Rejected before the body is buffered
+                    Some(ResolvedRoute {
+                        handler,
+                        content_type: ContentTypeCheck::Unsupported,
+                        ..
+                    }) => {
+                        tracing::debug!(
+                            handler = handler.name(),
+                            content_type = ?parts.headers.get(hyper::header::CONTENT_TYPE),
+                            "Unsupported content type, rejecting request"
+                        );
+                        let response =
+                            make_error_response(StatusCode::UNSUPPORTED_MEDIA_TYPE).map(full_body);
+                        (response, handler.name(), Outcome::UnsupportedContentType)
+                    }
+                    Some(ResolvedRoute { handler, .. }) if matches!(route_permit, Some(None)) => {
+                        tracing::warn!(
+                            handler = handler.name(),
+                            "Route at its limit of concurrent requests, rejecting request"
+                        );
+                        let response =
+                            make_error_response(StatusCode::SERVICE_UNAVAILABLE).map(full_body);
+                        (response, handler.name(), Outcome::RouteBudgetExceeded)
+                    }
+                    // Streamed to the cell, so the body is neither buffered nor budgeted, except
+                    // for the start the handler routes by
+                    Some(ResolvedRoute {
+                        handler,
+                        cells,
+                        forward_headers,
+                        ..
+                    }) if matches!(handler.execution_mode(), ExecutionMode::PassThrough(_)) => {
+                        let handler_name = handler.name();
+                        if let Some(forward_headers) = forward_headers {
+                            forward_headers.apply(&mut parts.headers);
                         }
-                        Err(_) => {
-                            let response =
-                                make_error_response(StatusCode::BAD_REQUEST).map(Full::new);
-                            (response, handler_name)
+                        let body = body
+                            .map_err(|e| IngestRouterError::RequestBodyError(e.to_string()))
+                            .boxed();
+                        // Only the start of the body is read, the rest is streamed
+                        let read = match handler.body_prefix_limit() {
+                            Some(limit) => read_prefix(body, limit)
+                                .await
+                                .map(|(prefix, body)| (Some(prefix), body)),
+                            None => Ok((None, body)),
+                        };
+                        match read {
+                            Ok((prefix, body)) => {
+                                let head =
+                                    Request::from_parts(parts, prefix.clone().unwrap_or_default());
+                                if audit.is_some() {
+                                    audit_keys = handler.audit_keys(&head);
+                                }
+                                let (mut parts, _) = head.into_parts();
+                                if let Some(prefix) = prefix {
+                                    parts.extensions.insert(BodyPrefix(prefix));
+                                }
+                                let request = Request::from_parts(parts, body);
+                                let response = executor.pass_through(handler, request, cells).await;
+                                (response, handler_name, Outcome::Routed)
+                            }
+                            Err(_) => {
+                                let response =
+                                    make_error_response(StatusCode::BAD_REQUEST).map(full_body);
+                                (response, handler_name, Outcome::InvalidBody)
+                            }
                         }
                     }
-                }
-                None => {
-                    let response = make_error_response(StatusCode::BAD_REQUEST).map(Full::new);
-                    (response, "none")
-                }
-            };
+                    Some(ResolvedRoute { handler, .. }) if memory_budget.is_exhausted() => {
+                        tracing::warn!(
+                            handler = handler.name(),
+                            buffered_bytes = memory_budget.used(),
+                            "Memory budget exhausted, rejecting request"
+                        );
+                        let response =
+                            make_error_response(StatusCode::SERVICE_UNAVAILABLE).map(full_body);
+                        (response, handler.name(), Outcome::BudgetExceeded)
+                    }
+                    Some(ResolvedRoute {
+                        handler,
+                        cells,
+                        content_type: ContentTypeCheck::Accepted(content_type),
+                        forward_headers,
+                        ..
+                    }) => {
+                        let handler_name = handler.name();
+                        if let Some(content_type) = content_type {
+                            parts.extensions.insert(content_type);
+                        }
+                        if let Some(forward_headers) = forward_headers {
+                            forward_headers.apply(&mut parts.headers);
+                        }
+                        // Held until the request completes, the body is shared by the split requests
+                        let mut reservation = memory_budget.reservation();
+                        match reservation.collect(body).await {
+                            Ok(bytes) => {
+                                let request = Request::from_parts(parts, bytes);
+                                if audit.is_some() {
+                                    audit_keys = handler.audit_keys(&request);
+                                }
+                                let response = executor.execute(handler, request, cells).await;
+                                (response, handler_name, Outcome::Routed)
+                            }
+                            Err(CollectError::BudgetExceeded) => {
+                                tracing::warn!(
+                                    handler = handler_name,
+                                    "Memory budget exceeded while reading request body"
+                                );
+                                let response = make_error_response(StatusCode::SERVICE_UNAVAILABLE)
+                                    .map(full_body);
+                                (response, handler_name, Outcome::BudgetExceeded)
+                            }
+                            Err(CollectError::Body(_)) => {
+                                let response =
+                                    make_error_response(StatusCode::BAD_REQUEST).map(full_body);
+                                (response, handler_name, Outcome::InvalidBody)
+                            }
+                        }
+                    }
+                    None => {
+                        let response = make_error_response(StatusCode::BAD_REQUEST).map(full_body);
+                        (response, "none", Outcome::NoRoute)
+                    }
+                };
+
+            request_id.set_header(response.headers_mut());
+
+            if let (Some(audit), Some((method, path))) = (audit, request_info) {
+                let cells = response
+                    .extensions()
+                    .get::<RoutedCells>()
+                    .map(|RoutedCells(cells)| cells.as_slice())
+                    .unwrap_or_default();
+
+                audit.log(&AuditRecord {
+                    timestamp: chrono::Utc::now(),
+                    method: method.as_str(),
+                    path: &path,
+                    handler: handler_name,
+                    locality: locality.as_deref(),
+                    keys: audit_keys.iter().map(|key| hash_key(key)).collect(),
+                    cells,
+                    status: response.status().as_u16(),
+                    outcome,
+                });
+            }
 
             // Record metrics (1% sample)
             if REQUEST_COUNT
@@ -98,7 +293,9 @@ where
             INFLIGHT.fetch_sub(1, Ordering::Relaxed);
 
             Ok(response)
-        })
+        }
+        .instrument(span);
+        Box::pin(future)
     }
 }
 
@@ -109,8 +306,10 @@ mod tests {
     use crate::api::utils::deserialize_body;
     use crate::config::{CellConfig, HandlerAction, HttpMethod, Match, Route};
     use crate::testutils::create_test_locator;
+    use http_body_util::Full;
     use hyper::Method;
-    use hyper::header::HOST;
+    use hyper::header::{CONTENT_TYPE, HOST};
+    use shared::http::REQUEST_ID_HEADER;
     use std::collections::HashMap;
     use std::net::TcpStream;
     use std::process::{Child, Command};
@@ -159,18 +358,39 @@ mod tests {
                     host: Some("us.sentry.io".to_string()),
                     path: Some("/api/0/relays/projectconfigs/".to_string()),
                     method: Some(HttpMethod::Post),
+                    headers: vec![],
                 },
                 action: HandlerAction::RelayProjectConfigs,
                 locality: "us".to_string(),
+                content_types: vec!["application/json".to_string()],
+                max_concurrent_requests: None,
+                forward_headers: None,
             },
             Route {
                 r#match: Match {
                     host: Some("us.sentry.io".to_string()),
                     path: Some("/api/0/relays/live/".to_string()),
                     method: Some(HttpMethod::Get),
+                    headers: vec![],
                 },
                 action: HandlerAction::Health,
                 locality: "us".to_string(),
+                content_types: vec![],
+                max_concurrent_requests: None,
+                forward_headers: None,
+            },
+            Route {
+                r#match: Match {
+                    host: Some("us.sentry.io".to_string()),
+                    path: Some("/api/{project_id}/envelope/".to_string()),
+                    method: Some(HttpMethod::Post),
+                    headers: vec![],
+                },
+                action: HandlerAction::Envelope,
+                locality: "us".to_string(),
+                content_types: vec![],
+                max_concurrent_requests: None,
+                forward_headers: None,
             },
         ];
 
@@ -180,6 +400,9 @@ mod tests {
                 id: "us1".to_string(),
                 sentry_url: Url::parse("https://sentry.io/us1").unwrap(),
                 relay_url: Url::parse("http://localhost:8000").unwrap(),
+                protocol_version: None,
+                tls: None,
+                max_rps: None,
             }],
         )]);
 
@@ -198,19 +421,23 @@ mod tests {
             .method(Method::POST)
             .uri("/api/0/relays/projectconfigs/")
             .header(HOST, "us.sentry.io")
+            .header(CONTENT_TYPE, "application/json")
             .body(Full::new(Bytes::from(body)))
             .unwrap();
         signer.sign_request(request.headers_mut(), body.as_bytes());
 
         let service = IngestRouterService::new(
-            router::Router::new(routes_config, localities, locator),
+            router::Router::new(routes_config, localities, router::HandlerDeps::new(locator)),
             config::RelayTimeouts {
                 http_timeout_secs: 5000,
                 task_initial_timeout_secs: 10000,
                 task_subsequent_timeout_secs: 10000,
+                ..Default::default()
             },
             verifier,
             signer,
+            None,
+            None,
         );
 
         let response = service.call(request).await.unwrap();
@@ -218,6 +445,7 @@ mod tests {
         let (parts, body) = response.into_parts();
 
         assert_eq!(parts.status, 200);
+        assert!(parts.headers.contains_key(&REQUEST_ID_HEADER));
 
         // Convert BoxBody to Bytes for deserialize_body
         let body_bytes = body.collect().await.unwrap().to_bytes();
@@ -226,6 +454,20 @@ mod tests {
         assert_eq!(parsed.pending_keys.len(), 0);
         assert_eq!(parsed.extra_fields.len(), 2);
 
+        // Unsupported content type
+        let request = Request::builder()
+            .method(Method::POST)
+            .uri("/api/0/relays/projectconfigs/")
+            .header(HOST, "us.sentry.io")
+            .header(CONTENT_TYPE, "application/x-www-form-urlencoded")
+            .header(&REQUEST_ID_HEADER, "edge-42")
+            .body(Full::new(Bytes::from("publicKeys=aaaa")))
+            .unwrap();
+        let response = service.call(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        // The request id is returned with local responses too
+        assert_eq!(response.headers()[&REQUEST_ID_HEADER], "edge-42");
+
         // Healthcheck
         let request = Request::builder()
             .method(Method::GET)
@@ -236,5 +478,118 @@ mod tests {
 
         let response = service.call(request).await.unwrap();
         assert_eq!(response.status(), 200);
+
+        // Envelope, routed to the cell of the DSN in its header
+        let event_id = "9ec79c33ec9942ab8353589fcb2e04dc";
+        let body = format!(
+            "{{\"event_id\":\"{event_id}\",\"dsn\":\"https://aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa@o1.ingest.sentry.io/1\"}}\n{{\"type\":\"event\",\"length\":2}}\n{{}}\n"
+        );
+        let request = Request::builder()
+            .method(Method::POST)
+            .uri("/api/1/envelope/")
+            .header(HOST, "us.sentry.io")
+            .header(CONTENT_TYPE, "application/x-sentry-envelope")
+            .body(Full::new(Bytes::from(body)))
+            .unwrap();
+        let response = service.call(request).await.unwrap();
+        assert_eq!(response.status(), 200);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let parsed: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(parsed["id"], event_id);
+
+        // Envelopes without a key are not forwarded
+        let request = Request::builder()
+            .method(Method::POST)
+            .uri("/api/1/envelope/")
+            .header(HOST, "us.sentry.io")
+            .body(Full::new(Bytes::from("{}\n")))
+            .unwrap();
+        let response = service.call(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_route_budget() {
+        let route = |path: &str, action, max_concurrent_requests| Route {
+            r#match: Match {
+                host: Some("us.sentry.io".to_string()),
+                path: Some(path.to_string()),
+                method: None,
+                headers: vec![],
+            },
+            action,
+            locality: "us".to_string(),
+            content_types: vec![],
+            max_concurrent_requests,
+            forward_headers: None,
+        };
+        let localities = HashMap::from([(
+            "us".to_string(),
+            vec![CellConfig {
+                id: "us1".to_string(),
+                sentry_url: Url::parse("http://localhost:8080").unwrap(),
+                relay_url: Url::parse("http://localhost:8090").unwrap(),
+                protocol_version: None,
+                tls: None,
+                max_rps: None,
+            }],
+        )]);
+
+        let router = router::Router::new(
+            vec![
+                route(
+                    "/api/0/relays/projectconfigs/",
+                    HandlerAction::RelayProjectConfigs,
+                    Some(1),
+                ),
+                route("/other/", HandlerAction::RelayProjectConfigs, None),
+            ],
+            localities,
+            router::HandlerDeps::new(create_test_locator(HashMap::new()).await),
+        );
+        let (signer, verifier) = make_signing_keypair();
+        let service = IngestRouterService::new(
+            router.clone(),
+            config::RelayTimeouts::default(),
+            verifier,
+            signer,
+            None,
+            None,
+        );
+
+        let request = |path: &str| {
+            Request::builder()
+                .method(Method::POST)
+                .uri(path)
+                .header(HOST, "us.sentry.io")
+                .body(Full::new(Bytes::new()))
+                .unwrap()
+        };
+
+        // Saturate the project configs route, the router shares its budget with the service
+        let budget = router
+            .resolve(&request("/api/0/relays/projectconfigs/"))
+            .and_then(|resolved| resolved.budget)
+            .unwrap();
+        let permit = budget.try_acquire().unwrap();
+
+        let response = service
+            .call(request("/api/0/relays/projectconfigs/"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+        // Other routes are not affected, the unsigned request is rejected by verification
+        let response = service.call(request("/other/")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        // Requests are accepted again once a permit is returned
+        drop(permit);
+        let response = service
+            .call(request("/api/0/relays/projectconfigs/"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(budget.in_use(), 0);
     }
 }
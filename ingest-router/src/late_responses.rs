@@ -0,0 +1,184 @@
+//! Cell responses that arrived after the deadline of their request.
+//!
+//! In `ExecutionMode::Parallel`, cells that have not responded by the subsequent task
+//! timeout are reported as timed out. When `late_response_ttl_secs` is set, their requests
+//! keep running in the background, and successful responses are kept for that long. The
+//! next identical request to the same cell takes the kept response instead of waiting on
+//! the cell again, so that a cell that is slow for a set of keys still contributes them to
+//! the retry of the client.
+//!
+//! Responses are only replayed to the relay that sent the original request, with the same
+//! credentials, see `LateResponses::key`.
+use crate::auth::RELAY_ID_HEADER;
+use hyper::body::Bytes;
+use hyper::header::{AUTHORIZATION, HeaderName, HeaderValue};
+use hyper::{Request, Response};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Bounds the memory held by late responses if cells are slow for many requests at once
+const MAX_ENTRIES: usize = 1000;
+
+pub type Key = [u8; 32];
+
+/// Headers that authenticate the sender of a request, part of the key
+const AUTH_HEADERS: [HeaderName; 2] = [AUTHORIZATION, HeaderName::from_static("x-sentry-auth")];
+
+/// `X-Sentry-Relay-Id` of the relay a request was received from, before synapse re-signed
+/// it with its own id. The executor inserts this into the extensions of split requests.
+#[derive(Clone, Debug)]
+pub struct OriginRelay(pub HeaderValue);
+
+pub struct LateResponses {
+    ttl: Duration,
+    entries: Mutex<HashMap<Key, (Instant, Response<Bytes>)>>,
+}
+
+impl LateResponses {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Identifies a request to a cell by the relay that sent it, its credentials, method,
+    /// path, query and body. Signatures are not part of the key, since they differ between
+    /// otherwise identical requests.
+    pub fn key(cell_id: &str, request: &Request<Bytes>) -> Key {
+        let relay_id = match request.extensions().get::<OriginRelay>() {
+            Some(OriginRelay(relay_id)) => Some(relay_id),
+            None => request.headers().get(&RELAY_ID_HEADER),
+        };
+        let mut parts = vec![
+            cell_id.as_bytes(),
+            relay_id.map_or(&b""[..], HeaderValue::as_bytes),
+        ];
+        for name in &AUTH_HEADERS {
+            let values = request.headers().get_all(name);
+            parts.extend(values.iter().map(HeaderValue::as_bytes));
+            // Separates the values of consecutive headers
+            parts.push(b"");
+        }
+        parts.extend([
+            request.method().as_str().as_bytes(),
+            request
+                .uri()
+                .path_and_query()
+                .map_or("", |path_and_query| path_and_query.as_str())
+                .as_bytes(),
+        ]);
+
+        let mut hasher = Sha256::new();
+        for part in parts {
+            hasher.update((part.len() as u64).to_le_bytes());
+            hasher.update(part);
+        }
+        hasher.update(request.body());
+        hasher.finalize().into()
+    }
+
+    /// Keeps the response for the next identical request. Returns false if it was dropped
+    /// because too many responses are kept already.
+    pub fn insert(&self, key: Key, response: Response<Bytes>, now: Instant) -> bool {
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|_, (inserted_at, _)| now.duration_since(*inserted_at) < self.ttl);
+        if entries.len() >= MAX_ENTRIES && !entries.contains_key(&key) {
+            return false;
+        }
+        entries.insert(key, (now, response));
+        true
+    }
+
+    #[cfg(test)]
+    pub fn is_empty(&self) -> bool {
+        self.entries.lock().unwrap().is_empty()
+    }
+
+    /// Takes the unexpired response kept for the key, every response is only used once.
+    pub fn take(&self, key: &Key, now: Instant) -> Option<Response<Bytes>> {
+        let (inserted_at, response) = self.entries.lock().unwrap().remove(key)?;
+        (now.duration_since(inserted_at) < self.ttl).then_some(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(uri: &str, body: &'static str) -> Request<Bytes> {
+        Request::builder()
+            .method("POST")
+            .uri(uri)
+            .body(Bytes::from_static(body.as_bytes()))
+            .unwrap()
+    }
+
+    #[test]
+    fn test_key() {
+        let key = LateResponses::key("us1", &request("/api/0/relays/projectconfigs/", "{}"));
+        assert_eq!(
+            key,
+            LateResponses::key("us1", &request("/api/0/relays/projectconfigs/", "{}"))
+        );
+
+        let with_header = |name: &str, value: &str| {
+            let mut request = request("/api/0/relays/projectconfigs/", "{}");
+            request.headers_mut().insert(
+                HeaderName::from_bytes(name.as_bytes()).unwrap(),
+                value.parse().unwrap(),
+            );
+            request
+        };
+        let mut resigned = with_header("x-sentry-relay-id", "synapse");
+        resigned
+            .extensions_mut()
+            .insert(OriginRelay(HeaderValue::from_static("relay-1")));
+        for (cell_id, request) in [
+            ("us2", request("/api/0/relays/projectconfigs/", "{}")),
+            (
+                "us1",
+                request("/api/0/relays/projectconfigs/?version=3", "{}"),
+            ),
+            ("us1", request("/api/0/relays/projectconfigs/", "{\"a\":1}")),
+            ("us1", with_header("x-sentry-relay-id", "relay-1")),
+            ("us1", with_header("x-sentry-auth", "Sentry sentry_key=abc")),
+            ("us1", with_header("authorization", "Bearer abc")),
+        ] {
+            assert_ne!(key, LateResponses::key(cell_id, &request));
+        }
+
+        // Re-signed requests are keyed by the relay they were received from
+        assert_eq!(
+            LateResponses::key("us1", &resigned),
+            LateResponses::key("us1", &with_header("x-sentry-relay-id", "relay-1"))
+        );
+        // Signatures differ between identical requests
+        assert_eq!(
+            key,
+            LateResponses::key("us1", &with_header("x-sentry-relay-signature", "sig"))
+        );
+    }
+
+    #[test]
+    fn test_insert_take() {
+        let late_responses = LateResponses::new(Duration::from_secs(60));
+        let key = LateResponses::key("us1", &request("/", "{}"));
+        let now = Instant::now();
+
+        assert!(late_responses.insert(key, Response::new(Bytes::from_static(b"late")), now));
+        let response = late_responses.take(&key, now + Duration::from_secs(59));
+        assert_eq!(response.unwrap().body().as_ref(), b"late");
+        assert!(late_responses.take(&key, now).is_none());
+
+        // Expired responses are not used
+        late_responses.insert(key, Response::new(Bytes::new()), now);
+        assert!(
+            late_responses
+                .take(&key, now + Duration::from_secs(60))
+                .is_none()
+        );
+    }
+}
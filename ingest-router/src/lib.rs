@@ -1,23 +1,40 @@
+pub mod admin;
 pub mod api;
+pub mod audit;
 pub mod auth;
+pub mod canary;
+pub mod cell_reconciliation;
 pub mod config;
 pub mod errors;
 mod executor;
 pub mod handler;
+pub mod header_allow_list;
 pub mod http;
 pub mod ingest_router_service;
+mod late_responses;
 pub mod locality;
+pub mod memory_budget;
 pub mod metrics_defs;
+mod panic_breaker;
+pub mod rate_limit;
+pub mod route_budget;
 pub mod router;
+pub mod routing_drift;
+pub mod streaming;
+pub mod tls;
+pub mod traffic_report;
 
 #[cfg(test)]
 mod testutils;
 
 use crate::errors::IngestRouterError;
 use auth::{RelaySigner, RelayVerifier};
+use locator::admin::LocatorAdmin;
 use locator::client::Locator;
 use shared::http::run_http_service;
 use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
 
 use shared::admin_service::AdminService;
 
@@ -26,39 +43,122 @@ pub async fn run(config: config::Config, credentials_path: &Path) -> Result<(),
 
     let verifier = RelayVerifier::from_relays(config.relay_keys)?;
     let signer = RelaySigner::from_file(credentials_path)?;
+    let audit_log = config.audit_log.map(audit::AuditLogger::new).transpose()?;
+    let cell_clients = tls::CellClients::from_config(&config.localities)?;
+    let rate_limits = rate_limit::CellRateLimits::from_config(&config.localities);
+    let listener_tls = config
+        .listener
+        .tls
+        .as_ref()
+        .map(config::ListenerTls::acceptor)
+        .transpose()?;
+    let routing_drift = config.routing_drift.map(routing_drift::RoutingDrift::new);
+    let traffic_report = config
+        .traffic_report
+        .map(|config| Arc::new(traffic_report::TrafficReport::new(config)));
 
-    let ingest_router_service = ingest_router_service::IngestRouterService::new(
-        router::Router::new(config.routes, config.localities, locator.clone()),
+    let router = router::Router::new(
+        config.routes,
+        config.localities,
+        router::HandlerDeps {
+            locator: locator.clone(),
+            cross_locality_routing: config.cross_locality_routing,
+            relay_heartbeat: config.relay_heartbeat,
+            public_keys: config.public_keys,
+            key_distribution: routing_drift
+                .as_ref()
+                .map(routing_drift::RoutingDrift::distribution),
+            traffic_report: traffic_report.clone(),
+        },
+    );
+
+    let mut ingest_router_service = ingest_router_service::IngestRouterService::new(
+        router.clone(),
         config.relay_timeouts,
         verifier,
         signer,
-    );
-    let admin_service = AdminService::new({
+        config.max_buffered_body_bytes,
+        audit_log.clone(),
+    )
+    .with_cell_clients(cell_clients)
+    .with_rate_limits(rate_limits);
+    if let Some(panic_breaker) = config.panic_breaker {
+        ingest_router_service = ingest_router_service.with_panic_breaker(panic_breaker);
+    }
+    if let Some(traffic_report) = &traffic_report {
+        ingest_router_service = ingest_router_service.with_traffic_report(traffic_report.clone());
+    }
+    let canary_task = config
+        .canary
+        .map(|canary| tokio::spawn(ingest_router_service.canary(canary).run()));
+    let routing_drift_task = routing_drift.map(|routing_drift| tokio::spawn(routing_drift.run()));
+    let cell_reconciliation_task = config.cell_reconciliation.map(|reconciliation| {
+        tokio::spawn(
+            cell_reconciliation::CellReconciliation::new(
+                reconciliation,
+                locator.clone(),
+                router.localities(),
+            )
+            .run(),
+        )
+    });
+    let mut admin_service = AdminService::new({
         let locator = locator.clone();
         move || locator.is_ready()
-    });
+    })
+    .with_endpoints(Arc::new(LocatorAdmin::new(locator.clone())))
+    .with_endpoints(Arc::new(admin::RouterAdmin::new(router)));
+    if let Some(traffic_report) = traffic_report {
+        admin_service = admin_service.with_endpoints(traffic_report);
+    }
+    if let Some(auth) = &config.admin_listener.auth {
+        admin_service = admin_service.with_bearer_tokens(auth.bearer_tokens()?);
+    }
 
+    let drain_timeout = Duration::from_secs(config.drain_timeout_secs);
     let router_task = run_http_service(
         &config.listener.host,
         config.listener.port,
         ingest_router_service,
+        listener_tls,
+        drain_timeout,
     );
     let admin_task = run_http_service(
         &config.admin_listener.host,
         config.admin_listener.port,
         admin_service,
+        None,
+        drain_timeout,
     );
 
-    tokio::select! {
-        result = async { tokio::try_join!(router_task, admin_task) } => {
-            result?;
-        }
-        _ = tokio::signal::ctrl_c() => {
-            tracing::info!("Shutting down ingest-router...");
-        }
-    }
+    tokio::try_join!(router_task, admin_task)?;
+    tracing::info!("Connections drained, shutting down ingest-router...");
 
+    for task in [canary_task, routing_drift_task, cell_reconciliation_task]
+        .into_iter()
+        .flatten()
+    {
+        task.abort();
+    }
     locator.shutdown().await;
+    if let Some(audit_log) = audit_log {
+        let _ = tokio::task::spawn_blocking(move || audit_log.flush()).await;
+    }
+
+    Ok(())
+}
 
+/// Checks the config, and the relay keys, cell TLS settings and listener certificates like
+/// [`run`] does, without contacting the locator or reading the relay credentials.
+pub fn validate(config: &config::Config) -> Result<(), IngestRouterError> {
+    config.validate()?;
+    RelayVerifier::from_relays(config.relay_keys.clone())?;
+    tls::CellClients::from_config(&config.localities)?;
+    config
+        .listener
+        .tls
+        .as_ref()
+        .map(config::ListenerTls::acceptor)
+        .transpose()?;
     Ok(())
 }
@@ -21,11 +21,12 @@
 //! ```
 //!
 //! `Localties` is built at startup from configuration and remains immutable
-//! during request processing.
+//! during request processing, except for the cells marked as ineligible by the cell
+//! reconciliation, which are left out of `cell_list`.
 
 use indexmap::IndexMap;
-use std::collections::HashMap;
-use std::sync::Arc;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, RwLock};
 use url::Url;
 
 use crate::config::CellConfig;
@@ -37,6 +38,8 @@ pub struct Upstream {
     pub relay_url: Url,
     /// Sentry URL for reaching sentry API endpoints
     pub sentry_url: Url,
+    /// Declared relay protocol version, None for the current one
+    pub protocol_version: Option<u32>,
 }
 
 impl From<CellConfig> for Upstream {
@@ -44,6 +47,7 @@ impl From<CellConfig> for Upstream {
         Self {
             relay_url: config.relay_url,
             sentry_url: config.sentry_url,
+            protocol_version: config.protocol_version,
         }
     }
 }
@@ -54,6 +58,11 @@ struct CellsInner {
     locality: String,
     /// Map of cell_id to upstream, preserving insertion order (first = highest priority)
     cells: IndexMap<String, Upstream>,
+    /// Every configured cell across all localities, keyed by cell_id. Used to reach cells
+    /// outside this locality when a key is owned by another locality.
+    all_cells: Arc<HashMap<String, (String, Upstream)>>,
+    /// Cells of all localities that are not sent requests fanned out to the locality
+    ineligible: Arc<RwLock<HashSet<String>>>,
 }
 
 #[derive(Clone, Debug)]
@@ -63,7 +72,12 @@ pub struct Cells {
 
 impl Cells {
     /// Build cells from cell configurations
-    fn from_config(locality: String, cell_configs: Vec<CellConfig>) -> Self {
+    fn from_config(
+        locality: String,
+        cell_configs: Vec<CellConfig>,
+        all_cells: Arc<HashMap<String, (String, Upstream)>>,
+        ineligible: Arc<RwLock<HashSet<String>>>,
+    ) -> Self {
         let cells: IndexMap<String, Upstream> = cell_configs
             .into_iter()
             .map(|config| {
@@ -74,7 +88,12 @@ impl Cells {
             .collect();
 
         Self {
-            inner: Arc::new(CellsInner { locality, cells }),
+            inner: Arc::new(CellsInner {
+                locality,
+                cells,
+                all_cells,
+                ineligible,
+            }),
         }
     }
 
@@ -82,9 +101,16 @@ impl Cells {
         &self.inner.locality
     }
 
-    /// Returns order list of cell ids
+    /// Returns order list of cell ids, without the ineligible cells
     pub fn cell_list(&self) -> impl Iterator<Item = &String> {
-        self.inner.cells.keys()
+        self.inner.cells.keys().filter(|cell_id| {
+            !self
+                .inner
+                .ineligible
+                .read()
+                .unwrap_or_else(|e| e.into_inner())
+                .contains(*cell_id)
+        })
     }
 
     /// Get upstream for a cell_id, or None if not found
@@ -96,27 +122,94 @@ impl Cells {
     pub fn contains_cell(&self, cell_id: &str) -> bool {
         self.inner.cells.contains_key(cell_id)
     }
+
+    /// Get the locality of any configured cell, including cells outside this locality
+    pub fn locality_of(&self, cell_id: &str) -> Option<&str> {
+        self.inner
+            .all_cells
+            .get(cell_id)
+            .map(|(locality, _)| locality.as_str())
+    }
+
+    /// Get the declared protocol version of any configured cell, None for the current one
+    pub fn protocol_version(&self, cell_id: &str) -> Option<u32> {
+        self.resolve_upstream(cell_id)
+            .and_then(|upstream| upstream.protocol_version)
+    }
+
+    /// Get upstream for a cell_id, falling back to cells in other localities
+    pub fn resolve_upstream(&self, cell_id: &str) -> Option<&Upstream> {
+        self.get_upstream(cell_id).or_else(|| {
+            self.inner
+                .all_cells
+                .get(cell_id)
+                .map(|(_, upstream)| upstream)
+        })
+    }
 }
 
 /// Maps localities to their cells (which map to upstreams)
+#[derive(Clone)]
 pub struct Localities {
     /// Mapping from locality to cells
     locality_to_cells: HashMap<String, Cells>,
+    ineligible: Arc<RwLock<HashSet<String>>>,
 }
 
 impl Localities {
     /// Build locality mappings from configuration
     pub fn new(localities: HashMap<String, Vec<CellConfig>>) -> Self {
+        let all_cells: Arc<HashMap<String, (String, Upstream)>> = Arc::new(
+            localities
+                .iter()
+                .flat_map(|(locality, cells_config)| {
+                    cells_config.iter().map(|config| {
+                        (
+                            config.id.clone(),
+                            (locality.clone(), Upstream::from(config.clone())),
+                        )
+                    })
+                })
+                .collect(),
+        );
+
+        let ineligible = Arc::new(RwLock::new(HashSet::new()));
+
         // Build locality -> cells mapping
         let locality_to_cells = localities
             .into_iter()
             .map(|(locality, cells_config)| {
-                let cells = Cells::from_config(locality.clone(), cells_config);
+                let cells = Cells::from_config(
+                    locality.clone(),
+                    cells_config,
+                    all_cells.clone(),
+                    ineligible.clone(),
+                );
                 (locality, cells)
             })
             .collect();
 
-        Self { locality_to_cells }
+        Self {
+            locality_to_cells,
+            ineligible,
+        }
+    }
+
+    /// Every configured cell with its locality, including the ineligible ones
+    pub fn configured_cells(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.locality_to_cells.iter().flat_map(|(locality, cells)| {
+            cells
+                .inner
+                .cells
+                .keys()
+                .map(move |cell_id| (locality.as_str(), cell_id.as_str()))
+        })
+    }
+
+    /// Replaces the cells left out of the cell lists of their locality. Requests routed
+    /// to them by key still reach them.
+    pub fn set_ineligible(&self, cells: HashSet<String>) {
+        *self.ineligible.write().unwrap_or_else(|e| e.into_inner()) = cells;
     }
 
     /// Get the cells for a specific locality
@@ -134,6 +227,9 @@ mod tests {
             id: id.to_string(),
             sentry_url: Url::parse(sentry_url).unwrap(),
             relay_url: Url::parse(relay_url).unwrap(),
+            protocol_version: None,
+            tls: None,
+            max_rps: None,
         }
     }
 
@@ -188,5 +284,23 @@ mod tests {
 
         // Verify unknown locality returns None
         assert!(localities.get_cells("unknown").is_none());
+
+        // Cells in other localities are reachable but not part of this locality
+        assert!(!us_cells.contains_cell("de1"));
+        assert!(us_cells.get_upstream("de1").is_none());
+        assert_eq!(us_cells.locality_of("de1"), Some("de"));
+        assert_eq!(
+            us_cells.resolve_upstream("de1").unwrap().relay_url.as_str(),
+            "http://de-relay.example.com/"
+        );
+        assert!(us_cells.resolve_upstream("unknown").is_none());
+
+        // Ineligible cells are left out of the cell list, but can still be reached
+        localities.set_ineligible(HashSet::from(["us1".to_string()]));
+        let cell_list: Vec<_> = us_cells.cell_list().collect();
+        assert_eq!(cell_list, vec!["us2"]);
+        assert!(us_cells.get_upstream("us1").is_some());
+        localities.set_ineligible(HashSet::new());
+        assert_eq!(us_cells.cell_list().count(), 2);
     }
 }
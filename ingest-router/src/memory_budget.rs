@@ -0,0 +1,184 @@
+//! Accounting of request bodies buffered in memory.
+//!
+//! The ingest router collects each request body in full before splitting it across cells.
+//! `MemoryBudget` tracks the total bytes buffered across all in-flight requests so that new
+//! requests can be rejected once a configured ceiling is reached, instead of running out of
+//! memory under a burst of large requests. The bytes buffered are reported in the
+//! `buffered_body.bytes` gauge as they are reserved and released.
+use crate::metrics_defs::BUFFERED_BODY_BYTES;
+use http_body_util::BodyExt;
+use hyper::body::{Body, Bytes};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+#[derive(thiserror::Error, Debug, PartialEq)]
+pub enum CollectError {
+    #[error("failed to read request body: {0}")]
+    Body(String),
+    #[error("memory budget exceeded")]
+    BudgetExceeded,
+}
+
+#[derive(Clone, Debug)]
+pub struct MemoryBudget {
+    inner: Arc<Inner>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    // No limit if None, bytes are still accounted for
+    limit: Option<u64>,
+    used: AtomicU64,
+}
+
+impl MemoryBudget {
+    pub fn new(limit: Option<u64>) -> Self {
+        MemoryBudget {
+            inner: Arc::new(Inner {
+                limit,
+                used: AtomicU64::new(0),
+            }),
+        }
+    }
+
+    /// Bytes currently buffered across all reservations
+    pub fn used(&self) -> u64 {
+        self.inner.used.load(Ordering::Relaxed)
+    }
+
+    /// Whether the budget has been used up
+    pub fn is_exhausted(&self) -> bool {
+        self.inner.limit.is_some_and(|limit| self.used() >= limit)
+    }
+
+    /// Creates an empty reservation which grows as body bytes are buffered, and returns
+    /// its bytes to the budget when dropped.
+    pub fn reservation(&self) -> Reservation {
+        Reservation {
+            budget: self.clone(),
+            bytes: 0,
+        }
+    }
+
+    fn try_acquire(&self, bytes: u64) -> bool {
+        let result = self
+            .inner
+            .used
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |used| {
+                let new_used = used.saturating_add(bytes);
+                match self.inner.limit {
+                    Some(limit) if new_used > limit => None,
+                    _ => Some(new_used),
+                }
+            });
+        if result.is_err() {
+            return false;
+        }
+        metrics::gauge!(BUFFERED_BODY_BYTES.name).increment(bytes as f64);
+        true
+    }
+
+    fn release(&self, bytes: u64) {
+        self.inner.used.fetch_sub(bytes, Ordering::Relaxed);
+        metrics::gauge!(BUFFERED_BODY_BYTES.name).decrement(bytes as f64);
+    }
+}
+
+pub struct Reservation {
+    budget: MemoryBudget,
+    bytes: u64,
+}
+
+impl Reservation {
+    /// Reserves additional bytes, returns false if this would exceed the budget.
+    pub fn grow(&mut self, bytes: u64) -> bool {
+        if !self.budget.try_acquire(bytes) {
+            return false;
+        }
+        self.bytes += bytes;
+        true
+    }
+
+    /// Collects the body, accounting its bytes against the budget as they are read.
+    pub async fn collect<B>(&mut self, mut body: B) -> Result<Bytes, CollectError>
+    where
+        B: Body<Data = Bytes> + Unpin,
+        B::Error: std::fmt::Display,
+    {
+        let mut buf = Vec::new();
+
+        while let Some(frame) = body.frame().await {
+            let frame = frame.map_err(|e| CollectError::Body(e.to_string()))?;
+            if let Ok(data) = frame.into_data() {
+                if !self.grow(data.len() as u64) {
+                    return Err(CollectError::BudgetExceeded);
+                }
+                buf.extend_from_slice(&data);
+            }
+        }
+
+        Ok(Bytes::from(buf))
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        self.budget.release(self.bytes);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http_body_util::Full;
+
+    #[test]
+    fn test_reservation() {
+        let budget = MemoryBudget::new(Some(100));
+
+        let mut first = budget.reservation();
+        assert!(first.grow(60));
+        assert_eq!(budget.used(), 60);
+        assert!(!budget.is_exhausted());
+
+        let mut second = budget.reservation();
+        assert!(!second.grow(50), "exceeds the budget");
+        assert!(second.grow(40));
+        assert!(budget.is_exhausted());
+
+        // Bytes are returned when reservations are dropped
+        drop(first);
+        assert_eq!(budget.used(), 40);
+        drop(second);
+        assert_eq!(budget.used(), 0);
+    }
+
+    #[test]
+    fn test_unlimited() {
+        let budget = MemoryBudget::new(None);
+        let mut reservation = budget.reservation();
+        assert!(reservation.grow(u64::MAX / 2));
+        assert!(!budget.is_exhausted());
+    }
+
+    #[tokio::test]
+    async fn test_collect() {
+        let budget = MemoryBudget::new(Some(10));
+
+        let mut reservation = budget.reservation();
+        let body = reservation
+            .collect(Full::new(Bytes::from_static(b"hello")))
+            .await
+            .unwrap();
+        assert_eq!(body.as_ref(), b"hello");
+        assert_eq!(budget.used(), 5);
+
+        let mut reservation = budget.reservation();
+        assert_eq!(
+            reservation
+                .collect(Full::new(Bytes::from_static(b"too large")))
+                .await,
+            Err(CollectError::BudgetExceeded)
+        );
+    }
+}
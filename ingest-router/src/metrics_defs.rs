@@ -15,11 +15,165 @@ pub const REQUESTS_INFLIGHT: MetricDef = MetricDef {
 pub const UPSTREAM_REQUEST_DURATION: MetricDef = MetricDef {
     name: "upstream.request.duration",
     metric_type: MetricType::Histogram,
-    description: "Per-cell upstream request duration in seconds. Tagged with cell_id, status (the status-code if successful, 'timeout', or 'error').",
+    description: "Per-cell upstream request duration in seconds, until the response headers arrive for streamed responses. Tagged with cell_id, status (the status-code if successful, 'timeout', or 'error').",
+};
+
+pub const CROSS_LOCALITY_KEYS: MetricDef = MetricDef {
+    name: "cross_locality.keys",
+    metric_type: MetricType::Counter,
+    description: "Number of keys forwarded to a cell outside the route's locality. Tagged with handler, locality, target_locality.",
+};
+
+pub const DUPLICATE_KEYS: MetricDef = MetricDef {
+    name: "duplicate_keys",
+    metric_type: MetricType::Counter,
+    description: "Number of repeated keys removed from requests before routing. Tagged with handler.",
+};
+
+pub const HEARTBEAT_ACK_LAG: MetricDef = MetricDef {
+    name: "heartbeat.ack_lag",
+    metric_type: MetricType::Histogram,
+    description: "Time in seconds from broadcasting a relay heartbeat until a cell acknowledged it. Tagged with cell_id.",
+};
+
+pub const BUFFERED_BODY_BYTES: MetricDef = MetricDef {
+    name: "buffered_body.bytes",
+    metric_type: MetricType::Gauge,
+    description: "Bytes of request bodies currently buffered across in-flight requests",
+};
+
+pub const CANARY_RESULT: MetricDef = MetricDef {
+    name: "canary.result",
+    metric_type: MetricType::Counter,
+    description: "Outcome of a synthetic canary request. Tagged with check, host, result ('success', 'no_route', 'upstream_error' or 'missing_keys').",
+};
+
+pub const CANARY_DURATION: MetricDef = MetricDef {
+    name: "canary.duration",
+    metric_type: MetricType::Histogram,
+    description: "Duration of a synthetic canary request in seconds, through the full split and merge path. Tagged with check, host.",
+};
+
+pub const ROUTE_BUDGET_INFLIGHT: MetricDef = MetricDef {
+    name: "route_budget.inflight",
+    metric_type: MetricType::Gauge,
+    description: "Requests currently holding a permit of a route with max_concurrent_requests. Tagged with handler, locality.",
+};
+
+pub const ROUTE_BUDGET_REJECTED: MetricDef = MetricDef {
+    name: "route_budget.rejected",
+    metric_type: MetricType::Counter,
+    description: "Requests rejected with 503 because their route was at max_concurrent_requests. Tagged with handler, locality.",
+};
+
+pub const RESPONSE_SCHEMA_DEVIATIONS: MetricDef = MetricDef {
+    name: "response.schema_deviations",
+    metric_type: MetricType::Counter,
+    description: "Cell responses whose shape deviates from the cell's declared protocol version. Tagged with handler, cell_id, protocol_version, field.",
+};
+
+pub const MERGE_CONFLICTS: MetricDef = MetricDef {
+    name: "merge.conflicts",
+    metric_type: MetricType::Counter,
+    description: "Keys returned by more than one cell while merging responses. Tagged with handler, kept_cell_id, dropped_cell_id.",
+};
+
+pub const LATE_RESPONSES: MetricDef = MetricDef {
+    name: "late_responses",
+    metric_type: MetricType::Counter,
+    description: "Cell responses that arrived after the subsequent task timeout. Tagged with cell_id, outcome ('cached', 'dropped' or 'used').",
+};
+
+pub const QUORUM_FAILURES: MetricDef = MetricDef {
+    name: "merge.quorum_failures",
+    metric_type: MetricType::Counter,
+    description: "Broadcast requests failed because too few cells succeeded or requested IDs were unresolved. Tagged with handler, reason ('quorum' or 'unresolved').",
+};
+
+pub const ROUTING_KEY_SHARE: MetricDef = MetricDef {
+    name: "routing.key_share",
+    metric_type: MetricType::Gauge,
+    description: "Share of the project config keys routed to a cell in the last routing drift window. Tagged with cell_id.",
+};
+
+pub const ROUTING_DRIFT: MetricDef = MetricDef {
+    name: "routing.drift",
+    metric_type: MetricType::Gauge,
+    description: "Share of the routed project config keys that moved to another cell compared to the previous window, between 0 and 1.",
+};
+
+pub const CELL_RETRIES: MetricDef = MetricDef {
+    name: "upstream.retries",
+    metric_type: MetricType::Counter,
+    description: "Cell requests retried after failing to reach the cell. Tagged with cell_id.",
+};
+
+pub const CELL_TASK_PANICS: MetricDef = MetricDef {
+    name: "upstream.task_panics",
+    metric_type: MetricType::Counter,
+    description: "Cell request tasks that panicked. Tagged with cell_id.",
+};
+
+pub const PANIC_BREAKER_REJECTED: MetricDef = MetricDef {
+    name: "panic_breaker.rejected",
+    metric_type: MetricType::Counter,
+    description: "Cell requests not sent because the cell's panic breaker is open. Tagged with cell_id.",
+};
+
+pub const RATE_LIMIT_REJECTED: MetricDef = MetricDef {
+    name: "rate_limit.rejected",
+    metric_type: MetricType::Counter,
+    description: "Cell requests not sent because the cell exceeded its max_rps. Tagged with cell_id.",
+};
+
+pub const RATE_LIMIT_TOKENS: MetricDef = MetricDef {
+    name: "rate_limit.tokens",
+    metric_type: MetricType::Gauge,
+    description: "Requests a cell with max_rps can still be sent right away, after the last request to it. Tagged with cell_id.",
+};
+
+pub const CELLS_UNRECONCILED: MetricDef = MetricDef {
+    name: "cells.unreconciled",
+    metric_type: MetricType::Gauge,
+    description: "Cells known to only one of the config and the locator's cell catalog, set by the cell reconciliation. Tagged with missing_from ('catalog' for configured cells the locator does not know, 'config' for catalog cells that are not configured).",
+};
+
+pub const CELLS_INELIGIBLE: MetricDef = MetricDef {
+    name: "cells.ineligible",
+    metric_type: MetricType::Gauge,
+    description: "Configured cells left out of requests sent to all cells of a locality because they are missing from the locator's cell catalog.",
+};
+
+pub const AUDIT_DROPPED: MetricDef = MetricDef {
+    name: "audit.dropped",
+    metric_type: MetricType::Counter,
+    description: "Audit records dropped because the queue of records waiting to be written was full.",
 };
 
 pub const ALL_METRICS: &[MetricDef] = &[
     REQUEST_DURATION,
     REQUESTS_INFLIGHT,
     UPSTREAM_REQUEST_DURATION,
+    CROSS_LOCALITY_KEYS,
+    DUPLICATE_KEYS,
+    HEARTBEAT_ACK_LAG,
+    BUFFERED_BODY_BYTES,
+    CANARY_RESULT,
+    CANARY_DURATION,
+    ROUTE_BUDGET_INFLIGHT,
+    ROUTE_BUDGET_REJECTED,
+    RESPONSE_SCHEMA_DEVIATIONS,
+    MERGE_CONFLICTS,
+    LATE_RESPONSES,
+    QUORUM_FAILURES,
+    ROUTING_KEY_SHARE,
+    ROUTING_DRIFT,
+    CELL_RETRIES,
+    CELL_TASK_PANICS,
+    PANIC_BREAKER_REJECTED,
+    RATE_LIMIT_REJECTED,
+    RATE_LIMIT_TOKENS,
+    CELLS_UNRECONCILED,
+    CELLS_INELIGIBLE,
+    AUDIT_DROPPED,
 ];
@@ -0,0 +1,131 @@
+//! Panics of the tasks sending requests to cells.
+//!
+//! A panic in a cell request task only fails that cell's part of the request, which is
+//! reported like any other failed cell. The panic is logged at error level with its
+//! payload, which the Sentry tracing layer turns into an event. When `panic_breaker` is
+//! configured, a cell whose tasks panic `threshold` times within `window_secs` is not sent
+//! requests for `open_secs`, on the assumption that its responses or keys hit a bug.
+use crate::config;
+use crate::metrics_defs::CELL_TASK_PANICS;
+use std::any::Any;
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+#[derive(Default)]
+struct CellPanics {
+    recent: VecDeque<Instant>,
+    open_until: Option<Instant>,
+}
+
+pub struct PanicBreaker {
+    threshold: usize,
+    window: Duration,
+    open: Duration,
+    cells: Mutex<HashMap<String, CellPanics>>,
+}
+
+impl PanicBreaker {
+    pub fn new(config: config::PanicBreaker) -> Self {
+        Self {
+            threshold: config.threshold as usize,
+            window: Duration::from_secs(config.window_secs),
+            open: Duration::from_secs(config.open_secs),
+            cells: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Records a panic of a task of the cell. Returns true if this opened the breaker.
+    pub fn record(&self, cell_id: &str, now: Instant) -> bool {
+        let mut cells = self.cells.lock().unwrap();
+        let cell = cells.entry(cell_id.to_string()).or_default();
+        cell.recent.push_back(now);
+        while cell
+            .recent
+            .front()
+            .is_some_and(|&at| now.duration_since(at) >= self.window)
+        {
+            cell.recent.pop_front();
+        }
+        if cell.recent.len() < self.threshold || cell.open_until.is_some_and(|until| now < until) {
+            return false;
+        }
+        cell.recent.clear();
+        cell.open_until = Some(now + self.open);
+        true
+    }
+
+    /// Whether requests to the cell are currently held back.
+    pub fn is_open(&self, cell_id: &str, now: Instant) -> bool {
+        self.cells
+            .lock()
+            .unwrap()
+            .get(cell_id)
+            .and_then(|cell| cell.open_until)
+            .is_some_and(|until| now < until)
+    }
+}
+
+/// Reports the panic of a cell request task, and records it in the breaker if there is one.
+pub fn report(cell_id: &str, payload: &(dyn Any + Send), breaker: Option<&PanicBreaker>) {
+    let message = payload_message(payload);
+    tracing::error!(cell_id, panic = message, "Cell request task panicked");
+    metrics::counter!(CELL_TASK_PANICS.name, "cell_id" => cell_id.to_string()).increment(1);
+
+    if let Some(breaker) = breaker
+        && breaker.record(cell_id, Instant::now())
+    {
+        tracing::error!(
+            cell_id,
+            open_secs = breaker.open.as_secs(),
+            "Panic breaker opened, not sending requests to the cell"
+        );
+    }
+}
+
+/// Message of a panic, the payload of `panic!` with a message is a `&str` or a `String`.
+fn payload_message(payload: &(dyn Any + Send)) -> &str {
+    payload
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("<non-string payload>")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_breaker() {
+        let breaker = PanicBreaker::new(config::PanicBreaker {
+            threshold: 2,
+            window_secs: 60,
+            open_secs: 30,
+        });
+        let start = Instant::now();
+        let secs = Duration::from_secs;
+
+        assert!(!breaker.record("us1", start));
+        assert!(!breaker.is_open("us1", start));
+        // The first panic is outside the window
+        assert!(!breaker.record("us1", start + secs(60)));
+        assert!(breaker.record("us1", start + secs(70)));
+        assert!(breaker.is_open("us1", start + secs(70)));
+        assert!(!breaker.is_open("us2", start + secs(70)));
+        assert!(!breaker.is_open("us1", start + secs(100)));
+    }
+
+    #[test]
+    fn test_payload_message() {
+        let payload = std::panic::catch_unwind(|| panic!("static")).unwrap_err();
+        assert_eq!(payload_message(payload.as_ref()), "static");
+
+        let key = "abc";
+        let payload = std::panic::catch_unwind(|| panic!("key {key}")).unwrap_err();
+        assert_eq!(payload_message(payload.as_ref()), "key abc");
+
+        let payload = std::panic::catch_unwind(|| std::panic::panic_any(1)).unwrap_err();
+        assert_eq!(payload_message(payload.as_ref()), "<non-string payload>");
+    }
+}
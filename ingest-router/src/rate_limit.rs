@@ -0,0 +1,138 @@
+//! Rate limits of the requests sent to cells.
+//!
+//! A cell with `max_rps` has a token bucket holding up to one second of requests, which
+//! refills at `max_rps`. Every request sent to the cell takes a token, so that a fleet of
+//! relays cannot overload a small cell. Requests to a cell without tokens are not sent and
+//! fail like a cell that cannot be reached. A request whose cells are all limited is
+//! rejected with 429, and a `Retry-After` of when the first of them has a token again.
+use crate::config::CellConfig;
+use crate::metrics_defs::{RATE_LIMIT_REJECTED, RATE_LIMIT_TOKENS};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+struct TokenBucket {
+    max_rps: f64,
+    tokens: f64,
+    updated_at: Instant,
+}
+
+impl TokenBucket {
+    fn new(max_rps: u32, now: Instant) -> Self {
+        Self {
+            max_rps: max_rps.into(),
+            tokens: max_rps.into(),
+            updated_at: now,
+        }
+    }
+
+    /// Takes a token, or returns how long until there is one.
+    fn try_take(&mut self, now: Instant) -> Result<(), Duration> {
+        let elapsed = now.saturating_duration_since(self.updated_at).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.max_rps).min(self.max_rps);
+        self.updated_at = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - self.tokens) / self.max_rps))
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct CellRateLimits {
+    cells: HashMap<String, Mutex<TokenBucket>>,
+}
+
+impl CellRateLimits {
+    /// Builds a bucket for every cell with `max_rps`
+    pub fn from_config(localities: &HashMap<String, Vec<CellConfig>>) -> Self {
+        let now = Instant::now();
+        let cells = localities
+            .values()
+            .flatten()
+            .filter_map(|cell| {
+                let bucket = TokenBucket::new(cell.max_rps?, now);
+                Some((cell.id.clone(), Mutex::new(bucket)))
+            })
+            .collect();
+        Self { cells }
+    }
+
+    /// Takes a token of the cell, or returns how long until it has one. Cells without
+    /// `max_rps` are not limited.
+    pub fn try_acquire(&self, cell_id: &str, now: Instant) -> Result<(), Duration> {
+        let Some(bucket) = self.cells.get(cell_id) else {
+            return Ok(());
+        };
+        let mut bucket = bucket.lock().unwrap();
+        let result = bucket.try_take(now);
+        metrics::gauge!(RATE_LIMIT_TOKENS.name, "cell_id" => cell_id.to_string())
+            .set(bucket.tokens);
+        if result.is_err() {
+            metrics::counter!(RATE_LIMIT_REJECTED.name, "cell_id" => cell_id.to_string())
+                .increment(1);
+        }
+        result
+    }
+}
+
+/// Value of the `Retry-After` header for a wait, in whole seconds and at least one.
+pub fn retry_after_secs(wait: Duration) -> u64 {
+    wait.as_secs_f64().ceil().max(1.0) as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use url::Url;
+
+    fn cell(id: &str, max_rps: Option<u32>) -> CellConfig {
+        CellConfig {
+            id: id.to_string(),
+            sentry_url: Url::parse("http://sentry:8080").unwrap(),
+            relay_url: Url::parse("http://relay:8090").unwrap(),
+            protocol_version: None,
+            tls: None,
+            max_rps,
+        }
+    }
+
+    #[test]
+    fn test_rate_limits() {
+        let limits = CellRateLimits::from_config(&HashMap::from([(
+            "us".to_string(),
+            vec![cell("us1", Some(2)), cell("us2", None)],
+        )]));
+        let start = Instant::now();
+        let millis = Duration::from_millis;
+
+        // Bursts of up to one second of requests
+        assert!(limits.try_acquire("us1", start).is_ok());
+        assert!(limits.try_acquire("us1", start).is_ok());
+        assert_eq!(limits.try_acquire("us1", start), Err(millis(500)));
+        assert_eq!(
+            limits.try_acquire("us1", start + millis(250)),
+            Err(millis(250))
+        );
+        assert!(limits.try_acquire("us1", start + millis(500)).is_ok());
+        // Tokens do not accumulate beyond one second of requests
+        assert!(limits.try_acquire("us1", start + millis(10_000)).is_ok());
+        assert!(limits.try_acquire("us1", start + millis(10_000)).is_ok());
+        assert!(limits.try_acquire("us1", start + millis(10_000)).is_err());
+
+        for _ in 0..100 {
+            assert!(limits.try_acquire("us2", start).is_ok());
+        }
+        assert!(limits.try_acquire("unknown", start).is_ok());
+    }
+
+    #[test]
+    fn test_retry_after_secs() {
+        assert_eq!(retry_after_secs(Duration::ZERO), 1);
+        assert_eq!(retry_after_secs(Duration::from_millis(1)), 1);
+        assert_eq!(retry_after_secs(Duration::from_secs(1)), 1);
+        assert_eq!(retry_after_secs(Duration::from_millis(1500)), 2);
+    }
+}
@@ -0,0 +1,108 @@
+//! Per-route limits on concurrently handled requests.
+//!
+//! Requests of every route share the same runtime and upstream connections, so a storm on
+//! one endpoint (e.g. project configs) could otherwise starve the others (e.g. public keys).
+//! Routes with `max_concurrent_requests` get a `RouteBudget`, and requests over the limit
+//! are rejected with 503 right away instead of queueing.
+use crate::metrics_defs::{ROUTE_BUDGET_INFLIGHT, ROUTE_BUDGET_REJECTED};
+use std::sync::Arc;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+#[derive(Clone, Debug)]
+pub struct RouteBudget {
+    inner: Arc<Inner>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    limit: usize,
+    semaphore: Arc<Semaphore>,
+    // Metric tags identifying the route
+    handler: &'static str,
+    locality: String,
+}
+
+impl RouteBudget {
+    pub fn new(limit: usize, handler: &'static str, locality: String) -> Self {
+        RouteBudget {
+            inner: Arc::new(Inner {
+                limit,
+                semaphore: Arc::new(Semaphore::new(limit)),
+                handler,
+                locality,
+            }),
+        }
+    }
+
+    /// Number of requests currently holding a permit
+    pub fn in_use(&self) -> usize {
+        self.inner.limit - self.inner.semaphore.available_permits()
+    }
+
+    /// Takes a permit for one request, which is returned when dropped. None if the route is
+    /// saturated.
+    pub fn try_acquire(&self) -> Option<RoutePermit> {
+        match self.inner.semaphore.clone().try_acquire_owned() {
+            Ok(permit) => {
+                self.record_in_use(self.in_use());
+                Some(RoutePermit {
+                    budget: self.clone(),
+                    _permit: permit,
+                })
+            }
+            Err(_) => {
+                metrics::counter!(
+                    ROUTE_BUDGET_REJECTED.name,
+                    "handler" => self.inner.handler,
+                    "locality" => self.inner.locality.clone(),
+                )
+                .increment(1);
+                None
+            }
+        }
+    }
+
+    fn record_in_use(&self, in_use: usize) {
+        metrics::gauge!(
+            ROUTE_BUDGET_INFLIGHT.name,
+            "handler" => self.inner.handler,
+            "locality" => self.inner.locality.clone(),
+        )
+        .set(in_use as f64);
+    }
+}
+
+pub struct RoutePermit {
+    budget: RouteBudget,
+    _permit: OwnedSemaphorePermit,
+}
+
+impl Drop for RoutePermit {
+    fn drop(&mut self) {
+        // The semaphore permit is only released after this
+        self.budget
+            .record_in_use(self.budget.in_use().saturating_sub(1));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_route_budget() {
+        let budget = RouteBudget::new(2, "PublicKeys", "us".to_string());
+
+        let first = budget.try_acquire().unwrap();
+        let _second = budget.try_acquire().unwrap();
+        assert_eq!(budget.in_use(), 2);
+
+        // Saturated
+        assert!(budget.try_acquire().is_none());
+
+        // Permits are returned when dropped
+        drop(first);
+        assert_eq!(budget.in_use(), 1);
+        assert!(budget.try_acquire().is_some());
+    }
+}
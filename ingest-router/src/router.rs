@@ -1,31 +1,122 @@
 use crate::api::any_cell_handler::AnyCellHandler;
+use crate::api::envelope;
+use crate::api::minidump;
+use crate::api::ndjson_merge_handler::NdjsonMergeHandler;
+use crate::api::paginated_merge_handler::PaginatedMergeHandler;
 use crate::api::project_config::ProjectConfigsHandler;
-use crate::config::{CellConfig, HandlerAction, Route};
-use crate::handler::Handler;
+use crate::api::public_keys::PublicKeysHandler;
+use crate::api::quorum::QuorumPolicy;
+use crate::api::relay_heartbeat::RelayHeartbeatHandler;
+use crate::api::single_project::SingleProjectHandler;
+use crate::config::{CellConfig, HandlerAction, PublicKeys, RelayHeartbeat, Route};
+use crate::handler::{Handler, RequestContentType};
+use crate::header_allow_list::HeaderAllowList;
 use crate::locality::{Cells, Localities};
+use crate::route_budget::RouteBudget;
+use crate::routing_drift::KeyDistribution;
+use crate::traffic_report::TrafficReport;
 use hyper::Request;
+use hyper::header::{CONTENT_TYPE, HeaderMap};
 use locator::client::Locator;
+use serde::Serialize;
 use std::collections::HashMap;
 use std::sync::Arc;
 
+/// Route matched for a request
+pub struct ResolvedRoute {
+    pub handler: Arc<dyn Handler>,
+    pub cells: Cells,
+    pub content_type: ContentTypeCheck,
+    /// Limit on concurrently handled requests of the route, if configured
+    pub budget: Option<RouteBudget>,
+    /// Request headers forwarded to cells, all if not configured
+    pub forward_headers: Option<HeaderAllowList>,
+}
+
+/// Route a request would be matched to, for the admin listener
+#[derive(Debug, Serialize)]
+pub struct MatchedRoute {
+    /// Position of the route in the route table
+    pub index: usize,
+    pub route: Route,
+    /// Name of the route's handler
+    pub handler: &'static str,
+    /// Cells of the route's locality
+    pub cells: Vec<String>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ContentTypeCheck {
+    /// Accepted by the route, with the request's content type if it has one
+    Accepted(Option<RequestContentType>),
+    /// The route does not accept the request's content type
+    Unsupported,
+}
+
 /// Router that matches incoming requests against configured routes
+#[derive(Clone)]
 pub struct Router {
     routes: Arc<Vec<Route>>,
+    // Indexed like `routes`
+    budgets: Arc<Vec<Option<RouteBudget>>>,
+    // Indexed like `routes`
+    forward_headers: Arc<Vec<Option<HeaderAllowList>>>,
     action_to_handler: HashMap<HandlerAction, Arc<dyn Handler>>,
     localities_to_cells: Localities,
 }
 
+/// What the handlers of the routes are built with
+pub struct HandlerDeps {
+    pub locator: Locator,
+    pub cross_locality_routing: bool,
+    pub relay_heartbeat: RelayHeartbeat,
+    pub public_keys: PublicKeys,
+    /// Distribution of the project keys of project config requests, see `routing_drift`
+    pub key_distribution: Option<Arc<KeyDistribution>>,
+    pub traffic_report: Option<Arc<TrafficReport>>,
+}
+
+impl HandlerDeps {
+    /// Handlers resolving keys with `locator`, with the default options
+    pub fn new(locator: Locator) -> Self {
+        Self {
+            locator,
+            cross_locality_routing: false,
+            relay_heartbeat: RelayHeartbeat::default(),
+            public_keys: PublicKeys::default(),
+            key_distribution: None,
+            traffic_report: None,
+        }
+    }
+}
+
 impl Router {
     /// Creates a new router with the given routes
     pub fn new(
         routes: Vec<Route>,
         localities: HashMap<String, Vec<CellConfig>>,
-        locator: Locator,
+        deps: HandlerDeps,
     ) -> Self {
-        let action_to_handler = HashMap::from([
+        let HandlerDeps {
+            locator,
+            cross_locality_routing,
+            relay_heartbeat,
+            public_keys,
+            key_distribution,
+            traffic_report,
+        } = deps;
+        let mut project_configs =
+            ProjectConfigsHandler::new(locator.clone(), cross_locality_routing);
+        if let Some(distribution) = key_distribution {
+            project_configs = project_configs.with_key_distribution(distribution);
+        }
+        if let Some(traffic_report) = traffic_report {
+            project_configs = project_configs.with_traffic_report(traffic_report);
+        }
+        let mut action_to_handler = HashMap::from([
             (
                 HandlerAction::RelayProjectConfigs,
-                Arc::new(ProjectConfigsHandler::new(locator)) as Arc<dyn Handler>,
+                Arc::new(project_configs) as Arc<dyn Handler>,
             ),
             (
                 HandlerAction::Health,
@@ -41,27 +132,110 @@ impl Router {
             ),
             (
                 HandlerAction::PublicKeys,
-                Arc::new(AnyCellHandler::new("PublicKeys")),
+                Arc::new(PublicKeysHandler::new(
+                    QuorumPolicy::new(public_keys.quorum)
+                        .require_all_resolved(public_keys.require_all_resolved),
+                )),
+            ),
+            (
+                HandlerAction::RelayHeartbeat,
+                Arc::new(RelayHeartbeatHandler::new(relay_heartbeat.quorum)),
+            ),
+            (
+                HandlerAction::Envelope,
+                Arc::new(envelope::handler(locator.clone())),
+            ),
+            (
+                HandlerAction::Minidump,
+                Arc::new(minidump::handler(locator.clone())),
             ),
         ]);
+        // Configured per route
+        for route in &routes {
+            let handler: Arc<dyn Handler> = match &route.action {
+                HandlerAction::NdjsonMerge { source_field } => {
+                    Arc::new(NdjsonMergeHandler::new(source_field.clone()))
+                }
+                HandlerAction::PaginatedMerge { source_field } => {
+                    Arc::new(PaginatedMergeHandler::new(source_field.clone()))
+                }
+                HandlerAction::SingleProject { key_from } => {
+                    Arc::new(SingleProjectHandler::from_config(locator.clone(), key_from))
+                }
+                HandlerAction::ProxyToCell { key_from, target } => Arc::new(
+                    SingleProjectHandler::proxy_to_cell(locator.clone(), key_from, *target),
+                ),
+                _ => continue,
+            };
+            action_to_handler
+                .entry(route.action.clone())
+                .or_insert(handler);
+        }
+
+        let budgets = routes
+            .iter()
+            .map(|route| {
+                let limit = route.max_concurrent_requests?;
+                let handler = action_to_handler.get(&route.action)?.name();
+                Some(RouteBudget::new(limit, handler, route.locality.clone()))
+            })
+            .collect();
+        let forward_headers = routes
+            .iter()
+            .map(|route| route.forward_headers.as_deref().map(HeaderAllowList::new))
+            .collect();
 
         Self {
             routes: Arc::new(routes),
+            budgets: Arc::new(budgets),
+            forward_headers: Arc::new(forward_headers),
             action_to_handler,
             localities_to_cells: Localities::new(localities),
         }
     }
 
     /// Finds the first route that matches the incoming request
-    pub fn resolve<B>(&self, req: &Request<B>) -> Option<(Arc<dyn Handler>, Cells)> {
+    pub fn resolve<B>(&self, req: &Request<B>) -> Option<ResolvedRoute> {
+        let (index, route) = self.find_route(req)?;
+        let cells = self.localities_to_cells.get_cells(&route.locality)?;
+        let handler = self.action_to_handler.get(&route.action)?.clone();
+        Some(ResolvedRoute {
+            handler,
+            cells,
+            content_type: check_content_type(req.headers(), &route.content_types),
+            budget: self.budgets[index].clone(),
+            forward_headers: self.forward_headers[index].clone(),
+        })
+    }
+
+    /// Cells of the localities, shared with the handlers
+    pub fn localities(&self) -> Localities {
+        self.localities_to_cells.clone()
+    }
+
+    /// The routes in the order they are tried
+    pub fn routes(&self) -> &[Route] {
+        &self.routes
+    }
+
+    /// The route the request would be matched to, without handling it
+    pub fn match_request<B>(&self, req: &Request<B>) -> Option<MatchedRoute> {
+        let (index, route) = self.find_route(req)?;
+        let cells = self.localities_to_cells.get_cells(&route.locality)?;
+        let handler = self.action_to_handler.get(&route.action)?;
+        Some(MatchedRoute {
+            index,
+            route: route.clone(),
+            handler: handler.name(),
+            cells: cells.cell_list().cloned().collect(),
+        })
+    }
+
+    fn find_route<B>(&self, req: &Request<B>) -> Option<(usize, &Route)> {
         self.routes
             .iter()
-            .find(|route| self.matches_route(req, route))
-            .and_then(|route| {
-                let cells = self.localities_to_cells.get_cells(&route.locality)?;
-                let handler = self.action_to_handler.get(&route.action)?.clone();
-                Some((handler, cells))
-            })
+            .enumerate()
+            .find(|(_, route)| self.matches_route(req, route))
     }
 
     /// Checks if a request matches a route's criteria
@@ -87,7 +261,7 @@ impl Router {
 
         // Match path if specified
         if let Some(expected_path) = &route.r#match.path
-            && req.uri().path() != expected_path
+            && !path_matches(expected_path, req.uri().path())
         {
             return false;
         }
@@ -99,15 +273,68 @@ impl Router {
             return false;
         }
 
+        // Match headers if specified
+        for expected in &route.r#match.headers {
+            match req.headers().get(expected.name.as_str()) {
+                Some(value) if expected.value.as_ref().is_none_or(|v| value == v) => {}
+                _ => return false,
+            }
+        }
+
         true
     }
 }
 
+/// Checks a request path against a route path, whose `{name}` segments match any
+/// non-empty segment.
+fn path_matches(pattern: &str, path: &str) -> bool {
+    if !pattern.contains('{') {
+        return pattern == path;
+    }
+    let mut segments = path.split('/');
+    pattern.split('/').all(|expected| {
+        segments.next().is_some_and(|segment| {
+            let placeholder = expected.starts_with('{') && expected.ends_with('}');
+            if placeholder {
+                !segment.is_empty()
+            } else {
+                segment == expected
+            }
+        })
+    }) && segments.next().is_none()
+}
+
+/// Checks the request's content type against the content types accepted by a route.
+fn check_content_type(headers: &HeaderMap, accepted: &[String]) -> ContentTypeCheck {
+    // Media type without parameters such as the charset
+    let content_type = headers
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split(';').next())
+        .map(|media_type| media_type.trim().to_ascii_lowercase())
+        .filter(|media_type| !media_type.is_empty());
+
+    if accepted.is_empty() {
+        return ContentTypeCheck::Accepted(content_type.map(RequestContentType));
+    }
+
+    match content_type {
+        Some(content_type)
+            if accepted
+                .iter()
+                .any(|a| a.eq_ignore_ascii_case(&content_type)) =>
+        {
+            ContentTypeCheck::Accepted(Some(RequestContentType(content_type)))
+        }
+        _ => ContentTypeCheck::Unsupported,
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::config::{HttpMethod, Match, Route};
-    use crate::testutils::get_mock_provider;
+    use crate::config::{HeaderMatch, HttpMethod, KeySource, Match, Route};
+    use crate::testutils::{get_mock_provider, unreachable_control_plane};
     use http_body_util::Empty;
     use http_body_util::{BodyExt, combinators::BoxBody};
     use hyper::body::Bytes;
@@ -124,18 +351,26 @@ mod tests {
                     host: Some("api.example.com".into()),
                     path: Some("/api/test".into()),
                     method: Some(HttpMethod::Post),
+                    headers: vec![],
                 },
                 action: HandlerAction::RelayProjectConfigs,
                 locality: "us".to_string(),
+                content_types: vec![],
+                max_concurrent_requests: None,
+                forward_headers: None,
             },
             Route {
                 r#match: Match {
                     host: None,
                     path: Some("/health".to_string()),
                     method: Some(HttpMethod::Get),
+                    headers: vec![],
                 },
                 action: HandlerAction::Health,
                 locality: "us".to_string(),
+                content_types: vec![],
+                max_concurrent_requests: None,
+                forward_headers: None,
             },
         ];
 
@@ -147,20 +382,23 @@ mod tests {
                 id: "us1".to_string(),
                 sentry_url: Url::parse("https://sentry.io/us1").unwrap(),
                 relay_url: Url::parse("https://relay.io/us1").unwrap(),
+                protocol_version: None,
+                tls: None,
+                max_rps: None,
             }],
         )]);
 
         let (_dir, provider) = get_mock_provider().await;
         let locator_service = LocatorService::new(
             LocatorDataType::ProjectKey,
-            "http://control-plane-url".to_string(),
+            unreachable_control_plane(),
             Arc::new(provider),
             None,
             None,
         );
         let locator = Locator::from_in_process_service(locator_service);
 
-        Router::new(routes, localities, locator)
+        Router::new(routes, localities, HandlerDeps::new(locator))
     }
 
     fn test_request(
@@ -187,13 +425,13 @@ mod tests {
 
         // Should match first route
         let req = test_request(Method::POST, "/api/test", Some("api.example.com"));
-        let (handler, _cells) = router.resolve(&req).unwrap();
-        assert_eq!(handler.name(), "ProjectConfigsHandler");
+        let resolved = router.resolve(&req).unwrap();
+        assert_eq!(resolved.handler.name(), "ProjectConfigsHandler");
 
         // Should match second route
         let req = test_request(Method::GET, "/health", None);
-        let (handler, _cells) = router.resolve(&req).unwrap();
-        assert_eq!(handler.name(), "HealthCheck");
+        let resolved = router.resolve(&req).unwrap();
+        assert_eq!(resolved.handler.name(), "HealthCheck");
     }
 
     #[tokio::test]
@@ -204,6 +442,22 @@ mod tests {
         assert!(router.resolve(&req).is_none());
     }
 
+    #[tokio::test]
+    async fn test_match_request() {
+        let router = test_router(None).await;
+
+        let req = test_request(Method::GET, "/health", None);
+        let matched = router.match_request(&req).unwrap();
+        assert_eq!(matched.index, 1);
+        assert_eq!(matched.route, router.routes()[1]);
+        assert_eq!(matched.handler, "HealthCheck");
+        assert_eq!(matched.cells, vec!["us1".to_string()]);
+
+        // The method is part of the match
+        let req = test_request(Method::POST, "/health", None);
+        assert!(router.match_request(&req).is_none());
+    }
+
     #[tokio::test]
     async fn test_host_matching_with_port() {
         let routes = vec![Route {
@@ -211,17 +465,21 @@ mod tests {
                 host: Some("api.example.com".to_string()),
                 path: None,
                 method: None,
+                headers: vec![],
             },
             action: HandlerAction::RelayProjectConfigs,
             locality: "us".to_string(),
+            content_types: vec![],
+            max_concurrent_requests: None,
+            forward_headers: None,
         }];
 
         let router = test_router(Some(routes)).await;
 
         // Should strip port and match
         let req = test_request(Method::GET, "/test", Some("api.example.com:8080"));
-        let (handler, _cells) = router.resolve(&req).unwrap();
-        assert_eq!(handler.name(), "ProjectConfigsHandler");
+        let resolved = router.resolve(&req).unwrap();
+        assert_eq!(resolved.handler.name(), "ProjectConfigsHandler");
     }
 
     #[tokio::test]
@@ -231,20 +489,183 @@ mod tests {
                 host: None,
                 path: Some("/api/test".to_string()),
                 method: Some(HttpMethod::Post),
+                headers: vec![],
             },
             action: HandlerAction::RelayProjectConfigs,
             locality: "us".to_string(),
+            content_types: vec![],
+            max_concurrent_requests: None,
+            forward_headers: None,
         }];
 
         let router = test_router(Some(routes)).await;
 
         // POST should match
         let req = test_request(Method::POST, "/api/test", None);
-        let (handler, _cells) = router.resolve(&req).unwrap();
-        assert_eq!(handler.name(), "ProjectConfigsHandler");
+        let resolved = router.resolve(&req).unwrap();
+        assert_eq!(resolved.handler.name(), "ProjectConfigsHandler");
 
         // GET should not match
         let req = test_request(Method::GET, "/api/test", None);
         assert!(router.resolve(&req).is_none());
     }
+
+    #[tokio::test]
+    async fn test_path_placeholders() {
+        let routes = vec![Route {
+            r#match: Match {
+                host: None,
+                path: Some("/api/{project_id}/minidump/".to_string()),
+                method: Some(HttpMethod::Post),
+                headers: vec![],
+            },
+            action: HandlerAction::Minidump,
+            locality: "us".to_string(),
+            content_types: vec![],
+            max_concurrent_requests: None,
+            forward_headers: None,
+        }];
+
+        let router = test_router(Some(routes)).await;
+
+        let req = test_request(Method::POST, "/api/42/minidump/?sentry_key=abc", None);
+        let resolved = router.resolve(&req).unwrap();
+        assert_eq!(resolved.handler.name(), "MinidumpHandler");
+
+        for path in [
+            "/api//minidump/",
+            "/api/42/minidump",
+            "/api/42/minidump/extra",
+            "/api/42/43/minidump/",
+        ] {
+            let req = test_request(Method::POST, path, None);
+            assert!(router.resolve(&req).is_none(), "{path}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_single_project_route() {
+        let routes = vec![Route {
+            r#match: Match {
+                host: None,
+                path: Some("/api/{project_id}/store/".to_string()),
+                method: Some(HttpMethod::Post),
+                headers: vec![],
+            },
+            action: HandlerAction::SingleProject {
+                key_from: vec![KeySource::AuthHeader],
+            },
+            locality: "us".to_string(),
+            content_types: vec![],
+            max_concurrent_requests: None,
+            forward_headers: None,
+        }];
+
+        let router = test_router(Some(routes)).await;
+
+        let req = test_request(Method::POST, "/api/42/store/", None);
+        let resolved = router.resolve(&req).unwrap();
+        assert_eq!(resolved.handler.name(), "SingleProjectHandler");
+    }
+
+    #[tokio::test]
+    async fn test_header_matching() {
+        let routes = vec![Route {
+            r#match: Match {
+                host: None,
+                path: Some("/api/test".to_string()),
+                method: None,
+                headers: vec![
+                    HeaderMatch {
+                        name: "X-Sentry-Relay-Id".to_string(),
+                        value: None,
+                        param: None,
+                    },
+                    HeaderMatch {
+                        name: "x-tenant".to_string(),
+                        value: Some("acme".to_string()),
+                        param: None,
+                    },
+                ],
+            },
+            action: HandlerAction::RelayProjectConfigs,
+            locality: "us".to_string(),
+            content_types: vec![],
+            max_concurrent_requests: None,
+            forward_headers: None,
+        }];
+
+        let router = test_router(Some(routes)).await;
+        let request = |headers: &[(&'static str, &'static str)]| {
+            let mut req = test_request(Method::POST, "/api/test", None);
+            for (name, value) in headers {
+                req.headers_mut()
+                    .insert(*name, http::HeaderValue::from_static(value));
+            }
+            req
+        };
+
+        let req = request(&[("x-sentry-relay-id", "relay-1"), ("x-tenant", "acme")]);
+        assert!(router.resolve(&req).is_some());
+
+        // Wrong value
+        let req = request(&[("x-sentry-relay-id", "relay-1"), ("x-tenant", "other")]);
+        assert!(router.resolve(&req).is_none());
+
+        // Missing header
+        let req = request(&[("x-tenant", "acme")]);
+        assert!(router.resolve(&req).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_content_type() {
+        let routes = vec![Route {
+            r#match: Match {
+                host: None,
+                path: Some("/api/test".to_string()),
+                method: None,
+                headers: vec![],
+            },
+            action: HandlerAction::RelayProjectConfigs,
+            locality: "us".to_string(),
+            content_types: vec!["application/json".to_string()],
+            max_concurrent_requests: None,
+            forward_headers: None,
+        }];
+
+        let router = test_router(Some(routes)).await;
+        let request = |content_type: Option<&str>| {
+            let mut req = test_request(Method::POST, "/api/test", None);
+            if let Some(content_type) = content_type {
+                req.headers_mut()
+                    .insert(CONTENT_TYPE, content_type.parse().unwrap());
+            }
+            req
+        };
+
+        // Parameters and case are ignored
+        let resolved = router
+            .resolve(&request(Some("Application/JSON; charset=utf-8")))
+            .unwrap();
+        assert_eq!(
+            resolved.content_type,
+            ContentTypeCheck::Accepted(Some(RequestContentType("application/json".to_string())))
+        );
+
+        let resolved = router
+            .resolve(&request(Some("application/x-www-form-urlencoded")))
+            .unwrap();
+        assert_eq!(resolved.content_type, ContentTypeCheck::Unsupported);
+
+        let resolved = router.resolve(&request(None)).unwrap();
+        assert_eq!(resolved.content_type, ContentTypeCheck::Unsupported);
+
+        // Routes without content types accept anything
+        let router = test_router(None).await;
+        let req = test_request(Method::GET, "/health", None);
+        assert_eq!(
+            router.resolve(&req).unwrap().content_type,
+            ContentTypeCheck::Accepted(None)
+        );
+    }
 }
@@ -0,0 +1,162 @@
+//! Drift of the distribution of routed keys across cells.
+//!
+//! The project configs handler counts the keys it routes to each cell. At the end of every
+//! window, the share of the keys routed to each cell is emitted as `routing.key_share` and
+//! compared to the previous window. The drift is the share of keys that would have to move
+//! to another cell to turn the previous distribution into the current one, between 0 and 1.
+//! It is emitted as `routing.drift`, and a warning with the largest shifts is logged once
+//! it reaches the threshold: routing that shifts outside of a planned migration may be
+//! caused by a control plane bug. Windows with fewer than `min_keys` keys are skipped, as
+//! their distribution is mostly noise.
+use crate::config::RoutingDrift as RoutingDriftConfig;
+use crate::handler::CellId;
+use crate::metrics_defs::{ROUTING_DRIFT, ROUTING_KEY_SHARE};
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::MissedTickBehavior;
+
+// Number of cells whose share changed the most that are logged with a drift
+const LOGGED_SHIFTS: usize = 5;
+
+/// Keys routed to each cell in the current window
+#[derive(Debug, Default)]
+pub struct KeyDistribution {
+    counts: Mutex<HashMap<CellId, u64>>,
+}
+
+impl KeyDistribution {
+    pub fn record(&self, cell_id: &str, keys: usize) {
+        let mut counts = self.counts.lock().unwrap_or_else(|e| e.into_inner());
+        *counts.entry(cell_id.to_string()).or_default() += keys as u64;
+    }
+
+    fn take(&self) -> HashMap<CellId, u64> {
+        std::mem::take(&mut *self.counts.lock().unwrap_or_else(|e| e.into_inner()))
+    }
+}
+
+pub struct RoutingDrift {
+    distribution: Arc<KeyDistribution>,
+    window: Duration,
+    threshold: f64,
+    min_keys: u64,
+    // Share of the keys of each cell in the last compared window
+    previous: Option<HashMap<CellId, f64>>,
+}
+
+impl RoutingDrift {
+    pub fn new(config: RoutingDriftConfig) -> Self {
+        Self {
+            distribution: Arc::default(),
+            window: Duration::from_secs(config.window_secs),
+            threshold: config.threshold,
+            min_keys: config.min_keys,
+            previous: None,
+        }
+    }
+
+    /// Distribution the routed keys are recorded in
+    pub fn distribution(&self) -> Arc<KeyDistribution> {
+        self.distribution.clone()
+    }
+
+    /// Compares the distribution once per window, forever.
+    pub async fn run(mut self) {
+        let mut interval = tokio::time::interval(self.window);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        // The first tick completes immediately
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            self.close_window();
+        }
+    }
+
+    /// Compares the keys recorded since the last call to the previous window, returns the
+    /// drift if the windows were compared.
+    fn close_window(&mut self) -> Option<f64> {
+        let counts = self.distribution.take();
+        let total: u64 = counts.values().sum();
+        if total < self.min_keys {
+            tracing::debug!(total, "Too few routed keys to compare their distribution");
+            return None;
+        }
+        let shares: HashMap<CellId, f64> = counts
+            .into_iter()
+            .map(|(cell_id, count)| (cell_id, count as f64 / total as f64))
+            .collect();
+
+        let previous = self.previous.replace(shares.clone()).unwrap_or_default();
+        let cells: HashSet<&CellId> = previous.keys().chain(shares.keys()).collect();
+        let mut shifts: Vec<(&CellId, f64)> = cells
+            .into_iter()
+            .map(|cell_id| {
+                let share = shares.get(cell_id).copied().unwrap_or_default();
+                // Cells without keys in this window are reset instead of keeping their share
+                metrics::gauge!(ROUTING_KEY_SHARE.name, "cell_id" => cell_id.clone()).set(share);
+                let before = previous.get(cell_id).copied().unwrap_or_default();
+                (cell_id, share - before)
+            })
+            .collect();
+        if previous.is_empty() {
+            return None;
+        }
+
+        let drift = shifts.iter().map(|(_, shift)| shift.abs()).sum::<f64>() / 2.0;
+        metrics::gauge!(ROUTING_DRIFT.name).set(drift);
+        if drift >= self.threshold {
+            shifts.sort_by(|a, b| b.1.abs().total_cmp(&a.1.abs()));
+            shifts.truncate(LOGGED_SHIFTS);
+            tracing::warn!(
+                drift,
+                threshold = self.threshold,
+                keys = total,
+                ?shifts,
+                "Distribution of routed keys across cells shifted"
+            );
+        }
+        Some(drift)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn routing_drift() -> RoutingDrift {
+        RoutingDrift::new(RoutingDriftConfig {
+            window_secs: 300,
+            threshold: 0.2,
+            min_keys: 100,
+        })
+    }
+
+    #[test]
+    fn test_close_window() {
+        let mut drift = routing_drift();
+        let distribution = drift.distribution();
+
+        // Nothing to compare the first window to
+        distribution.record("us1", 50);
+        distribution.record("us2", 50);
+        assert_eq!(drift.close_window(), None);
+
+        distribution.record("us1", 50);
+        distribution.record("us2", 25);
+        distribution.record("us2", 25);
+        assert_eq!(drift.close_window(), Some(0.0));
+
+        // A quarter of the keys moved from us2 to a new cell
+        distribution.record("us1", 50);
+        distribution.record("us2", 25);
+        distribution.record("us3", 25);
+        assert_eq!(drift.close_window(), Some(0.25));
+
+        // Too few keys, compared to the last full window later on
+        distribution.record("us1", 10);
+        assert_eq!(drift.close_window(), None);
+        distribution.record("us1", 100);
+        assert_eq!(drift.close_window(), Some(0.5));
+    }
+}
@@ -0,0 +1,256 @@
+//! Streaming merge of line-delimited responses.
+//!
+//! In `ExecutionMode::Streaming`, the response bodies of all cells are read concurrently and
+//! split into lines, which are forwarded into a single response body as soon as they are
+//! complete. Lines of different cells are interleaved in arrival order, lines are never
+//! split or merged.
+//!
+//! A cell whose stream fails, goes idle for longer than the HTTP timeout, or sends a line
+//! longer than `MAX_LINE_LEN` is cut off. Its remaining lines are replaced by an error line,
+//! so that clients can tell a truncated response from a complete one.
+use crate::errors::IngestRouterError;
+use crate::handler::{CellId, Handler};
+use http_body_util::BodyExt;
+use hyper::body::{Body, Bytes, Frame};
+use std::fmt::Display;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+/// Lines buffered between the cells and the client before cells are back-pressured
+pub const LINE_BUFFER: usize = 64;
+
+/// Longest line accepted from a cell, without its newline
+pub const MAX_LINE_LEN: usize = 1024 * 1024;
+
+/// Response body of merged lines, ending once every cell's stream has ended
+pub struct MergedLines {
+    rx: mpsc::Receiver<Bytes>,
+}
+
+impl MergedLines {
+    pub fn new(rx: mpsc::Receiver<Bytes>) -> Self {
+        Self { rx }
+    }
+}
+
+impl Body for MergedLines {
+    type Data = Bytes;
+    type Error = IngestRouterError;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        self.rx
+            .poll_recv(cx)
+            .map(|line| line.map(|line| Ok(Frame::data(line))))
+    }
+}
+
+#[derive(Debug, PartialEq)]
+struct LineTooLong;
+
+/// Splits `data` off at every newline, keeping the incomplete last line in `buffer`. Only
+/// `data` is scanned, since `buffer` holds no newline.
+fn split_lines(buffer: &mut Vec<u8>, data: &[u8]) -> Result<Vec<Bytes>, LineTooLong> {
+    let scanned = buffer.len();
+    buffer.extend_from_slice(data);
+
+    let mut lines = Vec::new();
+    let mut start = 0;
+    for (offset, _) in data.iter().enumerate().filter(|(_, byte)| **byte == b'\n') {
+        let end = scanned + offset;
+        let mut line = &buffer[start..end];
+        start = end + 1;
+        if line.last() == Some(&b'\r') {
+            line = &line[..line.len() - 1];
+        }
+        if line.len() > MAX_LINE_LEN {
+            return Err(LineTooLong);
+        }
+        if !line.is_empty() {
+            lines.push(Bytes::copy_from_slice(line));
+        }
+    }
+    buffer.drain(..start);
+
+    if buffer.len() > MAX_LINE_LEN {
+        return Err(LineTooLong);
+    }
+    Ok(lines)
+}
+
+/// Line sent in place of the remaining lines of a cell that was cut off
+fn error_line(cell_id: &str, error: impl Display) -> Bytes {
+    let line = serde_json::json!({
+        "error": "response truncated",
+        "cell_id": cell_id,
+        "reason": error.to_string(),
+    });
+    Bytes::from(format!("{line}\n"))
+}
+
+/// Forwards the lines of a cell's response body to `tx` until the body ends, fails, or the
+/// client goes away. The cell is cut off if no data arrives for `idle_timeout`.
+pub async fn forward_lines<B>(
+    handler: Arc<dyn Handler>,
+    cell_id: CellId,
+    mut body: B,
+    tx: mpsc::Sender<Bytes>,
+    idle_timeout: Duration,
+) where
+    B: Body<Data = Bytes> + Unpin,
+    B::Error: Display,
+{
+    let mut buffer = Vec::new();
+
+    loop {
+        let frame = match tokio::time::timeout(idle_timeout, body.frame()).await {
+            Ok(frame) => frame,
+            Err(_) => {
+                let error = format!("no data for {}s", idle_timeout.as_secs_f64());
+                return cut_off(&*handler, &cell_id, &tx, error).await;
+            }
+        };
+        let (data, ended) = match frame {
+            Some(Ok(frame)) => match frame.into_data() {
+                Ok(data) => (data, false),
+                // Trailers are not forwarded
+                Err(_) => continue,
+            },
+            Some(Err(e)) => return cut_off(&*handler, &cell_id, &tx, e).await,
+            // A last line without a trailing newline is still complete
+            None => (Bytes::from_static(b"\n"), true),
+        };
+        let Ok(lines) = split_lines(&mut buffer, &data) else {
+            let error = format!("line longer than {MAX_LINE_LEN} bytes");
+            return cut_off(&*handler, &cell_id, &tx, error).await;
+        };
+
+        for line in lines {
+            let mut line = handler.map_line(&cell_id, line).to_vec();
+            line.push(b'\n');
+            if tx.send(Bytes::from(line)).await.is_err() {
+                // The client disconnected
+                return;
+            }
+        }
+
+        if ended {
+            return;
+        }
+    }
+}
+
+async fn cut_off(
+    handler: &dyn Handler,
+    cell_id: &str,
+    tx: &mpsc::Sender<Bytes>,
+    error: impl Display,
+) {
+    tracing::warn!(
+        cell_id = %cell_id,
+        error = %error,
+        "{} response stream failed",
+        handler.name()
+    );
+    let _ = tx.send(error_line(cell_id, error)).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::ndjson_merge_handler::NdjsonMergeHandler;
+
+    #[test]
+    fn test_split_lines() {
+        let mut buffer = Vec::new();
+
+        assert_eq!(
+            split_lines(&mut buffer, b"{\"a\":1}\n{\"b\""),
+            Ok(vec![Bytes::from_static(b"{\"a\":1}")])
+        );
+        assert_eq!(buffer, b"{\"b\"");
+
+        // Completed by a later chunk, empty lines and CRLF line endings are handled
+        assert_eq!(
+            split_lines(&mut buffer, b":2}\r\n\n{\"c\":3}\n"),
+            Ok(vec![
+                Bytes::from_static(b"{\"b\":2}"),
+                Bytes::from_static(b"{\"c\":3}")
+            ])
+        );
+        assert!(buffer.is_empty());
+
+        // Lines are limited in length, complete or not
+        let long = vec![b'a'; MAX_LINE_LEN + 1];
+        assert_eq!(split_lines(&mut Vec::new(), &long), Err(LineTooLong));
+        let mut buffer = long[..MAX_LINE_LEN].to_vec();
+        assert_eq!(split_lines(&mut buffer, b"a\n"), Err(LineTooLong));
+        let mut buffer = long[..MAX_LINE_LEN - 1].to_vec();
+        assert_eq!(split_lines(&mut buffer, b"a\n").unwrap().len(), 1);
+    }
+
+    // Body sending `data` and then never ending
+    struct StalledBody(Option<Bytes>);
+
+    impl Body for StalledBody {
+        type Data = Bytes;
+        type Error = std::convert::Infallible;
+
+        fn poll_frame(
+            mut self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+        ) -> Poll<Option<Result<Frame<Bytes>, Self::Error>>> {
+            match self.0.take() {
+                Some(data) => Poll::Ready(Some(Ok(Frame::data(data)))),
+                None => Poll::Pending,
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_forward_lines_cut_off() {
+        let handler: Arc<dyn Handler> = Arc::new(NdjsonMergeHandler::new(None));
+        let lines = |body| async {
+            let (tx, rx) = mpsc::channel(LINE_BUFFER);
+            forward_lines(
+                handler.clone(),
+                "us1".into(),
+                body,
+                tx,
+                Duration::from_millis(20),
+            )
+            .await;
+            MergedLines::new(rx).collect().await.unwrap().to_bytes()
+        };
+
+        // Idle cells are cut off after the complete lines
+        let body = lines(StalledBody(Some(Bytes::from_static(b"{\"a\":1}\n{\"b\"")))).await;
+        let mut body = body.split(|byte| *byte == b'\n');
+        assert_eq!(body.next().unwrap(), b"{\"a\":1}");
+        let error: serde_json::Value = serde_json::from_slice(body.next().unwrap()).unwrap();
+        assert_eq!(error["error"], "response truncated");
+        assert_eq!(error["cell_id"], "us1");
+
+        // Cells sending too long lines are cut off
+        let long = Bytes::from(vec![b'a'; MAX_LINE_LEN + 1]);
+        let body = lines(StalledBody(Some(long))).await;
+        let error: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(error["error"], "response truncated");
+    }
+
+    #[tokio::test]
+    async fn test_merged_lines() {
+        let (tx, rx) = mpsc::channel(LINE_BUFFER);
+        tx.send(Bytes::from_static(b"{\"a\":1}\n")).await.unwrap();
+        tx.send(Bytes::from_static(b"{\"b\":2}\n")).await.unwrap();
+        drop(tx);
+
+        let body = MergedLines::new(rx).collect().await.unwrap().to_bytes();
+        assert_eq!(body.as_ref(), b"{\"a\":1}\n{\"b\":2}\n");
+    }
+}
@@ -1,4 +1,6 @@
 use crate::auth::{RelayInfo, RelaySigner, RelayVerifier, generate_credentials_json};
+use crate::config::CellConfig;
+use crate::locality::{Cells, Localities};
 use locator::backup_routes::{BackupRouteProvider, FilesystemRouteProvider};
 use locator::client::Locator;
 use locator::config::Compression;
@@ -6,6 +8,7 @@ use locator::types::RouteData;
 use std::collections::HashMap;
 use std::io::Write;
 use std::sync::Arc;
+use url::Url;
 
 pub async fn get_mock_provider() -> (tempfile::TempDir, FilesystemRouteProvider) {
     let route_data = RouteData::from(
@@ -60,6 +63,27 @@ impl BackupRouteProvider for MockBackupProvider {
     async fn store(&self, _data: &RouteData) -> Result<(), locator::backup_routes::BackupError> {
         Ok(())
     }
+
+    async fn round_trip(
+        &self,
+        data: &RouteData,
+    ) -> Result<RouteData, locator::backup_routes::BackupError> {
+        Ok(data.clone())
+    }
+}
+
+/// Control plane config for tests that load routes from the backup provider. Retries
+/// are disabled so the locator falls back to the backup immediately.
+pub fn unreachable_control_plane() -> locator::config::ControlPlane {
+    locator::config::ControlPlane {
+        url: "http://invalid-control-plane:8000".to_string(),
+        retry: locator::config::RetryPolicy {
+            max_retries: 0,
+            ..Default::default()
+        },
+        pagination: Default::default(),
+        signing: None,
+    }
 }
 
 pub async fn create_test_locator(key_to_cell: HashMap<String, String>) -> Locator {
@@ -69,6 +93,7 @@ pub async fn create_test_locator(key_to_cell: HashMap<String, String>) -> Locato
         HashMap::from([
             ("us1".to_string(), "us".to_string()),
             ("us2".to_string(), "us".to_string()),
+            ("de1".to_string(), "de".to_string()),
         ]),
     );
 
@@ -76,7 +101,7 @@ pub async fn create_test_locator(key_to_cell: HashMap<String, String>) -> Locato
 
     let service = locator::locator::Locator::new(
         locator::config::LocatorDataType::ProjectKey,
-        "http://invalid-control-plane:8000".to_string(),
+        unreachable_control_plane(),
         provider,
         None,
         None,
@@ -95,3 +120,21 @@ pub async fn create_test_locator(key_to_cell: HashMap<String, String>) -> Locato
 
     locator
 }
+
+/// The cells of the `us` locality, with their URLs derived from their ids
+pub fn create_test_cells(ids: &[&str]) -> Cells {
+    let cells = ids
+        .iter()
+        .map(|id| CellConfig {
+            id: id.to_string(),
+            sentry_url: Url::parse(&format!("http://sentry-{id}:8080")).unwrap(),
+            relay_url: Url::parse(&format!("http://relay-{id}:8090")).unwrap(),
+            protocol_version: None,
+            tls: None,
+            max_rps: None,
+        })
+        .collect();
+    Localities::new(HashMap::from([("us".to_string(), cells)]))
+        .get_cells("us")
+        .unwrap()
+}
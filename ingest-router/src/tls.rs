@@ -0,0 +1,314 @@
+//! HTTP clients used to reach the cells.
+//!
+//! Cells are reached through a shared client trusting the public CA roots, unless their
+//! configuration has TLS settings. Cells behind an internal PKI get their own client,
+//! trusting the configured CA bundle and presenting the configured client certificate.
+//!
+//! The settings are loaded when the router starts, so that a missing or malformed file
+//! fails the startup rather than every request to the cell. Skipping the server
+//! certificate verification is rejected in production, which is the default environment
+//! unless `SENTRY_ENVIRONMENT` says otherwise.
+use crate::config::{CellConfig, CellTls};
+use crate::http::RequestBody;
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+use hyper_util::client::legacy::Client;
+use hyper_util::client::legacy::connect::HttpConnector;
+use hyper_util::rt::TokioExecutor;
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{CryptoProvider, verify_tls12_signature, verify_tls13_signature};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use rustls::{ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use thiserror::Error;
+
+/// Client used to send requests to cells
+pub type HttpClient = Client<HttpsConnector<HttpConnector>, RequestBody>;
+
+#[derive(Error, Debug)]
+pub enum TlsError {
+    #[error("Failed to read {path}: {source}")]
+    ReadFile {
+        path: PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
+
+    #[error("Invalid PEM file {0}: {1}")]
+    InvalidPem(PathBuf, String),
+
+    #[error("No certificates found in {0}")]
+    NoCertificates(PathBuf),
+
+    #[error("Cell {0} has a client certificate without a key, or a key without a certificate")]
+    IncompleteClientCert(String),
+
+    #[error("Cell {0} skips certificate verification, which is not allowed in production")]
+    InsecureInProduction(String),
+
+    #[error("Invalid TLS configuration for cell {0}: {1}")]
+    InvalidConfig(String, rustls::Error),
+}
+
+/// HTTP clients of the cells, keyed by cell id
+#[derive(Clone)]
+pub struct CellClients {
+    default: HttpClient,
+    cells: HashMap<String, HttpClient>,
+}
+
+impl Default for CellClients {
+    fn default() -> Self {
+        Self {
+            default: build_client(default_tls_config()),
+            cells: HashMap::new(),
+        }
+    }
+}
+
+impl CellClients {
+    /// Builds a client for every cell with TLS settings
+    pub fn from_config(localities: &HashMap<String, Vec<CellConfig>>) -> Result<Self, TlsError> {
+        Self::build(localities, is_production())
+    }
+
+    fn build(
+        localities: &HashMap<String, Vec<CellConfig>>,
+        production: bool,
+    ) -> Result<Self, TlsError> {
+        let mut clients = Self::default();
+        for cell in localities.values().flatten() {
+            if let Some(tls) = &cell.tls {
+                let config = tls_config(&cell.id, tls, production)?;
+                clients.cells.insert(cell.id.clone(), build_client(config));
+            }
+        }
+        Ok(clients)
+    }
+
+    /// Returns the client of the cell
+    pub fn get(&self, cell_id: &str) -> &HttpClient {
+        self.cells.get(cell_id).unwrap_or(&self.default)
+    }
+}
+
+fn is_production() -> bool {
+    std::env::var("SENTRY_ENVIRONMENT").map_or(true, |environment| environment == "production")
+}
+
+fn build_client(config: ClientConfig) -> HttpClient {
+    let connector = HttpsConnectorBuilder::new()
+        .with_tls_config(config)
+        .https_or_http()
+        .enable_http1()
+        .build();
+    Client::builder(TokioExecutor::new()).build(connector)
+}
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(rustls::crypto::ring::default_provider())
+}
+
+fn default_tls_config() -> ClientConfig {
+    let roots = RootCertStore::from_iter(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+    ClientConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()
+        .expect("default protocol versions are supported")
+        .with_root_certificates(roots)
+        .with_no_client_auth()
+}
+
+fn tls_config(cell_id: &str, tls: &CellTls, production: bool) -> Result<ClientConfig, TlsError> {
+    let invalid = |e| TlsError::InvalidConfig(cell_id.to_string(), e);
+    let provider = provider();
+    let builder = ClientConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .map_err(invalid)?;
+
+    let builder = if tls.insecure_skip_verify {
+        if production {
+            return Err(TlsError::InsecureInProduction(cell_id.to_string()));
+        }
+        tracing::warn!(cell_id, "Skipping certificate verification of cell");
+        builder
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(NoVerification(provider)))
+    } else {
+        let roots = match &tls.ca_file {
+            Some(path) => {
+                let mut roots = RootCertStore::empty();
+                for cert in read_certs(path)? {
+                    roots.add(cert).map_err(invalid)?;
+                }
+                roots
+            }
+            None => RootCertStore::from_iter(webpki_roots::TLS_SERVER_ROOTS.iter().cloned()),
+        };
+        builder.with_root_certificates(roots)
+    };
+
+    match (&tls.client_cert_file, &tls.client_key_file) {
+        (Some(cert_path), Some(key_path)) => {
+            let certs = read_certs(cert_path)?;
+            let key = PrivateKeyDer::from_pem_slice(&read_file(key_path)?)
+                .map_err(|e| TlsError::InvalidPem(key_path.clone(), e.to_string()))?;
+            builder.with_client_auth_cert(certs, key).map_err(invalid)
+        }
+        (None, None) => Ok(builder.with_no_client_auth()),
+        _ => Err(TlsError::IncompleteClientCert(cell_id.to_string())),
+    }
+}
+
+fn read_file(path: &Path) -> Result<Vec<u8>, TlsError> {
+    std::fs::read(path).map_err(|source| TlsError::ReadFile {
+        path: path.to_path_buf(),
+        source,
+    })
+}
+
+fn read_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>, TlsError> {
+    let certs = CertificateDer::pem_slice_iter(&read_file(path)?)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| TlsError::InvalidPem(path.to_path_buf(), e.to_string()))?;
+    if certs.is_empty() {
+        return Err(TlsError::NoCertificates(path.to_path_buf()));
+    }
+    Ok(certs)
+}
+
+/// Accepts any server certificate, while still checking the handshake signatures
+#[derive(Debug)]
+struct NoVerification(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for NoVerification {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use url::Url;
+
+    fn localities(tls: CellTls) -> HashMap<String, Vec<CellConfig>> {
+        HashMap::from([(
+            "us".to_string(),
+            vec![CellConfig {
+                id: "us1".to_string(),
+                sentry_url: Url::parse("https://sentry-us1:8080").unwrap(),
+                relay_url: Url::parse("https://relay-us1:8090").unwrap(),
+                protocol_version: None,
+                tls: Some(tls),
+                max_rps: None,
+            }],
+        )])
+    }
+
+    fn pem_file(contents: &str) -> tempfile::NamedTempFile {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        write!(file, "{contents}").unwrap();
+        file
+    }
+
+    #[test]
+    fn test_insecure_skip_verify() {
+        let tls = CellTls {
+            insecure_skip_verify: true,
+            ..Default::default()
+        };
+
+        assert!(matches!(
+            CellClients::build(&localities(tls.clone()), true),
+            Err(TlsError::InsecureInProduction(cell_id)) if cell_id == "us1"
+        ));
+        let clients = CellClients::build(&localities(tls), false).unwrap();
+        assert!(clients.cells.contains_key("us1"));
+    }
+
+    #[test]
+    fn test_invalid_settings() {
+        let build = |tls| CellClients::build(&localities(tls), true);
+
+        // Both the certificate and the key are required
+        let cert = pem_file("");
+        assert!(matches!(
+            build(CellTls {
+                client_cert_file: Some(cert.path().to_path_buf()),
+                ..Default::default()
+            }),
+            Err(TlsError::IncompleteClientCert(_))
+        ));
+
+        assert!(matches!(
+            build(CellTls {
+                ca_file: Some("/nonexistent/ca.pem".into()),
+                ..Default::default()
+            }),
+            Err(TlsError::ReadFile { .. })
+        ));
+
+        let ca = pem_file("not a certificate");
+        assert!(matches!(
+            build(CellTls {
+                ca_file: Some(ca.path().to_path_buf()),
+                ..Default::default()
+            }),
+            Err(TlsError::NoCertificates(_))
+        ));
+    }
+
+    #[test]
+    fn test_default_client() {
+        let clients = CellClients::build(&localities(CellTls::default()), true).unwrap();
+        assert!(clients.cells.contains_key("us1"));
+
+        // Cells without TLS settings share the default client
+        let mut config = localities(CellTls::default());
+        config.get_mut("us").unwrap()[0].tls = None;
+        let clients = CellClients::build(&config, true).unwrap();
+        assert!(clients.cells.is_empty());
+    }
+}
@@ -0,0 +1,303 @@
+//! Traffic of each locality and cell over a sliding window, for operators without access
+//! to the metrics.
+//!
+//! `GET /admin/traffic` on the admin listener reports, for every locality and each of its
+//! cells, the requests sent to the cells and the share of them that failed, and the
+//! project keys routed and the share of them returned pending. Keys the locator could not
+//! route to a cell count as pending keys of the locality only. Counts are kept in memory,
+//! in ten slices of the window, so the window slides in steps of a tenth of its length.
+//! Requests are counted for the locality of their route, even if their keys are forwarded
+//! to a cell of another locality. Streamed requests only count as failed if no cell
+//! succeeds.
+use crate::config::TrafficReport as TrafficReportConfig;
+use crate::handler::CellId;
+use http::{Method, Request, StatusCode};
+use hyper::body::Bytes;
+use serde::Serialize;
+use shared::admin_service::json_response;
+use shared::admin_service::{AdminEndpoints, AdminResponse};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::ops::AddAssign;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// Slices of the window
+const BUCKETS: u32 = 10;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+struct Counts {
+    requests: u64,
+    errors: u64,
+    keys: u64,
+    pending_keys: u64,
+}
+
+impl AddAssign for Counts {
+    fn add_assign(&mut self, other: Self) {
+        self.requests += other.requests;
+        self.errors += other.errors;
+        self.keys += other.keys;
+        self.pending_keys += other.pending_keys;
+    }
+}
+
+/// Counts of a slice of the window, by locality and cell. Keys not routed to any cell
+/// have no cell.
+struct Bucket {
+    start: Instant,
+    counts: HashMap<(String, Option<CellId>), Counts>,
+}
+
+pub struct TrafficReport {
+    window: Duration,
+    buckets: Mutex<VecDeque<Bucket>>,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+struct Report {
+    window_secs: u64,
+    localities: Vec<LocalityTraffic>,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+struct LocalityTraffic {
+    locality: String,
+    #[serde(flatten)]
+    traffic: Traffic,
+    cells: Vec<CellTraffic>,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+struct CellTraffic {
+    cell_id: CellId,
+    #[serde(flatten)]
+    traffic: Traffic,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+struct Traffic {
+    requests: u64,
+    errors: u64,
+    /// Null without requests
+    error_rate: Option<f64>,
+    keys: u64,
+    pending_keys: u64,
+    /// Null without keys
+    pending_ratio: Option<f64>,
+}
+
+impl From<Counts> for Traffic {
+    fn from(counts: Counts) -> Self {
+        let ratio = |part: u64, total: u64| (total > 0).then(|| part as f64 / total as f64);
+        Self {
+            requests: counts.requests,
+            errors: counts.errors,
+            error_rate: ratio(counts.errors, counts.requests),
+            keys: counts.keys,
+            pending_keys: counts.pending_keys,
+            pending_ratio: ratio(counts.pending_keys, counts.keys),
+        }
+    }
+}
+
+impl TrafficReport {
+    pub fn new(config: TrafficReportConfig) -> Self {
+        Self {
+            window: Duration::from_secs(config.window_secs),
+            buckets: Mutex::default(),
+        }
+    }
+
+    /// Records a request sent to a cell of the locality.
+    pub fn record_request(&self, locality: &str, cell_id: &str, failed: bool, now: Instant) {
+        self.add(
+            locality,
+            Some(cell_id),
+            Counts {
+                requests: 1,
+                errors: failed as u64,
+                ..Default::default()
+            },
+            now,
+        );
+    }
+
+    /// Records keys of a request of the locality, routed to the cell or to no cell, and how
+    /// many of them were returned pending.
+    pub fn record_keys(
+        &self,
+        locality: &str,
+        cell_id: Option<&str>,
+        keys: usize,
+        pending_keys: usize,
+        now: Instant,
+    ) {
+        self.add(
+            locality,
+            cell_id,
+            Counts {
+                keys: keys as u64,
+                pending_keys: pending_keys as u64,
+                ..Default::default()
+            },
+            now,
+        );
+    }
+
+    fn add(&self, locality: &str, cell_id: Option<&str>, counts: Counts, now: Instant) {
+        let mut buckets = self.buckets.lock().unwrap_or_else(|e| e.into_inner());
+        self.expire(&mut buckets, now);
+        let bucket_len = self.window / BUCKETS;
+        if buckets
+            .back()
+            .is_none_or(|bucket| now.saturating_duration_since(bucket.start) >= bucket_len)
+        {
+            buckets.push_back(Bucket {
+                start: now,
+                counts: HashMap::new(),
+            });
+        }
+        if let Some(bucket) = buckets.back_mut() {
+            *bucket
+                .counts
+                .entry((locality.to_string(), cell_id.map(str::to_string)))
+                .or_default() += counts;
+        }
+    }
+
+    // Drops the slices that left the window
+    fn expire(&self, buckets: &mut VecDeque<Bucket>, now: Instant) {
+        while buckets
+            .front()
+            .is_some_and(|bucket| now.saturating_duration_since(bucket.start) >= self.window)
+        {
+            buckets.pop_front();
+        }
+    }
+
+    fn report(&self, now: Instant) -> Report {
+        let mut buckets = self.buckets.lock().unwrap_or_else(|e| e.into_inner());
+        self.expire(&mut buckets, now);
+
+        let mut localities: BTreeMap<&str, (Counts, BTreeMap<&str, Counts>)> = BTreeMap::new();
+        for ((locality, cell_id), counts) in buckets.iter().flat_map(|bucket| &bucket.counts) {
+            let (total, cells) = localities.entry(locality).or_default();
+            *total += *counts;
+            if let Some(cell_id) = cell_id {
+                *cells.entry(cell_id).or_default() += *counts;
+            }
+        }
+
+        Report {
+            window_secs: self.window.as_secs(),
+            localities: localities
+                .into_iter()
+                .map(|(locality, (total, cells))| LocalityTraffic {
+                    locality: locality.to_string(),
+                    traffic: total.into(),
+                    cells: cells
+                        .into_iter()
+                        .map(|(cell_id, counts)| CellTraffic {
+                            cell_id: cell_id.to_string(),
+                            traffic: counts.into(),
+                        })
+                        .collect(),
+                })
+                .collect(),
+        }
+    }
+}
+
+impl AdminEndpoints for TrafficReport {
+    fn call(&self, request: &Request<Bytes>) -> Option<AdminResponse> {
+        if request.method() != Method::GET || request.uri().path() != "/admin/traffic" {
+            return None;
+        }
+        let response = json_response(StatusCode::OK, &self.report(Instant::now()));
+        Some(Box::pin(async move { response }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_report() {
+        let report = TrafficReport::new(TrafficReportConfig { window_secs: 100 });
+        let start = Instant::now();
+        report.record_request("us", "us1", false, start);
+        report.record_request("us", "us1", true, start);
+        report.record_request("us", "us2", false, start);
+        report.record_keys("us", Some("us1"), 8, 2, start);
+        report.record_keys("us", None, 2, 2, start);
+        let later = start + Duration::from_secs(50);
+        report.record_request("de", "de1", false, later);
+
+        let traffic = |requests, errors, keys, pending_keys| {
+            Traffic::from(Counts {
+                requests,
+                errors,
+                keys,
+                pending_keys,
+            })
+        };
+        assert_eq!(
+            report.report(later),
+            Report {
+                window_secs: 100,
+                localities: vec![
+                    LocalityTraffic {
+                        locality: "de".to_string(),
+                        traffic: traffic(1, 0, 0, 0),
+                        cells: vec![CellTraffic {
+                            cell_id: "de1".to_string(),
+                            traffic: traffic(1, 0, 0, 0),
+                        }],
+                    },
+                    LocalityTraffic {
+                        locality: "us".to_string(),
+                        traffic: traffic(3, 1, 10, 4),
+                        cells: vec![
+                            CellTraffic {
+                                cell_id: "us1".to_string(),
+                                traffic: traffic(2, 1, 8, 2),
+                            },
+                            CellTraffic {
+                                cell_id: "us2".to_string(),
+                                traffic: traffic(1, 0, 0, 0),
+                            },
+                        ],
+                    },
+                ],
+            }
+        );
+        let us = &report.report(later).localities[1];
+        assert_eq!(us.traffic.pending_ratio, Some(0.4));
+        assert_eq!(us.cells[1].traffic.pending_ratio, None);
+
+        // The first requests left the window
+        let report = report.report(start + Duration::from_secs(100));
+        assert_eq!(report.localities.len(), 1);
+        assert_eq!(report.localities[0].locality, "de");
+    }
+
+    #[tokio::test]
+    async fn test_admin_endpoint() {
+        let report = TrafficReport::new(TrafficReportConfig { window_secs: 60 });
+        report.record_request("us", "us1", true, Instant::now());
+
+        let request = Request::get("/admin/traffic").body(Bytes::new()).unwrap();
+        let response = report.call(&request).unwrap().await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(body["window_secs"], 60);
+        assert_eq!(body["localities"][0]["locality"], "us");
+        assert_eq!(body["localities"][0]["error_rate"], 1.0);
+        assert_eq!(body["localities"][0]["cells"][0]["cell_id"], "us1");
+        assert_eq!(body["localities"][0]["cells"][0]["requests"], 1);
+
+        let request = Request::get("/admin/routes").body(Bytes::new()).unwrap();
+        assert!(report.call(&request).is_none());
+    }
+}
@@ -0,0 +1,41 @@
+"""Client for the locator HTTP API.
+
+Mirrors the HTTP client of the Rust crate (`locator::client`): lookups go to a single
+locator, or to the shard of the key for sharded locators. Like the Rust client, it keeps
+no cache of its own. The locator keeps all mappings in memory and caches ids that are not
+found, so every lookup reflects the locator's current view.
+"""
+
+from .client import (
+    CellAssignment,
+    Deleted,
+    Freshness,
+    InternalError,
+    LocalityNotServed,
+    Locator,
+    LocatorError,
+    NoCell,
+    NotReady,
+    NotYetSynced,
+    RequestError,
+    StaleLookup,
+    WrongShard,
+    shard_of,
+)
+
+__all__ = [
+    "CellAssignment",
+    "Deleted",
+    "Freshness",
+    "InternalError",
+    "LocalityNotServed",
+    "Locator",
+    "LocatorError",
+    "NoCell",
+    "NotReady",
+    "NotYetSynced",
+    "RequestError",
+    "StaleLookup",
+    "WrongShard",
+    "shard_of",
+]
@@ -0,0 +1,218 @@
+import json
+import urllib.error
+import urllib.parse
+import urllib.request
+from concurrent.futures import ThreadPoolExecutor
+from dataclasses import dataclass
+from enum import Enum
+from typing import Any, Iterable, Optional
+
+
+class LocatorError(Exception):
+    """Base class of all errors of the client."""
+
+
+class NoCell(LocatorError):
+    """The id is unknown and its locality has no default cell (404)."""
+
+
+class NotReady(LocatorError):
+    """The locator has not loaded its mappings yet, or is shutting down (503)."""
+
+
+class NotYetSynced(NotReady):
+    """The id is unknown, but the locator has not synced with the control plane recently
+    and may not know it yet (503)."""
+
+
+class Deleted(LocatorError):
+    """The id was deleted in the control plane (410)."""
+
+
+class WrongShard(LocatorError):
+    """The id belongs to another shard (421). The client's topology is outdated."""
+
+
+class LocalityNotServed(LocatorError):
+    """The locator does not serve the requested locality (400)."""
+
+
+class InternalError(LocatorError):
+    """The locator failed with another status."""
+
+
+class RequestError(LocatorError):
+    """The locator could not be reached or returned an unreadable response."""
+
+
+class Freshness(str, Enum):
+    # The mapping was refreshed from the control plane recently
+    FRESH = "fresh"
+    # The last known mapping, which may have changed since
+    STALE = "stale"
+    # No mapping is known, this is the locality's default cell
+    DEFAULT = "default"
+
+
+@dataclass(frozen=True)
+class StaleLookup:
+    cell: str
+    freshness: Freshness
+    # Seconds since the mappings were last refreshed, None if they never were
+    age_secs: Optional[int]
+
+
+@dataclass(frozen=True)
+class CellAssignment:
+    cell: str
+    weight: int
+    primary: bool
+
+
+def _fnv1a(key: str) -> int:
+    # Must stay identical to `locator::shard`, clients and locators agree on it
+    hash = 0xCBF29CE484222325
+    for byte in key.encode("utf-8"):
+        hash = ((hash ^ byte) * 0x100000001B3) & 0xFFFFFFFFFFFFFFFF
+    return hash
+
+
+def shard_of(key: str, count: int) -> int:
+    """The shard of the key, out of `count` shards."""
+    return _fnv1a(key) % max(count, 1)
+
+
+_ERRORS = {
+    404: NoCell,
+    503: NotReady,
+    421: WrongShard,
+    400: LocalityNotServed,
+    410: Deleted,
+}
+
+
+def _error(e: urllib.error.HTTPError) -> LocatorError:
+    if e.code == 503:
+        try:
+            code = json.load(e).get("code")
+        except (OSError, ValueError, AttributeError):
+            code = None
+        if code == "not_yet_synced":
+            return NotYetSynced(f"locator returned {e.code}")
+    return _ERRORS.get(e.code, InternalError)(f"locator returned {e.code}")
+
+
+class _HttpClient:
+    def __init__(self, url: str, api_key: Optional[str], timeout: float):
+        self.url = url
+        self.api_key = api_key
+        self.timeout = timeout
+
+    def get(self, path: str, params: dict[str, str]) -> Any:
+        # Lookups go to the url as is, other endpoints are below it
+        url = self.url.rstrip("/") + path if path else self.url
+        if params:
+            url += "?" + urllib.parse.urlencode(params)
+        request = urllib.request.Request(url)
+        if self.api_key is not None:
+            request.add_header("Authorization", f"Bearer {self.api_key}")
+
+        try:
+            with urllib.request.urlopen(request, timeout=self.timeout) as response:
+                return json.load(response)
+        except urllib.error.HTTPError as e:
+            raise _error(e) from e
+        except (OSError, ValueError) as e:
+            raise RequestError(str(e)) from e
+
+    def lookup(self, id: str, locality: Optional[str], **extra: str) -> Any:
+        params = {"id": id, **extra}
+        if locality is not None:
+            params["locality"] = locality
+        return self.get("", params)
+
+    def lookup_multi(self, id: str, locality: Optional[str]) -> Any:
+        params = {"id": id}
+        if locality is not None:
+            params["locality"] = locality
+        return self.get("/cells", params)
+
+
+class Locator:
+    """Looks up the cell of organization ids, slugs or project keys.
+
+    Pass `url` for a single locator, or `shard_urls` or `discovery_url` for a locator
+    sharded by key hash. With `discovery_url`, the urls of the shards are requested from
+    that locator once, when the client is created.
+    """
+
+    def __init__(
+        self,
+        url: Optional[str] = None,
+        *,
+        shard_urls: Optional[list[str]] = None,
+        discovery_url: Optional[str] = None,
+        api_key: Optional[str] = None,
+        timeout: float = 5.0,
+    ):
+        if sum(option is not None for option in (url, shard_urls, discovery_url)) != 1:
+            raise ValueError("exactly one of url, shard_urls and discovery_url is required")
+
+        if discovery_url is not None:
+            topology = _HttpClient(discovery_url, api_key, timeout).get("/shards", {})
+            shard_urls = topology["urls"]
+        if shard_urls is not None:
+            if not shard_urls:
+                raise ValueError("a sharded locator needs at least one shard url")
+            self._clients = [_HttpClient(url, api_key, timeout) for url in shard_urls]
+        else:
+            assert url is not None
+            self._clients = [_HttpClient(url, api_key, timeout)]
+
+    def _client(self, id: str) -> _HttpClient:
+        return self._clients[shard_of(id, len(self._clients))]
+
+    def lookup(self, id: str, locality: Optional[str] = None) -> str:
+        """The cell of the id. Fails with `NotReady` until the locator has loaded."""
+        return self._client(id).lookup(id, locality)["cell"]
+
+    def lookup_stale(self, id: str, locality: Optional[str] = None) -> StaleLookup:
+        """The last known cell of the id with its freshness, even while the locator is
+        not ready."""
+        response = self._client(id).lookup(id, locality, allow_stale="true")
+        return StaleLookup(
+            cell=response["cell"],
+            freshness=Freshness(response["freshness"]),
+            age_secs=response.get("age_secs"),
+        )
+
+    def lookup_multi(self, id: str, locality: Optional[str] = None) -> list[CellAssignment]:
+        """Every cell the id is assigned to, with the weights of their traffic."""
+        response = self._client(id).lookup_multi(id, locality)
+        return [
+            CellAssignment(
+                cell=cell["cell"],
+                weight=cell["weight"],
+                primary=cell.get("primary", False),
+            )
+            for cell in response["cells"]
+        ]
+
+    def lookup_batch(
+        self,
+        ids: Iterable[str],
+        locality: Optional[str] = None,
+        max_workers: int = 8,
+    ) -> dict[str, str | LocatorError]:
+        """The cells of many ids, looked up concurrently. Every id maps to its cell or to
+        the error of its lookup, so one failed lookup does not fail the whole batch."""
+
+        def lookup(id: str) -> str | LocatorError:
+            try:
+                return self.lookup(id, locality)
+            except LocatorError as e:
+                return e
+
+        ids = list(dict.fromkeys(ids))
+        with ThreadPoolExecutor(max_workers=max_workers) as executor:
+            return dict(zip(ids, executor.map(lookup, ids)))
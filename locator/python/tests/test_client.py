@@ -0,0 +1,199 @@
+import json
+import threading
+import unittest
+from http.server import BaseHTTPRequestHandler, ThreadingHTTPServer
+from urllib.parse import parse_qs, urlparse
+
+from synapse_locator import (
+    CellAssignment,
+    Deleted,
+    Freshness,
+    LocalityNotServed,
+    Locator,
+    NoCell,
+    NotReady,
+    NotYetSynced,
+    RequestError,
+    StaleLookup,
+    WrongShard,
+    shard_of,
+)
+from synapse_locator.client import _fnv1a
+
+MAPPINGS = {"1": "us1", "2": "us1", "sentry": "us2", "getsentry": "de1"}
+DELETED = {"3"}
+
+
+class MockLocator:
+    """Serves the lookups of the keys in `MAPPINGS` that belong to its shard."""
+
+    def __init__(self, index=0, count=1, ready=True):
+        self.index = index
+        self.count = count
+        self.ready = ready
+        self.synced = True
+        self.localities = None
+        self.requests = []
+        self.urls = []
+
+        mock = self
+
+        class Handler(BaseHTTPRequestHandler):
+            def do_GET(self):
+                url = urlparse(self.path)
+                params = {k: v[0] for k, v in parse_qs(url.query).items()}
+                mock.requests.append((url.path, params, self.headers.get("Authorization")))
+                status, body = mock.respond(url.path, params)
+                self.send_response(status)
+                self.send_header("content-type", "application/json")
+                self.end_headers()
+                self.wfile.write(json.dumps(body).encode())
+
+            def log_message(self, *args):
+                pass
+
+        self.server = ThreadingHTTPServer(("127.0.0.1", 0), Handler)
+        self.url = f"http://127.0.0.1:{self.server.server_port}"
+        threading.Thread(target=self.server.serve_forever, daemon=True).start()
+
+    def respond(self, path, params):
+        if path == "/shards":
+            return 200, {"index": self.index, "urls": self.urls, "keys": 0}
+
+        id = params["id"]
+        if shard_of(id, self.count) != self.index:
+            return 421, {}
+        locality = params.get("locality")
+        if self.localities is not None and locality and locality not in self.localities:
+            return 400, {}
+        stale = params.get("allow_stale") == "true"
+        if not self.ready and not stale:
+            return 503, {}
+        if id in DELETED:
+            return 410, {"error_message": "the id was deleted", "code": "deleted"}
+        if id not in MAPPINGS:
+            if not self.synced:
+                return 503, {"error_message": "not synced", "code": "not_yet_synced"}
+            return 404, {}
+        if path == "/cells":
+            return 200, {"cells": [{"cell": MAPPINGS[id], "weight": 1, "primary": True}]}
+        if stale:
+            return 200, {"cell": MAPPINGS[id], "freshness": "stale"}
+        return 200, {"cell": MAPPINGS[id]}
+
+    def close(self):
+        self.server.shutdown()
+        self.server.server_close()
+
+
+class TestShardOf(unittest.TestCase):
+    def test_same_as_locator(self):
+        # Test vectors of `locator::shard`
+        self.assertEqual(_fnv1a(""), 0xCBF29CE484222325)
+        self.assertEqual(_fnv1a("sentry"), 0xFC2790188A30BB6A)
+        self.assertEqual(shard_of("sentry", 4), 2)
+        self.assertEqual(shard_of("getsentry", 4), 0)
+        self.assertEqual(shard_of("sentry", 0), 0)
+
+
+class TestLocator(unittest.TestCase):
+    def setUp(self):
+        self.mock = MockLocator()
+        self.addCleanup(self.mock.close)
+
+    def test_lookups(self):
+        locator = Locator(self.mock.url, api_key="secret")
+        self.assertEqual(locator.lookup("1", locality="us"), "us1")
+        self.assertEqual(
+            self.mock.requests[-1], ("/", {"id": "1", "locality": "us"}, "Bearer secret")
+        )
+
+        self.assertEqual(
+            locator.lookup_stale("sentry"),
+            StaleLookup(cell="us2", freshness=Freshness.STALE, age_secs=None),
+        )
+        self.assertEqual(
+            locator.lookup_multi("getsentry"),
+            [CellAssignment(cell="de1", weight=1, primary=True)],
+        )
+        with self.assertRaises(NoCell):
+            locator.lookup("unknown")
+
+    def test_not_ready(self):
+        self.mock.ready = False
+        locator = Locator(self.mock.url)
+        with self.assertRaises(NotReady):
+            locator.lookup("1")
+        # Stale lookups are served regardless
+        self.assertEqual(locator.lookup_stale("1").cell, "us1")
+
+    def test_deleted_and_not_yet_synced(self):
+        locator = Locator(self.mock.url)
+        with self.assertRaises(Deleted):
+            locator.lookup("3")
+        self.mock.synced = False
+        with self.assertRaises(NotYetSynced):
+            locator.lookup("unknown")
+        # Still a NotReady error for callers that do not tell them apart
+        with self.assertRaises(NotReady):
+            locator.lookup("unknown")
+        self.assertEqual(locator.lookup("1"), "us1")
+
+    def test_locality_not_served(self):
+        self.mock.localities = ["us"]
+        locator = Locator(self.mock.url)
+        self.assertEqual(locator.lookup("1", locality="us"), "us1")
+        with self.assertRaises(LocalityNotServed):
+            locator.lookup("getsentry", locality="de")
+
+    def test_lookup_batch(self):
+        locator = Locator(self.mock.url)
+        results = locator.lookup_batch(["1", "sentry", "unknown", "1"])
+        self.assertEqual(list(results), ["1", "sentry", "unknown"])
+        self.assertEqual(results["1"], "us1")
+        self.assertEqual(results["sentry"], "us2")
+        self.assertIsInstance(results["unknown"], NoCell)
+
+    def test_unreachable(self):
+        self.mock.close()
+        with self.assertRaises(RequestError):
+            Locator(self.mock.url).lookup("1")
+
+    def test_arguments(self):
+        with self.assertRaises(ValueError):
+            Locator()
+        with self.assertRaises(ValueError):
+            Locator(self.mock.url, shard_urls=[self.mock.url])
+        with self.assertRaises(ValueError):
+            Locator(shard_urls=[])
+
+
+class TestShardedLocator(unittest.TestCase):
+    def setUp(self):
+        self.shards = [MockLocator(index, 2) for index in range(2)]
+        urls = [shard.url for shard in self.shards]
+        for shard in self.shards:
+            shard.urls = urls
+            self.addCleanup(shard.close)
+
+    def test_routes_to_shard(self):
+        for locator in [
+            Locator(shard_urls=[shard.url for shard in self.shards]),
+            Locator(discovery_url=self.shards[1].url),
+        ]:
+            self.assertEqual(
+                locator.lookup_batch(MAPPINGS),
+                MAPPINGS,
+            )
+
+    def test_wrong_shard(self):
+        # The topology of a single shard is outdated
+        locator = Locator(shard_urls=[self.shards[0].url])
+        ids = [id for id in MAPPINGS if shard_of(id, 2) == 1]
+        self.assertTrue(ids)
+        with self.assertRaises(WrongShard):
+            locator.lookup(ids[0])
+
+
+if __name__ == "__main__":
+    unittest.main()
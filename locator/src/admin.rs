@@ -0,0 +1,196 @@
+//! Admin endpoints of services with a locator client, for on-call debugging:
+//! - `GET /admin/locator/stats` returns the size and age of the mappings and the size of
+//!   the negative cache, of every shard for sharded locators
+//! - `GET /admin/locator/lookup?id=...&locality=...` looks up an id like the service does,
+//!   along with the freshness of its mapping. It also answers while the locator is not
+//!   ready, with the mappings loaded so far.
+//! - `GET /readyz` returns the readiness of the locator with its reasons, e.g. a failing
+//!   backup route provider. It fails with 503 only if the locator is not ready.
+use crate::api::{ApiErrorResponse, error_status};
+use crate::client::{ClientError, Locator};
+use crate::types::{LocatorStats, ReadinessStatus};
+use axum::extract::Query;
+use bytes::Bytes;
+use http::{Method, Request, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use shared::admin_service::{AdminEndpoints, AdminResponse, json_response};
+
+pub struct LocatorAdmin {
+    locator: Locator,
+}
+
+#[derive(Serialize)]
+struct StatsResponse {
+    locators: Vec<LocatorStats>,
+}
+
+#[derive(Deserialize)]
+struct LookupParams {
+    id: String,
+    locality: Option<String>,
+}
+
+impl LocatorAdmin {
+    pub fn new(locator: Locator) -> Self {
+        Self { locator }
+    }
+}
+
+impl AdminEndpoints for LocatorAdmin {
+    fn call(&self, request: &Request<Bytes>) -> Option<AdminResponse> {
+        if request.method() != Method::GET {
+            return None;
+        }
+        let locator = self.locator.clone();
+        match request.uri().path() {
+            "/admin/locator/stats" => Some(Box::pin(async move {
+                match locator.stats().await {
+                    Ok(locators) => json_response(StatusCode::OK, &StatsResponse { locators }),
+                    Err(e) => client_error_response(e),
+                }
+            })),
+            "/readyz" => Some(Box::pin(async move {
+                match locator.readiness().await {
+                    Ok(readiness) => {
+                        let status = match readiness.status {
+                            ReadinessStatus::NotReady => StatusCode::SERVICE_UNAVAILABLE,
+                            ReadinessStatus::Ready | ReadinessStatus::Degraded => StatusCode::OK,
+                        };
+                        json_response(status, &readiness)
+                    }
+                    Err(e) => client_error_response(e),
+                }
+            })),
+            "/admin/locator/lookup" => {
+                let params = Query::<LookupParams>::try_from_uri(request.uri());
+                Some(Box::pin(async move {
+                    let Ok(Query(params)) = params else {
+                        return error_response(StatusCode::BAD_REQUEST, "id is required", None);
+                    };
+                    match locator
+                        .lookup_stale(&params.id, params.locality.as_deref())
+                        .await
+                    {
+                        Ok(lookup) => json_response(StatusCode::OK, &lookup),
+                        Err(e) => client_error_response(e),
+                    }
+                }))
+            }
+            _ => None,
+        }
+    }
+}
+
+fn client_error_response(error: ClientError) -> Response<Bytes> {
+    match error {
+        ClientError::LocatorError(e) => {
+            error_response(error_status(&e), &e.to_string(), Some(e.code()))
+        }
+        // The remote locator could not be reached
+        e => error_response(StatusCode::BAD_GATEWAY, &e.to_string(), None),
+    }
+}
+
+fn error_response(
+    status: StatusCode,
+    message: &str,
+    code: Option<&'static str>,
+) -> Response<Bytes> {
+    let body = ApiErrorResponse {
+        error_message: message.to_string(),
+        code,
+    };
+    json_response(status, &body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backup_routes::{BackupRouteProvider, FilesystemRouteProvider};
+    use crate::config::{self, LocatorDataType};
+    use crate::locator::{Locator as LocatorService, Startup};
+    use crate::types::RouteData;
+    use std::collections::HashMap;
+    use std::sync::Arc;
+
+    fn request(method: Method, uri: &str) -> Request<Bytes> {
+        Request::builder()
+            .method(method)
+            .uri(uri)
+            .body(Bytes::new())
+            .unwrap()
+    }
+
+    async fn call(admin: &LocatorAdmin, uri: &str) -> (StatusCode, serde_json::Value) {
+        let response = admin.call(&request(Method::GET, uri)).unwrap().await;
+        let body = serde_json::from_slice(response.body()).unwrap();
+        (response.status(), body)
+    }
+
+    #[tokio::test]
+    async fn test_locator_admin() {
+        // The control plane is unreachable, the mappings are loaded from the backup
+        let route_data = RouteData::from(
+            HashMap::from([("org_1".into(), "us1".into())]),
+            Some("cursor1".into()),
+            HashMap::from([("us1".into(), "us".into())]),
+        );
+        let dir = tempfile::tempdir().unwrap();
+        let provider = FilesystemRouteProvider::new(
+            dir.path().to_str().unwrap(),
+            "backup.bin",
+            config::Compression::None,
+        );
+        provider.store(&route_data).await.unwrap();
+        let service = LocatorService::new(
+            LocatorDataType::Organization,
+            config::ControlPlane {
+                url: "http://127.0.0.1:1".into(),
+                retry: config::RetryPolicy {
+                    max_retries: 0,
+                    ..Default::default()
+                },
+                pagination: Default::default(),
+                signing: None,
+            },
+            Arc::new(provider),
+            None,
+            None,
+        );
+        service.wait_for_startup(Startup::Loaded).await;
+        let admin = LocatorAdmin::new(Locator::from_in_process_service(service));
+
+        let (status, body) = call(&admin, "/admin/locator/stats").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["locators"][0]["ids"], 1);
+        assert_eq!(body["locators"][0]["cursor"], "cursor1");
+        assert_eq!(
+            body["locators"][0]["last_refresh_age_secs"],
+            serde_json::Value::Null
+        );
+
+        let (status, body) = call(&admin, "/admin/locator/lookup?id=org_1&locality=us").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["cell"], "us1");
+        assert_eq!(body["freshness"], "stale");
+
+        let (status, body) = call(&admin, "/admin/locator/lookup?id=org_1&locality=de").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(body["code"], "locality_mismatch");
+
+        let (status, _) = call(&admin, "/admin/locator/lookup").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        let (status, body) = call(&admin, "/readyz").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["status"], "ready");
+        assert_eq!(body["reasons"], serde_json::json!([]));
+
+        assert!(admin.call(&request(Method::GET, "/health")).is_none());
+        assert!(
+            admin
+                .call(&request(Method::POST, "/admin/locator/stats"))
+                .is_none()
+        );
+    }
+}
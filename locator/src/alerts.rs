@@ -0,0 +1,332 @@
+//! Webhook notifications for sync problems.
+//!
+//! The locator notifies a webhook when control plane syncs fail repeatedly, when the
+//! mappings have not been synced for longer than the staleness threshold, and when writing
+//! the backup fails. An alert is sent once when the problem starts, again every
+//! `repeat_interval_secs` while it lasts, and a resolved notification is sent once it is
+//! over. Notifications are posted in the background, so a slow or failing webhook does
+//! not delay the syncs.
+use crate::clock::Clock;
+use crate::config::Alerts as AlertsConfig;
+use crate::metrics_defs::ALERT_NOTIFICATIONS;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AlertKind {
+    SyncFailures,
+    Staleness,
+    BackupWrite,
+}
+
+impl AlertKind {
+    fn as_str(&self) -> &'static str {
+        match self {
+            AlertKind::SyncFailures => "sync_failures",
+            AlertKind::Staleness => "staleness",
+            AlertKind::BackupWrite => "backup_write",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AlertStatus {
+    Firing,
+    Resolved,
+}
+
+impl AlertStatus {
+    fn as_str(&self) -> &'static str {
+        match self {
+            AlertStatus::Firing => "firing",
+            AlertStatus::Resolved => "resolved",
+        }
+    }
+}
+
+/// Body posted to the webhook
+///
+/// # Example
+/// ```json
+/// {"alert": "sync_failures", "status": "firing", "message": "3 consecutive control plane syncs failed: ..."}
+/// ```
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Notification {
+    pub alert: AlertKind,
+    pub status: AlertStatus,
+    pub message: String,
+}
+
+/// Destination of the notifications
+pub trait AlertSink: Send + Sync {
+    fn send(&self, notification: Notification);
+}
+
+/// Posts notifications to a webhook URL
+struct WebhookSink {
+    url: String,
+    client: reqwest::Client,
+}
+
+impl AlertSink for WebhookSink {
+    fn send(&self, notification: Notification) {
+        let request = self
+            .client
+            .post(&self.url)
+            .timeout(WEBHOOK_TIMEOUT)
+            .json(&notification);
+        tokio::spawn(async move {
+            let outcome = match request.send().await.and_then(|r| r.error_for_status()) {
+                Ok(_) => "sent",
+                Err(e) => {
+                    tracing::warn!(alert = ?notification.alert, "Failed to send alert: {e:?}");
+                    "failed"
+                }
+            };
+            metrics::counter!(
+                ALERT_NOTIFICATIONS.name,
+                "alert" => notification.alert.as_str(),
+                "status" => notification.status.as_str(),
+                "outcome" => outcome,
+            )
+            .increment(1);
+        });
+    }
+}
+
+#[derive(Default)]
+struct State {
+    consecutive_sync_failures: u32,
+    // Ongoing problems, with the time they were last notified
+    firing: HashMap<AlertKind, Instant>,
+}
+
+pub struct Alerts {
+    sink: Arc<dyn AlertSink>,
+    clock: Arc<dyn Clock>,
+    sync_failure_threshold: u32,
+    max_staleness: Duration,
+    repeat_interval: Duration,
+    state: Mutex<State>,
+}
+
+impl Alerts {
+    pub fn new(config: AlertsConfig, clock: Arc<dyn Clock>) -> Self {
+        let sink = Arc::new(WebhookSink {
+            url: config.webhook_url.clone(),
+            client: reqwest::Client::new(),
+        });
+        Self::with_sink(config, clock, sink)
+    }
+
+    fn with_sink(config: AlertsConfig, clock: Arc<dyn Clock>, sink: Arc<dyn AlertSink>) -> Self {
+        Alerts {
+            sink,
+            clock,
+            sync_failure_threshold: config.sync_failure_threshold,
+            max_staleness: Duration::from_secs(config.max_staleness_secs),
+            repeat_interval: Duration::from_secs(config.repeat_interval_secs),
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    /// A control plane sync succeeded
+    pub fn sync_succeeded(&self) {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        state.consecutive_sync_failures = 0;
+        self.resolve(&mut state, AlertKind::SyncFailures, || {
+            "Control plane syncs recovered".to_string()
+        });
+    }
+
+    /// A control plane sync failed
+    pub fn sync_failed(&self, error: &dyn Display) {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        state.consecutive_sync_failures += 1;
+        let failures = state.consecutive_sync_failures;
+        if failures >= self.sync_failure_threshold {
+            self.fire(&mut state, AlertKind::SyncFailures, || {
+                format!("{failures} consecutive control plane syncs failed: {error}")
+            });
+        }
+    }
+
+    /// The mappings were last synced from the control plane `age` ago
+    pub fn check_staleness(&self, age: Duration) {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        if age > self.max_staleness {
+            self.fire(&mut state, AlertKind::Staleness, || {
+                format!(
+                    "Mappings were not synced from the control plane for {}s",
+                    age.as_secs()
+                )
+            });
+        } else {
+            self.resolve(&mut state, AlertKind::Staleness, || {
+                "Mappings are synced from the control plane again".to_string()
+            });
+        }
+    }
+
+    /// The backup was written to the backup route provider
+    pub fn backup_written(&self) {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        self.resolve(&mut state, AlertKind::BackupWrite, || {
+            "Backup writes recovered".to_string()
+        });
+    }
+
+    /// Writing the backup failed
+    pub fn backup_write_failed(&self, error: &dyn Display) {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        self.fire(&mut state, AlertKind::BackupWrite, || {
+            format!("Failed to store backup routes: {error}")
+        });
+    }
+
+    fn fire(&self, state: &mut State, alert: AlertKind, message: impl FnOnce() -> String) {
+        let now = self.clock.now();
+        if let Some(last) = state.firing.get(&alert)
+            && now.saturating_duration_since(*last) < self.repeat_interval
+        {
+            return;
+        }
+        state.firing.insert(alert, now);
+        self.sink.send(Notification {
+            alert,
+            status: AlertStatus::Firing,
+            message: message(),
+        });
+    }
+
+    fn resolve(&self, state: &mut State, alert: AlertKind, message: impl FnOnce() -> String) {
+        if state.firing.remove(&alert).is_some() {
+            self.sink.send(Notification {
+                alert,
+                status: AlertStatus::Resolved,
+                message: message(),
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+
+    #[derive(Default)]
+    struct RecordingSink(Mutex<Vec<Notification>>);
+
+    impl RecordingSink {
+        fn take(&self) -> Vec<(AlertKind, AlertStatus)> {
+            std::mem::take(&mut *self.0.lock().unwrap())
+                .into_iter()
+                .map(|notification| (notification.alert, notification.status))
+                .collect()
+        }
+    }
+
+    impl AlertSink for RecordingSink {
+        fn send(&self, notification: Notification) {
+            self.0.lock().unwrap().push(notification);
+        }
+    }
+
+    fn alerts() -> (Alerts, Arc<RecordingSink>, Arc<MockClock>) {
+        let config = AlertsConfig {
+            webhook_url: "http://alerts.internal".to_string(),
+            sync_failure_threshold: 2,
+            max_staleness_secs: 600,
+            repeat_interval_secs: 3600,
+        };
+        let sink = Arc::new(RecordingSink::default());
+        let clock = Arc::new(MockClock::new(0));
+        let alerts = Alerts::with_sink(config, clock.clone(), sink.clone());
+        (alerts, sink, clock)
+    }
+
+    #[test]
+    fn test_sync_failures() {
+        let (alerts, sink, _) = alerts();
+
+        // A success resets the consecutive failures
+        alerts.sync_failed(&"timeout");
+        alerts.sync_succeeded();
+        alerts.sync_failed(&"timeout");
+        assert_eq!(sink.take(), vec![]);
+
+        alerts.sync_failed(&"timeout");
+        assert_eq!(
+            sink.take(),
+            vec![(AlertKind::SyncFailures, AlertStatus::Firing)]
+        );
+        // Ongoing failures are not notified again
+        alerts.sync_failed(&"timeout");
+        assert_eq!(sink.take(), vec![]);
+
+        alerts.sync_succeeded();
+        alerts.sync_succeeded();
+        assert_eq!(
+            sink.take(),
+            vec![(AlertKind::SyncFailures, AlertStatus::Resolved)]
+        );
+    }
+
+    #[test]
+    fn test_staleness() {
+        let (alerts, sink, _) = alerts();
+
+        alerts.check_staleness(Duration::from_secs(600));
+        assert_eq!(sink.take(), vec![]);
+
+        alerts.check_staleness(Duration::from_secs(601));
+        alerts.check_staleness(Duration::from_secs(700));
+        assert_eq!(
+            sink.take(),
+            vec![(AlertKind::Staleness, AlertStatus::Firing)]
+        );
+
+        alerts.check_staleness(Duration::from_secs(60));
+        assert_eq!(
+            sink.take(),
+            vec![(AlertKind::Staleness, AlertStatus::Resolved)]
+        );
+    }
+
+    #[test]
+    fn test_repeat_interval() {
+        let (alerts, sink, clock) = alerts();
+
+        alerts.backup_write_failed(&"permission denied");
+        clock.advance(Duration::from_secs(3599));
+        alerts.backup_write_failed(&"permission denied");
+        assert_eq!(
+            sink.take(),
+            vec![(AlertKind::BackupWrite, AlertStatus::Firing)]
+        );
+
+        // Still failing after the repeat interval
+        clock.advance(Duration::from_secs(1));
+        alerts.backup_write_failed(&"permission denied");
+        assert_eq!(
+            sink.take(),
+            vec![(AlertKind::BackupWrite, AlertStatus::Firing)]
+        );
+
+        // Other alerts are independent
+        alerts.sync_succeeded();
+        alerts.backup_written();
+        assert_eq!(
+            sink.take(),
+            vec![(AlertKind::BackupWrite, AlertStatus::Resolved)]
+        );
+    }
+}
@@ -1,19 +1,35 @@
-use crate::backup_routes::BackupRouteProvider;
-use crate::config::{
-    ControlPlane as ControlPlaneConfig, Listener as ListenerConfig, LocatorDataType,
-};
+use crate::auth::{ApiKeys, AuthError};
+use crate::client::CALLER_HEADER;
+use crate::config::{ApiKey, Listener as ListenerConfig};
 use crate::locator::{Locator, LocatorError};
+use crate::metrics_defs::{API_CALLER_REQUESTS, API_REQUESTS};
+use crate::rebalance::RebalanceReport;
+use crate::shard::{ReshardPlan, ShardInfo};
+use crate::types::{
+    CatalogCell, Cell, CellAssignment, Freshness, LocatorStats, Readiness, ReadinessStatus,
+    StaleLookup,
+};
 use axum::{
     Json, Router,
-    extract::{Query, State},
-    http::StatusCode,
+    extract::{MatchedPath, Query, Request, State},
+    http::{
+        HeaderValue, Method, StatusCode,
+        header::{AUTHORIZATION, RETRY_AFTER},
+    },
+    middleware::{self, Next},
     response::{IntoResponse, Response},
-    routing::get,
+    routing::{get, post, put},
 };
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use shared::http::shutdown_signal;
+use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::net::TcpListener;
+use tracing::Instrument;
+
+// Most ids accepted by one batch lookup
+const MAX_BATCH_IDS: usize = 1000;
 
 #[derive(thiserror::Error, Debug)]
 pub enum LocatorApiError {
@@ -21,35 +37,110 @@ pub enum LocatorApiError {
     IoError(#[from] std::io::Error),
     #[error("backup route provider error: {0}")]
     BackupRouteProvider(#[from] crate::backup_routes::BackupError),
+    #[error("invalid shard configuration: {0}")]
+    InvalidShard(String),
+    #[error("invalid cell catalog: {0}")]
+    InvalidCatalog(String),
+    #[error("invalid datagram listener: {0}")]
+    InvalidDatagramListener(String),
+    #[error("invalid backup route store: {0}")]
+    InvalidBackupRouteStore(String),
 }
 
-pub async fn serve(
-    data_type: LocatorDataType,
-    listener: ListenerConfig,
-    control_plane: ControlPlaneConfig,
-    provider: Arc<dyn BackupRouteProvider + 'static>,
-    localities: Option<Vec<String>>,
-    locality_to_default_cell: Option<HashMap<String, String>>,
-) -> Result<(), LocatorApiError> {
-    let locator = Locator::new(
-        data_type,
-        control_plane.url,
-        provider,
-        localities,
-        locality_to_default_cell,
+/// Routes of the API. Lookup keys cannot replace the cell catalog, `PUT /catalog` is only
+/// served with `catalog_write_keys` and only accepts those.
+fn router(
+    locator: Locator,
+    api_keys: Option<Vec<ApiKey>>,
+    catalog_write_keys: Option<Vec<ApiKey>>,
+) -> Router {
+    // Only callers named by a key are metric tags, so that the header can't add new ones
+    let known_callers: Arc<HashSet<String>> = Arc::new(
+        api_keys
+            .iter()
+            .chain(&catalog_write_keys)
+            .flatten()
+            .map(|key| key.caller.clone())
+            .collect(),
     );
-    let app = Router::new()
+    let mut app = Router::new()
         .route("/", get(handler))
+        .route("/cells", get(cells_handler))
+        .route("/lookup_full", get(lookup_full_handler))
+        .route("/lookup_batch", post(lookup_batch_handler))
+        .route("/catalog", get(catalog_handler))
+        .route("/history", get(history_handler))
+        .route("/rebalance", get(rebalance_handler))
+        .route("/shards", get(shards_handler))
+        .route("/shards/plan", get(reshard_plan_handler))
+        .route("/stats", get(stats_handler))
+        .route_layer(middleware::from_fn_with_state(
+            known_callers.clone(),
+            track_caller,
+        ))
         .with_state(locator.clone());
+    if let Some(api_keys) = api_keys {
+        app = app.layer(middleware::from_fn_with_state(
+            Arc::new(ApiKeys::new(api_keys, locator.clock())),
+            authenticate,
+        ));
+    }
+
+    if let Some(write_keys) = catalog_write_keys {
+        app = app.merge(
+            Router::new()
+                .route("/catalog", put(register_catalog_handler))
+                .route_layer(middleware::from_fn_with_state(known_callers, track_caller))
+                .with_state(locator.clone())
+                .layer(middleware::from_fn_with_state(
+                    Arc::new(ApiKeys::new(write_keys, locator.clock())),
+                    authenticate,
+                )),
+        );
+    }
+    if locator.is_read_only() {
+        app = app.layer(middleware::from_fn(reject_writes));
+    }
+
+    // Probes don't have an API key, so readiness is added after the authentication
+    app.merge(
+        Router::new()
+            .route("/readyz", get(readiness_handler))
+            .with_state(locator),
+    )
+}
+
+pub async fn serve(
+    listener: ListenerConfig,
+    locator: Locator,
+    api_keys: Option<Vec<ApiKey>>,
+    catalog_write_keys: Option<Vec<ApiKey>>,
+    drain_timeout: Duration,
+) -> Result<(), LocatorApiError> {
+    let app = router(locator.clone(), api_keys, catalog_write_keys);
 
     let addr = format!("{}:{}", listener.host, listener.port);
 
     let listener = TcpListener::bind(addr).await?;
-    axum::serve(listener, app)
-        .with_graceful_shutdown(async {
-            let _ = tokio::signal::ctrl_c().await;
-        })
-        .await?;
+    let (signaled_tx, signaled) = tokio::sync::oneshot::channel();
+    let server = axum::serve(listener, app).with_graceful_shutdown(async move {
+        shutdown_signal().await;
+        tracing::info!("Shutting down locator, draining connections...");
+        let _ = signaled_tx.send(());
+    });
+    // axum waits for every open connection to close, however long that takes
+    let drain_expired = async move {
+        if signaled.await.is_err() {
+            std::future::pending::<()>().await;
+        }
+        tokio::time::sleep(drain_timeout).await;
+    };
+    tokio::select! {
+        result = server.into_future() => result?,
+        _ = drain_expired => {
+            tracing::warn!("Connections still open after draining for {drain_timeout:?}");
+        }
+    }
 
     locator.shutdown().await;
 
@@ -59,6 +150,11 @@ pub async fn serve(
 #[derive(Serialize)]
 struct ApiResponse {
     cell: String,
+    /// Only set for lookups with `allow_stale`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    freshness: Option<Freshness>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    age_secs: Option<u64>,
 }
 
 impl IntoResponse for ApiResponse {
@@ -69,47 +165,472 @@ impl IntoResponse for ApiResponse {
 
 impl From<String> for ApiResponse {
     fn from(cell: String) -> Self {
-        ApiResponse { cell }
+        ApiResponse {
+            cell,
+            freshness: None,
+            age_secs: None,
+        }
     }
 }
 
+impl From<StaleLookup> for ApiResponse {
+    fn from(lookup: StaleLookup) -> Self {
+        ApiResponse {
+            cell: lookup.cell,
+            freshness: Some(lookup.freshness),
+            age_secs: lookup.age_secs,
+        }
+    }
+}
+
+/// Response of the `/cells` endpoint, listing every cell of the id.
 #[derive(Serialize)]
-struct ApiErrorResponse {
-    error_message: String,
+struct CellsApiResponse {
+    cells: Vec<CellAssignment>,
+}
+
+impl IntoResponse for CellsApiResponse {
+    fn into_response(self) -> Response {
+        (StatusCode::OK, Json(self)).into_response()
+    }
+}
+
+/// Body of the `/lookup_batch` endpoint.
+#[derive(Deserialize, Debug)]
+struct BatchLookupRequest {
+    ids: Vec<String>,
+    locality: Option<String>,
+}
+
+/// Response of the `/lookup_batch` endpoint. Ids without a cell are left out.
+#[derive(Serialize)]
+struct BatchLookupResponse {
+    cells: HashMap<String, String>,
+    /// Every cell of the ids that span multiple cells
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    assignments: HashMap<String, Vec<CellAssignment>>,
+}
+
+/// Body of the `/catalog` endpoint, both for reads and registrations.
+#[derive(Serialize, Deserialize)]
+struct CatalogBody {
+    cells: Vec<CatalogCell>,
+}
+
+#[derive(Serialize)]
+pub(crate) struct ApiErrorResponse {
+    pub error_message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub code: Option<&'static str>,
 }
 
 #[derive(Deserialize, Debug)]
 struct Params {
     id: String,
     locality: Option<String>,
+    /// Return the last known cell with its freshness instead of failing while the locator
+    /// is not ready. Only used by `/`.
+    #[serde(default)]
+    allow_stale: bool,
 }
 
 async fn handler(
     State(locator): State<Locator>,
     Query(params): Query<Params>,
 ) -> Result<ApiResponse, LocatorError> {
+    if params.allow_stale {
+        return locator
+            .lookup_stale(&params.id, params.locality.as_deref())
+            .await
+            .map(|lookup| lookup.into());
+    }
+
     locator
         .lookup(&params.id, params.locality.as_deref())
         .await
         .map(|cell| cell.into())
 }
 
+#[derive(Deserialize, Debug)]
+struct HistoryParams {
+    id: String,
+    /// Unix timestamp in seconds
+    at: u64,
+}
+
+async fn history_handler(
+    State(locator): State<Locator>,
+    Query(params): Query<HistoryParams>,
+) -> Result<ApiResponse, LocatorError> {
+    locator
+        .lookup_at(&params.id, params.at)
+        .await
+        .map(|cell| cell.into())
+}
+
+async fn cells_handler(
+    State(locator): State<Locator>,
+    Query(params): Query<Params>,
+) -> Result<CellsApiResponse, LocatorError> {
+    locator
+        .lookup_multi(&params.id, params.locality.as_deref())
+        .await
+        .map(|cells| CellsApiResponse { cells })
+}
+
+/// Same as `/`, but responds with the cell's locality and labels along with its id.
+async fn lookup_full_handler(
+    State(locator): State<Locator>,
+    Query(params): Query<Params>,
+) -> Result<Json<Cell>, LocatorError> {
+    locator
+        .lookup_full(&params.id, params.locality.as_deref())
+        .await
+        .map(Json)
+}
+
+async fn lookup_batch_handler(
+    State(locator): State<Locator>,
+    Json(body): Json<BatchLookupRequest>,
+) -> Result<Json<BatchLookupResponse>, Response> {
+    if body.ids.len() > MAX_BATCH_IDS {
+        return Err(error_response(
+            StatusCode::BAD_REQUEST,
+            &format!("at most {MAX_BATCH_IDS} ids can be looked up at once"),
+        ));
+    }
+    let ids: Vec<&str> = body.ids.iter().map(String::as_str).collect();
+    let mut assignments = locator
+        .lookup_many_multi(&ids, body.locality.as_deref())
+        .await
+        .map_err(IntoResponse::into_response)?;
+    // The primary cell is in the locality, like for `lookup_many`
+    let cells = assignments
+        .iter()
+        .filter_map(|(id, assignments)| {
+            let primary = assignments
+                .iter()
+                .find(|a| a.primary)
+                .or(assignments.first())?;
+            Some((id.clone(), primary.cell.clone()))
+        })
+        .collect();
+    assignments.retain(|_, assignments| assignments.len() > 1);
+    Ok(Json(BatchLookupResponse { cells, assignments }))
+}
+
+async fn catalog_handler(State(locator): State<Locator>) -> Result<Json<CatalogBody>, Response> {
+    locator
+        .cell_catalog()
+        .map(|cells| Json(CatalogBody { cells }))
+        .ok_or_else(|| error_response(StatusCode::NOT_FOUND, "no cell catalog is registered"))
+}
+
+async fn register_catalog_handler(
+    State(locator): State<Locator>,
+    Json(body): Json<CatalogBody>,
+) -> Result<Json<CatalogBody>, Response> {
+    locator
+        .register_cells(body.cells)
+        .await
+        .map_err(|e| error_response(StatusCode::BAD_REQUEST, &e))?;
+    catalog_handler(State(locator)).await
+}
+
+async fn rebalance_handler(
+    State(locator): State<Locator>,
+) -> Result<Json<RebalanceReport>, LocatorError> {
+    locator.rebalance_report().await.map(Json)
+}
+
+async fn stats_handler(State(locator): State<Locator>) -> Json<LocatorStats> {
+    Json(locator.stats().await)
+}
+
+/// Readiness with its reasons. Degraded locators answer lookups, so only a locator that is
+/// not ready fails the probe.
+async fn readiness_handler(State(locator): State<Locator>) -> (StatusCode, Json<Readiness>) {
+    let readiness = locator.readiness();
+    let status = match readiness.status {
+        ReadinessStatus::NotReady => StatusCode::SERVICE_UNAVAILABLE,
+        ReadinessStatus::Ready | ReadinessStatus::Degraded => StatusCode::OK,
+    };
+    (status, Json(readiness))
+}
+
+async fn shards_handler(State(locator): State<Locator>) -> Result<Json<ShardInfo>, Response> {
+    locator.shard_info().await.map(Json).ok_or_else(not_sharded)
+}
+
+#[derive(Deserialize, Debug)]
+struct ReshardPlanParams {
+    /// Number of shards after resharding
+    count: usize,
+}
+
+async fn reshard_plan_handler(
+    State(locator): State<Locator>,
+    Query(params): Query<ReshardPlanParams>,
+) -> Result<Json<ReshardPlan>, Response> {
+    if params.count == 0 {
+        return Err(error_response(
+            StatusCode::BAD_REQUEST,
+            "count must be at least 1",
+        ));
+    }
+    locator
+        .reshard_plan(params.count)
+        .await
+        .map(Json)
+        .ok_or_else(not_sharded)
+}
+
+fn not_sharded() -> Response {
+    error_response(StatusCode::NOT_FOUND, "the locator is not sharded")
+}
+
+/// Rejects everything but reads on read-only locators. Batch lookups are reads sent with
+/// POST.
+async fn reject_writes(request: Request, next: Next) -> Response {
+    if matches!(*request.method(), Method::GET | Method::HEAD)
+        || request.uri().path() == "/lookup_batch"
+    {
+        return next.run(request).await;
+    }
+    error_response(StatusCode::FORBIDDEN, "the locator is read-only")
+}
+
+/// Caller identified by its API key
+#[derive(Clone)]
+struct AuthenticatedCaller(String);
+
+/// Records the calling service of every request in a span and the `api.caller_requests`
+/// metric. Callers are identified by their API key, or by `X-Synapse-Caller` on APIs
+/// without keys.
+async fn track_caller(
+    State(known_callers): State<Arc<HashSet<String>>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let caller = caller(&request, &known_callers);
+    let endpoint = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map_or("unknown", |path| path.as_str())
+        .to_string();
+
+    let span = tracing::info_span!("api_request", caller, endpoint);
+    let response = next.run(request).instrument(span).await;
+    let status = response.status().as_u16().to_string();
+    tracing::debug!(caller, endpoint, status, "Handled API request");
+
+    metrics::counter!(
+        API_CALLER_REQUESTS.name,
+        "caller" => caller,
+        "endpoint" => endpoint,
+        "status" => status,
+    )
+    .increment(1);
+
+    response
+}
+
+/// Name of the calling service, "unknown" if it did not identify itself and "other" if
+/// it sent a name that no API key is configured for.
+fn caller(request: &Request, known_callers: &HashSet<String>) -> String {
+    match request.extensions().get::<AuthenticatedCaller>() {
+        Some(AuthenticatedCaller(name)) => name.clone(),
+        None => match request.headers().get(CALLER_HEADER) {
+            Some(value) => match value.to_str() {
+                Ok(name) if known_callers.contains(name) => name.to_string(),
+                _ => "other".to_string(),
+            },
+            None => "unknown".to_string(),
+        },
+    }
+}
+
+/// Rejects requests without a valid API key or over the caller's quota.
+async fn authenticate(
+    State(api_keys): State<Arc<ApiKeys>>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let authorization = request
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok());
+
+    let (caller, response) = match api_keys.authorize(authorization) {
+        Ok(caller) => {
+            request
+                .extensions_mut()
+                .insert(AuthenticatedCaller(caller.name.clone()));
+            (caller.name.clone(), next.run(request).await)
+        }
+        Err(AuthError::Unauthorized) => (
+            "unknown".to_string(),
+            error_response(StatusCode::UNAUTHORIZED, "missing or invalid API key"),
+        ),
+        Err(AuthError::RateLimited { caller }) => {
+            let mut response =
+                error_response(StatusCode::TOO_MANY_REQUESTS, "API key quota exceeded");
+            response
+                .headers_mut()
+                .insert(RETRY_AFTER, HeaderValue::from(1));
+            (caller, response)
+        }
+    };
+
+    metrics::counter!(
+        API_REQUESTS.name,
+        "caller" => caller,
+        "status" => response.status().as_u16().to_string(),
+    )
+    .increment(1);
+
+    response
+}
+
+fn error_response(status: StatusCode, message: &str) -> Response {
+    let body = Json(ApiErrorResponse {
+        error_message: message.to_string(),
+        code: None,
+    });
+    (status, body).into_response()
+}
+
+/// Status of the responses to failed lookups
+pub(crate) fn error_status(error: &LocatorError) -> StatusCode {
+    match error {
+        LocatorError::NoCell => StatusCode::NOT_FOUND,
+        LocatorError::LocalityMismatch {
+            requested: _,
+            actual: _,
+        } => StatusCode::NOT_FOUND,
+        LocatorError::Deleted => StatusCode::GONE,
+        LocatorError::NotReady | LocatorError::NotYetSynced => StatusCode::SERVICE_UNAVAILABLE,
+        LocatorError::WrongShard => StatusCode::MISDIRECTED_REQUEST,
+        LocatorError::LocalityNotServed => StatusCode::BAD_REQUEST,
+        LocatorError::InternalError => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
 impl IntoResponse for LocatorError {
     fn into_response(self) -> Response {
-        let status = match self {
-            LocatorError::NoCell => StatusCode::NOT_FOUND,
-            LocatorError::LocalityMismatch {
-                requested: _,
-                actual: _,
-            } => StatusCode::NOT_FOUND,
-            LocatorError::NotReady => StatusCode::SERVICE_UNAVAILABLE,
-            LocatorError::InternalError => StatusCode::INTERNAL_SERVER_ERROR,
-        };
+        let status = error_status(&self);
 
         let body = Json(ApiErrorResponse {
             error_message: self.to_string(),
+            code: Some(self.code()),
         });
-
         (status, body).into_response()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backup_routes::FilesystemRouteProvider;
+    use crate::config::{Compression, ControlPlane, LocatorDataType};
+    use axum::body::Body;
+
+    #[test]
+    fn test_caller() {
+        let request = |caller: Option<&str>| {
+            let mut request = Request::builder();
+            if let Some(caller) = caller {
+                request = request.header(CALLER_HEADER, caller);
+            }
+            request.body(Body::empty()).unwrap()
+        };
+
+        let known = HashSet::from(["ingest-router".to_string(), "proxy".to_string()]);
+
+        assert_eq!(caller(&request(None), &known), "unknown");
+        assert_eq!(
+            caller(&request(Some("ingest-router")), &known),
+            "ingest-router"
+        );
+        assert_eq!(caller(&request(Some("batch-jobs")), &known), "other");
+        assert_eq!(caller(&request(Some(&"a".repeat(65))), &known), "other");
+        assert_eq!(caller(&request(Some("proxy")), &HashSet::new()), "other");
+
+        // The API key takes precedence over the header
+        let mut authenticated = request(Some("proxy"));
+        authenticated
+            .extensions_mut()
+            .insert(AuthenticatedCaller("batch-jobs".into()));
+        assert_eq!(caller(&authenticated, &known), "batch-jobs");
+    }
+
+    #[tokio::test]
+    async fn test_catalog_write_keys() {
+        let key = |caller: &str, key: &str| ApiKey {
+            caller: caller.into(),
+            key: key.into(),
+            requests_per_second: None,
+        };
+        let dir = tempfile::tempdir().unwrap();
+        let locator = Locator::new(
+            LocatorDataType::Organization,
+            ControlPlane {
+                url: "http://127.0.0.1:1".into(),
+                retry: Default::default(),
+                pagination: Default::default(),
+                signing: None,
+            },
+            Arc::new(FilesystemRouteProvider::new(
+                dir.path().to_str().unwrap(),
+                "backup.bin",
+                Compression::None,
+            )),
+            None,
+            None,
+        );
+
+        let serve = |write_keys: Option<Vec<ApiKey>>| {
+            let app = router(
+                locator.clone(),
+                Some(vec![key("proxy", "lookup-key")]),
+                write_keys,
+            );
+            async move {
+                let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+                let addr = listener.local_addr().unwrap();
+                tokio::spawn(async move { axum::serve(listener, app).await });
+                format!("http://{addr}/catalog")
+            }
+        };
+        let client = reqwest::Client::new();
+        let body = serde_json::json!({"cells": [{"id": "us1", "locality": "us"}]});
+        let put = |url: &str, key: &str| client.put(url).bearer_auth(key).json(&body).send();
+
+        // Not served without write keys, not even to lookup keys
+        let url = serve(None).await;
+        let response = put(&url, "lookup-key").await.unwrap();
+        assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+
+        let url = serve(Some(vec![key("deploy", "write-key")])).await;
+        let response = put(&url, "lookup-key").await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let response = put(&url, "write-key").await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // Reads keep the lookup keys
+        let response = client
+            .get(&url)
+            .bearer_auth("lookup-key")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = client
+            .get(&url)
+            .bearer_auth("write-key")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+}
@@ -0,0 +1,225 @@
+//! API key authentication and per-caller quotas for the lookup API.
+//!
+//! Every configured key identifies a caller. Requests must send their key as
+//! `Authorization: Bearer <key>`, and are attributed to the caller in metrics. Callers with
+//! a quota are rate limited with a token bucket that holds one second worth of requests, so
+//! a runaway client cannot exhaust the locator for everyone else.
+//!
+//! Keys are compared by their SHA-256 digest, in constant time and against every
+//! configured key, so that the time taken reveals neither a key nor which key matched.
+use crate::clock::Clock;
+use crate::config::ApiKey;
+use sha2::{Digest, Sha256};
+use shared::constant_time;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+pub struct ApiKeys {
+    // With the SHA-256 digest of their key, so that requests do not compare the key itself
+    callers: Vec<([u8; 32], Caller)>,
+    // Refills the quotas
+    clock: Arc<dyn Clock>,
+}
+
+pub struct Caller {
+    pub name: String,
+    limiter: Option<TokenBucket>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum AuthError {
+    /// No key or an unknown key was sent
+    Unauthorized,
+    /// The caller exceeded its quota
+    RateLimited { caller: String },
+}
+
+impl ApiKeys {
+    pub fn new(keys: Vec<ApiKey>, clock: Arc<dyn Clock>) -> Self {
+        let now = clock.now();
+        let callers = keys
+            .into_iter()
+            .map(|key| {
+                let caller = Caller {
+                    name: key.caller,
+                    limiter: key
+                        .requests_per_second
+                        .map(|rate| TokenBucket::new(rate, now)),
+                };
+                (digest(&key.key), caller)
+            })
+            .collect();
+
+        Self { callers, clock }
+    }
+
+    /// Authenticates the request from its `Authorization` header value and takes one
+    /// request from the caller's quota.
+    pub fn authorize(&self, authorization: Option<&str>) -> Result<&Caller, AuthError> {
+        let key = authorization
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .ok_or(AuthError::Unauthorized)?;
+        let digest = digest(key.trim());
+        // Every key is compared, rather than stopping at the match
+        let caller = self
+            .callers
+            .iter()
+            .fold(None, |found, (expected, caller)| {
+                let matches = constant_time::eq(expected, &digest);
+                found.or(matches.then_some(caller))
+            })
+            .ok_or(AuthError::Unauthorized)?;
+
+        if let Some(limiter) = &caller.limiter
+            && !limiter.try_acquire(self.clock.now())
+        {
+            return Err(AuthError::RateLimited {
+                caller: caller.name.clone(),
+            });
+        }
+
+        Ok(caller)
+    }
+}
+
+fn digest(key: &str) -> [u8; 32] {
+    Sha256::digest(key.as_bytes()).into()
+}
+
+/// Allows `rate` requests per second, with bursts of up to `rate` requests.
+struct TokenBucket {
+    rate: f64,
+    state: Mutex<BucketState>,
+}
+
+struct BucketState {
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl TokenBucket {
+    /// Full at `now`
+    fn new(requests_per_second: u32, now: Instant) -> Self {
+        let rate = f64::from(requests_per_second);
+        Self {
+            rate,
+            state: Mutex::new(BucketState {
+                tokens: rate,
+                refilled_at: now,
+            }),
+        }
+    }
+
+    fn try_acquire(&self, now: Instant) -> bool {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+
+        let elapsed = now.saturating_duration_since(state.refilled_at);
+        state.tokens = (state.tokens + elapsed.as_secs_f64() * self.rate).min(self.rate);
+        state.refilled_at = now;
+
+        if state.tokens >= 1.0 {
+            state.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+    use std::time::Duration;
+
+    fn api_keys(clock: Arc<MockClock>) -> ApiKeys {
+        ApiKeys::new(
+            vec![
+                ApiKey {
+                    caller: "proxy".into(),
+                    key: "proxy-key".into(),
+                    requests_per_second: None,
+                },
+                ApiKey {
+                    caller: "batch-job".into(),
+                    key: "batch-key".into(),
+                    requests_per_second: Some(2),
+                },
+            ],
+            clock,
+        )
+    }
+
+    #[test]
+    fn test_authorize() {
+        let keys = api_keys(Arc::new(MockClock::new(0)));
+
+        assert_eq!(
+            keys.authorize(Some("Bearer proxy-key")).unwrap().name,
+            "proxy"
+        );
+        assert_eq!(
+            keys.authorize(Some("Bearer invalid")).err(),
+            Some(AuthError::Unauthorized)
+        );
+        assert_eq!(
+            keys.authorize(Some("proxy-key")).err(),
+            Some(AuthError::Unauthorized)
+        );
+        assert_eq!(keys.authorize(None).err(), Some(AuthError::Unauthorized));
+        assert_eq!(
+            keys.authorize(Some("Bearer ")).err(),
+            Some(AuthError::Unauthorized)
+        );
+        assert_eq!(
+            keys.authorize(Some("Bearer batch-key")).unwrap().name,
+            "batch-job"
+        );
+    }
+
+    #[test]
+    fn test_quota() {
+        let clock = Arc::new(MockClock::new(0));
+        let keys = api_keys(clock.clone());
+
+        // The burst is one second worth of requests
+        assert!(keys.authorize(Some("Bearer batch-key")).is_ok());
+        assert!(keys.authorize(Some("Bearer batch-key")).is_ok());
+        assert_eq!(
+            keys.authorize(Some("Bearer batch-key")).err(),
+            Some(AuthError::RateLimited {
+                caller: "batch-job".into()
+            })
+        );
+
+        // Other callers are not affected
+        assert!(keys.authorize(Some("Bearer proxy-key")).is_ok());
+
+        // One request per 500ms
+        clock.advance(Duration::from_millis(500));
+        assert!(keys.authorize(Some("Bearer batch-key")).is_ok());
+        assert!(keys.authorize(Some("Bearer batch-key")).is_err());
+    }
+
+    #[test]
+    fn test_token_bucket_refill() {
+        let start = MockClock::new(0).now();
+        let bucket = TokenBucket::new(10, start);
+
+        for _ in 0..10 {
+            assert!(bucket.try_acquire(start));
+        }
+        assert!(!bucket.try_acquire(start));
+
+        // One token per 100ms
+        assert!(bucket.try_acquire(start + Duration::from_millis(100)));
+        assert!(!bucket.try_acquire(start + Duration::from_millis(100)));
+
+        // Never more than the burst
+        let later = start + Duration::from_secs(60);
+        for _ in 0..10 {
+            assert!(bucket.try_acquire(later));
+        }
+        assert!(!bucket.try_acquire(later));
+    }
+}
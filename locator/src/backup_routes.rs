@@ -2,13 +2,26 @@
 /// a previously stored copy, even when the control plane is unavailable.
 use crate::config;
 use crate::cursor::Cursor;
-use crate::types::RouteData;
+use crate::history::MappingHistory;
+use crate::types::{Cell, CellAssignment, CellId, RouteData};
+use std::collections::{HashMap, HashSet};
 use std::fs::File;
-use std::io::{self, Read, Write};
+use std::io::{self, BufRead, Read, Write};
 use std::path::{Path, PathBuf};
-use std::sync::Mutex;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// Starts every backup, followed by its format version. Backups without it were written
+// before the format was versioned. 0xff never starts a bincode encoded map.
+const MAGIC: [u8; 4] = [0xff, b'S', b'Y', b'N'];
+// Version of the encoding of `RouteData`, to be incremented whenever its fields change.
+// Older versions must keep being decoded, since the backup is read after an upgrade.
+const FORMAT_VERSION: u8 = 2;
 
 static METADATA_KEY: &str = "last_cursor";
+static LEASE_OBJECT_KEY: &str = "backup-routes.lease";
+// Suffix of the temporary copy written by round trips
+static SELF_TEST_SUFFIX: &str = ".self-test";
 
 #[derive(thiserror::Error, Debug)]
 pub enum BackupError {
@@ -35,12 +48,24 @@ pub enum BackupError {
 
     #[error("reqwest error: {0}")]
     Reqwest(#[from] reqwest::Error),
+
+    #[error("lease error: {0}")]
+    Lease(#[from] serde_json::Error),
+
+    #[error("unsupported backup format version {0}")]
+    UnsupportedVersion(u8),
+
+    #[error("invalid config: {0}")]
+    InvalidConfig(String),
 }
 
 #[async_trait::async_trait]
 pub trait BackupRouteProvider: Send + Sync {
     async fn load(&self) -> Result<RouteData, BackupError>;
     async fn store(&self, route_data: &RouteData) -> Result<(), BackupError>;
+    /// Stores the route data to a temporary copy next to the backup and loads it back,
+    /// leaving the backup itself untouched. Used by the self-test.
+    async fn round_trip(&self, route_data: &RouteData) -> Result<RouteData, BackupError>;
 }
 
 #[derive(Clone)]
@@ -79,43 +104,108 @@ impl Codec {
     fn write<W: Write>(&self, writer: &mut W, data: &RouteData) -> Result<usize, BackupError> {
         match self.compression {
             Compression::None => {
-                let size = bincode::encode_into_std_write(data, writer, self.config)?;
+                let size = self.encode(writer, data)?;
                 writer.flush()?;
                 Ok(size)
             }
             Compression::Zstd(level) => {
                 let mut encoder = zstd::stream::write::Encoder::new(writer, level)?;
-                let size = bincode::encode_into_std_write(data, &mut encoder, self.config)?;
+                let size = self.encode(&mut encoder, data)?;
                 encoder.finish()?;
                 Ok(size)
             }
             Compression::Gzip => {
                 let mut encoder =
                     flate2::write::GzEncoder::new(writer, flate2::Compression::default());
-                let size = bincode::encode_into_std_write(data, &mut encoder, self.config)?;
+                let size = self.encode(&mut encoder, data)?;
                 encoder.finish()?;
                 Ok(size)
             }
         }
     }
 
-    fn read<R: Read>(&self, mut reader: R) -> Result<RouteData, BackupError> {
+    fn read<R: Read>(&self, reader: R) -> Result<RouteData, BackupError> {
         match self.compression {
-            Compression::None => {
-                let value: RouteData = bincode::decode_from_std_read(&mut reader, self.config)?;
-                Ok(value)
-            }
-            Compression::Zstd(_) => {
-                let mut decoder = zstd::stream::read::Decoder::new(reader)?;
-                let decoded: RouteData = bincode::decode_from_std_read(&mut decoder, self.config)?;
-                Ok(decoded)
+            Compression::None => self.decode(reader),
+            Compression::Zstd(_) => self.decode(zstd::stream::read::Decoder::new(reader)?),
+            Compression::Gzip => self.decode(flate2::read::GzDecoder::new(reader)),
+        }
+    }
+
+    fn encode<W: Write>(&self, writer: &mut W, data: &RouteData) -> Result<usize, BackupError> {
+        writer.write_all(&MAGIC)?;
+        writer.write_all(&[FORMAT_VERSION])?;
+        let size = bincode::encode_into_std_write(data, writer, self.config)?;
+        Ok(MAGIC.len() + 1 + size)
+    }
+
+    fn decode<R: Read>(&self, reader: R) -> Result<RouteData, BackupError> {
+        let mut reader = io::BufReader::new(reader);
+        if reader.fill_buf()?.first() != Some(&MAGIC[0]) {
+            let legacy: LegacyRouteData = bincode::decode_from_std_read(&mut reader, self.config)?;
+            return Ok(legacy.into());
+        }
+
+        let mut header = [0; MAGIC.len() + 1];
+        reader.read_exact(&mut header)?;
+        match header {
+            [0xff, b'S', b'Y', b'N', FORMAT_VERSION] => {
+                Ok(bincode::decode_from_std_read(&mut reader, self.config)?)
             }
-            Compression::Gzip => {
-                let mut decoder = flate2::read::GzDecoder::new(reader);
-                let decoded: RouteData = bincode::decode_from_std_read(&mut decoder, self.config)?;
-                Ok(decoded)
+            [0xff, b'S', b'Y', b'N', 1] => {
+                let v1: RouteDataV1 = bincode::decode_from_std_read(&mut reader, self.config)?;
+                Ok(v1.into())
             }
+            [0xff, b'S', b'Y', b'N', version] => Err(BackupError::UnsupportedVersion(version)),
+            _ => Err(BackupError::Decode(bincode::error::DecodeError::Other(
+                "invalid backup header",
+            ))),
+        }
+    }
+}
+
+/// `RouteData` as written before backups had a format version
+#[derive(bincode::Decode)]
+struct LegacyRouteData {
+    id_to_cell: HashMap<String, CellId>,
+    last_cursor: Option<String>,
+    // Id and locality of every cell
+    cells: HashMap<CellId, (String, String)>,
+}
+
+impl From<LegacyRouteData> for RouteData {
+    fn from(legacy: LegacyRouteData) -> Self {
+        let cell_to_locality = legacy
+            .cells
+            .into_iter()
+            .map(|(cell_id, (_, locality))| (cell_id, locality))
+            .collect();
+        RouteData::from(legacy.id_to_cell, legacy.last_cursor, cell_to_locality)
+    }
+}
+
+/// `RouteData` of format version 1, whose tombstones have no time
+#[derive(bincode::Decode)]
+struct RouteDataV1 {
+    id_to_cell: HashMap<String, CellId>,
+    id_to_cells: HashMap<String, Vec<CellAssignment>>,
+    last_cursor: Option<String>,
+    cells: HashMap<CellId, Arc<Cell>>,
+    history: MappingHistory,
+    deleted: HashSet<String>,
+}
+
+impl From<RouteDataV1> for RouteData {
+    fn from(v1: RouteDataV1) -> Self {
+        RouteData {
+            id_to_cell: v1.id_to_cell,
+            id_to_cells: v1.id_to_cells,
+            last_cursor: v1.last_cursor,
+            cells: v1.cells,
+            history: v1.history,
+            deleted: HashMap::new(),
         }
+        .with_deleted(v1.deleted)
     }
 }
 
@@ -157,6 +247,19 @@ impl BackupRouteProvider for FilesystemRouteProvider {
 
         Ok(())
     }
+
+    async fn round_trip(&self, route_data: &RouteData) -> Result<RouteData, BackupError> {
+        let mut path = self.path.clone().into_os_string();
+        path.push(SELF_TEST_SUFFIX);
+
+        let mut writer = io::BufWriter::new(File::create(&path)?);
+        self.codec.write(&mut writer, route_data)?;
+        drop(writer);
+        let loaded = self.codec.read(io::BufReader::new(File::open(&path)?));
+
+        std::fs::remove_file(&path)?;
+        loaded
+    }
 }
 
 // The google-cloud-storage crate does not expose a way to view the object metadata via the Storage client.
@@ -209,10 +312,113 @@ impl MetadataClient {
     }
 }
 
+/// Contents of the lease object.
+#[derive(serde::Serialize, serde::Deserialize, Debug, PartialEq)]
+struct LeaseRecord {
+    holder: String,
+    // Unix timestamp in seconds
+    expires_at: u64,
+}
+
+impl LeaseRecord {
+    // A lease can be taken if it is free, expired or already held by this replica
+    fn can_acquire(&self, holder: &str, now: u64) -> bool {
+        self.holder == holder || self.expires_at <= now
+    }
+}
+
+/// Elects a single writer among locator replicas sharing a GCS backup.
+///
+/// The lease is a small object next to the backup. Replicas take or renew it before writing
+/// the backup, using a generation precondition so that only one of several concurrent
+/// attempts succeeds. The leader renews the lease with every backup write. If it disappears,
+/// the lease expires after the TTL and the next replica to write the backup takes over.
+struct BackupLease {
+    // Unique id of this replica
+    holder: String,
+    ttl: Duration,
+}
+
+impl BackupLease {
+    fn new(ttl: Duration) -> Self {
+        let hostname = std::env::var("HOSTNAME").unwrap_or_else(|_| "locator".into());
+        BackupLease {
+            holder: format!("{hostname}-{:08x}", rand::random::<u32>()),
+            ttl,
+        }
+    }
+
+    fn now() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs()
+    }
+
+    /// Takes or renews the lease. Returns false if another replica holds it.
+    async fn try_acquire(
+        &self,
+        client: &google_cloud_storage::client::Storage,
+        bucket_name: &str,
+    ) -> Result<bool, BackupError> {
+        let now = Self::now();
+
+        // Generation 0 makes the write conditional on the lease object not existing yet
+        let generation = match client
+            .read_object(bucket_name, LEASE_OBJECT_KEY)
+            .send()
+            .await
+        {
+            Ok(mut response) => {
+                let generation = response.object().generation;
+                let mut data = Vec::new();
+                while let Some(chunk) = response.next().await {
+                    data.extend_from_slice(&chunk?);
+                }
+
+                let lease: LeaseRecord = serde_json::from_slice(&data)?;
+                if !lease.can_acquire(&self.holder, now) {
+                    tracing::debug!(
+                        holder = lease.holder,
+                        "Backup lease is held by another replica"
+                    );
+                    return Ok(false);
+                }
+                generation
+            }
+            Err(e) if e.http_status_code() == Some(404) => 0,
+            Err(e) => return Err(e.into()),
+        };
+
+        let lease = LeaseRecord {
+            holder: self.holder.clone(),
+            expires_at: now + self.ttl.as_secs(),
+        };
+
+        let result = client
+            .write_object(
+                bucket_name,
+                LEASE_OBJECT_KEY,
+                bytes::Bytes::from(serde_json::to_vec(&lease)?),
+            )
+            .set_if_generation_match(generation)
+            .send_buffered()
+            .await;
+
+        match result {
+            Ok(_) => Ok(true),
+            // Another replica changed the lease since it was read
+            Err(e) if e.http_status_code() == Some(412) => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
 // Route provider alternative that uses Google Cloud storage instead of local filesystem.
 // This code does not handle object versioning and TTLs -- this should be configured at
 //the bucket level.
 // This backend assumes Google's Application Default Credentials are being used.
+// When multiple replicas share a bucket, only the holder of the backup lease writes.
 pub struct GcsRouteProvider {
     bucket_name: String,
     codec: Codec,
@@ -223,12 +429,14 @@ pub struct GcsRouteProvider {
     metadata_client: MetadataClient,
     // The latest known cursor in the backup store. Used to avoid redundant uploads.
     last_cursor: Mutex<Option<Cursor>>,
+    lease: BackupLease,
 }
 
 impl GcsRouteProvider {
     pub async fn new(
         bucket: String,
         compression: config::Compression,
+        lease_ttl: Duration,
     ) -> Result<Self, BackupError> {
         let object_key = "backup-routes.bin".to_string();
 
@@ -249,6 +457,7 @@ impl GcsRouteProvider {
             client,
             metadata_client,
             last_cursor: Mutex::new(None),
+            lease: BackupLease::new(lease_ttl),
         })
     }
 }
@@ -289,6 +498,16 @@ impl BackupRouteProvider for GcsRouteProvider {
         };
         let new_last_cursor: Cursor = cursor_str.parse()?;
 
+        // Renew the lease even if the backup itself is up to date, so the leader keeps it
+        if !self
+            .lease
+            .try_acquire(&self.client, &self.bucket_name)
+            .await?
+        {
+            tracing::info!("Skipping route store: another replica holds the backup lease");
+            return Ok(());
+        }
+
         // Check the cursor stored in GCS metadata first. Only proceed with
         // write if the new cursor is later than the one already stored.
         // If there is no cursor, the file may not exist proceed with the write.
@@ -332,15 +551,37 @@ impl BackupRouteProvider for GcsRouteProvider {
 
         Ok(())
     }
+
+    // The copy is not deleted, which needs the gRPC API, and is overwritten by the next
+    // round trip instead. It is written without taking the lease.
+    async fn round_trip(&self, route_data: &RouteData) -> Result<RouteData, BackupError> {
+        let object_key = format!("{}{SELF_TEST_SUFFIX}", self.object_key);
+
+        let mut buffer: Vec<u8> = Vec::new();
+        self.codec.write(&mut buffer, route_data)?;
+        self.client
+            .write_object(&self.bucket_name, &object_key, bytes::Bytes::from(buffer))
+            .send_buffered()
+            .await?;
+
+        let mut response = self
+            .client
+            .read_object(&self.bucket_name, &object_key)
+            .send()
+            .await?;
+        let mut data = Vec::new();
+        while let Some(chunk) = response.next().await {
+            data.extend_from_slice(&chunk?);
+        }
+        self.codec.read(io::Cursor::new(data))
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::types::Cell;
     use base64::{Engine as _, engine::general_purpose::STANDARD};
-    use std::collections::HashMap;
-    use std::sync::Arc;
+    use std::collections::BTreeMap;
 
     fn get_route_data() -> RouteData {
         let cursor_json_str = serde_json::json!({
@@ -352,14 +593,32 @@ mod tests {
 
         RouteData {
             id_to_cell: HashMap::from([("org1".into(), "cell1".into())]),
+            id_to_cells: HashMap::from([(
+                "org1".into(),
+                vec![
+                    CellAssignment {
+                        cell: "cell1".into(),
+                        weight: 90,
+                        primary: true,
+                    },
+                    CellAssignment {
+                        cell: "cell2".into(),
+                        weight: 10,
+                        primary: false,
+                    },
+                ],
+            )]),
             last_cursor: Some(last_cursor),
             cells: HashMap::from([(
                 "cell1".into(),
                 Arc::new(Cell {
                     id: "cell1".into(),
                     locality: "us".into(),
+                    labels: BTreeMap::from([("maintenance".into(), "false".into())]),
                 }),
             )]),
+            history: MappingHistory::default(),
+            deleted: HashMap::from([("org2".into(), 1_700_000_000)]),
         }
     }
 
@@ -375,13 +634,62 @@ mod tests {
             let data = get_route_data();
             let mut buffer: Vec<u8> = Vec::new();
             let size = codec.write(&mut buffer, &data).unwrap();
-            assert_eq!(size, 78);
+            assert_eq!(size, 137);
             let mut reader: &[u8] = &buffer;
             let decoded = codec.read(&mut reader).unwrap();
             assert_eq!(data, decoded);
         }
     }
 
+    #[test]
+    fn test_codec_versions() {
+        let codec = Codec::new(Compression::None);
+
+        // Written before the format was versioned: {"org1": "cell1"}, cursor "c" and cell1
+        // in "us"
+        let mut legacy: &[u8] = b"\x01\x04org1\x05cell1\x01\x01c\x01\x05cell1\x05cell1\x02us";
+        let decoded = codec.read(&mut legacy).unwrap();
+        assert_eq!(
+            decoded,
+            RouteData::from(
+                HashMap::from([("org1".into(), "cell1".into())]),
+                Some("c".into()),
+                HashMap::from([("cell1".into(), "us".into())]),
+            )
+        );
+
+        // Version 1 had tombstones without the time they were seen
+        let data = get_route_data();
+        let mut v1 = vec![0xff, b'S', b'Y', b'N', 1];
+        v1.extend(
+            bincode::encode_to_vec(
+                (
+                    &data.id_to_cell,
+                    &data.id_to_cells,
+                    &data.last_cursor,
+                    &data.cells,
+                    &data.history,
+                    HashSet::from(["org2".to_string()]),
+                ),
+                bincode::config::standard(),
+            )
+            .unwrap(),
+        );
+        let decoded = codec.read(&mut &v1[..]).unwrap();
+        assert_eq!(decoded.deleted, HashMap::from([("org2".into(), 0)]));
+        assert_eq!(decoded.id_to_cells, data.id_to_cells);
+        assert_eq!(decoded.history, data.history);
+
+        let mut buffer = Vec::new();
+        codec.write(&mut buffer, &data).unwrap();
+        assert_eq!(buffer[..5], [0xff, b'S', b'Y', b'N', FORMAT_VERSION]);
+        buffer[4] = FORMAT_VERSION + 1;
+        assert!(matches!(
+            codec.read(&mut &buffer[..]),
+            Err(BackupError::UnsupportedVersion(_))
+        ));
+    }
+
     #[tokio::test]
     async fn test_filesystem() {
         let dir = tempfile::tempdir().unwrap();
@@ -398,14 +706,45 @@ mod tests {
         assert_eq!(data, loaded);
     }
 
+    #[test]
+    fn test_lease_can_acquire() {
+        let lease = LeaseRecord {
+            holder: "replica-a".into(),
+            expires_at: 100,
+        };
+
+        // The holder can always renew
+        assert!(lease.can_acquire("replica-a", 50));
+        // Other replicas wait for the lease to expire
+        assert!(!lease.can_acquire("replica-b", 50));
+        assert!(lease.can_acquire("replica-b", 100));
+    }
+
+    #[test]
+    fn test_validate_lease_ttl() {
+        let store = |lease_ttl_secs| config::BackupRouteStoreType::Gcs {
+            bucket: "bucket".into(),
+            compression: config::Compression::None,
+            lease_ttl_secs,
+        };
+        // The lease must outlast the time between two backups
+        assert!(store(0).validate().is_err());
+        assert!(store(360).validate().is_err());
+        assert!(store(361).validate().is_ok());
+    }
+
     #[tokio::test]
     async fn test_gcs() {
         let endpoint = "http://localhost:4443";
         let bucket = "test-bucket";
 
-        let mut provider = GcsRouteProvider::new(bucket.into(), config::Compression::Zstd1)
-            .await
-            .unwrap();
+        let mut provider = GcsRouteProvider::new(
+            bucket.into(),
+            config::Compression::Zstd1,
+            Duration::from_secs(60),
+        )
+        .await
+        .unwrap();
 
         // Override the clients so we can use the local emulator
         provider.client = google_cloud_storage::client::Storage::builder()
@@ -0,0 +1,139 @@
+//! Catalog of the known cells.
+//!
+//! Without a catalog, the cells are only known from the mappings synced from the control
+//! plane, so a mapping to a cell that does not exist is indistinguishable from a valid one.
+//! The full cell list can be registered from the config or through the API. Once it is, lookups
+//! resolving to a cell missing from the catalog are counted in `catalog.unknown_cells`, and
+//! other components can query the catalog from the locator. The catalog is held in memory,
+//! so cells registered through the API must be registered again after a restart.
+use crate::metrics_defs::CATALOG_UNKNOWN_CELLS;
+use crate::types::{CatalogCell, CellId};
+use std::collections::{HashMap, HashSet};
+use std::sync::RwLock;
+
+#[derive(Default)]
+pub struct CellCatalog {
+    cells: RwLock<Option<HashMap<CellId, CatalogCell>>>,
+}
+
+impl CellCatalog {
+    pub fn new(cells: Option<Vec<CatalogCell>>) -> Self {
+        let catalog = CellCatalog::default();
+        if let Some(cells) = cells {
+            catalog.register(cells);
+        }
+        catalog
+    }
+
+    /// Checks that the cells have an id and a locality, valid URLs and no duplicate ids.
+    pub fn validate(cells: &[CatalogCell]) -> Result<(), String> {
+        let mut ids = HashSet::new();
+        for cell in cells {
+            if cell.id.is_empty() {
+                return Err("cell without an id".to_string());
+            }
+            if cell.locality.is_empty() {
+                return Err(format!("cell {} has no locality", cell.id));
+            }
+            for url in [&cell.sentry_url, &cell.relay_url].into_iter().flatten() {
+                if reqwest::Url::parse(url).is_err() {
+                    return Err(format!("cell {} has an invalid url {url}", cell.id));
+                }
+            }
+            if !ids.insert(cell.id.as_str()) {
+                return Err(format!("cell {} is listed more than once", cell.id));
+            }
+        }
+        Ok(())
+    }
+
+    /// Replaces the catalog. The cells must have been validated.
+    pub fn register(&self, cells: Vec<CatalogCell>) {
+        let cells = cells
+            .into_iter()
+            .map(|cell| (cell.id.clone(), cell))
+            .collect();
+        *self.cells.write().unwrap_or_else(|e| e.into_inner()) = Some(cells);
+    }
+
+    /// The registered cells sorted by id, None if no catalog was registered.
+    pub fn list(&self) -> Option<Vec<CatalogCell>> {
+        let cells = self.cells.read().unwrap_or_else(|e| e.into_inner());
+        cells.as_ref().map(|cells| {
+            let mut cells: Vec<_> = cells.values().cloned().collect();
+            cells.sort_by(|a, b| a.id.cmp(&b.id));
+            cells
+        })
+    }
+
+    /// Whether the cell is known. Every cell is known if no catalog was registered.
+    pub fn contains(&self, cell_id: &str) -> bool {
+        let cells = self.cells.read().unwrap_or_else(|e| e.into_inner());
+        cells
+            .as_ref()
+            .is_none_or(|cells| cells.contains_key(cell_id))
+    }
+
+    /// Reports a lookup that resolved to `cell_id` if the cell is not in the catalog.
+    pub fn check(&self, cell_id: &str) {
+        if !self.contains(cell_id) {
+            tracing::debug!(
+                cell_id,
+                "Lookup resolved to a cell missing from the catalog"
+            );
+            metrics::counter!(CATALOG_UNKNOWN_CELLS.name, "cell_id" => cell_id.to_string())
+                .increment(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cell(id: &str, locality: &str) -> CatalogCell {
+        CatalogCell {
+            id: id.to_string(),
+            locality: locality.to_string(),
+            sentry_url: Some(format!("https://sentry-{id}.internal")),
+            relay_url: None,
+        }
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(CellCatalog::validate(&[cell("us1", "us"), cell("de1", "de")]).is_ok());
+        assert!(CellCatalog::validate(&[]).is_ok());
+
+        assert!(CellCatalog::validate(&[cell("", "us")]).is_err());
+        assert!(CellCatalog::validate(&[cell("us1", "")]).is_err());
+        assert_eq!(
+            CellCatalog::validate(&[cell("us1", "us"), cell("us1", "de")]),
+            Err("cell us1 is listed more than once".to_string())
+        );
+
+        let mut invalid_url = cell("us1", "us");
+        invalid_url.relay_url = Some("not a url".to_string());
+        assert!(CellCatalog::validate(&[invalid_url]).is_err());
+    }
+
+    #[test]
+    fn test_register() {
+        let catalog = CellCatalog::new(None);
+        assert_eq!(catalog.list(), None);
+        assert!(catalog.contains("us1"));
+
+        catalog.register(vec![cell("us2", "us"), cell("us1", "us")]);
+        assert_eq!(
+            catalog.list(),
+            Some(vec![cell("us1", "us"), cell("us2", "us")])
+        );
+        assert!(catalog.contains("us1"));
+        assert!(!catalog.contains("de1"));
+
+        // Registering replaces the catalog
+        catalog.register(vec![cell("de1", "de")]);
+        assert!(!catalog.contains("us1"));
+        assert!(catalog.contains("de1"));
+    }
+}
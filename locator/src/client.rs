@@ -1,8 +1,16 @@
-use crate::config::{BackupRouteStoreType, LocatorDataType};
+use crate::client_cache::LookupCache;
+use crate::config::{BackupRouteStoreType, ClientCache, ControlPlane, LocatorDataType};
+use crate::datagram::DatagramClient;
 use crate::get_provider;
 use crate::locator::{Locator as LocatorService, LocatorError};
-use http::StatusCode;
+use crate::metrics_defs::DATAGRAM_FALLBACKS;
+use crate::shard::{ShardInfo, shard_of};
+use crate::types::{
+    CatalogCell, Cell, CellAssignment, LocatorStats, Readiness, ReadinessStatus, StaleLookup,
+};
+use http::{Method, StatusCode};
 use std::collections::HashMap;
+use std::sync::Arc;
 
 #[derive(thiserror::Error, Debug)]
 pub enum ClientError {
@@ -14,24 +22,73 @@ pub enum ClientError {
     ReqwestError(#[from] reqwest::Error),
     #[error("I/O error: {0}")]
     IoError(#[from] std::io::Error),
+    #[error("Invalid shard topology: {0}")]
+    InvalidShards(String),
+    #[error("Invalid client config: {0}")]
+    InvalidConfig(String),
+    /// Error of a lookup shared by concurrent lookups of the same key, see `LookupCache`
+    #[error("{0}")]
+    Shared(Arc<ClientError>),
 }
 
+impl ClientError {
+    /// The error of a shared lookup. Locator errors are kept as is so that callers can
+    /// still match on them.
+    pub(crate) fn shared(error: &Arc<ClientError>) -> Self {
+        match error.as_ref() {
+            ClientError::LocatorError(e) => ClientError::LocatorError(e.clone()),
+            _ => ClientError::Shared(error.clone()),
+        }
+    }
+}
+
+/// Header with which services identify themselves to remote locators
+pub const CALLER_HEADER: &str = "x-synapse-caller";
+
 /// Configuration for creating a Locator client
 pub struct LocatorConfig {
     pub locator_type: LocatorType,
     pub data_type: LocatorDataType,
+    /// Name of this service, sent to remote locators in `X-Synapse-Caller` so that their
+    /// load can be broken down by caller
+    pub caller: Option<String>,
 }
 
 pub enum LocatorType {
     InProcess {
-        control_plane_url: String,
+        control_plane: ControlPlane,
         backup_route_store_type: BackupRouteStoreType,
         localities: Option<Vec<String>>,
         locality_to_default_cell: Option<HashMap<String, String>>,
     },
     Url {
         url: String,
+        /// Sent as a bearer token if the locator API requires API keys
+        api_key: Option<String>,
+        /// Address of the locator's datagram listener, which lookups are sent to instead of
+        /// the API. Only for locators on the same host.
+        datagram_addr: Option<String>,
+        /// Cache of the plain lookups. Disabled if not set.
+        cache: Option<ClientCache>,
     },
+    /// Locators each holding one shard of the keyspace. Every lookup is sent to the
+    /// locator of the key's shard.
+    Sharded {
+        shards: ShardTopology,
+        /// Sent as a bearer token if the locator API requires API keys
+        api_key: Option<String>,
+    },
+}
+
+/// Where the locator URLs of a sharded keyspace come from
+#[derive(Clone, Debug, serde::Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ShardTopology {
+    /// Locator URL of every shard, by index
+    Urls(Vec<String>),
+    /// Any of the sharded locators, whose `/shards` endpoint is asked for the URLs on
+    /// startup
+    DiscoveryUrl(String),
 }
 
 /// A unified locator client that can work with either an in-process locator
@@ -43,7 +100,7 @@ impl Locator {
     pub async fn new(config: LocatorConfig) -> Result<Self, ClientError> {
         match config.locator_type {
             LocatorType::InProcess {
-                control_plane_url,
+                control_plane,
                 backup_route_store_type,
                 localities,
                 locality_to_default_cell,
@@ -51,13 +108,59 @@ impl Locator {
                 let provider = get_provider(backup_route_store_type).await?;
                 Ok(Locator(LocatorInner::InProcess(LocatorService::new(
                     config.data_type,
-                    control_plane_url,
+                    control_plane,
                     provider,
                     localities,
                     locality_to_default_cell,
                 ))))
             }
-            LocatorType::Url { url } => Ok(Locator(LocatorInner::Url(HttpClient::new(url)))),
+            LocatorType::Url {
+                url,
+                api_key,
+                datagram_addr,
+                cache,
+            } => {
+                let mut client = HttpClient::new(url, api_key, config.caller, config.data_type);
+                if let Some(addr) = datagram_addr {
+                    client.datagram = Some(DatagramClient::connect(&addr).await?);
+                }
+                client.cache = cache.map(LookupCache::new).transpose()?.map(Arc::new);
+                Ok(Locator(LocatorInner::Url(client)))
+            }
+            LocatorType::Sharded { shards, api_key } => {
+                let urls = match shards {
+                    ShardTopology::Urls(urls) => urls,
+                    ShardTopology::DiscoveryUrl(url) => {
+                        HttpClient::new(
+                            url,
+                            api_key.clone(),
+                            config.caller.clone(),
+                            config.data_type,
+                        )
+                        .discover_shards()
+                        .await?
+                    }
+                };
+                if urls.is_empty() {
+                    return Err(ClientError::InvalidShards("no shard urls".into()));
+                }
+
+                let shards = urls
+                    .into_iter()
+                    .map(|url| {
+                        HttpClient::new(
+                            url,
+                            api_key.clone(),
+                            config.caller.clone(),
+                            config.data_type,
+                        )
+                    })
+                    .collect();
+                Ok(Locator(LocatorInner::Sharded(
+                    Arc::new(shards),
+                    config.data_type,
+                )))
+            }
         }
     }
 
@@ -71,6 +174,155 @@ impl Locator {
         match &self.0 {
             LocatorInner::InProcess(l) => Ok(l.lookup(id, locality).await?),
             LocatorInner::Url(client) => Ok(client.lookup(id, locality).await?),
+            LocatorInner::Sharded(shards, data_type) => {
+                Ok(shard(shards, *data_type, id).lookup(id, locality).await?)
+            }
+        }
+    }
+
+    /// Returns the last known cell with its freshness, see `LocatorService::lookup_stale`.
+    pub async fn lookup_stale(
+        &self,
+        id: &str,
+        locality: Option<&str>,
+    ) -> Result<StaleLookup, ClientError> {
+        match &self.0 {
+            LocatorInner::InProcess(l) => Ok(l.lookup_stale(id, locality).await?),
+            LocatorInner::Url(client) => Ok(client.lookup_stale(id, locality).await?),
+            LocatorInner::Sharded(shards, data_type) => Ok(shard(shards, *data_type, id)
+                .lookup_stale(id, locality)
+                .await?),
+        }
+    }
+
+    /// Looks up the cells of many ids at once, see `LocatorService::lookup_many`. Sharded
+    /// locators are sent one batch per shard, in parallel.
+    pub async fn lookup_many(
+        &self,
+        ids: &[&str],
+        locality: Option<&str>,
+    ) -> Result<HashMap<String, String>, ClientError> {
+        match &self.0 {
+            LocatorInner::InProcess(l) => Ok(l.lookup_many(ids, locality).await?),
+            LocatorInner::Url(client) => Ok(client.lookup_many(ids, locality).await?),
+            LocatorInner::Sharded(shards, data_type) => {
+                lookup_sharded(
+                    shards,
+                    *data_type,
+                    ids,
+                    locality,
+                    |shard, batch, locality| async move {
+                        let batch: Vec<&str> = batch.iter().map(String::as_str).collect();
+                        shard.lookup_many(&batch, locality.as_deref()).await
+                    },
+                )
+                .await
+            }
+        }
+    }
+
+    /// Same as `lookup_many`, but returns every cell of the ids that span multiple cells,
+    /// see `LocatorService::lookup_many_multi`.
+    pub async fn lookup_many_multi(
+        &self,
+        ids: &[&str],
+        locality: Option<&str>,
+    ) -> Result<HashMap<String, Vec<CellAssignment>>, ClientError> {
+        match &self.0 {
+            LocatorInner::InProcess(l) => Ok(l.lookup_many_multi(ids, locality).await?),
+            LocatorInner::Url(client) => Ok(client.lookup_many_multi(ids, locality).await?),
+            LocatorInner::Sharded(shards, data_type) => {
+                lookup_sharded(
+                    shards,
+                    *data_type,
+                    ids,
+                    locality,
+                    |shard, batch, locality| async move {
+                        let batch: Vec<&str> = batch.iter().map(String::as_str).collect();
+                        shard.lookup_many_multi(&batch, locality.as_deref()).await
+                    },
+                )
+                .await
+            }
+        }
+    }
+
+    /// Returns the cell of the id with its locality and labels, see
+    /// `LocatorService::lookup_full`.
+    pub async fn lookup_full(&self, id: &str, locality: Option<&str>) -> Result<Cell, ClientError> {
+        match &self.0 {
+            LocatorInner::InProcess(l) => Ok(l.lookup_full(id, locality).await?),
+            LocatorInner::Url(client) => Ok(client.lookup_full(id, locality).await?),
+            LocatorInner::Sharded(shards, data_type) => Ok(shard(shards, *data_type, id)
+                .lookup_full(id, locality)
+                .await?),
+        }
+    }
+
+    /// Returns every cell the id is assigned to, see `LocatorService::lookup_multi`.
+    pub async fn lookup_multi(
+        &self,
+        id: &str,
+        locality: Option<&str>,
+    ) -> Result<Vec<CellAssignment>, ClientError> {
+        match &self.0 {
+            LocatorInner::InProcess(l) => Ok(l.lookup_multi(id, locality).await?),
+            LocatorInner::Url(client) => Ok(client.lookup_multi(id, locality).await?),
+            LocatorInner::Sharded(shards, data_type) => Ok(shard(shards, *data_type, id)
+                .lookup_multi(id, locality)
+                .await?),
+        }
+    }
+
+    /// Returns the registered cells, None if the locator has no cell catalog. Shards share
+    /// the catalog of the config, so the first shard is asked.
+    pub async fn cell_catalog(&self) -> Result<Option<Vec<CatalogCell>>, ClientError> {
+        match &self.0 {
+            LocatorInner::InProcess(l) => Ok(l.cell_catalog()),
+            LocatorInner::Url(client) => client.cell_catalog().await,
+            LocatorInner::Sharded(shards, _) => shards[0].cell_catalog().await,
+        }
+    }
+
+    /// Size and age of the mappings, see `LocatorService::stats`. Sharded locators return
+    /// the stats of every shard, by index.
+    pub async fn stats(&self) -> Result<Vec<LocatorStats>, ClientError> {
+        match &self.0 {
+            LocatorInner::InProcess(l) => Ok(vec![l.stats().await]),
+            LocatorInner::Url(client) => Ok(vec![client.stats().await?]),
+            LocatorInner::Sharded(shards, _) => {
+                let mut stats = Vec::with_capacity(shards.len());
+                for shard in shards.iter() {
+                    stats.push(shard.stats().await?);
+                }
+                Ok(stats)
+            }
+        }
+    }
+
+    /// Readiness of the locator with its reasons, see `LocatorService::readiness`. Sharded
+    /// locators have the status of their least ready shard, and the reasons of every shard.
+    pub async fn readiness(&self) -> Result<Readiness, ClientError> {
+        match &self.0 {
+            LocatorInner::InProcess(l) => Ok(l.readiness()),
+            LocatorInner::Url(client) => client.readiness().await,
+            LocatorInner::Sharded(shards, _) => {
+                let mut readiness = Readiness {
+                    status: ReadinessStatus::Ready,
+                    reasons: Vec::new(),
+                };
+                for (index, shard) in shards.iter().enumerate() {
+                    let shard = shard.readiness().await?;
+                    readiness.status = readiness.status.max(shard.status);
+                    readiness.reasons.extend(
+                        shard
+                            .reasons
+                            .into_iter()
+                            .map(|reason| format!("shard {index}: {reason}")),
+                    );
+                }
+                Ok(readiness)
+            }
         }
     }
 
@@ -78,6 +330,7 @@ impl Locator {
         match &self.0 {
             LocatorInner::InProcess(l) => l.is_ready(),
             LocatorInner::Url(client) => client.is_ready(),
+            LocatorInner::Sharded(..) => true,
         }
     }
 
@@ -85,6 +338,7 @@ impl Locator {
         match &self.0 {
             LocatorInner::InProcess(l) => l.shutdown().await,
             LocatorInner::Url(client) => client.shutdown(),
+            LocatorInner::Sharded(..) => {}
         }
     }
 }
@@ -93,6 +347,46 @@ impl Locator {
 enum LocatorInner {
     InProcess(LocatorService),
     Url(HttpClient),
+    Sharded(Arc<Vec<HttpClient>>, LocatorDataType),
+}
+
+/// Sends one batch per shard with `lookup`, in parallel, and merges the results.
+async fn lookup_sharded<T, F, Fut>(
+    shards: &[HttpClient],
+    data_type: LocatorDataType,
+    ids: &[&str],
+    locality: Option<&str>,
+    lookup: F,
+) -> Result<HashMap<String, T>, ClientError>
+where
+    F: Fn(HttpClient, Vec<String>, Option<String>) -> Fut,
+    Fut: Future<Output = Result<HashMap<String, T>, ClientError>> + Send + 'static,
+    T: Send + 'static,
+{
+    let mut batches = vec![Vec::new(); shards.len()];
+    for &id in ids {
+        batches[shard_of(&data_type.normalize_id(id), shards.len())].push(id.to_string());
+    }
+
+    let mut lookups = tokio::task::JoinSet::new();
+    for (shard, batch) in shards.iter().zip(batches) {
+        if batch.is_empty() {
+            continue;
+        }
+        lookups.spawn(lookup(shard.clone(), batch, locality.map(String::from)));
+    }
+
+    let mut cells = HashMap::with_capacity(ids.len());
+    while let Some(result) = lookups.join_next().await {
+        let result = result.map_err(|_| ClientError::LocatorError(LocatorError::InternalError))?;
+        cells.extend(result?);
+    }
+    Ok(cells)
+}
+
+/// The client of the key's shard. Keys are sharded by their normalized form.
+fn shard<'a>(shards: &'a [HttpClient], data_type: LocatorDataType, id: &str) -> &'a HttpClient {
+    &shards[shard_of(&data_type.normalize_id(id), shards.len())]
 }
 
 #[derive(serde::Deserialize)]
@@ -100,43 +394,268 @@ struct LocatorApiResponse {
     cell: String,
 }
 
+#[derive(serde::Deserialize)]
+struct CellsApiResponse {
+    cells: Vec<CellAssignment>,
+}
+
+#[derive(serde::Serialize)]
+struct BatchApiRequest<'a> {
+    ids: &'a [&'a str],
+    locality: Option<&'a str>,
+}
+
+#[derive(serde::Deserialize)]
+struct BatchApiResponse {
+    cells: HashMap<String, String>,
+    /// Left out by locators that predate multi-cell batches
+    #[serde(default)]
+    assignments: HashMap<String, Vec<CellAssignment>>,
+}
+
+#[derive(serde::Deserialize)]
+struct CatalogApiResponse {
+    cells: Vec<CatalogCell>,
+}
+
 #[derive(Clone)]
 struct HttpClient {
     client: reqwest::Client,
     url: String,
+    api_key: Option<String>,
+    caller: Option<String>,
+    // Ids are sent in their normalized form, such as the public key of a DSN
+    data_type: LocatorDataType,
+    // Plain lookups are sent to the datagram listener first if set
+    datagram: Option<DatagramClient>,
+    // Plain lookups are answered from the cache first if set
+    cache: Option<Arc<LookupCache>>,
 }
 
 impl HttpClient {
-    pub fn new(url: String) -> Self {
+    pub fn new(
+        url: String,
+        api_key: Option<String>,
+        caller: Option<String>,
+        data_type: LocatorDataType,
+    ) -> Self {
         HttpClient {
             client: reqwest::Client::new(),
             url,
+            api_key,
+            caller,
+            data_type,
+            datagram: None,
+            cache: None,
         }
     }
 
     async fn lookup(&self, id: &str, locality: Option<&str>) -> Result<String, ClientError> {
+        let id = &self.data_type.normalize_id(id);
+        match &self.cache {
+            Some(cache) => {
+                // The lookup is shared by concurrent lookups and outlives this one
+                let (client, owned_id, owned_locality) =
+                    (self.clone(), id.to_string(), locality.map(str::to_string));
+                cache
+                    .get_or_lookup(id, locality, || async move {
+                        client
+                            .lookup_uncached(&owned_id, owned_locality.as_deref())
+                            .await
+                    })
+                    .await
+            }
+            None => self.lookup_uncached(id, locality).await,
+        }
+    }
+
+    async fn lookup_uncached(
+        &self,
+        id: &str,
+        locality: Option<&str>,
+    ) -> Result<String, ClientError> {
+        if let Some(datagram) = &self.datagram {
+            match datagram.lookup(id, locality).await {
+                Some(result) => return Ok(result?),
+                None => metrics::counter!(DATAGRAM_FALLBACKS.name).increment(1),
+            }
+        }
+        let response = self.get(&self.url, id, locality, &[]).await?;
+        Ok(response.json::<LocatorApiResponse>().await?.cell)
+    }
+
+    async fn lookup_stale(
+        &self,
+        id: &str,
+        locality: Option<&str>,
+    ) -> Result<StaleLookup, ClientError> {
+        let id = &self.data_type.normalize_id(id);
+        let response = self
+            .get(&self.url, id, locality, &[("allow_stale", "true")])
+            .await?;
+        Ok(response.json::<StaleLookup>().await?)
+    }
+
+    async fn lookup_full(&self, id: &str, locality: Option<&str>) -> Result<Cell, ClientError> {
+        let url = format!("{}/lookup_full", self.url.trim_end_matches('/'));
+        let id = &self.data_type.normalize_id(id);
+        let response = self.get(&url, id, locality, &[]).await?;
+        Ok(response.json::<Cell>().await?)
+    }
+
+    async fn lookup_multi(
+        &self,
+        id: &str,
+        locality: Option<&str>,
+    ) -> Result<Vec<CellAssignment>, ClientError> {
+        let url = format!("{}/cells", self.url.trim_end_matches('/'));
+        let id = &self.data_type.normalize_id(id);
+        let response = self.get(&url, id, locality, &[]).await?;
+        Ok(response.json::<CellsApiResponse>().await?.cells)
+    }
+
+    async fn lookup_many(
+        &self,
+        ids: &[&str],
+        locality: Option<&str>,
+    ) -> Result<HashMap<String, String>, ClientError> {
+        let url = format!("{}/lookup_batch", self.url.trim_end_matches('/'));
+        let (keys, requested) = self.normalize_ids(ids);
+        let keys: Vec<&str> = keys.iter().map(String::as_str).collect();
+        let response = self
+            .request(Method::POST, url)
+            .json(&BatchApiRequest {
+                ids: &keys,
+                locality,
+            })
+            .send()
+            .await?;
+        let response = check_status(response).await?;
+        let cells = response.json::<BatchApiResponse>().await?.cells;
+        Ok(by_requested_id(cells, &requested))
+    }
+
+    async fn lookup_many_multi(
+        &self,
+        ids: &[&str],
+        locality: Option<&str>,
+    ) -> Result<HashMap<String, Vec<CellAssignment>>, ClientError> {
+        let url = format!("{}/lookup_batch", self.url.trim_end_matches('/'));
+        let (keys, requested) = self.normalize_ids(ids);
+        let keys: Vec<&str> = keys.iter().map(String::as_str).collect();
+        let response = self
+            .request(Method::POST, url)
+            .json(&BatchApiRequest {
+                ids: &keys,
+                locality,
+            })
+            .send()
+            .await?;
+        let response = check_status(response).await?;
+        let BatchApiResponse {
+            cells,
+            mut assignments,
+        } = response.json().await?;
+        let cells = cells
+            .into_iter()
+            .map(|(id, cell)| {
+                let cells = assignments
+                    .remove(&id)
+                    .unwrap_or_else(|| vec![CellAssignment::single(cell)]);
+                (id, cells)
+            })
+            .collect();
+        Ok(by_requested_id(cells, &requested))
+    }
+
+    /// The distinct normalized ids of a batch, and the ids each of them was requested as.
+    fn normalize_ids<'a>(&self, ids: &[&'a str]) -> (Vec<String>, HashMap<String, Vec<&'a str>>) {
+        let mut keys = Vec::with_capacity(ids.len());
+        let mut requested: HashMap<String, Vec<&str>> = HashMap::with_capacity(ids.len());
+        for &id in ids {
+            let key = self.data_type.normalize_id(id).into_owned();
+            let ids = requested.entry(key.clone()).or_default();
+            if ids.is_empty() {
+                keys.push(key);
+            }
+            ids.push(id);
+        }
+        (keys, requested)
+    }
+
+    /// Asks a sharded locator for the URLs of all shards.
+    async fn discover_shards(&self) -> Result<Vec<String>, ClientError> {
+        let url = format!("{}/shards", self.url.trim_end_matches('/'));
+        let response = self
+            .request(Method::GET, url)
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(response.json::<ShardInfo>().await?.urls)
+    }
+
+    async fn cell_catalog(&self) -> Result<Option<Vec<CatalogCell>>, ClientError> {
+        let url = format!("{}/catalog", self.url.trim_end_matches('/'));
+        let response = self.request(Method::GET, url).send().await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        let response = response.error_for_status()?;
+        Ok(Some(response.json::<CatalogApiResponse>().await?.cells))
+    }
+
+    async fn stats(&self) -> Result<LocatorStats, ClientError> {
+        let url = format!("{}/stats", self.url.trim_end_matches('/'));
+        let response = self.request(Method::GET, url).send().await?;
+        Ok(response.error_for_status()?.json::<LocatorStats>().await?)
+    }
+
+    /// Readiness of the remote locator, which answers 503 with the reasons when it is not
+    /// ready
+    async fn readiness(&self) -> Result<Readiness, ClientError> {
+        let url = format!("{}/readyz", self.url.trim_end_matches('/'));
+        let response = self.request(Method::GET, url).send().await?;
+        let response = match response.status() {
+            StatusCode::SERVICE_UNAVAILABLE => response,
+            _ => response.error_for_status()?,
+        };
+        Ok(response.json::<Readiness>().await?)
+    }
+
+    /// Request to `url` with the API key and caller name
+    fn request(&self, method: Method, url: impl reqwest::IntoUrl) -> reqwest::RequestBuilder {
+        let mut request = self.client.request(method, url);
+        if let Some(api_key) = &self.api_key {
+            request = request.bearer_auth(api_key);
+        }
+        if let Some(caller) = &self.caller {
+            request = request.header(CALLER_HEADER, caller);
+        }
+        request
+    }
+
+    /// Sends a lookup request, returns the response if it was successful.
+    async fn get(
+        &self,
+        url: &str,
+        id: &str,
+        locality: Option<&str>,
+        extra_params: &[(&'static str, &str)],
+    ) -> Result<reqwest::Response, ClientError> {
         let mut query_params = HashMap::new();
         query_params.insert("id", id);
 
         if let Some(loc) = locality {
             query_params.insert("locality", loc);
         }
+        query_params.extend(extra_params.iter().copied());
 
         let response = self
-            .client
-            .get(&self.url)
+            .request(Method::GET, url)
             .query(&query_params)
             .send()
             .await?;
-
-        match response.status() {
-            StatusCode::OK => Ok(response.json::<LocatorApiResponse>().await?.cell),
-            StatusCode::NOT_FOUND => Err(ClientError::LocatorError(LocatorError::NoCell)),
-            StatusCode::SERVICE_UNAVAILABLE => {
-                Err(ClientError::LocatorError(LocatorError::NotReady))
-            }
-            _ => Err(ClientError::LocatorError(LocatorError::InternalError)),
-        }
+        check_status(response).await
     }
 
     fn is_ready(&self) -> bool {
@@ -145,3 +664,71 @@ impl HttpClient {
 
     fn shutdown(&self) {}
 }
+
+#[derive(serde::Deserialize)]
+struct ApiErrorResponse {
+    #[serde(default)]
+    code: Option<String>,
+}
+
+/// Results of a batch keyed by the ids as passed, which may differ from the normalized ones
+fn by_requested_id<T: Clone>(
+    results: HashMap<String, T>,
+    requested: &HashMap<String, Vec<&str>>,
+) -> HashMap<String, T> {
+    let mut by_id = HashMap::with_capacity(results.len());
+    for (key, result) in results {
+        for id in requested.get(&key).into_iter().flatten() {
+            by_id.insert(id.to_string(), result.clone());
+        }
+    }
+    by_id
+}
+
+/// Returns the response of a lookup if it was successful, the locator error otherwise.
+async fn check_status(response: reqwest::Response) -> Result<reqwest::Response, ClientError> {
+    let error = match response.status() {
+        StatusCode::OK => return Ok(response),
+        StatusCode::NOT_FOUND => LocatorError::NoCell,
+        StatusCode::GONE => LocatorError::Deleted,
+        // Not ready and not yet synced share the status, told apart by the error code
+        StatusCode::SERVICE_UNAVAILABLE => match response.json::<ApiErrorResponse>().await {
+            Ok(body) if body.code.as_deref() == Some(LocatorError::NotYetSynced.code()) => {
+                LocatorError::NotYetSynced
+            }
+            _ => LocatorError::NotReady,
+        },
+        StatusCode::MISDIRECTED_REQUEST => LocatorError::WrongShard,
+        StatusCode::BAD_REQUEST => LocatorError::LocalityNotServed,
+        _ => LocatorError::InternalError,
+    };
+    Err(ClientError::LocatorError(error))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize_ids() {
+        let key = "e12d836b15bb49d7bbf99e64295d995b";
+        let dsn = format!("https://{}@o1.ingest.sentry.io/42", key.to_uppercase());
+        let client = HttpClient::new(
+            "http://locator".into(),
+            None,
+            None,
+            LocatorDataType::ProjectKey,
+        );
+
+        // Only the public key is sent, once
+        let (keys, requested) = client.normalize_ids(&[key, &dsn]);
+        assert_eq!(keys, vec![key.to_string()]);
+
+        // Results are returned for every id as passed
+        let cells = HashMap::from([(key.to_string(), "us1".to_string())]);
+        let cells = by_requested_id(cells, &requested);
+        assert_eq!(cells.len(), 2);
+        assert_eq!(cells[key], "us1");
+        assert_eq!(cells[&dsn], "us1");
+    }
+}
@@ -0,0 +1,272 @@
+//! Cache of the lookups of remote locator clients, see `LocatorType::Url`.
+//!
+//! Successful lookups are cached for `ttl_secs`, failed lookups are not cached. When a key
+//! misses the cache, concurrent lookups of the key wait for the first one rather than all
+//! being sent to the locator, and all of them get its result, including its error. The
+//! lookup runs in its own task, so that it completes and is cleaned up even if the lookups
+//! waiting for it are cancelled.
+use crate::client::ClientError;
+use crate::clock::{Clock, SystemClock};
+use crate::config::ClientCache as ClientCacheConfig;
+use crate::metrics_defs::{CLIENT_CACHE_HIT, CLIENT_CACHE_MISS};
+use moka::policy::EvictionPolicy;
+use moka::sync::Cache;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+
+// Key and locality of a lookup
+type CacheKey = (String, Option<String>);
+
+// Result of a lookup, shared by the lookups waiting for it
+type SharedResult = Result<String, Arc<ClientError>>;
+
+pub struct LookupCache {
+    // Cell of every key with its expiry time. Expiry is checked against the clock rather
+    // than left to the cache. Once full, the least recently used keys are evicted.
+    cache: Cache<CacheKey, (Instant, String)>,
+    ttl: Duration,
+    clock: Arc<dyn Clock>,
+    // Result of every key being looked up, set once the lookup completes
+    in_flight: Arc<Mutex<HashMap<CacheKey, watch::Receiver<Option<SharedResult>>>>>,
+}
+
+impl LookupCache {
+    pub fn new(config: ClientCacheConfig) -> Result<Self, ClientError> {
+        Self::with_clock(config, Arc::new(SystemClock))
+    }
+
+    fn with_clock(config: ClientCacheConfig, clock: Arc<dyn Clock>) -> Result<Self, ClientError> {
+        if config.ttl_secs == 0 || config.max_entries == 0 {
+            return Err(ClientError::InvalidConfig(
+                "cache ttl_secs and max_entries must be greater than 0".into(),
+            ));
+        }
+        Ok(LookupCache {
+            cache: Cache::builder()
+                .max_capacity(config.max_entries)
+                .eviction_policy(EvictionPolicy::lru())
+                .build(),
+            ttl: Duration::from_secs(config.ttl_secs),
+            clock,
+            in_flight: Arc::default(),
+        })
+    }
+
+    /// The cached cell of the key, looked up with `lookup` and cached if missing.
+    pub async fn get_or_lookup<F, Fut>(
+        &self,
+        id: &str,
+        locality: Option<&str>,
+        lookup: F,
+    ) -> Result<String, ClientError>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<String, ClientError>> + Send + 'static,
+    {
+        let key = (id.to_string(), locality.map(str::to_string));
+        if let Some(cell) = self.get(&key) {
+            metrics::counter!(CLIENT_CACHE_HIT.name).increment(1);
+            return Ok(cell);
+        }
+
+        let mut result = {
+            let mut in_flight = self.in_flight.lock().unwrap_or_else(|e| e.into_inner());
+            match in_flight.get(&key) {
+                Some(result) => {
+                    metrics::counter!(CLIENT_CACHE_HIT.name).increment(1);
+                    result.clone()
+                }
+                None => {
+                    // Looked up since the cache was read, the entry is removed after the
+                    // cell was cached
+                    if let Some(cell) = self.get(&key) {
+                        metrics::counter!(CLIENT_CACHE_HIT.name).increment(1);
+                        return Ok(cell);
+                    }
+                    metrics::counter!(CLIENT_CACHE_MISS.name).increment(1);
+                    let (sender, result) = watch::channel(None);
+                    in_flight.insert(key.clone(), result.clone());
+                    tokio::spawn(self.complete(key, lookup(), sender));
+                    result
+                }
+            }
+        };
+
+        let result = match result.wait_for(Option::is_some).await {
+            Ok(result) => result.clone(),
+            // The lookup task panicked
+            Err(_) => None,
+        };
+        match result {
+            Some(Ok(cell)) => Ok(cell),
+            Some(Err(e)) => Err(ClientError::shared(&e)),
+            None => Err(ClientError::IoError(std::io::Error::other(
+                "lookup task failed",
+            ))),
+        }
+    }
+
+    /// Caches the result of the lookup and hands it to the lookups waiting for it.
+    fn complete<Fut>(
+        &self,
+        key: CacheKey,
+        lookup: Fut,
+        sender: watch::Sender<Option<SharedResult>>,
+    ) -> impl Future<Output = ()> + Send + 'static
+    where
+        Fut: Future<Output = Result<String, ClientError>> + Send + 'static,
+    {
+        let cache = self.cache.clone();
+        let ttl = self.ttl;
+        let clock = self.clock.clone();
+        let in_flight = self.in_flight.clone();
+        async move {
+            let result = lookup.await.map_err(Arc::new);
+            if let Ok(cell) = &result {
+                cache.insert(key.clone(), (clock.now() + ttl, cell.clone()));
+            }
+            in_flight
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .remove(&key);
+            let _ = sender.send(Some(result));
+        }
+    }
+
+    fn get(&self, key: &CacheKey) -> Option<String> {
+        match self.cache.get(key) {
+            Some((expires_at, cell)) if self.clock.now() < expires_at => Some(cell),
+            Some(_) => {
+                self.cache.invalidate(key);
+                None
+            }
+            None => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+    use crate::locator::LocatorError;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn cache(clock: Arc<MockClock>) -> LookupCache {
+        let config = ClientCacheConfig {
+            ttl_secs: 30,
+            max_entries: 100,
+        };
+        LookupCache::with_clock(config, clock).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_get_or_lookup() {
+        let clock = Arc::new(MockClock::new(0));
+        let cache = cache(clock.clone());
+        let lookups = Arc::new(AtomicUsize::new(0));
+        let lookup = || {
+            let lookups = lookups.clone();
+            async move {
+                lookups.fetch_add(1, Ordering::SeqCst);
+                tokio::task::yield_now().await;
+                Ok("us1".to_string())
+            }
+        };
+
+        // Concurrent misses are looked up once
+        let results = tokio::join!(
+            cache.get_or_lookup("org", None, lookup),
+            cache.get_or_lookup("org", None, lookup),
+            cache.get_or_lookup("org", None, lookup),
+        );
+        assert_eq!(results.0.unwrap(), "us1");
+        assert_eq!(results.1.unwrap(), "us1");
+        assert_eq!(results.2.unwrap(), "us1");
+        assert_eq!(lookups.load(Ordering::SeqCst), 1);
+        assert!(cache.in_flight.lock().unwrap().is_empty());
+
+        // Keys are cached by locality
+        cache
+            .get_or_lookup("org", Some("de"), lookup)
+            .await
+            .unwrap();
+        assert_eq!(lookups.load(Ordering::SeqCst), 2);
+
+        // Expired keys are looked up again
+        clock.advance(Duration::from_secs(29));
+        cache.get_or_lookup("org", None, lookup).await.unwrap();
+        assert_eq!(lookups.load(Ordering::SeqCst), 2);
+        clock.advance(Duration::from_secs(1));
+        cache.get_or_lookup("org", None, lookup).await.unwrap();
+        assert_eq!(lookups.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn test_failed_lookups() {
+        let cache = cache(Arc::new(MockClock::new(0)));
+        let lookups = Arc::new(AtomicUsize::new(0));
+        let failed = || {
+            let lookups = lookups.clone();
+            async move {
+                lookups.fetch_add(1, Ordering::SeqCst);
+                tokio::task::yield_now().await;
+                Err(ClientError::LocatorError(LocatorError::NoCell))
+            }
+        };
+
+        // Concurrent lookups share the error
+        let results = tokio::join!(
+            cache.get_or_lookup("org", None, failed),
+            cache.get_or_lookup("org", None, failed),
+        );
+        for result in [results.0, results.1] {
+            assert!(matches!(
+                result,
+                Err(ClientError::LocatorError(LocatorError::NoCell))
+            ));
+        }
+        assert_eq!(lookups.load(Ordering::SeqCst), 1);
+
+        // Failures are not cached
+        assert!(cache.get_or_lookup("org", None, failed).await.is_err());
+        assert_eq!(lookups.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_lookups() {
+        let cache = cache(Arc::new(MockClock::new(0)));
+        let (release, released) = tokio::sync::oneshot::channel::<()>();
+        let lookup = || async move {
+            released.await.unwrap();
+            Ok("us1".to_string())
+        };
+
+        // The lookup outlives the cancelled request, and is cleaned up once it completes
+        let cancelled = tokio::time::timeout(
+            Duration::from_millis(10),
+            cache.get_or_lookup("org", None, lookup),
+        )
+        .await;
+        assert!(cancelled.is_err());
+        assert_eq!(cache.in_flight.lock().unwrap().len(), 1);
+
+        let waiting = cache.get_or_lookup("org", None, || async { unreachable!() });
+        release.send(()).unwrap();
+        assert_eq!(waiting.await.unwrap(), "us1");
+        assert!(cache.in_flight.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_invalid_config() {
+        for (ttl_secs, max_entries) in [(0, 100), (30, 0)] {
+            let config = ClientCacheConfig {
+                ttl_secs,
+                max_entries,
+            };
+            assert!(LookupCache::new(config).is_err());
+        }
+    }
+}
@@ -0,0 +1,87 @@
+//! Time source of the locator.
+//!
+//! Refresh intervals, staleness and negative cache TTLs are all measured with a `Clock`, so
+//! that tests can control time with a `MockClock` instead of sleeping. The refresh loop
+//! itself is driven by tokio's timer, which tests can pause with `tokio::time::pause`.
+use std::fmt::Debug;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+pub trait Clock: Debug + Send + Sync {
+    /// Monotonic time, for measuring intervals
+    fn now(&self) -> Instant;
+
+    /// Wall clock time as a unix timestamp in seconds, for timestamps that are stored
+    fn unix_now(&self) -> u64;
+}
+
+/// Reads tokio's clock, which follows the system clock unless paused.
+#[derive(Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        tokio::time::Instant::now().into_std()
+    }
+
+    fn unix_now(&self) -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs()
+    }
+}
+
+/// Clock that only moves when advanced.
+#[derive(Debug)]
+pub struct MockClock {
+    start: Instant,
+    unix_start: u64,
+    elapsed: Mutex<Duration>,
+}
+
+impl MockClock {
+    /// Starts at the given unix timestamp
+    pub fn new(unix_start: u64) -> Self {
+        MockClock {
+            start: Instant::now(),
+            unix_start,
+            elapsed: Mutex::new(Duration::ZERO),
+        }
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        *self.elapsed.lock().unwrap_or_else(|e| e.into_inner()) += duration;
+    }
+
+    fn elapsed(&self) -> Duration {
+        *self.elapsed.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        self.start + self.elapsed()
+    }
+
+    fn unix_now(&self) -> u64 {
+        self.unix_start + self.elapsed().as_secs()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mock_clock() {
+        let clock = MockClock::new(1000);
+        let start = clock.now();
+        assert_eq!(clock.now(), start);
+        assert_eq!(clock.unix_now(), 1000);
+
+        clock.advance(Duration::from_secs(90));
+        assert_eq!(clock.now() - start, Duration::from_secs(90));
+        assert_eq!(clock.unix_now(), 1090);
+    }
+}
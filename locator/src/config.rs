@@ -1,5 +1,9 @@
+use crate::project_key;
+use crate::types::CatalogCell;
 use serde::Deserialize;
+use std::borrow::Cow;
 use std::collections::HashMap;
+use std::path::PathBuf;
 
 // TODO: This configuration is temporary: once these options are tested, we
 // should choose the best one for use globally.
@@ -16,6 +20,10 @@ fn default_base_dir() -> String {
     "/tmp/synapse-cache".into()
 }
 
+fn default_lease_ttl_secs() -> u64 {
+    900
+}
+
 #[derive(Clone, Deserialize, Debug, PartialEq)]
 #[serde(rename_all = "snake_case")]
 #[serde(tag = "type")]
@@ -29,12 +37,124 @@ pub enum BackupRouteStoreType {
     Gcs {
         bucket: String,
         compression: Compression,
+        /// How long a replica holds the backup write lease without renewing it.
+        /// Must be longer than the backup interval of 5 minutes plus the refresh interval
+        /// of 1 minute.
+        #[serde(default = "default_lease_ttl_secs")]
+        lease_ttl_secs: u64,
     },
 }
 
+impl BackupRouteStoreType {
+    pub fn validate(&self) -> Result<(), String> {
+        if let BackupRouteStoreType::Gcs { lease_ttl_secs, .. } = self {
+            // The lease is renewed with the first refresh after the backup interval
+            let renewal = crate::locator::BACKUP_INTERVAL + crate::locator::REFRESH_INTERVAL;
+            if *lease_ttl_secs <= renewal.as_secs() {
+                return Err(format!(
+                    "lease_ttl_secs must be greater than {} seconds, the longest time between backups",
+                    renewal.as_secs()
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
 #[derive(Clone, Deserialize, Debug, PartialEq)]
 pub struct ControlPlane {
     pub url: String,
+    #[serde(default)]
+    pub retry: RetryPolicy,
+    #[serde(default)]
+    pub pagination: Pagination,
+    /// Secret the requests are signed with, see `signing`. Read from the
+    /// `SYNAPSE_HMAC_SECRET` environment variable if not set.
+    #[serde(default)]
+    pub signing: Option<Signing>,
+}
+
+/// Where the secret of the HMAC signatures of control plane requests is read from
+#[derive(Clone, Deserialize, Debug, PartialEq)]
+#[serde(default)]
+pub struct Signing {
+    /// File holding the secret, e.g. a mounted secret. Trailing newlines are ignored.
+    /// The environment variable is used if not set.
+    pub key_file: Option<PathBuf>,
+    /// Environment variable holding the secret. Default: SYNAPSE_HMAC_SECRET
+    pub key_env: String,
+    /// Seconds after which the key file is read again, so that it can be rotated without a
+    /// restart. The environment variable is only read at startup. Default: 60
+    pub reload_interval_secs: u64,
+}
+
+impl Default for Signing {
+    fn default() -> Self {
+        Signing {
+            key_file: None,
+            key_env: "SYNAPSE_HMAC_SECRET".to_string(),
+            reload_interval_secs: 60,
+        }
+    }
+}
+
+/// Paging of full snapshots from the control plane. With more than one parallel page
+/// fetch, the snapshot is split into ranges of update times following the cursor of the
+/// first page, which are fetched concurrently and merged in order. Incremental syncs are
+/// always fetched serially.
+#[derive(Clone, Deserialize, Debug, PartialEq)]
+#[serde(default)]
+pub struct Pagination {
+    /// Rows requested per page, at most 10000. The control plane default is used if unset.
+    pub page_size: Option<u32>,
+    /// Page fetches in flight while loading a snapshot, at most 16. Default: 1
+    pub parallel_pages: usize,
+    /// Seconds of update times covered by each range of a parallel snapshot. Default: 86400
+    pub range_secs: u64,
+    /// Bytes of fetched pages waiting to be merged, above which only the range being
+    /// merged fetches further pages. Default: 64 MiB
+    pub max_buffered_bytes: usize,
+}
+
+impl Default for Pagination {
+    fn default() -> Self {
+        Pagination {
+            page_size: None,
+            parallel_pages: 1,
+            range_secs: 86400,
+            max_buffered_bytes: 64 * 1024 * 1024,
+        }
+    }
+}
+
+/// Retry behavior for control plane requests. Retries apply to each page fetch
+/// individually, and are attempted on connection errors, timeouts and retriable
+/// status codes (429, 500, 502, 503, 504).
+#[derive(Clone, Deserialize, Debug, PartialEq)]
+#[serde(default)]
+pub struct RetryPolicy {
+    /// Number of retries per page fetch. Default: 3
+    pub max_retries: u32,
+    /// Delay before the first retry, doubled with each subsequent retry. Default: 500ms
+    pub base_backoff_ms: u64,
+    /// Upper bound for the delay between retries. Default: 10000ms
+    pub max_backoff_ms: u64,
+    /// Fraction of each delay that is randomized, between 0.0 and 1.0. Default: 0.0
+    pub jitter: f64,
+    /// Timeout for an individual control plane request. Default: 30 seconds
+    pub request_timeout_secs: u64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_retries: 3,
+            base_backoff_ms: 500,
+            max_backoff_ms: 10_000,
+            jitter: 0.0,
+            request_timeout_secs: 30,
+        }
+    }
 }
 
 #[derive(Clone, Deserialize, Debug, PartialEq)]
@@ -58,13 +178,28 @@ impl Default for Listener {
     }
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Clone, Copy, Deserialize, Debug, PartialEq)]
 #[serde(rename_all = "snake_case")]
 pub enum LocatorDataType {
     Organization,
+    /// Public keys of projects. Lookups also accept full DSNs, see `project_key`.
     ProjectKey,
 }
 
+impl LocatorDataType {
+    /// The id under which the control plane maps the looked up id
+    pub fn normalize_id(self, id: &str) -> Cow<'_, str> {
+        match self {
+            LocatorDataType::Organization => Cow::Borrowed(id),
+            LocatorDataType::ProjectKey => project_key::normalize(id),
+        }
+    }
+}
+
+fn default_tombstone_retention_secs() -> u64 {
+    30 * 24 * 3600
+}
+
 #[derive(Deserialize, Debug)]
 pub struct Config {
     #[serde(default)]
@@ -74,4 +209,163 @@ pub struct Config {
     pub localities: Option<Vec<String>>,
     pub locality_to_default_cell: Option<HashMap<String, String>>,
     pub data_type: LocatorDataType,
+    /// Require one of these keys on every API request. The API is open if not set.
+    pub api_keys: Option<Vec<ApiKey>>,
+    pub warm_cache: Option<WarmCache>,
+    #[serde(default)]
+    pub negative_cache: NegativeCache,
+    /// Seconds tombstones of deleted ids are kept after the locator first saw them. Their
+    /// ids are unknown afterwards. Default: 2592000 (30 days)
+    #[serde(default = "default_tombstone_retention_secs")]
+    pub tombstone_retention_secs: u64,
+    /// Relative capacity of cells, used by the rebalancing report. Cells that are not
+    /// listed have a weight of 1.
+    #[serde(default)]
+    pub capacity_weights: HashMap<String, u32>,
+    /// Only keep the keys of this shard, for keyspaces sharded across locators.
+    pub shard: Option<Shard>,
+    /// Serve lookups from the backup route store only, for disaster recovery regions where
+    /// the control plane is unreachable by design. The control plane is never contacted,
+    /// the backup is never written and the API rejects writes.
+    #[serde(default)]
+    pub read_only: bool,
+    /// Webhook notified of failing syncs, stale mappings and failing backup writes.
+    /// Disabled if not set.
+    pub alerts: Option<Alerts>,
+    /// Every cell of the deployment. Lookups resolving to other cells are reported, and
+    /// the list is served by `/catalog`. Can be replaced through the API.
+    pub cells: Option<Vec<CatalogCell>>,
+    /// Keys allowed to replace the cell catalog with `PUT /catalog`, which is not served
+    /// if not set. The keys of `api_keys` cannot replace it.
+    pub catalog_write_keys: Option<Vec<ApiKey>>,
+    /// Answers lookups sent as UDP datagrams by callers on the same host, see `datagram`.
+    /// Must bind to a loopback address, and cannot be set along with `api_keys`. Disabled
+    /// if not set.
+    pub datagram_listener: Option<Listener>,
+    /// What lookups do when the loader's refresh queue is full. Default: coalesce
+    #[serde(default)]
+    pub refresh_overflow: RefreshOverflow,
+    /// Seconds given to open connections to finish on SIGTERM or Ctrl-C. Default: 30
+    #[serde(default = "default_drain_timeout_secs")]
+    pub drain_timeout_secs: u64,
+}
+
+fn default_drain_timeout_secs() -> u64 {
+    30
+}
+
+fn default_sync_failure_threshold() -> u32 {
+    3
+}
+
+fn default_max_staleness_secs() -> u64 {
+    600
+}
+
+fn default_repeat_interval_secs() -> u64 {
+    3600
+}
+
+/// Alerts posted to a webhook
+#[derive(Clone, Deserialize, Debug, PartialEq)]
+pub struct Alerts {
+    /// URL the notifications are posted to as JSON
+    pub webhook_url: String,
+    /// Number of consecutive failed control plane syncs before alerting. Default: 3
+    #[serde(default = "default_sync_failure_threshold")]
+    pub sync_failure_threshold: u32,
+    /// Alert when the mappings were not synced from the control plane for longer than
+    /// this. Default: 600 seconds
+    #[serde(default = "default_max_staleness_secs")]
+    pub max_staleness_secs: u64,
+    /// Time after which an ongoing problem is notified again. Default: 3600 seconds
+    #[serde(default = "default_repeat_interval_secs")]
+    pub repeat_interval_secs: u64,
+}
+
+/// This locator's shard of a keyspace sharded across locators by key hash
+#[derive(Clone, Deserialize, Debug, PartialEq)]
+pub struct Shard {
+    /// Index of this locator in `urls`
+    pub index: usize,
+    /// Locator URL of every shard, served to clients discovering the topology. The number
+    /// of shards is the number of URLs.
+    pub urls: Vec<String>,
+}
+
+/// Behavior of lookups that miss while the queue of refresh requests to the loader is
+/// full, e.g. during a burst of unknown ids
+#[derive(Clone, Copy, Default, Deserialize, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+#[serde(tag = "mode")]
+pub enum RefreshOverflow {
+    /// Wait for the next refresh without queueing another one
+    #[default]
+    Coalesce,
+    /// Wait up to `timeout_ms` for room in the queue, then answer without refreshing
+    Block { timeout_ms: u64 },
+    /// Answer without refreshing, as if the id was not found
+    Drop,
+}
+
+/// Cache of ids that were not found after a refresh, which are not refreshed again until
+/// they expire
+#[derive(Clone, Deserialize, Debug, PartialEq)]
+#[serde(default)]
+pub struct NegativeCache {
+    /// Seconds until a cached id is looked up in the control plane again. Default: 5
+    pub ttl_secs: u64,
+    /// Number of ids kept, the least recently used are evicted first. Default: 1000
+    pub max_entries: u64,
+}
+
+impl Default for NegativeCache {
+    fn default() -> Self {
+        NegativeCache {
+            ttl_secs: 5,
+            max_entries: 1000,
+        }
+    }
+}
+
+/// Cache of the lookups of a client of a remote locator
+#[derive(Clone, Deserialize, Debug, PartialEq)]
+#[serde(default)]
+pub struct ClientCache {
+    /// Seconds a looked up cell is cached for. Default: 30
+    pub ttl_secs: u64,
+    /// Number of ids kept, the least recently used are evicted first. Default: 10000
+    pub max_entries: u64,
+}
+
+impl Default for ClientCache {
+    fn default() -> Self {
+        ClientCache {
+            ttl_secs: 30,
+            max_entries: 10_000,
+        }
+    }
+}
+
+fn default_max_hot_ids() -> u64 {
+    10_000
+}
+
+/// Lookup caches persisted across restarts
+#[derive(Clone, Deserialize, Debug, PartialEq)]
+pub struct WarmCache {
+    /// File the caches are written to on shutdown and restored from on startup
+    pub path: String,
+    /// Number of recently looked up ids to keep. Default: 10000
+    #[serde(default = "default_max_hot_ids")]
+    pub max_hot_ids: u64,
+}
+
+#[derive(Clone, Deserialize, Debug, PartialEq)]
+pub struct ApiKey {
+    /// Name of the caller, used to attribute requests in metrics
+    pub caller: String,
+    pub key: String,
+    /// Quota of the caller, unlimited if not set
+    pub requests_per_second: Option<u32>,
 }
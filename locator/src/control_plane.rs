@@ -1,18 +1,27 @@
-const BASE_DELAY: u64 = 500;
 const AUTH_SCHEME: &str = "Signature";
 const HMAC_SIGNATURE_PREFIX: &str = "synapse0";
 
-use crate::config::LocatorDataType;
-use crate::metrics_defs::{CONTROL_PLANE_SYNC_DURATION, CONTROL_PLANE_SYNC_ROWS};
-use crate::types::{CellId, RouteData};
+use crate::config::{ControlPlane as ControlPlaneConfig, LocatorDataType, RetryPolicy, Shard};
+use crate::cursor::Cursor;
+use crate::metrics_defs::{
+    CONTROL_PLANE_RETRIES_EXHAUSTED, CONTROL_PLANE_SYNC_DURATION, CONTROL_PLANE_SYNC_ROWS,
+};
+use crate::signing::SigningKey;
+use crate::types::{CellAssignment, CellId, RouteData};
 use hmac::{Hmac, Mac};
 use reqwest::{StatusCode, Url};
 use serde::Deserialize;
 use sha2::Sha256;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::sync::Arc;
 use std::time::Instant;
+use tokio::sync::{mpsc, watch};
+use tokio::task::JoinHandle;
 use tokio::time::{Duration, sleep};
 
+const MAX_PAGE_SIZE: u32 = 10_000;
+const MAX_PARALLEL_PAGES: usize = 16;
+
 #[derive(Deserialize)]
 #[serde(untagged)]
 enum ControlPlaneRecord {
@@ -20,10 +29,20 @@ enum ControlPlaneRecord {
         id: String,
         slug: String,
         cell: CellId,
+        // Set if the org spans multiple cells
+        #[serde(default)]
+        cells: Vec<CellAssignment>,
+        // Tombstone of a deleted org
+        #[serde(default)]
+        deleted: bool,
     },
     ProjectKey {
         publickey: String,
         cell: CellId,
+        #[serde(default)]
+        cells: Vec<CellAssignment>,
+        #[serde(default)]
+        deleted: bool,
     },
 }
 
@@ -32,12 +51,18 @@ struct ControlPlaneMetadata {
     cursor: Option<String>,
     has_more: bool,
     cell_to_locality: HashMap<String, String>,
+    // Attributes of the cells, returned by lookups of their ids
+    #[serde(default)]
+    cell_labels: HashMap<CellId, BTreeMap<String, String>>,
 }
 
 #[derive(Deserialize)]
 struct ControlPlaneData {
     data: Vec<ControlPlaneRecord>,
     metadata: ControlPlaneMetadata,
+    // Size of the response body
+    #[serde(skip)]
+    bytes: usize,
 }
 
 #[derive(thiserror::Error, Debug)]
@@ -48,16 +73,55 @@ pub enum ControlPlaneError {
     InvalidUrl(String),
     #[error("control plane returned unsuccessful status: {0}")]
     ControlPlaneStatus(StatusCode),
+    #[error("invalid response: {0}")]
+    InvalidResponse(#[from] serde_json::Error),
     #[error("missing cursor in response")]
     MissingCursor,
+    #[error("page fetch failed: {0}")]
+    PageFetch(#[from] tokio::task::JoinError),
+}
+
+impl ControlPlaneError {
+    fn is_retriable(&self) -> bool {
+        const RETRIABLE_STATUS_CODES: &[StatusCode] = &[
+            StatusCode::TOO_MANY_REQUESTS,     // 429
+            StatusCode::INTERNAL_SERVER_ERROR, // 500
+            StatusCode::BAD_GATEWAY,           // 502
+            StatusCode::SERVICE_UNAVAILABLE,   // 503
+            StatusCode::GATEWAY_TIMEOUT,       // 504
+        ];
+
+        match self {
+            ControlPlaneError::ReqwestError(e) => e.is_timeout() || e.is_connect(),
+            ControlPlaneError::ControlPlaneStatus(status) => {
+                RETRIABLE_STATUS_CODES.contains(status)
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Delay before the given retry (0-indexed): exponential backoff capped at
+/// `max_backoff_ms`, with up to `jitter` of the delay randomized away.
+fn retry_delay(policy: &RetryPolicy, retry: u32) -> Duration {
+    let backoff = policy
+        .base_backoff_ms
+        .saturating_mul(2_u64.saturating_pow(retry))
+        .min(policy.max_backoff_ms);
+
+    let jitter = policy.jitter.clamp(0.0, 1.0);
+    let factor = 1.0 - jitter * rand::random::<f64>();
+
+    Duration::from_millis((backoff as f64 * factor) as u64)
 }
 
 /// Control plane client for syncing route mappings from Sentry's control silo.
 ///
 /// # HMAC Authentication
 ///
-/// The `SYNAPSE_HMAC_SECRET` environment variable should contain a raw string secret
-/// that will be used as the key for HMAC-SHA256 authentication.
+/// The secret used as the key for HMAC-SHA256 authentication is read as configured in
+/// `signing`, by default from the `SYNAPSE_HMAC_SECRET` environment variable. A key file
+/// is read again periodically so that it can be rotated, see `signing`.
 ///
 /// The signature will be included in HTTP requests to the control plane in the `Authorization` header:
 ///
@@ -65,22 +129,30 @@ pub enum ControlPlaneError {
 /// Authorization: Signature synapse0:<hex-encoded-hmac-sha256-signature>
 /// ```
 ///
-/// The signature is computed as HMAC-SHA256 of path:body, signed with the secret
-/// key from `SYNAPSE_HMAC_SECRET`. For GET requests (as used here), the body is empty bytes.
+/// The signature is computed as HMAC-SHA256 of path:body, signed with the secret.
+/// For GET requests (as used here), the body is empty bytes.
 ///
-/// If `SYNAPSE_HMAC_SECRET` is not set, HMAC authentication will be disabled and a
-/// warning will be logged. The `Authorization` header will not be added to requests.
+/// If there is no secret, HMAC authentication will be disabled and a warning will be
+/// logged. The `Authorization` header will not be added to requests.
+#[derive(Clone)]
 pub struct ControlPlane {
     client: reqwest::Client,
     full_url: String,
     localities: Option<Vec<String>>,
-    hmac_secret: Option<String>,
+    signing_key: Arc<SigningKey>,
+    retry_policy: RetryPolicy,
+    page_size: Option<u32>,
+    parallel_pages: usize,
+    range_secs: u64,
+    max_buffered_bytes: usize,
+    /// Keys of other shards are skipped
+    shard: Option<Shard>,
 }
 
 impl ControlPlane {
     pub fn new(
         data_type: LocatorDataType,
-        base_url: String,
+        config: ControlPlaneConfig,
         localities: Option<Vec<String>>,
     ) -> Self {
         let path = match data_type {
@@ -88,21 +160,53 @@ impl ControlPlane {
             LocatorDataType::ProjectKey => "api/0/internal/projectkey-cell-mappings",
         };
 
-        let full_url = format!("{}/{}/", base_url.trim_end_matches('/'), path);
-
-        let hmac_secret = std::env::var("SYNAPSE_HMAC_SECRET").ok().or_else(|| {
-            tracing::warn!("SYNAPSE_HMAC_SECRET not set, HMAC authentication disabled");
-            None
-        });
+        let full_url = format!("{}/{}/", config.url.trim_end_matches('/'), path);
+
+        let signing_key = Arc::new(SigningKey::new(config.signing));
+
+        // Bounds the size of the pages held in memory
+        let pagination = config.pagination;
+        let page_size = pagination
+            .page_size
+            .map(|size| size.clamp(1, MAX_PAGE_SIZE));
+        let parallel_pages = pagination.parallel_pages.clamp(1, MAX_PARALLEL_PAGES);
+        let range_secs = pagination.range_secs.max(1);
+        if page_size != pagination.page_size
+            || parallel_pages != pagination.parallel_pages
+            || range_secs != pagination.range_secs
+        {
+            tracing::warn!(
+                page_size,
+                parallel_pages,
+                range_secs,
+                "Control plane pagination settings out of bounds, clamped"
+            );
+        }
 
         ControlPlane {
             client: reqwest::Client::new(),
             full_url,
             localities,
-            hmac_secret,
+            signing_key,
+            retry_policy: config.retry,
+            page_size,
+            parallel_pages,
+            range_secs,
+            max_buffered_bytes: pagination.max_buffered_bytes,
+            shard: None,
         }
     }
 
+    /// Only loads the keys of the shard
+    pub fn with_shard(mut self, shard: Option<Shard>) -> Self {
+        self.shard = shard;
+        self
+    }
+
+    fn owns(&self, key: &str) -> bool {
+        self.shard.as_ref().is_none_or(|shard| shard.owns(key))
+    }
+
     // A cursor is passed for incremental loading. No cursor means the full snapshot will be loaded.
     pub async fn load_mappings(
         &self,
@@ -130,6 +234,20 @@ impl ControlPlane {
         result
     }
 
+    /// Fetches and parses the first page of a snapshot only, to check that the control
+    /// plane is reachable and accepts this locator's requests.
+    pub async fn load_first_page(&self) -> Result<RouteData, ControlPlaneError> {
+        let mut pages = Pages::default();
+        let page = self.fetch_page(None).await?;
+        self.add_page(&mut pages, page)?;
+
+        let data = RouteData::from(pages.org_to_cell, pages.cursor, pages.cell_to_locality)
+            .with_multi_cell(pages.org_to_cells)
+            .with_deleted(pages.deleted)
+            .with_cell_labels(pages.cell_labels);
+        Ok(data)
+    }
+
     /// Computes HMAC-SHA256 signature for the given path and body.
     /// Returns the hex-encoded signature, using path:body format.
     fn compute_hmac_signature(secret: &str, path: &str, body: &[u8]) -> String {
@@ -149,102 +267,335 @@ impl ControlPlane {
         &self,
         cursor: Option<&str>,
     ) -> Result<RouteData, ControlPlaneError> {
-        const RETRIABLE_STATUS_CODES: &[StatusCode] = &[
-            StatusCode::TOO_MANY_REQUESTS,     // 429
-            StatusCode::INTERNAL_SERVER_ERROR, // 500
-            StatusCode::BAD_GATEWAY,           // 502
-            StatusCode::SERVICE_UNAVAILABLE,   // 503
-            StatusCode::GATEWAY_TIMEOUT,       // 504
-        ];
+        let mut pages = Pages {
+            cursor: cursor.map(String::from),
+            ..Default::default()
+        };
 
-        let mut cell_to_locality: HashMap<String, String> = HashMap::new();
-        let mut org_to_cell = HashMap::new();
-        let mut next_cursor: Option<String> = cursor.map(String::from);
-        let mut page_fetches = 0;
+        if cursor.is_none() && self.parallel_pages > 1 {
+            self.fetch_snapshot_parallel(&mut pages).await?;
+        } else {
+            self.fetch_pages(&mut pages).await?;
+        }
 
-        // 3 retries per page fetch
-        let mut retries = 0;
+        tracing::info!("Fetched {} pages from control plane", pages.count);
+
+        let data = RouteData::from(pages.org_to_cell, pages.cursor, pages.cell_to_locality)
+            .with_multi_cell(pages.org_to_cells)
+            .with_deleted(pages.deleted)
+            .with_cell_labels(pages.cell_labels);
 
+        Ok(data)
+    }
+
+    /// Fetches pages starting after the cursor of `pages`, until there are no more pages.
+    async fn fetch_pages(&self, pages: &mut Pages) -> Result<(), ControlPlaneError> {
         loop {
-            let mut url = Url::parse(&self.full_url)
-                .map_err(|e| ControlPlaneError::InvalidUrl(e.to_string()))?;
+            let page = self.fetch_page(pages.cursor.as_deref()).await?;
+            if !self.add_page(pages, page)? {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Fetches a snapshot as consecutive ranges of `range_secs` update times, starting at
+    /// the cursor of the first page. The ranges are fetched concurrently, and their pages
+    /// merged in order so that a row seen by two ranges keeps its latest value. Ranges are
+    /// started until one reaches the end of the rows, which also picks up the rows updated
+    /// while the snapshot is loading.
+    ///
+    /// Pages wait for their range to be merged in memory. Once they exceed
+    /// `max_buffered_bytes`, only the range being merged fetches further pages.
+    async fn fetch_snapshot_parallel(&self, pages: &mut Pages) -> Result<(), ControlPlaneError> {
+        let first = self.fetch_page(None).await?;
+        if !self.add_page(pages, first)? {
+            return Ok(());
+        }
 
-            if let Some(ref c) = next_cursor {
-                url.query_pairs_mut().append_pair("cursor", c);
+        let start = pages
+            .cursor
+            .as_deref()
+            .and_then(|cursor| cursor.parse::<Cursor>().ok())
+            .map(|cursor| cursor.updated_at);
+        let (Some(start), Some(first_cursor)) = (start, pages.cursor.clone()) else {
+            // A cursor of an unknown format cannot be split into ranges
+            return self.fetch_pages(pages).await;
+        };
+
+        let control_plane = Arc::new(self.clone());
+        let buffered = Arc::new(watch::Sender::new(Buffered::default()));
+        let mut ranges = VecDeque::new();
+        let mut next_range = 0;
+        for merged in 0.. {
+            while ranges.len() < self.parallel_pages {
+                // A cursor with an empty id starts before every row of its update time
+                let range_start = start.saturating_add(self.range_secs.saturating_mul(next_range));
+                let cursor = match next_range {
+                    0 => first_cursor.clone(),
+                    _ => Cursor {
+                        updated_at: range_start,
+                        id: String::new(),
+                    }
+                    .encode(),
+                };
+                let (pages_tx, received) = mpsc::unbounded_channel();
+                let task = tokio::spawn(control_plane.clone().fetch_range(
+                    next_range as usize,
+                    cursor,
+                    range_start.saturating_add(self.range_secs),
+                    pages_tx,
+                    buffered.clone(),
+                ));
+                ranges.push_back(RangeTask {
+                    task,
+                    pages: received,
+                });
+                next_range += 1;
             }
 
-            // Add locality query parameters if configured
-            if let Some(ref localities) = self.localities {
-                for locality in localities {
-                    url.query_pairs_mut().append_pair("locality", locality);
-                }
+            let mut range = ranges.pop_front().expect("ranges were started");
+            buffered.send_modify(|buffered| buffered.front = merged);
+            while let Some(page) = range.pages.recv().await {
+                let bytes = page.bytes;
+                self.add_page(pages, page)?;
+                buffered.send_modify(|buffered| buffered.bytes -= bytes);
             }
+            if (&mut range.task).await?? {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Fetches the pages of a range, from the cursor until a page ends at or after the
+    /// `end` update time. Returns whether the range reached the end of the rows.
+    async fn fetch_range(
+        self: Arc<Self>,
+        index: usize,
+        mut cursor: String,
+        end: u64,
+        pages_tx: mpsc::UnboundedSender<ControlPlaneData>,
+        buffered: Arc<watch::Sender<Buffered>>,
+    ) -> Result<bool, ControlPlaneError> {
+        let mut budget = buffered.subscribe();
+        loop {
+            // The range being merged always proceeds, so that the buffer drains
+            let _ = budget
+                .wait_for(|buffered| {
+                    buffered.front == index || buffered.bytes < self.max_buffered_bytes
+                })
+                .await;
+
+            let page = self.fetch_page(Some(&cursor)).await?;
+            buffered.send_modify(|buffered| buffered.bytes += page.bytes);
+            let next = page
+                .metadata
+                .cursor
+                .clone()
+                .filter(|_| page.metadata.has_more);
+            let has_more = page.metadata.has_more;
+            if pages_tx.send(page).is_err() {
+                return Ok(false);
+            }
+            let Some(next) = next else {
+                // Pages with more rows but no cursor fail the merge
+                return Ok(!has_more);
+            };
+            let reached_end = next
+                .parse::<Cursor>()
+                .is_ok_and(|cursor| cursor.updated_at >= end);
+            if reached_end {
+                return Ok(false);
+            }
+            cursor = next;
+        }
+    }
 
+    /// Fetches the page after the cursor, retrying failed requests
+    async fn fetch_page(
+        &self,
+        cursor: Option<&str>,
+    ) -> Result<ControlPlaneData, ControlPlaneError> {
+        let mut url =
+            Url::parse(&self.full_url).map_err(|e| ControlPlaneError::InvalidUrl(e.to_string()))?;
+
+        if let Some(c) = cursor {
+            url.query_pairs_mut().append_pair("cursor", c);
+        }
+
+        if let Some(page_size) = self.page_size {
+            url.query_pairs_mut()
+                .append_pair("per_page", &page_size.to_string());
+        }
+
+        // Add locality query parameters if configured
+        if let Some(ref localities) = self.localities {
+            for locality in localities {
+                url.query_pairs_mut().append_pair("locality", locality);
+            }
+        }
+
+        let mut retries = 0;
+
+        loop {
             // Build request with optional HMAC authentication
-            let mut request = self.client.get(url.clone());
+            let mut request = self
+                .client
+                .get(url.clone())
+                .timeout(Duration::from_secs(self.retry_policy.request_timeout_secs));
 
-            if let Some(secret) = &self.hmac_secret {
+            if let Some(secret) = self.signing_key.secret() {
                 // For GET requests, body is empty bytes
-                let signature = Self::compute_hmac_signature(secret, url.path(), &[]);
+                let signature = Self::compute_hmac_signature(&secret, url.path(), &[]);
                 let auth_header =
                     format!("{} {}:{}", AUTH_SCHEME, HMAC_SIGNATURE_PREFIX, signature);
                 request = request.header("Authorization", auth_header);
             }
 
-            let response = request.send().await?;
-
-            if !response.status().is_success() {
-                if RETRIABLE_STATUS_CODES.contains(&response.status()) && retries < 3 {
-                    // Backoff between retries
-                    let retry_millis = BASE_DELAY * 2_u64.pow(retries);
-                    sleep(Duration::from_millis(retry_millis)).await;
+            let result = match request.send().await {
+                Ok(response) if response.status().is_success() => Ok(response),
+                Ok(response) => Err(ControlPlaneError::ControlPlaneStatus(response.status())),
+                Err(e) => Err(ControlPlaneError::from(e)),
+            };
+
+            match result {
+                Ok(response) => {
+                    let body = response.bytes().await?;
+                    let mut page: ControlPlaneData = serde_json::from_slice(&body)?;
+                    page.bytes = body.len();
+                    return Ok(page);
+                }
+                Err(err) if err.is_retriable() && retries < self.retry_policy.max_retries => {
+                    tracing::warn!(error = %err, retries, "Control plane request failed, retrying");
+                    sleep(retry_delay(&self.retry_policy, retries)).await;
                     retries += 1;
-                    continue;
-                } else {
-                    return Err(ControlPlaneError::ControlPlaneStatus(response.status()));
+                }
+                Err(err) => {
+                    if err.is_retriable() {
+                        metrics::counter!(CONTROL_PLANE_RETRIES_EXHAUSTED.name).increment(1);
+                    }
+                    return Err(err);
                 }
             }
+        }
+    }
 
-            // Response successful, reset retries counter
-            retries = 0;
-
-            let json_response = response.json::<ControlPlaneData>().await?;
-
-            cell_to_locality.extend(json_response.metadata.cell_to_locality);
-
-            for row in json_response.data {
-                match row {
-                    ControlPlaneRecord::Org { id, slug, cell } => {
-                        org_to_cell.insert(id, cell.clone());
-                        org_to_cell.insert(slug, cell);
+    /// Adds the rows of a page. Returns whether there are more pages.
+    fn add_page(
+        &self,
+        pages: &mut Pages,
+        page: ControlPlaneData,
+    ) -> Result<bool, ControlPlaneError> {
+        pages
+            .cell_to_locality
+            .extend(page.metadata.cell_to_locality);
+        pages.cell_labels.extend(page.metadata.cell_labels);
+
+        for row in page.data {
+            match row {
+                ControlPlaneRecord::Org {
+                    id,
+                    slug,
+                    cell,
+                    cells,
+                    deleted,
+                } => {
+                    // The id and the slug of an org may belong to different shards
+                    for key in [id, slug] {
+                        if !self.owns(&key) {
+                            continue;
+                        }
+                        if deleted {
+                            pages.delete(key);
+                            continue;
+                        }
+                        pages.deleted.remove(&key);
+                        if cells.len() > 1 {
+                            pages.org_to_cells.insert(key.clone(), cells.clone());
+                        }
+                        pages.org_to_cell.insert(key, cell.clone());
+                    }
+                }
+                ControlPlaneRecord::ProjectKey {
+                    publickey,
+                    cell,
+                    cells,
+                    deleted,
+                } => {
+                    if !self.owns(&publickey) {
+                        continue;
                     }
-                    ControlPlaneRecord::ProjectKey { publickey, cell } => {
-                        org_to_cell.insert(publickey, cell);
+                    if deleted {
+                        pages.delete(publickey);
+                        continue;
                     }
+                    pages.deleted.remove(&publickey);
+                    if cells.len() > 1 {
+                        pages.org_to_cells.insert(publickey.clone(), cells);
+                    }
+                    pages.org_to_cell.insert(publickey, cell);
                 }
             }
+        }
 
-            page_fetches += 1;
-
-            match (
-                json_response.metadata.has_more,
-                json_response.metadata.cursor,
-            ) {
-                (true, Some(c)) => next_cursor = Some(c),
-                (true, None) => return Err(ControlPlaneError::MissingCursor),
-                (false, Some(c)) => {
-                    next_cursor = Some(c);
-                    break;
-                }
-                (false, None) => break,
+        pages.count += 1;
+
+        match (page.metadata.has_more, page.metadata.cursor) {
+            (true, Some(c)) => {
+                pages.cursor = Some(c);
+                Ok(true)
+            }
+            (true, None) => Err(ControlPlaneError::MissingCursor),
+            (false, Some(c)) => {
+                pages.cursor = Some(c);
+                Ok(false)
             }
+            (false, None) => Ok(false),
         }
+    }
+}
 
-        tracing::info!("Fetched {page_fetches} pages from control plane");
+/// Rows collected from consecutive pages
+#[derive(Default)]
+struct Pages {
+    org_to_cell: HashMap<String, CellId>,
+    org_to_cells: HashMap<String, Vec<CellAssignment>>,
+    cell_to_locality: HashMap<String, String>,
+    cell_labels: HashMap<CellId, BTreeMap<String, String>>,
+    // Ids whose last row is a tombstone
+    deleted: HashSet<String>,
+    // Cursor of the last page
+    cursor: Option<String>,
+    count: usize,
+}
 
-        let data = RouteData::from(org_to_cell, next_cursor, cell_to_locality);
+impl Pages {
+    /// Replaces the rows of a deleted id with its tombstone
+    fn delete(&mut self, id: String) {
+        self.org_to_cell.remove(&id);
+        self.org_to_cells.remove(&id);
+        self.deleted.insert(id);
+    }
+}
 
-        Ok(data)
+/// Pages fetched by the ranges of a snapshot and not merged yet
+#[derive(Default)]
+struct Buffered {
+    // Index of the range being merged
+    front: usize,
+    bytes: usize,
+}
+
+/// Fetch of a range of a snapshot and the pages it fetched, aborted if the snapshot fails
+/// or is cancelled
+struct RangeTask {
+    task: JoinHandle<Result<bool, ControlPlaneError>>,
+    pages: mpsc::UnboundedReceiver<ControlPlaneData>,
+}
+
+impl Drop for RangeTask {
+    fn drop(&mut self) {
+        self.task.abort();
     }
 }
 
@@ -252,14 +603,24 @@ impl ControlPlane {
 mod tests {
     use super::*;
 
+    use crate::config::{Pagination, Signing};
     use crate::testutils::TestControlPlaneServer;
 
+    fn control_plane_config(port: u16) -> ControlPlaneConfig {
+        ControlPlaneConfig {
+            url: format!("http://127.0.0.1:{port}/"),
+            retry: RetryPolicy::default(),
+            pagination: Default::default(),
+            signing: None,
+        }
+    }
+
     #[tokio::test]
     async fn test_control_plane() {
         let server = TestControlPlaneServer::spawn("127.0.0.1").unwrap();
         let control_plane = ControlPlane::new(
             LocatorDataType::Organization,
-            format!("http://127.0.0.1:{}/", server.port),
+            control_plane_config(server.port),
             None,
         );
         let response = control_plane.load_mappings(None).await;
@@ -276,7 +637,7 @@ mod tests {
         let server = TestControlPlaneServer::spawn("127.0.0.1").unwrap();
         let control_plane = ControlPlane::new(
             LocatorDataType::Organization,
-            format!("http://127.0.0.1:{}/", server.port),
+            control_plane_config(server.port),
             Some(vec!["de".into()]),
         );
         let response = control_plane.load_mappings(None).await;
@@ -296,7 +657,7 @@ mod tests {
         let server = TestControlPlaneServer::spawn("127.0.0.1").unwrap();
         let control_plane = ControlPlane::new(
             LocatorDataType::ProjectKey,
-            format!("http://127.0.0.1:{}/", server.port),
+            control_plane_config(server.port),
             None,
         );
         let response = control_plane.load_mappings(None).await;
@@ -310,6 +671,153 @@ mod tests {
         );
     }
 
+    #[tokio::test]
+    async fn test_control_plane_page_size() {
+        let server = TestControlPlaneServer::spawn("127.0.0.1").unwrap();
+        let mut config = control_plane_config(server.port);
+        config.pagination.page_size = Some(2);
+        let control_plane = ControlPlane::new(LocatorDataType::Organization, config, None);
+
+        let mut pages = Pages::default();
+        control_plane.fetch_pages(&mut pages).await.unwrap();
+        assert_eq!(pages.count, 8);
+        assert_eq!(pages.org_to_cell.len(), 30);
+    }
+
+    #[tokio::test]
+    async fn test_load_first_page() {
+        let server = TestControlPlaneServer::spawn("127.0.0.1").unwrap();
+        let mut config = control_plane_config(server.port);
+        config.pagination.page_size = Some(2);
+        let control_plane = ControlPlane::new(LocatorDataType::Organization, config, None);
+
+        // Two orgs, by id and slug
+        let data = control_plane.load_first_page().await.unwrap();
+        assert_eq!(data.id_to_cell.len(), 4);
+        assert!(data.last_cursor.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_control_plane_parallel_pages() {
+        let server = TestControlPlaneServer::spawn("127.0.0.1").unwrap();
+        let load = |page_size, parallel_pages, range_secs, max_buffered_bytes| {
+            let mut config = control_plane_config(server.port);
+            config.pagination = Pagination {
+                page_size: Some(page_size),
+                parallel_pages,
+                range_secs,
+                max_buffered_bytes,
+            };
+            async move {
+                ControlPlane::new(LocatorDataType::Organization, config, None)
+                    .load_mappings(None)
+                    .await
+                    .unwrap()
+            }
+        };
+
+        // Rows are updated a minute apart
+        let serial = load(2, 1, 60, usize::MAX).await;
+        for (page_size, parallel_pages, range_secs, max_buffered_bytes) in [
+            (1, 2, 60, usize::MAX),
+            (2, 3, 120, usize::MAX),
+            (20, 4, 3600, usize::MAX),
+            (1, 4, 1, usize::MAX),
+            // Only the range being merged proceeds
+            (1, 4, 60, 1),
+        ] {
+            let parallel = load(page_size, parallel_pages, range_secs, max_buffered_bytes).await;
+            assert_eq!(parallel.id_to_cell, serial.id_to_cell);
+            // The cursor of the last page is kept for incremental syncs
+            assert_eq!(parallel.last_cursor, serial.last_cursor);
+        }
+        assert_eq!(serial.id_to_cell.len(), 30);
+
+        // Incremental syncs continue from the cursor
+        let mut config = control_plane_config(server.port);
+        config.pagination.parallel_pages = 4;
+        let incremental = ControlPlane::new(LocatorDataType::Organization, config, None)
+            .load_mappings(serial.last_cursor.as_deref())
+            .await
+            .unwrap();
+        assert!(incremental.id_to_cell.is_empty());
+    }
+
+    #[test]
+    fn test_retry_delay() {
+        let policy = RetryPolicy {
+            base_backoff_ms: 500,
+            max_backoff_ms: 3000,
+            ..Default::default()
+        };
+
+        assert_eq!(retry_delay(&policy, 0), Duration::from_millis(500));
+        assert_eq!(retry_delay(&policy, 1), Duration::from_millis(1000));
+        assert_eq!(retry_delay(&policy, 2), Duration::from_millis(2000));
+        // Capped at max backoff
+        assert_eq!(retry_delay(&policy, 3), Duration::from_millis(3000));
+        assert_eq!(retry_delay(&policy, 100), Duration::from_millis(3000));
+
+        // Jitter only ever shortens the delay
+        let policy = RetryPolicy {
+            jitter: 0.5,
+            ..policy
+        };
+        for _ in 0..10 {
+            let delay = retry_delay(&policy, 1);
+            assert!(delay >= Duration::from_millis(500) && delay <= Duration::from_millis(1000));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_signing_key_rotation() {
+        let authorizations = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let server = shared::testutils::MockServer::spawn({
+            let authorizations = authorizations.clone();
+            move |request| {
+                let authorization = request.headers().get("authorization").cloned();
+                authorizations.lock().unwrap().push(authorization);
+                let page = serde_json::json!({
+                    "data": [],
+                    "metadata": { "cursor": null, "has_more": false, "cell_to_locality": {} },
+                });
+                http::Response::new(http_body_util::Full::new(page.to_string().into()))
+            }
+        })
+        .await;
+
+        let dir = tempfile::tempdir().unwrap();
+        let key_file = dir.path().join("secret");
+        std::fs::write(&key_file, "secret1").unwrap();
+        let mut config = control_plane_config(server.port());
+        config.signing = Some(Signing {
+            key_file: Some(key_file.clone()),
+            ..Default::default()
+        });
+        let control_plane = ControlPlane::new(LocatorDataType::Organization, config, None);
+
+        control_plane.load_first_page().await.unwrap();
+        std::fs::write(&key_file, "secret2").unwrap();
+        control_plane.signing_key.reload().await;
+        control_plane.load_first_page().await.unwrap();
+
+        let path = "/api/0/internal/org-cell-mappings/";
+        let expected = ["secret1", "secret2"].map(|secret| {
+            let signature = ControlPlane::compute_hmac_signature(secret, path, &[]);
+            Some(format!("{AUTH_SCHEME} {HMAC_SIGNATURE_PREFIX}:{signature}"))
+        });
+        let authorizations = authorizations.lock().unwrap();
+        let authorizations: Vec<_> = authorizations
+            .iter()
+            .map(|value| {
+                value
+                    .as_ref()
+                    .map(|value| value.to_str().unwrap().to_string())
+            })
+            .collect();
+        assert_eq!(authorizations, expected);
+    }
+
     #[test]
     fn test_compute_hmac_signature() {
         let secret = "test_secret";
@@ -1,8 +1,8 @@
 use base64::{Engine as _, engine::general_purpose::STANDARD};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use std::str::FromStr;
 
-#[derive(Clone, Debug, Deserialize, PartialEq, Eq)]
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
 pub struct Cursor {
     // seconds since 1970-01-01 00:00:00 UTC
     pub updated_at: u64,
@@ -19,6 +19,13 @@ impl FromStr for Cursor {
     }
 }
 
+impl Cursor {
+    /// Encodes the cursor in the format of the control plane
+    pub fn encode(&self) -> String {
+        STANDARD.encode(serde_json::to_vec(self).expect("cursor serializes to JSON"))
+    }
+}
+
 impl PartialOrd for Cursor {
     fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
         Some(self.cmp(other))
@@ -67,6 +74,10 @@ mod tests {
         let cursor: Cursor = encoded.parse().unwrap();
         assert_eq!(cursor.updated_at, 1757030409);
         assert_eq!(cursor.id, "a");
+
+        let cursor: Cursor = cursor.encode().parse().unwrap();
+        assert_eq!(cursor.updated_at, 1757030409);
+        assert_eq!(cursor.id, "a");
     }
 
     #[test]
@@ -0,0 +1,497 @@
+//! Datagram lookup protocol for callers on the same host.
+//!
+//! HTTP lookups pay for a request and response with headers, and for connection management,
+//! which dominates the cost of a lookup answered from memory. Callers co-located with the
+//! locator, like the proxy, can instead send lookups as single UDP datagrams to the
+//! `datagram_listener`. Only plain lookups are supported, other requests go over HTTP.
+//!
+//! Frames are compact binary, with integers in big endian and strings prefixed with their
+//! length as a u16:
+//! - request: version (u8), request id (u32), id (string), locality (string, empty if none)
+//! - response: version (u8), request id (u32), status (u8), followed by the cell if the
+//!   status is `OK`, or the requested and the actual locality for `LOCALITY_MISMATCH`
+//!
+//! The client matches responses to requests by their request id. Datagrams can be lost,
+//! so lookups without an answer within `LOOKUP_TIMEOUT` are retried over HTTP. The listener
+//! is not authenticated, so it only binds to loopback addresses and cannot be enabled
+//! along with `api_keys`.
+use crate::locator::{Locator, LocatorError};
+use crate::metrics_defs::DATAGRAM_REQUESTS;
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::sync::oneshot;
+
+pub const VERSION: u8 = 1;
+
+// Largest frame, which leaves room for ids and cells of a few hundred bytes
+const MAX_FRAME_LEN: usize = 1024;
+
+/// Time after which a lookup is retried over HTTP
+pub const LOOKUP_TIMEOUT: Duration = Duration::from_millis(200);
+
+const OK: u8 = 0;
+const NO_CELL: u8 = 1;
+const NOT_YET_SYNCED: u8 = 2;
+const DELETED: u8 = 3;
+const LOCALITY_MISMATCH: u8 = 4;
+const NOT_READY: u8 = 5;
+const WRONG_SHARD: u8 = 6;
+const LOCALITY_NOT_SERVED: u8 = 7;
+const INTERNAL_ERROR: u8 = 8;
+
+#[derive(thiserror::Error, Debug, PartialEq)]
+pub enum FrameError {
+    #[error("unsupported version {0}")]
+    Version(u8),
+    #[error("truncated frame")]
+    Truncated,
+    #[error("frame longer than {MAX_FRAME_LEN} bytes")]
+    TooLong,
+    #[error("invalid UTF-8 string")]
+    InvalidString,
+    #[error("unknown status {0}")]
+    Status(u8),
+}
+
+#[derive(Debug, PartialEq)]
+pub struct LookupRequest {
+    pub request_id: u32,
+    pub id: String,
+    pub locality: Option<String>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct LookupResponse {
+    pub request_id: u32,
+    pub result: Result<String, LocatorError>,
+}
+
+impl LookupRequest {
+    pub fn encode(&self) -> Result<Vec<u8>, FrameError> {
+        let mut frame = vec![VERSION];
+        frame.extend_from_slice(&self.request_id.to_be_bytes());
+        put_string(&mut frame, &self.id)?;
+        put_string(&mut frame, self.locality.as_deref().unwrap_or_default())?;
+        check_len(frame)
+    }
+
+    pub fn decode(frame: &[u8]) -> Result<Self, FrameError> {
+        let mut reader = Reader::new(frame)?;
+        let request_id = reader.u32()?;
+        let id = reader.string()?;
+        let locality = Some(reader.string()?).filter(|locality| !locality.is_empty());
+        Ok(Self {
+            request_id,
+            id,
+            locality,
+        })
+    }
+}
+
+impl LookupResponse {
+    pub fn encode(&self) -> Result<Vec<u8>, FrameError> {
+        let mut frame = vec![VERSION];
+        frame.extend_from_slice(&self.request_id.to_be_bytes());
+        match &self.result {
+            Ok(cell) => {
+                frame.push(OK);
+                put_string(&mut frame, cell)?;
+            }
+            Err(LocatorError::LocalityMismatch { requested, actual }) => {
+                frame.push(LOCALITY_MISMATCH);
+                put_string(&mut frame, requested)?;
+                put_string(&mut frame, actual)?;
+            }
+            Err(error) => frame.push(match error {
+                LocatorError::NoCell => NO_CELL,
+                LocatorError::NotYetSynced => NOT_YET_SYNCED,
+                LocatorError::Deleted => DELETED,
+                LocatorError::NotReady => NOT_READY,
+                LocatorError::WrongShard => WRONG_SHARD,
+                LocatorError::LocalityNotServed => LOCALITY_NOT_SERVED,
+                LocatorError::LocalityMismatch { .. } | LocatorError::InternalError => {
+                    INTERNAL_ERROR
+                }
+            }),
+        }
+        check_len(frame)
+    }
+
+    pub fn decode(frame: &[u8]) -> Result<Self, FrameError> {
+        let mut reader = Reader::new(frame)?;
+        let request_id = reader.u32()?;
+        let result = match reader.u8()? {
+            OK => Ok(reader.string()?),
+            NO_CELL => Err(LocatorError::NoCell),
+            NOT_YET_SYNCED => Err(LocatorError::NotYetSynced),
+            DELETED => Err(LocatorError::Deleted),
+            LOCALITY_MISMATCH => Err(LocatorError::LocalityMismatch {
+                requested: reader.string()?,
+                actual: reader.string()?,
+            }),
+            NOT_READY => Err(LocatorError::NotReady),
+            WRONG_SHARD => Err(LocatorError::WrongShard),
+            LOCALITY_NOT_SERVED => Err(LocatorError::LocalityNotServed),
+            INTERNAL_ERROR => Err(LocatorError::InternalError),
+            status => return Err(FrameError::Status(status)),
+        };
+        Ok(Self { request_id, result })
+    }
+}
+
+fn put_string(frame: &mut Vec<u8>, value: &str) -> Result<(), FrameError> {
+    let len = u16::try_from(value.len()).map_err(|_| FrameError::TooLong)?;
+    frame.extend_from_slice(&len.to_be_bytes());
+    frame.extend_from_slice(value.as_bytes());
+    Ok(())
+}
+
+fn check_len(frame: Vec<u8>) -> Result<Vec<u8>, FrameError> {
+    if frame.len() > MAX_FRAME_LEN {
+        return Err(FrameError::TooLong);
+    }
+    Ok(frame)
+}
+
+struct Reader<'a> {
+    frame: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    /// Reader of the frame after its version
+    fn new(frame: &'a [u8]) -> Result<Self, FrameError> {
+        let mut reader = Reader { frame };
+        match reader.u8()? {
+            VERSION => Ok(reader),
+            version => Err(FrameError::Version(version)),
+        }
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], FrameError> {
+        if self.frame.len() < len {
+            return Err(FrameError::Truncated);
+        }
+        let (value, rest) = self.frame.split_at(len);
+        self.frame = rest;
+        Ok(value)
+    }
+
+    fn u8(&mut self) -> Result<u8, FrameError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, FrameError> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn string(&mut self) -> Result<String, FrameError> {
+        let len = self.take(2)?;
+        let len = u16::from_be_bytes([len[0], len[1]]) as usize;
+        String::from_utf8(self.take(len)?.to_vec()).map_err(|_| FrameError::InvalidString)
+    }
+}
+
+/// Checks that the listener only accepts datagrams from the same host.
+pub fn validate_listener(host: &str, api_keys: bool) -> Result<(), String> {
+    if api_keys {
+        return Err(
+            "the datagram listener is not authenticated and cannot be enabled with api_keys".into(),
+        );
+    }
+    match host.parse::<IpAddr>() {
+        Ok(ip) if ip.is_loopback() => Ok(()),
+        _ => Err(format!(
+            "the datagram listener is not authenticated and must bind to a loopback address, not {host}"
+        )),
+    }
+}
+
+/// Answers the lookups received on the socket, forever.
+pub async fn serve(socket: UdpSocket, locator: Locator) {
+    let socket = Arc::new(socket);
+    let mut buf = [0; MAX_FRAME_LEN];
+    loop {
+        let (len, peer) = match socket.recv_from(&mut buf).await {
+            Ok(received) => received,
+            Err(error) => {
+                tracing::warn!(%error, "Failed to receive a lookup datagram");
+                continue;
+            }
+        };
+        let request = match LookupRequest::decode(&buf[..len]) {
+            Ok(request) => request,
+            Err(error) => {
+                tracing::debug!(%error, %peer, "Invalid lookup datagram");
+                metrics::counter!(DATAGRAM_REQUESTS.name, "outcome" => "invalid").increment(1);
+                continue;
+            }
+        };
+
+        let socket = socket.clone();
+        let locator = locator.clone();
+        tokio::spawn(async move {
+            let result = locator
+                .lookup(&request.id, request.locality.as_deref())
+                .await;
+            let outcome = match &result {
+                Ok(_) => "ok",
+                Err(error) => error.code(),
+            };
+            metrics::counter!(DATAGRAM_REQUESTS.name, "outcome" => outcome).increment(1);
+
+            let response = LookupResponse {
+                request_id: request.request_id,
+                result,
+            };
+            let sent = match response.encode() {
+                Ok(frame) => socket.send_to(&frame, peer).await.map(|_| ()),
+                Err(error) => Err(io::Error::other(error)),
+            };
+            if let Err(error) = sent {
+                tracing::debug!(%error, %peer, "Failed to answer a lookup datagram");
+            }
+        });
+    }
+}
+
+type Pending = Arc<Mutex<HashMap<u32, oneshot::Sender<Result<String, LocatorError>>>>>;
+
+/// Client sending lookups to a datagram listener
+#[derive(Clone)]
+pub(crate) struct DatagramClient {
+    inner: Arc<DatagramClientInner>,
+}
+
+struct DatagramClientInner {
+    socket: Arc<UdpSocket>,
+    // Lookups waiting for their response, by request id
+    pending: Pending,
+    next_request_id: AtomicU32,
+    receiver: tokio::task::AbortHandle,
+}
+
+impl Drop for DatagramClientInner {
+    fn drop(&mut self) {
+        self.receiver.abort();
+    }
+}
+
+impl DatagramClient {
+    pub async fn connect(addr: &str) -> io::Result<Self> {
+        let addr = tokio::net::lookup_host(addr)
+            .await?
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no address"))?;
+        let local: IpAddr = match addr {
+            SocketAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+            SocketAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+        };
+        let socket = UdpSocket::bind((local, 0)).await?;
+        socket.connect(addr).await?;
+        let socket = Arc::new(socket);
+
+        let pending = Pending::default();
+        let receiver = tokio::spawn(receive(socket.clone(), pending.clone())).abort_handle();
+        Ok(Self {
+            inner: Arc::new(DatagramClientInner {
+                socket,
+                pending,
+                next_request_id: AtomicU32::new(0),
+                receiver,
+            }),
+        })
+    }
+
+    /// Looks up the id, None if no answer was received in time.
+    pub async fn lookup(
+        &self,
+        id: &str,
+        locality: Option<&str>,
+    ) -> Option<Result<String, LocatorError>> {
+        let request_id = self.inner.next_request_id.fetch_add(1, Ordering::Relaxed);
+        let frame = LookupRequest {
+            request_id,
+            id: id.to_string(),
+            locality: locality.map(String::from),
+        }
+        .encode()
+        .ok()?;
+
+        let (sender, response) = oneshot::channel();
+        self.pending().insert(request_id, sender);
+        let sent = self.inner.socket.send(&frame).await;
+        let result = match sent {
+            Ok(_) => tokio::time::timeout(LOOKUP_TIMEOUT, response)
+                .await
+                .ok()
+                .and_then(Result::ok),
+            Err(error) => {
+                tracing::debug!(%error, "Failed to send a lookup datagram");
+                None
+            }
+        };
+        self.pending().remove(&request_id);
+        result
+    }
+
+    fn pending(
+        &self,
+    ) -> std::sync::MutexGuard<'_, HashMap<u32, oneshot::Sender<Result<String, LocatorError>>>>
+    {
+        self.inner.pending.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Hands the received responses to the lookups waiting for them.
+async fn receive(socket: Arc<UdpSocket>, pending: Pending) {
+    let mut buf = [0; MAX_FRAME_LEN];
+    loop {
+        let len = match socket.recv(&mut buf).await {
+            Ok(len) => len,
+            // The listener is down, which the waiting lookups find out from their timeout
+            Err(error) => {
+                tracing::debug!(%error, "Failed to receive a lookup datagram");
+                continue;
+            }
+        };
+        match LookupResponse::decode(&buf[..len]) {
+            Ok(response) => {
+                let sender = pending
+                    .lock()
+                    .unwrap_or_else(|e| e.into_inner())
+                    .remove(&response.request_id);
+                // Lookups that timed out are no longer waiting
+                if let Some(sender) = sender {
+                    let _ = sender.send(response.result);
+                }
+            }
+            Err(error) => tracing::debug!(%error, "Invalid lookup response datagram"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backup_routes::{BackupRouteProvider, FilesystemRouteProvider};
+    use crate::config::{self, LocatorDataType};
+    use crate::locator::Startup;
+    use crate::types::RouteData;
+
+    #[test]
+    fn test_validate_listener() {
+        assert!(validate_listener("127.0.0.1", false).is_ok());
+        assert!(validate_listener("::1", false).is_ok());
+        assert!(validate_listener("0.0.0.0", false).is_err());
+        assert!(validate_listener("localhost", false).is_err());
+        // Lookups would bypass the API keys
+        assert!(validate_listener("127.0.0.1", true).is_err());
+    }
+
+    #[test]
+    fn test_frames() {
+        let request = LookupRequest {
+            request_id: 7,
+            id: "org_1".into(),
+            locality: Some("us".into()),
+        };
+        let frame = request.encode().unwrap();
+        assert_eq!(frame, b"\x01\x00\x00\x00\x07\x00\x05org_1\x00\x02us");
+        assert_eq!(LookupRequest::decode(&frame).unwrap(), request);
+
+        for result in [
+            Ok("us1".to_string()),
+            Err(LocatorError::NotReady),
+            Err(LocatorError::LocalityMismatch {
+                requested: "de".into(),
+                actual: "us".into(),
+            }),
+        ] {
+            let response = LookupResponse {
+                request_id: u32::MAX,
+                result,
+            };
+            let frame = response.encode().unwrap();
+            assert_eq!(LookupResponse::decode(&frame).unwrap(), response);
+        }
+
+        assert_eq!(
+            LookupRequest::decode(b"\x02\x00\x00\x00\x07"),
+            Err(FrameError::Version(2))
+        );
+        assert_eq!(
+            LookupRequest::decode(&frame[..frame.len() - 1]),
+            Err(FrameError::Truncated)
+        );
+        let too_long = LookupRequest {
+            request_id: 0,
+            id: "a".repeat(MAX_FRAME_LEN),
+            locality: None,
+        };
+        assert_eq!(too_long.encode(), Err(FrameError::TooLong));
+    }
+
+    #[tokio::test]
+    async fn test_datagram_lookups() {
+        // The control plane is unreachable, the mappings are loaded from the backup
+        let route_data = RouteData::from(
+            HashMap::from([("org_0".into(), "us1".into())]),
+            Some("cursor1".into()),
+            HashMap::from([("us1".into(), "us".into())]),
+        );
+        let dir = tempfile::tempdir().unwrap();
+        let provider = FilesystemRouteProvider::new(
+            dir.path().to_str().unwrap(),
+            "backup.bin",
+            config::Compression::None,
+        );
+        provider.store(&route_data).await.unwrap();
+        let locator = Locator::new(
+            LocatorDataType::Organization,
+            config::ControlPlane {
+                url: "http://127.0.0.1:1".into(),
+                retry: config::RetryPolicy {
+                    max_retries: 0,
+                    ..Default::default()
+                },
+                pagination: Default::default(),
+                signing: None,
+            },
+            Arc::new(provider),
+            None,
+            None,
+        );
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        let server = tokio::spawn(serve(socket, locator.clone()));
+        locator.wait_for_startup(Startup::Loaded).await;
+        assert!(locator.is_ready());
+
+        let client = DatagramClient::connect(&addr.to_string()).await.unwrap();
+        let lookups = HashMap::from([
+            (("org_0", None), Ok("us1".to_string())),
+            (("org_0", Some("us")), Ok("us1".to_string())),
+            (
+                ("org_0", Some("de")),
+                Err(LocatorError::LocalityMismatch {
+                    requested: "de".into(),
+                    actual: "us".into(),
+                }),
+            ),
+        ]);
+        for ((id, locality), expected) in lookups {
+            assert_eq!(client.lookup(id, locality).await, Some(expected));
+        }
+
+        // Lookups time out once the listener is gone
+        server.abort();
+        let _ = server.await;
+        assert_eq!(client.lookup("org_0", None).await, None);
+    }
+}
@@ -0,0 +1,157 @@
+//! Bounded history of mapping changes.
+//!
+//! Records every change of an id's cell observed by the locator, so that it can answer
+//! which cell an id mapped to at a point in the past. The history is part of `RouteData`
+//! and therefore stored in the backup along with the mappings.
+//!
+//! Changes are timestamped when the locator observes them, which can be up to one refresh
+//! interval after the change was made in the control plane.
+use crate::types::CellId;
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
+
+/// Maximum number of changes retained, the oldest changes are dropped first.
+pub const MAX_CHANGES: usize = 100_000;
+
+#[derive(Clone, Debug, PartialEq, Serialize, bincode::Encode, bincode::Decode)]
+pub struct MappingChange {
+    pub id: String,
+    /// Unix timestamp in seconds
+    pub observed_at: u64,
+    /// None if the id was not mapped before
+    pub from: Option<CellId>,
+    pub to: CellId,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, bincode::Encode, bincode::Decode)]
+pub struct MappingHistory {
+    // Oldest first
+    changes: VecDeque<MappingChange>,
+}
+
+impl MappingHistory {
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.changes.len()
+    }
+
+    fn record(&mut self, change: MappingChange) {
+        if self.changes.len() >= MAX_CHANGES {
+            self.changes.pop_front();
+        }
+        self.changes.push_back(change);
+    }
+
+    /// Records the ids in `updates` whose cell differs from `current`.
+    pub fn record_changes(
+        &mut self,
+        current: &HashMap<String, CellId>,
+        updates: &HashMap<String, CellId>,
+        observed_at: u64,
+    ) {
+        for (id, cell) in updates {
+            let from = current.get(id);
+            if from != Some(cell) {
+                self.record(MappingChange {
+                    id: id.clone(),
+                    observed_at,
+                    from: from.cloned(),
+                    to: cell.clone(),
+                });
+            }
+        }
+    }
+
+    /// Changes of the id, oldest first
+    pub fn changes_for<'a>(&'a self, id: &'a str) -> impl Iterator<Item = &'a MappingChange> {
+        self.changes.iter().filter(move |change| change.id == id)
+    }
+
+    /// Cell the id mapped to at the given unix timestamp. `current` is the id's cell now,
+    /// which is assumed if no change of the id has been recorded.
+    pub fn cell_at(&self, id: &str, at: u64, current: Option<&CellId>) -> Option<CellId> {
+        // Latest change at or before the timestamp
+        let mut latest: Option<&MappingChange> = None;
+
+        for change in self.changes_for(id) {
+            if change.observed_at > at {
+                return match latest {
+                    Some(latest) => Some(latest.to.clone()),
+                    // The first recorded change happened after the timestamp
+                    None => change.from.clone(),
+                };
+            }
+            latest = Some(change);
+        }
+
+        match latest {
+            Some(latest) => Some(latest.to.clone()),
+            None => current.cloned(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cell_at() {
+        let mut history = MappingHistory::default();
+        let mut current = HashMap::from([("org_1".to_string(), "us1".to_string())]);
+
+        // Moved to us2 at 100, then to de at 200
+        for (at, cell) in [(100, "us2"), (200, "de")] {
+            let updates = HashMap::from([("org_1".to_string(), cell.to_string())]);
+            history.record_changes(&current, &updates, at);
+            current.extend(updates);
+        }
+
+        // Unchanged mappings are not recorded
+        history.record_changes(&current.clone(), &current, 300);
+        assert_eq!(history.len(), 2);
+
+        let cell_at = |at| history.cell_at("org_1", at, current.get("org_1"));
+        assert_eq!(cell_at(50), Some("us1".into()));
+        assert_eq!(cell_at(100), Some("us2".into()));
+        assert_eq!(cell_at(150), Some("us2".into()));
+        assert_eq!(cell_at(250), Some("de".into()));
+
+        // Ids without changes are assumed to be in their current cell
+        assert_eq!(
+            history.cell_at("org_2", 50, Some(&"us1".into())),
+            Some("us1".into())
+        );
+    }
+
+    #[test]
+    fn test_new_id() {
+        let mut history = MappingHistory::default();
+        let updates = HashMap::from([("org_1".to_string(), "us1".to_string())]);
+        history.record_changes(&HashMap::new(), &updates, 100);
+
+        // Not mapped before it was created
+        assert_eq!(history.cell_at("org_1", 50, Some(&"us1".into())), None);
+        assert_eq!(
+            history.cell_at("org_1", 100, Some(&"us1".into())),
+            Some("us1".into())
+        );
+    }
+
+    #[test]
+    fn test_bounded() {
+        let mut history = MappingHistory::default();
+        for i in 0..MAX_CHANGES + 10 {
+            let updates = HashMap::from([(format!("org_{i}"), "us1".to_string())]);
+            history.record_changes(&HashMap::new(), &updates, i as u64);
+        }
+
+        assert_eq!(history.len(), MAX_CHANGES);
+        // The oldest changes were dropped
+        assert_eq!(history.changes_for("org_0").count(), 0);
+        assert_eq!(history.changes_for("org_10").count(), 1);
+    }
+}
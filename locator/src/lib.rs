@@ -1,13 +1,27 @@
+pub mod admin;
+mod alerts;
 mod api;
+mod auth;
 pub mod backup_routes;
+pub mod catalog;
 pub mod client;
+mod client_cache;
+pub mod clock;
 pub mod config;
 mod control_plane;
 mod cursor;
+pub mod datagram;
+pub mod history;
 pub mod locator;
 pub mod metrics_defs;
 mod negative_cache;
+pub mod project_key;
+pub mod rebalance;
+pub mod self_test;
+pub mod shard;
+mod signing;
 pub mod types;
+mod warm_cache;
 use std::sync::Arc;
 
 #[cfg(test)]
@@ -15,25 +29,84 @@ mod testutils;
 
 use backup_routes::{BackupError, BackupRouteProvider, FilesystemRouteProvider, GcsRouteProvider};
 use config::BackupRouteStoreType;
+use locator::{Locator, LocatorOptions};
 
 /// Run the locator API in standalone mode.
 pub async fn run(config: config::Config) -> Result<(), api::LocatorApiError> {
+    validate(&config)?;
+
     let provider = get_provider(config.backup_route_store.r#type).await?;
 
-    api::serve(
+    let locator = Locator::with_options(
         config.data_type,
-        config.listener,
         config.control_plane,
         provider,
         config.localities,
         config.locality_to_default_cell,
+        LocatorOptions {
+            warm_cache: config.warm_cache,
+            capacity_weights: config.capacity_weights,
+            shard: config.shard,
+            read_only: config.read_only,
+            alerts: config.alerts,
+            cells: config.cells,
+            refresh_overflow: config.refresh_overflow,
+            negative_cache: config.negative_cache,
+            tombstone_retention: std::time::Duration::from_secs(config.tombstone_retention_secs),
+            ..Default::default()
+        },
+    );
+
+    let datagram_task = match config.datagram_listener {
+        Some(listener) => {
+            let socket =
+                tokio::net::UdpSocket::bind(format!("{}:{}", listener.host, listener.port)).await?;
+            Some(tokio::spawn(datagram::serve(socket, locator.clone())))
+        }
+        None => None,
+    };
+
+    let result = api::serve(
+        config.listener,
+        locator,
+        config.api_keys,
+        config.catalog_write_keys,
+        std::time::Duration::from_secs(config.drain_timeout_secs),
     )
-    .await
+    .await;
+    if let Some(task) = datagram_task {
+        task.abort();
+    }
+    result
+}
+
+/// Checks the config like [`run`] does, without contacting the control plane or the
+/// backup route store.
+pub fn validate(config: &config::Config) -> Result<(), api::LocatorApiError> {
+    config
+        .backup_route_store
+        .r#type
+        .validate()
+        .map_err(api::LocatorApiError::InvalidBackupRouteStore)?;
+    if let Some(shard) = &config.shard {
+        shard
+            .validate()
+            .map_err(api::LocatorApiError::InvalidShard)?;
+    }
+    if let Some(cells) = &config.cells {
+        catalog::CellCatalog::validate(cells).map_err(api::LocatorApiError::InvalidCatalog)?;
+    }
+    if let Some(listener) = &config.datagram_listener {
+        datagram::validate_listener(&listener.host, config.api_keys.is_some())
+            .map_err(api::LocatorApiError::InvalidDatagramListener)?;
+    }
+    Ok(())
 }
 
 pub async fn get_provider(
     store_type: BackupRouteStoreType,
 ) -> Result<Arc<dyn BackupRouteProvider + 'static>, BackupError> {
+    store_type.validate().map_err(BackupError::InvalidConfig)?;
     match store_type {
         BackupRouteStoreType::Filesystem {
             base_dir,
@@ -47,6 +120,14 @@ pub async fn get_provider(
         BackupRouteStoreType::Gcs {
             bucket,
             compression,
-        } => Ok(Arc::new(GcsRouteProvider::new(bucket, compression).await?)),
+            lease_ttl_secs,
+        } => Ok(Arc::new(
+            GcsRouteProvider::new(
+                bucket,
+                compression,
+                std::time::Duration::from_secs(lease_ttl_secs),
+            )
+            .await?,
+        )),
     }
 }
@@ -1,18 +1,48 @@
-use crate::config::LocatorDataType;
+use crate::alerts::Alerts;
+use crate::clock::{Clock, SystemClock};
+use crate::config::{
+    Alerts as AlertsConfig, ControlPlane as ControlPlaneConfig, LocatorDataType,
+    NegativeCache as NegativeCacheConfig, RefreshOverflow, Shard, WarmCache as WarmCacheConfig,
+};
 use crate::control_plane::ControlPlane;
-use crate::types::{Cell, RouteData};
+use crate::history::MappingHistory;
+use crate::metrics_defs::{REFRESH_QUEUE_DEPTH, REFRESH_QUEUE_OVERFLOWS};
+use crate::types::{
+    CatalogCell, Cell, CellAssignment, CellId, Freshness, LocatorStats, Readiness, ReadinessStatus,
+    RouteData, StaleLookup,
+};
 use std::sync::Arc;
 use std::time::Instant;
 
 use crate::backup_routes::{BackupError, BackupRouteProvider};
+use crate::catalog::CellCatalog;
 use crate::negative_cache::NegativeCache;
-use std::collections::HashMap;
+use crate::rebalance::RebalanceReport;
+use crate::shard::{ReshardPlan, ShardInfo};
+use crate::warm_cache::{HotLookups, NegativeEntry, WarmCacheDump};
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use std::sync::OnceLock;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::time::Duration;
 use tokio::sync::RwLock;
-use tokio::sync::{AcquireError, Mutex, mpsc, oneshot};
+use tokio::sync::mpsc::error::{SendTimeoutError, TrySendError};
+use tokio::sync::{AcquireError, Mutex, mpsc, oneshot, watch};
 use tokio::sync::{Semaphore, SemaphorePermit};
 
+// Commands waiting for the loader, mostly refreshes of lookups that missed
+const COMMAND_QUEUE_CAPACITY: usize = 64;
+
+/// How often the mappings are refreshed from the control plane
+pub(crate) const REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How often the refreshed mappings are written to the backup route provider
+pub(crate) const BACKUP_INTERVAL: Duration = Duration::from_secs(300);
+
+/// How long tombstones of deleted ids are kept by default
+pub(crate) const TOMBSTONE_RETENTION: Duration = Duration::from_secs(30 * 24 * 3600);
+
 struct LocatorInner {
     id_to_cell_map: Arc<IdToCell>,
     handle: Mutex<Option<tokio::task::JoinHandle<()>>>,
@@ -23,24 +53,85 @@ pub struct Locator {
     inner: Arc<LocatorInner>,
 }
 
+/// Optional behavior of a `Locator`
+pub struct LocatorOptions {
+    /// Measures refresh intervals, staleness and cache TTLs
+    pub clock: Arc<dyn Clock>,
+    /// Persists the lookup caches across restarts
+    pub warm_cache: Option<WarmCacheConfig>,
+    /// Relative capacity of cells in the rebalancing report
+    pub capacity_weights: HashMap<CellId, u32>,
+    /// Only keeps and answers the keys of this shard
+    pub shard: Option<Shard>,
+    /// Loads the mappings from the backup route provider only, without contacting the
+    /// control plane or writing backups
+    pub read_only: bool,
+    /// Notifies a webhook of failing syncs, stale mappings and failing backup writes
+    pub alerts: Option<AlertsConfig>,
+    /// Known cells that lookups are validated against
+    pub cells: Option<Vec<CatalogCell>>,
+    /// What lookups do when the refresh queue is full
+    pub refresh_overflow: RefreshOverflow,
+    /// TTL and size of the cache of ids not found
+    pub negative_cache: NegativeCacheConfig,
+    /// How long tombstones of deleted ids are kept after they were first seen
+    pub tombstone_retention: Duration,
+}
+
+impl Default for LocatorOptions {
+    fn default() -> Self {
+        LocatorOptions {
+            clock: Arc::new(SystemClock),
+            warm_cache: None,
+            capacity_weights: HashMap::new(),
+            shard: None,
+            read_only: false,
+            alerts: None,
+            cells: None,
+            refresh_overflow: RefreshOverflow::default(),
+            negative_cache: NegativeCacheConfig::default(),
+            tombstone_retention: TOMBSTONE_RETENTION,
+        }
+    }
+}
+
 impl Locator {
     pub fn new(
         data_type: LocatorDataType,
-        control_plane_url: String,
+        control_plane: ControlPlaneConfig,
+        backup_provider: Arc<dyn BackupRouteProvider + 'static>,
+        localities: Option<Vec<String>>,
+        locality_to_default_cell: Option<HashMap<String, String>>,
+    ) -> Self {
+        Self::with_options(
+            data_type,
+            control_plane,
+            backup_provider,
+            localities,
+            locality_to_default_cell,
+            LocatorOptions::default(),
+        )
+    }
+
+    pub fn with_options(
+        data_type: LocatorDataType,
+        control_plane: ControlPlaneConfig,
         backup_provider: Arc<dyn BackupRouteProvider + 'static>,
         localities: Option<Vec<String>>,
         locality_to_default_cell: Option<HashMap<String, String>>,
+        options: LocatorOptions,
     ) -> Self {
         // Channel to send commands to the worker thread.
-        let (tx, rx) = mpsc::channel::<Command>(64);
+        let (tx, rx) = mpsc::channel::<Command>(COMMAND_QUEUE_CAPACITY);
 
         let id_to_cell_map = Arc::new(IdToCell::new(
             data_type,
-            control_plane_url,
+            control_plane,
             backup_provider,
             localities,
             locality_to_default_cell,
             tx.clone(),
+            options,
         ));
 
         // Spawn the loader thread. All loading should happen from this thread.
@@ -61,49 +152,296 @@ impl Locator {
     }
 
     pub async fn lookup(&self, id: &str, locality: Option<&str>) -> Result<String, LocatorError> {
-        self.inner.id_to_cell_map.lookup(id, locality).await
+        let id = self.normalize_id(id);
+        let id: &str = &id;
+        self.check_shard(id)?;
+        self.check_locality(locality)?;
+        let cell = self.inner.id_to_cell_map.lookup(id, locality).await?;
+        self.inner.id_to_cell_map.catalog.check(&cell);
+        Ok(cell)
+    }
+
+    /// Same as `lookup`, but returns the cell with its locality and the labels set in the
+    /// control plane rather than its id only.
+    pub async fn lookup_full(
+        &self,
+        id: &str,
+        locality: Option<&str>,
+    ) -> Result<Cell, LocatorError> {
+        let id = self.normalize_id(id);
+        let id: &str = &id;
+        self.check_shard(id)?;
+        self.check_locality(locality)?;
+        let cell = self.inner.id_to_cell_map.lookup_full(id, locality).await?;
+        self.inner.id_to_cell_map.catalog.check(&cell.id);
+        Ok(cell)
+    }
+
+    /// Looks up the cells of many ids at once. Ids without a cell, or whose cell is outside
+    /// the requested locality, are left out of the result.
+    pub async fn lookup_many(
+        &self,
+        ids: &[&str],
+        locality: Option<&str>,
+    ) -> Result<HashMap<String, String>, LocatorError> {
+        let normalized: Vec<Cow<str>> = ids.iter().map(|id| self.normalize_id(id)).collect();
+        let keys: Vec<&str> = normalized.iter().map(AsRef::as_ref).collect();
+        for id in &keys {
+            self.check_shard(id)?;
+        }
+        self.check_locality(locality)?;
+        let cells = self
+            .inner
+            .id_to_cell_map
+            .lookup_many(&keys, locality)
+            .await?;
+        for cell in cells.values() {
+            self.inner.id_to_cell_map.catalog.check(cell);
+        }
+        // Keyed by the ids as passed, which may differ from the normalized ones
+        Ok(ids
+            .iter()
+            .zip(keys)
+            .filter_map(|(id, key)| Some((id.to_string(), cells.get(key)?.clone())))
+            .collect())
+    }
+
+    /// Same as `lookup_many`, but returns every cell of the ids that span multiple cells,
+    /// see `lookup_multi`.
+    pub async fn lookup_many_multi(
+        &self,
+        ids: &[&str],
+        locality: Option<&str>,
+    ) -> Result<HashMap<String, Vec<CellAssignment>>, LocatorError> {
+        let normalized: Vec<Cow<str>> = ids.iter().map(|id| self.normalize_id(id)).collect();
+        let keys: Vec<&str> = normalized.iter().map(AsRef::as_ref).collect();
+        for id in &keys {
+            self.check_shard(id)?;
+        }
+        self.check_locality(locality)?;
+        let mut cells = self
+            .inner
+            .id_to_cell_map
+            .lookup_many_multi(&keys, locality)
+            .await?;
+        for assignment in cells.values().flatten() {
+            self.inner.id_to_cell_map.catalog.check(&assignment.cell);
+        }
+        // Keyed by the ids as passed, which may differ from the normalized ones
+        Ok(ids
+            .iter()
+            .zip(keys)
+            .filter_map(|(id, key)| Some((id.to_string(), cells.remove(key)?)))
+            .collect())
+    }
+
+    /// Returns every cell the id is assigned to with their weights. Ids that live in a
+    /// single cell return that cell as the only, primary assignment.
+    pub async fn lookup_multi(
+        &self,
+        id: &str,
+        locality: Option<&str>,
+    ) -> Result<Vec<CellAssignment>, LocatorError> {
+        let id = self.normalize_id(id);
+        let id: &str = &id;
+        self.check_shard(id)?;
+        self.check_locality(locality)?;
+        let assignments = self.inner.id_to_cell_map.lookup_multi(id, locality).await?;
+        for assignment in &assignments {
+            self.inner.id_to_cell_map.catalog.check(&assignment.cell);
+        }
+        Ok(assignments)
+    }
+
+    /// Returns the cell the id was mapped to at the given unix timestamp, as far as this
+    /// locator's history goes back.
+    pub async fn lookup_at(&self, id: &str, at: u64) -> Result<String, LocatorError> {
+        let id = self.normalize_id(id);
+        let id: &str = &id;
+        self.check_shard(id)?;
+        self.inner.id_to_cell_map.lookup_at(id, at).await
+    }
+
+    /// Like `lookup`, but returns the last known cell instead of failing while the locator
+    /// is not ready, together with how fresh the mapping is. For callers that prefer routing
+    /// to a possibly outdated cell over failing the request.
+    pub async fn lookup_stale(
+        &self,
+        id: &str,
+        locality: Option<&str>,
+    ) -> Result<StaleLookup, LocatorError> {
+        let id = self.normalize_id(id);
+        let id: &str = &id;
+        self.check_shard(id)?;
+        self.check_locality(locality)?;
+        let lookup = self.inner.id_to_cell_map.lookup_stale(id, locality).await?;
+        self.inner.id_to_cell_map.catalog.check(&lookup.cell);
+        Ok(lookup)
+    }
+
+    /// The topology of the sharded keyspace, None if this locator is not sharded.
+    pub async fn shard_info(&self) -> Option<ShardInfo> {
+        self.inner.id_to_cell_map.shard_info().await
+    }
+
+    /// Size and age of the mappings and the negative cache, for debugging.
+    pub async fn stats(&self) -> LocatorStats {
+        self.inner.id_to_cell_map.stats().await
+    }
+
+    /// Where this shard's keys would go with `count` shards, None if this locator is not
+    /// sharded.
+    pub async fn reshard_plan(&self, count: usize) -> Option<ReshardPlan> {
+        self.inner.id_to_cell_map.reshard_plan(count).await
+    }
+
+    /// The registered cells sorted by id, None if no catalog was registered.
+    pub fn cell_catalog(&self) -> Option<Vec<CatalogCell>> {
+        self.inner.id_to_cell_map.catalog.list()
+    }
+
+    /// Replaces the cell catalog. Fails without changing it if the cells are invalid.
+    pub async fn register_cells(&self, cells: Vec<CatalogCell>) -> Result<(), String> {
+        CellCatalog::validate(&cells)?;
+        self.inner.id_to_cell_map.catalog.register(cells);
+        self.inner.id_to_cell_map.check_catalog().await;
+        Ok(())
+    }
+
+    /// Kind of the ids this locator maps
+    pub fn data_type(&self) -> LocatorDataType {
+        self.inner.id_to_cell_map.data_type
+    }
+
+    // Project keys may be passed as DSNs
+    fn normalize_id<'a>(&self, id: &'a str) -> Cow<'a, str> {
+        self.inner.id_to_cell_map.data_type.normalize_id(id)
+    }
+
+    fn check_shard(&self, id: &str) -> Result<(), LocatorError> {
+        match &self.inner.id_to_cell_map.shard {
+            Some(shard) if !shard.owns(id) => Err(LocatorError::WrongShard),
+            _ => Ok(()),
+        }
+    }
+
+    fn check_locality(&self, locality: Option<&str>) -> Result<(), LocatorError> {
+        match (&self.inner.id_to_cell_map.localities, locality) {
+            (Some(localities), Some(locality)) if !localities.contains(locality) => {
+                Err(LocatorError::LocalityNotServed)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Summarizes how ids are distributed across cells compared to their capacity weights,
+    /// with suggested moves to even it out.
+    pub async fn rebalance_report(&self) -> Result<RebalanceReport, LocatorError> {
+        self.inner.id_to_cell_map.rebalance_report().await
     }
 
+    /// Stops the loader once the refresh in progress, if any, finished, and stores the
+    /// mappings refreshed since the last backup. Lookups fail with `NotReady` from then on,
+    /// even where a locality default applies. Stale lookups still return the last known
+    /// cell.
     pub async fn shutdown(&self) {
-        // Send shutdown command to the worker thread to end the incremental loading loop
+        let Some(handle) = self.inner.handle.lock().await.take() else {
+            // Already shut down
+            return;
+        };
         tracing::info!("shutting down locator");
 
+        // Send shutdown command to the worker thread to end the incremental loading loop
         if let Err(e) = self.inner.id_to_cell_map.tx.send(Command::Shutdown).await {
             tracing::error!("Failed to send shutdown command: {:?}", e);
             return;
         }
-
-        let Some(handle) = self.inner.handle.lock().await.take() else {
-            return;
-        };
         if let Err(e) = handle.await {
             tracing::error!("Worker task panicked during shutdown: {:?}", e);
         }
+
+        self.inner.id_to_cell_map.dump_warm_cache().await;
     }
 
     pub fn is_ready(&self) -> bool {
         self.inner.id_to_cell_map.ready.load(Ordering::Relaxed)
     }
+
+    /// Time source of the locator, shared with the quotas of the API
+    pub fn clock(&self) -> Arc<dyn Clock> {
+        self.inner.id_to_cell_map.clock.clone()
+    }
+
+    /// Waits until the loader has reached the startup step, so that tests do not sleep
+    #[cfg(test)]
+    pub(crate) async fn wait_for_startup(&self, step: Startup) {
+        let mut startup = self.inner.id_to_cell_map.startup.subscribe();
+        tokio::time::timeout(
+            Duration::from_secs(10),
+            startup.wait_for(|last| *last >= step),
+        )
+        .await
+        .expect("the loader did not reach the startup step in time")
+        .unwrap();
+    }
+
+    /// Whether the mappings are loaded and the backup route provider works, with the
+    /// reasons if not.
+    pub fn readiness(&self) -> Readiness {
+        self.inner.id_to_cell_map.readiness()
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.inner.id_to_cell_map.read_only
+    }
 }
 
-#[derive(thiserror::Error, Debug, PartialEq)]
+#[derive(thiserror::Error, Debug, Clone, PartialEq)]
 pub enum LocatorError {
     #[error("no cell found for id")]
     NoCell,
 
+    #[error("no cell found for id, the mappings may not be synced yet")]
+    NotYetSynced,
+
+    #[error("the id was deleted")]
+    Deleted,
+
     #[error("requested locality does not match the cell's locality")]
     LocalityMismatch { requested: String, actual: String },
 
     #[error("the locator is not ready yet")]
     NotReady,
 
+    #[error("the id belongs to another shard")]
+    WrongShard,
+
+    #[error("the locator does not serve the requested locality")]
+    LocalityNotServed,
+
     #[error("internal error")]
     InternalError,
 }
 
+impl LocatorError {
+    /// Identifies the error in API responses, where several errors share a status
+    pub fn code(&self) -> &'static str {
+        match self {
+            LocatorError::NoCell => "no_cell",
+            LocatorError::NotYetSynced => "not_yet_synced",
+            LocatorError::Deleted => "deleted",
+            LocatorError::LocalityMismatch { .. } => "locality_mismatch",
+            LocatorError::NotReady => "not_ready",
+            LocatorError::WrongShard => "wrong_shard",
+            LocatorError::LocalityNotServed => "locality_not_served",
+            LocatorError::InternalError => "internal_error",
+        }
+    }
+}
+
 #[derive(thiserror::Error, Debug)]
 pub enum LoadError {
-    #[error("Error loading backup")]
+    #[error("Error loading backup: {0}")]
     BackupError(#[from] BackupError),
     #[error("Another load operation is in progress")]
     ConcurrentLoad(#[from] AcquireError),
@@ -121,14 +459,27 @@ pub enum Command {
     Shutdown,
 }
 
+/// Why the latest operations on the backup route provider failed, None if they succeeded
+#[derive(Debug, Default)]
+struct BackupHealth {
+    write_error: Option<String>,
+    probe_error: Option<String>,
+    // Reloads of read-only locators
+    reload_error: Option<String>,
+}
+
 struct RouteDataWithTimestamp {
     data: RouteData,
     last_updated: Option<Instant>,
+    // When the data was last written to the backup route provider
+    last_backup: Option<Instant>,
 }
 
 /// Synchronizes the id to cell mappings from the control plane and backup route provider.
 /// This struct is used internally by the Locator.
 struct IdToCell {
+    // Kind of ids, looked up ids are normalized according to it
+    data_type: LocatorDataType,
     control_plane: ControlPlane,
     // Pre-built default cells keyed by locality. Built once at startup so
     // default-fallback lookups don't allocate, and stored separately from
@@ -142,54 +493,165 @@ struct IdToCell {
     // Used by the readiness probe. Initially false and set to true once any snapshot
     // has been loaded and mappings are available.
     ready: AtomicBool,
+    // Set once the loader received the Shutdown command
+    stopped: AtomicBool,
     backup_routes: Arc<dyn BackupRouteProvider + Send + Sync>,
     // Standard interval between refresh attempts.
     refresh_interval: std::time::Duration,
     // Minimum duration between refresh attempts.
     min_refresh_interval: std::time::Duration,
+    // Interval between writes of incrementally updated data to the backup route provider.
+    // Writes also renew the backup lease, so this must be shorter than the lease TTL.
+    backup_interval: std::time::Duration,
+    // Interval between probes of the backup route provider, which store and load a
+    // temporary copy.
+    backup_probe_interval: std::time::Duration,
+    // Failures of the backup route provider, reported by the readiness
+    backup_health: Arc<std::sync::Mutex<BackupHealth>>,
     // Channel to send commands to the loader task.
     tx: mpsc::Sender<Command>,
+    refresh_overflow: RefreshOverflow,
+    // Lookups that found the command queue full and wait for the next refresh, with the
+    // time of their lookup.
+    coalesced_refreshes: std::sync::Mutex<Vec<(Instant, oneshot::Sender<()>)>>,
+    clock: Arc<dyn Clock>,
+    // File the negative cache and hot lookups are persisted to, if configured.
+    warm_cache_path: Option<PathBuf>,
+    // Ids recently resolved to a cell. Only tracked if the warm cache is configured.
+    hot_lookups: Option<HotLookups>,
+    // Unix timestamp of the last refresh before the warm cache was dumped.
+    restored_updated_at: OnceLock<u64>,
+    capacity_weights: HashMap<CellId, u32>,
+    shard: Option<Shard>,
+    // Only the mappings of cells in these localities are kept, all if not set.
+    localities: Option<HashSet<String>>,
+    // Mappings are reloaded from the backup route provider every backup interval instead
+    // of being synchronized from the control plane.
+    read_only: bool,
+    alerts: Option<Alerts>,
+    // Known cells, if registered
+    catalog: CellCatalog,
+    // Tombstones first seen longer ago are dropped, and their ids become unknown
+    tombstone_retention: Duration,
+    // Last startup step of the loader, which tests wait for
+    startup: watch::Sender<Startup>,
+}
+
+/// Steps of the loader's startup, in order
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum Startup {
+    Starting,
+    /// The warm cache was restored, if configured
+    CacheRestored,
+    /// The initial snapshot was loaded, or failed with default cells configured
+    Loaded,
 }
 
 impl IdToCell {
     pub fn new(
         data_type: LocatorDataType,
-        control_plane_url: String,
+        control_plane: ControlPlaneConfig,
         backup_routes: Arc<dyn BackupRouteProvider + Send + Sync>,
         localities: Option<Vec<String>>,
         locality_to_default_cell: Option<HashMap<String, String>>,
         tx: mpsc::Sender<Command>,
+        options: LocatorOptions,
     ) -> Self {
+        let LocatorOptions {
+            clock,
+            warm_cache,
+            capacity_weights,
+            shard,
+            read_only,
+            alerts,
+            cells,
+            refresh_overflow,
+            negative_cache,
+            tombstone_retention,
+        } = options;
+
         let data = RouteDataWithTimestamp {
             data: RouteData {
                 id_to_cell: HashMap::new(),
+                id_to_cells: HashMap::new(),
                 last_cursor: None,
                 cells: HashMap::new(),
+                history: MappingHistory::default(),
+                deleted: HashMap::new(),
             },
             last_updated: None,
+            last_backup: None,
         };
 
         let locality_to_default_cell = locality_to_default_cell
             .unwrap_or_default()
             .into_iter()
-            .map(|(locality, id)| (locality.clone(), Arc::new(Cell { id, locality })))
+            .map(|(locality, id)| (locality.clone(), Arc::new(Cell::new(id, locality))))
             .collect();
 
+        let locality_filter = localities
+            .as_ref()
+            .map(|localities| localities.iter().cloned().collect());
+
         IdToCell {
-            control_plane: ControlPlane::new(data_type, control_plane_url, localities),
+            data_type,
+            control_plane: ControlPlane::new(data_type, control_plane, localities)
+                .with_shard(shard.clone()),
             locality_to_default_cell,
             data: RwLock::new(data),
-            negative_cache: NegativeCache::new(),
+            negative_cache: NegativeCache::new(negative_cache, clock.clone()),
             update_lock: Semaphore::new(1),
             ready: AtomicBool::new(false),
+            stopped: AtomicBool::new(false),
             backup_routes,
-            refresh_interval: Duration::from_secs(60),
+            refresh_interval: REFRESH_INTERVAL,
             min_refresh_interval: Duration::from_secs(1),
+            backup_interval: BACKUP_INTERVAL,
+            backup_probe_interval: Duration::from_secs(60),
+            backup_health: Arc::default(),
             tx,
+            refresh_overflow,
+            coalesced_refreshes: std::sync::Mutex::default(),
+            warm_cache_path: warm_cache
+                .as_ref()
+                .map(|config| PathBuf::from(&config.path)),
+            hot_lookups: warm_cache.map(|config| HotLookups::new(config.max_hot_ids)),
+            restored_updated_at: OnceLock::new(),
+            capacity_weights,
+            shard,
+            localities: locality_filter,
+            read_only,
+            alerts: alerts.map(|config| Alerts::new(config, clock.clone())),
+            catalog: CellCatalog::new(cells),
+            tombstone_retention,
+            clock,
+            startup: watch::Sender::new(Startup::Starting),
         }
     }
 
     pub async fn lookup(&self, id: &str, locality: Option<&str>) -> Result<String, LocatorError> {
+        let cell = self.lookup_cell(id, locality).await?;
+        Ok(cell.id.clone())
+    }
+
+    /// Same as `lookup`, but returns the cell with its locality and labels.
+    pub async fn lookup_full(
+        &self,
+        id: &str,
+        locality: Option<&str>,
+    ) -> Result<Cell, LocatorError> {
+        let cell = self.lookup_cell(id, locality).await?;
+        // Default cells are built at startup, before the control plane returns their labels
+        let read_guard = self.data.read().await;
+        let cell = read_guard.data.cells.get(&cell.id).unwrap_or(&cell);
+        Ok(Cell::clone(cell))
+    }
+
+    async fn lookup_cell(
+        &self,
+        id: &str,
+        locality: Option<&str>,
+    ) -> Result<Arc<Cell>, LocatorError> {
         // Looks up the cell for a given id and locality.
         // Id is generally either an org id, org slug or project key
         // Returns `Ok(Cell)` if found, or a default applies.
@@ -199,17 +661,17 @@ impl IdToCell {
         // Before initial load: skip data/refresh paths and serve via default
         // if one applies, otherwise NotReady.
         if !self.ready.load(Ordering::Relaxed) {
-            return locality
-                .and_then(|loc| self.locality_to_default_cell.get(loc))
-                .map(|cell| cell.id.clone())
-                .ok_or(LocatorError::NotReady);
+            return self.default_cell_when_not_ready(locality).cloned();
         }
 
-        let start_lookup = Instant::now();
+        let start_lookup = self.clock.now();
 
         // Fetch cell and immediately release read lock
         let maybe_cell = {
             let read_guard = self.data.read().await;
+            if read_guard.data.deleted.contains_key(id) {
+                return Err(LocatorError::Deleted);
+            }
             read_guard
                 .data
                 .id_to_cell
@@ -219,48 +681,42 @@ impl IdToCell {
 
         // Check the negative cache and possibly refresh data from control plane
         let maybe_cell = if maybe_cell.is_none() {
-            if self.negative_cache.contains(id) {
+            if self.negative_cache.contains(id) || !self.refresh(start_lookup).await {
                 None
             } else {
-                let (ack_tx, ack_rx) = oneshot::channel::<Result<(), LoadError>>();
-
-                match self.tx.try_send(Command::Refresh(start_lookup, ack_tx)) {
-                    Ok(()) => {
-                        if let Err(err) = ack_rx.await {
-                            tracing::warn!("recv error: {:?}", err);
-                        }
-
-                        // Re-acquire the read lock
-                        let read_guard = self.data.read().await;
-                        let res = read_guard
-                            .data
-                            .id_to_cell
-                            .get(id)
-                            .and_then(|cell_id| read_guard.data.cells.get(cell_id).cloned());
-
-                        // Record still not found after refresh, add to negative cache
-                        if res.is_none() {
-                            self.negative_cache.insert(id);
-                        }
-
-                        res
-                    }
-                    Err(e) => {
-                        // channel is closed or full
-                        tracing::warn!("channel error: {:?}", e);
-                        None
-                    }
+                // Re-acquire the read lock
+                let read_guard = self.data.read().await;
+                if read_guard.data.deleted.contains_key(id) {
+                    return Err(LocatorError::Deleted);
                 }
+                let res = read_guard
+                    .data
+                    .id_to_cell
+                    .get(id)
+                    .and_then(|cell_id| read_guard.data.cells.get(cell_id).cloned());
+
+                // Record still not found after refresh, add to negative cache
+                if res.is_none() {
+                    self.negative_cache.insert(id);
+                }
+
+                res
             }
         } else {
             maybe_cell
         };
 
+        if let (Some(hot_lookups), Some(cell)) = (&self.hot_lookups, &maybe_cell) {
+            hot_lookups.insert(id, cell.clone());
+        }
+
         // If no cell is found, apply the locality default
         let maybe_cell = maybe_cell
             .or_else(|| locality.and_then(|loc| self.locality_to_default_cell.get(loc).cloned()));
 
-        let cell = maybe_cell.ok_or(LocatorError::NoCell)?;
+        let Some(cell) = maybe_cell else {
+            return Err(self.missing_error().await);
+        };
 
         if let Some(requested_locality) = locality
             && cell.locality != requested_locality
@@ -271,7 +727,493 @@ impl IdToCell {
             });
         }
 
-        Ok(cell.id.clone())
+        Ok(cell)
+    }
+
+    // Lookups before the initial load get the locality's default cell, if one applies.
+    // Lookups after shutdown always fail.
+    fn default_cell_when_not_ready(
+        &self,
+        locality: Option<&str>,
+    ) -> Result<&Arc<Cell>, LocatorError> {
+        if self.stopped.load(Ordering::Relaxed) {
+            return Err(LocatorError::NotReady);
+        }
+        locality
+            .and_then(|loc| self.locality_to_default_cell.get(loc))
+            .ok_or(LocatorError::NotReady)
+    }
+
+    /// Same as `lookup` for every id, but with a single pass under the read lock and at
+    /// most one refresh for all ids that are not found.
+    pub async fn lookup_many(
+        &self,
+        ids: &[&str],
+        locality: Option<&str>,
+    ) -> Result<HashMap<String, String>, LocatorError> {
+        if !self.ready.load(Ordering::Relaxed) {
+            let cell = self.default_cell_when_not_ready(locality)?;
+            return Ok(ids
+                .iter()
+                .map(|id| (id.to_string(), cell.id.clone()))
+                .collect());
+        }
+
+        let start_lookup = self.clock.now();
+
+        let mut found = HashMap::with_capacity(ids.len());
+        let mut missing = Vec::new();
+        let mut deleted = HashSet::new();
+        {
+            let read_guard = self.data.read().await;
+            for &id in ids {
+                match read_guard
+                    .data
+                    .id_to_cell
+                    .get(id)
+                    .and_then(|cell_id| read_guard.data.cells.get(cell_id))
+                {
+                    Some(cell) => {
+                        found.insert(id, cell.clone());
+                    }
+                    None if read_guard.data.deleted.contains_key(id) => {
+                        deleted.insert(id);
+                    }
+                    None if !self.negative_cache.contains(id) => missing.push(id),
+                    None => {}
+                }
+            }
+        }
+
+        // One refresh for all ids that are not found and not known to be missing
+        if !missing.is_empty() && self.refresh(start_lookup).await {
+            let read_guard = self.data.read().await;
+            for id in missing {
+                match read_guard
+                    .data
+                    .id_to_cell
+                    .get(id)
+                    .and_then(|cell_id| read_guard.data.cells.get(cell_id))
+                {
+                    Some(cell) => {
+                        found.insert(id, cell.clone());
+                    }
+                    None if read_guard.data.deleted.contains_key(id) => {
+                        deleted.insert(id);
+                    }
+                    None => self.negative_cache.insert(id),
+                }
+            }
+        }
+
+        if let Some(hot_lookups) = &self.hot_lookups {
+            for (id, cell) in &found {
+                hot_lookups.insert(id, cell.clone());
+            }
+        }
+
+        // Ids without a cell get the locality default, unless they were deleted
+        let default_cell = locality.and_then(|loc| self.locality_to_default_cell.get(loc));
+        Ok(ids
+            .iter()
+            .filter(|&id| !deleted.contains(id))
+            .filter_map(|&id| {
+                let cell = found.get(id).or(default_cell)?;
+                let in_locality = locality.is_none_or(|loc| cell.locality == loc);
+                in_locality.then(|| (id.to_string(), cell.id.clone()))
+            })
+            .collect())
+    }
+
+    /// Same as `lookup_many`, with the assignments of the ids that span multiple cells.
+    /// Only the cells in the requested locality are returned.
+    pub async fn lookup_many_multi(
+        &self,
+        ids: &[&str],
+        locality: Option<&str>,
+    ) -> Result<HashMap<String, Vec<CellAssignment>>, LocatorError> {
+        let cells = self.lookup_many(ids, locality).await?;
+
+        let read_guard = self.data.read().await;
+        let in_locality = |assignment: &CellAssignment| {
+            locality.is_none_or(|loc| {
+                read_guard
+                    .data
+                    .cells
+                    .get(&assignment.cell)
+                    .is_some_and(|cell| cell.locality == loc)
+            })
+        };
+        Ok(cells
+            .into_iter()
+            .map(|(id, cell)| {
+                let assignments = read_guard
+                    .data
+                    .id_to_cells
+                    .get(&id)
+                    .map(|assignments| {
+                        assignments
+                            .iter()
+                            .filter(|assignment| in_locality(assignment))
+                            .cloned()
+                            .collect::<Vec<_>>()
+                    })
+                    .filter(|assignments| !assignments.is_empty())
+                    .unwrap_or_else(|| vec![CellAssignment::single(cell)]);
+                (id, assignments)
+            })
+            .collect())
+    }
+
+    /// Asks the loader for a refresh started after `requested_at` and waits until it
+    /// finished. When the command queue is full, the lookup waits for the next refresh,
+    /// waits for room in the queue or gives up, see `RefreshOverflow`. Returns false if no
+    /// refresh was waited for.
+    async fn refresh(&self, requested_at: Instant) -> bool {
+        let (ack_tx, ack_rx) = oneshot::channel::<Result<(), LoadError>>();
+        let command = Command::Refresh(requested_at, ack_tx);
+        self.record_queue_depth();
+
+        let overflow = match self.refresh_overflow {
+            RefreshOverflow::Block { timeout_ms } => {
+                let timeout = Duration::from_millis(timeout_ms);
+                match self.tx.send_timeout(command, timeout).await {
+                    Ok(()) => None,
+                    Err(SendTimeoutError::Timeout(_)) => Some("timed_out"),
+                    Err(SendTimeoutError::Closed(_)) => {
+                        tracing::warn!("Refresh not requested, the loader stopped");
+                        return false;
+                    }
+                }
+            }
+            RefreshOverflow::Coalesce | RefreshOverflow::Drop => match self.tx.try_send(command) {
+                Ok(()) => None,
+                Err(TrySendError::Full(_))
+                    if self.refresh_overflow == RefreshOverflow::Coalesce =>
+                {
+                    Some("coalesced")
+                }
+                Err(TrySendError::Full(_)) => Some("dropped"),
+                Err(TrySendError::Closed(_)) => {
+                    tracing::warn!("Refresh not requested, the loader stopped");
+                    return false;
+                }
+            },
+        };
+
+        match overflow {
+            None => {
+                if let Err(err) = ack_rx.await {
+                    tracing::warn!("recv error: {:?}", err);
+                }
+                true
+            }
+            Some(outcome) => {
+                metrics::counter!(REFRESH_QUEUE_OVERFLOWS.name, "outcome" => outcome).increment(1);
+                if outcome != "coalesced" {
+                    tracing::warn!("Refresh queue full, refresh {outcome}");
+                    return false;
+                }
+                let (done_tx, done_rx) = oneshot::channel();
+                {
+                    let mut coalesced = self.coalesced_refreshes.lock().unwrap();
+                    // The loader no longer answers them after shutdown
+                    if self.stopped.load(Ordering::Relaxed) {
+                        return false;
+                    }
+                    coalesced.push((requested_at, done_tx));
+                }
+                // Only fails if the loader stopped
+                done_rx.await.is_ok()
+            }
+        }
+    }
+
+    fn record_queue_depth(&self) {
+        let depth = self.tx.max_capacity() - self.tx.capacity();
+        metrics::gauge!(REFRESH_QUEUE_DEPTH.name).set(depth as f64);
+    }
+
+    /// Answers a refresh command, along with the lookups that found the queue full since
+    /// the previous refresh. Skips the refresh if the data is recent enough for all of them.
+    async fn handle_refresh(
+        &self,
+        requested_at: Instant,
+        tx: oneshot::Sender<Result<(), LoadError>>,
+    ) {
+        let coalesced = std::mem::take(&mut *self.coalesced_refreshes.lock().unwrap());
+        let requested_at = coalesced
+            .iter()
+            .map(|(requested_at, _)| *requested_at)
+            .fold(requested_at, Instant::max);
+        let last_updated = self.data.read().await.last_updated;
+
+        // Immediately send response if data is up to date, otherwise load incremental updates
+        if self.read_only {
+            // Nothing newer to load than the current backup
+            let _ = tx.send(Ok(()));
+        } else if let Some(updated) = last_updated
+            && updated + self.min_refresh_interval >= requested_at
+        {
+            let _ = tx.send(Ok(()));
+        } else {
+            let _ = tx.send(self.load_incremental().await);
+        }
+        for (_, done) in coalesced {
+            let _ = done.send(());
+        }
+    }
+
+    pub async fn lookup_multi(
+        &self,
+        id: &str,
+        locality: Option<&str>,
+    ) -> Result<Vec<CellAssignment>, LocatorError> {
+        if self.ready.load(Ordering::Relaxed) {
+            let read_guard = self.data.read().await;
+            if let Some(assignments) = read_guard.data.id_to_cells.get(id) {
+                let locality_of = |cell_id: &str| {
+                    read_guard
+                        .data
+                        .cells
+                        .get(cell_id)
+                        .map(|cell| cell.locality.as_str())
+                };
+
+                let Some(requested_locality) = locality else {
+                    return Ok(assignments.clone());
+                };
+
+                // Only cells in the requested locality can serve the id
+                let in_locality: Vec<_> = assignments
+                    .iter()
+                    .filter(|a| locality_of(&a.cell) == Some(requested_locality))
+                    .cloned()
+                    .collect();
+
+                if !in_locality.is_empty() {
+                    return Ok(in_locality);
+                }
+
+                let primary = read_guard.data.id_to_cell.get(id);
+                return Err(LocatorError::LocalityMismatch {
+                    requested: requested_locality.to_string(),
+                    actual: primary
+                        .and_then(|cell_id| locality_of(cell_id))
+                        .unwrap_or_default()
+                        .to_string(),
+                });
+            }
+        }
+
+        // Ids in a single cell, or not loaded yet
+        let cell = self.lookup(id, locality).await?;
+        Ok(vec![CellAssignment::single(cell)])
+    }
+
+    pub async fn rebalance_report(&self) -> Result<RebalanceReport, LocatorError> {
+        if !self.ready.load(Ordering::Relaxed) {
+            return Err(LocatorError::NotReady);
+        }
+
+        let read_guard = self.data.read().await;
+        Ok(RebalanceReport::generate(
+            &read_guard.data,
+            &self.capacity_weights,
+        ))
+    }
+
+    /// Logs the mapped cells that are missing from the catalog, if one is registered.
+    async fn check_catalog(&self) {
+        let read_guard = self.data.read().await;
+        let mut missing: Vec<_> = read_guard
+            .data
+            .cells
+            .keys()
+            .filter(|cell_id| !self.catalog.contains(cell_id))
+            .collect();
+        if !missing.is_empty() {
+            missing.sort();
+            tracing::warn!(?missing, "Ids are mapped to cells missing from the catalog");
+        }
+    }
+
+    pub async fn shard_info(&self) -> Option<ShardInfo> {
+        let shard = self.shard.as_ref()?;
+        Some(ShardInfo {
+            index: shard.index,
+            urls: shard.urls.clone(),
+            keys: self.data.read().await.data.id_to_cell.len() as u64,
+        })
+    }
+
+    pub async fn reshard_plan(&self, count: usize) -> Option<ReshardPlan> {
+        let shard = self.shard.as_ref()?;
+        let read_guard = self.data.read().await;
+        let keys = read_guard.data.id_to_cell.keys().map(String::as_str);
+        Some(ReshardPlan::generate(shard.index, keys, count))
+    }
+
+    pub async fn lookup_stale(
+        &self,
+        id: &str,
+        locality: Option<&str>,
+    ) -> Result<StaleLookup, LocatorError> {
+        // Not stopped once the loader stopped, the last known cell is still served
+        let ready = self.ready.load(Ordering::Relaxed);
+
+        // Unknown ids go through the regular lookup, which refreshes the mappings
+        if ready && !self.data.read().await.data.id_to_cell.contains_key(id) {
+            let cell = self.lookup(id, locality).await?;
+            let read_guard = self.data.read().await;
+            let age = read_guard
+                .last_updated
+                .map(|updated| self.elapsed_since(updated));
+            let freshness = if read_guard.data.id_to_cell.contains_key(id) {
+                self.freshness(ready, age)
+            } else {
+                Freshness::Default
+            };
+            return Ok(StaleLookup {
+                cell,
+                freshness,
+                age_secs: age.map(|age| age.as_secs()),
+            });
+        }
+
+        // Whatever was loaded last, even if the locator is not ready
+        let read_guard = self.data.read().await;
+        let age = read_guard
+            .last_updated
+            .map(|updated| self.elapsed_since(updated));
+        let known = read_guard
+            .data
+            .id_to_cell
+            .get(id)
+            .and_then(|cell_id| read_guard.data.cells.get(cell_id).cloned());
+
+        // Until the first load, ids looked up before the last restart are still known
+        let restored = match (&known, &self.hot_lookups) {
+            (None, Some(hot_lookups)) if !ready => hot_lookups.get(id),
+            _ => None,
+        };
+
+        let (cell, freshness, age) = match (known, restored) {
+            (Some(cell), _) => (cell, self.freshness(ready, age), age),
+            (None, Some(cell)) => (cell, Freshness::Stale, self.restored_age()),
+            (None, None) if read_guard.data.deleted.contains_key(id) => {
+                return Err(LocatorError::Deleted);
+            }
+            (None, None) => match locality.and_then(|loc| self.locality_to_default_cell.get(loc)) {
+                Some(cell) => (cell.clone(), Freshness::Default, age),
+                None if ready => return Err(LocatorError::NoCell),
+                None => return Err(LocatorError::NotReady),
+            },
+        };
+
+        if let Some(requested_locality) = locality
+            && cell.locality != requested_locality
+        {
+            return Err(LocatorError::LocalityMismatch {
+                requested: requested_locality.to_string(),
+                actual: cell.locality.clone(),
+            });
+        }
+
+        Ok(StaleLookup {
+            cell: cell.id.clone(),
+            freshness,
+            age_secs: age.map(|age| age.as_secs()),
+        })
+    }
+
+    async fn stats(&self) -> LocatorStats {
+        let ready = self.ready.load(Ordering::Relaxed);
+        let read_guard = self.data.read().await;
+        let age = read_guard
+            .last_updated
+            .map(|updated| self.elapsed_since(updated));
+        LocatorStats {
+            ready,
+            freshness: self.freshness(ready, age),
+            ids: read_guard.data.id_to_cell.len(),
+            multi_cell_ids: read_guard.data.id_to_cells.len(),
+            deleted_ids: read_guard.data.deleted.len(),
+            cells: read_guard.data.cells.len(),
+            cursor: read_guard.data.last_cursor.clone(),
+            last_refresh_age_secs: age.map(|age| age.as_secs()),
+            last_backup_age_secs: read_guard
+                .last_backup
+                .map(|backup| self.elapsed_since(backup).as_secs()),
+            negative_cache_size: self.negative_cache.entries().len(),
+        }
+    }
+
+    fn readiness(&self) -> Readiness {
+        let mut reasons = Vec::new();
+        let mut status = ReadinessStatus::Ready;
+        if !self.ready.load(Ordering::Relaxed) {
+            status = ReadinessStatus::NotReady;
+            reasons
+                .push("mappings are not loaded yet, or the locator is shutting down".to_string());
+        }
+        let health = self.backup_health.lock().unwrap();
+        for (operation, error) in [
+            ("write", &health.write_error),
+            ("probe", &health.probe_error),
+            ("reload", &health.reload_error),
+        ] {
+            if let Some(error) = error {
+                status = status.max(ReadinessStatus::Degraded);
+                reasons.push(format!("backup {operation} failed: {error}"));
+            }
+        }
+        Readiness { status, reasons }
+    }
+
+    /// Age of the mappings the restored hot lookups were taken from
+    fn restored_age(&self) -> Option<Duration> {
+        let updated_at = *self.restored_updated_at.get()?;
+        Some(Duration::from_secs(
+            self.clock.unix_now().saturating_sub(updated_at),
+        ))
+    }
+
+    /// Error of a lookup that found no cell. The id is unknown if the mappings are fresh,
+    /// otherwise it may have been created after the last successful refresh.
+    async fn missing_error(&self) -> LocatorError {
+        let age = self
+            .data
+            .read()
+            .await
+            .last_updated
+            .map(|updated| self.elapsed_since(updated));
+        match self.freshness(self.ready.load(Ordering::Relaxed), age) {
+            Freshness::Fresh => LocatorError::NoCell,
+            _ => LocatorError::NotYetSynced,
+        }
+    }
+
+    /// Mappings are fresh if the locator is ready and the last refresh from the control
+    /// plane succeeded within two refresh intervals.
+    fn freshness(&self, ready: bool, age: Option<Duration>) -> Freshness {
+        match age {
+            Some(age) if ready && age <= 2 * self.refresh_interval => Freshness::Fresh,
+            _ => Freshness::Stale,
+        }
+    }
+
+    pub async fn lookup_at(&self, id: &str, at: u64) -> Result<String, LocatorError> {
+        if !self.ready.load(Ordering::Relaxed) {
+            return Err(LocatorError::NotReady);
+        }
+
+        let read_guard = self.data.read().await;
+        read_guard
+            .data
+            .history
+            .cell_at(id, at, read_guard.data.id_to_cell.get(id))
+            .ok_or(LocatorError::NoCell)
     }
 
     /// Performs an initial full load, then periodically reloads
@@ -279,52 +1221,77 @@ impl IdToCell {
     /// command is received. The loop runs indefinitely until the Shutdown
     /// command is received.
     pub async fn start(&self, mut rx: mpsc::Receiver<Command>) -> Result<(), LoadError> {
+        let started = self.clock.now();
+        self.restore_warm_cache().await;
+        self.startup.send_replace(Startup::CacheRestored);
+
         // With defaults configured, a failed initial load is non-fatal:
         // the process stays up, /ready returns 503, and the periodic loop
         // retries. Without defaults, fail fast as before.
         match self.load_snapshot().await {
-            Ok(()) => self.ready.store(true, Ordering::Relaxed),
+            Ok(()) => {
+                self.ready.store(true, Ordering::Relaxed);
+                self.check_catalog().await;
+            }
             Err(err) if !self.locality_to_default_cell.is_empty() => {
                 tracing::warn!("Initial snapshot load failed: {err:?}; will retry");
             }
             Err(err) => return Err(err),
         }
+        self.startup.send_replace(Startup::Loaded);
 
         // Once a snapshot is loaded, the worker periodically requests incremental results
         // until the Shutdown command is received.
         // If the Refresh command is received, the incremental load can be triggered ahead
-        // of schedule.
+        // of schedule. Read-only locators reload the backup instead, which is written
+        // elsewhere at most once per backup interval.
+        let interval = if self.read_only {
+            self.backup_interval
+        } else {
+            self.refresh_interval
+        };
+        // Read-only locators only read the backup, which their reloads already check
+        let mut probe_interval = tokio::time::interval(self.backup_probe_interval);
+        probe_interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        let mut probe = None;
         loop {
             tokio::select! {
-                _ = tokio::time::sleep(self.refresh_interval) => {
+                _ = probe_interval.tick(), if !self.read_only => {
+                    self.probe_backup(&mut probe);
+                }
+                _ = tokio::time::sleep(interval) => {
+                    // Lookups that found the queue full after its last refresh command
+                    let coalesced = std::mem::take(&mut *self.coalesced_refreshes.lock().unwrap());
                     // If the initial snapshot failed, keep retrying it (which also writes
                     // the backup file on success) until we're ready. Only then move to
                     // incremental updates for steady state.
-                    if self.ready.load(Ordering::Relaxed) {
+                    if self.ready.load(Ordering::Relaxed) && !self.read_only {
                         if let Err(err) = self.load_incremental().await {
                             tracing::warn!("Incremental load failed: {err:?}; will retry");
                         }
                     } else {
                         match self.load_snapshot().await {
-                            Ok(()) => self.ready.store(true, Ordering::Relaxed),
+                            Ok(()) => {
+                                self.ready.store(true, Ordering::Relaxed);
+                                self.check_catalog().await;
+                            }
                             Err(err) => tracing::warn!("Snapshot retry failed: {err:?}; will retry"),
                         }
                     }
+                    for (_, done) in coalesced {
+                        let _ = done.send(());
+                    }
+                    self.check_staleness(started).await;
                 }
                 Some(cmd) = rx.recv() => {
+                    self.record_queue_depth();
                     match cmd {
                         Command::Refresh(requested_at, tx) => {
-                            let last_updated = self.data.read().await.last_updated;
-
-                            // Immediately send response if data is up to date, otherwise load incremental updates
-                            if let Some(updated) = last_updated && updated + self.min_refresh_interval >= requested_at {
-                                let _ = tx.send(Ok(()));
-                            } else {
-                                let _ = tx.send(self.load_incremental().await);
-                            }
+                            self.handle_refresh(requested_at, tx).await;
                         }
                         Command::Shutdown => {
                             // Mark the locator as no longer ready and exit the loop
+                            self.stopped.store(true, Ordering::Relaxed);
                             self.ready.store(false, Ordering::Relaxed);
                             break;
                         },
@@ -333,55 +1300,170 @@ impl IdToCell {
             }
         }
 
+        // Lookups waiting for a refresh that will not happen
+        self.coalesced_refreshes.lock().unwrap().clear();
+        if let Some(probe) = probe {
+            probe.abort();
+        }
+        self.store_final_backup().await;
+
         Ok(())
     }
 
-    /// Loads the entire mapping in pages from the control plane.
-    /// If the control plane is unreachable, fall back to stored local copy.
-    /// This function should attempt to fetch data from the control plane.
-    /// Once the configured retries have been exhausted, it will attempt to
-    /// load from the backup route provider.
+    /// Stores the mappings refreshed from the control plane since the last backup, so that
+    /// the next start does not fall back to older ones.
+    async fn store_final_backup(&self) {
+        if self.read_only {
+            return;
+        }
+        let snapshot = {
+            let mut write_guard = self.data.write().await;
+            let refreshed = match (write_guard.last_updated, write_guard.last_backup) {
+                (Some(updated), Some(backup)) => updated > backup,
+                (Some(_), None) => true,
+                (None, _) => false,
+            };
+            refreshed.then(|| self.backup_snapshot(&mut write_guard))
+        };
+        if let Some(snapshot) = snapshot {
+            self.store_backup(&snapshot).await;
+        }
+    }
+
+    /// Loads the entire mapping in pages from the control plane.
+    /// If the control plane is unreachable, fall back to stored local copy.
+    /// This function should attempt to fetch data from the control plane.
+    /// Once the configured retries have been exhausted, it will attempt to
+    /// load from the backup route provider.
     async fn load_snapshot(&self) -> Result<(), LoadError> {
-        let mut snapshot_requested_time: Option<Instant> = Some(Instant::now());
+        if self.read_only {
+            return self.load_backup().await;
+        }
+
+        let mut snapshot_requested_time: Option<Instant> = Some(self.clock.now());
 
         // Hold permit for the duration of this function
         let _permit = self.get_permit().await?;
 
         // Fetch data from the control plane. If unavailable fallback to the backup route provider.
         let route_data = match self.control_plane.load_mappings(None).await {
-            Ok(data) => data,
+            Ok(data) => {
+                self.alert(|alerts| alerts.sync_succeeded());
+                data
+            }
             Err(err) => {
+                self.alert(|alerts| alerts.sync_failed(&err));
                 tracing::warn!(
                     "Error loading from control plane: {err:?}, falling back to backup route provider"
                 );
 
                 snapshot_requested_time = None;
 
-                // Load from the backup route provider
-                self.backup_routes.load().await?
+                self.load_backup_data().await?
             }
         };
 
+        // The control plane does not provide the history. When nothing has been loaded yet,
+        // the history is continued from the backup and changes are recorded against the
+        // backup's mappings.
+        let backup = if snapshot_requested_time.is_some()
+            && self.data.read().await.data.id_to_cell.is_empty()
+        {
+            self.backup_routes
+                .load()
+                .await
+                .inspect_err(|e| tracing::warn!("Failed to load history from backup: {e:?}"))
+                .ok()
+        } else {
+            None
+        };
+
         let mut write_guard = self.data.write().await;
 
+        let data = &mut write_guard.data;
+        if let Some(backup) = backup {
+            data.history = backup.history;
+            data.id_to_cell = backup.id_to_cell;
+            data.deleted = backup.deleted;
+        } else if data.id_to_cell.is_empty() {
+            data.history = route_data.history;
+        }
+        if !data.id_to_cell.is_empty() {
+            data.history.record_changes(
+                &data.id_to_cell,
+                &route_data.id_to_cell,
+                self.clock.unix_now(),
+            );
+        }
+
         write_guard.data.id_to_cell = route_data.id_to_cell;
+        write_guard.data.id_to_cells = route_data.id_to_cells;
         write_guard.data.last_cursor = route_data.last_cursor;
         write_guard.data.cells = route_data.cells;
+        let previous = std::mem::take(&mut write_guard.data.deleted);
+        write_guard.data.deleted = self.seen_tombstones(route_data.deleted, &previous);
         write_guard.last_updated = snapshot_requested_time;
 
         // Store the backup if we successfully loaded from the control plane
-        if snapshot_requested_time.is_some()
-            && let Err(e) = self.backup_routes.store(&write_guard.data).await
-        {
-            tracing::error!("Failed to store backup routes: {e:?}");
+        let snapshot = snapshot_requested_time
+            .is_some()
+            .then(|| self.backup_snapshot(&mut write_guard));
+        drop(write_guard);
+        if let Some(snapshot) = snapshot {
+            self.store_backup(&snapshot).await;
+        }
+
+        Ok(())
+    }
+
+    /// Replaces the mappings with the backup's, for read-only locators. The mappings are
+    /// never considered fresh, since the backup may be old.
+    async fn load_backup(&self) -> Result<(), LoadError> {
+        let _permit = self.get_permit().await?;
+        let route_data = self
+            .load_backup_data()
+            .await
+            .inspect(|_| self.backup_health.lock().unwrap().reload_error = None)
+            .inspect_err(|e| {
+                self.backup_health.lock().unwrap().reload_error = Some(e.to_string());
+            })?;
+
+        let mut write_guard = self.data.write().await;
+        let data = &mut write_guard.data;
+        if data.id_to_cell.is_empty() {
+            data.history = route_data.history;
+        } else {
+            data.history.record_changes(
+                &data.id_to_cell,
+                &route_data.id_to_cell,
+                self.clock.unix_now(),
+            );
         }
+        data.id_to_cell = route_data.id_to_cell;
+        data.id_to_cells = route_data.id_to_cells;
+        data.last_cursor = route_data.last_cursor;
+        data.cells = route_data.cells;
 
         Ok(())
     }
 
+    /// Loads from the backup route provider. The backup may have been written before the
+    /// shard topology changed, or by a locator serving other localities.
+    async fn load_backup_data(&self) -> Result<RouteData, LoadError> {
+        let mut route_data = self.backup_routes.load().await?;
+        if let Some(shard) = &self.shard {
+            route_data.id_to_cell.retain(|id, _| shard.owns(id));
+            route_data.id_to_cells.retain(|id, _| shard.owns(id));
+        }
+        if let Some(localities) = &self.localities {
+            route_data.retain_localities(localities);
+        }
+        Ok(route_data)
+    }
+
     /// Load incremental updates from the control plane.
     async fn load_incremental(&self) -> Result<(), LoadError> {
-        let incremental_requested_time = Instant::now();
+        let incremental_requested_time = self.clock.now();
 
         // Hold permit for the duration of this function
         let _permit = self.get_permit().await?;
@@ -396,18 +1478,206 @@ impl IdToCell {
         let route_data = self
             .control_plane
             .load_mappings(current_cursor.as_deref())
-            .await?;
+            .await
+            .inspect(|_| self.alert(|alerts| alerts.sync_succeeded()))
+            .inspect_err(|err| self.alert(|alerts| alerts.sync_failed(err)))?;
 
         // Merge the incremental data with the existing data
         let mut write_guard = self.data.write().await;
+        // Updated ids may have moved from multiple cells back to a single cell, or have
+        // been recreated after they were deleted
+        for id in route_data.id_to_cell.keys() {
+            write_guard.data.id_to_cells.remove(id);
+            write_guard.data.deleted.remove(id);
+        }
+        for id in route_data.deleted.keys() {
+            write_guard.data.id_to_cell.remove(id);
+            write_guard.data.id_to_cells.remove(id);
+        }
+        let data = &mut write_guard.data;
+        data.history.record_changes(
+            &data.id_to_cell,
+            &route_data.id_to_cell,
+            self.clock.unix_now(),
+        );
         write_guard.data.id_to_cell.extend(route_data.id_to_cell);
+        write_guard.data.id_to_cells.extend(route_data.id_to_cells);
         write_guard.data.last_cursor = route_data.last_cursor;
         write_guard.data.cells.extend(route_data.cells);
+        let previous = std::mem::take(&mut write_guard.data.deleted);
+        let mut deleted = self.seen_tombstones(route_data.deleted, &previous);
+        deleted.extend(self.seen_tombstones(previous, &HashMap::new()));
+        write_guard.data.deleted = deleted;
         write_guard.last_updated = Some(incremental_requested_time);
 
+        // Periodically flush to the backup route provider. This also lets a standby replica
+        // take over backup writes once the current writer stops renewing its lease.
+        let snapshot = write_guard
+            .last_backup
+            .is_none_or(|last_backup| self.elapsed_since(last_backup) >= self.backup_interval)
+            .then(|| self.backup_snapshot(&mut write_guard));
+        drop(write_guard);
+        if let Some(snapshot) = snapshot {
+            self.store_backup(&snapshot).await;
+        }
+
         Ok(())
     }
 
+    /// Copies the mappings to store in the backup, so that the backup is written without
+    /// holding the lock. The next backup is due after the backup interval, even if this one
+    /// fails.
+    /// Tombstones with the time they were first seen: the time in `previous` for known
+    /// tombstones, now for tombstones not seen yet. Tombstones seen longer than the
+    /// retention ago are dropped.
+    fn seen_tombstones(
+        &self,
+        mut deleted: HashMap<String, u64>,
+        previous: &HashMap<String, u64>,
+    ) -> HashMap<String, u64> {
+        let now = self.clock.unix_now();
+        deleted.retain(|id, seen_at| {
+            if *seen_at == 0 {
+                *seen_at = previous.get(id).copied().unwrap_or(now);
+            }
+            now.saturating_sub(*seen_at) < self.tombstone_retention.as_secs()
+        });
+        deleted
+    }
+
+    fn backup_snapshot(&self, data: &mut RouteDataWithTimestamp) -> RouteData {
+        data.last_backup = Some(self.clock.now());
+        data.data.clone()
+    }
+
+    async fn store_backup(&self, data: &RouteData) {
+        match self.backup_routes.store(data).await {
+            Ok(()) => {
+                self.backup_health.lock().unwrap().write_error = None;
+                self.alert(|alerts| alerts.backup_written());
+            }
+            Err(e) => {
+                tracing::error!("Failed to store backup routes: {e:?}");
+                self.backup_health.lock().unwrap().write_error = Some(e.to_string());
+                self.alert(|alerts| alerts.backup_write_failed(&e));
+            }
+        }
+    }
+
+    /// Stores and loads back a temporary copy of an empty mapping, so that a failing backup
+    /// route provider is noticed before the backup is needed. The backup itself is untouched.
+    /// The probe runs in the background, so that a slow provider does not hold up refreshes,
+    /// and is skipped while the previous one is still running.
+    fn probe_backup(&self, running: &mut Option<tokio::task::JoinHandle<()>>) {
+        if running.as_ref().is_some_and(|probe| !probe.is_finished()) {
+            return;
+        }
+        let backup_routes = self.backup_routes.clone();
+        let backup_health = self.backup_health.clone();
+        *running = Some(tokio::spawn(async move {
+            let probe = RouteData::from(HashMap::new(), None, HashMap::new());
+            let probe_error = match backup_routes.round_trip(&probe).await {
+                Ok(_) => None,
+                Err(e) => {
+                    tracing::warn!("Backup route provider probe failed: {e:?}");
+                    Some(e.to_string())
+                }
+            };
+            backup_health.lock().unwrap().probe_error = probe_error;
+        }));
+    }
+
+    async fn restore_warm_cache(&self) {
+        let Some(path) = &self.warm_cache_path else {
+            return;
+        };
+        let dump = match WarmCacheDump::read(path).await {
+            Ok(Some(dump)) => dump,
+            Ok(None) => return,
+            Err(e) => {
+                tracing::warn!("Failed to read warm cache: {e:?}");
+                return;
+            }
+        };
+
+        let now = self.clock.unix_now();
+        let negative: Vec<_> = dump
+            .negative
+            .into_iter()
+            .filter(|entry| entry.expires_at > now)
+            .collect();
+        for entry in &negative {
+            self.negative_cache
+                .insert_for(&entry.id, Duration::from_secs(entry.expires_at - now));
+        }
+        let hot = dump.hot.len();
+        if let Some(hot_lookups) = &self.hot_lookups {
+            for entry in dump.hot {
+                let cell = Cell::new(entry.cell, entry.locality);
+                hot_lookups.insert(&entry.id, Arc::new(cell));
+            }
+        }
+        if let Some(updated_at) = dump.updated_at {
+            let _ = self.restored_updated_at.set(updated_at);
+        }
+
+        tracing::info!(negative = negative.len(), hot, "Restored warm cache");
+    }
+
+    async fn dump_warm_cache(&self) {
+        let Some(path) = &self.warm_cache_path else {
+            return;
+        };
+
+        let now = self.clock.unix_now();
+        let last_updated = self.data.read().await.last_updated;
+        let updated_at = last_updated
+            .map(|updated| now.saturating_sub(self.elapsed_since(updated).as_secs()))
+            .or(self.restored_updated_at.get().copied());
+        let dump = WarmCacheDump {
+            updated_at,
+            negative: self
+                .negative_cache
+                .entries()
+                .into_iter()
+                .map(|(id, ttl)| NegativeEntry {
+                    id,
+                    expires_at: now + ttl.as_secs(),
+                })
+                .collect(),
+            hot: self
+                .hot_lookups
+                .as_ref()
+                .map(|hot_lookups| hot_lookups.entries())
+                .unwrap_or_default(),
+        };
+
+        if let Err(e) = dump.write(path).await {
+            tracing::error!("Failed to write warm cache: {e:?}");
+        }
+    }
+
+    fn alert(&self, f: impl FnOnce(&Alerts)) {
+        if let Some(alerts) = &self.alerts {
+            f(alerts);
+        }
+    }
+
+    /// Alerts if the mappings were not synced from the control plane for too long, or not
+    /// since the locator started. Read-only locators never sync.
+    async fn check_staleness(&self, started: Instant) {
+        if let Some(alerts) = &self.alerts
+            && !self.read_only
+        {
+            let last_updated = self.data.read().await.last_updated;
+            alerts.check_staleness(self.elapsed_since(last_updated.unwrap_or(started)));
+        }
+    }
+
+    fn elapsed_since(&self, instant: Instant) -> Duration {
+        self.clock.now().saturating_duration_since(instant)
+    }
+
     /// Guard that ensures only one load operation is in progress at a time.
     async fn get_permit(&self) -> Result<SemaphorePermit<'_>, AcquireError> {
         self.update_lock.acquire().await
@@ -418,8 +1688,11 @@ impl IdToCell {
 mod tests {
     use super::*;
     use crate::backup_routes::FilesystemRouteProvider;
+    use crate::clock::MockClock;
     use crate::config;
     use crate::testutils::TestControlPlaneServer;
+    use std::collections::BTreeMap;
+    use std::path::Path;
     use std::time::Duration;
 
     async fn get_mock_provider() -> (tempfile::TempDir, Arc<FilesystemRouteProvider>) {
@@ -443,6 +1716,26 @@ mod tests {
         (dir, Arc::new(provider))
     }
 
+    /// Lets the spawned tasks run until they all wait, e.g. for a lock
+    async fn settle() {
+        for _ in 0..100 {
+            tokio::task::yield_now().await;
+        }
+    }
+
+    fn control_plane_config(url: String) -> config::ControlPlane {
+        config::ControlPlane {
+            url,
+            // Fall back to the backup provider immediately when the control plane is down
+            retry: config::RetryPolicy {
+                max_retries: 0,
+                ..Default::default()
+            },
+            pagination: Default::default(),
+            signing: None,
+        }
+    }
+
     #[tokio::test]
     async fn test_locator_control_plane_available() {
         // Control plane available, use results from control plane
@@ -455,7 +1748,7 @@ mod tests {
 
         let locator = Locator::new(
             LocatorDataType::Organization,
-            format!("http://{}:{}", host, server.port),
+            control_plane_config(format!("http://{}:{}", host, server.port)),
             provider.clone(),
             None,
             Some(HashMap::from([("de".into(), "de".into())])),
@@ -466,8 +1759,7 @@ mod tests {
             Err(LocatorError::NotReady)
         );
 
-        // Wait for control plane
-        tokio::time::sleep(Duration::from_millis(100)).await;
+        locator.wait_for_startup(Startup::Loaded).await;
 
         // org "0" is in the control plane
         assert_eq!(locator.lookup("0", Some("us")).await, Ok("us1".into()));
@@ -481,67 +1773,1193 @@ mod tests {
         // Org "0" should be written to the backup provider
         let provider_data = provider.load().await.unwrap();
         assert_eq!(provider_data.id_to_cell.get("0").unwrap(), "us1");
+        assert_eq!(
+            locator.readiness(),
+            Readiness {
+                status: ReadinessStatus::Ready,
+                reasons: Vec::new(),
+            }
+        );
     }
 
     #[tokio::test]
-    async fn test_locator_control_plane_unavailable() {
-        // Control plane unavailable, load from backup provider
+    async fn test_readiness_with_failing_backup() {
+        let host = "127.0.0.1";
+        let server = TestControlPlaneServer::spawn(host).unwrap();
+
+        // Backups cannot be written to a directory that does not exist
+        let dir = tempfile::tempdir().unwrap();
+        let provider = FilesystemRouteProvider::new(
+            dir.path().join("missing").to_str().unwrap(),
+            "backup.bin",
+            config::Compression::None,
+        );
+        let locator = Locator::new(
+            LocatorDataType::Organization,
+            control_plane_config(format!("http://{}:{}", host, server.port)),
+            Arc::new(provider),
+            None,
+            None,
+        );
+        assert_eq!(locator.readiness().status, ReadinessStatus::NotReady);
+        locator.wait_for_startup(Startup::Loaded).await;
+        // The probe runs in the background
+        tokio::time::timeout(Duration::from_secs(10), async {
+            while locator.readiness().reasons.len() < 2 {
+                tokio::task::yield_now().await;
+            }
+        })
+        .await
+        .unwrap();
+
+        // Lookups are answered, but the write and the probe failed
+        assert!(locator.is_ready());
+        assert_eq!(locator.lookup("0", None).await, Ok("us1".into()));
+        let readiness = locator.readiness();
+        assert_eq!(readiness.status, ReadinessStatus::Degraded);
+        assert_eq!(readiness.reasons.len(), 2, "{readiness:?}");
+        assert!(readiness.reasons[0].starts_with("backup write failed"));
+        assert!(readiness.reasons[1].starts_with("backup probe failed"));
+    }
+
+    #[tokio::test]
+    async fn test_lookup_at() {
+        let host = "127.0.0.1";
+        let server = TestControlPlaneServer::spawn(host).unwrap();
+
+        // The backup has org "0" in de since it moved there from us1 at 100
+        let mut route_data = RouteData::from(
+            HashMap::from([("0".into(), "de".into())]),
+            Some("cursor1".into()),
+            HashMap::from([("us1".into(), "us".into()), ("de".into(), "de".into())]),
+        );
+        route_data.history.record_changes(
+            &HashMap::from([("0".into(), "us1".into())]),
+            &route_data.id_to_cell,
+            100,
+        );
+
+        let dir = tempfile::tempdir().unwrap();
+        let provider = FilesystemRouteProvider::new(
+            dir.path().to_str().unwrap(),
+            "backup.bin",
+            config::Compression::None,
+        );
+        provider.store(&route_data).await.unwrap();
+
+        let locator = Locator::new(
+            LocatorDataType::Organization,
+            control_plane_config(format!("http://{}:{}", host, server.port)),
+            Arc::new(provider),
+            None,
+            None,
+        );
+
+        assert_eq!(locator.lookup_at("0", 0).await, Err(LocatorError::NotReady));
+
+        locator.wait_for_startup(Startup::Loaded).await;
+
+        // The control plane has org "0" back in us1, the move is recorded on load
+        assert_eq!(locator.lookup("0", None).await, Ok("us1".into()));
+        assert_eq!(locator.lookup_at("0", 50).await, Ok("us1".into()));
+        assert_eq!(locator.lookup_at("0", 150).await, Ok("de".into()));
+        assert_eq!(locator.lookup_at("0", u64::MAX).await, Ok("us1".into()));
+
+        assert_eq!(
+            locator.lookup_at("invalid_org", 150).await,
+            Err(LocatorError::NoCell)
+        );
+    }
 
+    #[tokio::test]
+    async fn test_lookup_full() {
+        let host = "127.0.0.1";
+        let server = TestControlPlaneServer::spawn(host).unwrap();
         let (_dir, provider) = get_mock_provider().await;
 
         let locator = Locator::new(
             LocatorDataType::Organization,
-            "http://invalid-control-plane:8000".to_string(),
+            control_plane_config(format!("http://{}:{}", host, server.port)),
             provider,
             None,
             Some(HashMap::from([("de".into(), "de".into())])),
         );
 
+        locator.wait_for_startup(Startup::Loaded).await;
+
         assert_eq!(
-            locator.lookup("org_0", None).await,
-            Err(LocatorError::NotReady)
+            locator.lookup_full("0", Some("us")).await,
+            Ok(Cell {
+                id: "us1".into(),
+                locality: "us".into(),
+                labels: BTreeMap::from([("region".into(), "us-east1".into())]),
+            })
+        );
+        // Cells without labels in the control plane, such as the default cell
+        assert_eq!(
+            locator.lookup_full("missing", Some("de")).await,
+            Ok(Cell::new("de", "de"))
         );
+        assert_eq!(
+            locator.lookup_full("missing", None).await,
+            Err(LocatorError::NoCell)
+        );
+    }
 
-        // Sleep because of retries
-        tokio::time::sleep(Duration::from_millis(100)).await;
+    #[tokio::test]
+    async fn test_lookup_stale() {
+        let host = "127.0.0.1";
+        let server = TestControlPlaneServer::spawn(host).unwrap();
+        let (_dir, provider) = get_mock_provider().await;
+
+        let locator = Locator::new(
+            LocatorDataType::Organization,
+            control_plane_config(format!("http://{}:{}", host, server.port)),
+            provider,
+            None,
+            Some(HashMap::from([("de".into(), "de".into())])),
+        );
 
+        // Nothing loaded yet, only the default applies
         assert_eq!(
-            locator.lookup("0", Some("us")).await,
-            Err(LocatorError::NoCell)
+            locator.lookup_stale("0", None).await,
+            Err(LocatorError::NotReady)
+        );
+        assert_eq!(
+            locator.lookup_stale("0", Some("de")).await,
+            Ok(StaleLookup {
+                cell: "de".into(),
+                freshness: Freshness::Default,
+                age_secs: None,
+            })
         );
 
-        // Valid org and locality
-        assert_eq!(locator.lookup("org_0", Some("us")).await, Ok("us1".into()));
+        locator.wait_for_startup(Startup::Loaded).await;
 
-        // Invalid org, no default
         assert_eq!(
-            locator.lookup("invalid_org", Some("us")).await,
+            locator.lookup_stale("0", Some("us")).await,
+            Ok(StaleLookup {
+                cell: "us1".into(),
+                freshness: Freshness::Fresh,
+                age_secs: Some(0),
+            })
+        );
+        assert_eq!(
+            locator
+                .lookup_stale("invalid_org", Some("de"))
+                .await
+                .unwrap()
+                .freshness,
+            Freshness::Default
+        );
+        assert_eq!(
+            locator.lookup_stale("invalid_org", None).await,
             Err(LocatorError::NoCell)
         );
 
-        // Wrong locality requested
+        // The last known cell is still returned once the locator is no longer ready
+        locator.shutdown().await;
+        assert_eq!(locator.lookup("0", None).await, Err(LocatorError::NotReady));
         assert_eq!(
-            locator.lookup("org_1", Some("de")).await,
+            locator.lookup_stale("0", None).await.unwrap().freshness,
+            Freshness::Stale
+        );
+        assert_eq!(
+            locator.lookup_stale("0", Some("de")).await,
             Err(LocatorError::LocalityMismatch {
                 requested: "de".to_string(),
-                actual: "us".to_string()
+                actual: "us".to_string(),
             })
         );
+    }
+
+    #[tokio::test]
+    async fn test_freshness_with_mock_clock() {
+        let host = "127.0.0.1";
+        let server = TestControlPlaneServer::spawn(host).unwrap();
+        let clock = Arc::new(MockClock::new(1000));
+
+        // The backup has org "0" in de, the control plane has it in us1
+        let route_data = RouteData::from(
+            HashMap::from([("0".into(), "de".into())]),
+            Some("cursor1".into()),
+            HashMap::from([("us1".into(), "us".into()), ("de".into(), "de".into())]),
+        );
+        let dir = tempfile::tempdir().unwrap();
+        let provider = FilesystemRouteProvider::new(
+            dir.path().to_str().unwrap(),
+            "backup.bin",
+            config::Compression::None,
+        );
+        provider.store(&route_data).await.unwrap();
 
-        // Valid org, no locality
-        assert_eq!(locator.lookup("org_2", None).await, Ok("de".into()));
+        let locator = Locator::with_options(
+            LocatorDataType::Organization,
+            control_plane_config(format!("http://{}:{}", host, server.port)),
+            Arc::new(provider),
+            None,
+            None,
+            LocatorOptions {
+                clock: clock.clone(),
+                ..Default::default()
+            },
+        );
+        locator.wait_for_startup(Startup::Loaded).await;
 
-        // Default cell is used when org_id is not found
+        // The move is recorded at the clock's time
+        assert_eq!(locator.lookup_at("0", 999).await, Ok("de".into()));
+        assert_eq!(locator.lookup_at("0", 1000).await, Ok("us1".into()));
         assert_eq!(
-            locator.lookup("invalid_org", Some("de")).await,
-            Ok("de".into())
+            locator.lookup_stale("0", None).await,
+            Ok(StaleLookup {
+                cell: "us1".into(),
+                freshness: Freshness::Fresh,
+                age_secs: Some(0),
+            })
         );
 
-        // No default cell for locality returns error
+        // Fresh up to two refresh intervals after the last refresh
+        clock.advance(Duration::from_secs(120));
         assert_eq!(
-            locator.lookup("invalid_org", Some("us")).await,
+            locator.lookup_stale("0", None).await.unwrap().freshness,
+            Freshness::Fresh
+        );
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(
+            locator.lookup_stale("0", None).await,
+            Ok(StaleLookup {
+                cell: "us1".into(),
+                freshness: Freshness::Stale,
+                age_secs: Some(121),
+            })
+        );
+
+        let stats = locator.stats().await;
+        assert!(stats.ready);
+        assert_eq!(stats.freshness, Freshness::Stale);
+        assert_eq!(stats.last_refresh_age_secs, Some(121));
+        assert!(stats.ids > 0);
+        assert!(stats.cursor.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_warm_cache_restart() {
+        let host = "127.0.0.1";
+        let server = TestControlPlaneServer::spawn(host).unwrap();
+        let clock = Arc::new(MockClock::new(1000));
+        let dir = tempfile::tempdir().unwrap();
+        let warm_cache = config::WarmCache {
+            path: dir.path().join("warm_cache.json").to_str().unwrap().into(),
+            max_hot_ids: 100,
+        };
+        let options = || LocatorOptions {
+            clock: clock.clone(),
+            warm_cache: Some(warm_cache.clone()),
+            ..Default::default()
+        };
+
+        let (_backup_dir, provider) = get_mock_provider().await;
+        let locator = Locator::with_options(
+            LocatorDataType::Organization,
+            control_plane_config(format!("http://{}:{}", host, server.port)),
+            provider,
+            None,
+            None,
+            options(),
+        );
+        locator.wait_for_startup(Startup::Loaded).await;
+        assert_eq!(locator.lookup("0", None).await, Ok("us1".into()));
+        assert_eq!(
+            locator.lookup("invalid_org", None).await,
             Err(LocatorError::NoCell)
         );
+        locator.shutdown().await;
+
+        // Restarted with a control plane that never responds, so it does not become ready
+        clock.advance(Duration::from_secs(3));
+        let unresponsive = shared::testutils::MockServer::stalled().await;
+        let empty_dir = tempfile::tempdir().unwrap();
+        let locator = Locator::with_options(
+            LocatorDataType::Organization,
+            control_plane_config(unresponsive.url()),
+            Arc::new(FilesystemRouteProvider::new(
+                empty_dir.path().to_str().unwrap(),
+                "backup.bin",
+                config::Compression::None,
+            )),
+            None,
+            None,
+            options(),
+        );
+        // The snapshot never loads
+        locator.wait_for_startup(Startup::CacheRestored).await;
+
+        // Ids looked up before the restart are served stale
+        assert_eq!(locator.lookup("0", None).await, Err(LocatorError::NotReady));
+        assert_eq!(
+            locator.lookup_stale("0", None).await,
+            Ok(StaleLookup {
+                cell: "us1".into(),
+                freshness: Freshness::Stale,
+                age_secs: Some(3),
+            })
+        );
+        assert_eq!(
+            locator.lookup_stale("org_0", None).await,
+            Err(LocatorError::NotReady)
+        );
+
+        // The negative cache entry added at 1000 is restored with its remaining TTL and
+        // dumped again. The worker is still loading, so the dump is triggered directly.
+        locator.inner.id_to_cell_map.dump_warm_cache().await;
+        let dump = WarmCacheDump::read(Path::new(&warm_cache.path))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(dump.updated_at, Some(1000));
+        assert_eq!(
+            dump.negative,
+            vec![NegativeEntry {
+                id: "invalid_org".into(),
+                expires_at: 1005,
+            }]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_locator_control_plane_unavailable() {
+        // Control plane unavailable, load from backup provider
+
+        let (_dir, provider) = get_mock_provider().await;
+
+        let locator = Locator::new(
+            LocatorDataType::Organization,
+            control_plane_config("http://invalid-control-plane:8000".to_string()),
+            provider,
+            None,
+            Some(HashMap::from([("de".into(), "de".into())])),
+        );
+
+        assert_eq!(
+            locator.lookup("org_0", None).await,
+            Err(LocatorError::NotReady)
+        );
+
+        locator.wait_for_startup(Startup::Loaded).await;
+
+        // The backup may be missing orgs created since it was written
+        assert_eq!(
+            locator.lookup("0", Some("us")).await,
+            Err(LocatorError::NotYetSynced)
+        );
+
+        // Valid org and locality
+        assert_eq!(locator.lookup("org_0", Some("us")).await, Ok("us1".into()));
+
+        // Mappings from the backup are never considered fresh
+        assert_eq!(
+            locator.lookup_stale("org_0", Some("us")).await,
+            Ok(StaleLookup {
+                cell: "us1".into(),
+                freshness: Freshness::Stale,
+                age_secs: None,
+            })
+        );
+
+        // Invalid org, no default
+        assert_eq!(
+            locator.lookup("invalid_org", Some("us")).await,
+            Err(LocatorError::NotYetSynced)
+        );
+
+        // Wrong locality requested
+        assert_eq!(
+            locator.lookup("org_1", Some("de")).await,
+            Err(LocatorError::LocalityMismatch {
+                requested: "de".to_string(),
+                actual: "us".to_string()
+            })
+        );
+
+        // Valid org, no locality
+        assert_eq!(locator.lookup("org_2", None).await, Ok("de".into()));
+
+        // Default cell is used when org_id is not found
+        assert_eq!(
+            locator.lookup("invalid_org", Some("de")).await,
+            Ok("de".into())
+        );
+
+        // No default cell for locality returns error
+        assert_eq!(
+            locator.lookup("invalid_org", Some("us")).await,
+            Err(LocatorError::NotYetSynced)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_sync_failure_alert() {
+        // Webhook receiving the notifications
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let app = axum::Router::new().route(
+            "/alerts",
+            axum::routing::post(move |axum::Json(body): axum::Json<serde_json::Value>| {
+                let tx = tx.clone();
+                async move {
+                    let _ = tx.send(body);
+                }
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let webhook_url = format!("http://{}/alerts", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let (_dir, provider) = get_mock_provider().await;
+        let _locator = Locator::with_options(
+            LocatorDataType::Organization,
+            control_plane_config("http://invalid-control-plane:8000".to_string()),
+            provider,
+            None,
+            None,
+            LocatorOptions {
+                alerts: Some(config::Alerts {
+                    webhook_url,
+                    sync_failure_threshold: 1,
+                    max_staleness_secs: 600,
+                    repeat_interval_secs: 3600,
+                }),
+                ..Default::default()
+            },
+        );
+
+        // The initial snapshot falls back to the backup
+        let notification = tokio::time::timeout(Duration::from_secs(5), rx.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(notification["alert"], "sync_failures");
+        assert_eq!(notification["status"], "firing");
+    }
+
+    #[tokio::test]
+    async fn test_read_only() {
+        // The control plane is reachable, but never contacted
+        let host = "127.0.0.1";
+        let server = TestControlPlaneServer::spawn(host).unwrap();
+        let (_dir, provider) = get_mock_provider().await;
+
+        let locator = Locator::with_options(
+            LocatorDataType::Organization,
+            control_plane_config(format!("http://{}:{}", host, server.port)),
+            provider.clone(),
+            None,
+            None,
+            LocatorOptions {
+                read_only: true,
+                ..Default::default()
+            },
+        );
+        assert!(locator.is_read_only());
+
+        locator.wait_for_startup(Startup::Loaded).await;
+        assert!(locator.is_ready());
+
+        // Served from the backup provider
+        assert_eq!(locator.lookup("org_0", Some("us")).await, Ok("us1".into()));
+        assert_eq!(
+            locator.lookup_stale("org_2", None).await,
+            Ok(StaleLookup {
+                cell: "de".into(),
+                freshness: Freshness::Stale,
+                age_secs: None,
+            })
+        );
+        // Org "0" is only in the control plane, which was never synced
+        assert_eq!(
+            locator.lookup("0", None).await,
+            Err(LocatorError::NotYetSynced)
+        );
+
+        // The backup is left as it was
+        let provider_data = provider.load().await.unwrap();
+        assert!(!provider_data.id_to_cell.contains_key("0"));
+    }
+
+    #[tokio::test]
+    async fn test_cell_catalog() {
+        let (_dir, provider) = get_mock_provider().await;
+        let catalog_cell = |id: &str, locality: &str| CatalogCell {
+            id: id.into(),
+            locality: locality.into(),
+            sentry_url: None,
+            relay_url: None,
+        };
+
+        let locator = Locator::with_options(
+            LocatorDataType::Organization,
+            control_plane_config("http://127.0.0.1:1".into()),
+            provider,
+            None,
+            None,
+            LocatorOptions {
+                read_only: true,
+                cells: Some(vec![catalog_cell("us1", "us")]),
+                ..Default::default()
+            },
+        );
+        locator.wait_for_startup(Startup::Loaded).await;
+        assert_eq!(
+            locator.cell_catalog(),
+            Some(vec![catalog_cell("us1", "us")])
+        );
+
+        // Cells missing from the catalog are reported, not rejected
+        assert_eq!(locator.lookup("org_2", None).await, Ok("de".into()));
+
+        // Invalid catalogs leave the registered one in place
+        assert!(
+            locator
+                .register_cells(vec![catalog_cell("de", "de"), catalog_cell("de", "de")])
+                .await
+                .is_err()
+        );
+        assert_eq!(
+            locator.cell_catalog(),
+            Some(vec![catalog_cell("us1", "us")])
+        );
+
+        locator
+            .register_cells(vec![catalog_cell("us1", "us"), catalog_cell("de", "de")])
+            .await
+            .unwrap();
+        assert_eq!(
+            locator.cell_catalog(),
+            Some(vec![catalog_cell("de", "de"), catalog_cell("us1", "us")])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_lookup_multi() {
+        let assignments = vec![
+            CellAssignment {
+                cell: "de".into(),
+                weight: 20,
+                primary: false,
+            },
+            CellAssignment {
+                cell: "us1".into(),
+                weight: 80,
+                primary: true,
+            },
+        ];
+        let route_data = RouteData::from(
+            HashMap::from([("org_0".into(), "us1".into())]),
+            Some("cursor1".into()),
+            HashMap::from([("us1".into(), "us".into()), ("de".into(), "de".into())]),
+        )
+        .with_multi_cell(HashMap::from([("org_1".into(), assignments.clone())]));
+
+        let dir = tempfile::tempdir().unwrap();
+        let provider = FilesystemRouteProvider::new(
+            dir.path().to_str().unwrap(),
+            "backup.bin",
+            config::Compression::None,
+        );
+        provider.store(&route_data).await.unwrap();
+
+        let locator = Locator::new(
+            LocatorDataType::Organization,
+            control_plane_config("http://invalid-control-plane:8000".to_string()),
+            Arc::new(provider),
+            None,
+            None,
+        );
+
+        locator.wait_for_startup(Startup::Loaded).await;
+
+        // Single lookups return the primary cell
+        assert_eq!(locator.lookup("org_1", None).await, Ok("us1".into()));
+
+        assert_eq!(
+            locator.lookup_multi("org_1", None).await,
+            Ok(assignments.clone())
+        );
+        assert_eq!(
+            locator.lookup_multi("org_1", Some("de")).await,
+            Ok(vec![CellAssignment {
+                cell: "de".into(),
+                weight: 20,
+                primary: false,
+            }])
+        );
+        assert_eq!(
+            locator.lookup_multi("org_1", Some("eu")).await,
+            Err(LocatorError::LocalityMismatch {
+                requested: "eu".to_string(),
+                actual: "us".to_string()
+            })
+        );
+
+        // Orgs in a single cell
+        assert_eq!(
+            locator.lookup_multi("org_0", Some("us")).await,
+            Ok(vec![CellAssignment::single("us1".into())])
+        );
+        assert_eq!(
+            locator.lookup_multi("invalid_org", None).await,
+            Err(LocatorError::NotYetSynced)
+        );
+        // Batches return every cell of the orgs spanning multiple cells in the locality
+        assert_eq!(
+            locator
+                .lookup_many_multi(&["org_0", "org_1", "invalid_org"], None)
+                .await,
+            Ok(HashMap::from([
+                ("org_0".into(), vec![CellAssignment::single("us1".into())]),
+                ("org_1".into(), assignments),
+            ]))
+        );
+        assert_eq!(
+            locator.lookup_many_multi(&["org_1"], Some("us")).await,
+            Ok(HashMap::from([(
+                "org_1".into(),
+                vec![CellAssignment {
+                    cell: "us1".into(),
+                    weight: 80,
+                    primary: true,
+                }]
+            )]))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_deleted() {
+        let mut route_data = RouteData::from(
+            HashMap::from([("org_0".into(), "us1".into())]),
+            Some("cursor1".into()),
+            HashMap::from([("us1".into(), "us".into())]),
+        )
+        .with_deleted(HashSet::from(["org_deleted".into()]));
+        // Seen longer than the retention ago
+        route_data.deleted.insert("org_expired".into(), 5000);
+
+        let dir = tempfile::tempdir().unwrap();
+        let provider = FilesystemRouteProvider::new(
+            dir.path().to_str().unwrap(),
+            "backup.bin",
+            config::Compression::None,
+        );
+        provider.store(&route_data).await.unwrap();
+
+        let locator = Locator::with_options(
+            LocatorDataType::Organization,
+            control_plane_config("http://invalid-control-plane:8000".to_string()),
+            Arc::new(provider),
+            None,
+            Some(HashMap::from([("us".into(), "us1".into())])),
+            LocatorOptions {
+                clock: Arc::new(MockClock::new(10_000)),
+                tombstone_retention: Duration::from_secs(3600),
+                ..Default::default()
+            },
+        );
+
+        locator.wait_for_startup(Startup::Loaded).await;
+
+        // Expired tombstones are forgotten
+        assert_eq!(
+            locator.lookup("org_expired", None).await,
+            Err(LocatorError::NotYetSynced)
+        );
+        assert_eq!(locator.stats().await.deleted_ids, 1);
+
+        // Deleted ids do not get the locality default
+        assert_eq!(
+            locator.lookup("org_deleted", Some("us")).await,
+            Err(LocatorError::Deleted)
+        );
+        assert_eq!(
+            locator.lookup_stale("org_deleted", None).await,
+            Err(LocatorError::Deleted)
+        );
+        assert_eq!(
+            locator
+                .lookup_many(&["org_0", "org_deleted", "org_new"], Some("us"))
+                .await,
+            Ok(HashMap::from([
+                ("org_0".into(), "us1".into()),
+                ("org_new".into(), "us1".into()),
+            ]))
+        );
+        // Unknown ids may not be synced yet, since the mappings come from the backup
+        assert_eq!(
+            locator.lookup("org_new", None).await,
+            Err(LocatorError::NotYetSynced)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_project_key_dsn() {
+        let key = "e12d836b15bb49d7bbf99e64295d995b";
+        let route_data = RouteData::from(
+            HashMap::from([(key.into(), "us1".into())]),
+            Some("cursor1".into()),
+            HashMap::from([("us1".into(), "us".into())]),
+        );
+
+        let dir = tempfile::tempdir().unwrap();
+        let provider = FilesystemRouteProvider::new(
+            dir.path().to_str().unwrap(),
+            "backup.bin",
+            config::Compression::None,
+        );
+        provider.store(&route_data).await.unwrap();
+
+        let locator = Locator::new(
+            LocatorDataType::ProjectKey,
+            control_plane_config("http://invalid-control-plane:8000".to_string()),
+            Arc::new(provider),
+            None,
+            None,
+        );
+
+        locator.wait_for_startup(Startup::Loaded).await;
+
+        let dsn = format!("https://{}@o1.ingest.sentry.io/42", key.to_uppercase());
+        assert_eq!(locator.lookup(&dsn, None).await, Ok("us1".into()));
+        assert_eq!(locator.lookup_stale(&dsn, None).await.unwrap().cell, "us1");
+        // Results are keyed by the ids as passed
+        assert_eq!(
+            locator.lookup_many(&[dsn.as_str(), key], None).await,
+            Ok(HashMap::from([
+                (dsn.clone(), "us1".into()),
+                (key.into(), "us1".into()),
+            ]))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_lookup_many() {
+        let route_data = RouteData::from(
+            HashMap::from([
+                ("org_0".into(), "us1".into()),
+                ("org_1".into(), "de".into()),
+            ]),
+            Some("cursor1".into()),
+            HashMap::from([("us1".into(), "us".into()), ("de".into(), "de".into())]),
+        );
+
+        let dir = tempfile::tempdir().unwrap();
+        let provider = FilesystemRouteProvider::new(
+            dir.path().to_str().unwrap(),
+            "backup.bin",
+            config::Compression::None,
+        );
+        provider.store(&route_data).await.unwrap();
+
+        let locator = Locator::new(
+            LocatorDataType::Organization,
+            control_plane_config("http://invalid-control-plane:8000".to_string()),
+            Arc::new(provider),
+            None,
+            None,
+        );
+
+        locator.wait_for_startup(Startup::Loaded).await;
+
+        // Unknown ids are left out
+        assert_eq!(
+            locator
+                .lookup_many(&["org_0", "org_1", "invalid_org"], None)
+                .await,
+            Ok(HashMap::from([
+                ("org_0".into(), "us1".into()),
+                ("org_1".into(), "de".into()),
+            ]))
+        );
+        // So are ids outside the locality
+        assert_eq!(
+            locator.lookup_many(&["org_0", "org_1"], Some("us")).await,
+            Ok(HashMap::from([("org_0".into(), "us1".into())]))
+        );
+        assert_eq!(locator.lookup_many(&[], None).await, Ok(HashMap::new()));
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_during_refresh() {
+        let host = "127.0.0.1";
+        let server = TestControlPlaneServer::spawn(host).unwrap();
+        let clock = Arc::new(MockClock::new(1000));
+        let (dir, provider) = get_mock_provider().await;
+        let locator = Locator::with_options(
+            LocatorDataType::Organization,
+            control_plane_config(format!("http://{}:{}", host, server.port)),
+            provider.clone(),
+            None,
+            Some(HashMap::from([("us".into(), "us1".into())])),
+            LocatorOptions {
+                clock: clock.clone(),
+                ..Default::default()
+            },
+        );
+        locator.wait_for_startup(Startup::Loaded).await;
+        assert!(locator.is_ready());
+
+        // The snapshot is backed up right away, the refresh below only on shutdown
+        std::fs::remove_file(dir.path().join("backup.bin")).unwrap();
+        clock.advance(Duration::from_secs(2));
+
+        // Holding the update lock keeps the refresh of the unknown id in progress
+        let permit = locator
+            .inner
+            .id_to_cell_map
+            .update_lock
+            .acquire()
+            .await
+            .unwrap();
+        let lookup = tokio::spawn({
+            let locator = locator.clone();
+            async move { locator.lookup("unknown", None).await }
+        });
+        settle().await;
+        let shutdown = tokio::spawn({
+            let locator = locator.clone();
+            async move { locator.shutdown().await }
+        });
+        settle().await;
+        assert!(!lookup.is_finished());
+        assert!(!shutdown.is_finished());
+
+        // The refresh finishes before the loader stops
+        drop(permit);
+        assert!(matches!(
+            lookup.await.unwrap(),
+            Err(LocatorError::NoCell | LocatorError::NotYetSynced)
+        ));
+        shutdown.await.unwrap();
+        assert_eq!(
+            provider.load().await.unwrap().id_to_cell.get("0").unwrap(),
+            "us1"
+        );
+
+        // Lookups fail, even where a default cell applies
+        assert!(!locator.is_ready());
+        assert_eq!(
+            locator.lookup("0", Some("us")).await,
+            Err(LocatorError::NotReady)
+        );
+        assert_eq!(
+            locator.lookup_many(&["0"], Some("us")).await,
+            Err(LocatorError::NotReady)
+        );
+        // Stale lookups still return the last known cell
+        let stale = locator.lookup_stale("0", None).await.unwrap();
+        assert_eq!(
+            (stale.cell.as_str(), stale.freshness),
+            ("us1", Freshness::Stale)
+        );
+
+        // Shutting down again does nothing
+        locator.shutdown().await;
+    }
+
+    // Backup provider whose writes wait for `release` once `blocked` is set, and whose
+    // probes never finish once `probe_blocked` is set
+    struct BlockingProvider {
+        inner: Arc<FilesystemRouteProvider>,
+        blocked: AtomicBool,
+        probe_blocked: AtomicBool,
+        entered: tokio::sync::Notify,
+        release: tokio::sync::Notify,
+    }
+
+    #[async_trait::async_trait]
+    impl BackupRouteProvider for BlockingProvider {
+        async fn load(&self) -> Result<RouteData, BackupError> {
+            self.inner.load().await
+        }
+
+        async fn store(&self, route_data: &RouteData) -> Result<(), BackupError> {
+            if self.blocked.load(Ordering::SeqCst) {
+                self.entered.notify_one();
+                self.release.notified().await;
+            }
+            self.inner.store(route_data).await
+        }
+
+        async fn round_trip(&self, route_data: &RouteData) -> Result<RouteData, BackupError> {
+            if self.probe_blocked.load(Ordering::SeqCst) {
+                std::future::pending::<()>().await;
+            }
+            self.inner.round_trip(route_data).await
+        }
+    }
+
+    #[tokio::test]
+    async fn test_lookups_during_backup() {
+        let host = "127.0.0.1";
+        let server = TestControlPlaneServer::spawn(host).unwrap();
+        let clock = Arc::new(MockClock::new(1000));
+        let (_dir, inner) = get_mock_provider().await;
+        let provider = Arc::new(BlockingProvider {
+            inner,
+            blocked: AtomicBool::new(false),
+            probe_blocked: AtomicBool::new(false),
+            entered: tokio::sync::Notify::new(),
+            release: tokio::sync::Notify::new(),
+        });
+        let locator = Locator::with_options(
+            LocatorDataType::Organization,
+            control_plane_config(format!("http://{}:{}", host, server.port)),
+            provider.clone(),
+            None,
+            None,
+            LocatorOptions {
+                clock: clock.clone(),
+                ..Default::default()
+            },
+        );
+        locator.wait_for_startup(Startup::Loaded).await;
+        assert!(locator.is_ready());
+
+        // The refresh of the unknown id is due for a backup, which gets stuck
+        provider.blocked.store(true, Ordering::SeqCst);
+        clock.advance(BACKUP_INTERVAL);
+        let refresh = tokio::spawn({
+            let locator = locator.clone();
+            async move { locator.lookup("unknown", None).await }
+        });
+        provider.entered.notified().await;
+
+        // The mappings are not locked while the backup is written
+        let lookup = tokio::time::timeout(Duration::from_secs(1), locator.lookup("0", None));
+        assert_eq!(lookup.await.unwrap(), Ok("us1".into()));
+
+        provider.release.notify_one();
+        assert!(refresh.await.unwrap().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_refresh_during_probe() {
+        let host = "127.0.0.1";
+        let server = TestControlPlaneServer::spawn(host).unwrap();
+        let (_dir, inner) = get_mock_provider().await;
+        // The first probe starts along with the refresh loop and never finishes
+        let provider = Arc::new(BlockingProvider {
+            inner,
+            blocked: AtomicBool::new(false),
+            probe_blocked: AtomicBool::new(true),
+            entered: tokio::sync::Notify::new(),
+            release: tokio::sync::Notify::new(),
+        });
+        let locator = Locator::new(
+            LocatorDataType::Organization,
+            control_plane_config(format!("http://{}:{}", host, server.port)),
+            provider,
+            None,
+            None,
+        );
+        locator.wait_for_startup(Startup::Loaded).await;
+        assert!(locator.is_ready());
+
+        // The refresh of the unknown id is not held up by the probe
+        let lookup = tokio::time::timeout(Duration::from_secs(1), locator.lookup("unknown", None));
+        assert_eq!(lookup.await.unwrap(), Err(LocatorError::NoCell));
+        tokio::time::timeout(Duration::from_secs(1), locator.shutdown())
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_refresh_overflow() {
+        let (_dir, provider) = get_mock_provider().await;
+        // A queue of one command, with no loader reading it
+        let id_to_cell = |refresh_overflow| {
+            let (tx, rx) = mpsc::channel(1);
+            let id_to_cell = Arc::new(IdToCell::new(
+                LocatorDataType::Organization,
+                control_plane_config("http://invalid-control-plane:8000".to_string()),
+                provider.clone(),
+                None,
+                None,
+                tx,
+                LocatorOptions {
+                    read_only: true,
+                    refresh_overflow,
+                    ..Default::default()
+                },
+            ));
+            (id_to_cell, rx)
+        };
+
+        for refresh_overflow in [
+            RefreshOverflow::Drop,
+            RefreshOverflow::Block { timeout_ms: 10 },
+        ] {
+            let (id_to_cell, _rx) = id_to_cell(refresh_overflow);
+            id_to_cell.tx.try_send(Command::Shutdown).unwrap();
+            assert!(!id_to_cell.refresh(Instant::now()).await);
+        }
+
+        // Coalesced lookups are answered along with the next refresh
+        let (id_to_cell, mut rx) = id_to_cell(RefreshOverflow::Coalesce);
+        let (ack_tx, ack_rx) = oneshot::channel();
+        id_to_cell
+            .tx
+            .try_send(Command::Refresh(Instant::now(), ack_tx))
+            .unwrap();
+        let coalesced = tokio::spawn({
+            let id_to_cell = id_to_cell.clone();
+            async move { id_to_cell.refresh(Instant::now()).await }
+        });
+        while id_to_cell.coalesced_refreshes.lock().unwrap().is_empty() {
+            tokio::task::yield_now().await;
+        }
+        let Some(Command::Refresh(requested_at, tx)) = rx.recv().await else {
+            panic!("expected a refresh command");
+        };
+        id_to_cell.handle_refresh(requested_at, tx).await;
+        assert!(ack_rx.await.unwrap().is_ok());
+        assert!(coalesced.await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_sharded_locator() {
+        let ids: Vec<String> = (0..20).map(|i| format!("org_{i}")).collect();
+        let route_data = RouteData::from(
+            ids.iter().map(|id| (id.clone(), "us1".into())).collect(),
+            Some("cursor1".into()),
+            HashMap::from([("us1".into(), "us".into())]),
+        );
+
+        let dir = tempfile::tempdir().unwrap();
+        let provider = FilesystemRouteProvider::new(
+            dir.path().to_str().unwrap(),
+            "backup.bin",
+            config::Compression::None,
+        );
+        provider.store(&route_data).await.unwrap();
+
+        let shard = Shard {
+            index: 1,
+            urls: vec!["http://locator-0".into(), "http://locator-1".into()],
+        };
+        let locator = Locator::with_options(
+            LocatorDataType::Organization,
+            control_plane_config("http://invalid-control-plane:8000".to_string()),
+            Arc::new(provider),
+            None,
+            None,
+            LocatorOptions {
+                shard: Some(shard.clone()),
+                ..Default::default()
+            },
+        );
+
+        locator.wait_for_startup(Startup::Loaded).await;
+
+        // Keys of other shards loaded from the backup are dropped
+        let (own, other): (Vec<_>, Vec<_>) = ids.iter().partition(|id| shard.owns(id));
+        assert!(!own.is_empty() && !other.is_empty());
+        for id in &own {
+            assert_eq!(locator.lookup(id, None).await, Ok("us1".into()));
+        }
+        for id in &other {
+            assert_eq!(
+                locator.lookup(id, None).await,
+                Err(LocatorError::WrongShard)
+            );
+            assert_eq!(
+                locator.lookup_stale(id, None).await,
+                Err(LocatorError::WrongShard)
+            );
+        }
+
+        let info = locator.shard_info().await.unwrap();
+        assert_eq!(info.index, 1);
+        assert_eq!(info.urls, shard.urls);
+        assert_eq!(info.keys, own.len() as u64);
+
+        let plan = locator.reshard_plan(4).await.unwrap();
+        assert_eq!(
+            plan.staying + plan.moving.values().sum::<u64>(),
+            own.len() as u64
+        );
+    }
+
+    #[tokio::test]
+    async fn test_locality_filter() {
+        let route_data = RouteData::from(
+            HashMap::from([
+                ("org_us".into(), "us1".into()),
+                ("org_de".into(), "de1".into()),
+            ]),
+            Some("cursor1".into()),
+            HashMap::from([("us1".into(), "us".into()), ("de1".into(), "de".into())]),
+        )
+        .with_multi_cell(HashMap::from([(
+            "org_moving".into(),
+            vec![
+                CellAssignment {
+                    cell: "us1".into(),
+                    weight: 1,
+                    primary: true,
+                },
+                CellAssignment {
+                    cell: "de1".into(),
+                    weight: 1,
+                    primary: false,
+                },
+            ],
+        )]));
+
+        let dir = tempfile::tempdir().unwrap();
+        let provider = FilesystemRouteProvider::new(
+            dir.path().to_str().unwrap(),
+            "backup.bin",
+            config::Compression::None,
+        );
+        provider.store(&route_data).await.unwrap();
+
+        let locator = Locator::new(
+            LocatorDataType::Organization,
+            control_plane_config("http://invalid-control-plane:8000".to_string()),
+            Arc::new(provider),
+            Some(vec!["us".into()]),
+            None,
+        );
+
+        locator.wait_for_startup(Startup::Loaded).await;
+
+        // Mappings of other localities loaded from the backup are dropped
+        assert_eq!(locator.lookup("org_us", None).await, Ok("us1".into()));
+        assert_eq!(locator.lookup("org_us", Some("us")).await, Ok("us1".into()));
+        assert_eq!(
+            locator.lookup("org_de", None).await,
+            Err(LocatorError::NotYetSynced)
+        );
+        assert_eq!(
+            locator.lookup_multi("org_moving", None).await,
+            Ok(vec![CellAssignment {
+                cell: "us1".into(),
+                weight: 1,
+                primary: true,
+            }])
+        );
+
+        // Lookups for other localities are rejected
+        assert_eq!(
+            locator.lookup("org_de", Some("de")).await,
+            Err(LocatorError::LocalityNotServed)
+        );
+        assert_eq!(
+            locator.lookup_multi("org_de", Some("de")).await,
+            Err(LocatorError::LocalityNotServed)
+        );
+        assert_eq!(
+            locator.lookup_stale("org_de", Some("de")).await,
+            Err(LocatorError::LocalityNotServed)
+        );
     }
 
     #[tokio::test]
@@ -557,7 +2975,7 @@ mod tests {
 
         let locator = Locator::new(
             LocatorDataType::Organization,
-            "http://invalid-control-plane:8000".to_string(),
+            control_plane_config("http://invalid-control-plane:8000".to_string()),
             provider,
             None,
             Some(HashMap::from([(
@@ -566,7 +2984,7 @@ mod tests {
             )])),
         );
 
-        tokio::time::sleep(Duration::from_millis(100)).await;
+        locator.wait_for_startup(Startup::Loaded).await;
 
         assert!(!locator.is_ready());
 
@@ -14,6 +14,12 @@ pub const NEGATIVE_CACHE_MISS: MetricDef = MetricDef {
     description: "Number of lookups that missed the negative cache",
 };
 
+pub const NEGATIVE_CACHE_EVICTIONS: MetricDef = MetricDef {
+    name: "negative_cache.evictions",
+    metric_type: MetricType::Counter,
+    description: "Number of keys evicted from the full negative cache before they expired",
+};
+
 pub const CONTROL_PLANE_SYNC_DURATION: MetricDef = MetricDef {
     name: "control_plane.sync.duration",
     metric_type: MetricType::Histogram,
@@ -26,10 +32,88 @@ pub const CONTROL_PLANE_SYNC_ROWS: MetricDef = MetricDef {
     description: "Number of mappings returned from control plane sync",
 };
 
+pub const CONTROL_PLANE_RETRIES_EXHAUSTED: MetricDef = MetricDef {
+    name: "control_plane.retries_exhausted",
+    metric_type: MetricType::Counter,
+    description: "Number of control plane requests that failed after exhausting all retries",
+};
+
+pub const API_REQUESTS: MetricDef = MetricDef {
+    name: "api.requests",
+    metric_type: MetricType::Counter,
+    description: "Number of lookup API requests when API keys are configured. Tagged with caller, status.",
+};
+
+pub const API_CALLER_REQUESTS: MetricDef = MetricDef {
+    name: "api.caller_requests",
+    metric_type: MetricType::Counter,
+    description: "Number of API requests by calling service, identified by API key or X-Synapse-Caller. Tagged with caller, endpoint, status.",
+};
+
+pub const ALERT_NOTIFICATIONS: MetricDef = MetricDef {
+    name: "alerts.notifications",
+    metric_type: MetricType::Counter,
+    description: "Number of alert notifications posted to the webhook. Tagged with alert, status, outcome.",
+};
+
+pub const CATALOG_UNKNOWN_CELLS: MetricDef = MetricDef {
+    name: "catalog.unknown_cells",
+    metric_type: MetricType::Counter,
+    description: "Number of lookups resolved to a cell missing from the registered cell catalog. Tagged with cell_id.",
+};
+
+pub const DATAGRAM_REQUESTS: MetricDef = MetricDef {
+    name: "datagram.requests",
+    metric_type: MetricType::Counter,
+    description: "Number of lookups received on the datagram listener. Tagged with outcome ('ok', the error code, or 'invalid' for malformed datagrams).",
+};
+
+pub const DATAGRAM_FALLBACKS: MetricDef = MetricDef {
+    name: "datagram.fallbacks",
+    metric_type: MetricType::Counter,
+    description: "Number of client lookups sent over HTTP because the datagram listener did not answer in time.",
+};
+
+pub const CLIENT_CACHE_HIT: MetricDef = MetricDef {
+    name: "client_cache.hit",
+    metric_type: MetricType::Counter,
+    description: "Number of client lookups answered from the client's lookup cache.",
+};
+
+pub const CLIENT_CACHE_MISS: MetricDef = MetricDef {
+    name: "client_cache.miss",
+    metric_type: MetricType::Counter,
+    description: "Number of client lookups sent to the locator because they missed the client's lookup cache.",
+};
+
+pub const REFRESH_QUEUE_DEPTH: MetricDef = MetricDef {
+    name: "refresh_queue.depth",
+    metric_type: MetricType::Gauge,
+    description: "Number of commands, mostly refreshes of lookups that missed, waiting for the loader.",
+};
+
+pub const REFRESH_QUEUE_OVERFLOWS: MetricDef = MetricDef {
+    name: "refresh_queue.overflows",
+    metric_type: MetricType::Counter,
+    description: "Number of lookups that found the refresh queue full. Tagged with outcome ('coalesced', 'timed_out' or 'dropped').",
+};
+
 // TODO: all metrics must be added here for now, this can be done dynamically with a macro in the future.
 pub const ALL_METRICS: &[MetricDef] = &[
     NEGATIVE_CACHE_HIT,
     NEGATIVE_CACHE_MISS,
+    NEGATIVE_CACHE_EVICTIONS,
     CONTROL_PLANE_SYNC_DURATION,
     CONTROL_PLANE_SYNC_ROWS,
+    CONTROL_PLANE_RETRIES_EXHAUSTED,
+    API_REQUESTS,
+    API_CALLER_REQUESTS,
+    ALERT_NOTIFICATIONS,
+    CATALOG_UNKNOWN_CELLS,
+    DATAGRAM_REQUESTS,
+    DATAGRAM_FALLBACKS,
+    CLIENT_CACHE_HIT,
+    CLIENT_CACHE_MISS,
+    REFRESH_QUEUE_DEPTH,
+    REFRESH_QUEUE_OVERFLOWS,
 ];
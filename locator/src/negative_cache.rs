@@ -1,31 +1,71 @@
 // Lightweight negative cache which temporarily stores not found results in order to
 // prevent repeated lookups for missing keys.
-use crate::metrics_defs::{NEGATIVE_CACHE_HIT, NEGATIVE_CACHE_MISS};
+use crate::clock::Clock;
+use crate::config::NegativeCache as NegativeCacheConfig;
+use crate::metrics_defs::{NEGATIVE_CACHE_EVICTIONS, NEGATIVE_CACHE_HIT, NEGATIVE_CACHE_MISS};
+use moka::notification::RemovalCause;
+use moka::policy::EvictionPolicy;
 use moka::sync::Cache;
-use std::time::Duration;
-
-const SIZE: u64 = 1000;
-const TTL_SECS: u64 = 5;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 pub struct NegativeCache {
-    cache: Cache<String, ()>,
+    // Expiry time of each key. Expiry is checked against the clock rather than left to
+    // the cache, so that it follows the locator's clock. Once full, the least recently
+    // used keys are evicted.
+    cache: Cache<String, Instant>,
+    ttl: Duration,
+    clock: Arc<dyn Clock>,
 }
 
 impl NegativeCache {
-    pub fn new() -> Self {
+    pub fn new(config: NegativeCacheConfig, clock: Arc<dyn Clock>) -> Self {
         let cache = Cache::builder()
-            .max_capacity(SIZE)
-            .time_to_live(Duration::from_secs(TTL_SECS))
+            .max_capacity(config.max_entries)
+            .eviction_policy(EvictionPolicy::lru())
+            .eviction_listener(|_, _, cause| {
+                if cause == RemovalCause::Size {
+                    metrics::counter!(NEGATIVE_CACHE_EVICTIONS.name).increment(1);
+                }
+            })
             .build();
 
-        NegativeCache { cache }
+        NegativeCache {
+            cache,
+            ttl: Duration::from_secs(config.ttl_secs),
+            clock,
+        }
     }
+
     pub fn insert(&self, key: &str) {
-        self.cache.insert(key.to_string(), ());
+        self.insert_for(key, self.ttl);
+    }
+
+    /// Inserts a key that expires after `ttl` rather than the default TTL, for restoring
+    /// entries of a previous run.
+    pub fn insert_for(&self, key: &str, ttl: Duration) {
+        self.cache.insert(key.to_string(), self.clock.now() + ttl);
+    }
+
+    /// Unexpired keys with their remaining TTL
+    pub fn entries(&self) -> Vec<(String, Duration)> {
+        let now = self.clock.now();
+        self.cache
+            .iter()
+            .filter(|(_, expires_at)| *expires_at > now)
+            .map(|(key, expires_at)| (key.to_string(), expires_at - now))
+            .collect()
     }
 
     pub fn contains(&self, key: &str) -> bool {
-        let cache_hit = self.cache.contains_key(key);
+        let cache_hit = match self.cache.get(key) {
+            Some(expires_at) if self.clock.now() < expires_at => true,
+            Some(_) => {
+                self.cache.invalidate(key);
+                false
+            }
+            None => false,
+        };
         let metric_def = if cache_hit {
             NEGATIVE_CACHE_HIT
         } else {
@@ -35,3 +75,74 @@ impl NegativeCache {
         cache_hit
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+
+    const TTL_SECS: u64 = 5;
+
+    fn negative_cache(clock: Arc<MockClock>) -> NegativeCache {
+        NegativeCache::new(
+            NegativeCacheConfig {
+                ttl_secs: TTL_SECS,
+                max_entries: 1000,
+            },
+            clock,
+        )
+    }
+
+    #[test]
+    fn test_ttl() {
+        let clock = Arc::new(MockClock::new(0));
+        let cache = negative_cache(clock.clone());
+
+        cache.insert("org_1");
+        assert!(cache.contains("org_1"));
+        assert!(!cache.contains("org_2"));
+
+        clock.advance(Duration::from_secs(TTL_SECS - 1));
+        assert!(cache.contains("org_1"));
+
+        clock.advance(Duration::from_secs(1));
+        assert!(!cache.contains("org_1"));
+    }
+
+    #[test]
+    fn test_entries() {
+        let clock = Arc::new(MockClock::new(0));
+        let cache = negative_cache(clock.clone());
+
+        cache.insert("org_1");
+        cache.insert_for("org_2", Duration::from_secs(60));
+        clock.advance(Duration::from_secs(TTL_SECS));
+
+        // Expired keys are left out
+        assert_eq!(
+            cache.entries(),
+            vec![("org_2".to_string(), Duration::from_secs(60 - TTL_SECS))]
+        );
+        assert!(cache.contains("org_2"));
+    }
+
+    #[test]
+    fn test_max_entries() {
+        let cache = NegativeCache::new(
+            NegativeCacheConfig {
+                ttl_secs: 60,
+                max_entries: 2,
+            },
+            Arc::new(MockClock::new(0)),
+        );
+        for key in ["org_1", "org_2", "org_3"] {
+            cache.insert(key);
+            cache.cache.run_pending_tasks();
+        }
+
+        // The least recently used key was evicted
+        assert!(!cache.contains("org_1"));
+        assert!(cache.contains("org_2"));
+        assert!(cache.contains("org_3"));
+    }
+}
@@ -0,0 +1,276 @@
+//! Report of how ids are distributed across cells compared to their capacity.
+//!
+//! Every cell's target is its share of the ids of its locality, proportional to its
+//! capacity weight. Moves between cells of the same locality are suggested to bring every
+//! cell to its target, ids never move between localities. Ids that span multiple cells are
+//! counted in their primary cell.
+use crate::types::{CellId, RouteData};
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+
+/// Weight of cells without a configured capacity weight
+const DEFAULT_WEIGHT: u32 = 1;
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct RebalanceReport {
+    pub total_ids: u64,
+    pub localities: Vec<LocalityReport>,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct LocalityReport {
+    pub locality: String,
+    pub total_ids: u64,
+    pub cells: Vec<CellReport>,
+    pub moves: Vec<Move>,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct CellReport {
+    pub cell: CellId,
+    pub ids: u64,
+    pub weight: u32,
+    pub target_ids: u64,
+    /// Ids above the target, negative if the cell has room for more
+    pub deviation: i64,
+}
+
+/// Suggested move of a number of ids from one cell to another
+#[derive(Debug, PartialEq, Serialize)]
+pub struct Move {
+    pub from: CellId,
+    pub to: CellId,
+    pub ids: u64,
+}
+
+impl RebalanceReport {
+    pub fn generate(data: &RouteData, capacity_weights: &HashMap<CellId, u32>) -> Self {
+        let mut ids_per_cell: HashMap<&str, u64> = HashMap::new();
+        for cell_id in data.id_to_cell.values() {
+            *ids_per_cell.entry(cell_id.as_str()).or_default() += 1;
+        }
+
+        // Sorted for a stable report
+        let mut localities: BTreeMap<&str, Vec<(CellId, u64, u32)>> = BTreeMap::new();
+        for cell in data.cells.values() {
+            let ids = ids_per_cell.get(cell.id.as_str()).copied().unwrap_or(0);
+            let weight = capacity_weights
+                .get(&cell.id)
+                .copied()
+                .unwrap_or(DEFAULT_WEIGHT);
+            localities.entry(cell.locality.as_str()).or_default().push((
+                cell.id.clone(),
+                ids,
+                weight,
+            ));
+        }
+
+        let localities: Vec<LocalityReport> = localities
+            .into_iter()
+            .map(|(locality, mut cells)| {
+                cells.sort();
+                LocalityReport::generate(locality, cells)
+            })
+            .collect();
+
+        RebalanceReport {
+            total_ids: localities.iter().map(|locality| locality.total_ids).sum(),
+            localities,
+        }
+    }
+}
+
+impl LocalityReport {
+    fn generate(locality: &str, cells: Vec<(CellId, u64, u32)>) -> Self {
+        let total_ids = cells.iter().map(|(_, ids, _)| ids).sum();
+        let targets = targets(&cells, total_ids);
+
+        let cells: Vec<CellReport> = cells
+            .into_iter()
+            .zip(targets)
+            .map(|((cell, ids, weight), target_ids)| CellReport {
+                cell,
+                ids,
+                weight,
+                target_ids,
+                deviation: ids as i64 - target_ids as i64,
+            })
+            .collect();
+
+        LocalityReport {
+            locality: locality.to_string(),
+            total_ids,
+            moves: moves(&cells),
+            cells,
+        }
+    }
+}
+
+/// Splits the ids proportionally to the weights. Ids left over by rounding down go to the
+/// cells with the largest remainders, so that the targets add up to the total.
+fn targets(cells: &[(CellId, u64, u32)], total_ids: u64) -> Vec<u64> {
+    let total_weight: u64 = cells.iter().map(|(_, _, weight)| *weight as u64).sum();
+    // Without any capacity, there is nowhere to move ids to
+    if total_weight == 0 {
+        return cells.iter().map(|(_, ids, _)| *ids).collect();
+    }
+
+    let shares: Vec<(u64, u64)> = cells
+        .iter()
+        .map(|(_, _, weight)| {
+            let share = total_ids as u128 * *weight as u128;
+            (
+                (share / total_weight as u128) as u64,
+                (share % total_weight as u128) as u64,
+            )
+        })
+        .collect();
+    let mut targets: Vec<u64> = shares.iter().map(|(target, _)| *target).collect();
+
+    let left_over = total_ids - targets.iter().sum::<u64>();
+    let mut by_remainder: Vec<usize> = (0..cells.len()).collect();
+    by_remainder.sort_by_key(|i| std::cmp::Reverse(shares[*i].1));
+    for i in by_remainder.into_iter().take(left_over as usize) {
+        targets[i] += 1;
+    }
+    targets
+}
+
+/// Pairs the cells above their target with the cells below it, largest deviations first.
+fn moves(cells: &[CellReport]) -> Vec<Move> {
+    let mut surplus: Vec<(&CellId, u64)> = cells
+        .iter()
+        .filter(|cell| cell.deviation > 0)
+        .map(|cell| (&cell.cell, cell.deviation as u64))
+        .collect();
+    let mut deficit: Vec<(&CellId, u64)> = cells
+        .iter()
+        .filter(|cell| cell.deviation < 0)
+        .map(|cell| (&cell.cell, cell.deviation.unsigned_abs()))
+        .collect();
+    surplus.sort_by_key(|(_, ids)| std::cmp::Reverse(*ids));
+    deficit.sort_by_key(|(_, ids)| std::cmp::Reverse(*ids));
+
+    let mut moves = Vec::new();
+    let mut deficit = deficit.into_iter().peekable();
+    for (from, mut surplus_ids) in surplus {
+        while surplus_ids > 0
+            && let Some((to, deficit_ids)) = deficit.peek_mut()
+        {
+            let ids = surplus_ids.min(*deficit_ids);
+            moves.push(Move {
+                from: from.clone(),
+                to: to.to_string(),
+                ids,
+            });
+            surplus_ids -= ids;
+            *deficit_ids -= ids;
+            if *deficit_ids == 0 {
+                deficit.next();
+            }
+        }
+    }
+    moves
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::history::MappingHistory;
+    use crate::types::Cell;
+    use std::sync::Arc;
+
+    fn route_data(cells: &[(&str, &str, u64)]) -> RouteData {
+        let mut id_to_cell = HashMap::new();
+        for (cell_id, _, ids) in cells {
+            for i in 0..*ids {
+                id_to_cell.insert(format!("{cell_id}_{i}"), cell_id.to_string());
+            }
+        }
+
+        RouteData {
+            id_to_cell,
+            id_to_cells: HashMap::new(),
+            last_cursor: None,
+            cells: cells
+                .iter()
+                .map(|(id, locality, _)| (id.to_string(), Arc::new(Cell::new(*id, *locality))))
+                .collect(),
+            history: MappingHistory::default(),
+            deleted: HashMap::new(),
+        }
+    }
+
+    fn cell(cell: &str, ids: u64, weight: u32, target_ids: u64) -> CellReport {
+        CellReport {
+            cell: cell.into(),
+            ids,
+            weight,
+            target_ids,
+            deviation: ids as i64 - target_ids as i64,
+        }
+    }
+
+    fn move_ids(from: &str, to: &str, ids: u64) -> Move {
+        Move {
+            from: from.into(),
+            to: to.into(),
+            ids,
+        }
+    }
+
+    #[test]
+    fn test_report() {
+        let data = route_data(&[
+            ("us1", "us", 70),
+            ("us2", "us", 20),
+            ("us3", "us", 10),
+            ("de1", "de", 5),
+        ]);
+        let weights = HashMap::from([("us1".to_string(), 2), ("us2".to_string(), 2)]);
+
+        let report = RebalanceReport::generate(&data, &weights);
+        assert_eq!(
+            report,
+            RebalanceReport {
+                total_ids: 105,
+                localities: vec![
+                    LocalityReport {
+                        locality: "de".into(),
+                        total_ids: 5,
+                        cells: vec![cell("de1", 5, 1, 5)],
+                        moves: vec![],
+                    },
+                    LocalityReport {
+                        locality: "us".into(),
+                        total_ids: 100,
+                        cells: vec![
+                            cell("us1", 70, 2, 40),
+                            cell("us2", 20, 2, 40),
+                            cell("us3", 10, 1, 20),
+                        ],
+                        moves: vec![move_ids("us1", "us2", 20), move_ids("us1", "us3", 10)],
+                    },
+                ],
+            }
+        );
+    }
+
+    #[test]
+    fn test_targets_add_up() {
+        let cells = [
+            ("us1".to_string(), 10, 1),
+            ("us2".to_string(), 0, 1),
+            ("us3".to_string(), 0, 1),
+        ];
+        assert_eq!(targets(&cells, 10), vec![4, 3, 3]);
+
+        // Cells without capacity are drained
+        let cells = [("us1".to_string(), 3, 0), ("us2".to_string(), 1, 1)];
+        assert_eq!(targets(&cells, 4), vec![0, 4]);
+
+        // Nowhere to move ids to
+        let cells = [("us1".to_string(), 3, 0)];
+        assert_eq!(targets(&cells, 3), vec![3]);
+    }
+}
@@ -0,0 +1,248 @@
+//! Preflight checks of the locator dependencies.
+//!
+//! `synapse locator self-test` runs these checks against the configured dependencies and
+//! exits non-zero if one fails, so that a deployment can be stopped before a locator that
+//! cannot sync or back up its mappings serves traffic:
+//!
+//! - `control_plane`: the first page of a snapshot is fetched and parsed
+//! - `backup_route_store`: a temporary copy of that page is stored next to the backup and
+//!   loaded back. The backup itself is not written. In read-only mode, where the backup is
+//!   never written, the backup is loaded instead.
+//! - `serialization`: the loaded copy is identical to the stored data
+use crate::backup_routes::BackupRouteProvider;
+use crate::config::Config;
+use crate::control_plane::ControlPlane;
+use crate::get_provider;
+use crate::types::RouteData;
+use std::collections::HashMap;
+use std::fmt;
+
+#[derive(Debug, PartialEq)]
+pub enum Outcome {
+    Passed(String),
+    Failed(String),
+    Skipped(String),
+}
+
+/// Outcome of the check of one dependency
+#[derive(Debug, PartialEq)]
+pub struct Check {
+    pub dependency: &'static str,
+    pub outcome: Outcome,
+}
+
+impl Check {
+    pub fn failed(&self) -> bool {
+        matches!(self.outcome, Outcome::Failed(_))
+    }
+}
+
+impl fmt::Display for Check {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (status, detail) = match &self.outcome {
+            Outcome::Passed(detail) => ("PASS", detail),
+            Outcome::Failed(detail) => ("FAIL", detail),
+            Outcome::Skipped(detail) => ("SKIP", detail),
+        };
+        write!(f, "{status} {}: {detail}", self.dependency)
+    }
+}
+
+// Stored when the control plane cannot provide a page
+fn sample_route_data() -> RouteData {
+    RouteData::from(
+        HashMap::from([("self-test".to_string(), "self-test-cell".to_string())]),
+        None,
+        HashMap::from([("self-test-cell".to_string(), "self-test".to_string())]),
+    )
+}
+
+/// Checks every dependency of the locator, in order.
+pub async fn run(config: Config) -> Vec<Check> {
+    let mut checks = Vec::new();
+
+    let page = if config.read_only {
+        checks.push(Check {
+            dependency: "control_plane",
+            outcome: Outcome::Skipped("never contacted in read-only mode".to_string()),
+        });
+        None
+    } else {
+        let control_plane =
+            ControlPlane::new(config.data_type, config.control_plane, config.localities)
+                .with_shard(config.shard);
+        let (outcome, page) = match control_plane.load_first_page().await {
+            Ok(page) => (
+                Outcome::Passed(format!(
+                    "fetched {} mappings of {} cells",
+                    page.id_to_cell.len(),
+                    page.cells.len()
+                )),
+                Some(page),
+            ),
+            Err(e) => (Outcome::Failed(e.to_string()), None),
+        };
+        checks.push(Check {
+            dependency: "control_plane",
+            outcome,
+        });
+        page
+    };
+
+    let provider = match get_provider(config.backup_route_store.r#type).await {
+        Ok(provider) => provider,
+        Err(e) => {
+            checks.push(Check {
+                dependency: "backup_route_store",
+                outcome: Outcome::Failed(e.to_string()),
+            });
+            checks.push(Check {
+                dependency: "serialization",
+                outcome: Outcome::Skipped("backup route store unavailable".to_string()),
+            });
+            return checks;
+        }
+    };
+
+    if config.read_only {
+        checks.extend(check_backup(provider.as_ref()).await);
+    } else {
+        let data = page.unwrap_or_else(sample_route_data);
+        checks.extend(check_round_trip(provider.as_ref(), &data).await);
+    }
+    checks
+}
+
+async fn check_round_trip(provider: &dyn BackupRouteProvider, data: &RouteData) -> [Check; 2] {
+    match provider.round_trip(data).await {
+        Ok(loaded) => [
+            Check {
+                dependency: "backup_route_store",
+                outcome: Outcome::Passed("stored and loaded a temporary copy".to_string()),
+            },
+            Check {
+                dependency: "serialization",
+                outcome: if &loaded == data {
+                    Outcome::Passed(format!("{} mappings round-tripped", data.id_to_cell.len()))
+                } else {
+                    Outcome::Failed("loaded copy differs from the stored data".to_string())
+                },
+            },
+        ],
+        Err(e) => [
+            Check {
+                dependency: "backup_route_store",
+                outcome: Outcome::Failed(e.to_string()),
+            },
+            Check {
+                dependency: "serialization",
+                outcome: Outcome::Skipped("backup route store unavailable".to_string()),
+            },
+        ],
+    }
+}
+
+async fn check_backup(provider: &dyn BackupRouteProvider) -> [Check; 2] {
+    match provider.load().await {
+        Ok(data) => [
+            Check {
+                dependency: "backup_route_store",
+                outcome: Outcome::Passed(format!(
+                    "loaded the backup with {} mappings",
+                    data.id_to_cell.len()
+                )),
+            },
+            Check {
+                dependency: "serialization",
+                outcome: Outcome::Passed("backup decoded".to_string()),
+            },
+        ],
+        Err(e) => [
+            Check {
+                dependency: "backup_route_store",
+                outcome: Outcome::Failed(e.to_string()),
+            },
+            Check {
+                dependency: "serialization",
+                outcome: Outcome::Skipped("backup route store unavailable".to_string()),
+            },
+        ],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutils::TestControlPlaneServer;
+
+    fn config(control_plane_url: &str, base_dir: &str, read_only: bool) -> Config {
+        serde_json::from_value(serde_json::json!({
+            "control_plane": {"url": control_plane_url, "retry": {"max_retries": 0}},
+            "backup_route_store": {
+                "type": "filesystem",
+                "base_dir": base_dir,
+                "filename": "backup.bin",
+                "compression": "zstd1",
+            },
+            "localities": null,
+            "locality_to_default_cell": null,
+            "data_type": "organization",
+            "read_only": read_only,
+        }))
+        .unwrap()
+    }
+
+    fn statuses(checks: &[Check]) -> Vec<String> {
+        checks
+            .iter()
+            .map(|check| check.to_string().split(':').next().unwrap().to_string())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_run() {
+        let server = TestControlPlaneServer::spawn("127.0.0.1").unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let base_dir = dir.path().to_str().unwrap();
+
+        let checks = run(config(
+            &format!("http://127.0.0.1:{}/", server.port),
+            base_dir,
+            false,
+        ))
+        .await;
+        assert_eq!(
+            statuses(&checks),
+            vec![
+                "PASS control_plane",
+                "PASS backup_route_store",
+                "PASS serialization"
+            ]
+        );
+        assert!(!checks.iter().any(Check::failed));
+        // Only the temporary copy was written, and removed
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+
+        // The backup store is checked without the control plane
+        let checks = run(config("http://127.0.0.1:1/", base_dir, false)).await;
+        assert_eq!(
+            statuses(&checks),
+            vec![
+                "FAIL control_plane",
+                "PASS backup_route_store",
+                "PASS serialization"
+            ]
+        );
+
+        // There is no backup to load
+        let checks = run(config("http://127.0.0.1:1/", base_dir, true)).await;
+        assert_eq!(
+            statuses(&checks),
+            vec![
+                "SKIP control_plane",
+                "FAIL backup_route_store",
+                "SKIP serialization"
+            ]
+        );
+    }
+}
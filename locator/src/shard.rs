@@ -0,0 +1,141 @@
+//! Sharding of the keyspace across locator processes.
+//!
+//! Every key belongs to the shard at `hash(key) % shard count`. A sharded locator only
+//! keeps the mappings of its own keys and rejects lookups of other keys, and clients
+//! compute the shard of a key to query the right locator. The hash is part of the protocol
+//! between clients and locators, so it must never change.
+use crate::config::Shard;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// FNV-1a, stable across platforms and releases unlike the std hasher
+fn hash(key: &str) -> u64 {
+    key.bytes().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// The shard of the key, out of `count` shards
+pub fn shard_of(key: &str, count: usize) -> usize {
+    (hash(key) % count.max(1) as u64) as usize
+}
+
+/// Topology of a sharded locator, as served by its `/shards` endpoint
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct ShardInfo {
+    /// Index of the answering locator
+    pub index: usize,
+    /// Locator URL of every shard, by index
+    pub urls: Vec<String>,
+    /// Number of keys the answering locator holds
+    pub keys: u64,
+}
+
+impl Shard {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.index >= self.urls.len() {
+            return Err(format!(
+                "shard index {} is out of range for {} shard urls",
+                self.index,
+                self.urls.len()
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn count(&self) -> usize {
+        self.urls.len()
+    }
+
+    pub fn owns(&self, key: &str) -> bool {
+        shard_of(key, self.count()) == self.index
+    }
+}
+
+/// Where the keys of one shard go if the number of shards changes
+#[derive(Debug, PartialEq, Serialize)]
+pub struct ReshardPlan {
+    pub count: usize,
+    /// Keys that stay in the shard with the same index
+    pub staying: u64,
+    /// Keys that belong to another shard afterwards, by index
+    pub moving: BTreeMap<usize, u64>,
+}
+
+impl ReshardPlan {
+    pub fn generate<'a>(index: usize, keys: impl Iterator<Item = &'a str>, count: usize) -> Self {
+        let mut plan = ReshardPlan {
+            count,
+            staying: 0,
+            moving: BTreeMap::new(),
+        };
+        for key in keys {
+            match shard_of(key, count) {
+                shard if shard == index => plan.staying += 1,
+                shard => *plan.moving.entry(shard).or_default() += 1,
+            }
+        }
+        plan
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shard_of() {
+        // The hash is shared with clients of other versions and must stay the same
+        assert_eq!(hash(""), 0xcbf29ce484222325);
+        assert_eq!(hash("sentry"), 0xfc2790188a30bb6a);
+        assert_eq!(shard_of("sentry", 4), 2);
+
+        assert_eq!(shard_of("sentry", 1), 0);
+        // A shard count of zero is treated as a single shard
+        assert_eq!(shard_of("sentry", 0), 0);
+
+        // Keys are spread across all shards
+        let mut counts = [0; 4];
+        for i in 0..1000 {
+            counts[shard_of(&i.to_string(), 4)] += 1;
+        }
+        assert!(counts.iter().all(|count| *count > 200), "{counts:?}");
+    }
+
+    #[test]
+    fn test_shard() {
+        let shard = Shard {
+            index: 2,
+            urls: vec!["a".into(), "b".into(), "c".into(), "d".into()],
+        };
+        assert!(shard.validate().is_ok());
+        assert!(shard.owns("sentry"));
+        assert!(!shard.owns("getsentry"));
+
+        let shard = Shard { index: 4, ..shard };
+        assert!(shard.validate().is_err());
+    }
+
+    #[test]
+    fn test_reshard_plan() {
+        let keys: Vec<String> = (0..100).map(|i| i.to_string()).collect();
+        let own: Vec<&str> = keys
+            .iter()
+            .map(String::as_str)
+            .filter(|key| shard_of(key, 2) == 0)
+            .collect();
+
+        let plan = ReshardPlan::generate(0, own.iter().copied(), 4);
+        assert_eq!(plan.count, 4);
+        assert_eq!(
+            plan.staying + plan.moving.values().sum::<u64>(),
+            own.len() as u64
+        );
+        assert!(!plan.moving.contains_key(&0));
+
+        // Nothing moves without a change in the number of shards
+        let plan = ReshardPlan::generate(0, own.iter().copied(), 2);
+        assert_eq!(plan.staying, own.len() as u64);
+        assert!(plan.moving.is_empty());
+    }
+}
@@ -0,0 +1,168 @@
+//! Secret of the HMAC signatures of control plane requests, see `ControlPlane`.
+//!
+//! The secret is read from `key_file`, or else from the `key_env` environment variable.
+//! A `key_file` is read again every `reload_interval_secs` by a background task, so that a
+//! rotated secret is used without a restart. If it cannot be read again, the previous
+//! secret is kept. The environment of a process does not change, so rotating a secret
+//! read from `key_env` requires a restart. Without a secret, requests are sent unsigned.
+use crate::config::Signing;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::task::JoinHandle;
+
+#[derive(Debug)]
+enum Source {
+    File(PathBuf),
+    Env(String),
+}
+
+impl Source {
+    /// The secret, None if there is none
+    fn read(&self) -> std::io::Result<Option<String>> {
+        match self {
+            Source::File(path) => std::fs::read_to_string(path).map(|c| from_file(&c)),
+            Source::Env(name) => Ok(std::env::var(name).ok()),
+        }
+    }
+}
+
+// The secret in the contents of a key file, without trailing newlines
+fn from_file(contents: &str) -> Option<String> {
+    let secret = contents.trim_end_matches(['\r', '\n']);
+    (!secret.is_empty()).then(|| secret.to_string())
+}
+
+pub struct SigningKey {
+    secret: Arc<Secret>,
+    // Reads the key file again, stopped with the key
+    reload: Option<JoinHandle<()>>,
+}
+
+impl SigningKey {
+    /// Must be called within a tokio runtime if the secret is read from a file
+    pub fn new(config: Option<Signing>) -> Self {
+        let config = config.unwrap_or_default();
+        let source = match config.key_file {
+            Some(path) => Source::File(path),
+            None => Source::Env(config.key_env),
+        };
+        let current = source.read().unwrap_or_else(|e| {
+            tracing::warn!(?source, error = %e, "Failed to read the HMAC secret");
+            None
+        });
+        if current.is_none() {
+            tracing::warn!(?source, "No HMAC secret, HMAC authentication disabled");
+        }
+
+        let key_file = match source {
+            Source::File(path) => Some(path),
+            Source::Env(_) => None,
+        };
+        let secret = Arc::new(Secret {
+            current: RwLock::new(current),
+            key_file,
+        });
+        let reload = secret.key_file.is_some().then(|| {
+            let secret = secret.clone();
+            let interval = Duration::from_secs(config.reload_interval_secs.max(1));
+            tokio::spawn(async move {
+                let mut ticks =
+                    tokio::time::interval_at(tokio::time::Instant::now() + interval, interval);
+                ticks.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+                loop {
+                    ticks.tick().await;
+                    secret.reload().await;
+                }
+            })
+        });
+
+        Self { secret, reload }
+    }
+
+    /// The current secret
+    pub fn secret(&self) -> Option<String> {
+        self.secret.current.read().unwrap().clone()
+    }
+
+    /// Reads the key file again now, instead of waiting for the background task
+    #[cfg(test)]
+    pub async fn reload(&self) {
+        self.secret.reload().await;
+    }
+}
+
+impl Drop for SigningKey {
+    fn drop(&mut self) {
+        if let Some(reload) = &self.reload {
+            reload.abort();
+        }
+    }
+}
+
+struct Secret {
+    current: RwLock<Option<String>>,
+    // Read again periodically, None if the secret is read from the environment
+    key_file: Option<PathBuf>,
+}
+
+impl Secret {
+    /// Reads the key file again, keeping the previous secret if it cannot be read
+    async fn reload(&self) {
+        let Some(path) = &self.key_file else {
+            return;
+        };
+        match tokio::fs::read_to_string(path).await {
+            Ok(contents) => {
+                let reloaded = from_file(&contents);
+                let mut current = self.current.write().unwrap();
+                if reloaded != *current {
+                    tracing::info!(?path, "HMAC secret rotated");
+                }
+                *current = reloaded;
+            }
+            Err(e) => {
+                tracing::warn!(
+                    ?path,
+                    error = %e,
+                    "Failed to read the HMAC secret again, keeping the previous one"
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_rotation() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("secret");
+        std::fs::write(&path, "secret1\n").unwrap();
+        let key = SigningKey::new(Some(Signing {
+            key_file: Some(path.clone()),
+            ..Default::default()
+        }));
+        assert!(key.reload.is_some());
+        assert_eq!(key.secret().as_deref(), Some("secret1"));
+
+        std::fs::write(&path, "secret2").unwrap();
+        key.reload().await;
+        assert_eq!(key.secret().as_deref(), Some("secret2"));
+
+        // Kept if the file cannot be read
+        std::fs::remove_file(&path).unwrap();
+        key.reload().await;
+        assert_eq!(key.secret().as_deref(), Some("secret2"));
+
+        // Without a file, from the environment variable, never read again
+        let key = SigningKey::new(Some(Signing {
+            key_env: "SYNAPSE_TEST_UNSET_HMAC_SECRET".to_string(),
+            ..Default::default()
+        }));
+        assert!(key.reload.is_none());
+        assert_eq!(key.secret(), None);
+    }
+}
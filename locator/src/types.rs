@@ -1,5 +1,6 @@
+use crate::history::MappingHistory;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::sync::Arc;
 
 pub type CellId = String;
@@ -8,6 +9,10 @@ pub type CellId = String;
 pub struct Cell {
     pub id: String,
     pub locality: String,
+    /// Attributes of the cell set in the control plane, such as its region or whether it
+    /// is under maintenance
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub labels: BTreeMap<String, String>,
 }
 
 impl Cell {
@@ -19,15 +24,121 @@ impl Cell {
         Cell {
             id: id.into(),
             locality: locality.into(),
+            labels: BTreeMap::new(),
         }
     }
 }
 
+/// Entry of the cell catalog, the authoritative list of cells registered with the locator.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct CatalogCell {
+    pub id: CellId,
+    pub locality: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sentry_url: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub relay_url: Option<String>,
+}
+
+/// One of the cells an id is assigned to. Ids span multiple cells while they are being
+/// migrated, with traffic split between the cells according to their weights.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, bincode::Encode, bincode::Decode)]
+pub struct CellAssignment {
+    pub cell: CellId,
+    pub weight: u32,
+    #[serde(default)]
+    pub primary: bool,
+}
+
+impl CellAssignment {
+    /// Assignment of an id that lives in a single cell
+    pub fn single(cell: CellId) -> Self {
+        CellAssignment {
+            cell,
+            weight: 1,
+            primary: true,
+        }
+    }
+}
+
+/// Cell returned by a lookup that prefers availability over strict correctness, along with
+/// how much the mapping can be trusted.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct StaleLookup {
+    pub cell: CellId,
+    pub freshness: Freshness,
+    /// Seconds since the mappings were last refreshed from the control plane. None if they
+    /// were never refreshed, e.g. when they were loaded from the backup.
+    pub age_secs: Option<u64>,
+}
+
+/// Size and age of the mappings of a locator, for debugging
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct LocatorStats {
+    pub ready: bool,
+    pub freshness: Freshness,
+    /// Ids with a cell
+    pub ids: usize,
+    /// Ids spanning multiple cells
+    pub multi_cell_ids: usize,
+    pub deleted_ids: usize,
+    pub cells: usize,
+    /// Cursor the next refresh continues from
+    pub cursor: Option<String>,
+    /// Seconds since the mappings were last refreshed from the control plane, None if they
+    /// were never refreshed
+    pub last_refresh_age_secs: Option<u64>,
+    /// Seconds since the mappings were last written to the backup route provider
+    pub last_backup_age_secs: Option<u64>,
+    /// Ids recently found to have no cell, which are answered without a refresh
+    pub negative_cache_size: usize,
+}
+
+/// Detailed readiness of a locator, for readiness probes and operators
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Readiness {
+    pub status: ReadinessStatus,
+    /// Why the locator is degraded or not ready, empty if it is ready
+    pub reasons: Vec<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReadinessStatus {
+    Ready,
+    /// Lookups are answered, but the backup route provider is failing. The locator would
+    /// not be able to start again if the control plane went down.
+    Degraded,
+    /// The mappings are not loaded yet, or the locator is shutting down
+    NotReady,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Freshness {
+    /// The mapping was refreshed from the control plane recently
+    Fresh,
+    /// The last known mapping, which may have changed since. The locator is not ready, its
+    /// mappings were loaded from the backup or have not been refreshed for a while.
+    Stale,
+    /// No mapping is known, this is the locality's default cell
+    Default,
+}
+
 #[derive(Clone, Debug, PartialEq, bincode::Encode, bincode::Decode)]
 pub struct RouteData {
+    // Primary cell of every id
     pub id_to_cell: HashMap<String, CellId>,
+    // All cells of ids spanning multiple cells
+    pub id_to_cells: HashMap<String, Vec<CellAssignment>>,
     pub last_cursor: Option<String>,
     pub cells: HashMap<CellId, Arc<Cell>>,
+    // Changes of id_to_cell observed by this locator
+    pub history: MappingHistory,
+    // Ids deleted in the control plane, kept so that lookups can tell them from unknown ids,
+    // with the unix time the locator first saw their tombstone. 0 for tombstones of the
+    // control plane that the locator has not applied yet.
+    pub deleted: HashMap<String, u64>,
 }
 
 impl RouteData {
@@ -42,18 +153,64 @@ impl RouteData {
             .map(|(cell_id, locality)| {
                 (
                     cell_id.clone(),
-                    Arc::new(Cell {
-                        id: cell_id.clone(),
-                        locality: locality.clone(),
-                    }),
+                    Arc::new(Cell::new(cell_id.clone(), locality.clone())),
                 )
             })
             .collect::<HashMap<CellId, Arc<Cell>>>();
 
         RouteData {
             id_to_cell,
+            id_to_cells: HashMap::new(),
             last_cursor,
             cells,
+            history: MappingHistory::default(),
+            deleted: HashMap::new(),
         }
     }
+
+    /// Drops the cells outside of `localities` and the ids whose primary cell is one of them.
+    pub fn retain_localities(&mut self, localities: &HashSet<String>) {
+        self.cells
+            .retain(|_, cell| localities.contains(&cell.locality));
+        let cells = &self.cells;
+        self.id_to_cell
+            .retain(|_, cell_id| cells.contains_key(cell_id));
+        let id_to_cell = &self.id_to_cell;
+        self.id_to_cells.retain(|id, assignments| {
+            assignments.retain(|a| cells.contains_key(&a.cell));
+            id_to_cell.contains_key(id)
+        });
+    }
+
+    /// Adds ids spanning multiple cells. Their primary cell is also recorded in
+    /// `id_to_cell`, or the first cell if none is marked as primary.
+    pub fn with_multi_cell(mut self, id_to_cells: HashMap<String, Vec<CellAssignment>>) -> Self {
+        for (id, assignments) in &id_to_cells {
+            let primary = assignments
+                .iter()
+                .find(|a| a.primary)
+                .or_else(|| assignments.first());
+            if let Some(primary) = primary {
+                self.id_to_cell.insert(id.clone(), primary.cell.clone());
+            }
+        }
+        self.id_to_cells.extend(id_to_cells);
+        self
+    }
+
+    /// Sets the labels of the cells. Labels of unknown cells are ignored.
+    pub fn with_cell_labels(mut self, labels: HashMap<CellId, BTreeMap<String, String>>) -> Self {
+        for (cell_id, labels) in labels {
+            if let Some(cell) = self.cells.get_mut(&cell_id) {
+                Arc::make_mut(cell).labels = labels;
+            }
+        }
+        self
+    }
+
+    /// Adds the tombstones of deleted ids, not seen by the locator yet.
+    pub fn with_deleted(mut self, deleted: HashSet<String>) -> Self {
+        self.deleted.extend(deleted.into_iter().map(|id| (id, 0)));
+        self
+    }
 }
@@ -0,0 +1,116 @@
+//! Lookup caches persisted across restarts.
+//!
+//! On shutdown, the locator writes its unexpired negative cache entries and the ids it
+//! looked up most recently, with their cells, to a file and reads them back on startup.
+//! Restored negative cache entries keep ids that were recently absent from triggering
+//! on-miss refreshes against the control plane right after a restart. Restored hot ids
+//! answer stale lookups until the first snapshot is loaded.
+use crate::types::Cell;
+use moka::sync::Cache;
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+
+#[derive(Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct WarmCacheDump {
+    /// Unix timestamp in seconds of the last refresh of the mappings before the dump
+    pub updated_at: Option<u64>,
+    pub negative: Vec<NegativeEntry>,
+    pub hot: Vec<HotEntry>,
+}
+
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+pub struct NegativeEntry {
+    pub id: String,
+    /// Unix timestamp in seconds
+    pub expires_at: u64,
+}
+
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+pub struct HotEntry {
+    pub id: String,
+    pub cell: String,
+    pub locality: String,
+}
+
+impl WarmCacheDump {
+    /// Reads a dump, None if there is none yet.
+    pub async fn read(path: &Path) -> io::Result<Option<Self>> {
+        match tokio::fs::read(path).await {
+            Ok(bytes) => Ok(Some(serde_json::from_slice(&bytes)?)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Writes the dump, replacing the previous one at once so that it is never read
+    /// partially written.
+    pub async fn write(&self, path: &Path) -> io::Result<()> {
+        let tmp_path = path.with_extension("tmp");
+        tokio::fs::write(&tmp_path, serde_json::to_vec(self)?).await?;
+        tokio::fs::rename(&tmp_path, path).await
+    }
+}
+
+/// Ids recently resolved to a cell, bounded in size.
+pub struct HotLookups {
+    cache: Cache<String, Arc<Cell>>,
+}
+
+impl HotLookups {
+    pub fn new(max_ids: u64) -> Self {
+        Self {
+            cache: Cache::builder().max_capacity(max_ids).build(),
+        }
+    }
+
+    pub fn insert(&self, id: &str, cell: Arc<Cell>) {
+        self.cache.insert(id.to_string(), cell);
+    }
+
+    pub fn get(&self, id: &str) -> Option<Arc<Cell>> {
+        self.cache.get(id)
+    }
+
+    pub fn entries(&self) -> Vec<HotEntry> {
+        self.cache
+            .iter()
+            .map(|(id, cell)| HotEntry {
+                id: id.to_string(),
+                cell: cell.id.clone(),
+                locality: cell.locality.clone(),
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_read_write() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("warm_cache.json");
+
+        assert_eq!(WarmCacheDump::read(&path).await.unwrap(), None);
+
+        let hot = HotLookups::new(10);
+        hot.insert("org_1", Arc::new(Cell::new("us1", "us")));
+        let dump = WarmCacheDump {
+            updated_at: Some(1000),
+            negative: vec![NegativeEntry {
+                id: "org_2".into(),
+                expires_at: 1005,
+            }],
+            hot: hot.entries(),
+        };
+        dump.write(&path).await.unwrap();
+        assert_eq!(WarmCacheDump::read(&path).await.unwrap(), Some(dump));
+
+        // Corrupt dumps are reported
+        tokio::fs::write(&path, b"{").await.unwrap();
+        assert!(WarmCacheDump::read(&path).await.is_err());
+    }
+}
@@ -0,0 +1,279 @@
+//! End-to-end failover of the locator between the control plane and the backup routes.
+//!
+//! The control plane is mocked in-process so that it can be killed and restarted on the
+//! same port mid-run, and so that the cursors of the locator's requests can be checked.
+use axum::extract::{Query, State};
+use axum::routing::get;
+use axum::{Json, Router};
+use locator::backup_routes::{BackupRouteProvider, FilesystemRouteProvider};
+use locator::config::{Compression, ControlPlane, LocatorDataType, Pagination, RetryPolicy};
+use locator::locator::{Locator, LocatorError};
+use locator::types::Freshness;
+use serde_json::{Value, json};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+
+const MAPPINGS_PATH: &str = "/api/0/internal/projectkey-cell-mappings/";
+
+struct MockState {
+    // Public key and cell of every row, in update order
+    rows: Vec<(String, String)>,
+    // Cursor of every request received, None for snapshots
+    cursors: Vec<Option<String>>,
+}
+
+/// Control plane serving project key mappings. Cursors are the index of the last row of
+/// a page.
+struct MockControlPlane {
+    state: Arc<Mutex<MockState>>,
+    port: u16,
+    server: Option<(oneshot::Sender<()>, JoinHandle<()>)>,
+}
+
+impl MockControlPlane {
+    async fn start(rows: &[(&str, &str)]) -> Self {
+        let state = MockState {
+            rows: rows
+                .iter()
+                .map(|(key, cell)| (key.to_string(), cell.to_string()))
+                .collect(),
+            cursors: Vec::new(),
+        };
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut control_plane = MockControlPlane {
+            state: Arc::new(Mutex::new(state)),
+            port: listener.local_addr().unwrap().port(),
+            server: None,
+        };
+        control_plane.serve(listener);
+        control_plane
+    }
+
+    fn serve(&mut self, listener: TcpListener) {
+        let app = Router::new()
+            .route(MAPPINGS_PATH, get(mappings))
+            .with_state(self.state.clone());
+        let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
+        let handle = tokio::spawn(async move {
+            axum::serve(listener, app)
+                .with_graceful_shutdown(async {
+                    let _ = shutdown_rx.await;
+                })
+                .await
+                .unwrap();
+        });
+        self.server = Some((shutdown_tx, handle));
+    }
+
+    fn url(&self) -> String {
+        format!("http://127.0.0.1:{}", self.port)
+    }
+
+    /// Stops the server and closes its connections, requests fail to connect until it is
+    /// restarted.
+    async fn kill(&mut self) {
+        let (shutdown_tx, handle) = self.server.take().expect("control plane is running");
+        let _ = shutdown_tx.send(());
+        handle.await.unwrap();
+    }
+
+    async fn restart(&mut self) {
+        let listener = TcpListener::bind(("127.0.0.1", self.port)).await.unwrap();
+        self.serve(listener);
+    }
+
+    fn push_row(&self, key: &str, cell: &str) {
+        let mut state = self.state.lock().unwrap();
+        state.rows.push((key.to_string(), cell.to_string()));
+    }
+
+    fn cursors(&self) -> Vec<Option<String>> {
+        self.state.lock().unwrap().cursors.clone()
+    }
+}
+
+async fn mappings(
+    State(state): State<Arc<Mutex<MockState>>>,
+    Query(params): Query<HashMap<String, String>>,
+) -> Json<Value> {
+    let mut state = state.lock().unwrap();
+    let cursor = params.get("cursor").cloned();
+    state.cursors.push(cursor.clone());
+
+    let start = cursor
+        .as_deref()
+        .map_or(0, |cursor| cursor.parse::<usize>().unwrap() + 1);
+    let per_page = params
+        .get("per_page")
+        .map_or(10, |per_page| per_page.parse().unwrap());
+    let end = state.rows.len().min(start + per_page);
+    let data: Vec<Value> = state.rows[start..end]
+        .iter()
+        .enumerate()
+        .map(|(i, (key, cell))| json!({"id": start + i, "publickey": key, "cell": cell}))
+        .collect();
+    // Without new rows, incremental syncs keep their cursor
+    let next_cursor = if end > start {
+        Some((end - 1).to_string())
+    } else {
+        cursor
+    };
+
+    Json(json!({
+        "data": data,
+        "metadata": {
+            "cursor": next_cursor,
+            "has_more": end < state.rows.len(),
+            "cell_to_locality": {"us1": "us", "us2": "us"},
+        },
+    }))
+}
+
+fn key(digit: char) -> String {
+    digit.to_string().repeat(32)
+}
+
+fn new_locator(
+    control_plane_url: String,
+    backup: Arc<FilesystemRouteProvider>,
+    locality_to_default_cell: Option<HashMap<String, String>>,
+) -> Locator {
+    Locator::new(
+        LocatorDataType::ProjectKey,
+        ControlPlane {
+            url: control_plane_url,
+            // Fall back to the backup as soon as the control plane is down
+            retry: RetryPolicy {
+                max_retries: 0,
+                ..Default::default()
+            },
+            pagination: Pagination {
+                page_size: Some(2),
+                ..Default::default()
+            },
+            signing: None,
+        },
+        backup,
+        None,
+        locality_to_default_cell,
+    )
+}
+
+async fn wait_ready(locator: &Locator) {
+    for _ in 0..50 {
+        if locator.is_ready() {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    panic!("locator did not become ready");
+}
+
+#[tokio::test]
+async fn test_control_plane_failover() {
+    let keys: Vec<String> = "01234".chars().map(key).collect();
+    let rows: Vec<(&str, &str)> = keys
+        .iter()
+        .zip(["us1", "us2"].into_iter().cycle())
+        .map(|(key, cell)| (key.as_str(), cell))
+        .collect();
+    let mut control_plane = MockControlPlane::start(&rows).await;
+    let dir = tempfile::tempdir().unwrap();
+    let backup = Arc::new(FilesystemRouteProvider::new(
+        dir.path().to_str().unwrap(),
+        "backup.bin",
+        Compression::None,
+    ));
+
+    // The locator is ready once the snapshot is loaded, in pages of 2 rows
+    let primary = new_locator(control_plane.url(), backup.clone(), None);
+    assert!(!primary.is_ready());
+    assert_eq!(
+        primary.lookup(&keys[0], None).await,
+        Err(LocatorError::NotReady)
+    );
+    wait_ready(&primary).await;
+    assert_eq!(primary.lookup(&keys[1], Some("us")).await, Ok("us2".into()));
+    assert_eq!(
+        control_plane.cursors(),
+        vec![None, Some("1".into()), Some("3".into())]
+    );
+
+    // The snapshot is written to the backup with its cursor
+    let stored = backup.load().await.unwrap();
+    assert_eq!(stored.id_to_cell.len(), 5);
+    assert_eq!(stored.last_cursor.as_deref(), Some("4"));
+
+    // Known keys are still served while the control plane is down
+    control_plane.kill().await;
+    assert_eq!(primary.lookup(&keys[2], None).await, Ok("us1".into()));
+
+    // A locator started meanwhile falls back to the backup. Its mappings are never
+    // considered fresh, so unknown keys may just not be synced yet.
+    let standby = new_locator(control_plane.url(), backup.clone(), None);
+    wait_ready(&standby).await;
+    assert_eq!(standby.lookup(&keys[3], None).await, Ok("us2".into()));
+    let stale = standby.lookup_stale(&keys[4], None).await.unwrap();
+    assert_eq!(stale.freshness, Freshness::Stale);
+    assert_eq!(
+        standby.lookup(&key('e'), None).await,
+        Err(LocatorError::NotYetSynced)
+    );
+
+    // Once the control plane is back, the next refresh resumes from the backup's cursor
+    // instead of loading a new snapshot
+    let requests = control_plane.cursors().len();
+    control_plane.push_row(&key('5'), "us2");
+    control_plane.restart().await;
+    assert_eq!(standby.lookup(&key('5'), None).await, Ok("us2".into()));
+    assert_eq!(control_plane.cursors()[requests..], [Some("4".into())]);
+    let fresh = standby.lookup_stale(&keys[4], None).await.unwrap();
+    assert_eq!(fresh.freshness, Freshness::Fresh);
+
+    // The primary recovers too. Refreshes are at most once per second, so wait for the
+    // refresh of the lookup to reach the control plane.
+    tokio::time::sleep(Duration::from_millis(1100)).await;
+    assert_eq!(primary.lookup(&key('5'), None).await, Ok("us2".into()));
+
+    // Locators are no longer ready once shut down
+    primary.shutdown().await;
+    standby.shutdown().await;
+    assert!(!primary.is_ready());
+    assert!(!standby.is_ready());
+}
+
+#[tokio::test]
+async fn test_no_control_plane_and_no_backup() {
+    // Nothing listens on the port once the listener is dropped
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    drop(listener);
+    let dir = tempfile::tempdir().unwrap();
+    let backup = Arc::new(FilesystemRouteProvider::new(
+        dir.path().to_str().unwrap(),
+        "backup.bin",
+        Compression::None,
+    ));
+
+    // With default cells, the locator stays up but never becomes ready, and only the
+    // defaults are served
+    let locator = new_locator(
+        url,
+        backup,
+        Some(HashMap::from([("us".into(), "us1".into())])),
+    );
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert!(!locator.is_ready());
+    assert_eq!(
+        locator.lookup(&key('0'), Some("us")).await,
+        Ok("us1".into())
+    );
+    assert_eq!(
+        locator.lookup(&key('0'), None).await,
+        Err(LocatorError::NotReady)
+    );
+}
@@ -0,0 +1,550 @@
+//! Admin endpoints of the proxy.
+//!
+//! Besides the shared health and readiness checks, `GET /debug/reloads` lists the recent
+//! config reloads with their changes, and `GET /debug/upstreams` the health of the
+//! upstreams with health checks. The route tracing endpoints are exposed when route
+//! tracing is enabled:
+//! - `GET /debug/unmatched` lists the recorded unmatched requests
+//! - `POST /debug/replay` matches them against the route table in the YAML request body
+//!
+//! With request capture enabled, `GET /debug/captures` lists the requests and responses
+//! captured by routes.
+//!
+//! Endpoints exposing recorded requests or captures are only served with `auth`, and
+//! answer 403 otherwise.
+//!
+//! The route tables of the listeners can be checked without sending traffic:
+//! - `GET /admin/routes` lists the routes of every listener in the order they are tried
+//! - `POST /admin/routes/match` takes a JSON request with `path` and optionally `host`,
+//!   `method`, `headers` and `listener`, and returns the routes it would match, see
+//!   `RouteActions::resolve`. Flags are not evaluated.
+//!
+//! With upstream registration enabled, upstreams are registered at `/admin/upstreams`, see
+//! `upstream_registry`.
+//!
+//! With `auth`, every request must come from an allowed client IP and carry one of the
+//! bearer tokens. Requests other than health and readiness checks are logged with their
+//! client and outcome, so that the use of the debug endpoints can be audited.
+use crate::capture::CapturedRequests;
+use crate::client_ip::IpFilter;
+use crate::config::AdminAuth;
+use crate::config_diff::ReloadHistory;
+use crate::errors::ProxyError;
+use crate::health_check::UpstreamHealth;
+use crate::route_actions::{RouteActions, RouteEntry};
+use crate::route_tracing::{ReplayMatch, UnmatchedRequests};
+use http_body_util::combinators::BoxBody;
+use http_body_util::{BodyExt, Full, Limited};
+use hyper::body::{Bytes, Incoming};
+use hyper::header::HOST;
+use hyper::service::Service;
+use hyper::{Method, Request, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use shared::admin_service::{self, AdminEndpoints, AdminService, BearerTokens, is_probe};
+use shared::http::PeerAddr;
+use std::collections::{BTreeMap, HashMap};
+use std::convert::Infallible;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+
+// Route tables are small, larger bodies are rejected
+const MAX_REPLAY_BODY_BYTES: usize = 1024 * 1024;
+
+// Endpoints exposing recorded client requests and captures, only served if the listener
+// has `auth`. Their credentials are redacted, but the requests may still identify users.
+const RECORDED_REQUEST_PATHS: &[&str] = &["/debug/unmatched", "/debug/replay", "/debug/captures"];
+
+/// Name of the main listener in the route tables
+pub const MAIN_LISTENER: &str = "main";
+
+#[derive(Serialize)]
+struct ListenerRoutes<'a> {
+    listener: &'a str,
+    routes: Vec<RouteEntry>,
+}
+
+/// Request matched by `POST /admin/routes/match`
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct MatchRequest {
+    /// Listener whose routes are matched, the main listener if not set
+    listener: Option<String>,
+    host: Option<String>,
+    /// Path with an optional query
+    path: String,
+    method: Option<String>,
+    #[serde(default)]
+    headers: HashMap<String, String>,
+}
+
+impl MatchRequest {
+    /// Builds the parts of the request used for route matching.
+    fn to_request(&self) -> Result<Request<()>, String> {
+        let mut request = Request::builder().uri(self.path.as_str());
+        if let Some(method) = &self.method {
+            request = request.method(method.as_str());
+        }
+        if let Some(host) = &self.host {
+            request = request.header(HOST, host.as_str());
+        }
+        for (name, value) in &self.headers {
+            request = request.header(name.as_str(), value.as_str());
+        }
+        request.body(()).map_err(|e| e.to_string())
+    }
+}
+
+#[derive(Serialize)]
+struct MatchResponse {
+    listener: String,
+    /// Matched routes in order, the first one whose flag is enabled is used
+    matches: Vec<ReplayMatch>,
+}
+
+/// Checks of the admin requests
+#[derive(Debug)]
+pub struct AdminAccess {
+    bearer_tokens: BearerTokens,
+    ip_filter: Option<IpFilter>,
+}
+
+impl TryFrom<AdminAuth> for AdminAccess {
+    type Error = ProxyError;
+
+    fn try_from(config: AdminAuth) -> Result<Self, Self::Error> {
+        if config.bearer_tokens.is_empty() && config.client_ips.is_none() {
+            return Err(ProxyError::AdminAuth(
+                "auth requires bearer_tokens or client_ips".to_string(),
+            ));
+        }
+        let bearer_tokens =
+            BearerTokens::new(config.bearer_tokens).map_err(ProxyError::AdminAuth)?;
+        let ip_filter = config
+            .client_ips
+            .map(IpFilter::new)
+            .transpose()
+            .map_err(ProxyError::AdminAuth)?;
+
+        Ok(Self {
+            bearer_tokens,
+            ip_filter,
+        })
+    }
+}
+
+impl AdminAccess {
+    /// Returns the status the request is rejected with, if it is not allowed. Health and
+    /// readiness probes are always allowed, they cannot send a token.
+    fn check<B>(&self, request: &Request<B>) -> Result<(), StatusCode> {
+        if is_probe(request) {
+            return Ok(());
+        }
+        if let Some(ip_filter) = &self.ip_filter
+            && !ip_filter.allows(client_ip(request))
+        {
+            return Err(StatusCode::FORBIDDEN);
+        }
+        if self.bearer_tokens.is_empty() || self.bearer_tokens.allows(request) {
+            return Ok(());
+        }
+        Err(StatusCode::UNAUTHORIZED)
+    }
+}
+
+fn client_ip<B>(request: &Request<B>) -> Option<std::net::IpAddr> {
+    request
+        .extensions()
+        .get::<PeerAddr>()
+        .map(|peer_addr| peer_addr.0.ip())
+}
+
+pub struct ProxyAdminService<F> {
+    admin: AdminService<F, ProxyError>,
+    access: Option<AdminAccess>,
+    unmatched_requests: Option<Arc<UnmatchedRequests>>,
+    captured_requests: Option<Arc<CapturedRequests>>,
+    reloads: Arc<ReloadHistory>,
+    /// Of the main and the additional listeners
+    upstream_health: Vec<Arc<UpstreamHealth>>,
+    /// By listener name, the main listener first
+    routes: Vec<(String, Arc<RouteActions>)>,
+}
+
+impl<F> ProxyAdminService<F>
+where
+    F: Fn() -> bool,
+{
+    pub fn new(
+        is_ready: F,
+        unmatched_requests: Option<Arc<UnmatchedRequests>>,
+        captured_requests: Option<Arc<CapturedRequests>>,
+        reloads: Arc<ReloadHistory>,
+        upstream_health: Vec<Arc<UpstreamHealth>>,
+        routes: Vec<(String, Arc<RouteActions>)>,
+        access: Option<AdminAccess>,
+    ) -> Self {
+        Self {
+            admin: AdminService::new(is_ready),
+            access,
+            unmatched_requests,
+            captured_requests,
+            reloads,
+            upstream_health,
+            routes,
+        }
+    }
+
+    /// Serves the endpoints as well, see `AdminService::with_endpoints`.
+    pub fn with_endpoints(mut self, endpoints: Arc<dyn AdminEndpoints>) -> Self {
+        self.admin = self.admin.with_endpoints(endpoints);
+        self
+    }
+}
+
+impl<F> Service<Request<Incoming>> for ProxyAdminService<F>
+where
+    F: Fn() -> bool + Clone + Send + 'static,
+{
+    type Response = Response<BoxBody<Bytes, Infallible>>;
+    type Error = ProxyError;
+    type Future =
+        Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send + 'static>>;
+
+    fn call(&self, req: Request<Incoming>) -> Self::Future {
+        let checked = self.access.as_ref().map(|access| access.check(&req));
+        // Probes are too frequent to be logged
+        if !is_probe(&req) || matches!(checked, Some(Err(_))) {
+            tracing::info!(
+                method = %req.method(),
+                path = req.uri().path(),
+                client_ip = ?client_ip(&req),
+                rejected = checked.and_then(|checked| checked.err()).map(|status| status.as_u16()),
+                "Admin request"
+            );
+        }
+        if let Some(Err(status)) = checked {
+            let message = status.canonical_reason().unwrap_or_default().to_string();
+            return Box::pin(async move { Ok(text_response(status, message)) });
+        }
+        if self.access.is_none() && RECORDED_REQUEST_PATHS.contains(&req.uri().path()) {
+            let message = "recorded requests require admin auth".to_string();
+            return Box::pin(async move { Ok(text_response(StatusCode::FORBIDDEN, message)) });
+        }
+
+        if (req.method(), req.uri().path()) == (&Method::GET, "/debug/reloads") {
+            let reloads = self.reloads.clone();
+            return Box::pin(async move { Ok(json_response(&reloads.events())) });
+        }
+        if (req.method(), req.uri().path()) == (&Method::GET, "/debug/upstreams") {
+            // Listeners sharing an upstream probe it alike, so it is reported once
+            let report: BTreeMap<_, _> = self
+                .upstream_health
+                .iter()
+                .flat_map(|health| health.report())
+                .collect();
+            return Box::pin(async move { Ok(json_response(&report)) });
+        }
+        if (req.method(), req.uri().path()) == (&Method::GET, "/admin/routes") {
+            let tables: Vec<_> = self
+                .routes
+                .iter()
+                .map(|(listener, route_actions)| ListenerRoutes {
+                    listener,
+                    routes: route_actions.table(),
+                })
+                .collect();
+            // Serialized before the future, which must not borrow the route tables
+            let response = json_response(&tables);
+            return Box::pin(async move { Ok(response) });
+        }
+        if (req.method(), req.uri().path()) == (&Method::POST, "/admin/routes/match") {
+            let routes = self.routes.clone();
+            return Box::pin(async move {
+                let body = match Limited::new(req.into_body(), MAX_REPLAY_BODY_BYTES)
+                    .collect()
+                    .await
+                {
+                    Ok(body) => body.to_bytes(),
+                    Err(e) => return Ok(text_response(StatusCode::BAD_REQUEST, e.to_string())),
+                };
+                Ok(match_route(&routes, &body))
+            });
+        }
+        if (req.method(), req.uri().path()) == (&Method::GET, "/debug/captures")
+            && let Some(captured_requests) = self.captured_requests.clone()
+        {
+            return Box::pin(async move { Ok(json_response(&captured_requests.captured())) });
+        }
+
+        let Some(unmatched_requests) = self.unmatched_requests.clone() else {
+            return self.admin.call(req);
+        };
+
+        match (req.method(), req.uri().path()) {
+            (&Method::GET, "/debug/unmatched") => {
+                Box::pin(async move { Ok(json_response(&unmatched_requests.recorded())) })
+            }
+            (&Method::POST, "/debug/replay") => Box::pin(async move {
+                let body = match Limited::new(req.into_body(), MAX_REPLAY_BODY_BYTES)
+                    .collect()
+                    .await
+                {
+                    Ok(body) => body.to_bytes(),
+                    Err(e) => return Ok(text_response(StatusCode::BAD_REQUEST, e.to_string())),
+                };
+
+                let routes = String::from_utf8_lossy(&body);
+                match unmatched_requests.replay(&routes) {
+                    Ok(results) => Ok(json_response(&results)),
+                    Err(e) => Ok(text_response(StatusCode::BAD_REQUEST, e.to_string())),
+                }
+            }),
+            _ => self.admin.call(req),
+        }
+    }
+}
+
+/// Matches the request in `body` against the routes of its listener.
+fn match_route(
+    routes: &[(String, Arc<RouteActions>)],
+    body: &[u8],
+) -> Response<BoxBody<Bytes, Infallible>> {
+    let request: MatchRequest = match serde_json::from_slice(body) {
+        Ok(request) => request,
+        Err(e) => return text_response(StatusCode::BAD_REQUEST, e.to_string()),
+    };
+    let listener = request.listener.as_deref().unwrap_or(MAIN_LISTENER);
+    let Some((_, route_actions)) = routes.iter().find(|(name, _)| name == listener) else {
+        return text_response(
+            StatusCode::NOT_FOUND,
+            format!("unknown listener: {listener}"),
+        );
+    };
+    match request.to_request() {
+        Ok(http_request) => json_response(&MatchResponse {
+            listener: listener.to_string(),
+            matches: route_actions
+                .resolve(&http_request)
+                .into_iter()
+                .map(ReplayMatch::from)
+                .collect(),
+        }),
+        Err(e) => text_response(StatusCode::BAD_REQUEST, e),
+    }
+}
+
+fn json_response<T: Serialize>(value: &T) -> Response<BoxBody<Bytes, Infallible>> {
+    admin_service::json_response(StatusCode::OK, value).map(|body| Full::new(body).boxed())
+}
+
+fn text_response(status: StatusCode, message: String) -> Response<BoxBody<Bytes, Infallible>> {
+    let mut response = Response::new(Full::new(Bytes::from(message + "\n")).boxed());
+    *response.status_mut() = status;
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{ClientIps, Route as RouteConfig};
+    use hyper::header::{AUTHORIZATION, HeaderValue};
+
+    fn request(peer: &str, authorization: Option<&str>) -> Request<()> {
+        let mut request = Request::builder().uri("/debug/routes").body(()).unwrap();
+        request
+            .extensions_mut()
+            .insert(PeerAddr(format!("{peer}:1234").parse().unwrap()));
+        if let Some(authorization) = authorization {
+            request
+                .headers_mut()
+                .insert(AUTHORIZATION, HeaderValue::from_str(authorization).unwrap());
+        }
+        request
+    }
+
+    #[test]
+    fn test_access() {
+        let access = AdminAccess::try_from(AdminAuth {
+            bearer_tokens: vec!["old".into(), "new".into()],
+            client_ips: Some(ClientIps::Allow {
+                allow: vec!["10.0.0.0/8".into()],
+            }),
+        })
+        .unwrap();
+
+        let cases = [
+            (request("10.0.0.1", Some("Bearer new")), Ok(())),
+            (request("10.0.0.1", Some("Bearer old")), Ok(())),
+            (
+                request("10.0.0.1", Some("Bearer other")),
+                Err(StatusCode::UNAUTHORIZED),
+            ),
+            (
+                request("10.0.0.1", Some("Basic new")),
+                Err(StatusCode::UNAUTHORIZED),
+            ),
+            (request("10.0.0.1", None), Err(StatusCode::UNAUTHORIZED)),
+            (
+                request("192.168.0.1", Some("Bearer new")),
+                Err(StatusCode::FORBIDDEN),
+            ),
+        ];
+        for (request, expected) in cases {
+            assert_eq!(access.check(&request), expected, "{request:?}");
+        }
+
+        // Probes are exempt
+        for path in ["/health", "/ready"] {
+            let mut probe = request("192.168.0.1", None);
+            *probe.uri_mut() = path.parse().unwrap();
+            assert_eq!(access.check(&probe), Ok(()), "{path}");
+        }
+
+        let tokens_only = AdminAccess::try_from(AdminAuth {
+            bearer_tokens: vec!["new".into()],
+            client_ips: None,
+        })
+        .unwrap();
+        assert_eq!(
+            tokens_only.check(&request("192.168.0.1", Some("Bearer new"))),
+            Ok(())
+        );
+
+        for invalid in [
+            AdminAuth {
+                bearer_tokens: vec![],
+                client_ips: None,
+            },
+            AdminAuth {
+                bearer_tokens: vec!["".into()],
+                client_ips: None,
+            },
+            AdminAuth {
+                bearer_tokens: vec![],
+                client_ips: Some(ClientIps::Allow {
+                    allow: vec!["10.0.0.0/40".into()],
+                }),
+            },
+        ] {
+            assert!(AdminAccess::try_from(invalid).is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_match_route() {
+        let route_table = |yaml: &str| {
+            let routes: Vec<RouteConfig> = serde_yaml::from_str(yaml).unwrap();
+            Arc::new(RouteActions::try_new(routes).unwrap())
+        };
+        let routes = vec![
+            (
+                MAIN_LISTENER.to_string(),
+                route_table(
+                    r#"
+- match: {host: us.sentry.io, path: "/api/0/organizations/*", flag: new-cell}
+  action: {to: new}
+- match: {path: "/api/*"}
+  action: {to: main}
+"#,
+                ),
+            ),
+            (
+                "internal".to_string(),
+                route_table("- match: {path: \"/internal/*\"}\n  action: {to: internal}"),
+            ),
+        ];
+        let call = |body: &str| {
+            let response = match_route(&routes, body.as_bytes());
+            let status = response.status();
+            async move {
+                let body = response.into_body().collect().await.unwrap().to_bytes();
+                (status, serde_json::from_slice(&body).ok())
+            }
+        };
+
+        // The flagged route is returned along with the route used if its flag is disabled
+        let (status, body) = call(
+            r#"{"host": "us.sentry.io", "path": "/api/0/organizations/acme/issues/", "method": "GET"}"#,
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        let body: serde_json::Value = body.unwrap();
+        assert_eq!(body["listener"], "main");
+        let matches = body["matches"].as_array().unwrap();
+        assert_eq!(matches.len(), 2);
+        assert_eq!(matches[0]["flag"], "new-cell");
+        assert_eq!(matches[1]["pattern"], "/api/*");
+        assert_eq!(matches[1]["action"]["to"], "main");
+
+        let (_, body) = call(r#"{"path": "/internal/x", "listener": "internal"}"#).await;
+        assert_eq!(body.unwrap()["matches"][0]["action"]["to"], "internal");
+        let (_, body) = call(r#"{"path": "/other/"}"#).await;
+        assert_eq!(body.unwrap()["matches"], serde_json::json!([]));
+
+        let (status, _) = call(r#"{"path": "/api/", "listener": "other"}"#).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        for invalid in [
+            r#"{"host": "us.sentry.io"}"#,
+            r#"{"path": "/", "method": "G T"}"#,
+        ] {
+            let (status, _) = call(invalid).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST, "{invalid}");
+        }
+    }
+
+    /// Serves the admin service with route tracing on a local port
+    async fn serve_admin(access: Option<AdminAccess>) -> String {
+        let config: crate::config::Config = serde_yaml::from_str(
+            "{locator: {type: url, url: \"http://locator\"}, upstreams: [], routes: []}",
+        )
+        .unwrap();
+        let service = ProxyAdminService::new(
+            || true,
+            Some(Arc::new(UnmatchedRequests::new(10))),
+            None,
+            Arc::new(ReloadHistory::new(&config, None)),
+            Vec::new(),
+            Vec::new(),
+            access,
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(shared::http::serve_http_service(
+            listener,
+            service,
+            None,
+            std::future::pending(),
+        ));
+        format!("http://{addr}")
+    }
+
+    #[tokio::test]
+    async fn test_recorded_requests_require_auth() {
+        let client = reqwest::Client::new();
+
+        let url = serve_admin(None).await;
+        let response = client
+            .get(format!("{url}/debug/unmatched"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let response = client.get(format!("{url}/health")).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let access = AdminAccess::try_from(AdminAuth {
+            bearer_tokens: vec!["token".into()],
+            client_ips: None,
+        })
+        .unwrap();
+        let url = serve_admin(Some(access)).await;
+        let response = client
+            .get(format!("{url}/debug/unmatched"))
+            .bearer_auth("token")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+}
@@ -0,0 +1,140 @@
+//! Per-route allowed HTTP methods.
+//!
+//! Routes match requests regardless of their method. Routes with `methods` only forward
+//! the allowed methods, and answer others with 405 and an `Allow` header listing the
+//! allowed methods, so that clients learn what the route accepts. With `answer_options`,
+//! `OPTIONS` requests are answered with 204 and the same `Allow` header, unless `OPTIONS`
+//! itself is allowed.
+use crate::config::RouteMethods;
+use crate::errors::ProxyError;
+use http::header::ALLOW;
+use http::{HeaderValue, Method, Response, StatusCode};
+use http_body_util::combinators::BoxBody;
+use http_body_util::{BodyExt, Empty};
+use hyper::body::Bytes;
+use shared::http::make_boxed_error_response;
+
+#[derive(Debug, PartialEq)]
+pub struct AllowedMethods {
+    methods: Vec<Method>,
+    answer_options: bool,
+    // Value of the `Allow` header
+    allow: HeaderValue,
+}
+
+/// What to do with a request of a route with allowed methods
+#[derive(Debug, PartialEq)]
+pub enum MethodCheck {
+    Forward,
+    /// Answered with 204
+    AnswerOptions,
+    /// Answered with 405
+    NotAllowed,
+}
+
+impl TryFrom<RouteMethods> for AllowedMethods {
+    type Error = ProxyError;
+
+    fn try_from(config: RouteMethods) -> Result<Self, Self::Error> {
+        if config.allow.is_empty() {
+            return Err(ProxyError::InvalidRoute(
+                "methods.allow must not be empty".to_string(),
+            ));
+        }
+        let mut methods = Vec::new();
+        for method in &config.allow {
+            let method = Method::from_bytes(method.to_ascii_uppercase().as_bytes())
+                .map_err(|_| ProxyError::InvalidRoute(format!("Invalid method: {method}")))?;
+            if !methods.contains(&method) {
+                methods.push(method);
+            }
+        }
+        let answer_options = config.answer_options && !methods.contains(&Method::OPTIONS);
+
+        let mut allow: Vec<&str> = methods.iter().map(Method::as_str).collect();
+        if answer_options {
+            allow.push(Method::OPTIONS.as_str());
+        }
+        let allow = HeaderValue::from_str(&allow.join(", "))
+            .map_err(|e| ProxyError::InvalidRoute(e.to_string()))?;
+
+        Ok(Self {
+            methods,
+            answer_options,
+            allow,
+        })
+    }
+}
+
+impl AllowedMethods {
+    pub fn check(&self, method: &Method) -> MethodCheck {
+        if self.methods.contains(method) {
+            MethodCheck::Forward
+        } else if self.answer_options && method == Method::OPTIONS {
+            MethodCheck::AnswerOptions
+        } else {
+            MethodCheck::NotAllowed
+        }
+    }
+
+    /// Value of the `Allow` header of local answers
+    pub fn allow_header(&self) -> HeaderValue {
+        self.allow.clone()
+    }
+
+    /// Local answer to requests with the method, None if they are forwarded.
+    pub fn local_response(&self, method: &Method) -> Option<Response<BoxBody<Bytes, ProxyError>>> {
+        let mut response = match self.check(method) {
+            MethodCheck::Forward => return None,
+            MethodCheck::AnswerOptions => {
+                let mut response = Response::new(Empty::new().map_err(|e| match e {}).boxed());
+                *response.status_mut() = StatusCode::NO_CONTENT;
+                response
+            }
+            MethodCheck::NotAllowed => make_boxed_error_response(StatusCode::METHOD_NOT_ALLOWED),
+        };
+        response.headers_mut().insert(ALLOW, self.allow_header());
+        Some(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn allowed(allow: &[&str], answer_options: bool) -> Result<AllowedMethods, ProxyError> {
+        AllowedMethods::try_from(RouteMethods {
+            allow: allow.iter().map(|method| method.to_string()).collect(),
+            answer_options,
+        })
+    }
+
+    #[test]
+    fn test_check() {
+        let methods = allowed(&["get", "POST", "GET"], true).unwrap();
+        assert_eq!(methods.check(&Method::GET), MethodCheck::Forward);
+        assert_eq!(methods.check(&Method::POST), MethodCheck::Forward);
+        assert_eq!(methods.check(&Method::OPTIONS), MethodCheck::AnswerOptions);
+        assert_eq!(methods.check(&Method::DELETE), MethodCheck::NotAllowed);
+        assert_eq!(methods.allow_header(), "GET, POST, OPTIONS");
+        assert!(methods.local_response(&Method::GET).is_none());
+        let response = methods.local_response(&Method::DELETE).unwrap();
+        assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(response.headers()[ALLOW], "GET, POST, OPTIONS");
+        let response = methods.local_response(&Method::OPTIONS).unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert_eq!(response.headers()[ALLOW], "GET, POST, OPTIONS");
+
+        // Forwarded if allowed
+        let methods = allowed(&["GET", "OPTIONS"], true).unwrap();
+        assert_eq!(methods.check(&Method::OPTIONS), MethodCheck::Forward);
+        assert_eq!(methods.allow_header(), "GET, OPTIONS");
+
+        let methods = allowed(&["GET"], false).unwrap();
+        assert_eq!(methods.check(&Method::OPTIONS), MethodCheck::NotAllowed);
+        assert_eq!(methods.allow_header(), "GET");
+
+        assert!(allowed(&[], false).is_err());
+        assert!(allowed(&["GE T"], false).is_err());
+    }
+}
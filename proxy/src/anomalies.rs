@@ -0,0 +1,218 @@
+//! Sentry events for routing anomalies.
+//!
+//! Anomalies are reported as `tracing` errors, which the Sentry tracing layer installed by
+//! synapse sends as events once the Sentry client is initialized. Warnings and info logs of
+//! the same request, such as an upstream requesting backoff, are attached as breadcrumbs.
+//!
+//! Occurrences are counted per anomaly in fixed windows, and an event is sent when the
+//! count reaches the anomaly's threshold. At most one event is sent per anomaly and
+//! `min_event_interval_secs`, so that a persistent anomaly does not flood Sentry. Events
+//! include the number of threshold crossings that were suppressed since the last one.
+use crate::config::{AnomalyEvents as AnomalyEventsConfig, Resolver};
+use crate::errors::ProxyError;
+use http::StatusCode;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// Bounds the memory used by anomalies keyed by client input, such as hosts
+const MAX_TRACKED: usize = 1000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+enum Kind {
+    UnmatchedHost,
+    ResolverFailures,
+    UpstreamBackoff,
+}
+
+#[derive(Debug)]
+struct Counter {
+    window_start: Instant,
+    count: u64,
+    last_event: Option<Instant>,
+    suppressed: u64,
+}
+
+#[derive(Clone, Debug)]
+pub struct AnomalyEvents {
+    window: Duration,
+    unmatched_host_threshold: u64,
+    resolver_failure_threshold: u64,
+    min_event_interval: Duration,
+    counters: Arc<Mutex<HashMap<(Kind, String), Counter>>>,
+}
+
+impl From<AnomalyEventsConfig> for AnomalyEvents {
+    fn from(config: AnomalyEventsConfig) -> Self {
+        Self {
+            window: Duration::from_secs(config.window_secs),
+            unmatched_host_threshold: config.unmatched_host_threshold,
+            resolver_failure_threshold: config.resolver_failure_threshold,
+            min_event_interval: Duration::from_secs(config.min_event_interval_secs),
+            counters: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+}
+
+impl AnomalyEvents {
+    /// A request to `host` matched no route.
+    pub fn unmatched(&self, host: Option<&str>) {
+        let host = host.unwrap_or("none");
+        if let Some(suppressed) =
+            self.record(Kind::UnmatchedHost, host, self.unmatched_host_threshold)
+        {
+            tracing::error!(
+                host,
+                requests = self.unmatched_host_threshold,
+                window_secs = self.window.as_secs(),
+                suppressed,
+                "High volume of requests matching no route"
+            );
+        }
+    }
+
+    /// A dynamic route's resolver failed to resolve an upstream.
+    pub fn resolver_failed(&self, resolver: &Resolver, error: &ProxyError) {
+        let resolver = resolver.name();
+        if let Some(suppressed) = self.record(
+            Kind::ResolverFailures,
+            resolver,
+            self.resolver_failure_threshold,
+        ) {
+            tracing::error!(
+                resolver,
+                %error,
+                failures = self.resolver_failure_threshold,
+                window_secs = self.window.as_secs(),
+                suppressed,
+                "Resolver failures above threshold"
+            );
+        }
+    }
+
+    /// An upstream started backing off, so requests to it are answered locally.
+    pub fn backoff_started(&self, upstream: &str, status: StatusCode, delay: Duration) {
+        if let Some(suppressed) = self.record(Kind::UpstreamBackoff, upstream, 1) {
+            tracing::error!(
+                upstream,
+                status = status.as_u16(),
+                ?delay,
+                suppressed,
+                "Upstream backoff opened"
+            );
+        }
+    }
+
+    /// Counts an occurrence. Returns the number of suppressed events if an event is due.
+    fn record(&self, kind: Kind, key: &str, threshold: u64) -> Option<u64> {
+        let now = Instant::now();
+        let mut counters = self.counters.lock().unwrap_or_else(|e| e.into_inner());
+
+        let tracked_key = (kind, key.to_string());
+        if !counters.contains_key(&tracked_key) && counters.len() >= MAX_TRACKED {
+            // Forget anomalies that are neither counting nor rate limited
+            counters.retain(|_, counter| {
+                now.duration_since(counter.window_start) < self.window
+                    || counter
+                        .last_event
+                        .is_some_and(|last| now.duration_since(last) < self.min_event_interval)
+            });
+            if counters.len() >= MAX_TRACKED {
+                return None;
+            }
+        }
+
+        let counter = counters.entry(tracked_key).or_insert(Counter {
+            window_start: now,
+            count: 0,
+            last_event: None,
+            suppressed: 0,
+        });
+        if now.duration_since(counter.window_start) >= self.window {
+            counter.window_start = now;
+            counter.count = 0;
+        }
+        counter.count += 1;
+        if counter.count != threshold {
+            return None;
+        }
+
+        if counter
+            .last_event
+            .is_some_and(|last| now.duration_since(last) < self.min_event_interval)
+        {
+            counter.suppressed += 1;
+            return None;
+        }
+        counter.last_event = Some(now);
+        Some(std::mem::take(&mut counter.suppressed))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn anomaly_events(window: Duration, min_event_interval: Duration) -> AnomalyEvents {
+        let mut events = AnomalyEvents::from(AnomalyEventsConfig::default());
+        events.window = window;
+        events.min_event_interval = min_event_interval;
+        events
+    }
+
+    #[test]
+    fn test_threshold() {
+        let events = anomaly_events(Duration::from_secs(60), Duration::ZERO);
+
+        // Reported once per window, when the count reaches the threshold
+        assert_eq!(events.record(Kind::UnmatchedHost, "a.example", 3), None);
+        assert_eq!(events.record(Kind::UnmatchedHost, "a.example", 3), None);
+        assert_eq!(events.record(Kind::UnmatchedHost, "b.example", 3), None);
+        assert_eq!(events.record(Kind::UnmatchedHost, "a.example", 3), Some(0));
+        assert_eq!(events.record(Kind::UnmatchedHost, "a.example", 3), None);
+
+        // Counted separately per anomaly
+        assert_eq!(
+            events.record(Kind::UpstreamBackoff, "a.example", 1),
+            Some(0)
+        );
+    }
+
+    #[test]
+    fn test_window() {
+        let events = anomaly_events(Duration::from_millis(20), Duration::ZERO);
+
+        assert_eq!(events.record(Kind::ResolverFailures, "r", 2), None);
+        std::thread::sleep(Duration::from_millis(30));
+        // The previous window's occurrence is forgotten
+        assert_eq!(events.record(Kind::ResolverFailures, "r", 2), None);
+        assert_eq!(events.record(Kind::ResolverFailures, "r", 2), Some(0));
+    }
+
+    #[test]
+    fn test_rate_limit() {
+        let events = anomaly_events(Duration::ZERO, Duration::from_millis(50));
+
+        // Every occurrence starts a new window and reaches the threshold
+        assert_eq!(events.record(Kind::UpstreamBackoff, "us1", 1), Some(0));
+        assert_eq!(events.record(Kind::UpstreamBackoff, "us1", 1), None);
+        assert_eq!(events.record(Kind::UpstreamBackoff, "us1", 1), None);
+        assert_eq!(events.record(Kind::UpstreamBackoff, "us2", 1), Some(0));
+
+        std::thread::sleep(Duration::from_millis(60));
+        assert_eq!(events.record(Kind::UpstreamBackoff, "us1", 1), Some(2));
+    }
+
+    #[test]
+    fn test_max_tracked() {
+        let events = anomaly_events(Duration::from_secs(60), Duration::ZERO);
+        for i in 0..MAX_TRACKED {
+            events.record(Kind::UnmatchedHost, &i.to_string(), 2);
+        }
+
+        // New hosts are not tracked while all others are counting
+        assert_eq!(events.record(Kind::UnmatchedHost, "new", 1), None);
+        // Known hosts still are
+        assert_eq!(events.record(Kind::UnmatchedHost, "0", 2), Some(0));
+    }
+}
@@ -0,0 +1,180 @@
+//! Per-upstream backoff honoring `Retry-After`.
+//!
+//! When an upstream answers 429 or 503 with a `Retry-After` header, further requests to that
+//! upstream are answered locally with the same status until the indicated delay has passed,
+//! rather than forwarding them to an upstream that asked clients to back off. The delay is
+//! capped at `max_backoff_secs`.
+//!
+//! Route retries leave responses with `Retry-After` alone, see `retry`.
+use crate::config::UpstreamBackoff as UpstreamBackoffConfig;
+use crate::errors::ProxyError;
+use crate::metrics_defs::UPSTREAM_BACKOFF;
+use http::header::{HeaderMap, HeaderValue, RETRY_AFTER};
+use http_body_util::combinators::BoxBody;
+use hyper::body::Bytes;
+use hyper::{Response, StatusCode};
+use shared::http::make_boxed_error_response;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+#[derive(Clone, Debug)]
+pub struct UpstreamBackoff {
+    max_backoff: Duration,
+    windows: Arc<Mutex<HashMap<String, Window>>>,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Window {
+    until: Instant,
+    status: StatusCode,
+}
+
+impl From<UpstreamBackoffConfig> for UpstreamBackoff {
+    fn from(config: UpstreamBackoffConfig) -> Self {
+        Self::new(Duration::from_secs(config.max_backoff_secs))
+    }
+}
+
+impl UpstreamBackoff {
+    pub fn new(max_backoff: Duration) -> Self {
+        Self {
+            max_backoff,
+            windows: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Returns the local response if the upstream is backing off.
+    pub fn check(&self, upstream: &str) -> Option<Response<BoxBody<Bytes, ProxyError>>> {
+        let now = Instant::now();
+        let mut windows = self.windows.lock().unwrap_or_else(|e| e.into_inner());
+
+        let window = *windows.get(upstream)?;
+        if window.until <= now {
+            windows.remove(upstream);
+            return None;
+        }
+        drop(windows);
+
+        metrics::counter!(UPSTREAM_BACKOFF.name, "upstream" => upstream.to_string()).increment(1);
+
+        let mut response = make_boxed_error_response(window.status);
+        // Rounded up, so that clients do not retry before the window has passed
+        let remaining = (window.until - now).as_secs_f64().ceil() as u64;
+        response
+            .headers_mut()
+            .insert(RETRY_AFTER, HeaderValue::from(remaining));
+        Some(response)
+    }
+
+    /// Starts backing off from the upstream if the response asks for it. Returns the delay
+    /// if the upstream was not backing off already.
+    pub fn observe(
+        &self,
+        upstream: &str,
+        status: StatusCode,
+        headers: &HeaderMap,
+    ) -> Option<Duration> {
+        if status != StatusCode::TOO_MANY_REQUESTS && status != StatusCode::SERVICE_UNAVAILABLE {
+            return None;
+        }
+
+        let delay = headers
+            .get(RETRY_AFTER)
+            .and_then(|value| parse_retry_after(value, SystemTime::now()))?
+            .min(self.max_backoff);
+        if delay.is_zero() {
+            return None;
+        }
+
+        tracing::info!(upstream, ?delay, "Upstream requested backoff");
+        let now = Instant::now();
+        let previous = self
+            .windows
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(
+                upstream.to_string(),
+                Window {
+                    until: now + delay,
+                    status,
+                },
+            );
+        match previous {
+            Some(window) if window.until > now => None,
+            _ => Some(delay),
+        }
+    }
+}
+
+/// Parses a `Retry-After` value, either a number of seconds or an HTTP date.
+fn parse_retry_after(value: &HeaderValue, now: SystemTime) -> Option<Duration> {
+    let value = value.to_str().ok()?.trim();
+
+    if let Ok(secs) = value.parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+
+    let date = httpdate::parse_http_date(value).ok()?;
+    // Dates in the past mean no delay
+    Some(date.duration_since(now).unwrap_or_default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_retry_after() {
+        let now = httpdate::parse_http_date("Wed, 21 Oct 2015 07:28:00 GMT").unwrap();
+        let parse = |value: &'static str| parse_retry_after(&HeaderValue::from_static(value), now);
+
+        assert_eq!(parse("120"), Some(Duration::from_secs(120)));
+        assert_eq!(
+            parse("Wed, 21 Oct 2015 07:28:30 GMT"),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(parse("Wed, 21 Oct 2015 07:27:00 GMT"), Some(Duration::ZERO));
+        assert_eq!(parse("soon"), None);
+    }
+
+    #[test]
+    fn test_backoff() {
+        let backoff = UpstreamBackoff::new(Duration::from_secs(60));
+        let retry_after = |value: &'static str| {
+            HeaderMap::from_iter([(RETRY_AFTER, HeaderValue::from_static(value))])
+        };
+
+        // Only 429 and 503 with Retry-After start a backoff
+        backoff.observe("us1", StatusCode::OK, &retry_after("30"));
+        backoff.observe("us1", StatusCode::SERVICE_UNAVAILABLE, &HeaderMap::new());
+        assert!(backoff.check("us1").is_none());
+
+        // The delay is capped at the max backoff
+        let started = backoff.observe("us1", StatusCode::TOO_MANY_REQUESTS, &retry_after("3600"));
+        assert_eq!(started, Some(Duration::from_secs(60)));
+        // Extends the backoff without starting a new one
+        let started = backoff.observe("us1", StatusCode::TOO_MANY_REQUESTS, &retry_after("3600"));
+        assert_eq!(started, None);
+        let response = backoff.check("us1").unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers().get(RETRY_AFTER).unwrap(), "60");
+
+        // Other upstreams are unaffected
+        assert!(backoff.check("us2").is_none());
+    }
+
+    #[test]
+    fn test_backoff_expired() {
+        let backoff = UpstreamBackoff::new(Duration::from_millis(10));
+        backoff.observe(
+            "us1",
+            StatusCode::SERVICE_UNAVAILABLE,
+            &HeaderMap::from_iter([(RETRY_AFTER, HeaderValue::from_static("1"))]),
+        );
+        assert!(backoff.check("us1").is_some());
+
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(backoff.check("us1").is_none());
+    }
+}
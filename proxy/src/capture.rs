@@ -0,0 +1,416 @@
+//! Sampled capture of full requests and responses, for debugging.
+//!
+//! Routes with `capture` settings record a share of their requests together with the
+//! responses sent to clients. The captures are kept in a ring buffer and exposed on the
+//! admin listener. Credentials are redacted like in route tracing, and bodies are truncated
+//! to `max_body_bytes`. Bodies are copied while they stream through the proxy, so a capture
+//! is only kept once both bodies were read to the end or dropped.
+//!
+//! Sampling is deterministic, a route with a sample rate of 0.01 captures every hundredth
+//! request. Capturing stops at `until`, so that a forgotten capture does not keep
+//! recording traffic.
+use crate::config::{RequestCapture, RouteCapture};
+use crate::errors::ProxyError;
+use chrono::{DateTime, Utc};
+use http::header::HOST;
+use http::{Request, Response};
+use hyper::body::{Body, Bytes, Frame, SizeHint};
+use serde::Serialize;
+use shared::http::redacted_headers;
+use std::collections::VecDeque;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Decides which requests of a route are captured.
+#[derive(Debug)]
+pub struct CaptureSampler {
+    sample_rate: f64,
+    until: DateTime<Utc>,
+    max_body_bytes: usize,
+    // Requests of the route seen until `until`
+    seen: AtomicU64,
+}
+
+impl PartialEq for CaptureSampler {
+    fn eq(&self, other: &Self) -> bool {
+        (self.sample_rate, self.until, self.max_body_bytes)
+            == (other.sample_rate, other.until, other.max_body_bytes)
+    }
+}
+
+impl TryFrom<RouteCapture> for CaptureSampler {
+    type Error = ProxyError;
+
+    fn try_from(config: RouteCapture) -> Result<Self, Self::Error> {
+        if !(0.0..=1.0).contains(&config.sample_rate) {
+            return Err(ProxyError::InvalidRoute(format!(
+                "Capture sample rate must be between 0 and 1: {}",
+                config.sample_rate
+            )));
+        }
+
+        Ok(Self {
+            sample_rate: config.sample_rate,
+            until: config.until,
+            max_body_bytes: config.max_body_bytes,
+            seen: AtomicU64::new(0),
+        })
+    }
+}
+
+impl CaptureSampler {
+    /// Whether the current request of the route is captured
+    pub fn sample(&self, now: DateTime<Utc>) -> bool {
+        if now >= self.until {
+            return false;
+        }
+        let seen = self.seen.fetch_add(1, Ordering::Relaxed) as f64;
+        // Captured whenever the expected number of captures reaches the next whole number
+        ((seen + 1.0) * self.sample_rate).floor() > (seen * self.sample_rate).floor()
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct CapturedBody {
+    /// Body as text, invalid UTF-8 is replaced
+    pub data: String,
+    /// Whether the body was longer than the captured part
+    pub truncated: bool,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct CapturedResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: CapturedBody,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct CapturedRequest {
+    /// Unix timestamp in seconds
+    pub timestamp: u64,
+    /// Path pattern of the route that captured the request
+    pub route: String,
+    pub method: String,
+    pub host: Option<String>,
+    pub path: String,
+    pub headers: Vec<(String, String)>,
+    /// Only read if the request was forwarded to an upstream
+    pub body: CapturedBody,
+    /// Response sent to the client, missing if the request was dropped before
+    pub response: Option<CapturedResponse>,
+}
+
+pub struct CapturedRequests {
+    capacity: usize,
+    // Oldest first
+    captures: Mutex<VecDeque<CapturedRequest>>,
+}
+
+impl From<RequestCapture> for CapturedRequests {
+    fn from(config: RequestCapture) -> Self {
+        Self::new(config.max_requests)
+    }
+}
+
+impl CapturedRequests {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            captures: Mutex::new(VecDeque::with_capacity(capacity)),
+        }
+    }
+
+    /// Starts capturing `request`. The capture is kept once all handles to it are dropped.
+    pub fn start<B>(
+        self: &Arc<Self>,
+        request: &Request<B>,
+        route: &str,
+        sampler: &CaptureSampler,
+    ) -> Arc<Capture> {
+        // Same as route resolution, the host is taken from the URI or the Host header
+        let host = request
+            .uri()
+            .host()
+            .or_else(|| request.headers().get(HOST).and_then(|h| h.to_str().ok()));
+
+        let captured = CapturedRequest {
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+            route: route.to_string(),
+            method: request.method().to_string(),
+            host: host.map(String::from),
+            path: request.uri().path().to_string(),
+            headers: redacted_headers(request.headers()),
+            body: CapturedBody::default(),
+            response: None,
+        };
+
+        Arc::new(Capture {
+            max_body_bytes: sampler.max_body_bytes,
+            pending: Mutex::new(Pending {
+                request: captured,
+                request_body: Vec::new(),
+                response_body: Vec::new(),
+            }),
+            captures: self.clone(),
+        })
+    }
+
+    fn push(&self, captured: CapturedRequest) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        let mut captures = self.captures.lock().unwrap_or_else(|e| e.into_inner());
+        if captures.len() >= self.capacity {
+            captures.pop_front();
+        }
+        captures.push_back(captured);
+    }
+
+    /// Captured requests, oldest first
+    pub fn captured(&self) -> Vec<CapturedRequest> {
+        let captures = self.captures.lock().unwrap_or_else(|e| e.into_inner());
+        captures.iter().cloned().collect()
+    }
+}
+
+struct Pending {
+    request: CapturedRequest,
+    // Raw bodies, one byte longer than the limit if they were truncated
+    request_body: Vec<u8>,
+    response_body: Vec<u8>,
+}
+
+/// Capture of a request in flight, shared by the proxied request and response bodies.
+pub struct Capture {
+    max_body_bytes: usize,
+    pending: Mutex<Pending>,
+    captures: Arc<CapturedRequests>,
+}
+
+impl Capture {
+    /// Records the status and headers of the response sent to the client.
+    pub fn response<B>(&self, response: &Response<B>) {
+        let mut pending = self.pending.lock().unwrap_or_else(|e| e.into_inner());
+        pending.request.response = Some(CapturedResponse {
+            status: response.status().as_u16(),
+            headers: redacted_headers(response.headers()),
+            body: CapturedBody::default(),
+        });
+    }
+
+    fn append(&self, side: Side, data: &[u8]) {
+        let mut pending = self.pending.lock().unwrap_or_else(|e| e.into_inner());
+        let body = match side {
+            Side::Request => &mut pending.request_body,
+            Side::Response => &mut pending.response_body,
+        };
+        // One byte past the limit marks the body as truncated
+        let remaining = (self.max_body_bytes + 1).saturating_sub(body.len());
+        body.extend_from_slice(&data[..data.len().min(remaining)]);
+    }
+
+    fn body(&self, raw: Vec<u8>) -> CapturedBody {
+        let truncated = raw.len() > self.max_body_bytes;
+        let data = &raw[..raw.len().min(self.max_body_bytes)];
+        CapturedBody {
+            data: String::from_utf8_lossy(data).into_owned(),
+            truncated,
+        }
+    }
+}
+
+impl Drop for Capture {
+    fn drop(&mut self) {
+        let pending = self.pending.get_mut().unwrap_or_else(|e| e.into_inner());
+        let mut captured = pending.request.clone();
+        let request_body = std::mem::take(&mut pending.request_body);
+        let response_body = std::mem::take(&mut pending.response_body);
+        captured.body = self.body(request_body);
+        if let Some(response) = &mut captured.response {
+            response.body = self.body(response_body);
+        }
+        self.captures.push(captured);
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum Side {
+    Request,
+    Response,
+}
+
+/// Body that copies its data into a capture while it is read. Passes the body through
+/// unchanged if the request is not captured.
+pub struct CaptureBody<B> {
+    inner: B,
+    capture: Option<Arc<Capture>>,
+    side: Side,
+}
+
+impl<B> CaptureBody<B> {
+    pub fn new(inner: B, capture: Option<Arc<Capture>>, side: Side) -> Self {
+        Self {
+            inner,
+            capture,
+            side,
+        }
+    }
+}
+
+impl<B> Body for CaptureBody<B>
+where
+    B: Body<Data = Bytes> + Unpin,
+{
+    type Data = Bytes;
+    type Error = B::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let poll = Pin::new(&mut self.inner).poll_frame(cx);
+        if let Poll::Ready(Some(Ok(frame))) = &poll
+            && let (Some(capture), Some(data)) = (&self.capture, frame.data_ref())
+        {
+            capture.append(self.side, data);
+        }
+        // The capture is kept once the body is read, not when it is dropped. Readers may
+        // stop polling once the body reports its end.
+        if matches!(poll, Poll::Ready(None | Some(Err(_)))) || self.inner.is_end_stream() {
+            self.capture = None;
+        }
+        poll
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+    use http_body_util::{BodyExt, Full};
+
+    fn sampler(sample_rate: f64, max_body_bytes: usize) -> CaptureSampler {
+        CaptureSampler::try_from(RouteCapture {
+            sample_rate,
+            until: Utc::now() + Duration::hours(1),
+            max_body_bytes,
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_sample() {
+        let now = Utc::now();
+        for (sample_rate, expected) in [(0.0, 0), (0.1, 10), (0.25, 25), (1.0, 100)] {
+            let sampler = sampler(sample_rate, 0);
+            let sampled = (0..100).filter(|_| sampler.sample(now)).count();
+            assert_eq!(sampled, expected, "{sample_rate}");
+        }
+
+        // Nothing is captured after `until`
+        let sampler = sampler(1.0, 0);
+        assert!(!sampler.sample(now + Duration::hours(2)));
+
+        for sample_rate in [-0.1, 1.5, f64::NAN] {
+            let config = RouteCapture {
+                sample_rate,
+                until: now,
+                max_body_bytes: 0,
+            };
+            assert!(matches!(
+                CaptureSampler::try_from(config),
+                Err(ProxyError::InvalidRoute(_))
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_capture() {
+        let captures = Arc::new(CapturedRequests::new(1));
+        let sampler = sampler(1.0, 5);
+
+        let request = Request::builder()
+            .method("POST")
+            .uri("/api/1/envelope/")
+            .header("host", "us.sentry.io")
+            .header("authorization", "Bearer secret")
+            .header("x-sentry-auth", "Sentry sentry_key=secret")
+            .header("x-sentry-relay-signature", "secret")
+            .body(Full::new(Bytes::from_static(b"hello world")))
+            .unwrap();
+        let capture = captures.start(&request, "/api/{project_id}/envelope/", &sampler);
+        let body = CaptureBody::new(request.into_body(), Some(capture.clone()), Side::Request);
+        assert_eq!(body.collect().await.unwrap().to_bytes(), "hello world");
+
+        let response = Response::builder()
+            .header("set-cookie", "session=secret")
+            .body(Full::new(Bytes::from_static(b"ok")))
+            .unwrap();
+        capture.response(&response);
+        let body = CaptureBody::new(response.into_body(), Some(capture), Side::Response);
+
+        // Kept once the response body was read
+        assert!(captures.captured().is_empty());
+        assert_eq!(body.collect().await.unwrap().to_bytes(), "ok");
+
+        let captured = captures.captured();
+        assert_eq!(captured.len(), 1);
+        let captured = &captured[0];
+        assert_eq!(captured.route, "/api/{project_id}/envelope/");
+        assert_eq!(captured.method, "POST");
+        assert_eq!(captured.host.as_deref(), Some("us.sentry.io"));
+        for name in ["authorization", "x-sentry-auth", "x-sentry-relay-signature"] {
+            assert!(
+                captured
+                    .headers
+                    .contains(&(name.into(), "[redacted]".into())),
+                "{name}"
+            );
+        }
+        assert_eq!(
+            captured.body,
+            CapturedBody {
+                data: "hello".into(),
+                truncated: true,
+            }
+        );
+        let response = captured.response.as_ref().unwrap();
+        assert_eq!(response.status, 200);
+        assert!(
+            response
+                .headers
+                .contains(&("set-cookie".into(), "[redacted]".into()))
+        );
+        assert_eq!(
+            response.body,
+            CapturedBody {
+                data: "ok".into(),
+                truncated: false,
+            }
+        );
+
+        // The oldest capture is dropped
+        let request = Request::builder().uri("/other/").body(()).unwrap();
+        drop(captures.start(&request, "*", &sampler));
+        let captured = captures.captured();
+        assert_eq!(captured.len(), 1);
+        assert_eq!(captured[0].path, "/other/");
+        assert_eq!(captured[0].response, None);
+    }
+}
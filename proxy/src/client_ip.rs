@@ -0,0 +1,269 @@
+//! Client IP resolution and per-route IP allow and deny lists.
+//!
+//! The client IP is the address of the connection, unless the connection comes from a
+//! trusted proxy such as a load balancer. Then `X-Forwarded-For` is walked from the right,
+//! skipping trusted proxies, and the first address that is not trusted is the client. The
+//! header cannot be used to spoof the client IP, since only entries appended by trusted
+//! proxies are considered.
+use crate::config::ClientIps;
+use crate::errors::ProxyError;
+use http::{HeaderMap, Request};
+use shared::http::PeerAddr;
+use std::net::IpAddr;
+
+const X_FORWARDED_FOR: &str = "x-forwarded-for";
+
+/// An IP network in CIDR notation. A plain address is a network of that address only.
+#[derive(Debug, PartialEq)]
+struct IpNet {
+    addr: IpAddr,
+    prefix_len: u32,
+}
+
+impl IpNet {
+    fn parse(value: &str) -> Option<Self> {
+        let (addr, prefix_len) = match value.split_once('/') {
+            Some((addr, prefix_len)) => (addr, Some(prefix_len.parse().ok()?)),
+            None => (value, None),
+        };
+        let addr: IpAddr = addr.parse().ok()?;
+        let max_len = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix_len = prefix_len.unwrap_or(max_len);
+        (prefix_len <= max_len).then_some(Self { addr, prefix_len })
+    }
+
+    fn contains(&self, ip: IpAddr) -> bool {
+        // IPv4 clients may connect through IPv6 sockets as mapped addresses
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => prefix_matches(
+                net.to_bits().into(),
+                ip.to_bits().into(),
+                32,
+                self.prefix_len,
+            ),
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                prefix_matches(net.to_bits(), ip.to_bits(), 128, self.prefix_len)
+            }
+            _ => false,
+        }
+    }
+}
+
+fn prefix_matches(net: u128, ip: u128, bits: u32, prefix_len: u32) -> bool {
+    // Shifting by the full width would overflow
+    prefix_len == 0 || (net ^ ip) >> (bits - prefix_len) == 0
+}
+
+fn parse_nets(values: &[String]) -> Result<Vec<IpNet>, String> {
+    values
+        .iter()
+        .map(|value| IpNet::parse(value).ok_or_else(|| format!("Invalid IP network: {value}")))
+        .collect()
+}
+
+/// Resolves the client IP of requests, honoring `X-Forwarded-For` from trusted proxies.
+#[derive(Debug, Default)]
+pub struct ClientIpResolver {
+    trusted_proxies: Vec<IpNet>,
+}
+
+impl ClientIpResolver {
+    pub fn try_new(trusted_proxies: &[String]) -> Result<Self, ProxyError> {
+        Ok(Self {
+            trusted_proxies: parse_nets(trusted_proxies).map_err(ProxyError::TrustedProxies)?,
+        })
+    }
+
+    /// None if the request was not received by `run_http_service`, which records the
+    /// address of the connection.
+    pub fn resolve<B>(&self, request: &Request<B>) -> Option<IpAddr> {
+        let PeerAddr(peer_addr) = request.extensions().get::<PeerAddr>()?;
+        Some(self.resolve_forwarded(peer_addr.ip(), request.headers()))
+    }
+
+    fn resolve_forwarded(&self, peer_ip: IpAddr, headers: &HeaderMap) -> IpAddr {
+        let mut client_ip = peer_ip;
+
+        // Proxies append to the last header, so entries are walked from the right
+        let forwarded = headers
+            .get_all(X_FORWARDED_FOR)
+            .iter()
+            .rev()
+            .flat_map(|value| value.to_str().unwrap_or_default().rsplit(','));
+        for entry in forwarded {
+            if !self.is_trusted(client_ip) {
+                break;
+            }
+            // Whatever comes before an invalid entry cannot be trusted either
+            match entry.trim().parse() {
+                Ok(ip) => client_ip = ip,
+                Err(_) => break,
+            }
+        }
+
+        client_ip
+    }
+
+    fn is_trusted(&self, ip: IpAddr) -> bool {
+        self.trusted_proxies.iter().any(|net| net.contains(ip))
+    }
+}
+
+/// List of client IP networks that are allowed or denied, by a route or the admin listener.
+#[derive(Debug, PartialEq)]
+pub struct IpFilter {
+    allow: bool,
+    nets: Vec<IpNet>,
+}
+
+impl TryFrom<ClientIps> for IpFilter {
+    type Error = ProxyError;
+
+    fn try_from(config: ClientIps) -> Result<Self, Self::Error> {
+        Self::new(config).map_err(ProxyError::InvalidRoute)
+    }
+}
+
+impl IpFilter {
+    pub fn new(config: ClientIps) -> Result<Self, String> {
+        let (allow, nets) = match config {
+            ClientIps::Allow { allow } => (true, allow),
+            ClientIps::Deny { deny } => (false, deny),
+        };
+
+        Ok(Self {
+            allow,
+            nets: parse_nets(&nets)?,
+        })
+    }
+
+    /// Clients with an unknown IP are only allowed by deny lists.
+    pub fn allows(&self, client_ip: Option<IpAddr>) -> bool {
+        match client_ip {
+            Some(ip) => self.nets.iter().any(|net| net.contains(ip)) == self.allow,
+            None => !self.allow,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http::HeaderValue;
+
+    fn ip(value: &str) -> IpAddr {
+        value.parse().unwrap()
+    }
+
+    fn strings(values: &[&str]) -> Vec<String> {
+        values.iter().map(|value| value.to_string()).collect()
+    }
+
+    #[test]
+    fn test_ip_net() {
+        let net = IpNet::parse("10.1.0.0/16").unwrap();
+        assert!(net.contains(ip("10.1.2.3")));
+        assert!(net.contains(ip("::ffff:10.1.2.3")));
+        assert!(!net.contains(ip("10.2.0.1")));
+        assert!(!net.contains(ip("::1")));
+
+        let net = IpNet::parse("2001:db8::/32").unwrap();
+        assert!(net.contains(ip("2001:db8::1")));
+        assert!(!net.contains(ip("2001:db9::1")));
+
+        assert!(IpNet::parse("0.0.0.0/0").unwrap().contains(ip("1.2.3.4")));
+        assert!(IpNet::parse("1.2.3.4").unwrap().contains(ip("1.2.3.4")));
+        assert!(!IpNet::parse("1.2.3.4").unwrap().contains(ip("1.2.3.5")));
+
+        for invalid in ["10.0.0.0/33", "::/129", "10.0.0.0/", "example.com", ""] {
+            assert_eq!(IpNet::parse(invalid), None, "{invalid}");
+        }
+    }
+
+    #[test]
+    fn test_resolve_client_ip() {
+        let resolver = ClientIpResolver::try_new(&strings(&["10.0.0.0/8"])).unwrap();
+        let headers = |values: &[&'static str]| {
+            let mut headers = HeaderMap::new();
+            for value in values {
+                headers.append(X_FORWARDED_FOR, HeaderValue::from_static(value));
+            }
+            headers
+        };
+
+        let cases = [
+            // Not from a trusted proxy, the header is ignored
+            ("1.1.1.1", headers(&["2.2.2.2"]), "1.1.1.1"),
+            ("10.0.0.1", headers(&[]), "10.0.0.1"),
+            ("10.0.0.1", headers(&["2.2.2.2"]), "2.2.2.2"),
+            // Only the entries appended by trusted proxies are used
+            (
+                "10.0.0.1",
+                headers(&["3.3.3.3, 2.2.2.2, 10.0.0.2"]),
+                "2.2.2.2",
+            ),
+            (
+                "10.0.0.1",
+                headers(&["3.3.3.3", "2.2.2.2,10.0.0.2"]),
+                "2.2.2.2",
+            ),
+            ("10.0.0.1", headers(&["10.0.0.3, 10.0.0.2"]), "10.0.0.3"),
+            ("10.0.0.1", headers(&["2.2.2.2, invalid"]), "10.0.0.1"),
+        ];
+        for (peer_ip, headers, expected) in cases {
+            assert_eq!(
+                resolver.resolve_forwarded(ip(peer_ip), &headers),
+                ip(expected),
+                "{peer_ip} {headers:?}"
+            );
+        }
+
+        assert!(matches!(
+            ClientIpResolver::try_new(&strings(&["10.0.0.0/8", "invalid"])),
+            Err(ProxyError::TrustedProxies(_))
+        ));
+    }
+
+    #[test]
+    fn test_resolve_without_peer_addr() {
+        let resolver = ClientIpResolver::default();
+        let request = Request::builder().body(()).unwrap();
+        assert_eq!(resolver.resolve(&request), None);
+
+        let mut request = Request::builder()
+            .header(X_FORWARDED_FOR, "2.2.2.2")
+            .body(())
+            .unwrap();
+        request
+            .extensions_mut()
+            .insert(PeerAddr("1.1.1.1:1234".parse().unwrap()));
+        assert_eq!(resolver.resolve(&request), Some(ip("1.1.1.1")));
+    }
+
+    #[test]
+    fn test_ip_filter() {
+        let allow = IpFilter::try_from(ClientIps::Allow {
+            allow: strings(&["10.0.0.0/8", "192.168.1.1"]),
+        })
+        .unwrap();
+        assert!(allow.allows(Some(ip("10.1.2.3"))));
+        assert!(allow.allows(Some(ip("192.168.1.1"))));
+        assert!(!allow.allows(Some(ip("192.168.1.2"))));
+        assert!(!allow.allows(None));
+
+        let deny = IpFilter::try_from(ClientIps::Deny {
+            deny: strings(&["10.0.0.0/8"]),
+        })
+        .unwrap();
+        assert!(!deny.allows(Some(ip("10.1.2.3"))));
+        assert!(deny.allows(Some(ip("192.168.1.2"))));
+        assert!(deny.allows(None));
+
+        assert!(matches!(
+            IpFilter::try_from(ClientIps::Deny {
+                deny: strings(&["10.0.0.0/40"]),
+            }),
+            Err(ProxyError::InvalidRoute(_))
+        ));
+    }
+}
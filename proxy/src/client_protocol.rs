@@ -0,0 +1,172 @@
+//! Protocol of client connections, for protocol adoption analysis.
+//!
+//! The HTTP version of every request, and the TLS version, ALPN protocol and cipher suite
+//! of requests received on TLS listeners, are recorded in the request's log span and
+//! counted per route in `request.client_protocol`. With `forward_headers`, they are also
+//! sent to upstreams in `X-Synapse-Client-Protocol` and `X-Synapse-Client-Tls`. Copies of
+//! these headers sent by clients are always removed, so that upstreams can trust them.
+use crate::config::ClientProtocol as ClientProtocolConfig;
+use crate::metrics_defs::CLIENT_PROTOCOLS;
+use http::{HeaderMap, HeaderValue, Request, Version};
+use shared::http::ClientTls;
+
+pub const PROTOCOL_HEADER: &str = "x-synapse-client-protocol";
+pub const TLS_HEADER: &str = "x-synapse-client-tls";
+
+#[derive(Clone, Debug)]
+pub struct ClientProtocol {
+    forward_headers: bool,
+}
+
+/// Protocol a request was received with
+#[derive(Clone, Debug, PartialEq)]
+pub struct Protocol {
+    /// e.g. `HTTP/1.1`
+    pub version: &'static str,
+    /// None for plaintext listeners
+    pub tls: Option<ClientTls>,
+}
+
+impl From<ClientProtocolConfig> for ClientProtocol {
+    fn from(config: ClientProtocolConfig) -> Self {
+        Self {
+            forward_headers: config.forward_headers,
+        }
+    }
+}
+
+impl ClientProtocol {
+    /// Returns the protocol of the request, and sets the forwarded headers.
+    pub fn observe<B>(&self, request: &mut Request<B>) -> Protocol {
+        let protocol = Protocol {
+            version: version_name(request.version()),
+            tls: request.extensions().get::<ClientTls>().cloned(),
+        };
+        remove_headers(request.headers_mut());
+        if self.forward_headers {
+            protocol.set_headers(request.headers_mut());
+        }
+        protocol
+    }
+}
+
+impl Protocol {
+    /// Span recording the protocol with the events logged while handling the request,
+    /// within the request's span.
+    pub fn span(&self, request_span: &tracing::Span) -> tracing::Span {
+        tracing::info_span!(
+            parent: request_span,
+            "client",
+            protocol = self.version,
+            tls_version = self.tls.as_ref().map(|tls| tls.version),
+            alpn = self.tls.as_ref().and_then(|tls| tls.alpn.as_deref()),
+            cipher_suite = self.tls.as_ref().map(|tls| tls.cipher_suite),
+        )
+    }
+
+    /// Counts the request of the route, or of `none` if it matched no route.
+    pub fn count(&self, route: Option<&str>) {
+        metrics::counter!(
+            CLIENT_PROTOCOLS.name,
+            "protocol" => self.version,
+            "tls_version" => self.tls.as_ref().map_or("none", |tls| tls.version),
+            "alpn" => self
+                .tls
+                .as_ref()
+                .and_then(|tls| tls.alpn.clone())
+                .unwrap_or_else(|| "none".to_string()),
+            "route" => route.unwrap_or("none").to_string(),
+        )
+        .increment(1);
+    }
+
+    fn set_headers(&self, headers: &mut HeaderMap) {
+        headers.insert(PROTOCOL_HEADER, HeaderValue::from_static(self.version));
+        let Some(tls) = &self.tls else {
+            return;
+        };
+        let mut value = format!("version={}; cipher={}", tls.version, tls.cipher_suite);
+        if let Some(alpn) = &tls.alpn {
+            value.push_str("; alpn=");
+            value.push_str(alpn);
+        }
+        // ALPN protocols are arbitrary bytes, which may not fit in a header
+        if let Ok(value) = HeaderValue::from_str(&value) {
+            headers.insert(TLS_HEADER, value);
+        }
+    }
+}
+
+/// Removes the headers set by the proxy from a client request.
+pub fn remove_headers(headers: &mut HeaderMap) {
+    headers.remove(PROTOCOL_HEADER);
+    headers.remove(TLS_HEADER);
+}
+
+fn version_name(version: Version) -> &'static str {
+    match version {
+        Version::HTTP_09 => "HTTP/0.9",
+        Version::HTTP_10 => "HTTP/1.0",
+        Version::HTTP_11 => "HTTP/1.1",
+        Version::HTTP_2 => "HTTP/2",
+        Version::HTTP_3 => "HTTP/3",
+        _ => "unknown",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn client_protocol(forward_headers: bool) -> ClientProtocol {
+        ClientProtocol::from(ClientProtocolConfig { forward_headers })
+    }
+
+    #[test]
+    fn test_observe() {
+        let mut request = Request::builder()
+            .version(Version::HTTP_2)
+            .header(PROTOCOL_HEADER, "spoofed")
+            .header(TLS_HEADER, "spoofed")
+            .body(())
+            .unwrap();
+        request.extensions_mut().insert(ClientTls {
+            version: "TLSv1.3",
+            alpn: Some("h2".to_string()),
+            cipher_suite: "TLS13_AES_128_GCM_SHA256",
+        });
+
+        let protocol = client_protocol(true).observe(&mut request);
+        assert_eq!(protocol.version, "HTTP/2");
+        assert_eq!(protocol.tls.as_ref().unwrap().alpn.as_deref(), Some("h2"));
+        assert_eq!(request.headers()[PROTOCOL_HEADER], "HTTP/2");
+        assert_eq!(
+            request.headers()[TLS_HEADER],
+            "version=TLSv1.3; cipher=TLS13_AES_128_GCM_SHA256; alpn=h2"
+        );
+
+        // Plaintext requests have no TLS header, even if the client sent one
+        let mut request = Request::builder()
+            .header(TLS_HEADER, "spoofed")
+            .body(())
+            .unwrap();
+        let protocol = client_protocol(true).observe(&mut request);
+        assert_eq!(
+            protocol,
+            Protocol {
+                version: "HTTP/1.1",
+                tls: None
+            }
+        );
+        assert_eq!(request.headers()[PROTOCOL_HEADER], "HTTP/1.1");
+        assert!(request.headers().get(TLS_HEADER).is_none());
+
+        // Without forwarding, the client's headers are removed all the same
+        let mut request = Request::builder()
+            .header(PROTOCOL_HEADER, "spoofed")
+            .body(())
+            .unwrap();
+        client_protocol(false).observe(&mut request);
+        assert!(request.headers().get(PROTOCOL_HEADER).is_none());
+    }
+}
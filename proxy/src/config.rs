@@ -1,7 +1,14 @@
-use locator::client::{LocatorConfig as ClientLocatorConfig, LocatorType as ClientLocatorType};
-use locator::config::{BackupRouteStore, ControlPlane, LocatorDataType};
-use serde::Deserialize;
+use chrono::{DateTime, Utc};
+use locator::client::{
+    LocatorConfig as ClientLocatorConfig, LocatorType as ClientLocatorType, ShardTopology,
+};
+use locator::config::{BackupRouteStore, ClientCache, ControlPlane, LocatorDataType};
+use serde::{Deserialize, Serialize};
+pub use shared::http::HeaderMatch;
+pub use shared::tls::ListenerTls;
 use std::collections::HashMap;
+use std::net::IpAddr;
+use std::path::PathBuf;
 
 #[derive(Clone, Debug, Deserialize, PartialEq)]
 pub struct Config {
@@ -12,12 +19,42 @@ pub struct Config {
     #[serde(default)]
     pub admin_listener: AdminListener,
     pub locator: Locator,
+    pub slow_request_watchdog: Option<SlowRequestWatchdog>,
+    pub feature_flags: Option<FeatureFlags>,
+    pub upstream_backoff: Option<UpstreamBackoff>,
+    pub route_tracing: Option<RouteTracing>,
+    pub request_capture: Option<RequestCapture>,
+    pub force_upstream: Option<ForceUpstream>,
+    pub anomaly_events: Option<AnomalyEvents>,
+    #[serde(default)]
+    pub content_negotiation: bool,
+    /// Reports the protocol clients connect with in logs and metrics. Disabled if not set.
+    pub client_protocol: Option<ClientProtocol>,
+    pub hot_upgrade: Option<HotUpgrade>,
+    /// Lets upstreams of the main listener be registered at runtime on the admin listener.
+    /// Disabled if not set.
+    pub upstream_registration: Option<UpstreamRegistration>,
+    /// Seconds given to open connections to finish on SIGTERM or Ctrl-C. Default: 30
+    #[serde(default = "default_drain_timeout_secs")]
+    pub drain_timeout_secs: u64,
+    /// Listeners next to the main one, each serving its own routes and upstreams
+    #[serde(default)]
+    pub additional_listeners: Vec<AdditionalListener>,
 }
 
 #[derive(Clone, Debug, Deserialize, PartialEq)]
 pub struct Listener {
     pub host: String,
     pub port: u16,
+    /// Normalizes request paths before they are matched against routes and forwarded.
+    /// Paths are used as received if not set.
+    pub path_normalization: Option<PathNormalization>,
+    /// Networks of the proxies in front of the listener, such as load balancers, whose
+    /// `X-Forwarded-For` is trusted to carry the client IP. In CIDR notation.
+    #[serde(default)]
+    pub trusted_proxies: Vec<String>,
+    /// Terminates TLS on the listener. Plaintext HTTP is served if not set.
+    pub tls: Option<ListenerTls>,
 }
 
 impl Default for Listener {
@@ -25,14 +62,84 @@ impl Default for Listener {
         Listener {
             host: "0.0.0.0".into(),
             port: 3000,
+            path_normalization: None,
+            trusted_proxies: Vec::new(),
+            tls: None,
         }
     }
 }
 
+/// Listener with a route table and upstreams of its own, e.g. an internal listener next
+/// to the public one. It shares the locator, feature flags and request handling options
+/// of the proxy.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct AdditionalListener {
+    /// Identifies the listener in logs and in the route tables of the admin listener
+    pub name: String,
+    pub listener: Listener,
+    #[serde(default)]
+    pub upstreams: Vec<UpstreamConfig>,
+    pub routes: Vec<Route>,
+    /// Requests that match none of the listener's routes fall through to the routes of the
+    /// main listener, which can then use the main upstreams. Upstreams of the listener
+    /// replace main upstreams of the same name. Default: false
+    #[serde(default)]
+    pub inherit_routes: bool,
+}
+
+/// Upstreams registered and deregistered at runtime, see `upstream_registry`
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct UpstreamRegistration {
+    /// JSON file the registered upstreams are written to, and registered again from on
+    /// startup. Registered upstreams are lost on restart if not set.
+    pub state_file: Option<PathBuf>,
+}
+
+/// RFC 3986 normalization of request paths. All steps are enabled by default.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct PathNormalization {
+    /// Collapse runs of slashes into one, e.g. `/api//0/` to `/api/0/`
+    pub merge_slashes: bool,
+    /// Resolve `.` and `..` segments, e.g. `/api/0/./projects` to `/api/0/projects`
+    pub remove_dot_segments: bool,
+    pub percent_decoding: PercentDecoding,
+}
+
+impl Default for PathNormalization {
+    fn default() -> Self {
+        PathNormalization {
+            merge_slashes: true,
+            remove_dot_segments: true,
+            percent_decoding: PercentDecoding::Unreserved,
+        }
+    }
+}
+
+/// Which percent-encoded characters of a path are decoded
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum PercentDecoding {
+    /// Percent-encodings are left as they are
+    None,
+    /// Unreserved characters (letters, digits, `-`, `.`, `_` and `~`) are decoded, and the
+    /// hex digits of all other percent-encodings are uppercased
+    Unreserved,
+}
+
 #[derive(Clone, Debug, Deserialize, PartialEq)]
 pub struct AdminListener {
     pub host: String,
     pub port: u16,
+    /// Terminates TLS on the admin listener. With `client_ca_file`, only clients presenting
+    /// a certificate issued by one of its CAs can connect. Plaintext HTTP is served if not
+    /// set.
+    #[serde(default)]
+    pub tls: Option<ListenerTls>,
+    /// Authentication of the requests to all admin endpoints. Requests are not
+    /// authenticated if not set.
+    #[serde(default)]
+    pub auth: Option<AdminAuth>,
 }
 
 impl Default for AdminListener {
@@ -40,42 +147,524 @@ impl Default for AdminListener {
         AdminListener {
             host: "0.0.0.0".into(),
             port: 3001,
+            tls: None,
+            auth: None,
+        }
+    }
+}
+
+/// Authentication of admin requests. Requests must pass every configured check.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct AdminAuth {
+    /// Requests must send one of these tokens as `Authorization: Bearer <token>`, others
+    /// are rejected with 401. Several tokens allow rotating them.
+    #[serde(default)]
+    pub bearer_tokens: Vec<String>,
+    /// Clients allowed to use the admin endpoints, by their IP. Others are rejected with
+    /// 403.
+    #[serde(default)]
+    pub client_ips: Option<ClientIps>,
+}
+
+/// Zero-downtime restarts. A new proxy process takes the listening sockets over from the
+/// running one through `socket`, after which the running one stops accepting connections
+/// and drains the open ones.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct HotUpgrade {
+    /// Unix socket path on which the running proxy hands its listening sockets over
+    pub socket: std::path::PathBuf,
+    /// Seconds given to open connections to finish after the handoff. Default: 30
+    #[serde(default = "default_drain_timeout_secs")]
+    pub drain_timeout_secs: u64,
+}
+
+fn default_drain_timeout_secs() -> u64 {
+    30
+}
+
+/// Flags requests that take longer than the threshold and logs their timing breakdown.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct SlowRequestWatchdog {
+    pub threshold_ms: u64,
+    /// Send slow requests to Sentry as error events rather than logging a warning
+    #[serde(default)]
+    pub report_to_sentry: bool,
+}
+
+fn default_max_traced_requests() -> usize {
+    100
+}
+
+/// Records the most recent requests that matched no route, exposed on the admin listener.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct RouteTracing {
+    #[serde(default = "default_max_traced_requests")]
+    pub max_requests: usize,
+}
+
+fn default_max_captured_requests() -> usize {
+    100
+}
+
+/// Keeps the request/response pairs sampled by routes with `capture` settings, exposed on
+/// the admin listener.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct RequestCapture {
+    #[serde(default = "default_max_captured_requests")]
+    pub max_requests: usize,
+}
+
+/// Lets requests carrying `X-Synapse-Force-Upstream` and this token in
+/// `X-Synapse-Admin-Token` bypass route resolution, for debugging.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct ForceUpstream {
+    pub token: String,
+}
+
+/// Reports the HTTP version, and the TLS version, ALPN protocol and cipher suite of TLS
+/// listeners, that requests are received with.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct ClientProtocol {
+    /// Sends the protocol to upstreams in `X-Synapse-Client-Protocol` and
+    /// `X-Synapse-Client-Tls`. Default: false
+    #[serde(default)]
+    pub forward_headers: bool,
+}
+
+/// Sentry events for routing anomalies, sent through the Sentry client of the process.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct AnomalyEvents {
+    /// Length of the windows occurrences are counted in. Default: 60
+    pub window_secs: u64,
+    /// Requests to a host matching no route within a window that are reported. Default: 100
+    pub unmatched_host_threshold: u64,
+    /// Resolver failures within a window that are reported. Default: 10
+    pub resolver_failure_threshold: u64,
+    /// Minimum time between two events of the same anomaly. Default: 600
+    pub min_event_interval_secs: u64,
+}
+
+impl Default for AnomalyEvents {
+    fn default() -> Self {
+        AnomalyEvents {
+            window_secs: 60,
+            unmatched_host_threshold: 100,
+            resolver_failure_threshold: 10,
+            min_event_interval_secs: 600,
         }
     }
 }
 
+fn default_max_backoff_secs() -> u64 {
+    60
+}
+
+/// Honors `Retry-After` on 429 and 503 responses by answering requests to the upstream
+/// locally until the indicated delay has passed.
 #[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct UpstreamBackoff {
+    /// Upper bound for the delay requested by an upstream
+    #[serde(default = "default_max_backoff_secs")]
+    pub max_backoff_secs: u64,
+}
+
+fn default_flag_cache_ttl_secs() -> u64 {
+    30
+}
+
+fn default_flag_connect_timeout_ms() -> u64 {
+    250
+}
+
+fn default_flag_timeout_ms() -> u64 {
+    1000
+}
+
+/// Source of feature flags used to gate routes.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum FeatureFlags {
+    /// YAML file mapping flag names to their rollout, loaded at startup
+    File { path: String },
+    /// HTTP service answering `GET {url}?flag=<flag>&organization=<org>` with
+    /// `{"enabled": bool}`. Results are cached for `cache_ttl_secs`. Flags are evaluated
+    /// while the request waits, so evaluations give up after `timeout_ms` and the flag is
+    /// treated as disabled.
+    Http {
+        url: String,
+        #[serde(default = "default_flag_cache_ttl_secs")]
+        cache_ttl_secs: u64,
+        #[serde(default = "default_flag_connect_timeout_ms")]
+        connect_timeout_ms: u64,
+        #[serde(default = "default_flag_timeout_ms")]
+        timeout_ms: u64,
+    },
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
 pub struct UpstreamConfig {
     pub name: String,
     pub url: String,
+    /// HTTP/1.1 behavior towards upstreams that are sensitive to it. Upstreams with these
+    /// options get a client of their own. The shared client is used if not set.
+    #[serde(default)]
+    pub http1: Option<Http1Options>,
+    /// Reuse of pooled connections to the upstream, trading connection setup against
+    /// stickiness to one instance behind the upstream's load balancer.
+    #[serde(default)]
+    pub pool: Option<PoolOptions>,
+    /// How outbound connections to the upstream leave the host. Upstreams with these
+    /// options get a client of their own.
+    #[serde(default)]
+    pub egress: Option<EgressOptions>,
+    /// TLS settings of `https` upstreams. Upstreams with these options get a client of
+    /// their own. Public CA roots are trusted if not set.
+    #[serde(default)]
+    pub tls: Option<UpstreamTls>,
+    /// Probes the upstream in the background, and stops routing requests to it while the
+    /// probes fail. The upstream is always considered healthy if not set.
+    #[serde(default)]
+    pub health_check: Option<HealthCheck>,
 }
 
-#[derive(Clone, Debug, Deserialize, PartialEq)]
+fn default_health_check_interval_secs() -> u64 {
+    10
+}
+
+fn default_health_check_timeout_ms() -> u64 {
+    2000
+}
+
+fn default_unhealthy_threshold() -> u32 {
+    3
+}
+
+/// Active health check of an upstream
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct HealthCheck {
+    /// Path requested with `GET`, e.g. `/_health/`. Probes fail unless the upstream answers
+    /// with a 2xx status.
+    pub path: String,
+    /// Time between two probes. Default: 10 seconds
+    #[serde(default = "default_health_check_interval_secs")]
+    pub interval_secs: u64,
+    /// Probes without a response after this long fail. Default: 2000 milliseconds
+    #[serde(default = "default_health_check_timeout_ms")]
+    pub timeout_ms: u64,
+    /// Consecutive failed probes after which the upstream is down. A single successful
+    /// probe brings it up again. Default: 3
+    #[serde(default = "default_unhealthy_threshold")]
+    pub unhealthy_threshold: u32,
+}
+
+/// TLS settings of an upstream, for upstreams behind an internal PKI
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+#[serde(default)]
+pub struct UpstreamTls {
+    /// PEM bundle of the CA certificates trusted for the upstream, instead of the public roots
+    pub ca_file: Option<PathBuf>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+#[serde(default)]
+pub struct EgressOptions {
+    /// Local address connections are bound to, which selects the network interface they
+    /// leave through. Chosen by the operating system if not set.
+    pub bind_address: Option<IpAddr>,
+    /// Proxy that connections are tunnelled through, `http://host:port` for HTTP CONNECT
+    /// or `socks5://host:port`. Credentials may be given as `user:password@` in the URL.
+    pub proxy: Option<String>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+#[serde(default)]
+pub struct PoolOptions {
+    /// Upper bound for idle connections kept to the upstream. Unlimited if not set.
+    pub max_idle: Option<usize>,
+    /// Close idle connections after this long. Default: 90 seconds
+    pub idle_timeout_secs: Option<u64>,
+    /// Stop reusing connections once they are this old, so that requests are spread across
+    /// the instances behind the upstream's load balancer again. Unlimited if not set.
+    pub max_lifetime_secs: Option<u64>,
+    /// Fail connections, including the TLS handshake, that are not established after this
+    /// long. Unlimited if not set.
+    pub connect_timeout_secs: Option<u64>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(default)]
+pub struct Http1Options {
+    pub header_case: HeaderCase,
+    /// Read the whole request body before forwarding it, so that it is sent with
+    /// `Content-Length` instead of chunked.
+    pub buffer_request_body: bool,
+    /// Requests with larger bodies are answered with 413 when bodies are buffered.
+    /// Default: 1048576
+    pub max_buffered_body_bytes: usize,
+    /// Reuse connections to the upstream. If disabled, requests are sent with
+    /// `Connection: close`. Default: true
+    pub keep_alive: bool,
+}
+
+impl Default for Http1Options {
+    fn default() -> Self {
+        Http1Options {
+            header_case: HeaderCase::Lower,
+            buffer_request_body: false,
+            max_buffered_body_bytes: 1024 * 1024,
+            keep_alive: true,
+        }
+    }
+}
+
+/// Casing of the header names sent to an upstream
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum HeaderCase {
+    /// e.g. `content-type`
+    Lower,
+    /// e.g. `Content-Type`
+    Title,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
 pub struct Route {
     pub r#match: Match,
     pub action: Action,
+    /// Filters the upstream response headers passed to clients. All headers are passed if
+    /// not set.
+    #[serde(default)]
+    pub response_headers: Option<ResponseHeaders>,
+    /// Client IPs allowed to use the route. Other clients are rejected with 403. All
+    /// clients are allowed if not set.
+    #[serde(default)]
+    pub client_ips: Option<ClientIps>,
+    /// Drop the trailers of upstream responses, for clients that cannot parse them.
+    /// Default: false
+    #[serde(default)]
+    pub strip_trailers: bool,
+    /// Headers set, appended or removed on the requests forwarded to the upstream and the
+    /// responses passed to clients. No headers are rewritten if not set.
+    #[serde(default)]
+    pub headers: Option<HeaderRewrites>,
+    /// Records a sample of the route's requests with their responses, for debugging.
+    /// Requires `request_capture`. Nothing is captured if not set.
+    #[serde(default)]
+    pub capture: Option<RouteCapture>,
+    /// Time budget of the route's requests in milliseconds, from when they are received
+    /// until the upstream response headers arrive. Requests over budget are answered with
+    /// 504. Dynamic routes spend at most a quarter of it resolving the upstream, and use
+    /// the default upstream if that takes longer. No timeout if not set.
+    #[serde(default)]
+    pub timeout_ms: Option<u64>,
+    /// Retries of requests that fail to connect to the upstream or that the upstream
+    /// answers with a retried status. Requests are sent once if not set.
+    #[serde(default)]
+    pub retries: Option<RouteRetries>,
+    /// Routes are tried from the highest to the lowest priority, and in their configured
+    /// order within a priority. Default: 0
+    #[serde(default)]
+    pub priority: i32,
+    /// Methods the route accepts, requests with other methods are answered with 405. All
+    /// methods are forwarded if not set.
+    #[serde(default)]
+    pub methods: Option<RouteMethods>,
 }
 
-#[derive(Clone, Debug, Deserialize, PartialEq)]
+/// HTTP methods accepted by a route
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct RouteMethods {
+    /// Methods forwarded to the upstream, e.g. `GET`
+    pub allow: Vec<String>,
+    /// Answer `OPTIONS` requests with 204 and the allowed methods instead of forwarding
+    /// them, unless `OPTIONS` is allowed. Default: false
+    #[serde(default)]
+    pub answer_options: bool,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_retry_backoff_ms() -> u64 {
+    50
+}
+
+fn default_retry_statuses() -> Vec<u16> {
+    vec![502, 503]
+}
+
+fn default_max_retried_body_bytes() -> usize {
+    64 * 1024
+}
+
+/// Retry policy of a route. Request bodies are buffered so that they can be replayed.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct RouteRetries {
+    /// Attempts per request, including the first one
+    pub max_attempts: u32,
+    /// Delay before the first retry, doubled for every further retry. Default: 50
+    #[serde(default = "default_retry_backoff_ms")]
+    pub backoff_ms: u64,
+    /// Upstream response statuses that are retried. Default: [502, 503]
+    #[serde(default = "default_retry_statuses")]
+    pub statuses: Vec<u16>,
+    /// Retry requests whose connection to the upstream could not be established.
+    /// Default: true
+    #[serde(default = "default_true")]
+    pub connect_errors: bool,
+    /// Only retry requests with idempotent methods, which are safe to send twice.
+    /// Default: true
+    #[serde(default = "default_true")]
+    pub idempotent_only: bool,
+    /// Requests with larger or streamed bodies are not retried. Default: 65536
+    #[serde(default = "default_max_retried_body_bytes")]
+    pub max_body_bytes: usize,
+}
+
+fn default_max_captured_body_bytes() -> usize {
+    4096
+}
+
+/// Sampled capture of the full requests and responses of a route. Bodies are truncated and
+/// credentials redacted.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct RouteCapture {
+    /// Share of the route's requests that are captured, between 0 and 1
+    pub sample_rate: f64,
+    /// RFC 3339 timestamp after which nothing is captured anymore
+    pub until: DateTime<Utc>,
+    /// Bodies are truncated to this many bytes. Default: 4096
+    #[serde(default = "default_max_captured_body_bytes")]
+    pub max_body_bytes: usize,
+}
+
+/// Client IP networks in CIDR notation, a plain address is a network of one address.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(untagged, deny_unknown_fields)]
+pub enum ClientIps {
+    /// Only clients in these networks are allowed
+    Allow { allow: Vec<String> },
+    /// All clients except those in these networks are allowed
+    Deny { deny: Vec<String> },
+}
+
+/// Upstream response headers passed to clients. Names are case-insensitive, and a trailing
+/// `*` matches all headers with that prefix. Without it, `X-Cell-*` headers are removed.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(untagged, deny_unknown_fields)]
+pub enum ResponseHeaders {
+    /// Only these headers, plus the ones needed to read the body, are passed
+    Allow { allow: Vec<String> },
+    /// All headers except these are passed
+    Deny { deny: Vec<String> },
+}
+
+/// Header rewrites of a route, for each direction
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct HeaderRewrites {
+    /// Applied to the request before it is forwarded to the upstream
+    #[serde(default)]
+    pub request: Option<HeaderRewrite>,
+    /// Applied to the upstream response, after `response_headers` filtering
+    #[serde(default)]
+    pub response: Option<HeaderRewrite>,
+}
+
+/// Headers rewritten in one direction. Headers are removed first, then set, then
+/// appended. Names are case-insensitive.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct HeaderRewrite {
+    /// Replaces all values of the header
+    #[serde(default)]
+    pub set: HashMap<String, String>,
+    /// Adds a value to the header, keeping the existing ones
+    #[serde(default)]
+    pub append: HashMap<String, String>,
+    #[serde(default)]
+    pub remove: Vec<String>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
 pub struct Match {
     pub host: Option<String>,
     pub path: Option<String>,
+    /// Only match if this feature flag is enabled. Evaluated for the `organization`
+    /// path parameter if the route captures one.
+    pub flag: Option<String>,
+    /// Only match within this time window
+    pub active: Option<ActiveWindow>,
+    /// Only match if the request carries all of these headers
+    #[serde(default)]
+    pub headers: Vec<HeaderMatch>,
 }
 
-#[derive(Clone, Debug, Deserialize, PartialEq)]
+/// Time window in which a route is active, for scheduled cutovers. Outside the window the
+/// route is skipped and matching continues with the next route.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct ActiveWindow {
+    /// RFC 3339 timestamp from which the route is active, inclusive
+    pub start: Option<DateTime<Utc>>,
+    /// RFC 3339 timestamp until which the route is active, exclusive
+    pub end: Option<DateTime<Utc>>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
 #[serde(rename_all = "snake_case")]
 pub enum Resolver {
     CellFromOrganization,
     CellFromId,
 }
 
-#[derive(Clone, Debug, Deserialize, PartialEq)]
+impl Resolver {
+    /// Name of the resolver as configured
+    pub fn name(&self) -> &'static str {
+        match self {
+            Resolver::CellFromOrganization => "cell_from_organization",
+            Resolver::CellFromId => "cell_from_id",
+        }
+    }
+
+    /// Route parameter the resolver looks up the cell with
+    pub fn key(&self) -> &'static str {
+        match self {
+            Resolver::CellFromOrganization => "organization",
+            Resolver::CellFromId => "id",
+        }
+    }
+}
+
+/// Where a dynamic action reads the key of its resolver from
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum KeySource {
+    /// The path parameter named after the resolver's key, e.g. `{organization}`
+    #[default]
+    Path,
+    /// The query parameter `key_name`
+    Query,
+    /// The request header `key_name`
+    Header,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
 #[serde(untagged)]
 pub enum Action {
     Dynamic {
         resolver: Resolver,
         cell_to_upstream: HashMap<String, String>,
         default: Option<String>,
+        #[serde(default)]
+        key_source: KeySource,
+        /// Name of the query parameter or header holding the key
+        key_name: Option<String>,
     },
     Static {
         to: String,
@@ -91,7 +680,18 @@ pub struct HandlerConfig {
 #[serde(tag = "type")]
 pub enum LocatorType {
     #[serde(rename = "url")]
-    Url { url: String },
+    Url {
+        url: String,
+        /// API key of this service, if the locator API requires one
+        api_key: Option<String>,
+        /// Datagram listener of a locator on the same host, which lookups are sent to
+        /// instead of the API
+        #[serde(default)]
+        datagram_addr: Option<String>,
+        /// Cache of the lookups in this service. Disabled if not set.
+        #[serde(default)]
+        cache: Option<ClientCache>,
+    },
     #[serde(rename = "in_process")]
     InProcess {
         control_plane: ControlPlane,
@@ -99,6 +699,13 @@ pub enum LocatorType {
         localities: Option<Vec<String>>,
         locality_to_default_cell: Option<HashMap<String, String>>,
     },
+    /// Locators each holding one shard of the keyspace
+    #[serde(rename = "sharded")]
+    Sharded {
+        shards: ShardTopology,
+        /// API key of this service, if the locator API requires one
+        api_key: Option<String>,
+    },
 }
 
 #[derive(Clone, Debug, Deserialize, PartialEq)]
@@ -118,14 +725,28 @@ impl Locator {
                     localities,
                     locality_to_default_cell,
                 } => ClientLocatorType::InProcess {
-                    control_plane_url: control_plane.url,
+                    control_plane,
                     backup_route_store_type: backup_route_store.r#type,
                     localities,
                     locality_to_default_cell,
                 },
-                LocatorType::Url { url } => ClientLocatorType::Url { url },
+                LocatorType::Url {
+                    url,
+                    api_key,
+                    datagram_addr,
+                    cache,
+                } => ClientLocatorType::Url {
+                    url,
+                    api_key,
+                    datagram_addr,
+                    cache,
+                },
+                LocatorType::Sharded { shards, api_key } => {
+                    ClientLocatorType::Sharded { shards, api_key }
+                }
             },
             data_type: LocatorDataType::Organization,
+            caller: Some("proxy".into()),
         }
     }
 }
@@ -0,0 +1,511 @@
+//! Content-encoding negotiation between clients and upstreams.
+//!
+//! With `content_negotiation` enabled, upstreams are told they may compress responses with
+//! any of the encodings the proxy can decode, in addition to the ones the client accepts.
+//! Responses in an encoding the client does not accept are decoded while they are streamed,
+//! so clients never receive a body they cannot read. Responses the client accepts pass
+//! through compressed.
+//!
+//! Only a single gzip, deflate or zstd coding is decoded. Other encodings, which upstreams
+//! only send if the client asked for them or if they ignore `Accept-Encoding`, pass through.
+//! Codings the client rejects are rejected towards the upstream as well, so that a client
+//! accepting `*` is not sent an encoding it rejected and that cannot be decoded.
+//!
+//! Decoded bodies are limited to `MAX_DECODED_BYTES` and, past the first MiB, to
+//! `MAX_EXPANSION` times their encoded size, so that a small compressed body cannot make
+//! the proxy buffer or send gigabytes. Bodies exceeding a limit fail.
+use crate::errors::ProxyError;
+use crate::metrics_defs::CONTENT_DECODING;
+use flate2::write::{GzDecoder, ZlibDecoder};
+use http::header::{ACCEPT_ENCODING, CONTENT_ENCODING, CONTENT_LENGTH, ETAG};
+use http::{HeaderMap, HeaderValue, StatusCode};
+use hyper::body::{Body, Bytes, Frame, SizeHint};
+use std::io::{self, Write};
+use std::pin::Pin;
+use std::task::{Context, Poll, ready};
+
+/// Encodings the proxy can decode, advertised to upstreams
+const SUPPORTED: &[&str] = &["gzip", "deflate", "zstd"];
+
+/// Longest decoded body
+const MAX_DECODED_BYTES: u64 = 128 * 1024 * 1024;
+
+/// Largest ratio of decoded to encoded bytes, once more than `RATIO_GRACE_BYTES` are
+/// decoded
+const MAX_EXPANSION: u64 = 200;
+const RATIO_GRACE_BYTES: u64 = 1024 * 1024;
+
+/// Encoded bytes fed to the decoder at once, which bounds the output of a single write
+const DECODE_STEP: usize = 512;
+
+/// Content codings accepted by the client, parsed from `Accept-Encoding`
+#[derive(Debug, Default, PartialEq)]
+pub struct AcceptedEncodings {
+    accepted: Vec<String>,
+    // Codings with a weight of 0
+    rejected: Vec<String>,
+    // `*` matches every coding that is not listed
+    any: bool,
+}
+
+impl AcceptedEncodings {
+    pub fn parse(headers: &HeaderMap) -> Self {
+        let mut encodings = Self::default();
+        let codings = headers
+            .get_all(ACCEPT_ENCODING)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','));
+
+        for coding in codings {
+            let mut params = coding.split(';');
+            let name = params
+                .next()
+                .unwrap_or_default()
+                .trim()
+                .to_ascii_lowercase();
+            if name.is_empty() {
+                continue;
+            }
+            let weight = params
+                .find_map(|param| param.trim().strip_prefix("q="))
+                .and_then(|q| q.trim().parse::<f32>().ok())
+                .unwrap_or(1.0);
+
+            match (name.as_str(), weight > 0.0) {
+                ("*", accepted) => encodings.any = accepted,
+                (_, true) => encodings.accepted.push(name),
+                (_, false) => encodings.rejected.push(name),
+            }
+        }
+        encodings
+    }
+
+    pub fn accepts(&self, coding: &str) -> bool {
+        let coding = coding.to_ascii_lowercase();
+        if self.rejected.contains(&coding) {
+            return false;
+        }
+        // Identity is acceptable unless explicitly rejected
+        coding == "identity" || self.any || self.accepted.contains(&coding)
+    }
+}
+
+/// Parses the encodings the client accepts and replaces them on the request to the upstream
+/// with the ones the client accepts or the proxy can decode.
+pub fn negotiate(headers: &mut HeaderMap) -> AcceptedEncodings {
+    let accepted = AcceptedEncodings::parse(headers);
+
+    let mut advertised: Vec<&str> = accepted.accepted.iter().map(String::as_str).collect();
+    for coding in SUPPORTED {
+        if !advertised.contains(coding) {
+            advertised.push(coding);
+        }
+    }
+    if accepted.any {
+        advertised.push("*");
+    }
+    let rejected: Vec<String> = accepted
+        .rejected
+        .iter()
+        .filter(|coding| !SUPPORTED.contains(&coding.as_str()))
+        .map(|coding| format!("{coding};q=0"))
+        .collect();
+    advertised.extend(rejected.iter().map(String::as_str));
+
+    if let Ok(value) = HeaderValue::from_str(&advertised.join(", ")) {
+        headers.insert(ACCEPT_ENCODING, value);
+    }
+    accepted
+}
+
+/// Returns a decoder if the response is in an encoding the client does not accept, and
+/// updates the headers to describe the decoded body.
+pub fn decoder_for(
+    status: StatusCode,
+    headers: &mut HeaderMap,
+    accepted: &AcceptedEncodings,
+) -> Option<Decoder> {
+    // Responses without a body, or with a part of the encoded body
+    if matches!(
+        status,
+        StatusCode::NO_CONTENT | StatusCode::NOT_MODIFIED | StatusCode::PARTIAL_CONTENT
+    ) {
+        return None;
+    }
+
+    let encoding = headers.get(CONTENT_ENCODING)?.to_str().ok()?.trim();
+    if encoding
+        .split(',')
+        .all(|coding| accepted.accepts(coding.trim()))
+    {
+        return None;
+    }
+
+    let Some(decoder) = Decoder::new(encoding) else {
+        tracing::debug!(encoding, "Passing through unsupported content encoding");
+        metrics::counter!(CONTENT_DECODING.name, "encoding" => "other", "outcome" => "unsupported")
+            .increment(1);
+        return None;
+    };
+    metrics::counter!(
+        CONTENT_DECODING.name,
+        "encoding" => decoder.name(),
+        "outcome" => "decoded",
+    )
+    .increment(1);
+
+    headers.remove(CONTENT_ENCODING);
+    headers.remove(CONTENT_LENGTH);
+    // The decoded body is a different representation than the strong validator describes
+    if let Some(etag) = headers.get(ETAG)
+        && !etag.as_bytes().starts_with(b"W/")
+        && let Ok(weak) = HeaderValue::from_bytes(&[b"W/", etag.as_bytes()].concat())
+    {
+        headers.insert(ETAG, weak);
+    }
+
+    Some(decoder)
+}
+
+/// Streaming decoder of a single content coding
+pub struct Decoder {
+    coding: Coding,
+    encoded: u64,
+    decoded: u64,
+}
+
+enum Coding {
+    Gzip(GzDecoder<Vec<u8>>),
+    // The `deflate` coding is the zlib format
+    Deflate(ZlibDecoder<Vec<u8>>),
+    Zstd(zstd::stream::write::Decoder<'static, Vec<u8>>),
+}
+
+impl Decoder {
+    fn new(encoding: &str) -> Option<Self> {
+        let coding = match encoding.to_ascii_lowercase().as_str() {
+            "gzip" | "x-gzip" => Coding::Gzip(GzDecoder::new(Vec::new())),
+            "deflate" => Coding::Deflate(ZlibDecoder::new(Vec::new())),
+            "zstd" => Coding::Zstd(zstd::stream::write::Decoder::new(Vec::new()).ok()?),
+            _ => return None,
+        };
+        Some(Self {
+            coding,
+            encoded: 0,
+            decoded: 0,
+        })
+    }
+
+    fn name(&self) -> &'static str {
+        match self.coding {
+            Coding::Gzip(_) => "gzip",
+            Coding::Deflate(_) => "deflate",
+            Coding::Zstd(_) => "zstd",
+        }
+    }
+
+    /// Decodes a chunk, returning the output that is available so far
+    fn decode(&mut self, data: &[u8]) -> io::Result<Bytes> {
+        let mut output = Vec::new();
+        for step in data.chunks(DECODE_STEP) {
+            let decoded = match &mut self.coding {
+                Coding::Gzip(decoder) => {
+                    decoder.write_all(step)?;
+                    decoder.flush()?;
+                    decoder.get_mut()
+                }
+                Coding::Deflate(decoder) => {
+                    decoder.write_all(step)?;
+                    decoder.flush()?;
+                    decoder.get_mut()
+                }
+                Coding::Zstd(decoder) => {
+                    decoder.write_all(step)?;
+                    decoder.flush()?;
+                    decoder.get_mut()
+                }
+            };
+            self.encoded += step.len() as u64;
+            self.decoded += decoded.len() as u64;
+            output.append(decoded);
+            self.check_limits()?;
+        }
+        Ok(Bytes::from(output))
+    }
+
+    /// Returns the remaining output once the body has ended
+    fn finish(&mut self) -> io::Result<Bytes> {
+        let output = match &mut self.coding {
+            Coding::Gzip(decoder) => {
+                decoder.try_finish()?;
+                decoder.get_mut()
+            }
+            Coding::Deflate(decoder) => {
+                decoder.try_finish()?;
+                decoder.get_mut()
+            }
+            Coding::Zstd(decoder) => {
+                decoder.flush()?;
+                decoder.get_mut()
+            }
+        };
+        let output = std::mem::take(output);
+        self.decoded += output.len() as u64;
+        self.check_limits()?;
+        Ok(Bytes::from(output))
+    }
+
+    fn check_limits(&self) -> io::Result<()> {
+        let exceeded = if self.decoded > MAX_DECODED_BYTES {
+            format!("decoded body exceeds {MAX_DECODED_BYTES} bytes")
+        } else if self.decoded > RATIO_GRACE_BYTES && self.decoded > self.encoded * MAX_EXPANSION {
+            format!("decoded body exceeds {MAX_EXPANSION} times its encoded size")
+        } else {
+            return Ok(());
+        };
+        metrics::counter!(
+            CONTENT_DECODING.name,
+            "encoding" => self.name(),
+            "outcome" => "too_large",
+        )
+        .increment(1);
+        Err(io::Error::new(io::ErrorKind::InvalidData, exceeded))
+    }
+}
+
+/// Response body decoded while it is streamed. Trailers are passed after the decoded data.
+pub struct DecodedBody<B> {
+    inner: B,
+    // None once the encoded body has ended
+    decoder: Option<Decoder>,
+    trailers: Option<Frame<Bytes>>,
+}
+
+impl<B> DecodedBody<B> {
+    pub fn new(inner: B, decoder: Decoder) -> Self {
+        Self {
+            inner,
+            decoder: Some(decoder),
+            trailers: None,
+        }
+    }
+}
+
+impl<B> Body for DecodedBody<B>
+where
+    B: Body<Data = Bytes, Error = ProxyError> + Unpin,
+{
+    type Data = Bytes;
+    type Error = ProxyError;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        if let Some(trailers) = self.trailers.take() {
+            return Poll::Ready(Some(Ok(trailers)));
+        }
+
+        loop {
+            let this = &mut *self;
+            let Some(decoder) = this.decoder.as_mut() else {
+                return Pin::new(&mut this.inner).poll_frame(cx);
+            };
+
+            let frame = match ready!(Pin::new(&mut this.inner).poll_frame(cx)) {
+                Some(Ok(frame)) => frame,
+                Some(Err(e)) => return Poll::Ready(Some(Err(e))),
+                None => {
+                    let output = decoder.finish();
+                    this.decoder = None;
+                    return Poll::Ready(match output {
+                        Ok(output) if output.is_empty() => None,
+                        Ok(output) => Some(Ok(Frame::data(output))),
+                        Err(e) => Some(Err(e.into())),
+                    });
+                }
+            };
+
+            match frame.into_data() {
+                Ok(data) => match decoder.decode(&data) {
+                    // The decoder needs more input
+                    Ok(output) if output.is_empty() => continue,
+                    Ok(output) => return Poll::Ready(Some(Ok(Frame::data(output)))),
+                    Err(e) => return Poll::Ready(Some(Err(e.into()))),
+                },
+                // Trailers end the body
+                Err(trailers) => {
+                    let output = decoder.finish();
+                    this.decoder = None;
+                    return Poll::Ready(Some(match output {
+                        Ok(output) if output.is_empty() => Ok(trailers),
+                        Ok(output) => {
+                            this.trailers = Some(trailers);
+                            Ok(Frame::data(output))
+                        }
+                        Err(e) => Err(e.into()),
+                    }));
+                }
+            }
+        }
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.decoder.is_none() && self.trailers.is_none() && self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        // The decoded size is unknown
+        SizeHint::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::Compression;
+    use flate2::write::GzEncoder;
+    use http_body_util::BodyExt;
+    use std::collections::VecDeque;
+
+    struct Frames(VecDeque<Frame<Bytes>>);
+
+    impl Body for Frames {
+        type Data = Bytes;
+        type Error = ProxyError;
+
+        fn poll_frame(
+            mut self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+        ) -> Poll<Option<Result<Frame<Bytes>, ProxyError>>> {
+            Poll::Ready(self.0.pop_front().map(Ok))
+        }
+    }
+
+    fn headers(accept_encoding: &'static str) -> HeaderMap {
+        HeaderMap::from_iter([(ACCEPT_ENCODING, HeaderValue::from_static(accept_encoding))])
+    }
+
+    /// The encoded body in small chunks, followed by trailers
+    fn chunked(encoded: Vec<u8>) -> Frames {
+        let trailers = HeaderMap::from_iter([(
+            http::HeaderName::from_static("grpc-status"),
+            HeaderValue::from_static("0"),
+        )]);
+        Frames(
+            encoded
+                .chunks(7)
+                .map(|chunk| Frame::data(Bytes::copy_from_slice(chunk)))
+                .chain([Frame::trailers(trailers)])
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn test_accepted_encodings() {
+        let accepted = AcceptedEncodings::parse(&headers("GZIP;q=0.5, br, zstd;q=0"));
+        assert!(accepted.accepts("gzip"));
+        assert!(accepted.accepts("br"));
+        assert!(accepted.accepts("identity"));
+        assert!(!accepted.accepts("zstd"));
+        assert!(!accepted.accepts("deflate"));
+
+        let accepted = AcceptedEncodings::parse(&headers("*, gzip;q=0"));
+        assert!(accepted.accepts("zstd"));
+        assert!(!accepted.accepts("gzip"));
+
+        let accepted = AcceptedEncodings::parse(&HeaderMap::new());
+        assert!(accepted.accepts("identity"));
+        assert!(!accepted.accepts("gzip"));
+    }
+
+    #[test]
+    fn test_negotiate() {
+        let mut request_headers = headers("br, gzip");
+        negotiate(&mut request_headers);
+        assert_eq!(request_headers[ACCEPT_ENCODING], "br, gzip, deflate, zstd");
+
+        let mut request_headers = HeaderMap::new();
+        negotiate(&mut request_headers);
+        assert_eq!(request_headers[ACCEPT_ENCODING], "gzip, deflate, zstd");
+
+        // Codings the proxy cannot decode are only advertised if the client accepts them
+        let mut request_headers = headers("*, br;q=0, gzip;q=0");
+        negotiate(&mut request_headers);
+        assert_eq!(
+            request_headers[ACCEPT_ENCODING],
+            "gzip, deflate, zstd, *, br;q=0"
+        );
+    }
+
+    #[test]
+    fn test_decoder_for() {
+        let response_headers = |encoding: &'static str| {
+            HeaderMap::from_iter([
+                (CONTENT_ENCODING, HeaderValue::from_static(encoding)),
+                (CONTENT_LENGTH, HeaderValue::from_static("42")),
+                (ETAG, HeaderValue::from_static("\"abc\"")),
+            ])
+        };
+        let accepted = AcceptedEncodings::parse(&headers("br"));
+
+        // Accepted encodings pass through
+        let mut headers = response_headers("br");
+        assert!(decoder_for(StatusCode::OK, &mut headers, &accepted).is_none());
+        assert_eq!(headers[CONTENT_ENCODING], "br");
+
+        // Others are decoded
+        let mut headers = response_headers("gzip");
+        let decoder = decoder_for(StatusCode::OK, &mut headers, &accepted).unwrap();
+        assert_eq!(decoder.name(), "gzip");
+        assert!(!headers.contains_key(CONTENT_ENCODING));
+        assert!(!headers.contains_key(CONTENT_LENGTH));
+        assert_eq!(headers[ETAG], "W/\"abc\"");
+
+        // Unless they are not supported or there is no full body
+        let accepted = AcceptedEncodings::parse(&HeaderMap::new());
+        let mut headers = response_headers("br");
+        assert!(decoder_for(StatusCode::OK, &mut headers, &accepted).is_none());
+        let mut headers = response_headers("gzip");
+        assert!(decoder_for(StatusCode::PARTIAL_CONTENT, &mut headers, &accepted).is_none());
+        assert_eq!(headers[CONTENT_ENCODING], "gzip");
+    }
+
+    #[tokio::test]
+    async fn test_decoded_body() {
+        let plain = "synapse ".repeat(100);
+
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(plain.as_bytes()).unwrap();
+        let gzip = encoder.finish().unwrap();
+        let zstd = zstd::encode_all(plain.as_bytes(), 3).unwrap();
+
+        for (encoding, encoded) in [("gzip", gzip), ("zstd", zstd)] {
+            let decoder = Decoder::new(encoding).unwrap();
+            let collected = DecodedBody::new(chunked(encoded), decoder)
+                .collect()
+                .await
+                .unwrap();
+            assert_eq!(collected.trailers().unwrap()["grpc-status"], "0");
+            assert_eq!(collected.to_bytes(), plain.as_bytes());
+        }
+
+        // Bodies expanding too much fail
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::best());
+        encoder
+            .write_all(&vec![0; 2 * RATIO_GRACE_BYTES as usize])
+            .unwrap();
+        let bomb = encoder.finish().unwrap();
+        let decoder = Decoder::new("gzip").unwrap();
+        let result = DecodedBody::new(chunked(bomb), decoder).collect().await;
+        assert!(result.is_err());
+
+        // Corrupt bodies fail
+        let decoder = Decoder::new("gzip").unwrap();
+        let result = DecodedBody::new(chunked(b"not gzip".to_vec()), decoder)
+            .collect()
+            .await;
+        assert!(result.is_err());
+    }
+}
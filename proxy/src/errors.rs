@@ -12,12 +12,42 @@ pub enum ProxyError {
     InvalidUri(#[from] http::uri::InvalidUri),
     #[error("could not resolve route")]
     ResolverError,
+    #[error("route resolution timed out")]
+    ResolverTimeout,
     #[error("locator reqwest error: {0}")]
     ReqwestError(#[from] reqwest::Error),
+    #[error("upstream request timed out")]
+    UpstreamTimeout,
+    #[error("upstream request error: {0}")]
+    UpstreamRequest(#[from] hyper_util::client::legacy::Error),
+    #[error("request body error: {0}")]
+    RequestBody(String),
+    #[error("request body larger than {0} bytes")]
+    PayloadTooLarge(usize),
     #[error("hyper error: {0}")]
     Hyper(#[from] hyper::Error),
     #[error("backup route provider error: {0}")]
     BackupError(#[from] locator::backup_routes::BackupError),
+    #[error("feature flag configuration error: {0}")]
+    FeatureFlags(String),
+    #[error("force upstream configuration error: {0}")]
+    ForceUpstream(String),
+    #[error("trusted proxies configuration error: {0}")]
+    TrustedProxies(String),
+    #[error("egress configuration error: {0}")]
+    Egress(String),
+    #[error("upstream TLS configuration error: {0}")]
+    UpstreamTls(String),
+    #[error("health check configuration error: {0}")]
+    HealthCheck(String),
+    #[error("admin auth configuration error: {0}")]
+    AdminAuth(String),
+    #[error("upstream registration error: {0}")]
+    UpstreamRegistration(String),
+    #[error("hot upgrade error: {0}")]
+    HotUpgrade(String),
+    #[error("listener TLS configuration error: {0}")]
+    ListenerTls(#[from] shared::tls::TlsError),
     #[error("locator client error: {0}")]
     LocatorClientError(#[from] locator::client::ClientError),
 }
@@ -0,0 +1,314 @@
+//! Feature flag providers used to gate routes.
+//!
+//! A route with `match.flag` set only matches if the flag is enabled, which allows routes
+//! to be rolled out per organization without changing the proxy config.
+use crate::config::FeatureFlags;
+use crate::errors::ProxyError;
+use crate::route_actions::RouteMatch;
+use async_trait::async_trait;
+use moka::sync::Cache;
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::Duration;
+
+#[async_trait]
+pub trait FlagProvider: Send + Sync {
+    /// Whether the flag is enabled, optionally for a specific organization.
+    /// Unknown flags and evaluation failures are treated as disabled.
+    async fn is_enabled(&self, flag: &str, organization: Option<&str>) -> bool;
+}
+
+pub fn get_provider(config: FeatureFlags) -> Result<Arc<dyn FlagProvider>, ProxyError> {
+    match config {
+        FeatureFlags::File { path } => Ok(Arc::new(FileFlagProvider::from_file(&path)?)),
+        FeatureFlags::Http {
+            url,
+            cache_ttl_secs,
+            connect_timeout_ms,
+            timeout_ms,
+        } => Ok(Arc::new(HttpFlagProvider::new(
+            url,
+            Duration::from_secs(cache_ttl_secs),
+            Duration::from_millis(connect_timeout_ms),
+            Duration::from_millis(timeout_ms),
+        )?)),
+    }
+}
+
+/// Returns the first route match whose flag is enabled, or that is not gated by a flag.
+pub async fn first_enabled(
+    matches: Vec<RouteMatch>,
+    flags: Option<&dyn FlagProvider>,
+) -> Option<RouteMatch> {
+    for route_match in matches {
+        let enabled = match (&route_match.flag, flags) {
+            (None, _) => true,
+            (Some(flag), Some(flags)) => {
+                let organization = route_match.params.get("organization").map(String::as_str);
+                flags.is_enabled(flag, organization).await
+            }
+            (Some(_), None) => false,
+        };
+
+        if enabled {
+            return Some(route_match);
+        }
+    }
+
+    None
+}
+
+/// Rollout of a single flag in the flags file.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct FlagRollout {
+    /// Enabled for everyone
+    enabled: bool,
+    /// Enabled for these organizations (ids or slugs) only
+    organizations: HashSet<String>,
+}
+
+/// Flags loaded from a static YAML file:
+///
+/// ```yaml
+/// cellular_proxy_enabled:
+///   organizations: [sentry, "1"]
+/// ```
+pub struct FileFlagProvider {
+    flags: HashMap<String, FlagRollout>,
+}
+
+impl FileFlagProvider {
+    pub fn from_file(path: &str) -> Result<Self, ProxyError> {
+        let contents = std::fs::read_to_string(path)?;
+        Self::from_yaml(&contents)
+    }
+
+    pub fn from_yaml(contents: &str) -> Result<Self, ProxyError> {
+        let flags =
+            serde_yaml::from_str(contents).map_err(|e| ProxyError::FeatureFlags(e.to_string()))?;
+        Ok(Self { flags })
+    }
+}
+
+#[async_trait]
+impl FlagProvider for FileFlagProvider {
+    async fn is_enabled(&self, flag: &str, organization: Option<&str>) -> bool {
+        self.flags.get(flag).is_some_and(|rollout| {
+            rollout.enabled || organization.is_some_and(|org| rollout.organizations.contains(org))
+        })
+    }
+}
+
+#[derive(Deserialize)]
+struct FlagResponse {
+    enabled: bool,
+}
+
+/// Flags evaluated by an external HTTP service, cached per flag and organization.
+pub struct HttpFlagProvider {
+    client: reqwest::Client,
+    url: String,
+    cache: Cache<(String, Option<String>), bool>,
+}
+
+impl HttpFlagProvider {
+    pub fn new(
+        url: String,
+        cache_ttl: Duration,
+        connect_timeout: Duration,
+        timeout: Duration,
+    ) -> Result<Self, ProxyError> {
+        let client = reqwest::Client::builder()
+            .connect_timeout(connect_timeout)
+            .timeout(timeout)
+            .build()
+            .map_err(|e| ProxyError::FeatureFlags(e.to_string()))?;
+        Ok(HttpFlagProvider {
+            client,
+            url,
+            cache: Cache::builder()
+                .max_capacity(100_000)
+                .time_to_live(cache_ttl)
+                .build(),
+        })
+    }
+
+    async fn fetch(&self, flag: &str, organization: Option<&str>) -> Result<bool, reqwest::Error> {
+        let mut query = vec![("flag", flag)];
+        if let Some(org) = organization {
+            query.push(("organization", org));
+        }
+
+        let response = self
+            .client
+            .get(&self.url)
+            .query(&query)
+            .send()
+            .await?
+            .error_for_status()?;
+
+        Ok(response.json::<FlagResponse>().await?.enabled)
+    }
+}
+
+#[async_trait]
+impl FlagProvider for HttpFlagProvider {
+    async fn is_enabled(&self, flag: &str, organization: Option<&str>) -> bool {
+        let key = (flag.to_string(), organization.map(String::from));
+        if let Some(enabled) = self.cache.get(&key) {
+            return enabled;
+        }
+
+        match self.fetch(flag, organization).await {
+            Ok(enabled) => {
+                self.cache.insert(key, enabled);
+                enabled
+            }
+            Err(e) => {
+                // Not cached, so the flag is evaluated again on the next request
+                tracing::warn!(flag, error = %e, "Failed to evaluate feature flag");
+                false
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_file_provider() {
+        let provider = FileFlagProvider::from_yaml(
+            r#"
+cellular_proxy_enabled:
+  organizations: [sentry, "1"]
+everyone:
+  enabled: true
+"#,
+        )
+        .unwrap();
+
+        assert!(
+            provider
+                .is_enabled("cellular_proxy_enabled", Some("sentry"))
+                .await
+        );
+        assert!(
+            provider
+                .is_enabled("cellular_proxy_enabled", Some("1"))
+                .await
+        );
+        assert!(
+            !provider
+                .is_enabled("cellular_proxy_enabled", Some("other"))
+                .await
+        );
+        assert!(!provider.is_enabled("cellular_proxy_enabled", None).await);
+
+        assert!(provider.is_enabled("everyone", None).await);
+        assert!(provider.is_enabled("everyone", Some("other")).await);
+
+        // Unknown flags are disabled
+        assert!(!provider.is_enabled("unknown", Some("sentry")).await);
+    }
+
+    #[tokio::test]
+    async fn test_first_enabled() {
+        let provider = FileFlagProvider::from_yaml(
+            r#"
+cellular_proxy_enabled:
+  organizations: [sentry]
+"#,
+        )
+        .unwrap();
+
+        let route_match = |org: &str, flag: Option<&str>, to: &str| RouteMatch {
+            pattern: Arc::from("/organizations/{organization}/*"),
+            params: HashMap::from([("organization".to_string(), org.to_string())]),
+            action: crate::config::Action::Static { to: to.to_string() },
+            flag: flag.map(String::from),
+            header_filter: Default::default(),
+            ip_filter: None,
+            strip_trailers: false,
+            header_rewriter: None,
+            capture: None,
+            timeout: None,
+            retry: None,
+            methods: None,
+        };
+        let target = |m: Option<RouteMatch>| match m.map(|m| m.action) {
+            Some(crate::config::Action::Static { to }) => Some(to),
+            _ => None,
+        };
+
+        // Enabled for the organization, the gated route is used
+        let matches = vec![
+            route_match("sentry", Some("cellular_proxy_enabled"), "cell"),
+            route_match("sentry", None, "monolith"),
+        ];
+        assert_eq!(
+            target(first_enabled(matches, Some(&provider)).await),
+            Some("cell".into())
+        );
+
+        // Disabled for the organization, falls through to the next route
+        let matches = vec![
+            route_match("other", Some("cellular_proxy_enabled"), "cell"),
+            route_match("other", None, "monolith"),
+        ];
+        assert_eq!(
+            target(first_enabled(matches, Some(&provider)).await),
+            Some("monolith".into())
+        );
+
+        // Only gated routes and the flag is disabled
+        let matches = vec![route_match("other", Some("cellular_proxy_enabled"), "cell")];
+        assert_eq!(target(first_enabled(matches, Some(&provider)).await), None);
+    }
+
+    #[test]
+    fn test_file_provider_invalid() {
+        assert!(matches!(
+            FileFlagProvider::from_yaml("flag: [1, 2]"),
+            Err(ProxyError::FeatureFlags(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_http_provider_unavailable() {
+        // Evaluation failures disable the flag
+        let provider = HttpFlagProvider::new(
+            "http://127.0.0.1:1/flags".into(),
+            Duration::from_secs(30),
+            Duration::from_millis(250),
+            Duration::from_secs(1),
+        )
+        .unwrap();
+        assert!(
+            !provider
+                .is_enabled("cellular_proxy_enabled", Some("sentry"))
+                .await
+        );
+    }
+
+    #[tokio::test]
+    async fn test_http_provider_timeout() {
+        // Evaluations of a service that does not answer give up after the timeout
+        let server = crate::testutils::MockServer::stalled().await;
+        let provider = HttpFlagProvider::new(
+            format!("{}/flags", server.url()),
+            Duration::from_secs(30),
+            Duration::from_millis(250),
+            Duration::from_millis(50),
+        )
+        .unwrap();
+        let enabled = tokio::time::timeout(
+            Duration::from_secs(5),
+            provider.is_enabled("cellular_proxy_enabled", Some("sentry")),
+        );
+        assert!(!enabled.await.unwrap());
+    }
+}
@@ -0,0 +1,133 @@
+//! Per-request override of route resolution, for debugging.
+//!
+//! Requests carrying `X-Synapse-Force-Upstream` along with the configured token in
+//! `X-Synapse-Admin-Token` skip route resolution and are sent to the named upstream. This
+//! allows reproducing cell specific bugs from a laptop without changing the route table.
+//!
+//! Both headers are always removed before the request is forwarded, so that the token never
+//! reaches an upstream, even if the override is not enabled.
+use crate::config::ForceUpstream as ForceUpstreamConfig;
+use crate::errors::ProxyError;
+use http::{HeaderMap, HeaderValue};
+use shared::constant_time;
+
+pub const FORCE_UPSTREAM_HEADER: &str = "x-synapse-force-upstream";
+pub const ADMIN_TOKEN_HEADER: &str = "x-synapse-admin-token";
+
+#[derive(Debug, PartialEq)]
+pub enum Forced {
+    Upstream(String),
+    /// The admin token is missing or wrong
+    Unauthorized,
+}
+
+#[derive(Clone, Debug)]
+pub struct ForceUpstream {
+    token: String,
+}
+
+impl TryFrom<ForceUpstreamConfig> for ForceUpstream {
+    type Error = ProxyError;
+
+    fn try_from(config: ForceUpstreamConfig) -> Result<Self, Self::Error> {
+        if config.token.is_empty() {
+            return Err(ProxyError::ForceUpstream(
+                "the admin token must not be empty".into(),
+            ));
+        }
+        Ok(Self {
+            token: config.token,
+        })
+    }
+}
+
+impl ForceUpstream {
+    /// Removes the override headers and returns the forced upstream, if one was requested.
+    pub fn take(&self, headers: &mut HeaderMap<HeaderValue>) -> Option<Forced> {
+        let (upstream, token) = remove_headers(headers);
+        let upstream = upstream?;
+
+        let authorized = token.is_some_and(|token| self.token_matches(token.as_bytes()));
+        match upstream.to_str() {
+            Ok(upstream) if authorized => Some(Forced::Upstream(upstream.to_string())),
+            _ => Some(Forced::Unauthorized),
+        }
+    }
+
+    fn token_matches(&self, value: &[u8]) -> bool {
+        constant_time::eq(value, self.token.as_bytes())
+    }
+}
+
+/// Removes the override headers without evaluating them.
+pub fn remove_headers(
+    headers: &mut HeaderMap<HeaderValue>,
+) -> (Option<HeaderValue>, Option<HeaderValue>) {
+    (
+        headers.remove(FORCE_UPSTREAM_HEADER),
+        headers.remove(ADMIN_TOKEN_HEADER),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http::HeaderName;
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        HeaderMap::from_iter(pairs.iter().map(|(name, value)| {
+            (
+                HeaderName::from_static(name),
+                HeaderValue::from_static(value),
+            )
+        }))
+    }
+
+    #[test]
+    fn test_take() {
+        let force_upstream = ForceUpstream::try_from(ForceUpstreamConfig {
+            token: "secret".into(),
+        })
+        .unwrap();
+
+        let mut request_headers = headers(&[
+            (FORCE_UPSTREAM_HEADER, "us2-getsentry"),
+            (ADMIN_TOKEN_HEADER, "secret"),
+            ("x-custom", "value"),
+        ]);
+        assert_eq!(
+            force_upstream.take(&mut request_headers),
+            Some(Forced::Upstream("us2-getsentry".into()))
+        );
+        // Only the override headers are removed
+        assert_eq!(request_headers.len(), 1);
+        assert!(request_headers.contains_key("x-custom"));
+
+        for token in [None, Some("wrong"), Some("secret2")] {
+            let mut request_headers = headers(&[(FORCE_UPSTREAM_HEADER, "us2-getsentry")]);
+            if let Some(token) = token {
+                request_headers.insert(ADMIN_TOKEN_HEADER, HeaderValue::from_static(token));
+            }
+            assert_eq!(
+                force_upstream.take(&mut request_headers),
+                Some(Forced::Unauthorized)
+            );
+            assert!(request_headers.is_empty());
+        }
+
+        // The token alone does not force anything, but is still removed
+        let mut request_headers = headers(&[(ADMIN_TOKEN_HEADER, "secret")]);
+        assert_eq!(force_upstream.take(&mut request_headers), None);
+        assert!(request_headers.is_empty());
+    }
+
+    #[test]
+    fn test_empty_token() {
+        assert!(matches!(
+            ForceUpstream::try_from(ForceUpstreamConfig {
+                token: String::new()
+            }),
+            Err(ProxyError::ForceUpstream(_))
+        ));
+    }
+}
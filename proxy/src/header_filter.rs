@@ -0,0 +1,231 @@
+//! Per-route filtering of upstream response headers.
+//!
+//! Routes can either allow-list the headers passed to clients or deny-list headers that
+//! must not leave the internal network, such as the `X-Cell-*` headers set by cells. Header
+//! names are case-insensitive, and a trailing `*` matches every header with that prefix.
+//!
+//! Allow-lists always pass the headers needed to read the body, so that allow-listing
+//! cannot produce responses clients are unable to decode. Routes without a list deny
+//! `X-Cell-*`, so that internal headers are only passed to clients when a route opts in.
+use crate::config::ResponseHeaders;
+use crate::errors::ProxyError;
+use http::header::{CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE, HeaderMap, TRANSFER_ENCODING};
+use http::{HeaderName, HeaderValue};
+
+// Denied on routes without `response_headers`
+const DENIED_BY_DEFAULT: &str = "x-cell-*";
+
+// Passed regardless of the allow-list
+const ALWAYS_ALLOWED: &[HeaderName] = &[
+    CONTENT_ENCODING,
+    CONTENT_LENGTH,
+    CONTENT_TYPE,
+    TRANSFER_ENCODING,
+];
+
+#[derive(Debug, PartialEq)]
+pub struct HeaderFilter {
+    allow: bool,
+    patterns: Vec<Pattern>,
+}
+
+#[derive(Debug, PartialEq)]
+enum Pattern {
+    Exact(HeaderName),
+    // Lowercased
+    Prefix(String),
+}
+
+impl Pattern {
+    fn parse(pattern: &str) -> Result<Self, ProxyError> {
+        let invalid =
+            |_| ProxyError::InvalidRoute(format!("Invalid response header name: {pattern}"));
+
+        match pattern.strip_suffix('*') {
+            Some(prefix) => {
+                // Validated as a header name, so that the prefix can match at all
+                if !prefix.is_empty() {
+                    HeaderName::from_bytes(prefix.as_bytes()).map_err(invalid)?;
+                }
+                Ok(Pattern::Prefix(prefix.to_ascii_lowercase()))
+            }
+            None => Ok(Pattern::Exact(
+                HeaderName::from_bytes(pattern.as_bytes()).map_err(invalid)?,
+            )),
+        }
+    }
+
+    fn matches(&self, name: &HeaderName) -> bool {
+        // Header names are always lowercase
+        match self {
+            Pattern::Exact(exact) => exact == name,
+            Pattern::Prefix(prefix) => name.as_str().starts_with(prefix.as_str()),
+        }
+    }
+}
+
+impl TryFrom<ResponseHeaders> for HeaderFilter {
+    type Error = ProxyError;
+
+    fn try_from(config: ResponseHeaders) -> Result<Self, Self::Error> {
+        let (allow, patterns) = match config {
+            ResponseHeaders::Allow { allow } => (true, allow),
+            ResponseHeaders::Deny { deny } => (false, deny),
+        };
+
+        let patterns = patterns
+            .iter()
+            .map(|pattern| Pattern::parse(pattern))
+            .collect::<Result<_, _>>()?;
+
+        Ok(Self { allow, patterns })
+    }
+}
+
+impl Default for HeaderFilter {
+    fn default() -> Self {
+        Self {
+            allow: false,
+            patterns: vec![Pattern::Prefix(
+                DENIED_BY_DEFAULT.trim_end_matches('*').to_string(),
+            )],
+        }
+    }
+}
+
+impl HeaderFilter {
+    fn passes(&self, name: &HeaderName) -> bool {
+        if self.allow && ALWAYS_ALLOWED.contains(name) {
+            return true;
+        }
+        let matched = self.patterns.iter().any(|pattern| pattern.matches(name));
+        matched == self.allow
+    }
+
+    /// Removes the headers that may not be passed to clients.
+    pub fn apply(&self, headers: &mut HeaderMap<HeaderValue>) {
+        let removed: Vec<HeaderName> = headers
+            .keys()
+            .filter(|name| !self.passes(name))
+            .cloned()
+            .collect();
+
+        for name in removed {
+            headers.remove(name);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response_headers() -> HeaderMap {
+        HeaderMap::from_iter(
+            [
+                ("content-type", "application/json"),
+                ("content-length", "2"),
+                ("x-cell-id", "us1"),
+                ("X-Cell-Region", "us"),
+                ("x-request-id", "abc"),
+                ("set-cookie", "session=1"),
+            ]
+            .map(|(name, value)| {
+                (
+                    HeaderName::from_bytes(name.as_bytes()).unwrap(),
+                    HeaderValue::from_static(value),
+                )
+            }),
+        )
+    }
+
+    fn names(headers: &HeaderMap) -> Vec<&str> {
+        let mut names: Vec<_> = headers.keys().map(|name| name.as_str()).collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn test_deny() {
+        let filter = HeaderFilter::try_from(ResponseHeaders::Deny {
+            deny: vec!["X-CELL-*".into(), "Set-Cookie".into()],
+        })
+        .unwrap();
+
+        let mut headers = response_headers();
+        filter.apply(&mut headers);
+        assert_eq!(
+            names(&headers),
+            vec!["content-length", "content-type", "x-request-id"]
+        );
+    }
+
+    #[test]
+    fn test_allow() {
+        let filter = HeaderFilter::try_from(ResponseHeaders::Allow {
+            allow: vec!["X-Request-ID".into()],
+        })
+        .unwrap();
+
+        // Headers needed to read the body are always passed
+        let mut headers = response_headers();
+        filter.apply(&mut headers);
+        assert_eq!(
+            names(&headers),
+            vec!["content-length", "content-type", "x-request-id"]
+        );
+
+        let filter = HeaderFilter::try_from(ResponseHeaders::Allow {
+            allow: vec!["x-cell-*".into()],
+        })
+        .unwrap();
+        let mut headers = response_headers();
+        filter.apply(&mut headers);
+        assert_eq!(
+            names(&headers),
+            vec![
+                "content-length",
+                "content-type",
+                "x-cell-id",
+                "x-cell-region"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_default() {
+        let mut headers = response_headers();
+        HeaderFilter::default().apply(&mut headers);
+        assert_eq!(
+            names(&headers),
+            vec![
+                "content-length",
+                "content-type",
+                "set-cookie",
+                "x-request-id"
+            ]
+        );
+
+        // An empty deny-list passes every header
+        let filter = HeaderFilter::try_from(ResponseHeaders::Deny { deny: vec![] }).unwrap();
+        let mut headers = response_headers();
+        filter.apply(&mut headers);
+        assert_eq!(headers.len(), 6);
+    }
+
+    #[test]
+    fn test_invalid_header_name() {
+        assert!(matches!(
+            HeaderFilter::try_from(ResponseHeaders::Deny {
+                deny: vec!["x cell".into()]
+            }),
+            Err(ProxyError::InvalidRoute(_))
+        ));
+        assert!(matches!(
+            HeaderFilter::try_from(ResponseHeaders::Allow {
+                allow: vec!["x:*".into()]
+            }),
+            Err(ProxyError::InvalidRoute(_))
+        ));
+    }
+}
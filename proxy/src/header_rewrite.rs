@@ -0,0 +1,199 @@
+//! Per-route rewriting of request and response headers.
+//!
+//! Routes can set, append and remove headers on the requests forwarded to the upstream and
+//! on the responses passed to clients, for example to tell a cell which routing decision
+//! was made with `X-Sentry-Cell` or to strip internal headers. Headers are removed first,
+//! then set, then appended, so a header can be both removed and set to replace it. Names
+//! and values are validated when the routes are loaded.
+//!
+//! Responses generated by the proxy itself, such as 502s, are not rewritten.
+use crate::config::{HeaderRewrite, HeaderRewrites};
+use crate::errors::ProxyError;
+use http::header::HeaderMap;
+use http::{HeaderName, HeaderValue};
+
+#[derive(Debug, Default, PartialEq)]
+pub struct HeaderRewriter {
+    request: Rewrite,
+    response: Rewrite,
+}
+
+#[derive(Debug, Default, PartialEq)]
+struct Rewrite {
+    set: Vec<(HeaderName, HeaderValue)>,
+    append: Vec<(HeaderName, HeaderValue)>,
+    remove: Vec<HeaderName>,
+}
+
+fn parse_name(name: &str) -> Result<HeaderName, ProxyError> {
+    HeaderName::from_bytes(name.as_bytes())
+        .map_err(|_| ProxyError::InvalidRoute(format!("Invalid rewritten header name: {name}")))
+}
+
+fn parse_header((name, value): (String, String)) -> Result<(HeaderName, HeaderValue), ProxyError> {
+    let value = HeaderValue::try_from(value.as_str()).map_err(|_| {
+        ProxyError::InvalidRoute(format!("Invalid value of rewritten header {name}: {value}"))
+    })?;
+    Ok((parse_name(&name)?, value))
+}
+
+impl TryFrom<HeaderRewrite> for Rewrite {
+    type Error = ProxyError;
+
+    fn try_from(config: HeaderRewrite) -> Result<Self, Self::Error> {
+        let mut set: Vec<_> = config
+            .set
+            .into_iter()
+            .map(parse_header)
+            .collect::<Result<_, _>>()?;
+        let mut append: Vec<_> = config
+            .append
+            .into_iter()
+            .map(parse_header)
+            .collect::<Result<_, _>>()?;
+        // Config maps are unordered, sorted so that appended values have a stable order
+        set.sort_by(|a, b| a.0.as_str().cmp(b.0.as_str()));
+        append.sort_by(|a, b| a.0.as_str().cmp(b.0.as_str()));
+        let remove = config
+            .remove
+            .iter()
+            .map(|name| parse_name(name))
+            .collect::<Result<_, _>>()?;
+
+        Ok(Self {
+            set,
+            append,
+            remove,
+        })
+    }
+}
+
+impl Rewrite {
+    fn apply(&self, headers: &mut HeaderMap<HeaderValue>) {
+        for name in &self.remove {
+            headers.remove(name);
+        }
+        for (name, value) in &self.set {
+            headers.insert(name.clone(), value.clone());
+        }
+        for (name, value) in &self.append {
+            headers.append(name.clone(), value.clone());
+        }
+    }
+}
+
+impl TryFrom<HeaderRewrites> for HeaderRewriter {
+    type Error = ProxyError;
+
+    fn try_from(config: HeaderRewrites) -> Result<Self, Self::Error> {
+        Ok(Self {
+            request: config.request.unwrap_or_default().try_into()?,
+            response: config.response.unwrap_or_default().try_into()?,
+        })
+    }
+}
+
+impl HeaderRewriter {
+    /// Rewrites the headers of a request forwarded to the upstream.
+    pub fn apply_request(&self, headers: &mut HeaderMap<HeaderValue>) {
+        self.request.apply(headers);
+    }
+
+    /// Rewrites the headers of an upstream response passed to the client.
+    pub fn apply_response(&self, headers: &mut HeaderMap<HeaderValue>) {
+        self.response.apply(headers);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn map(entries: &[(&str, &str)]) -> HashMap<String, String> {
+        entries
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    fn values<'a>(headers: &'a HeaderMap, name: &str) -> Vec<&'a str> {
+        headers
+            .get_all(name)
+            .iter()
+            .map(|value| value.to_str().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_apply() {
+        let rewriter = HeaderRewriter::try_from(HeaderRewrites {
+            request: Some(HeaderRewrite {
+                set: map(&[("X-Sentry-Cell", "us1")]),
+                append: map(&[("Via-Route", "organizations")]),
+                remove: vec!["X-Internal-Token".into(), "X-Sentry-Cell".into()],
+            }),
+            response: Some(HeaderRewrite {
+                remove: vec!["x-cell-id".into()],
+                ..Default::default()
+            }),
+        })
+        .unwrap();
+
+        let mut headers = HeaderMap::from_iter([
+            (
+                HeaderName::from_static("x-sentry-cell"),
+                HeaderValue::from_static("spoofed"),
+            ),
+            (
+                HeaderName::from_static("x-internal-token"),
+                HeaderValue::from_static("secret"),
+            ),
+            (
+                HeaderName::from_static("via-route"),
+                HeaderValue::from_static("edge"),
+            ),
+            (
+                HeaderName::from_static("x-cell-id"),
+                HeaderValue::from_static("us1"),
+            ),
+        ]);
+        rewriter.apply_request(&mut headers);
+
+        // Set headers replace the ones sent by the client
+        assert_eq!(values(&headers, "x-sentry-cell"), vec!["us1"]);
+        assert!(headers.get("x-internal-token").is_none());
+        assert_eq!(values(&headers, "via-route"), vec!["edge", "organizations"]);
+        // Response rewrites do not apply to requests
+        assert_eq!(values(&headers, "x-cell-id"), vec!["us1"]);
+
+        rewriter.apply_response(&mut headers);
+        assert!(headers.get("x-cell-id").is_none());
+    }
+
+    #[test]
+    fn test_invalid() {
+        let invalid = |rewrite: HeaderRewrite| {
+            matches!(
+                HeaderRewriter::try_from(HeaderRewrites {
+                    request: None,
+                    response: Some(rewrite),
+                }),
+                Err(ProxyError::InvalidRoute(_))
+            )
+        };
+
+        assert!(invalid(HeaderRewrite {
+            set: map(&[("x cell", "us1")]),
+            ..Default::default()
+        }));
+        assert!(invalid(HeaderRewrite {
+            append: map(&[("x-cell", "us1\n")]),
+            ..Default::default()
+        }));
+        assert!(invalid(HeaderRewrite {
+            remove: vec!["x:cell".into()],
+            ..Default::default()
+        }));
+    }
+}
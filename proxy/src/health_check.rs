@@ -0,0 +1,298 @@
+//! Active health checks of upstreams.
+//!
+//! Upstreams with `health_check` are probed in the background with `GET {path}` every
+//! `interval_secs`. A probe fails on a connection error, after `timeout_ms` or with a status
+//! other than 2xx. An upstream is down after `unhealthy_threshold` consecutive failed probes,
+//! and up again after its next successful probe. Upstreams start out up, so that a proxy
+//! that just started does not reject requests before the first probes have finished.
+//!
+//! Requests of static routes to an upstream that is down are answered with 503, and dynamic
+//! routes use their `default` upstream instead of a resolved upstream that is down. Forced
+//! upstreams are used regardless of their health.
+use crate::config::HealthCheck;
+use crate::errors::ProxyError;
+use crate::metrics_defs::UPSTREAM_HEALTHY;
+use chrono::{DateTime, Utc};
+use hyper::StatusCode;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::future::Future;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+use tokio::time::MissedTickBehavior;
+
+/// Health of the upstreams with health checks, shared with their probe tasks
+#[derive(Debug, Default)]
+pub struct UpstreamHealth {
+    // Upstreams registered at runtime are added and removed, see `add` and `remove`
+    states: RwLock<HashMap<String, Arc<Mutex<HealthState>>>>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct HealthState {
+    pub healthy: bool,
+    pub consecutive_failures: u32,
+    pub last_probe: Option<DateTime<Utc>>,
+    /// Why the last probe failed, None if it succeeded
+    pub last_error: Option<String>,
+}
+
+impl Default for HealthState {
+    fn default() -> Self {
+        Self {
+            healthy: true,
+            consecutive_failures: 0,
+            last_probe: None,
+            last_error: None,
+        }
+    }
+}
+
+/// Rejects health checks that cannot be run.
+pub fn validate(upstream: &str, config: &HealthCheck) -> Result<(), ProxyError> {
+    let error = if !config.path.starts_with('/') {
+        "path must start with '/'"
+    } else if http::uri::PathAndQuery::try_from(config.path.as_str()).is_err() {
+        "path is not a valid URI path"
+    } else if config.interval_secs == 0 {
+        "interval_secs must be positive"
+    } else if config.timeout_ms == 0 {
+        "timeout_ms must be positive"
+    } else if config.unhealthy_threshold == 0 {
+        "unhealthy_threshold must be positive"
+    } else {
+        return Ok(());
+    };
+    Err(ProxyError::HealthCheck(format!("{upstream}: {error}")))
+}
+
+impl UpstreamHealth {
+    pub fn new(upstreams: impl IntoIterator<Item = String>) -> Self {
+        Self {
+            states: RwLock::new(
+                upstreams
+                    .into_iter()
+                    .map(|upstream| (upstream, Arc::default()))
+                    .collect(),
+            ),
+        }
+    }
+
+    /// Starts tracking the health of an upstream, which is up until its probes fail. Probes
+    /// spawned for the upstream before are stopped.
+    pub fn add(&self, upstream: String) {
+        self.states
+            .write()
+            .unwrap()
+            .insert(upstream, Arc::default());
+    }
+
+    /// Stops tracking the health of an upstream, and its probes.
+    pub fn remove(&self, upstream: &str) {
+        self.states.write().unwrap().remove(upstream);
+    }
+
+    /// Whether requests can be routed to the upstream. Upstreams without health checks
+    /// are always healthy.
+    pub fn is_healthy(&self, upstream: &str) -> bool {
+        self.state(upstream)
+            .is_none_or(|state| state.lock().unwrap().healthy)
+    }
+
+    /// Health of every upstream with health checks, by name
+    pub fn report(&self) -> BTreeMap<String, HealthState> {
+        self.states
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(upstream, state)| (upstream.clone(), state.lock().unwrap().clone()))
+            .collect()
+    }
+
+    fn state(&self, upstream: &str) -> Option<Arc<Mutex<HealthState>>> {
+        self.states.read().unwrap().get(upstream).cloned()
+    }
+
+    /// Records the outcome of a probe, and marks the upstream down or up accordingly.
+    fn record(&self, upstream: &str, outcome: Result<(), String>, unhealthy_threshold: u32) {
+        let Some(state) = self.state(upstream) else {
+            return;
+        };
+        let mut state = state.lock().unwrap();
+        let was_healthy = state.healthy;
+        state.last_probe = Some(Utc::now());
+        match outcome {
+            Ok(()) => {
+                state.healthy = true;
+                state.consecutive_failures = 0;
+                state.last_error = None;
+            }
+            Err(error) => {
+                state.consecutive_failures = state.consecutive_failures.saturating_add(1);
+                state.healthy &= state.consecutive_failures < unhealthy_threshold;
+                state.last_error = Some(error);
+            }
+        }
+
+        if state.healthy != was_healthy {
+            if state.healthy {
+                tracing::info!(upstream, "Upstream is up again");
+            } else {
+                tracing::warn!(
+                    upstream,
+                    failures = state.consecutive_failures,
+                    error = state.last_error.as_deref(),
+                    "Upstream is down"
+                );
+            }
+        }
+        metrics::gauge!(UPSTREAM_HEALTHY.name, "upstream" => upstream.to_string())
+            .set(if state.healthy { 1.0 } else { 0.0 });
+    }
+
+    /// Probes the upstream until the health is dropped. `probe` sends the request and
+    /// returns the status of the response.
+    pub fn spawn<F, Fut>(self: &Arc<Self>, upstream: String, config: &HealthCheck, probe: F)
+    where
+        F: Fn() -> Fut + Send + 'static,
+        Fut: Future<Output = Result<StatusCode, ProxyError>> + Send,
+    {
+        let health = Arc::downgrade(self);
+        // Probes stop once the upstream is removed or added again
+        let state = self.state(&upstream);
+        let timeout = Duration::from_millis(config.timeout_ms);
+        let unhealthy_threshold = config.unhealthy_threshold;
+        let mut interval = tokio::time::interval(Duration::from_secs(config.interval_secs));
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        tokio::spawn(async move {
+            loop {
+                interval.tick().await;
+                let outcome = match tokio::time::timeout(timeout, probe()).await {
+                    Ok(Ok(status)) if status.is_success() => Ok(()),
+                    Ok(Ok(status)) => Err(format!("status {}", status.as_u16())),
+                    Ok(Err(e)) => Err(e.to_string()),
+                    Err(_) => Err("timed out".to_string()),
+                };
+                // The proxy service that owned the health was dropped
+                let Some(health) = health.upgrade() else {
+                    break;
+                };
+                let current = health.state(&upstream);
+                if !matches!((&state, &current), (Some(a), Some(b)) if Arc::ptr_eq(a, b)) {
+                    break;
+                }
+                health.record(&upstream, outcome, unhealthy_threshold);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn health_check(path: &str) -> HealthCheck {
+        HealthCheck {
+            path: path.into(),
+            interval_secs: 1,
+            timeout_ms: 100,
+            unhealthy_threshold: 2,
+        }
+    }
+
+    #[test]
+    fn test_record() {
+        let health = UpstreamHealth::new(["us1".to_string()]);
+        assert!(health.is_healthy("us1"));
+        assert!(health.is_healthy("unchecked"));
+
+        // Down after two consecutive failures
+        health.record("us1", Err("status 500".into()), 2);
+        assert!(health.is_healthy("us1"));
+        health.record("us1", Err("timed out".into()), 2);
+        assert!(!health.is_healthy("us1"));
+        let report = health.report();
+        let state = &report["us1"];
+        assert_eq!(state.consecutive_failures, 2);
+        assert_eq!(state.last_error.as_deref(), Some("timed out"));
+
+        // Up after one success
+        health.record("us1", Ok(()), 2);
+        assert!(health.is_healthy("us1"));
+        assert_eq!(health.report()["us1"].last_error, None);
+
+        health.record("unchecked", Err("status 500".into()), 1);
+        assert!(!health.report().contains_key("unchecked"));
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(validate("us1", &health_check("/_health/")).is_ok());
+        assert!(validate("us1", &health_check("_health")).is_err());
+        assert!(validate("us1", &health_check("/_health now")).is_err());
+        for invalid in [
+            HealthCheck {
+                interval_secs: 0,
+                ..health_check("/")
+            },
+            HealthCheck {
+                timeout_ms: 0,
+                ..health_check("/")
+            },
+            HealthCheck {
+                unhealthy_threshold: 0,
+                ..health_check("/")
+            },
+        ] {
+            assert!(validate("us1", &invalid).is_err(), "{invalid:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_spawn() {
+        let health = Arc::new(UpstreamHealth::new(["us1".to_string()]));
+        health.spawn("us1".into(), &health_check("/"), || async {
+            Ok(StatusCode::SERVICE_UNAVAILABLE)
+        });
+
+        // The first probe is sent right away, the second one after the interval
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while health.is_healthy("us1") {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(
+            health.report()["us1"].last_error.as_deref(),
+            Some("status 503")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_add_remove() {
+        let health = Arc::new(UpstreamHealth::default());
+        health.add("us2".into());
+        health.spawn("us2".into(), &health_check("/"), || async {
+            Ok(StatusCode::SERVICE_UNAVAILABLE)
+        });
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while health.is_healthy("us2") {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+
+        // Added again, the upstream starts out up and its previous probes stop
+        health.add("us2".into());
+        assert!(health.is_healthy("us2"));
+        tokio::time::sleep(Duration::from_millis(1500)).await;
+        assert_eq!(health.report()["us2"].last_probe, None);
+
+        health.remove("us2");
+        assert!(health.report().is_empty());
+        assert!(health.is_healthy("us2"));
+    }
+}
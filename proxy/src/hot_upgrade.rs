@@ -0,0 +1,478 @@
+//! Zero-downtime restarts by handing the listening sockets over to a new process.
+//!
+//! The listening sockets are obtained, in order of preference:
+//! 1. From systemd socket activation, if `LISTEN_FDS` is set for this process. The first
+//!    socket is the proxy listener, and the second one, if passed, the admin listener.
+//!    Further sockets are used by the additional listeners bound to the same address.
+//! 2. From the running proxy, if `hot_upgrade.socket` is configured and a proxy listens on
+//!    it. It sends its listening sockets over the unix socket (`SCM_RIGHTS`) followed by
+//!    its config (see `config_diff`), then stops accepting connections and drains the
+//!    open ones.
+//! 3. By binding the configured addresses.
+//!
+//! Additional listeners take over the socket bound to their address, so they can be added,
+//! removed and reordered across upgrades. Listeners whose socket was not handed over bind
+//! their address.
+//!
+//! Both processes accept connections on the same sockets until the handoff is complete,
+//! so no connection is refused during the restart.
+//!
+//! The unix socket is only accessible to the user of the running proxy, and processes of
+//! other users connecting to it anyway are not handed anything.
+use crate::config::{Config, Listener};
+use crate::config_diff::{HandedOverConfig, ReloadHistory};
+use crate::errors::ProxyError;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, ToSocketAddrs};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::net::UnixListener;
+
+// First file descriptor passed by systemd, after stdin, stdout and stderr
+const SD_LISTEN_FDS_START: RawFd = 3;
+const HANDOFF_MESSAGE: u8 = b'L';
+// Proxy and admin listeners, and the additional listeners
+const MAX_HANDED_OVER_SOCKETS: usize = 64;
+// Longest a process waits on the other one during a handoff
+const HANDOFF_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Listening sockets of the proxy and admin listeners
+pub struct Listeners {
+    pub proxy: TcpListener,
+    pub admin: TcpListener,
+    /// Sockets of the additional listeners, in configured order
+    pub additional: Vec<TcpListener>,
+    /// Config of the proxy the sockets were taken over from
+    pub handed_over: Option<HandedOverConfig>,
+}
+
+impl Listeners {
+    /// Obtains the listening sockets, see the module documentation. Blocks while the sockets
+    /// are taken over from a running proxy.
+    pub fn acquire(config: &Config) -> Result<Self, ProxyError> {
+        let (inherited, handed_over) = match systemd_sockets()? {
+            sockets if !sockets.is_empty() => {
+                tracing::info!(count = sockets.len(), "Using sockets from systemd");
+                (sockets, None)
+            }
+            _ => match &config.hot_upgrade {
+                Some(hot_upgrade) => take_over(&hot_upgrade.socket)?,
+                None => (Vec::new(), None),
+            },
+        };
+        let mut inherited = inherited.into_iter();
+
+        let proxy = match inherited.next() {
+            Some(socket) => socket,
+            None => bind(&config.listener.host, config.listener.port)?,
+        };
+        let admin = match inherited.next() {
+            Some(socket) => socket,
+            None => bind(&config.admin_listener.host, config.admin_listener.port)?,
+        };
+        let mut inherited: Vec<TcpListener> = inherited.collect();
+        let additional = config
+            .additional_listeners
+            .iter()
+            .map(|additional| {
+                match inherited
+                    .iter()
+                    .position(|socket| is_bound_to(socket, &additional.listener))
+                {
+                    Some(index) => Ok(inherited.swap_remove(index)),
+                    None => bind(&additional.listener.host, additional.listener.port),
+                }
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        if !inherited.is_empty() {
+            tracing::info!(
+                count = inherited.len(),
+                "Closing inherited sockets of removed listeners"
+            );
+        }
+
+        for socket in [&proxy, &admin].into_iter().chain(&additional) {
+            socket.set_nonblocking(true)?;
+        }
+
+        Ok(Listeners {
+            proxy,
+            admin,
+            additional,
+            handed_over,
+        })
+    }
+
+    fn raw_fds(&self) -> Vec<RawFd> {
+        [&self.proxy, &self.admin]
+            .into_iter()
+            .chain(&self.additional)
+            .map(|socket| socket.as_raw_fd())
+            .collect()
+    }
+}
+
+/// Whether the socket is bound to the listener's address. Host names are resolved, and
+/// the socket is taken over if it is bound to any of their addresses. Listeners on port 0
+/// never take a socket over.
+fn is_bound_to(socket: &TcpListener, listener: &Listener) -> bool {
+    let Ok(addr) = socket.local_addr() else {
+        return false;
+    };
+    if listener.port == 0 || addr.port() != listener.port {
+        return false;
+    }
+    match (listener.host.as_str(), listener.port).to_socket_addrs() {
+        Ok(mut resolved) => resolved.any(|resolved| resolved == addr),
+        Err(e) => {
+            tracing::warn!(host = %listener.host, "Failed to resolve listener host: {e}");
+            false
+        }
+    }
+}
+
+fn bind(host: &str, port: u16) -> Result<TcpListener, ProxyError> {
+    Ok(TcpListener::bind(format!("{host}:{port}"))?)
+}
+
+fn systemd_sockets() -> Result<Vec<TcpListener>, ProxyError> {
+    let for_this_process = std::env::var("LISTEN_PID")
+        .ok()
+        .and_then(|pid| pid.parse::<u32>().ok())
+        == Some(std::process::id());
+    if !for_this_process {
+        return Ok(Vec::new());
+    }
+    let count = std::env::var("LISTEN_FDS")
+        .ok()
+        .and_then(|count| count.parse::<RawFd>().ok())
+        .unwrap_or(0);
+
+    (SD_LISTEN_FDS_START..SD_LISTEN_FDS_START + count)
+        .map(|fd| {
+            // SAFETY: systemd passes the sockets as open file descriptors owned by this
+            // process, starting at SD_LISTEN_FDS_START
+            let socket = unsafe { TcpListener::from_raw_fd(fd) };
+            socket.local_addr().map_err(|e| {
+                ProxyError::HotUpgrade(format!("file descriptor {fd} is not a TCP socket: {e}"))
+            })?;
+            Ok(socket)
+        })
+        .collect()
+}
+
+/// Takes the listening sockets and config over from the proxy listening on `path`.
+/// Returns no sockets if no proxy is listening.
+fn take_over(path: &Path) -> Result<(Vec<TcpListener>, Option<HandedOverConfig>), ProxyError> {
+    let mut stream = match UnixStream::connect(path) {
+        Ok(stream) => stream,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok((Vec::new(), None)),
+        Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => {
+            tracing::info!(path = %path.display(), "Removing stale hot upgrade socket");
+            std::fs::remove_file(path)?;
+            return Ok((Vec::new(), None));
+        }
+        Err(e) => return Err(e.into()),
+    };
+    stream.set_read_timeout(Some(HANDOFF_TIMEOUT))?;
+
+    let fds = recv_fds(&stream, MAX_HANDED_OVER_SOCKETS)
+        .map_err(|e| ProxyError::HotUpgrade(format!("failed to receive sockets: {e}")))?;
+    // The running proxy sends its config and closes the connection once it gave up the
+    // socket path
+    let mut sent_config = Vec::new();
+    let handed_over = match stream.read_to_end(&mut sent_config) {
+        Ok(_) if sent_config.is_empty() => None,
+        Ok(_) => serde_json::from_slice(&sent_config)
+            .inspect_err(|e| tracing::warn!("Ignoring config of running proxy: {e}"))
+            .ok(),
+        Err(e) => {
+            tracing::warn!("Failed to receive config of running proxy: {e}");
+            None
+        }
+    };
+
+    tracing::info!(
+        count = fds.len(),
+        "Took listening sockets over from running proxy"
+    );
+    Ok((
+        fds.into_iter().map(TcpListener::from).collect(),
+        handed_over,
+    ))
+}
+
+/// Hands the listening sockets over to the next proxy process
+pub struct Handoff {
+    listener: UnixListener,
+    path: PathBuf,
+    fds: Vec<RawFd>,
+    config: Vec<u8>,
+}
+
+impl Handoff {
+    /// Listens on `path` for the next process. The sockets of `listeners` must stay open
+    /// until the handoff is complete.
+    pub fn bind(
+        path: &Path,
+        listeners: &Listeners,
+        reloads: &ReloadHistory,
+    ) -> Result<Self, ProxyError> {
+        // A previous process that was not upgraded may have left the socket behind
+        match std::fs::remove_file(path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+        let listener = UnixListener::bind(path)?;
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+        Ok(Handoff {
+            listener,
+            path: path.to_path_buf(),
+            fds: listeners.raw_fds(),
+            config: reloads.handover(),
+        })
+    }
+
+    /// Completes once the sockets were handed over to a new process
+    pub async fn serve(self) {
+        loop {
+            let sent = match self.listener.accept().await {
+                Ok((stream, _)) if !same_user(&stream) => {
+                    tracing::warn!("Refused hot upgrade from a process of another user");
+                    continue;
+                }
+                Ok((stream, _)) => {
+                    let fds = self.fds.clone();
+                    let config = self.config.clone();
+                    // The config is written with blocking I/O, off the runtime
+                    let sent = tokio::task::spawn_blocking(move || {
+                        let mut stream = stream.into_std()?;
+                        stream.set_nonblocking(false)?;
+                        stream.set_write_timeout(Some(HANDOFF_TIMEOUT))?;
+                        send_fds(&stream, &fds)?;
+                        stream.write_all(&config)?;
+                        Ok(stream)
+                    })
+                    .await;
+                    sent.unwrap_or_else(|e| Err(io::Error::other(e)))
+                }
+                Err(e) => Err(e),
+            };
+            match sent {
+                Ok(stream) => {
+                    // The new process binds the path once the connection is closed
+                    let _ = std::fs::remove_file(&self.path);
+                    drop(stream);
+                    tracing::info!("Handed listening sockets over to new proxy process");
+                    return;
+                }
+                Err(e) => tracing::warn!("Failed to hand listening sockets over: {e}"),
+            }
+        }
+    }
+}
+
+/// Whether the peer runs as the user of this process
+fn same_user(stream: &tokio::net::UnixStream) -> bool {
+    // SAFETY: geteuid cannot fail
+    let uid = unsafe { libc::geteuid() };
+    stream.peer_cred().is_ok_and(|cred| cred.uid() == uid)
+}
+
+fn send_fds(stream: &UnixStream, fds: &[RawFd]) -> io::Result<()> {
+    let payload = [HANDOFF_MESSAGE];
+    let mut iov = libc::iovec {
+        iov_base: payload.as_ptr() as *mut libc::c_void,
+        iov_len: payload.len(),
+    };
+    let data_len = std::mem::size_of_val(fds) as u32;
+    // SAFETY: CMSG_SPACE only computes a size
+    let space = unsafe { libc::CMSG_SPACE(data_len) } as usize;
+    // u64 elements align the buffer for cmsghdr
+    let mut control = vec![0u64; space.div_ceil(8)];
+
+    // SAFETY: the message points to the payload and control buffers, which outlive the
+    // call, and the control buffer has room for one header with `fds`
+    unsafe {
+        let mut msg: libc::msghdr = std::mem::zeroed();
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr().cast();
+        msg.msg_controllen = space as _;
+
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        (*cmsg).cmsg_level = libc::SOL_SOCKET;
+        (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+        (*cmsg).cmsg_len = libc::CMSG_LEN(data_len) as _;
+        std::ptr::copy_nonoverlapping(
+            fds.as_ptr(),
+            libc::CMSG_DATA(cmsg).cast::<RawFd>(),
+            fds.len(),
+        );
+
+        if libc::sendmsg(stream.as_raw_fd(), &msg, 0) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+fn recv_fds(stream: &UnixStream, max: usize) -> io::Result<Vec<OwnedFd>> {
+    let mut payload = [0u8; 1];
+    let mut iov = libc::iovec {
+        iov_base: payload.as_mut_ptr().cast(),
+        iov_len: payload.len(),
+    };
+    let data_len = (max * std::mem::size_of::<RawFd>()) as u32;
+    // SAFETY: CMSG_SPACE only computes a size
+    let space = unsafe { libc::CMSG_SPACE(data_len) } as usize;
+    let mut control = vec![0u64; space.div_ceil(8)];
+
+    let mut fds = Vec::new();
+    // SAFETY: the message points to the payload and control buffers, which outlive the
+    // call. The received descriptors are new and owned by this process.
+    unsafe {
+        let mut msg: libc::msghdr = std::mem::zeroed();
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr().cast();
+        msg.msg_controllen = space as _;
+
+        if libc::recvmsg(stream.as_raw_fd(), &mut msg, libc::MSG_CMSG_CLOEXEC) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+        while !cmsg.is_null() {
+            if (*cmsg).cmsg_level == libc::SOL_SOCKET && (*cmsg).cmsg_type == libc::SCM_RIGHTS {
+                let data = libc::CMSG_DATA(cmsg).cast::<RawFd>();
+                let count = ((*cmsg).cmsg_len as usize - libc::CMSG_LEN(0) as usize)
+                    / std::mem::size_of::<RawFd>();
+                for i in 0..count {
+                    fds.push(OwnedFd::from_raw_fd(data.add(i).read_unaligned()));
+                }
+            }
+            cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+        }
+
+        if msg.msg_flags & libc::MSG_CTRUNC != 0 {
+            return Err(io::Error::other("too many sockets received"));
+        }
+    }
+
+    if payload[0] != HANDOFF_MESSAGE || fds.is_empty() {
+        return Err(io::Error::other("no sockets received"));
+    }
+    Ok(fds)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::net::TcpStream;
+
+    fn config(socket: PathBuf) -> Config {
+        serde_yaml::from_str(&format!(
+            r#"
+upstreams: []
+routes: []
+listener: {{host: "127.0.0.1", port: 0}}
+admin_listener: {{host: "127.0.0.1", port: 0}}
+locator: {{type: url, url: "http://locator"}}
+hot_upgrade: {{socket: "{}"}}
+additional_listeners:
+  - name: internal
+    listener: {{host: "127.0.0.1", port: 0}}
+    routes: []
+"#,
+            socket.display()
+        ))
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_handoff() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = config(dir.path().join("proxy.sock"));
+        let socket = &config.hot_upgrade.as_ref().unwrap().socket;
+
+        // Nothing to take over from
+        let running = Listeners::acquire(&config).unwrap();
+        assert!(running.handed_over.is_none());
+        let reloads = ReloadHistory::new(&config, None);
+        let handoff = Handoff::bind(socket, &running, &reloads).unwrap();
+        let mode = std::fs::metadata(socket).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        let handed_over = tokio::spawn(handoff.serve());
+
+        let config_clone = config.clone();
+        let mut upgraded = tokio::task::spawn_blocking(move || Listeners::acquire(&config_clone))
+            .await
+            .unwrap()
+            .unwrap();
+        handed_over.await.unwrap();
+        assert!(!socket.exists());
+
+        // The new process records the reload
+        let reloads = ReloadHistory::new(&config, upgraded.handed_over.take());
+        assert_eq!(reloads.events().len(), 1);
+
+        // The new process serves on the same addresses
+        assert_eq!(
+            upgraded.proxy.local_addr().unwrap(),
+            running.proxy.local_addr().unwrap()
+        );
+        assert_eq!(
+            upgraded.admin.local_addr().unwrap(),
+            running.admin.local_addr().unwrap()
+        );
+        assert_eq!(upgraded.additional.len(), 1);
+        drop(running);
+        let addr = upgraded.proxy.local_addr().unwrap();
+        let mut client = TcpStream::connect(addr).unwrap();
+        client.write_all(b"ping").unwrap();
+        upgraded.proxy.set_nonblocking(false).unwrap();
+        let (mut accepted, _) = upgraded.proxy.accept().unwrap();
+        let mut received = [0u8; 4];
+        accepted.read_exact(&mut received).unwrap();
+        assert_eq!(&received, b"ping");
+
+        // The socket path is free for the next upgrade
+        Handoff::bind(socket, &upgraded, &reloads).unwrap();
+    }
+
+    #[test]
+    fn test_is_bound_to() {
+        let socket = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = socket.local_addr().unwrap().port();
+        let listener = |host: &str, port| Listener {
+            host: host.into(),
+            port,
+            ..Default::default()
+        };
+        assert!(is_bound_to(&socket, &listener("127.0.0.1", port)));
+        assert!(!is_bound_to(&socket, &listener("0.0.0.0", port)));
+        // Host names are resolved
+        assert!(is_bound_to(&socket, &listener("localhost", port)));
+        assert!(!is_bound_to(&socket, &listener("localhost", port + 1)));
+        assert!(!is_bound_to(&socket, &listener("127.0.0.1", 0)));
+    }
+
+    #[test]
+    fn test_stale_socket() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = config(dir.path().join("proxy.sock"));
+        let socket = &config.hot_upgrade.as_ref().unwrap().socket;
+
+        // Left behind by a process that exited
+        drop(std::os::unix::net::UnixListener::bind(socket).unwrap());
+        assert!(socket.exists());
+
+        Listeners::acquire(&config).unwrap();
+        assert!(!socket.exists());
+    }
+}
@@ -1,43 +1,354 @@
+mod admin;
+mod allowed_methods;
+mod anomalies;
+mod backoff;
+mod capture;
+mod client_ip;
+mod client_protocol;
 pub mod config;
+mod config_diff;
+mod connector;
+mod content_encoding;
 mod errors;
+mod feature_flags;
+mod force_upstream;
+mod header_filter;
+mod header_rewrite;
+mod health_check;
+mod hot_upgrade;
 pub mod metrics_defs;
+mod path_normalization;
+mod pool_stats;
 mod proxy_service;
 mod resolvers;
+mod retry;
 mod route_actions;
+mod route_metrics;
+mod route_tracing;
+mod trailers;
+mod upstream_client;
+mod upstream_errors;
+mod upstream_registry;
 mod upstreams;
+mod watchdog;
 
-use crate::errors::ProxyError;
+#[cfg(test)]
+mod testutils;
+
+use crate::admin::{AdminAccess, ProxyAdminService};
+use crate::config_diff::ReloadHistory;
+pub use crate::connector::{ConnectInfo, TimedConnector};
+pub use crate::errors::ProxyError;
+pub use crate::feature_flags::{FileFlagProvider, FlagProvider, HttpFlagProvider};
+use crate::hot_upgrade::{Handoff, Listeners};
+use crate::proxy_service::ConfiguredParts;
+pub use crate::proxy_service::{ProxyService, ProxyServiceBuilder};
+use hyper::body::Incoming;
+use locator::admin::LocatorAdmin;
 use locator::client::Locator;
-use shared::admin_service::AdminService;
-use shared::http::run_http_service;
+use shared::http::{serve_http_service, shutdown_signal};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio::sync::watch;
+use tokio::task::JoinSet;
 
 pub async fn run(config: config::Config) -> Result<(), ProxyError> {
-    let locator = Locator::new(config.locator.to_client_config()).await?;
-
-    let proxy_service =
-        proxy_service::ProxyService::try_new(locator.clone(), config.routes, config.upstreams)?;
-    let admin_service = AdminService::new({
-        let locator = locator.clone();
-        move || locator.is_ready()
-    });
-
-    let proxy_task = run_http_service(&config.listener.host, config.listener.port, proxy_service);
-    let admin_task = run_http_service(
-        &config.admin_listener.host,
-        config.admin_listener.port,
-        admin_service,
-    );
+    // Taken over before the locator starts, so that the running proxy keeps serving
+    // while this one initializes
+    let mut listeners = {
+        let config = config.clone();
+        tokio::task::spawn_blocking(move || Listeners::acquire(&config))
+            .await
+            .map_err(|e| ProxyError::HotUpgrade(e.to_string()))??
+    };
+    let tls = config
+        .listener
+        .tls
+        .as_ref()
+        .map(config::ListenerTls::acceptor)
+        .transpose()?;
+    let admin_tls = config
+        .admin_listener
+        .tls
+        .as_ref()
+        .map(config::ListenerTls::acceptor)
+        .transpose()?;
+    let admin_access = config
+        .admin_listener
+        .auth
+        .clone()
+        .map(AdminAccess::try_from)
+        .transpose()?;
+    let reloads = Arc::new(ReloadHistory::new(&config, listeners.handed_over.take()));
+
+    let additional_tls = config
+        .additional_listeners
+        .iter()
+        .map(|additional| {
+            additional
+                .listener
+                .tls
+                .as_ref()
+                .map(config::ListenerTls::acceptor)
+                .transpose()
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let locator = Locator::new(config.locator.clone().to_client_config()).await?;
+    let feature_flags = config
+        .feature_flags
+        .clone()
+        .map(feature_flags::get_provider)
+        .transpose()?;
+
+    let mut builder = service_builder(&config, &locator, &feature_flags, &config.listener)
+        .routes(config.routes.clone())
+        .upstreams(config.upstreams.clone());
+    if let Some(route_tracing) = config.route_tracing.clone() {
+        builder = builder.route_tracing(route_tracing);
+    }
+    if let Some(request_capture) = config.request_capture.clone() {
+        builder = builder.request_capture(request_capture);
+    }
+    let proxy_service = builder.build()?;
+
+    let additional_services = config
+        .additional_listeners
+        .iter()
+        .map(|additional| {
+            let mut builder =
+                service_builder(&config, &locator, &feature_flags, &additional.listener)
+                    .routes(additional.routes.clone())
+                    .upstreams(additional.upstreams.clone())
+                    .recordings_of(&proxy_service);
+            if additional.inherit_routes {
+                builder = builder
+                    .fallback_routes(config.routes.clone())
+                    .upstreams(inherited_upstreams(&config, additional).cloned());
+            }
+            tracing::info!(
+                name = %additional.name,
+                routes = additional.routes.len(),
+                inherit_routes = additional.inherit_routes,
+                "Serving additional listener"
+            );
+            builder.build()
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let admin_service = ProxyAdminService::new(
+        {
+            let locator = locator.clone();
+            move || locator.is_ready()
+        },
+        proxy_service.unmatched_requests(),
+        proxy_service.captured_requests(),
+        reloads.clone(),
+        std::iter::once(&proxy_service)
+            .chain(&additional_services)
+            .map(ProxyService::upstream_health)
+            .collect(),
+        std::iter::once(admin::MAIN_LISTENER)
+            .chain(config.additional_listeners.iter().map(|a| a.name.as_str()))
+            .zip(std::iter::once(&proxy_service).chain(&additional_services))
+            .map(|(name, service)| (name.to_string(), service.route_actions()))
+            .collect(),
+        admin_access,
+    )
+    .with_endpoints(Arc::new(LocatorAdmin::new(locator.clone())));
+    let admin_service = match &config.upstream_registration {
+        Some(registration) => {
+            let registry = proxy_service.upstream_registry(registration.state_file.clone());
+            registry.restore().await?;
+            admin_service.with_endpoints(Arc::new(registry))
+        }
+        None => admin_service,
+    };
 
-    tokio::select! {
-        result = async { tokio::try_join!(proxy_task, admin_task) } => {
-            result?;
+    // Set once the sockets were handed over to a new proxy process
+    let (handed_over_tx, handed_over) = watch::channel(false);
+    let handoff_drain_timeout = match &config.hot_upgrade {
+        Some(hot_upgrade) => {
+            let handoff = Handoff::bind(&hot_upgrade.socket, &listeners, &reloads)?;
+            tokio::spawn(async move {
+                handoff.serve().await;
+                let _ = handed_over_tx.send(true);
+            });
+            Duration::from_secs(hot_upgrade.drain_timeout_secs)
         }
-        _ = tokio::signal::ctrl_c() => {
-            tracing::info!("Shutting down proxy...");
+        None => Duration::ZERO,
+    };
+    let signal_drain_timeout = Duration::from_secs(config.drain_timeout_secs);
+    // Completes with the time given to open connections to finish
+    let shutdown = move |mut handed_over: watch::Receiver<bool>| async move {
+        let handoff = async {
+            if handed_over.wait_for(|done| *done).await.is_err() {
+                // Hot upgrades are disabled
+                std::future::pending::<()>().await;
+            }
+        };
+        tokio::select! {
+            _ = handoff => {
+                tracing::info!("Sockets handed over, draining connections...");
+                handoff_drain_timeout
+            }
+            _ = shutdown_signal() => {
+                tracing::info!("Shutting down proxy, draining connections...");
+                signal_drain_timeout
+            }
         }
+    };
+
+    let proxy_task = serve_http_service(
+        TcpListener::from_std(listeners.proxy)?,
+        proxy_service,
+        tls,
+        shutdown(handed_over.clone()),
+    );
+    let mut additional_tasks = JoinSet::new();
+    for ((socket, service), tls) in listeners
+        .additional
+        .into_iter()
+        .zip(additional_services)
+        .zip(additional_tls)
+    {
+        additional_tasks.spawn(serve_http_service(
+            TcpListener::from_std(socket)?,
+            service,
+            tls,
+            shutdown(handed_over.clone()),
+        ));
     }
+    let additional_task = async move {
+        while let Some(result) = additional_tasks.join_next().await {
+            result.map_err(std::io::Error::other)??;
+        }
+        Ok::<_, ProxyError>(())
+    };
+    let admin_task = serve_http_service(
+        TcpListener::from_std(listeners.admin)?,
+        admin_service,
+        admin_tls,
+        shutdown(handed_over),
+    );
+
+    tokio::try_join!(proxy_task, admin_task, additional_task)?;
+    tracing::info!("Connections drained, shutting down proxy...");
 
     locator.shutdown().await;
 
     Ok(())
 }
+
+/// Checks the config like [`run`] does, without binding the listeners or starting the
+/// locator.
+pub fn validate(config: &config::Config) -> Result<(), ProxyError> {
+    let tls = [&config.listener.tls, &config.admin_listener.tls]
+        .into_iter()
+        .chain(config.additional_listeners.iter().map(|a| &a.listener.tls))
+        .flatten();
+    for tls in tls {
+        tls.acceptor()?;
+    }
+    config
+        .admin_listener
+        .auth
+        .clone()
+        .map(AdminAccess::try_from)
+        .transpose()?;
+
+    validate_listener(
+        config,
+        &config.listener,
+        config.routes.clone(),
+        Vec::new(),
+        config.upstreams.clone(),
+    )?;
+    for additional in &config.additional_listeners {
+        let mut upstreams = additional.upstreams.clone();
+        let mut fallback_routes = Vec::new();
+        if additional.inherit_routes {
+            upstreams.extend(inherited_upstreams(config, additional).cloned());
+            fallback_routes = config.routes.clone();
+        }
+        validate_listener(
+            config,
+            &additional.listener,
+            additional.routes.clone(),
+            fallback_routes,
+            upstreams,
+        )?;
+    }
+    Ok(())
+}
+
+/// Checks the routes and upstreams of a listener with the checks of
+/// [`ProxyServiceBuilder::build`].
+fn validate_listener(
+    config: &config::Config,
+    listener: &config::Listener,
+    routes: Vec<config::Route>,
+    fallback_routes: Vec<config::Route>,
+    upstreams: Vec<config::UpstreamConfig>,
+) -> Result<(), ProxyError> {
+    ConfiguredParts::try_new(
+        routes,
+        fallback_routes,
+        upstreams,
+        &listener.trusted_proxies,
+        config.force_upstream.clone(),
+        config.feature_flags.is_some(),
+        config.request_capture.is_some(),
+    )?;
+    Ok(())
+}
+
+/// Main upstreams used by the inherited routes of an additional listener, unless the
+/// listener has an upstream of the same name
+fn inherited_upstreams<'a>(
+    config: &'a config::Config,
+    additional: &'a config::AdditionalListener,
+) -> impl Iterator<Item = &'a config::UpstreamConfig> {
+    config.upstreams.iter().filter(|upstream| {
+        !additional
+            .upstreams
+            .iter()
+            .any(|own| own.name == upstream.name)
+    })
+}
+
+/// Builder of the proxy service of a listener, with the options shared by all listeners
+fn service_builder(
+    config: &config::Config,
+    locator: &Locator,
+    feature_flags: &Option<Arc<dyn FlagProvider>>,
+    listener: &config::Listener,
+) -> ProxyServiceBuilder<Incoming> {
+    let mut builder =
+        ProxyService::builder(locator.clone()).trusted_proxies(listener.trusted_proxies.clone());
+    if let Some(watchdog) = config.slow_request_watchdog.clone() {
+        builder = builder.slow_request_watchdog(watchdog);
+    }
+    if let Some(backoff) = config.upstream_backoff.clone() {
+        builder = builder.upstream_backoff(backoff);
+    }
+    if let Some(force_upstream) = config.force_upstream.clone() {
+        builder = builder.force_upstream(force_upstream);
+    }
+    if let Some(anomaly_events) = config.anomaly_events.clone() {
+        builder = builder.anomaly_events(anomaly_events);
+    }
+    if config.content_negotiation {
+        builder = builder.content_negotiation(true);
+    }
+    if let Some(client_protocol) = config.client_protocol.clone() {
+        builder = builder.client_protocol(client_protocol);
+    }
+    if let Some(path_normalization) = listener.path_normalization.clone() {
+        builder = builder.path_normalization(path_normalization);
+    }
+    if let Some(feature_flags) = feature_flags {
+        builder = builder.feature_flags(feature_flags.clone());
+    }
+    builder
+}
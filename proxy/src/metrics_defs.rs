@@ -3,7 +3,7 @@ use shared::metrics_defs::{MetricDef, MetricType};
 pub const REQUEST_DURATION: MetricDef = MetricDef {
     name: "request.duration",
     metric_type: MetricType::Histogram,
-    description: "Proxy request duration in seconds. Tagged with status, upstream. Sampled at 1%.",
+    description: "Proxy request duration in seconds. Tagged with status, upstream, route (the path pattern of the matched route, or 'none'). Sampled at 1%.",
 };
 
 pub const REQUESTS_INFLIGHT: MetricDef = MetricDef {
@@ -12,5 +12,106 @@ pub const REQUESTS_INFLIGHT: MetricDef = MetricDef {
     description: "Number of requests currently being processed.",
 };
 
+pub const SLOW_REQUESTS: MetricDef = MetricDef {
+    name: "request.slow",
+    metric_type: MetricType::Counter,
+    description: "Number of requests exceeding the slow request watchdog threshold. Tagged with upstream.",
+};
+
+pub const UPSTREAM_BACKOFF: MetricDef = MetricDef {
+    name: "upstream.backoff",
+    metric_type: MetricType::Counter,
+    description: "Number of requests answered locally because the upstream requested a backoff with Retry-After. Tagged with upstream.",
+};
+
+pub const FORCED_UPSTREAM: MetricDef = MetricDef {
+    name: "request.forced_upstream",
+    metric_type: MetricType::Counter,
+    description: "Number of requests with an X-Synapse-Force-Upstream header. Tagged with upstream, authorized.",
+};
+
+pub const UPSTREAM_CONNECTIONS: MetricDef = MetricDef {
+    name: "upstream.connections",
+    metric_type: MetricType::Gauge,
+    description: "Number of connections to the upstream, approximate for HTTP/2 upstreams. Tagged with upstream, state (idle, in_use).",
+};
+
+pub const CONTENT_DECODING: MetricDef = MetricDef {
+    name: "response.content_decoding",
+    metric_type: MetricType::Counter,
+    description: "Upstream responses in an encoding the client does not accept. Tagged with encoding, outcome ('decoded', 'unsupported' or 'too_large').",
+};
+
+pub const ROUTE_DISTINCT_PATHS: MetricDef = MetricDef {
+    name: "route.distinct_paths",
+    metric_type: MetricType::Counter,
+    description: "Incremented the first time a concrete path is seen on a route, so that the total is the path cardinality of the route. Up to 1000 paths are counted per route. Tagged with route. Sampled at 1%.",
+};
+
+pub const RESOLVER_TIMEOUTS: MetricDef = MetricDef {
+    name: "resolver.timeout",
+    metric_type: MetricType::Counter,
+    description: "Number of dynamic route resolutions that exceeded their share of the route's time budget. The default upstream is used instead. Tagged with resolver.",
+};
+
+pub const REQUEST_TIMEOUTS: MetricDef = MetricDef {
+    name: "request.timeout",
+    metric_type: MetricType::Counter,
+    description: "Number of requests answered with 504 because the upstream response exceeded the route's time budget. Tagged with upstream.",
+};
+
+pub const UPSTREAM_RETRIES: MetricDef = MetricDef {
+    name: "upstream.retry",
+    metric_type: MetricType::Counter,
+    description: "Number of upstream requests retried according to the route's retry policy. Tagged with upstream, reason (the retried status or 'connect_error').",
+};
+
+pub const UPSTREAM_HEALTHY: MetricDef = MetricDef {
+    name: "upstream.healthy",
+    metric_type: MetricType::Gauge,
+    description: "1 if the upstream passes its health checks, 0 if it is down. Only reported for upstreams with health checks. Tagged with upstream.",
+};
+
+pub const UPSTREAM_UNHEALTHY: MetricDef = MetricDef {
+    name: "upstream.unhealthy",
+    metric_type: MetricType::Counter,
+    description: "Number of requests answered with 503 because their upstream is down according to its health checks. Tagged with upstream.",
+};
+
+pub const UPSTREAM_ERRORS: MetricDef = MetricDef {
+    name: "upstream.error",
+    metric_type: MetricType::Counter,
+    description: "Number of requests answered with 502 because the upstream request failed without a response. Tagged with upstream, category (dns, connect_timeout, connection_refused, connect, tls, reset, http2 or other).",
+};
+
+pub const REQUEST_BODY_ERRORS: MetricDef = MetricDef {
+    name: "request.body_error",
+    metric_type: MetricType::Counter,
+    description: "Number of requests answered with 400 because their body could not be read from the client while it was sent to the upstream. Tagged with upstream.",
+};
+
+pub const CLIENT_PROTOCOLS: MetricDef = MetricDef {
+    name: "request.client_protocol",
+    metric_type: MetricType::Counter,
+    description: "Number of requests by the protocol of the client connection, if client protocol reporting is enabled. Tagged with protocol (HTTP version), tls_version, alpn ('none' for plaintext listeners), route.",
+};
+
 // TODO: all metrics must be added here for now, this can be done dynamically with a macro in the future.
-pub const ALL_METRICS: &[MetricDef] = &[REQUEST_DURATION, REQUESTS_INFLIGHT];
+pub const ALL_METRICS: &[MetricDef] = &[
+    REQUEST_DURATION,
+    REQUESTS_INFLIGHT,
+    SLOW_REQUESTS,
+    UPSTREAM_BACKOFF,
+    FORCED_UPSTREAM,
+    UPSTREAM_CONNECTIONS,
+    CONTENT_DECODING,
+    ROUTE_DISTINCT_PATHS,
+    RESOLVER_TIMEOUTS,
+    REQUEST_TIMEOUTS,
+    UPSTREAM_RETRIES,
+    UPSTREAM_HEALTHY,
+    UPSTREAM_UNHEALTHY,
+    UPSTREAM_ERRORS,
+    REQUEST_BODY_ERRORS,
+    CLIENT_PROTOCOLS,
+];
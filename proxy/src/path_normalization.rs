@@ -0,0 +1,218 @@
+//! Normalization of request paths, following RFC 3986 section 6.2.2.
+//!
+//! Clients and SDKs occasionally send paths like `/api//0/./projects/`, which would neither
+//! match the routes written for `/api/0/projects/` nor be understood by every upstream.
+//! When enabled on the listener, paths are normalized before route matching, and the
+//! normalized path is what upstreams receive. The query is never changed.
+use crate::config::{PathNormalization, PercentDecoding};
+use http::Request;
+use http::uri::{PathAndQuery, Uri};
+use std::borrow::Cow;
+
+#[derive(Clone, Debug)]
+pub struct PathNormalizer {
+    config: PathNormalization,
+}
+
+impl From<PathNormalization> for PathNormalizer {
+    fn from(config: PathNormalization) -> Self {
+        Self { config }
+    }
+}
+
+impl PathNormalizer {
+    /// Replaces the request's path with its normalized form.
+    pub fn apply<B>(&self, request: &mut Request<B>) {
+        let path = request.uri().path();
+        let Cow::Owned(normalized) = self.normalize(path) else {
+            return;
+        };
+
+        match with_path(request.uri(), &normalized) {
+            Ok(uri) => {
+                tracing::debug!(path, normalized, "Normalized request path");
+                *request.uri_mut() = uri;
+            }
+            Err(e) => tracing::warn!(path, "Failed to normalize request path: {e}"),
+        }
+    }
+
+    /// Returns the normalized path, borrowed if it was already normalized.
+    pub fn normalize<'a>(&self, path: &'a str) -> Cow<'a, str> {
+        // Asterisk-form and the like have no segments
+        if !path.starts_with('/') {
+            return Cow::Borrowed(path);
+        }
+
+        let mut normalized = Cow::Borrowed(path);
+        if self.config.percent_decoding == PercentDecoding::Unreserved && path.contains('%') {
+            normalized = Cow::Owned(decode_unreserved(&normalized));
+        }
+        if self.config.merge_slashes && normalized.contains("//") {
+            normalized = Cow::Owned(merge_slashes(&normalized));
+        }
+        if self.config.remove_dot_segments && has_dot_segments(&normalized) {
+            normalized = Cow::Owned(remove_dot_segments(&normalized));
+        }
+
+        // Steps may leave the path unchanged, e.g. "%2f" only has its case changed
+        match normalized {
+            Cow::Owned(normalized) if normalized == path => Cow::Borrowed(path),
+            normalized => normalized,
+        }
+    }
+}
+
+fn with_path(uri: &Uri, path: &str) -> Result<Uri, http::Error> {
+    let path_and_query = match uri.query() {
+        Some(query) => PathAndQuery::try_from(format!("{path}?{query}"))?,
+        None => PathAndQuery::try_from(path)?,
+    };
+    let mut parts = uri.clone().into_parts();
+    parts.path_and_query = Some(path_and_query);
+    Ok(Uri::from_parts(parts)?)
+}
+
+/// Decodes percent-encoded unreserved characters and uppercases the hex digits of the
+/// remaining percent-encodings.
+fn decode_unreserved(path: &str) -> String {
+    let bytes = path.as_bytes();
+    let mut decoded = String::with_capacity(path.len());
+
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .filter(|_| bytes[i] == b'%')
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+
+        match hex {
+            Some(byte) if byte.is_ascii_alphanumeric() || b"-._~".contains(&byte) => {
+                decoded.push(char::from(byte));
+                i += 3;
+            }
+            Some(byte) => {
+                decoded.push_str(&format!("%{byte:02X}"));
+                i += 3;
+            }
+            None => {
+                // Only ASCII is ever changed, so the rest is copied as is
+                let len = path[i..].chars().next().map_or(1, char::len_utf8);
+                decoded.push_str(&path[i..i + len]);
+                i += len;
+            }
+        }
+    }
+    decoded
+}
+
+fn merge_slashes(path: &str) -> String {
+    let mut merged = String::with_capacity(path.len());
+    for c in path.chars() {
+        if !(c == '/' && merged.ends_with('/')) {
+            merged.push(c);
+        }
+    }
+    merged
+}
+
+fn has_dot_segments(path: &str) -> bool {
+    path.split('/')
+        .any(|segment| segment == "." || segment == "..")
+}
+
+/// Resolves `.` and `..` segments of an absolute path. `..` never goes above the root.
+fn remove_dot_segments(path: &str) -> String {
+    let segments: Vec<&str> = path[1..].split('/').collect();
+    let last = segments.len() - 1;
+
+    let mut output: Vec<&str> = Vec::with_capacity(segments.len());
+    for (i, segment) in segments.into_iter().enumerate() {
+        match segment {
+            "." => {}
+            ".." => {
+                output.pop();
+            }
+            segment => {
+                output.push(segment);
+                continue;
+            }
+        }
+        // A trailing dot segment refers to a directory
+        if i == last {
+            output.push("");
+        }
+    }
+
+    format!("/{}", output.join("/"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn normalizer() -> PathNormalizer {
+        PathNormalizer::from(PathNormalization::default())
+    }
+
+    #[test]
+    fn test_normalize() {
+        let normalizer = normalizer();
+        let cases = [
+            ("/api/0/projects/", "/api/0/projects/"),
+            ("/api//0/./projects", "/api/0/projects"),
+            ("///api/0/", "/api/0/"),
+            ("/api/0/organizations/../projects/", "/api/0/projects/"),
+            ("/api/0/..", "/api/"),
+            ("/api/0/.", "/api/0/"),
+            ("/../../api", "/api"),
+            ("/api/0/..foo/.bar", "/api/0/..foo/.bar"),
+            ("/api/%30/%7Efoo%2e/", "/api/0/~foo./"),
+            ("/api/%2e%2E/0/", "/0/"),
+            ("/api/a%2fb/%e2%82%ac", "/api/a%2Fb/%E2%82%AC"),
+            ("/api/%zz/%4", "/api/%zz/%4"),
+            ("/api/ü//0", "/api/ü/0"),
+            ("*", "*"),
+        ];
+        for (path, expected) in cases {
+            assert_eq!(normalizer.normalize(path), expected, "{path}");
+        }
+
+        // Already normalized paths are not copied
+        for path in ["/api/0/", "/api/%2F/"] {
+            assert!(matches!(normalizer.normalize(path), Cow::Borrowed(_)));
+        }
+    }
+
+    #[test]
+    fn test_normalize_steps_disabled() {
+        let normalizer = PathNormalizer::from(PathNormalization {
+            merge_slashes: false,
+            remove_dot_segments: true,
+            percent_decoding: PercentDecoding::None,
+        });
+
+        assert_eq!(normalizer.normalize("/api//0/./%30"), "/api//0/%30");
+        assert_eq!(normalizer.normalize("/api//../0"), "/api/0");
+    }
+
+    #[test]
+    fn test_apply() {
+        let normalizer = normalizer();
+
+        let mut request = Request::builder()
+            .uri("http://sentry.io/api//0/./projects/?cursor=a//b")
+            .body(())
+            .unwrap();
+        normalizer.apply(&mut request);
+        assert_eq!(
+            request.uri().to_string(),
+            "http://sentry.io/api/0/projects/?cursor=a//b"
+        );
+
+        let mut request = Request::builder().uri("/api//0/").body(()).unwrap();
+        normalizer.apply(&mut request);
+        assert_eq!(request.uri().to_string(), "/api/0/");
+    }
+}
@@ -0,0 +1,177 @@
+//! Connections pooled towards upstreams.
+//!
+//! hyper does not expose the state of its connection pool, so connections are counted
+//! from the outside. The connector counts the connections it opened until they are closed,
+//! and every upstream response counts as a connection in use until its body is finished or
+//! dropped. Open connections that are not in use are idle in the pool. HTTP/2 upstreams
+//! serve concurrent responses over one connection, so the counts are only exact for
+//! HTTP/1.1 upstreams.
+use crate::metrics_defs::UPSTREAM_CONNECTIONS;
+use hyper::body::{Body, Frame, SizeHint};
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::{Arc, LazyLock, Mutex, OnceLock};
+use std::task::{Context, Poll};
+
+/// Shared by all clients, connections are identified by the authority they connect to
+static POOL_STATS: LazyLock<Mutex<HashMap<String, Arc<Counts>>>> = LazyLock::new(Default::default);
+
+#[derive(Debug, Default)]
+struct Counts {
+    /// Name of the upstream, known once a request was sent to it
+    upstream: OnceLock<String>,
+    open: AtomicI64,
+    in_use: AtomicI64,
+}
+
+impl Counts {
+    fn get(authority: &str) -> Arc<Self> {
+        POOL_STATS
+            .lock()
+            .unwrap()
+            .entry(authority.to_string())
+            .or_default()
+            .clone()
+    }
+
+    fn idle(&self) -> i64 {
+        (self.open.load(Ordering::Relaxed) - self.in_use.load(Ordering::Relaxed)).max(0)
+    }
+
+    fn emit(&self) {
+        let Some(upstream) = self.upstream.get() else {
+            return;
+        };
+        let in_use = self.in_use.load(Ordering::Relaxed);
+        metrics::gauge!(UPSTREAM_CONNECTIONS.name, "upstream" => upstream.clone(), "state" => "in_use")
+            .set(in_use as f64);
+        metrics::gauge!(UPSTREAM_CONNECTIONS.name, "upstream" => upstream.clone(), "state" => "idle")
+            .set(self.idle() as f64);
+    }
+}
+
+/// Counts a connection as open until dropped.
+#[derive(Debug)]
+pub struct OpenConnection(Arc<Counts>);
+
+impl OpenConnection {
+    pub fn new(authority: &str) -> Self {
+        let counts = Counts::get(authority);
+        counts.open.fetch_add(1, Ordering::Relaxed);
+        counts.emit();
+        Self(counts)
+    }
+}
+
+impl Drop for OpenConnection {
+    fn drop(&mut self) {
+        self.0.open.fetch_sub(1, Ordering::Relaxed);
+        self.0.emit();
+    }
+}
+
+/// Counts a connection to the upstream as in use until dropped.
+#[derive(Debug)]
+pub struct InUse(Arc<Counts>);
+
+impl InUse {
+    pub fn new(upstream: &str, authority: &str) -> Self {
+        let counts = Counts::get(authority);
+        // Upstreams sharing an authority share their connections, the first name is used
+        let _ = counts.upstream.set(upstream.to_string());
+        counts.in_use.fetch_add(1, Ordering::Relaxed);
+        counts.emit();
+        Self(counts)
+    }
+}
+
+impl Drop for InUse {
+    fn drop(&mut self) {
+        self.0.in_use.fetch_sub(1, Ordering::Relaxed);
+        self.0.emit();
+    }
+}
+
+/// Response body that keeps its connection counted as in use until the body is finished.
+pub struct InUseBody<B> {
+    inner: B,
+    in_use: Option<InUse>,
+}
+
+impl<B> InUseBody<B> {
+    pub fn new(inner: B, in_use: InUse) -> Self {
+        Self {
+            inner,
+            in_use: Some(in_use),
+        }
+    }
+}
+
+impl<B> Body for InUseBody<B>
+where
+    B: Body + Unpin,
+{
+    type Data = B::Data;
+    type Error = B::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let poll = Pin::new(&mut self.inner).poll_frame(cx);
+        // The connection goes back to the pool once the body is read, not when it is dropped
+        if let Poll::Ready(None | Some(Err(_))) = poll {
+            self.in_use = None;
+        }
+        poll
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http_body_util::{BodyExt, Full};
+    use hyper::body::Bytes;
+
+    fn counts(authority: &str) -> (i64, i64) {
+        let counts = Counts::get(authority);
+        (counts.idle(), counts.in_use.load(Ordering::Relaxed))
+    }
+
+    #[tokio::test]
+    async fn test_pool_stats() {
+        let authority = "pool-stats.test:80";
+
+        let first = OpenConnection::new(authority);
+        let second = OpenConnection::new(authority);
+        assert_eq!(counts(authority), (2, 0));
+
+        let body = InUseBody::new(
+            Full::new(Bytes::from_static(b"ok")),
+            InUse::new("us", authority),
+        );
+        assert_eq!(counts(authority), (1, 1));
+
+        // Reading the body to the end releases the connection
+        assert_eq!(body.collect().await.unwrap().to_bytes(), "ok");
+        assert_eq!(counts(authority), (2, 0));
+
+        drop(first);
+        let in_use = InUse::new("us", authority);
+        assert_eq!(counts(authority), (0, 1));
+        drop(second);
+        // Never below zero, with HTTP/2 more responses than connections can be in flight
+        assert_eq!(counts(authority), (0, 1));
+        drop(in_use);
+        assert_eq!(counts(authority), (0, 0));
+    }
+}
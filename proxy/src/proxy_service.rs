@@ -1,24 +1,52 @@
+use crate::anomalies::AnomalyEvents;
+use crate::backoff::UpstreamBackoff;
+use crate::capture::{Capture, CaptureBody, CapturedRequests, Side};
+use crate::client_ip::ClientIpResolver;
+use crate::client_protocol::{self, ClientProtocol};
 use crate::config;
+use crate::connector::{ConnectInfo, TimedConnector, http_connector};
+use crate::content_encoding::{self, DecodedBody};
 use crate::errors::ProxyError;
-use crate::metrics_defs::{REQUEST_DURATION, REQUESTS_INFLIGHT};
+use crate::feature_flags::{self, FlagProvider};
+use crate::force_upstream::{self, ForceUpstream, Forced};
+use crate::health_check::{self, UpstreamHealth};
+use crate::metrics_defs::{
+    FORCED_UPSTREAM, REQUEST_DURATION, REQUEST_TIMEOUTS, REQUESTS_INFLIGHT, UPSTREAM_RETRIES,
+    UPSTREAM_UNHEALTHY,
+};
+use crate::path_normalization::PathNormalizer;
+use crate::pool_stats::{InUse, InUseBody};
 use crate::resolvers::Resolvers;
+use crate::retry::RetryPolicy;
 use crate::route_actions::{RouteActions, RouteMatch};
-use crate::upstreams::Upstreams;
-use http_body_util::BodyExt;
+use crate::route_metrics::RoutePaths;
+use crate::route_tracing::UnmatchedRequests;
+use crate::trailers::{self, StripTrailers};
+use crate::upstream_client::send;
+use crate::upstream_errors;
+use crate::upstream_registry::UpstreamRegistry;
+use crate::upstreams::{Upstream, Upstreams};
+use crate::watchdog::{RequestTimings, SlowRequestWatchdog};
+use chrono::Utc;
+use http::Uri;
 use http_body_util::combinators::BoxBody;
-use hyper::body::Bytes;
+use http_body_util::{BodyExt, Empty, Full};
+use hyper::body::{Bytes, Incoming};
 use hyper::service::Service;
 use hyper::{Request, Response, StatusCode};
 use hyper_util::client::legacy::Client;
-use hyper_util::client::legacy::connect::HttpConnector;
+use hyper_util::client::legacy::connect::Connect;
 use hyper_util::rt::TokioExecutor;
 use locator::client::Locator;
-use shared::http::{add_via_header, filter_hop_by_hop, make_boxed_error_response};
+use shared::http::{RequestId, add_via_header, filter_hop_by_hop, make_boxed_error_response};
 use std::future::Future;
+use std::marker::PhantomData;
+use std::path::PathBuf;
 use std::pin::Pin;
 use std::sync::Arc;
 use std::sync::atomic::{AtomicU64, Ordering};
 use std::time::Instant;
+use tracing::Instrument;
 
 // Counter for 1% metric sampling.
 static REQUEST_COUNT: AtomicU64 = AtomicU64::new(0);
@@ -26,16 +54,35 @@ static REQUEST_COUNT: AtomicU64 = AtomicU64::new(0);
 // Gauge for number of requests currently being processed.
 static INFLIGHT: AtomicU64 = AtomicU64::new(0);
 
-pub struct ProxyService<B>
+/// Proxies requests to upstreams according to the configured routes.
+///
+/// Implements hyper's `Service`, so besides being run by the proxy's own listener it can be
+/// mounted into any hyper server. Construct it with [`ProxyService::builder`].
+pub struct ProxyService<B, C = TimedConnector>
 where
     B: BodyExt<Data = Bytes> + Send + Sync + 'static,
     B::Error: std::error::Error + Send + Sync + 'static,
     B: Unpin,
 {
-    client: Client<HttpConnector, B>,
-    pub route_actions: RouteActions,
+    client: Client<C, BoxBody<Bytes, ProxyError>>,
+    pub route_actions: Arc<RouteActions>,
     upstreams: Arc<Upstreams>,
+    upstream_health: Arc<UpstreamHealth>,
     resolvers: Resolvers,
+    slow_request_watchdog: Option<SlowRequestWatchdog>,
+    upstream_backoff: Option<UpstreamBackoff>,
+    unmatched_requests: Option<Arc<UnmatchedRequests>>,
+    captured_requests: Option<Arc<CapturedRequests>>,
+    route_paths: Arc<RoutePaths>,
+    anomaly_events: Option<AnomalyEvents>,
+    feature_flags: Option<Arc<dyn FlagProvider>>,
+    force_upstream: Option<ForceUpstream>,
+    path_normalizer: Option<PathNormalizer>,
+    client_ip_resolver: ClientIpResolver,
+    content_negotiation: bool,
+    client_protocol: Option<ClientProtocol>,
+    // Requests are sent upstream as `BoxBody`, whatever body type they are received with
+    _body: PhantomData<fn(B)>,
 }
 
 impl<B> ProxyService<B>
@@ -44,57 +91,575 @@ where
     B::Error: std::error::Error + Send + Sync + 'static,
     B: Unpin,
 {
-    pub fn try_new(
-        locator: Locator,
-        route_config: Vec<config::Route>,
-        upstream_config: Vec<config::UpstreamConfig>,
-    ) -> Result<Self, ProxyError> {
-        let conn = HttpConnector::new();
-        let client = Client::builder(TokioExecutor::new())
-            .http2_adaptive_window(true)
-            .build(conn);
+    /// Starts building a proxy service which resolves dynamic routes with `locator`.
+    pub fn builder(locator: Locator) -> ProxyServiceBuilder<B> {
+        ProxyServiceBuilder {
+            locator,
+            routes: Vec::new(),
+            fallback_routes: Vec::new(),
+            upstreams: Vec::new(),
+            client: default_client(),
+            slow_request_watchdog: None,
+            upstream_backoff: None,
+            route_tracing: None,
+            request_capture: None,
+            unmatched_requests: None,
+            captured_requests: None,
+            feature_flags: None,
+            force_upstream: None,
+            anomaly_events: None,
+            path_normalization: None,
+            trusted_proxies: Vec::new(),
+            content_negotiation: false,
+            client_protocol: None,
+            _body: PhantomData,
+        }
+    }
+}
+
+/// Builder for [`ProxyService`].
+///
+/// Routes and upstreams are matched in the order they are added, just like in the
+/// config file.
+pub struct ProxyServiceBuilder<B, C = TimedConnector> {
+    locator: Locator,
+    routes: Vec<config::Route>,
+    fallback_routes: Vec<config::Route>,
+    upstreams: Vec<config::UpstreamConfig>,
+    client: Client<C, BoxBody<Bytes, ProxyError>>,
+    slow_request_watchdog: Option<config::SlowRequestWatchdog>,
+    upstream_backoff: Option<config::UpstreamBackoff>,
+    route_tracing: Option<config::RouteTracing>,
+    request_capture: Option<config::RequestCapture>,
+    // Recordings of another service, used instead of `route_tracing` and `request_capture`
+    unmatched_requests: Option<Arc<UnmatchedRequests>>,
+    captured_requests: Option<Arc<CapturedRequests>>,
+    feature_flags: Option<Arc<dyn FlagProvider>>,
+    force_upstream: Option<config::ForceUpstream>,
+    anomaly_events: Option<config::AnomalyEvents>,
+    path_normalization: Option<config::PathNormalization>,
+    trusted_proxies: Vec<String>,
+    content_negotiation: bool,
+    client_protocol: Option<config::ClientProtocol>,
+    _body: PhantomData<fn(B)>,
+}
+
+impl<B, C> ProxyServiceBuilder<B, C>
+where
+    B: BodyExt<Data = Bytes> + Send + Sync + 'static,
+    B::Error: std::error::Error + Send + Sync + 'static,
+    B: Unpin,
+    C: Connect + Clone + Send + Sync + 'static,
+{
+    pub fn route(mut self, route: config::Route) -> Self {
+        self.routes.push(route);
+        self
+    }
 
-        let route_actions = RouteActions::try_new(route_config)?;
+    pub fn routes(mut self, routes: impl IntoIterator<Item = config::Route>) -> Self {
+        self.routes.extend(routes);
+        self
+    }
 
-        let upstreams = Arc::new(Upstreams::try_new(upstream_config)?);
+    /// Adds routes that are only tried if none of the other routes match, whatever their
+    /// priority, such as the main routes inherited by an additional listener.
+    pub fn fallback_routes(mut self, routes: impl IntoIterator<Item = config::Route>) -> Self {
+        self.fallback_routes.extend(routes);
+        self
+    }
 
-        let resolvers = Resolvers::try_new(locator)?;
+    pub fn upstream(mut self, upstream: config::UpstreamConfig) -> Self {
+        self.upstreams.push(upstream);
+        self
+    }
 
-        Ok(Self {
+    pub fn upstreams(
+        mut self,
+        upstreams: impl IntoIterator<Item = config::UpstreamConfig>,
+    ) -> Self {
+        self.upstreams.extend(upstreams);
+        self
+    }
+
+    /// Uses the given client for upstream requests instead of the default one.
+    /// The connect time is only reported by the slow request watchdog if the client
+    /// uses a `TimedConnector`.
+    pub fn client<C2>(
+        self,
+        client: Client<C2, BoxBody<Bytes, ProxyError>>,
+    ) -> ProxyServiceBuilder<B, C2> {
+        ProxyServiceBuilder {
+            locator: self.locator,
+            routes: self.routes,
+            fallback_routes: self.fallback_routes,
+            upstreams: self.upstreams,
             client,
+            slow_request_watchdog: self.slow_request_watchdog,
+            upstream_backoff: self.upstream_backoff,
+            route_tracing: self.route_tracing,
+            request_capture: self.request_capture,
+            unmatched_requests: self.unmatched_requests,
+            captured_requests: self.captured_requests,
+            feature_flags: self.feature_flags,
+            force_upstream: self.force_upstream,
+            anomaly_events: self.anomaly_events,
+            path_normalization: self.path_normalization,
+            trusted_proxies: self.trusted_proxies,
+            content_negotiation: self.content_negotiation,
+            client_protocol: self.client_protocol,
+            _body: PhantomData,
+        }
+    }
+
+    pub fn slow_request_watchdog(mut self, watchdog: config::SlowRequestWatchdog) -> Self {
+        self.slow_request_watchdog = Some(watchdog);
+        self
+    }
+
+    /// Answers requests locally while an upstream is backing off after a 429 or 503 with
+    /// `Retry-After`.
+    pub fn upstream_backoff(mut self, backoff: config::UpstreamBackoff) -> Self {
+        self.upstream_backoff = Some(backoff);
+        self
+    }
+
+    /// Records requests that match no route, so they can be inspected on the admin listener.
+    pub fn route_tracing(mut self, route_tracing: config::RouteTracing) -> Self {
+        self.route_tracing = Some(route_tracing);
+        self
+    }
+
+    /// Keeps the requests and responses sampled by routes with `capture` settings, so they
+    /// can be inspected on the admin listener.
+    pub fn request_capture(mut self, request_capture: config::RequestCapture) -> Self {
+        self.request_capture = Some(request_capture);
+        self
+    }
+
+    /// Records unmatched and captured requests into the recordings of `service`, such as
+    /// the main listener's, so that they can be inspected together on the admin listener.
+    pub(crate) fn recordings_of<C2>(mut self, service: &ProxyService<B, C2>) -> Self {
+        self.unmatched_requests = service.unmatched_requests.clone();
+        self.captured_requests = service.captured_requests.clone();
+        self
+    }
+
+    /// Provider used to evaluate the flags of gated routes.
+    pub fn feature_flags(mut self, provider: Arc<dyn FlagProvider>) -> Self {
+        self.feature_flags = Some(provider);
+        self
+    }
+
+    /// Lets requests with the admin token pick their upstream with `X-Synapse-Force-Upstream`.
+    pub fn force_upstream(mut self, force_upstream: config::ForceUpstream) -> Self {
+        self.force_upstream = Some(force_upstream);
+        self
+    }
+
+    /// Reports routing anomalies to Sentry: hosts sending many requests that match no
+    /// route, resolver failures and upstreams starting to back off.
+    pub fn anomaly_events(mut self, anomaly_events: config::AnomalyEvents) -> Self {
+        self.anomaly_events = Some(anomaly_events);
+        self
+    }
+
+    /// Advertises the encodings the proxy can decode to upstreams, and decodes responses
+    /// in an encoding the client does not accept.
+    pub fn content_negotiation(mut self, enabled: bool) -> Self {
+        self.content_negotiation = enabled;
+        self
+    }
+
+    /// Reports the protocol of client connections in logs and metrics, and optionally to
+    /// upstreams.
+    pub fn client_protocol(mut self, client_protocol: config::ClientProtocol) -> Self {
+        self.client_protocol = Some(client_protocol);
+        self
+    }
+
+    /// Normalizes request paths before they are matched against routes and forwarded.
+    pub fn path_normalization(mut self, path_normalization: config::PathNormalization) -> Self {
+        self.path_normalization = Some(path_normalization);
+        self
+    }
+
+    /// Networks of the proxies in front of this one, whose `X-Forwarded-For` is trusted when
+    /// resolving the client IP for the routes' `client_ips`.
+    pub fn trusted_proxies(mut self, trusted_proxies: impl IntoIterator<Item = String>) -> Self {
+        self.trusted_proxies.extend(trusted_proxies);
+        self
+    }
+
+    pub fn build(self) -> Result<ProxyService<B, C>, ProxyError> {
+        let ConfiguredParts {
             route_actions,
             upstreams,
+            health_checks,
+            client_ip_resolver,
+            force_upstream,
+        } = ConfiguredParts::try_new(
+            self.routes,
+            self.fallback_routes,
+            self.upstreams,
+            &self.trusted_proxies,
+            self.force_upstream,
+            self.feature_flags.is_some(),
+            self.request_capture.is_some() || self.captured_requests.is_some(),
+        )?;
+        let upstreams = Arc::new(upstreams);
+
+        let upstream_health = Arc::new(UpstreamHealth::new(
+            health_checks.iter().map(|(name, _)| name.clone()),
+        ));
+        for (name, health_check) in health_checks {
+            let Some(upstream) = upstreams.get(&name) else {
+                continue;
+            };
+            spawn_health_check(
+                &upstream_health,
+                &self.client,
+                name,
+                upstream,
+                &health_check,
+            )?;
+        }
+
+        let resolvers = Resolvers::try_new(self.locator)?;
+
+        Ok(ProxyService {
+            client: self.client,
+            route_actions: Arc::new(route_actions),
+            upstreams,
+            upstream_health,
             resolvers,
+            slow_request_watchdog: self.slow_request_watchdog.map(SlowRequestWatchdog::from),
+            upstream_backoff: self.upstream_backoff.map(UpstreamBackoff::from),
+            unmatched_requests: self.unmatched_requests.or_else(|| {
+                self.route_tracing
+                    .map(|config| Arc::new(UnmatchedRequests::from(config)))
+            }),
+            captured_requests: self.captured_requests.or_else(|| {
+                self.request_capture
+                    .map(|config| Arc::new(CapturedRequests::from(config)))
+            }),
+            route_paths: Arc::default(),
+            anomaly_events: self.anomaly_events.map(AnomalyEvents::from),
+            feature_flags: self.feature_flags,
+            force_upstream,
+            path_normalizer: self.path_normalization.map(PathNormalizer::from),
+            client_ip_resolver,
+            content_negotiation: self.content_negotiation,
+            client_protocol: self.client_protocol.map(ClientProtocol::from),
+            _body: PhantomData,
         })
     }
 }
 
-impl<B> Service<Request<B>> for ProxyService<B>
+impl<B, C> ProxyService<B, C>
+where
+    B: BodyExt<Data = Bytes> + Send + Sync + 'static,
+    B::Error: std::error::Error + Send + Sync + 'static,
+    B: Unpin,
+{
+    /// Requests that matched no route, if route tracing is enabled.
+    pub(crate) fn unmatched_requests(&self) -> Option<Arc<UnmatchedRequests>> {
+        self.unmatched_requests.clone()
+    }
+
+    /// Requests captured by routes, if request capture is enabled.
+    pub(crate) fn captured_requests(&self) -> Option<Arc<CapturedRequests>> {
+        self.captured_requests.clone()
+    }
+
+    /// Health of the upstreams with health checks.
+    pub(crate) fn upstream_health(&self) -> Arc<UpstreamHealth> {
+        self.upstream_health.clone()
+    }
+
+    /// Routes of the service, for the admin listener.
+    pub(crate) fn route_actions(&self) -> Arc<RouteActions> {
+        self.route_actions.clone()
+    }
+}
+
+impl<B, C> ProxyService<B, C>
 where
     B: BodyExt<Data = Bytes> + Send + Sync + 'static,
     B::Error: std::error::Error + Send + Sync + 'static,
     B: Unpin,
+    C: Connect + Clone + Send + Sync + 'static,
+{
+    /// Registry of the upstreams added to the service at runtime, see `upstream_registry`.
+    pub(crate) fn upstream_registry(&self, state_file: Option<PathBuf>) -> UpstreamRegistry {
+        let upstream_health = self.upstream_health.clone();
+        let client = self.client.clone();
+        UpstreamRegistry::new(
+            self.upstreams.clone(),
+            self.upstream_health.clone(),
+            Arc::new(move |name, upstream, health_check| {
+                spawn_health_check(&upstream_health, &client, name, upstream, health_check)
+            }),
+            state_file,
+        )
+    }
+}
+
+/// Probes the upstream in the background. Probes are sent like proxied requests, with the
+/// upstream's own client if it has one.
+fn spawn_health_check<C>(
+    upstream_health: &Arc<UpstreamHealth>,
+    client: &Client<C, BoxBody<Bytes, ProxyError>>,
+    name: String,
+    upstream: Upstream,
+    health_check: &config::HealthCheck,
+) -> Result<(), ProxyError>
+where
+    C: Connect + Clone + Send + Sync + 'static,
+{
+    let uri = Uri::builder()
+        .scheme(upstream.scheme.clone())
+        .authority(upstream.authority.clone())
+        .path_and_query(health_check.path.as_str())
+        .build()
+        .map_err(|e| ProxyError::HealthCheck(format!("{name}: {e}")))?;
+    let client = client.clone();
+    upstream_health.spawn(name, health_check, move || {
+        let mut request = Request::new(Empty::<Bytes>::new());
+        *request.uri_mut() = uri.clone();
+        let client = client.clone();
+        let upstream = upstream.clone();
+        async move {
+            send_upstream(&client, &upstream, request)
+                .await
+                .map(|response| response.status())
+        }
+    });
+    Ok(())
+}
+
+/// Parts of a service built from its config alone, without a locator or a client, so that
+/// configs are validated by the same checks as on startup, see `crate::validate`.
+pub(crate) struct ConfiguredParts {
+    route_actions: RouteActions,
+    upstreams: Upstreams,
+    health_checks: Vec<(String, config::HealthCheck)>,
+    client_ip_resolver: ClientIpResolver,
+    force_upstream: Option<ForceUpstream>,
+}
+
+impl ConfiguredParts {
+    pub(crate) fn try_new(
+        routes: Vec<config::Route>,
+        fallback_routes: Vec<config::Route>,
+        upstreams: Vec<config::UpstreamConfig>,
+        trusted_proxies: &[String],
+        force_upstream: Option<config::ForceUpstream>,
+        feature_flags: bool,
+        request_capture: bool,
+    ) -> Result<Self, ProxyError> {
+        check_route_options(
+            routes.iter().chain(&fallback_routes),
+            feature_flags,
+            request_capture,
+        )?;
+
+        let route_actions = RouteActions::try_new_layered(vec![routes, fallback_routes])?;
+
+        let health_checks: Vec<_> = upstreams
+            .iter()
+            .filter_map(|upstream| Some((upstream.name.clone(), upstream.health_check.clone()?)))
+            .collect();
+        for (name, health_check) in &health_checks {
+            health_check::validate(name, health_check)?;
+        }
+
+        Ok(Self {
+            route_actions,
+            upstreams: Upstreams::try_new(upstreams)?,
+            health_checks,
+            client_ip_resolver: ClientIpResolver::try_new(trusted_proxies)?,
+            force_upstream: force_upstream.map(ForceUpstream::try_from).transpose()?,
+        })
+    }
+}
+
+/// Checks that the routes only use the options that are configured for the proxy.
+fn check_route_options<'a>(
+    routes: impl IntoIterator<Item = &'a config::Route>,
+    feature_flags: bool,
+    request_capture: bool,
+) -> Result<(), ProxyError> {
+    for route in routes {
+        if !feature_flags && route.r#match.flag.is_some() {
+            return Err(ProxyError::InvalidRoute(format!(
+                "Route is gated by a feature flag but no feature flag provider is configured: {:?}",
+                route.r#match
+            )));
+        }
+        if !request_capture && route.capture.is_some() {
+            return Err(ProxyError::InvalidRoute(format!(
+                "Route captures requests but request capture is not configured: {:?}",
+                route.r#match
+            )));
+        }
+    }
+    Ok(())
+}
+
+fn default_client() -> Client<TimedConnector, BoxBody<Bytes, ProxyError>> {
+    let conn = TimedConnector::new(http_connector());
+    Client::builder(TokioExecutor::new())
+        .http2_adaptive_window(true)
+        .build(conn)
+}
+
+impl<B, C> Service<Request<B>> for ProxyService<B, C>
+where
+    B: BodyExt<Data = Bytes> + Send + Sync + 'static,
+    B::Error: std::error::Error + Send + Sync + 'static,
+    B: Unpin,
+    C: Connect + Clone + Send + Sync + 'static,
 {
     type Response = Response<BoxBody<Bytes, ProxyError>>;
     type Error = ProxyError;
     type Future =
         Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send + 'static>>;
 
-    fn call(&self, request: Request<B>) -> Self::Future {
+    fn call(&self, mut request: Request<B>) -> Self::Future {
         let start = Instant::now();
         INFLIGHT.fetch_add(1, Ordering::Relaxed);
+        // Record request metrics (1% sample)
+        let sampled = REQUEST_COUNT
+            .fetch_add(1, Ordering::Relaxed)
+            .is_multiple_of(100);
+
+        let request_id = RequestId::ensure(request.headers_mut());
+        let span = request_id.span();
+        let protocol = match &self.client_protocol {
+            Some(client_protocol) => Some(client_protocol.observe(&mut request)),
+            None => {
+                client_protocol::remove_headers(request.headers_mut());
+                None
+            }
+        };
+        let protocol_span = protocol.as_ref().map(|protocol| protocol.span(&span));
+
+        if let Some(path_normalizer) = &self.path_normalizer {
+            path_normalizer.apply(&mut request);
+        }
+
+        let forced = match &self.force_upstream {
+            Some(force_upstream) => force_upstream.take(request.headers_mut()),
+            None => {
+                force_upstream::remove_headers(request.headers_mut());
+                None
+            }
+        };
+        if let Some(forced) = &forced {
+            let (upstream, authorized) = match forced {
+                Forced::Upstream(upstream) => (upstream.clone(), "true"),
+                Forced::Unauthorized => ("none".to_string(), "false"),
+            };
+            tracing::info!(upstream, authorized, "Request with forced upstream");
+            metrics::counter!(
+                FORCED_UPSTREAM.name,
+                "upstream" => upstream,
+                "authorized" => authorized,
+            )
+            .increment(1);
+        }
 
-        let route = self.route_actions.resolve(&request);
+        // Forced requests skip route resolution
+        let route_matches = match forced {
+            Some(_) => Vec::new(),
+            None => self.route_actions.resolve(&request),
+        };
 
-        tracing::debug!("Resolved route: {route:?}");
+        let client_ip = self.client_ip_resolver.resolve(&request);
 
+        let feature_flags = self.feature_flags.clone();
         let upstreams = self.upstreams.clone();
+        let upstream_health = self.upstream_health.clone();
         let resolvers = self.resolvers.clone();
         let client = self.client.clone();
+        let slow_request_watchdog = self.slow_request_watchdog.clone();
+        let upstream_backoff = self.upstream_backoff.clone();
+        let unmatched_requests = self.unmatched_requests.clone();
+        let captured_requests = self.captured_requests.clone();
+        let route_paths = self.route_paths.clone();
+        let anomaly_events = self.anomaly_events.clone();
+        let content_negotiation = self.content_negotiation;
+        let mut timings = RequestTimings::new(start);
+
+        // Only needed to describe slow requests
+        let request_info = slow_request_watchdog
+            .as_ref()
+            .map(|_| (request.method().clone(), request.uri().path().to_string()));
+        let sampled_path = sampled.then(|| request.uri().path().to_string());
+
+        let future = async move {
+            let route = feature_flags::first_enabled(route_matches, feature_flags.as_deref()).await;
+
+            tracing::debug!("Resolved route: {route:?}");
+
+            if route.is_none() && forced.is_none() {
+                if let Some(unmatched_requests) = &unmatched_requests {
+                    unmatched_requests.record(&request);
+                }
+                if let Some(anomaly_events) = &anomaly_events {
+                    // Same as route resolution, the host is taken from the URI or the Host header
+                    let host = request.uri().host().or_else(|| {
+                        request
+                            .headers()
+                            .get(http::header::HOST)
+                            .and_then(|h| h.to_str().ok())
+                    });
+                    anomaly_events.unmatched(host);
+                }
+            }
 
-        Box::pin(async move {
-            let upstream_name: Option<String> = match route {
-                Some(RouteMatch { action, params }) => match action {
+            let pattern = route.as_ref().map(|route| route.pattern.clone());
+            let capture = route
+                .as_ref()
+                .and_then(|route| Some((route, route.capture.as_ref()?)))
+                .zip(captured_requests.as_ref())
+                .filter(|((_, sampler), _)| sampler.sample(Utc::now()))
+                .map(|((route, sampler), captured_requests)| {
+                    captured_requests.start(&request, &route.pattern, sampler)
+                });
+            if let (Some(pattern), Some(path)) = (&pattern, &sampled_path) {
+                route_paths.record(pattern, path);
+            }
+            if let Some(protocol) = &protocol {
+                protocol.count(pattern.as_deref());
+            }
+
+            let header_filter = route.as_ref().map(|route| route.header_filter.clone());
+            let timeout = route.as_ref().and_then(|route| route.timeout);
+            let retry = route.as_ref().and_then(|route| route.retry.clone());
+            let strip_trailers = route.as_ref().is_some_and(|route| route.strip_trailers);
+            let header_rewriter = route
+                .as_ref()
+                .and_then(|route| route.header_rewriter.clone());
+
+            let ip_denied = route
+                .as_ref()
+                .and_then(|route| route.ip_filter.as_ref())
+                .is_some_and(|ip_filter| !ip_filter.allows(client_ip));
+            if ip_denied {
+                tracing::info!(?client_ip, "Client IP denied by route");
+            }
+            // Methods the route does not forward are answered locally
+            let method_response = route
+                .as_ref()
+                .and_then(|route| route.methods.as_ref())
+                .and_then(|methods| methods.local_response(request.method()));
+
+            let upstream_name: Option<String> = match (forced.as_ref(), route) {
+                _ if ip_denied || method_response.is_some() => None,
+                (Some(Forced::Upstream(upstream)), _) => Some(upstream.clone()),
+                (Some(Forced::Unauthorized), _) => None,
+                (None, Some(RouteMatch { action, params, .. })) => match action {
                     config::Action::Static { to } => Some(to),
                     config::Action::Dynamic {
                         resolver,
@@ -102,21 +667,60 @@ where
                         default,
                         ..
                     } => resolvers
-                        .resolve(&resolver, &cell_to_upstream, params)
+                        .resolve(
+                            &resolver,
+                            &cell_to_upstream,
+                            params,
+                            // A quarter of the budget, the rest is left for the upstream
+                            timeout.map(|timeout| start + timeout / 4),
+                        )
                         .await
+                        .inspect_err(|error| {
+                            if let Some(anomaly_events) = &anomaly_events {
+                                anomaly_events.resolver_failed(&resolver, error);
+                            }
+                        })
                         .ok()
                         .map(|s| s.to_string())
+                        .filter(|upstream| upstream_health.is_healthy(upstream))
                         .or(default),
                 },
-                None => None,
+                (None, None) => None,
             };
 
             let upstream = upstream_name.as_deref().and_then(|u| upstreams.get(u));
+            timings.resolved(Instant::now());
 
             tracing::debug!("Resolved upstream: {:?}", upstream);
 
-            let response = match upstream {
-                Some(u) => {
+            // Answered locally while the upstream is down, unless it was forced
+            let unhealthy = forced.is_none()
+                && upstream_name
+                    .as_deref()
+                    .is_some_and(|name| !upstream_health.is_healthy(name));
+
+            // Answered locally while the upstream is backing off
+            let local_response = method_response.or_else(|| {
+                upstream_name
+                    .as_deref()
+                    .zip(upstream_backoff.as_ref())
+                    .and_then(|(name, backoff)| backoff.check(name))
+            });
+
+            let response = match (upstream, local_response) {
+                _ if forced == Some(Forced::Unauthorized) || ip_denied => {
+                    make_boxed_error_response(StatusCode::FORBIDDEN)
+                }
+                _ if unhealthy => {
+                    metrics::counter!(
+                        UPSTREAM_UNHEALTHY.name,
+                        "upstream" => upstream_name.clone().unwrap_or_default(),
+                    )
+                    .increment(1);
+                    make_boxed_error_response(StatusCode::SERVICE_UNAVAILABLE)
+                }
+                (_, Some(response)) => response,
+                (Some(u), None) => {
                     // Build target URI: keep path+query, swap scheme+authority to upstream_base
                     let (mut parts, body) = request.into_parts();
 
@@ -135,26 +739,161 @@ where
 
                                 // Filter hop-by-hop headers and add via header to request
                                 let request_version = parts.version;
+                                let accepts_trailers =
+                                    !strip_trailers && trailers::accepts_trailers(&parts.headers);
                                 filter_hop_by_hop(&mut parts.headers, request_version);
+                                if accepts_trailers {
+                                    trailers::restore_te(&mut parts.headers);
+                                }
+                                if let Some(header_rewriter) = &header_rewriter {
+                                    header_rewriter.apply_request(&mut parts.headers);
+                                }
                                 add_via_header(&mut parts.headers, request_version);
+                                // Bodies of HEAD responses are never sent
+                                let accepted_encodings = (content_negotiation
+                                    && parts.method != http::Method::HEAD)
+                                    .then(|| content_encoding::negotiate(&mut parts.headers));
+
+                                let retry = retry.filter(|retry| {
+                                    retry.applies(&parts.method, &body.size_hint())
+                                });
 
-                                let outbound_request = Request::from_parts(parts, body);
+                                let in_use = InUse::new(
+                                    upstream_name.as_deref().unwrap_or_default(),
+                                    u.authority.as_str(),
+                                );
+                                let sending = async {
+                                    match retry {
+                                        Some(retry) => {
+                                            send_with_retries(
+                                                &client,
+                                                &u,
+                                                upstream_name.as_deref().unwrap_or_default(),
+                                                &retry,
+                                                parts,
+                                                body,
+                                                capture.clone(),
+                                            )
+                                            .await
+                                        }
+                                        None => {
+                                            let body = CaptureBody::new(
+                                                body,
+                                                capture.clone(),
+                                                Side::Request,
+                                            );
+                                            send_upstream(
+                                                &client,
+                                                &u,
+                                                Request::from_parts(parts, body),
+                                            )
+                                            .await
+                                        }
+                                    }
+                                };
+                                let result = match timeout {
+                                    Some(timeout) => {
+                                        tokio::time::timeout_at((start + timeout).into(), sending)
+                                            .await
+                                            .unwrap_or(Err(ProxyError::UpstreamTimeout))
+                                    }
+                                    None => sending.await,
+                                };
 
-                                match client.request(outbound_request).await {
+                                match result {
                                     Ok(mut response) => {
+                                        timings.headers_received(Instant::now());
+                                        if let (Some(backoff), Some(name)) =
+                                            (&upstream_backoff, &upstream_name)
+                                        {
+                                            let started = backoff.observe(
+                                                name,
+                                                response.status(),
+                                                response.headers(),
+                                            );
+                                            if let (Some(delay), Some(anomaly_events)) =
+                                                (started, &anomaly_events)
+                                            {
+                                                anomaly_events.backoff_started(
+                                                    name,
+                                                    response.status(),
+                                                    delay,
+                                                );
+                                            }
+                                        }
+                                        // Pooled connections were established before this
+                                        // request was resolved and cost nothing to connect
+                                        if let Some(info) = response
+                                            .extensions()
+                                            .get::<ConnectInfo>()
+                                            .filter(|info| info.established_at >= start)
+                                        {
+                                            timings.connected(info.connect_duration);
+                                        }
+
                                         // Filter hop-by-hop and add via to response from upstream
                                         let version = response.version();
+                                        let announced = trailers::announced(response.headers());
                                         filter_hop_by_hop(response.headers_mut(), version);
+                                        if !strip_trailers {
+                                            trailers::restore_announced(
+                                                response.headers_mut(),
+                                                announced,
+                                            );
+                                        }
+                                        if let Some(header_filter) = &header_filter {
+                                            header_filter.apply(response.headers_mut());
+                                        }
+                                        if let Some(header_rewriter) = &header_rewriter {
+                                            header_rewriter.apply_response(response.headers_mut());
+                                        }
                                         add_via_header(response.headers_mut(), version);
 
                                         // Convert the response body to BoxBody
-                                        let (parts, body) = response.into_parts();
-                                        let boxed_body = body.map_err(Into::into).boxed();
+                                        let (mut parts, body) = response.into_parts();
+                                        let decoder =
+                                            accepted_encodings.as_ref().and_then(|accepted| {
+                                                content_encoding::decoder_for(
+                                                    parts.status,
+                                                    &mut parts.headers,
+                                                    accepted,
+                                                )
+                                            });
+                                        let body = InUseBody::new(body, in_use);
+                                        let boxed_body = if strip_trailers {
+                                            StripTrailers(body).map_err(Into::into).boxed()
+                                        } else {
+                                            body.map_err(Into::into).boxed()
+                                        };
+                                        let boxed_body = match decoder {
+                                            Some(decoder) => {
+                                                DecodedBody::new(boxed_body, decoder).boxed()
+                                            }
+                                            None => boxed_body,
+                                        };
                                         Response::from_parts(parts, boxed_body)
                                     }
+                                    Err(ProxyError::UpstreamTimeout) => {
+                                        tracing::warn!(
+                                            upstream = upstream_name.as_deref(),
+                                            "Upstream request exceeded the route's time budget"
+                                        );
+                                        metrics::counter!(
+                                            REQUEST_TIMEOUTS.name,
+                                            "upstream" => upstream_name.clone().unwrap_or_default(),
+                                        )
+                                        .increment(1);
+                                        make_boxed_error_response(StatusCode::GATEWAY_TIMEOUT)
+                                    }
+                                    Err(ProxyError::PayloadTooLarge(limit)) => {
+                                        tracing::warn!(
+                                            upstream = upstream_name.as_deref(),
+                                            "Request body exceeds the {limit} bytes buffered for the upstream"
+                                        );
+                                        make_boxed_error_response(StatusCode::PAYLOAD_TOO_LARGE)
+                                    }
                                     Err(e) => {
-                                        tracing::error!("Upstream request failed: {e}");
-                                        make_boxed_error_response(StatusCode::BAD_GATEWAY)
+                                        upstream_errors::error_response(&e, upstream_name.as_deref())
                                     }
                                 }
                             }
@@ -168,21 +907,39 @@ where
                         make_boxed_error_response(StatusCode::BAD_REQUEST)
                     }
                 }
-                None => {
+                (None, None) => {
                     // No upstream found, return 404
                     make_boxed_error_response(StatusCode::NOT_FOUND)
                 }
             };
 
-            // Record request metric (1% sample)
-            if REQUEST_COUNT
-                .fetch_add(1, Ordering::Relaxed)
-                .is_multiple_of(100)
-            {
+            let response = match (&slow_request_watchdog, request_info) {
+                (Some(watchdog), Some((method, path))) => watchdog.watch(
+                    response,
+                    timings,
+                    method,
+                    path,
+                    upstream_name.as_deref().unwrap_or("none"),
+                ),
+                _ => response,
+            };
+
+            let mut response = match capture {
+                Some(capture) => {
+                    capture.response(&response);
+                    response
+                        .map(|body| CaptureBody::new(body, Some(capture), Side::Response).boxed())
+                }
+                None => response,
+            };
+            request_id.set_header(response.headers_mut());
+
+            if sampled {
                 metrics::histogram!(
                     REQUEST_DURATION.name,
                     "status" => response.status().as_u16().to_string(),
                     "upstream" => upstream_name.unwrap_or_else(|| "none".to_string()),
+                    "route" => pattern.map_or_else(|| "none".to_string(), |p| p.to_string()),
                 )
                 .record(start.elapsed().as_secs_f64());
 
@@ -193,55 +950,119 @@ where
             INFLIGHT.fetch_sub(1, Ordering::Relaxed);
 
             Ok(response)
-        })
+        }
+        .instrument(protocol_span.unwrap_or_else(tracing::Span::none))
+        .instrument(span);
+        Box::pin(future)
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use http_body_util::Full;
-    use std::process::{Child, Command};
-    use std::time::Duration;
-
-    struct TestServer {
-        child: Child,
+/// Sends the request with the upstream's options, or with the shared client.
+async fn send_upstream<C, B>(
+    client: &Client<C, BoxBody<Bytes, ProxyError>>,
+    upstream: &Upstream,
+    request: Request<B>,
+) -> Result<Response<Incoming>, ProxyError>
+where
+    C: Connect + Clone + Send + Sync + 'static,
+    B: BodyExt<Data = Bytes> + Send + Sync + Unpin + 'static,
+    B::Error: std::error::Error + Send + Sync + 'static,
+{
+    match &upstream.client {
+        Some(upstream_client) => {
+            upstream_client
+                .request(client, request, upstream.max_lifetime)
+                .await
+        }
+        None => {
+            let request = request.map(|body| {
+                body.map_err(|e| ProxyError::RequestBody(e.to_string()))
+                    .boxed()
+            });
+            send(client, request, upstream.max_lifetime)
+                .await
+                .map_err(ProxyError::from)
+        }
     }
+}
 
-    impl TestServer {
-        fn spawn() -> std::io::Result<Self> {
-            let child = Command::new("python")
-                .arg("../scripts/echo_server.py")
-                .spawn()?;
+/// Buffers the request body, then sends the request until it succeeds or the retry policy
+/// gives up. Only the first attempt is captured.
+async fn send_with_retries<C, B>(
+    client: &Client<C, BoxBody<Bytes, ProxyError>>,
+    upstream: &Upstream,
+    upstream_name: &str,
+    retry: &RetryPolicy,
+    parts: http::request::Parts,
+    body: B,
+    mut capture: Option<Arc<Capture>>,
+) -> Result<Response<Incoming>, ProxyError>
+where
+    C: Connect + Clone + Send + Sync + 'static,
+    B: BodyExt<Data = Bytes> + Send + Sync + Unpin + 'static,
+    B::Error: std::error::Error + Send + Sync + 'static,
+{
+    let body = body
+        .collect()
+        .await
+        .map_err(|e| ProxyError::RequestBody(e.to_string()))?
+        .to_bytes();
 
-            Ok(Self { child })
-        }
-    }
+    let mut attempt = 1;
+    loop {
+        let body = CaptureBody::new(Full::new(body.clone()), capture.take(), Side::Request);
+        // Every attempt has the extensions of the original request as well
+        let request = Request::from_parts(parts.clone(), body);
 
-    impl Drop for TestServer {
-        fn drop(&mut self) {
-            let _ = self.child.kill();
-            let _ = self.child.wait();
-        }
+        let result = send_upstream(client, upstream, request).await;
+        let reason = match retry.retry_reason(&result) {
+            Some(reason) if attempt < retry.max_attempts => reason,
+            _ => return result,
+        };
+        tracing::debug!(
+            upstream = upstream_name,
+            attempt,
+            reason,
+            "Retrying upstream request"
+        );
+        metrics::counter!(
+            UPSTREAM_RETRIES.name,
+            "upstream" => upstream_name.to_string(),
+            "reason" => reason,
+        )
+        .increment(1);
+        tokio::time::sleep(retry.backoff(attempt)).await;
+        attempt += 1;
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::feature_flags::FileFlagProvider;
+    use crate::testutils::{Echoed, MockServer, MockUpstream, locator_client};
+    use http_body_util::Full;
+    use hyper_util::client::legacy::connect::HttpConnector;
+    use shared::http::PeerAddr;
+    use std::collections::HashMap;
+    use std::sync::atomic::AtomicUsize;
+    use std::time::Duration;
 
     #[tokio::test]
     async fn test_proxy_service() {
-        // Start the test echo server
-        let _server = TestServer::spawn().expect("Failed to spawn test server");
-
-        // Wait for the server to start
-        std::thread::sleep(Duration::from_millis(500));
+        let upstream = MockServer::echo("upstream").await;
 
         let config = config::Config {
             upstreams: vec![
-                config::UpstreamConfig {
-                    name: "upstream".to_string(),
-                    url: "http://127.0.0.1:8100".to_string(),
-                },
+                upstream.upstream("upstream"),
                 config::UpstreamConfig {
                     name: "invalid_upstream".to_string(),
                     url: "http://256.256.256.256:8100".to_string(),
+                    http1: None,
+                    pool: None,
+                    egress: None,
+                    tls: None,
+                    health_check: None,
                 },
             ],
             routes: vec![
@@ -249,41 +1070,92 @@ mod tests {
                     r#match: config::Match {
                         host: None,
                         path: Some("test".to_string()),
+                        flag: None,
+                        active: None,
+                        headers: vec![],
                     },
                     action: config::Action::Static {
                         to: "upstream".to_string(),
                     },
+                    response_headers: None,
+                    client_ips: None,
+                    strip_trailers: false,
+                    headers: None,
+                    capture: None,
+                    timeout_ms: None,
+                    retries: None,
+                    methods: None,
+                    priority: 0,
                 },
                 config::Route {
                     r#match: config::Match {
                         host: None,
                         path: None,
+                        flag: None,
+                        active: None,
+                        headers: vec![],
                     },
                     action: config::Action::Static {
                         to: "invalid_upstream".to_string(),
                     },
+                    response_headers: None,
+                    client_ips: None,
+                    strip_trailers: false,
+                    headers: None,
+                    capture: None,
+                    timeout_ms: None,
+                    retries: None,
+                    methods: None,
+                    priority: 0,
                 },
             ],
             listener: config::Listener {
                 host: "127.0.0.1".to_string(),
                 port: 8080,
+                path_normalization: None,
+                trusted_proxies: Vec::new(),
+                tls: None,
             },
             admin_listener: config::AdminListener {
                 host: "127.0.0.1".to_string(),
                 port: 8081,
+                tls: None,
+                auth: None,
             },
             locator: config::Locator {
                 r#type: config::LocatorType::Url {
                     url: "something".to_string(),
+                    api_key: None,
+                    datagram_addr: None,
+                    cache: None,
                 },
             },
+            slow_request_watchdog: None,
+            feature_flags: None,
+            upstream_backoff: None,
+            route_tracing: None,
+            request_capture: None,
+            force_upstream: Some(config::ForceUpstream {
+                token: "secret".to_string(),
+            }),
+            anomaly_events: None,
+            content_negotiation: false,
+            client_protocol: None,
+            hot_upgrade: None,
+            upstream_registration: None,
+            drain_timeout_secs: 30,
+            additional_listeners: Vec::new(),
         };
 
         let locator = Locator::new(config.locator.to_client_config())
             .await
             .unwrap();
 
-        let service = ProxyService::try_new(locator, config.routes, config.upstreams)
+        let service = ProxyService::builder(locator)
+            .routes(config.routes)
+            .upstreams(config.upstreams)
+            .force_upstream(config.force_upstream.unwrap())
+            .build()
             .expect("Failed to create proxy service");
 
         let content = b"hello world\n";
@@ -297,11 +1169,13 @@ mod tests {
             .unwrap();
         let response = service.call(request).await.expect("Request failed");
         assert_eq!(response.status(), StatusCode::OK);
-        assert_eq!(response.headers().get("x-custom").unwrap(), "test");
-        assert_eq!(response.headers().get("host").unwrap(), "127.0.0.1:8100");
-        tracing::debug!("response headers: {:?}", response.headers());
-        let body_bytes = response.into_body().collect().await.unwrap().to_bytes();
-        assert_eq!(body_bytes.as_ref(), content);
+        let echoed = Echoed::from_response(response).await;
+        assert_eq!(echoed.headers["x-custom"], "test");
+        assert_eq!(
+            echoed.headers["host"],
+            upstream.url().trim_start_matches("http://")
+        );
+        assert_eq!(echoed.body.as_bytes(), content);
 
         // Invalid request (no upstream)
         let request = Request::builder()
@@ -312,5 +1186,999 @@ mod tests {
             .unwrap();
         let response = service.call(request).await.expect("Request failed");
         assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+
+        // Forced upstream, the override headers are not forwarded
+        let forced_request = |token: &'static str| {
+            Request::builder()
+                .uri("http://example.com/invalid")
+                .header("x-synapse-force-upstream", "upstream")
+                .header("x-synapse-admin-token", token)
+                .body(Full::new(Bytes::from_static(content)))
+                .unwrap()
+        };
+        let response = service
+            .call(forced_request("secret"))
+            .await
+            .expect("Request failed");
+        assert_eq!(response.status(), StatusCode::OK);
+        let echoed = Echoed::from_response(response).await;
+        assert!(!echoed.headers.contains_key("x-synapse-force-upstream"));
+        assert!(!echoed.headers.contains_key("x-synapse-admin-token"));
+
+        // Wrong token
+        let response = service
+            .call(forced_request("wrong"))
+            .await
+            .expect("Request failed");
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn test_builder() {
+        let locator = Locator::new(
+            config::Locator {
+                r#type: config::LocatorType::Url {
+                    url: "something".to_string(),
+                    api_key: None,
+                    datagram_addr: None,
+                    cache: None,
+                },
+            }
+            .to_client_config(),
+        )
+        .await
+        .unwrap();
+
+        let gated_route = config::Route {
+            r#match: config::Match {
+                host: None,
+                path: None,
+                flag: Some("cellular_proxy_enabled".into()),
+                active: None,
+                headers: vec![],
+            },
+            action: config::Action::Static {
+                to: "upstream".into(),
+            },
+            response_headers: None,
+            client_ips: None,
+            strip_trailers: false,
+            headers: None,
+            capture: None,
+            timeout_ms: None,
+            retries: None,
+            methods: None,
+            priority: 0,
+        };
+        let upstream = config::UpstreamConfig {
+            name: "upstream".into(),
+            url: "http://127.0.0.1:8100".into(),
+            http1: None,
+            pool: None,
+            egress: None,
+            tls: None,
+            health_check: None,
+        };
+
+        // Gated routes require a flag provider
+        let result = ProxyService::<Full<Bytes>>::builder(locator.clone())
+            .route(gated_route.clone())
+            .upstream(upstream.clone())
+            .build();
+        assert!(matches!(result, Err(ProxyError::InvalidRoute(_))));
+
+        // Custom client and flag provider
+        let client = Client::builder(TokioExecutor::new()).build(HttpConnector::new());
+        let flags = FileFlagProvider::from_yaml("cellular_proxy_enabled: {}").unwrap();
+        let service = ProxyService::builder(locator.clone())
+            .route(gated_route)
+            .upstream(upstream)
+            .client(client)
+            .feature_flags(Arc::new(flags))
+            .route_tracing(config::RouteTracing { max_requests: 10 })
+            .build()
+            .unwrap();
+
+        // The flag is disabled and there is no other route
+        let request = Request::builder()
+            .uri("http://example.com/test")
+            .body(Full::new(Bytes::new()))
+            .unwrap();
+        let response = service.call(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        // The unmatched request was recorded
+        let recorded = service.unmatched_requests().unwrap().recorded();
+        assert_eq!(recorded.len(), 1);
+        assert_eq!(recorded[0].path, "/test");
+
+        // Services of other listeners record into the same requests
+        let other = ProxyService::<Full<Bytes>>::builder(locator)
+            .recordings_of(&service)
+            .build()
+            .unwrap();
+        let request = Request::builder()
+            .uri("http://example.com/other")
+            .body(Full::new(Bytes::new()))
+            .unwrap();
+        let response = other.call(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let recorded = service.unmatched_requests().unwrap().recorded();
+        assert_eq!(recorded.len(), 2);
+        assert_eq!(recorded[1].path, "/other");
+    }
+
+    #[tokio::test]
+    async fn test_client_ips() {
+        let locator = Locator::new(
+            config::Locator {
+                r#type: config::LocatorType::Url {
+                    url: "something".to_string(),
+                    api_key: None,
+                    datagram_addr: None,
+                    cache: None,
+                },
+            }
+            .to_client_config(),
+        )
+        .await
+        .unwrap();
+
+        // Nothing listens on the upstream, so allowed requests fail with 502
+        let service = ProxyService::<Full<Bytes>>::builder(locator)
+            .route(config::Route {
+                r#match: config::Match {
+                    host: None,
+                    path: Some("admin".into()),
+                    flag: None,
+                    active: None,
+                    headers: vec![],
+                },
+                action: config::Action::Static {
+                    to: "upstream".into(),
+                },
+                response_headers: None,
+                client_ips: Some(config::ClientIps::Allow {
+                    allow: vec!["10.0.0.0/8".into()],
+                }),
+                strip_trailers: false,
+                headers: None,
+                capture: None,
+                timeout_ms: None,
+                retries: None,
+                methods: None,
+                priority: 0,
+            })
+            .upstream(config::UpstreamConfig {
+                name: "upstream".into(),
+                url: "http://127.0.0.1:1".into(),
+                http1: None,
+                pool: None,
+                egress: None,
+                tls: None,
+                health_check: None,
+            })
+            .trusted_proxies(["192.168.0.1".to_string()])
+            .build()
+            .unwrap();
+
+        let request = |peer_addr: Option<&str>, forwarded_for: Option<&'static str>| {
+            let mut builder = Request::builder().uri("http://example.com/admin");
+            if let Some(forwarded_for) = forwarded_for {
+                builder = builder.header("x-forwarded-for", forwarded_for);
+            }
+            let mut request = builder.body(Full::new(Bytes::new())).unwrap();
+            if let Some(peer_addr) = peer_addr {
+                request
+                    .extensions_mut()
+                    .insert(PeerAddr(peer_addr.parse().unwrap()));
+            }
+            request
+        };
+
+        let cases = [
+            (Some("10.0.0.1:1234"), None, StatusCode::BAD_GATEWAY),
+            (Some("1.1.1.1:1234"), None, StatusCode::FORBIDDEN),
+            // Forwarded by a trusted proxy
+            (
+                Some("192.168.0.1:1234"),
+                Some("10.0.0.1"),
+                StatusCode::BAD_GATEWAY,
+            ),
+            (
+                Some("192.168.0.1:1234"),
+                Some("1.1.1.1"),
+                StatusCode::FORBIDDEN,
+            ),
+            // Not forwarded by a trusted proxy
+            (
+                Some("1.1.1.1:1234"),
+                Some("10.0.0.1"),
+                StatusCode::FORBIDDEN,
+            ),
+            // Unknown client IPs are not on the allow list
+            (None, None, StatusCode::FORBIDDEN),
+        ];
+        for (peer_addr, forwarded_for, expected) in cases {
+            let response = service
+                .call(request(peer_addr, forwarded_for))
+                .await
+                .unwrap();
+            assert_eq!(
+                response.status(),
+                expected,
+                "{peer_addr:?} {forwarded_for:?}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_trailers() {
+        use crate::testutils::read_head;
+        use tokio::io::AsyncWriteExt;
+
+        // Answers with a chunked body that has a chunk extension and a trailer, and reports
+        // whether the request accepted trailers
+        let upstream = MockServer::spawn_tcp(|mut stream, _| async move {
+            let Some(head) = read_head(&mut stream).await else {
+                return;
+            };
+            let te = head.contains("te: trailers\r\n");
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nconnection: close\r\ntransfer-encoding: chunked\r\n\
+                 trailer: grpc-status\r\nx-te: {te}\r\n\r\n\
+                 2;ext=1\r\nok\r\n0\r\ngrpc-status: 0\r\n\r\n"
+            );
+            stream.write_all(response.as_bytes()).await.unwrap();
+        })
+        .await;
+        let port = upstream.port();
+
+        let locator = Locator::new(
+            config::Locator {
+                r#type: config::LocatorType::Url {
+                    url: "something".to_string(),
+                    api_key: None,
+                    datagram_addr: None,
+                    cache: None,
+                },
+            }
+            .to_client_config(),
+        )
+        .await
+        .unwrap();
+
+        let route = |path: &str, strip_trailers| config::Route {
+            r#match: config::Match {
+                host: None,
+                path: Some(path.into()),
+                flag: None,
+                active: None,
+                headers: vec![],
+            },
+            action: config::Action::Static {
+                to: "upstream".into(),
+            },
+            response_headers: None,
+            client_ips: None,
+            strip_trailers,
+            headers: None,
+            capture: None,
+            timeout_ms: None,
+            retries: None,
+            methods: None,
+            priority: 0,
+        };
+        let service = ProxyService::<Full<Bytes>>::builder(locator)
+            .route(route("grpc", false))
+            .route(route("legacy", true))
+            .upstream(config::UpstreamConfig {
+                name: "upstream".into(),
+                url: format!("http://127.0.0.1:{port}"),
+                http1: None,
+                pool: None,
+                egress: None,
+                tls: None,
+                health_check: None,
+            })
+            .build()
+            .unwrap();
+
+        let request = |path: &str| {
+            Request::builder()
+                .uri(format!("http://example.com/{path}"))
+                .header("te", "trailers")
+                .body(Full::new(Bytes::new()))
+                .unwrap()
+        };
+
+        let response = service.call(request("grpc")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["x-te"], "true");
+        assert_eq!(response.headers()["trailer"], "grpc-status");
+        let body = response.into_body().collect().await.unwrap();
+        assert_eq!(body.trailers().unwrap()["grpc-status"], "0");
+        assert_eq!(body.to_bytes(), "ok");
+
+        let response = service.call(request("legacy")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["x-te"], "false");
+        assert!(response.headers().get("trailer").is_none());
+        let body = response.into_body().collect().await.unwrap();
+        assert!(body.trailers().is_none());
+        assert_eq!(body.to_bytes(), "ok");
+    }
+
+    fn route(host: Option<&str>, path: Option<&str>, action: config::Action) -> config::Route {
+        config::Route {
+            r#match: config::Match {
+                host: host.map(Into::into),
+                path: path.map(Into::into),
+                flag: None,
+                active: None,
+                headers: vec![],
+            },
+            action,
+            response_headers: None,
+            client_ips: None,
+            strip_trailers: false,
+            headers: None,
+            capture: None,
+            timeout_ms: None,
+            retries: None,
+            methods: None,
+            priority: 0,
+        }
+    }
+
+    fn to(upstream: &str) -> config::Action {
+        config::Action::Static {
+            to: upstream.into(),
+        }
+    }
+
+    fn get(uri: &str) -> Request<Full<Bytes>> {
+        Request::builder()
+            .uri(uri)
+            .body(Full::new(Bytes::new()))
+            .unwrap()
+    }
+
+    /// Name of the echo upstream that served the request, or the status of the response
+    async fn served_by(
+        service: &ProxyService<Full<Bytes>>,
+        request: Request<Full<Bytes>>,
+    ) -> Result<String, StatusCode> {
+        let response = service.call(request).await.unwrap();
+        match response.headers().get("x-upstream") {
+            Some(name) if response.status() == StatusCode::OK => {
+                Ok(name.to_str().unwrap().to_string())
+            }
+            _ => Err(response.status()),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_static_routes() {
+        let us = MockServer::echo("us").await;
+        let assets = MockServer::echo("assets").await;
+        let locator = locator_client("http://127.0.0.1:1".into()).await;
+
+        let service = ProxyService::<Full<Bytes>>::builder(locator)
+            .route(route(Some("us.sentry.io"), None, to("us")))
+            .route(route(None, Some("/static/*"), to("assets")))
+            .upstream(us.upstream("us"))
+            .upstream(assets.upstream("assets"))
+            .build()
+            .unwrap();
+
+        // Method, path, query and body are forwarded as is
+        let request = Request::builder()
+            .method("POST")
+            .uri("http://us.sentry.io/api/0/projects/?cursor=abc")
+            .body(Full::new(Bytes::from_static(b"payload")))
+            .unwrap();
+        let response = service.call(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let echoed = Echoed::from_response(response).await;
+        assert_eq!(echoed.method, "POST");
+        assert_eq!(echoed.uri, "/api/0/projects/?cursor=abc");
+        assert_eq!(echoed.body, "payload");
+        assert_eq!(
+            echoed.headers["host"],
+            us.url().trim_start_matches("http://")
+        );
+
+        // Routes are matched by the host header of origin-form requests, which is forwarded
+        let origin_form = Request::builder()
+            .uri("/api/0/projects/")
+            .header("host", "us.sentry.io")
+            .body(Full::new(Bytes::new()))
+            .unwrap();
+        assert_eq!(served_by(&service, origin_form).await, Ok("us".into()));
+
+        // Routes are evaluated in order
+        assert_eq!(
+            served_by(&service, get("http://us.sentry.io/static/app.js")).await,
+            Ok("us".into())
+        );
+        assert_eq!(
+            served_by(&service, get("http://de.sentry.io/static/app.js")).await,
+            Ok("assets".into())
+        );
+        assert_eq!(
+            served_by(&service, get("http://de.sentry.io/api/0/")).await,
+            Err(StatusCode::NOT_FOUND)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_buffered_body_limit() {
+        let us = MockServer::echo("us").await;
+        let locator = locator_client("http://127.0.0.1:1".into()).await;
+
+        let mut upstream = us.upstream("us");
+        upstream.http1 = Some(config::Http1Options {
+            buffer_request_body: true,
+            max_buffered_body_bytes: 4,
+            ..Default::default()
+        });
+        let service = ProxyService::<Full<Bytes>>::builder(locator)
+            .route(route(None, None, to("us")))
+            .upstream(upstream)
+            .build()
+            .unwrap();
+
+        let post = |body: &'static [u8]| {
+            Request::builder()
+                .method("POST")
+                .uri("http://us.sentry.io/api/1/envelope/")
+                .body(Full::new(Bytes::from_static(body)))
+                .unwrap()
+        };
+        let response = service.call(post(b"1234")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(Echoed::from_response(response).await.body, "1234");
+        let response = service.call(post(b"12345")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    #[tokio::test]
+    async fn test_header_rewrites() {
+        let us = MockServer::echo("us").await;
+        let locator = locator_client("http://127.0.0.1:1".into()).await;
+
+        let mut rewritten = route(None, Some("/api/*"), to("us"));
+        rewritten.headers = Some(config::HeaderRewrites {
+            request: Some(config::HeaderRewrite {
+                set: HashMap::from([("X-Sentry-Cell".into(), "us1".into())]),
+                remove: vec!["X-Internal-Token".into()],
+                ..Default::default()
+            }),
+            response: Some(config::HeaderRewrite {
+                set: HashMap::from([("X-Upstream".into(), "cell".into())]),
+                append: HashMap::from([("Cache-Control".into(), "no-store".into())]),
+                ..Default::default()
+            }),
+        });
+        let service = ProxyService::<Full<Bytes>>::builder(locator)
+            .route(rewritten)
+            .route(route(None, None, to("us")))
+            .upstream(us.upstream("us"))
+            .build()
+            .unwrap();
+
+        let request = |path: &str| {
+            Request::builder()
+                .uri(format!("http://sentry.io/{path}"))
+                .header("x-sentry-cell", "spoofed")
+                .header("x-internal-token", "secret")
+                .body(Full::new(Bytes::new()))
+                .unwrap()
+        };
+
+        let response = service.call(request("api/0/")).await.unwrap();
+        assert_eq!(response.headers()["x-upstream"], "cell");
+        assert_eq!(response.headers()["cache-control"], "no-store");
+        let echoed = Echoed::from_response(response).await;
+        assert_eq!(echoed.headers["x-sentry-cell"], "us1");
+        assert!(!echoed.headers.contains_key("x-internal-token"));
+
+        // Other routes are not rewritten
+        let response = service.call(request("other/")).await.unwrap();
+        assert_eq!(response.headers()["x-upstream"], "us");
+        assert!(response.headers().get("cache-control").is_none());
+        let echoed = Echoed::from_response(response).await;
+        assert_eq!(echoed.headers["x-sentry-cell"], "spoofed");
+        assert_eq!(echoed.headers["x-internal-token"], "secret");
+    }
+
+    #[tokio::test]
+    async fn test_allowed_methods() {
+        let us = MockServer::echo("us").await;
+        let locator = locator_client("http://127.0.0.1:1".into()).await;
+
+        let mut restricted = route(None, Some("/api/*"), to("us"));
+        restricted.methods = Some(config::RouteMethods {
+            allow: vec!["GET".into(), "post".into()],
+            answer_options: true,
+        });
+        let service = ProxyService::<Full<Bytes>>::builder(locator)
+            .route(restricted)
+            .route(route(None, None, to("us")))
+            .upstream(us.upstream("us"))
+            .build()
+            .unwrap();
+
+        let request = |method: &str, path: &str| {
+            Request::builder()
+                .method(method)
+                .uri(format!("http://sentry.io/{path}"))
+                .body(Full::new(Bytes::new()))
+                .unwrap()
+        };
+
+        assert_eq!(
+            served_by(&service, request("POST", "api/0/")).await,
+            Ok("us".into())
+        );
+
+        // Not forwarded to the upstream
+        let response = service.call(request("DELETE", "api/0/")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(response.headers()["allow"], "GET, POST, OPTIONS");
+        assert!(response.headers().get("x-upstream").is_none());
+
+        let response = service.call(request("OPTIONS", "api/0/")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert_eq!(response.headers()["allow"], "GET, POST, OPTIONS");
+        assert!(response.headers().get("x-upstream").is_none());
+
+        // Other routes forward every method
+        assert_eq!(
+            served_by(&service, request("DELETE", "other/")).await,
+            Ok("us".into())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_request_capture() {
+        let us = MockServer::echo("us").await;
+        let locator = locator_client("http://127.0.0.1:1".into()).await;
+
+        let mut captured = route(None, Some("/api/*"), to("us"));
+        captured.capture = Some(config::RouteCapture {
+            sample_rate: 0.5,
+            until: Utc::now() + chrono::Duration::hours(1),
+            max_body_bytes: 4,
+        });
+
+        // Capturing routes require request capture
+        let result = ProxyService::<Full<Bytes>>::builder(locator.clone())
+            .route(captured.clone())
+            .upstream(us.upstream("us"))
+            .build();
+        assert!(matches!(result, Err(ProxyError::InvalidRoute(_))));
+
+        let service = ProxyService::<Full<Bytes>>::builder(locator)
+            .route(captured)
+            .route(route(None, None, to("us")))
+            .upstream(us.upstream("us"))
+            .request_capture(config::RequestCapture { max_requests: 10 })
+            .build()
+            .unwrap();
+
+        for path in ["api/0/", "api/1/", "api/2/", "other/"] {
+            let request = Request::builder()
+                .method("POST")
+                .uri(format!("http://sentry.io/{path}"))
+                .header("authorization", "Bearer secret")
+                .body(Full::new(Bytes::from_static(b"payload")))
+                .unwrap();
+            let response = service.call(request).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            Echoed::from_response(response).await;
+        }
+
+        // Every second request of the capturing route
+        let captured = service.captured_requests().unwrap().captured();
+        assert_eq!(captured.len(), 1);
+        let captured = &captured[0];
+        assert_eq!(captured.path, "/api/1/");
+        assert!(
+            captured
+                .headers
+                .contains(&("authorization".into(), "[redacted]".into()))
+        );
+        assert_eq!(captured.body.data, "payl");
+        assert!(captured.body.truncated);
+        let response = captured.response.as_ref().unwrap();
+        assert_eq!(response.status, 200);
+        assert_eq!(response.body.data.len(), 4);
+        assert!(response.body.truncated);
+    }
+
+    #[tokio::test]
+    async fn test_dynamic_resolution() {
+        let us1 = MockServer::echo("us1").await;
+        let de1 = MockServer::echo("de1").await;
+        let fallback = MockServer::echo("fallback").await;
+        let locator_api = MockServer::locator(HashMap::from([
+            ("acme".to_string(), "us1".to_string()),
+            ("globex".to_string(), "de1".to_string()),
+            ("initech".to_string(), "us9".to_string()),
+        ]))
+        .await;
+        let locator = locator_client(locator_api.url()).await;
+
+        let cell_to_upstream = HashMap::from([
+            ("us1".to_string(), "us1".to_string()),
+            ("de1".to_string(), "de1".to_string()),
+        ]);
+        let service = ProxyService::<Full<Bytes>>::builder(locator)
+            .route(route(
+                None,
+                Some("/api/0/organizations/{organization}/*"),
+                config::Action::Dynamic {
+                    resolver: config::Resolver::CellFromOrganization,
+                    cell_to_upstream: cell_to_upstream.clone(),
+                    default: Some("fallback".into()),
+                    key_source: config::KeySource::Path,
+                    key_name: None,
+                },
+            ))
+            .route(route(
+                None,
+                Some("/cells/{id}/*"),
+                config::Action::Dynamic {
+                    resolver: config::Resolver::CellFromId,
+                    cell_to_upstream,
+                    default: None,
+                    key_source: config::KeySource::Path,
+                    key_name: None,
+                },
+            ))
+            .upstream(us1.upstream("us1"))
+            .upstream(de1.upstream("de1"))
+            .upstream(fallback.upstream("fallback"))
+            .build()
+            .unwrap();
+
+        let cases = [
+            ("/api/0/organizations/acme/issues/", Ok("us1".into())),
+            ("/api/0/organizations/globex/issues/", Ok("de1".into())),
+            // Unknown organizations and cells without an upstream use the default
+            (
+                "/api/0/organizations/unknown/issues/",
+                Ok("fallback".into()),
+            ),
+            (
+                "/api/0/organizations/initech/issues/",
+                Ok("fallback".into()),
+            ),
+            ("/cells/de1/health/", Ok("de1".into())),
+            ("/cells/us9/health/", Err(StatusCode::NOT_FOUND)),
+        ];
+        for (path, expected) in cases {
+            let uri = format!("http://sentry.io{path}");
+            assert_eq!(served_by(&service, get(&uri)).await, expected, "{path}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_health_checks() {
+        let us1 = MockServer::echo("us1").await;
+        // Fails its health checks, but would serve requests
+        let de1 = MockServer::spawn(|request| {
+            let status = match request.uri().path() {
+                "/_health/" => StatusCode::INTERNAL_SERVER_ERROR,
+                _ => StatusCode::OK,
+            };
+            Response::builder()
+                .status(status)
+                .header("x-upstream", "de1")
+                .body(Full::new(Bytes::new()))
+                .unwrap()
+        })
+        .await;
+        let fallback = MockServer::echo("fallback").await;
+        let locator_api = MockServer::locator(HashMap::from([
+            ("acme".to_string(), "us1".to_string()),
+            ("globex".to_string(), "de1".to_string()),
+        ]))
+        .await;
+        let locator = locator_client(locator_api.url()).await;
+
+        let health_check = config::HealthCheck {
+            path: "/_health/".into(),
+            interval_secs: 1,
+            timeout_ms: 1000,
+            unhealthy_threshold: 1,
+        };
+        let with_health_check = |mut upstream: config::UpstreamConfig| {
+            upstream.health_check = Some(health_check.clone());
+            upstream
+        };
+        let service = ProxyService::<Full<Bytes>>::builder(locator)
+            .route(route(None, Some("/de/*"), to("de1")))
+            .route(route(
+                None,
+                Some("/api/0/organizations/{organization}/*"),
+                config::Action::Dynamic {
+                    resolver: config::Resolver::CellFromOrganization,
+                    cell_to_upstream: HashMap::from([
+                        ("us1".to_string(), "us1".to_string()),
+                        ("de1".to_string(), "de1".to_string()),
+                    ]),
+                    default: Some("fallback".into()),
+                    key_source: config::KeySource::Path,
+                    key_name: None,
+                },
+            ))
+            .upstream(with_health_check(us1.upstream("us1")))
+            .upstream(with_health_check(de1.upstream("de1")))
+            .upstream(fallback.upstream("fallback"))
+            .build()
+            .unwrap();
+
+        let upstream_health = service.upstream_health();
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while upstream_health.is_healthy("de1") {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        let report = upstream_health.report();
+        assert!(report["us1"].healthy);
+        assert_eq!(report["de1"].last_error.as_deref(), Some("status 500"));
+
+        let cases = [
+            ("/de/issues/", Err(StatusCode::SERVICE_UNAVAILABLE)),
+            ("/api/0/organizations/acme/issues/", Ok("us1".into())),
+            // Dynamic routes use the default instead of an upstream that is down
+            ("/api/0/organizations/globex/issues/", Ok("fallback".into())),
+        ];
+        for (path, expected) in cases {
+            let uri = format!("http://sentry.io{path}");
+            assert_eq!(served_by(&service, get(&uri)).await, expected, "{path}");
+        }
+
+        // Invalid health checks are rejected
+        let mut invalid = with_health_check(us1.upstream("us1"));
+        invalid.health_check.as_mut().unwrap().interval_secs = 0;
+        let locator = locator_client("http://127.0.0.1:1".into()).await;
+        assert!(
+            ProxyService::<Full<Bytes>>::builder(locator)
+                .upstream(invalid)
+                .build()
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_timeouts() {
+        let stalled = MockServer::stalled().await;
+        let fallback = MockServer::echo("fallback").await;
+        let locator = locator_client(stalled.url()).await;
+
+        let service = ProxyService::<Full<Bytes>>::builder(locator)
+            .route(config::Route {
+                timeout_ms: Some(400),
+                ..route(
+                    None,
+                    Some("/dynamic/{organization}/*"),
+                    config::Action::Dynamic {
+                        resolver: config::Resolver::CellFromOrganization,
+                        cell_to_upstream: HashMap::from([("us1".into(), "stalled".into())]),
+                        default: Some("fallback".into()),
+                        key_source: config::KeySource::Path,
+                        key_name: None,
+                    },
+                )
+            })
+            .route(config::Route {
+                timeout_ms: Some(200),
+                ..route(None, Some("/stalled/*"), to("stalled"))
+            })
+            .upstream(stalled.upstream("stalled"))
+            .upstream(fallback.upstream("fallback"))
+            .build()
+            .unwrap();
+
+        let cases = [
+            // The locator does not answer within a quarter of the budget
+            ("/dynamic/acme/", Ok("fallback".into())),
+            ("/stalled/", Err(StatusCode::GATEWAY_TIMEOUT)),
+        ];
+        for (path, expected) in cases {
+            let uri = format!("http://sentry.io{path}");
+            let start = Instant::now();
+            assert_eq!(served_by(&service, get(&uri)).await, expected, "{path}");
+            assert!(start.elapsed() < Duration::from_secs(1), "{path}");
+        }
+
+        let invalid = ProxyService::<Full<Bytes>>::builder(locator_client(stalled.url()).await)
+            .route(config::Route {
+                timeout_ms: Some(0),
+                ..route(None, Some("/stalled/*"), to("stalled"))
+            })
+            .upstream(stalled.upstream("stalled"))
+            .build();
+        assert!(invalid.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_retries() {
+        // Every other request fails with 503
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let flapping = MockServer::spawn({
+            let attempts = attempts.clone();
+            move |_request| {
+                let mut response = Response::new(Full::new(Bytes::new()));
+                if attempts.fetch_add(1, Ordering::Relaxed).is_multiple_of(2) {
+                    *response.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+                } else {
+                    response
+                        .headers_mut()
+                        .insert("x-upstream", http::HeaderValue::from_static("flapping"));
+                }
+                response
+            }
+        })
+        .await;
+        let locator = locator_client("http://127.0.0.1:1".into()).await;
+
+        let retries = config::RouteRetries {
+            max_attempts: 2,
+            backoff_ms: 10,
+            statuses: vec![502, 503],
+            connect_errors: true,
+            idempotent_only: true,
+            max_body_bytes: 1024,
+        };
+        let service = ProxyService::<Full<Bytes>>::builder(locator)
+            .route(config::Route {
+                retries: Some(retries),
+                ..route(None, Some("/retried/*"), to("flapping"))
+            })
+            .route(route(None, None, to("flapping")))
+            .upstream(flapping.upstream("flapping"))
+            .build()
+            .unwrap();
+
+        let post = |uri: &str| {
+            Request::builder()
+                .method(http::Method::POST)
+                .uri(uri)
+                .body(Full::new(Bytes::from("data")))
+                .unwrap()
+        };
+        let cases = [
+            (get("http://sentry.io/retried/"), Ok("flapping".into()), 2),
+            // Not idempotent
+            (
+                post("http://sentry.io/retried/"),
+                Err(StatusCode::SERVICE_UNAVAILABLE),
+                3,
+            ),
+            (get("http://sentry.io/retried/"), Ok("flapping".into()), 4),
+            // No retries
+            (
+                get("http://sentry.io/"),
+                Err(StatusCode::SERVICE_UNAVAILABLE),
+                5,
+            ),
+        ];
+        for (request, expected, total_attempts) in cases {
+            let path = request.uri().path().to_string();
+            assert_eq!(served_by(&service, request).await, expected, "{path}");
+            assert_eq!(attempts.load(Ordering::Relaxed), total_attempts, "{path}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_error_paths() {
+        let unavailable = MockServer::status(StatusCode::SERVICE_UNAVAILABLE, "").await;
+        let fallback = MockServer::echo("fallback").await;
+        // Nothing listens on the locator
+        let locator = locator_client("http://127.0.0.1:1".into()).await;
+
+        let dynamic = |path: &str, default: Option<&str>| {
+            route(
+                None,
+                Some(path),
+                config::Action::Dynamic {
+                    resolver: config::Resolver::CellFromOrganization,
+                    cell_to_upstream: HashMap::from([("us1".into(), "fallback".into())]),
+                    default: default.map(Into::into),
+                    key_source: config::KeySource::Path,
+                    key_name: None,
+                },
+            )
+        };
+        let service = ProxyService::<Full<Bytes>>::builder(locator)
+            .route(dynamic("/with-default/{organization}/*", Some("fallback")))
+            .route(dynamic("/no-default/{organization}/*", None))
+            .route(route(None, Some("/unavailable/*"), to("unavailable")))
+            .route(route(None, Some("/unreachable/*"), to("unreachable")))
+            .upstream(unavailable.upstream("unavailable"))
+            .upstream(fallback.upstream("fallback"))
+            .upstream(config::UpstreamConfig {
+                name: "unreachable".into(),
+                url: "http://127.0.0.1:1".into(),
+                http1: None,
+                pool: None,
+                egress: None,
+                tls: None,
+                health_check: None,
+            })
+            .build()
+            .unwrap();
+
+        let cases = [
+            // The locator cannot be reached
+            ("/with-default/acme/", Ok("fallback".into())),
+            ("/no-default/acme/", Err(StatusCode::NOT_FOUND)),
+            // Upstream errors are passed through
+            ("/unavailable/", Err(StatusCode::SERVICE_UNAVAILABLE)),
+            ("/unreachable/", Err(StatusCode::BAD_GATEWAY)),
+            ("/unknown/", Err(StatusCode::NOT_FOUND)),
+        ];
+        for (path, expected) in cases {
+            let uri = format!("http://sentry.io{path}");
+            assert_eq!(served_by(&service, get(&uri)).await, expected, "{path}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_header_handling() {
+        let upstream = MockServer::echo("upstream").await;
+        let locator = locator_client("http://127.0.0.1:1".into()).await;
+
+        let mut filtered = route(None, Some("/filtered/*"), to("upstream"));
+        filtered.response_headers = Some(config::ResponseHeaders::Deny {
+            deny: vec!["x-upstream".into()],
+        });
+        let service = ProxyService::<Full<Bytes>>::builder(locator)
+            .route(filtered)
+            .route(route(None, None, to("upstream")))
+            .upstream(upstream.upstream("upstream"))
+            .build()
+            .unwrap();
+
+        let request = |path: &str| {
+            Request::builder()
+                .uri(format!("http://sentry.io{path}"))
+                .header("connection", "x-hop")
+                .header("x-hop", "1")
+                .header("proxy-authorization", "Basic c2VjcmV0")
+                .header("x-custom", "kept")
+                .body(Full::new(Bytes::new()))
+                .unwrap()
+        };
+
+        let response = service.call(request("/")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["via"], "1.1 synapse");
+        assert_eq!(response.headers()["x-upstream"], "upstream");
+        let request_id = response.headers()["x-request-id"]
+            .to_str()
+            .unwrap()
+            .to_string();
+        let echoed = Echoed::from_response(response).await;
+        // Hop-by-hop headers, including those listed in Connection, are not forwarded
+        assert!(!echoed.headers.contains_key("x-hop"));
+        assert!(!echoed.headers.contains_key("proxy-authorization"));
+        assert_eq!(echoed.headers["x-custom"], "kept");
+        assert_eq!(echoed.headers["via"], "1.1 synapse");
+        // The generated request id is forwarded and returned
+        assert_eq!(echoed.headers["x-request-id"], request_id);
+
+        // Response headers are filtered by the route
+        let response = service.call(request("/filtered/")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(!response.headers().contains_key("x-upstream"));
+        assert_eq!(response.headers()["content-type"], "application/json");
     }
 }
@@ -1,7 +1,9 @@
 use crate::config::Resolver;
 use crate::errors::ProxyError;
+use crate::metrics_defs::RESOLVER_TIMEOUTS;
 use locator::client::Locator;
 use std::collections::HashMap;
+use std::time::Instant;
 
 #[derive(Clone)]
 pub struct Resolvers {
@@ -13,17 +15,30 @@ impl Resolvers {
         Ok(Resolvers { locator })
     }
 
+    /// Resolves the upstream based on the resolver name and parameters. Fails with
+    /// `ResolverTimeout` if the resolver is still running at the deadline.
     pub async fn resolve<'a>(
         &self,
         resolver: &Resolver,
         cell_to_upstream: &'a HashMap<String, String>,
         params: HashMap<String, String>,
+        deadline: Option<Instant>,
     ) -> Result<&'a str, ProxyError> {
-        // Resolve the upstream based on the resolver name and parameters
-        // Return the upstream name or an error if resolution fails
-        let cell = match resolver {
-            Resolver::CellFromOrganization => self.cell_from_organization(params).await,
-            Resolver::CellFromId => self.cell_from_id(params).await,
+        let cell = async {
+            match resolver {
+                Resolver::CellFromOrganization => self.cell_from_organization(params).await,
+                Resolver::CellFromId => self.cell_from_id(params).await,
+            }
+        };
+        let cell = match deadline {
+            Some(deadline) => tokio::time::timeout_at(deadline.into(), cell)
+                .await
+                .map_err(|_| {
+                    metrics::counter!(RESOLVER_TIMEOUTS.name, "resolver" => resolver.name())
+                        .increment(1);
+                    ProxyError::ResolverTimeout
+                })?,
+            None => cell.await,
         }?;
         cell_to_upstream
             .get(&cell)
@@ -53,11 +68,13 @@ impl Resolvers {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::testutils::{MockServer, locator_client};
     use locator::backup_routes::{BackupRouteProvider, FilesystemRouteProvider};
-    use locator::config::{Compression, LocatorDataType};
+    use locator::config::{Compression, ControlPlane, LocatorDataType, RetryPolicy};
     use locator::locator::Locator as LocatorService;
     use locator::types::RouteData;
     use std::sync::Arc;
+    use std::time::Duration;
 
     async fn get_mock_provider() -> (tempfile::TempDir, FilesystemRouteProvider) {
         let route_data = RouteData::from(
@@ -85,7 +102,15 @@ mod tests {
         let (_dir, provider) = get_mock_provider().await;
         let service = LocatorService::new(
             LocatorDataType::Organization,
-            "http://control-plane-url".to_string(),
+            ControlPlane {
+                url: "http://control-plane-url".to_string(),
+                retry: RetryPolicy {
+                    max_retries: 0,
+                    ..Default::default()
+                },
+                pagination: Default::default(),
+                signing: None,
+            },
             Arc::new(provider),
             None,
             None,
@@ -109,7 +134,12 @@ mod tests {
         let mut params = HashMap::new();
         params.insert("id".to_string(), "us1".to_string());
         let result = resolvers
-            .resolve(&Resolver::CellFromId, &cell_to_upstream, params.clone())
+            .resolve(
+                &Resolver::CellFromId,
+                &cell_to_upstream,
+                params.clone(),
+                None,
+            )
             .await
             .unwrap();
         assert_eq!(result, "upstream1");
@@ -118,7 +148,12 @@ mod tests {
         let mut invalid_params = HashMap::new();
         invalid_params.insert("id".to_string(), "us999".to_string());
 
-        let result = resolvers.resolve(&Resolver::CellFromId, &cell_to_upstream, invalid_params);
+        let result = resolvers.resolve(
+            &Resolver::CellFromId,
+            &cell_to_upstream,
+            invalid_params,
+            None,
+        );
 
         assert!(result.await.is_err());
 
@@ -131,6 +166,7 @@ mod tests {
                 &Resolver::CellFromOrganization,
                 &cell_to_upstream,
                 org_params,
+                None,
             )
             .await
             .unwrap();
@@ -145,8 +181,30 @@ mod tests {
             &Resolver::CellFromOrganization,
             &cell_to_upstream,
             invalid_org_params,
+            None,
         );
 
         assert!(result.await.is_err());
     }
+
+    #[tokio::test]
+    async fn test_resolve_deadline() {
+        let locator_api = MockServer::stalled().await;
+        let locator = locator_client(locator_api.url()).await;
+        let resolvers = Resolvers::try_new(locator).unwrap();
+        let cell_to_upstream = HashMap::from([("us1".to_string(), "upstream1".to_string())]);
+        let params = HashMap::from([("organization".to_string(), "org_0".to_string())]);
+
+        let deadline = Instant::now() + Duration::from_millis(100);
+        let result = resolvers
+            .resolve(
+                &Resolver::CellFromOrganization,
+                &cell_to_upstream,
+                params,
+                Some(deadline),
+            )
+            .await;
+        assert!(matches!(result, Err(ProxyError::ResolverTimeout)));
+        assert!(Instant::now() < deadline + Duration::from_secs(1));
+    }
 }
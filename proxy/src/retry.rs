@@ -0,0 +1,150 @@
+//! Per-route retries of failed upstream requests.
+//!
+//! A request is retried when its connection to the upstream cannot be established, or when
+//! the upstream answers with one of the retried statuses, typically 502 and 503 while an
+//! upstream is restarting. Retries back off exponentially from `backoff_ms`.
+//!
+//! Request bodies are streamed to the upstream and cannot be replayed, so only requests
+//! whose body is known to fit in `max_body_bytes` are retried; their body is buffered
+//! before the first attempt. By default only idempotent methods are retried, since a
+//! request that failed with a 502 may have been processed by the upstream nonetheless.
+//! Responses with a `Retry-After` header are not retried, the upstream asked clients to
+//! back off.
+use crate::config::RouteRetries;
+use crate::errors::ProxyError;
+use http::header::RETRY_AFTER;
+use http::{Method, StatusCode};
+use hyper::Response;
+use hyper::body::{Incoming, SizeHint};
+use std::time::Duration;
+
+#[derive(Debug, PartialEq)]
+pub struct RetryPolicy {
+    pub max_attempts: u32,
+    backoff: Duration,
+    statuses: Vec<StatusCode>,
+    connect_errors: bool,
+    idempotent_only: bool,
+    max_body_bytes: u64,
+}
+
+impl TryFrom<RouteRetries> for RetryPolicy {
+    type Error = ProxyError;
+
+    fn try_from(config: RouteRetries) -> Result<Self, Self::Error> {
+        if config.max_attempts == 0 {
+            return Err(ProxyError::InvalidRoute(
+                "Retries need at least one attempt".to_string(),
+            ));
+        }
+        let statuses = config
+            .statuses
+            .iter()
+            .map(|&status| {
+                StatusCode::from_u16(status).map_err(|_| {
+                    ProxyError::InvalidRoute(format!("Invalid retried status: {status}"))
+                })
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Self {
+            max_attempts: config.max_attempts,
+            backoff: Duration::from_millis(config.backoff_ms),
+            statuses,
+            connect_errors: config.connect_errors,
+            idempotent_only: config.idempotent_only,
+            max_body_bytes: config.max_body_bytes as u64,
+        })
+    }
+}
+
+impl RetryPolicy {
+    /// Whether a request with this method and body can be retried.
+    pub fn applies(&self, method: &Method, body: &SizeHint) -> bool {
+        (!self.idempotent_only || method.is_idempotent())
+            && body
+                .upper()
+                .is_some_and(|upper| upper <= self.max_body_bytes)
+    }
+
+    /// Returns why the attempt should be retried, `None` if its result is final.
+    pub fn retry_reason(&self, result: &Result<Response<Incoming>, ProxyError>) -> Option<String> {
+        match result {
+            Ok(response)
+                if self.statuses.contains(&response.status())
+                    && !response.headers().contains_key(RETRY_AFTER) =>
+            {
+                Some(response.status().as_u16().to_string())
+            }
+            Err(ProxyError::UpstreamRequest(e)) if self.connect_errors && e.is_connect() => {
+                Some("connect_error".to_string())
+            }
+            _ => None,
+        }
+    }
+
+    /// Delay before the given retry, counting from 1.
+    pub fn backoff(&self, retry: u32) -> Duration {
+        self.backoff
+            .saturating_mul(2u32.saturating_pow(retry.saturating_sub(1)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(idempotent_only: bool) -> RetryPolicy {
+        RetryPolicy::try_from(RouteRetries {
+            max_attempts: 3,
+            backoff_ms: 50,
+            statuses: vec![502, 503],
+            connect_errors: true,
+            idempotent_only,
+            max_body_bytes: 1024,
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_applies() {
+        let idempotent_only = policy(true);
+        assert!(idempotent_only.applies(&Method::GET, &SizeHint::with_exact(0)));
+        assert!(idempotent_only.applies(&Method::PUT, &SizeHint::with_exact(1024)));
+        assert!(!idempotent_only.applies(&Method::PUT, &SizeHint::with_exact(1025)));
+        // Streamed bodies of unknown length
+        assert!(!idempotent_only.applies(&Method::GET, &SizeHint::new()));
+        assert!(!idempotent_only.applies(&Method::POST, &SizeHint::with_exact(0)));
+
+        let any_method = policy(false);
+        assert!(any_method.applies(&Method::POST, &SizeHint::with_exact(0)));
+    }
+
+    #[test]
+    fn test_backoff() {
+        let policy = policy(true);
+        assert_eq!(policy.backoff(1), Duration::from_millis(50));
+        assert_eq!(policy.backoff(2), Duration::from_millis(100));
+        assert_eq!(policy.backoff(3), Duration::from_millis(200));
+    }
+
+    #[test]
+    fn test_invalid() {
+        let config = RouteRetries {
+            max_attempts: 3,
+            backoff_ms: 50,
+            statuses: vec![502, 1000],
+            connect_errors: true,
+            idempotent_only: true,
+            max_body_bytes: 1024,
+        };
+        assert!(RetryPolicy::try_from(config.clone()).is_err());
+        assert!(
+            RetryPolicy::try_from(RouteRetries {
+                max_attempts: 0,
+                statuses: vec![],
+                ..config
+            })
+            .is_err()
+        );
+    }
+}
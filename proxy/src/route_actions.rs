@@ -1,6 +1,19 @@
-use crate::config::{Action, Route as RouteConfig};
+use crate::allowed_methods::AllowedMethods;
+use crate::capture::CaptureSampler;
+use crate::client_ip::IpFilter;
+use crate::config::{Action, ActiveWindow, HeaderMatch, KeySource, Route as RouteConfig};
 use crate::errors::ProxyError;
+use crate::header_filter::HeaderFilter;
+use crate::header_rewrite::HeaderRewriter;
+use crate::retry::RetryPolicy;
+use chrono::{DateTime, Utc};
+use http::{HeaderMap, HeaderName, HeaderValue};
+use serde::Serialize;
+use std::borrow::Cow;
 use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use url::form_urlencoded;
 
 #[derive(Debug)]
 enum PathSegment {
@@ -14,20 +27,177 @@ struct Path {
     has_trailing_splat: bool,
 }
 
+#[derive(Debug)]
+struct HeaderCondition {
+    name: HeaderName,
+    value: Option<HeaderValue>,
+    param: Option<String>,
+}
+
+/// Resolver key read from outside the path, stored in the resolver's route parameter
+#[derive(Debug)]
+enum ResolverKey {
+    Query {
+        name: String,
+        param: &'static str,
+    },
+    Header {
+        name: HeaderName,
+        param: &'static str,
+    },
+}
+
 #[derive(Debug, PartialEq)]
 pub struct RouteMatch {
+    /// Path pattern of the route as configured, `*` for routes without a path
+    pub pattern: Arc<str>,
     pub params: HashMap<String, String>,
     pub action: Action,
+    pub flag: Option<String>,
+    /// Filter for the upstream response headers
+    pub header_filter: Arc<HeaderFilter>,
+    /// Client IPs allowed to use the route
+    pub ip_filter: Option<Arc<IpFilter>>,
+    /// Drop the trailers of upstream responses
+    pub strip_trailers: bool,
+    /// Rewrites of the request and response headers
+    pub header_rewriter: Option<Arc<HeaderRewriter>>,
+    /// Sampling of the requests captured for debugging
+    pub capture: Option<Arc<CaptureSampler>>,
+    /// Time budget of the request
+    pub timeout: Option<Duration>,
+    /// Retries of failed upstream requests
+    pub retry: Option<Arc<RetryPolicy>>,
+    /// Methods forwarded to the upstream, all if not set
+    pub methods: Option<Arc<AllowedMethods>>,
+}
+
+/// A route of the table, in the order routes are tried, for the admin listener
+#[derive(Debug, PartialEq, Serialize)]
+pub struct RouteEntry {
+    pub host: Option<String>,
+    /// Path pattern of the route as configured, `*` for routes without a path
+    pub path: String,
+    pub headers: Vec<HeaderMatch>,
+    pub flag: Option<String>,
+    pub active: Option<ActiveWindow>,
+    pub priority: i32,
+    pub action: Action,
 }
 
 #[derive(Debug)]
 struct Route {
     host: Option<String>,
+    pattern: Arc<str>,
     path: Option<Path>,
+    flag: Option<String>,
+    active: Option<ActiveWindow>,
+    headers: Vec<HeaderCondition>,
+    resolver_key: Option<ResolverKey>,
     action: Action,
+    header_filter: Arc<HeaderFilter>,
+    ip_filter: Option<Arc<IpFilter>>,
+    strip_trailers: bool,
+    header_rewriter: Option<Arc<HeaderRewriter>>,
+    capture: Option<Arc<CaptureSampler>>,
+    timeout: Option<Duration>,
+    retry: Option<Arc<RetryPolicy>>,
+    methods: Option<Arc<AllowedMethods>>,
+    priority: i32,
 }
 
 impl Route {
+    // Returns true if this route matches every request the other route matches, so that the
+    // other route is unreachable after it.
+    fn shadows(&self, other: &Route) -> bool {
+        if self.flag.is_some() || self.active.is_some() || !self.headers.is_empty() {
+            return false;
+        }
+        if self.host.is_some() && self.host != other.host {
+            return false;
+        }
+        let Some(path) = &self.path else {
+            return true;
+        };
+        let Some(other_path) = &other.path else {
+            return false;
+        };
+        let lengths_match = if path.has_trailing_splat {
+            other_path.segments.len() >= path.segments.len()
+        } else {
+            !other_path.has_trailing_splat && other_path.segments.len() == path.segments.len()
+        };
+        lengths_match
+            && path.segments.iter().zip(&other_path.segments).all(
+                |(segment, other_segment)| match (segment, other_segment) {
+                    (PathSegment::Param(_), _) => true,
+                    (PathSegment::Static(s), PathSegment::Static(other)) => s == other,
+                    (PathSegment::Static(_), PathSegment::Param(_)) => false,
+                },
+            )
+    }
+
+    fn is_active(&self, now: DateTime<Utc>) -> bool {
+        self.active.as_ref().is_none_or(|window| {
+            window.start.is_none_or(|start| start <= now) && window.end.is_none_or(|end| now < end)
+        })
+    }
+
+    // Returns true if the request headers satisfy all header conditions of the route, and
+    // stores the values of the captured headers in params.
+    fn matches_headers(&self, headers: &HeaderMap, params: &mut HashMap<String, String>) -> bool {
+        for condition in &self.headers {
+            let Some(value) = headers.get(&condition.name) else {
+                return false;
+            };
+            if condition
+                .value
+                .as_ref()
+                .is_some_and(|expected| expected != value)
+            {
+                return false;
+            }
+            if let Some(param) = &condition.param {
+                let Ok(value) = value.to_str() else {
+                    return false;
+                };
+                params.insert(param.clone(), value.to_string());
+            }
+        }
+        true
+    }
+
+    // Stores the resolver key of the request in params, if it is read from the query or a
+    // header. Requests without the key still match, the resolver then falls back to the
+    // action's default.
+    fn extract_resolver_key(
+        &self,
+        query: Option<&str>,
+        headers: &HeaderMap,
+        params: &mut HashMap<String, String>,
+    ) {
+        let (param, value) = match &self.resolver_key {
+            None => return,
+            Some(ResolverKey::Query { name, param }) => (
+                param,
+                query.and_then(|query| {
+                    form_urlencoded::parse(query.as_bytes())
+                        .find_map(|(key, value)| (key == name.as_str()).then_some(value))
+                }),
+            ),
+            Some(ResolverKey::Header { name, param }) => (
+                param,
+                headers
+                    .get(name)
+                    .and_then(|value| value.to_str().ok())
+                    .map(Cow::Borrowed),
+            ),
+        };
+        if let Some(value) = value.filter(|value| !value.is_empty()) {
+            params.insert(param.to_string(), value.to_string());
+        }
+    }
+
     // Returns Some(RouteMatch) if the request matches this route, None otherwise.
     // Trailing slash normalization is applied to incoming requests.
     fn matches(&self, request_host: Option<&str>, request_path: &str) -> Option<RouteMatch> {
@@ -67,8 +237,18 @@ impl Route {
 
                 if path.has_trailing_splat || i_req == request_segments.len() {
                     Some(RouteMatch {
+                        pattern: self.pattern.clone(),
                         params,
                         action: self.action.clone(),
+                        flag: self.flag.clone(),
+                        header_filter: self.header_filter.clone(),
+                        ip_filter: self.ip_filter.clone(),
+                        strip_trailers: self.strip_trailers,
+                        header_rewriter: self.header_rewriter.clone(),
+                        capture: self.capture.clone(),
+                        timeout: self.timeout,
+                        retry: self.retry.clone(),
+                        methods: self.methods.clone(),
                     })
                 } else {
                     None
@@ -77,8 +257,18 @@ impl Route {
             None => {
                 // If no path is defined in the route, it matches anything
                 Some(RouteMatch {
+                    pattern: self.pattern.clone(),
                     params,
                     action: self.action.clone(),
+                    flag: self.flag.clone(),
+                    header_filter: self.header_filter.clone(),
+                    ip_filter: self.ip_filter.clone(),
+                    strip_trailers: self.strip_trailers,
+                    header_rewriter: self.header_rewriter.clone(),
+                    capture: self.capture.clone(),
+                    timeout: self.timeout,
+                    retry: self.retry.clone(),
+                    methods: self.methods.clone(),
                 })
             }
         }
@@ -90,6 +280,19 @@ impl TryFrom<RouteConfig> for Route {
     fn try_from(config: RouteConfig) -> Result<Self, Self::Error> {
         let is_static_action = matches!(config.action, Action::Static { .. });
 
+        if let Some(ActiveWindow {
+            start: Some(start),
+            end: Some(end),
+        }) = &config.r#match.active
+            && start >= end
+        {
+            return Err(ProxyError::InvalidRoute(format!(
+                "Active window ends before it starts: {start} - {end}"
+            )));
+        }
+
+        let pattern = Arc::from(config.r#match.path.as_deref().unwrap_or("*"));
+
         let path = match config.r#match.path {
             Some(path_str) => {
                 // Trim slashes
@@ -152,30 +355,248 @@ impl TryFrom<RouteConfig> for Route {
             None => None,
         };
 
+        let header_filter = Arc::new(
+            config
+                .response_headers
+                .map(HeaderFilter::try_from)
+                .transpose()?
+                .unwrap_or_default(),
+        );
+
+        let ip_filter = config
+            .client_ips
+            .map(IpFilter::try_from)
+            .transpose()?
+            .map(Arc::new);
+
+        let header_rewriter = config
+            .headers
+            .map(HeaderRewriter::try_from)
+            .transpose()?
+            .map(Arc::new);
+
+        let capture = config
+            .capture
+            .map(CaptureSampler::try_from)
+            .transpose()?
+            .map(Arc::new);
+
+        let retry = config
+            .retries
+            .map(RetryPolicy::try_from)
+            .transpose()?
+            .map(Arc::new);
+
+        let methods = config
+            .methods
+            .map(AllowedMethods::try_from)
+            .transpose()?
+            .map(Arc::new);
+
+        let path_params: Vec<&str> = path
+            .iter()
+            .flat_map(|path| &path.segments)
+            .filter_map(|segment| match segment {
+                PathSegment::Param(name) => Some(name.as_str()),
+                PathSegment::Static(_) => None,
+            })
+            .collect();
+        let headers = config
+            .r#match
+            .headers
+            .into_iter()
+            .map(|header| header_condition(header, is_static_action, &path_params))
+            .collect::<Result<Vec<_>, _>>()?;
+        let resolver_key = resolver_key(&config.action, &path_params, &headers)?;
+
+        if config.timeout_ms == Some(0) {
+            return Err(ProxyError::InvalidRoute(format!(
+                "Timeout must be positive in route: {pattern}"
+            )));
+        }
+
         Ok(Self {
             host: config.r#match.host,
+            pattern,
             path,
+            flag: config.r#match.flag,
+            active: config.r#match.active,
+            headers,
+            resolver_key,
             action: config.action,
+            header_filter,
+            ip_filter,
+            strip_trailers: config.strip_trailers,
+            header_rewriter,
+            capture,
+            timeout: config.timeout_ms.map(Duration::from_millis),
+            retry,
+            methods,
+            priority: config.priority,
         })
     }
 }
 
+fn header_condition(
+    config: HeaderMatch,
+    is_static_action: bool,
+    path_params: &[&str],
+) -> Result<HeaderCondition, ProxyError> {
+    let name = HeaderName::try_from(&config.name)
+        .map_err(|_| ProxyError::InvalidRoute(format!("Invalid header name: {}", config.name)))?;
+    let value = config
+        .value
+        .map(|value| {
+            HeaderValue::try_from(&value)
+                .map_err(|_| ProxyError::InvalidRoute(format!("Invalid header value: {value}")))
+        })
+        .transpose()?;
+    if let Some(param) = &config.param {
+        if is_static_action {
+            return Err(ProxyError::InvalidRoute(format!(
+                "Header parameters are not allowed with static actions: {param}"
+            )));
+        }
+        if !param.chars().all(|ch| ch.is_ascii_lowercase() || ch == '_') {
+            return Err(ProxyError::InvalidRoute(format!(
+                "Invalid parameter name: {param}"
+            )));
+        }
+        if path_params.contains(&param.as_str()) {
+            return Err(ProxyError::InvalidRoute(format!(
+                "Parameter is captured from both the path and a header: {param}"
+            )));
+        }
+    }
+    Ok(HeaderCondition {
+        name,
+        value,
+        param: config.param,
+    })
+}
+
+fn resolver_key(
+    action: &Action,
+    path_params: &[&str],
+    headers: &[HeaderCondition],
+) -> Result<Option<ResolverKey>, ProxyError> {
+    let Action::Dynamic {
+        resolver,
+        key_source,
+        key_name,
+        ..
+    } = action
+    else {
+        return Ok(None);
+    };
+    let param = resolver.key();
+    let name = match (key_source, key_name) {
+        (KeySource::Path, None) => return Ok(None),
+        (KeySource::Path, Some(name)) => {
+            return Err(ProxyError::InvalidRoute(format!(
+                "Key name is only used with the query and header key sources: {name}"
+            )));
+        }
+        (_, None) => {
+            return Err(ProxyError::InvalidRoute(format!(
+                "Key source {key_source:?} requires a key name"
+            )));
+        }
+        (_, Some(name)) => name,
+    };
+    let captured_elsewhere = path_params.contains(&param)
+        || headers
+            .iter()
+            .any(|header| header.param.as_deref() == Some(param));
+    if captured_elsewhere {
+        return Err(ProxyError::InvalidRoute(format!(
+            "Resolver key is also captured as a route parameter: {param}"
+        )));
+    }
+    Ok(Some(match key_source {
+        KeySource::Header => ResolverKey::Header {
+            name: HeaderName::try_from(name)
+                .map_err(|_| ProxyError::InvalidRoute(format!("Invalid header name: {name}")))?,
+            param,
+        },
+        _ => ResolverKey::Query {
+            name: name.clone(),
+            param,
+        },
+    }))
+}
+
 pub struct RouteActions {
     routes: Vec<Route>,
 }
 
 impl RouteActions {
     pub fn try_new(route_config: Vec<RouteConfig>) -> Result<Self, ProxyError> {
-        let routes: Vec<Route> = route_config
-            .into_iter()
-            .map(Route::try_from)
-            .collect::<Result<_, _>>()?;
+        Self::try_new_layered(vec![route_config])
+    }
+
+    /// Routes of a layer are all tried before the routes of the following layers, whatever
+    /// their priority.
+    pub fn try_new_layered(layers: Vec<Vec<RouteConfig>>) -> Result<Self, ProxyError> {
+        let mut routes: Vec<Route> = Vec::new();
+        for layer in layers {
+            let mut layer: Vec<Route> = layer
+                .into_iter()
+                .map(Route::try_from)
+                .collect::<Result<_, _>>()?;
+
+            // Stable, routes of equal priority keep their configured order
+            layer.sort_by_key(|route| std::cmp::Reverse(route.priority));
+            routes.extend(layer);
+        }
+
+        for (i, route) in routes.iter().enumerate() {
+            if let Some(shadowing) = routes[..i].iter().find(|earlier| earlier.shadows(route)) {
+                tracing::warn!(
+                    pattern = %route.pattern,
+                    host = ?route.host,
+                    shadowed_by = %shadowing.pattern,
+                    shadowed_by_host = ?shadowing.host,
+                    "Route is unreachable, an earlier route matches all of its requests"
+                );
+            }
+        }
 
         Ok(Self { routes })
     }
-    /// Matches the incoming request to a route, and returns the first matched route if any.
-    /// If no matches are found, return none.
-    pub fn resolve<B>(&self, request: &http::Request<B>) -> Option<RouteMatch> {
+
+    /// The routes in the order they are tried
+    pub fn table(&self) -> Vec<RouteEntry> {
+        self.routes
+            .iter()
+            .map(|route| RouteEntry {
+                host: route.host.clone(),
+                path: route.pattern.to_string(),
+                headers: route
+                    .headers
+                    .iter()
+                    .map(|condition| HeaderMatch {
+                        name: condition.name.to_string(),
+                        value: condition
+                            .value
+                            .as_ref()
+                            .map(|value| String::from_utf8_lossy(value.as_bytes()).into_owned()),
+                        param: condition.param.clone(),
+                    })
+                    .collect(),
+                flag: route.flag.clone(),
+                active: route.active.clone(),
+                priority: route.priority,
+                action: route.action.clone(),
+            })
+            .collect()
+    }
+
+    /// Matches the incoming request against the routes, and returns the matched routes in order,
+    /// up to and including the first one that is not gated by a feature flag. The first of these
+    /// whose flag is enabled should be used.
+    /// If no matches are found, return an empty list.
+    pub fn resolve<B>(&self, request: &http::Request<B>) -> Vec<RouteMatch> {
         tracing::debug!("Resolving route for request URI: {:?}", request.uri());
 
         // Host may come from authority part of URI (if absolute-form request)
@@ -191,16 +612,37 @@ impl RouteActions {
         tracing::debug!("Request path: {path}");
         tracing::debug!("Request query: {query:?}");
 
-        // Return the first matching route, if any
-        self.routes
+        let now = Utc::now();
+
+        // Gated routes may be skipped, so collect matches until one is guaranteed to apply
+        let mut matches = Vec::new();
+        for route_match in self
+            .routes
             .iter()
-            .find_map(|route| route.matches(host, path))
+            .filter(|route| route.is_active(now))
+            .filter_map(|route| {
+                let mut route_match = route.matches(host, path)?;
+                if !route.matches_headers(request.headers(), &mut route_match.params) {
+                    return None;
+                }
+                route.extract_resolver_key(query, request.headers(), &mut route_match.params);
+                Some(route_match)
+            })
+        {
+            let gated = route_match.flag.is_some();
+            matches.push(route_match);
+            if !gated {
+                break;
+            }
+        }
+        matches
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::config::ResponseHeaders;
 
     #[test]
     fn test_host_only() {
@@ -208,10 +650,22 @@ mod tests {
             r#match: crate::config::Match {
                 host: Some("sentry.io".to_string()),
                 path: None,
+                flag: None,
+                active: None,
+                headers: vec![],
             },
             action: crate::config::Action::Static {
                 to: "upstream".to_string(),
             },
+            response_headers: None,
+            client_ips: None,
+            strip_trailers: false,
+            headers: None,
+            capture: None,
+            timeout_ms: None,
+            retries: None,
+            methods: None,
+            priority: 0,
         };
 
         let route = Route::try_from(config).unwrap();
@@ -226,10 +680,22 @@ mod tests {
             r#match: crate::config::Match {
                 host: None,
                 path: Some("/api/test/".to_string()),
+                flag: None,
+                active: None,
+                headers: vec![],
             },
             action: crate::config::Action::Static {
                 to: "upstream".to_string(),
             },
+            response_headers: None,
+            client_ips: None,
+            strip_trailers: false,
+            headers: None,
+            capture: None,
+            timeout_ms: None,
+            retries: None,
+            methods: None,
+            priority: 0,
         };
 
         let route = Route::try_from(config).unwrap();
@@ -254,10 +720,22 @@ mod tests {
             r#match: crate::config::Match {
                 host: None,
                 path: Some("/api/test/*".to_string()),
+                flag: None,
+                active: None,
+                headers: vec![],
             },
             action: crate::config::Action::Static {
                 to: "upstream".to_string(),
             },
+            response_headers: None,
+            client_ips: None,
+            strip_trailers: false,
+            headers: None,
+            capture: None,
+            timeout_ms: None,
+            retries: None,
+            methods: None,
+            priority: 0,
         };
 
         let route = Route::try_from(config).unwrap();
@@ -276,10 +754,22 @@ mod tests {
             r#match: crate::config::Match {
                 host: None,
                 path: Some("/api/*/test".to_string()),
+                flag: None,
+                active: None,
+                headers: vec![],
             },
             action: crate::config::Action::Static {
                 to: "upstream".to_string(),
             },
+            response_headers: None,
+            client_ips: None,
+            strip_trailers: false,
+            headers: None,
+            capture: None,
+            timeout_ms: None,
+            retries: None,
+            methods: None,
+            priority: 0,
         };
         assert!(
             Route::try_from(config).is_err(),
@@ -291,10 +781,22 @@ mod tests {
             r#match: crate::config::Match {
                 host: None,
                 path: Some("/api/*/*".to_string()),
+                flag: None,
+                active: None,
+                headers: vec![],
             },
             action: crate::config::Action::Static {
                 to: "upstream".to_string(),
             },
+            response_headers: None,
+            client_ips: None,
+            strip_trailers: false,
+            headers: None,
+            capture: None,
+            timeout_ms: None,
+            retries: None,
+            methods: None,
+            priority: 0,
         };
         assert!(
             Route::try_from(config).is_err(),
@@ -306,10 +808,22 @@ mod tests {
             r#match: crate::config::Match {
                 host: None,
                 path: Some("/api/test*/more".to_string()),
+                flag: None,
+                active: None,
+                headers: vec![],
             },
             action: crate::config::Action::Static {
                 to: "upstream".to_string(),
             },
+            response_headers: None,
+            client_ips: None,
+            strip_trailers: false,
+            headers: None,
+            capture: None,
+            timeout_ms: None,
+            retries: None,
+            methods: None,
+            priority: 0,
         };
         assert!(
             Route::try_from(config).is_err(),
@@ -321,10 +835,22 @@ mod tests {
             r#match: crate::config::Match {
                 host: None,
                 path: Some("/api/**".to_string()),
+                flag: None,
+                active: None,
+                headers: vec![],
             },
             action: crate::config::Action::Static {
                 to: "upstream".to_string(),
             },
+            response_headers: None,
+            client_ips: None,
+            strip_trailers: false,
+            headers: None,
+            capture: None,
+            timeout_ms: None,
+            retries: None,
+            methods: None,
+            priority: 0,
         };
         assert!(
             Route::try_from(config).is_err(),
@@ -336,10 +862,22 @@ mod tests {
             r#match: crate::config::Match {
                 host: None,
                 path: Some("/api/{*splat}".to_string()),
+                flag: None,
+                active: None,
+                headers: vec![],
             },
             action: crate::config::Action::Static {
                 to: "upstream".to_string(),
             },
+            response_headers: None,
+            client_ips: None,
+            strip_trailers: false,
+            headers: None,
+            capture: None,
+            timeout_ms: None,
+            retries: None,
+            methods: None,
+            priority: 0,
         };
         assert!(
             Route::try_from(config).is_err(),
@@ -353,12 +891,26 @@ mod tests {
             r#match: crate::config::Match {
                 host: None,
                 path: Some("/api/users/{user_id}".to_string()),
+                flag: None,
+                active: None,
+                headers: vec![],
             },
             action: crate::config::Action::Dynamic {
                 resolver: crate::config::Resolver::CellFromId,
                 cell_to_upstream: HashMap::new(),
                 default: None,
+                key_source: crate::config::KeySource::Path,
+                key_name: None,
             },
+            response_headers: None,
+            client_ips: None,
+            strip_trailers: false,
+            headers: None,
+            capture: None,
+            timeout_ms: None,
+            retries: None,
+            methods: None,
+            priority: 0,
         };
 
         let route = Route::try_from(config.clone()).unwrap();
@@ -366,8 +918,18 @@ mod tests {
         assert_eq!(
             route.matches(None, "/api/users/123"),
             Some(RouteMatch {
+                pattern: Arc::from("/api/users/{user_id}"),
                 params: HashMap::from([("user_id".to_string(), "123".to_string())]),
                 action: config.action.clone(),
+                flag: None,
+                header_filter: Default::default(),
+                ip_filter: None,
+                strip_trailers: false,
+                header_rewriter: None,
+                capture: None,
+                timeout: None,
+                retry: None,
+                methods: None,
             })
         );
     }
@@ -381,12 +943,26 @@ mod tests {
             r#match: crate::config::Match {
                 host: None,
                 path: Some("/organization-avatar/{organization}/{avatar_id}".to_string()),
+                flag: None,
+                active: None,
+                headers: vec![],
             },
             action: crate::config::Action::Dynamic {
                 resolver: crate::config::Resolver::CellFromOrganization,
                 cell_to_upstream: HashMap::new(),
                 default: None,
+                key_source: crate::config::KeySource::Path,
+                key_name: None,
             },
+            response_headers: None,
+            client_ips: None,
+            strip_trailers: false,
+            headers: None,
+            capture: None,
+            timeout_ms: None,
+            retries: None,
+            methods: None,
+            priority: 0,
         };
 
         let route = Route::try_from(config.clone()).unwrap();
@@ -394,11 +970,21 @@ mod tests {
         assert_eq!(
             route.matches(None, "/organization-avatar/my-org/abc123/"),
             Some(RouteMatch {
+                pattern: Arc::from("/organization-avatar/{organization}/{avatar_id}"),
                 params: HashMap::from([
                     ("organization".to_string(), "my-org".to_string()),
                     ("avatar_id".to_string(), "abc123".to_string()),
                 ]),
                 action: config.action.clone(),
+                flag: None,
+                header_filter: Default::default(),
+                ip_filter: None,
+                strip_trailers: false,
+                header_rewriter: None,
+                capture: None,
+                timeout: None,
+                retry: None,
+                methods: None,
             }),
             "captures the slug as `organization`, not the avatar id"
         );
@@ -412,4 +998,490 @@ mod tests {
             "deprecated slug-less form must not match the slug locator"
         );
     }
+
+    #[test]
+    fn test_resolve_flagged_routes() {
+        let route = |path: &str, flag: Option<&str>, to: &str| RouteConfig {
+            r#match: crate::config::Match {
+                host: None,
+                path: Some(path.to_string()),
+                flag: flag.map(String::from),
+                active: None,
+                headers: vec![],
+            },
+            action: crate::config::Action::Static { to: to.to_string() },
+            response_headers: None,
+            client_ips: None,
+            strip_trailers: false,
+            headers: None,
+            capture: None,
+            timeout_ms: None,
+            retries: None,
+            methods: None,
+            priority: 0,
+        };
+
+        let route_actions = RouteActions::try_new(vec![
+            route("/api/*", Some("first_flag"), "first"),
+            route("/other", None, "other"),
+            route("/api/*", Some("second_flag"), "second"),
+            route("/api/*", None, "fallback"),
+            route("/api/*", None, "unreachable"),
+        ])
+        .unwrap();
+
+        let request = http::Request::builder()
+            .uri("http://example.com/api/0/")
+            .body(())
+            .unwrap();
+
+        // Gated matches are returned up to the first ungated one
+        let targets: Vec<_> = route_actions
+            .resolve(&request)
+            .into_iter()
+            .map(|m| match m.action {
+                Action::Static { to } => to,
+                Action::Dynamic { .. } => unreachable!(),
+            })
+            .collect();
+        assert_eq!(targets, vec!["first", "second", "fallback"]);
+    }
+
+    #[test]
+    fn test_route_order() {
+        let route = |path: &str, to: &str, priority: i32| -> RouteConfig {
+            serde_yaml::from_str(&format!(
+                "match:\n  path: {path}\naction:\n  to: {to}\npriority: {priority}\n"
+            ))
+            .unwrap()
+        };
+        let target = |routes: Vec<RouteConfig>, uri: &str| {
+            let request = http::Request::builder().uri(uri).body(()).unwrap();
+            match RouteActions::try_new(routes)
+                .unwrap()
+                .resolve(&request)
+                .pop()
+                .map(|m| m.action)
+            {
+                Some(Action::Static { to }) => to,
+                _ => unreachable!(),
+            }
+        };
+
+        // Routes of equal priority are tried in their configured order
+        let routes = vec![
+            route("/api/0/", "exact", 0),
+            route("/api/0/*", "prefix", 0),
+            route("/api/*", "splat", 0),
+        ];
+        assert_eq!(target(routes.clone(), "http://example.com/api/0/"), "exact");
+        assert_eq!(
+            target(routes.clone(), "http://example.com/api/0/x/"),
+            "prefix"
+        );
+        assert_eq!(target(routes, "http://example.com/api/1/"), "splat");
+        let routes = vec![route("/api/*", "splat", 0), route("/api/0/", "exact", 0)];
+        assert_eq!(target(routes, "http://example.com/api/0/"), "splat");
+
+        // Higher priority routes are tried first, whatever their order
+        let routes = vec![route("/api/*", "splat", 0), route("/api/0/", "exact", 1)];
+        assert_eq!(target(routes, "http://example.com/api/0/"), "exact");
+    }
+
+    #[test]
+    fn test_table() {
+        let routes: Vec<RouteConfig> = serde_yaml::from_str(
+            r#"
+- match: {path: "/api/*"}
+  action: {to: main}
+- match:
+    host: us.sentry.io
+    path: /api/0/
+    headers: [{name: X-Region, value: us}]
+  action: {to: us}
+"#,
+        )
+        .unwrap();
+        let table = RouteActions::try_new(routes).unwrap().table();
+
+        // In the order the routes are tried
+        assert_eq!(table.len(), 2);
+        assert_eq!(table[0].path, "/api/*");
+        assert_eq!(table[0].action, Action::Static { to: "main".into() });
+        assert_eq!(table[1].host.as_deref(), Some("us.sentry.io"));
+        assert_eq!(table[1].path, "/api/0/");
+        assert_eq!(
+            table[1].headers,
+            vec![HeaderMatch {
+                name: "x-region".into(),
+                value: Some("us".into()),
+                param: None,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_layered_routes() {
+        let route = |path: &str, to: &str, priority: i32| -> RouteConfig {
+            serde_yaml::from_str(&format!(
+                "match: {{path: \"{path}\"}}\naction: {{to: {to}}}\npriority: {priority}"
+            ))
+            .unwrap()
+        };
+        let route_actions = RouteActions::try_new_layered(vec![
+            vec![route("/api/*", "internal", 0)],
+            vec![
+                route("/api/0/projects/", "main", 10),
+                route("/health/", "main", 0),
+            ],
+        ])
+        .unwrap();
+
+        let target = |path: &str| {
+            let request = http::Request::builder().uri(path).body(()).unwrap();
+            route_actions
+                .resolve(&request)
+                .pop()
+                .map(|route_match| match route_match.action {
+                    Action::Static { to } => to,
+                    Action::Dynamic { .. } => unreachable!(),
+                })
+        };
+        // The first layer wins over higher priority routes of the next
+        assert_eq!(target("/api/0/projects/").as_deref(), Some("internal"));
+        assert_eq!(target("/health/").as_deref(), Some("main"));
+        assert_eq!(target("/other/"), None);
+    }
+
+    #[test]
+    fn test_shadowed_routes() {
+        let route = |yaml: &str| {
+            Route::try_from(serde_yaml::from_str::<RouteConfig>(yaml).unwrap()).unwrap()
+        };
+        let splat = route("match:\n  path: /api/*\naction:\n  to: a\n");
+        let param = route(
+            "match:\n  path: /api/{org}/\naction:\n  resolver: cell_from_id\n  cell_to_upstream: {}\n",
+        );
+        let exact = route("match:\n  path: /api/0/\naction:\n  to: a\n");
+        let host = route("match:\n  host: us.sentry.io\naction:\n  to: a\n");
+        let flagged = route("match:\n  path: /api/*\n  flag: f\naction:\n  to: a\n");
+
+        assert!(splat.shadows(&param));
+        assert!(splat.shadows(&exact));
+        assert!(param.shadows(&exact));
+        assert!(!exact.shadows(&param));
+        assert!(!param.shadows(&splat));
+        assert!(!host.shadows(&exact));
+        assert!(!flagged.shadows(&exact));
+    }
+
+    #[test]
+    fn test_active_window() {
+        let now = Utc::now();
+        let hour = chrono::Duration::hours(1);
+        let route = |active: Option<ActiveWindow>, to: &str| RouteConfig {
+            r#match: crate::config::Match {
+                host: None,
+                path: None,
+                flag: None,
+                active,
+                headers: vec![],
+            },
+            action: crate::config::Action::Static { to: to.to_string() },
+            response_headers: None,
+            client_ips: None,
+            strip_trailers: false,
+            headers: None,
+            capture: None,
+            timeout_ms: None,
+            retries: None,
+            methods: None,
+            priority: 0,
+        };
+        let window = |start, end| Some(ActiveWindow { start, end });
+
+        let request = http::Request::builder()
+            .uri("http://example.com/api/0/")
+            .body(())
+            .unwrap();
+        let target = |routes| {
+            let route_actions = RouteActions::try_new(routes).unwrap();
+            match route_actions.resolve(&request).pop().map(|m| m.action) {
+                Some(Action::Static { to }) => to,
+                _ => unreachable!(),
+            }
+        };
+
+        // Before the cutover
+        assert_eq!(
+            target(vec![
+                route(window(Some(now + hour), None), "new"),
+                route(None, "old"),
+            ]),
+            "old"
+        );
+        // After the cutover
+        assert_eq!(
+            target(vec![
+                route(window(Some(now - hour), None), "new"),
+                route(None, "old"),
+            ]),
+            "new"
+        );
+        // The window has ended
+        assert_eq!(
+            target(vec![
+                route(window(Some(now - hour * 2), Some(now - hour)), "new"),
+                route(None, "old"),
+            ]),
+            "old"
+        );
+
+        // Windows that end before they start are rejected
+        assert!(matches!(
+            RouteActions::try_new(vec![route(window(Some(now), Some(now - hour)), "new")]),
+            Err(ProxyError::InvalidRoute(_))
+        ));
+    }
+
+    #[test]
+    fn test_active_window_config() {
+        let config: RouteConfig = serde_yaml::from_str(
+            r#"
+match:
+  path: /api/*
+  active:
+    start: "2026-03-01T02:00:00Z"
+action:
+  to: us2
+"#,
+        )
+        .unwrap();
+
+        let active = config.r#match.active.unwrap();
+        assert_eq!(
+            active.start,
+            Some(
+                DateTime::parse_from_rfc3339("2026-03-01T02:00:00Z")
+                    .unwrap()
+                    .into()
+            )
+        );
+        assert_eq!(active.end, None);
+    }
+
+    #[test]
+    fn test_header_match() {
+        let routes: Vec<RouteConfig> = serde_yaml::from_str(
+            r#"
+- match:
+    path: /api/{organization}/
+    headers:
+      - name: X-Sentry-Relay-Id
+        param: relay_id
+      - name: x-tenant
+        value: acme
+  action:
+    resolver: cell_from_organization
+    cell_to_upstream: {}
+- match:
+    headers:
+      - name: X-Sentry-Relay-Id
+  action:
+    to: relays
+- match: {}
+  action:
+    to: default
+"#,
+        )
+        .unwrap();
+        let route_actions = RouteActions::try_new(routes).unwrap();
+
+        let resolve = |headers: &[(&str, &str)]| {
+            let mut request = http::Request::builder().uri("http://example.com/api/sentry/");
+            for (name, value) in headers {
+                request = request.header(*name, *value);
+            }
+            route_actions
+                .resolve(&request.body(()).unwrap())
+                .pop()
+                .unwrap()
+        };
+
+        let route_match = resolve(&[("x-sentry-relay-id", "relay-1"), ("X-Tenant", "acme")]);
+        assert_eq!(route_match.params["organization"], "sentry");
+        assert_eq!(route_match.params["relay_id"], "relay-1");
+
+        // Wrong value
+        let route_match = resolve(&[("x-sentry-relay-id", "relay-1"), ("x-tenant", "other")]);
+        assert_eq!(
+            route_match.action,
+            Action::Static {
+                to: "relays".to_string()
+            }
+        );
+
+        // Missing header
+        let route_match = resolve(&[]);
+        assert_eq!(
+            route_match.action,
+            Action::Static {
+                to: "default".to_string()
+            }
+        );
+    }
+
+    #[test]
+    fn test_header_match_invalid() {
+        let route = |path: &str, header: &str| -> RouteConfig {
+            serde_yaml::from_str(&format!(
+                r#"
+match:
+  path: {path}
+  headers:
+    - {header}
+action:
+  resolver: cell_from_id
+  cell_to_upstream: {{}}
+"#
+            ))
+            .unwrap()
+        };
+
+        assert!(Route::try_from(route("/api/", "{name: x-tenant, param: tenant}")).is_ok());
+        assert!(Route::try_from(route("/api/", "{name: \"bad header\"}")).is_err());
+        assert!(Route::try_from(route("/api/", "{name: x-tenant, param: Tenant}")).is_err());
+        assert!(
+            Route::try_from(route("/api/{tenant}/", "{name: x-tenant, param: tenant}")).is_err()
+        );
+    }
+
+    #[test]
+    fn test_resolver_key_source() {
+        let routes: Vec<RouteConfig> = serde_yaml::from_str(
+            r#"
+- match:
+    path: /api/0/organizations/
+  action:
+    resolver: cell_from_organization
+    key_source: query
+    key_name: slug
+    cell_to_upstream: {}
+- match:
+    path: /api/0/events/*
+  action:
+    resolver: cell_from_id
+    key_source: header
+    key_name: X-Cell-Id
+    cell_to_upstream: {}
+"#,
+        )
+        .unwrap();
+        let route_actions = RouteActions::try_new(routes).unwrap();
+
+        let params = |request: http::Request<()>| route_actions.resolve(&request).remove(0).params;
+        let request = |uri: &str| http::Request::builder().uri(uri).body(()).unwrap();
+
+        assert_eq!(
+            params(request("/api/0/organizations/?cursor=1&slug=sentry")),
+            HashMap::from([("organization".to_string(), "sentry".to_string())])
+        );
+        // Names and values are percent-decoded, as in a form
+        assert_eq!(
+            params(request("/api/0/organizations/?sl%75g=my%2Dorg+name")),
+            HashMap::from([("organization".to_string(), "my-org name".to_string())])
+        );
+        // Requests without the key still match, without the parameter
+        assert!(params(request("/api/0/organizations/?slugs=sentry")).is_empty());
+        assert!(params(request("/api/0/organizations/?slug=")).is_empty());
+
+        let with_header = http::Request::builder()
+            .uri("/api/0/events/1/")
+            .header("x-cell-id", "us1")
+            .body(())
+            .unwrap();
+        assert_eq!(
+            params(with_header),
+            HashMap::from([("id".to_string(), "us1".to_string())])
+        );
+        assert!(params(request("/api/0/events/1/?id=us1")).is_empty());
+    }
+
+    #[test]
+    fn test_resolver_key_source_invalid() {
+        let route = |path: &str, key: &str| -> RouteConfig {
+            serde_yaml::from_str(&format!(
+                r#"
+match:
+  path: {path}
+action:
+  resolver: cell_from_organization
+  cell_to_upstream: {{}}
+  {key}
+"#
+            ))
+            .unwrap()
+        };
+
+        assert!(Route::try_from(route("/api/", "key_source: query\n  key_name: slug")).is_ok());
+        // Query and header sources need a name, the path source takes none
+        assert!(Route::try_from(route("/api/", "key_source: query")).is_err());
+        assert!(Route::try_from(route("/api/", "key_name: slug")).is_err());
+        assert!(
+            Route::try_from(route(
+                "/api/",
+                "key_source: header\n  key_name: \"bad header\""
+            ))
+            .is_err()
+        );
+        // The key cannot also be captured from the path
+        assert!(
+            Route::try_from(route(
+                "/api/{organization}/",
+                "key_source: query\n  key_name: slug"
+            ))
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn test_response_headers_config() {
+        let config: RouteConfig = serde_yaml::from_str(
+            r#"
+match:
+  path: /api/*
+action:
+  to: us1
+response_headers:
+  deny: [X-Cell-*]
+"#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.response_headers,
+            Some(ResponseHeaders::Deny {
+                deny: vec!["X-Cell-*".to_string()]
+            })
+        );
+
+        let route_actions = RouteActions::try_new(vec![config.clone()]).unwrap();
+        let request = http::Request::builder().uri("/api/").body(()).unwrap();
+        let route_match = route_actions.resolve(&request).pop().unwrap();
+        let mut headers = http::HeaderMap::from_iter([(
+            http::HeaderName::from_static("x-cell-id"),
+            http::HeaderValue::from_static("us1"),
+        )]);
+        route_match.header_filter.apply(&mut headers);
+        assert!(headers.is_empty());
+
+        // Invalid header names are rejected
+        let mut invalid = config;
+        invalid.response_headers = Some(ResponseHeaders::Allow {
+            allow: vec!["x cell".to_string()],
+        });
+        assert!(matches!(
+            RouteActions::try_new(vec![invalid]),
+            Err(ProxyError::InvalidRoute(_))
+        ));
+    }
 }
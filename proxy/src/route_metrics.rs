@@ -0,0 +1,69 @@
+//! Path cardinality of the routes.
+//!
+//! Request metrics are tagged with the pattern of the matched route rather than the
+//! concrete path, which keeps the number of tag values bounded by the route table. A route
+//! whose pattern is too broad, such as a splat in front of ids, still receives many
+//! distinct paths. The `route.distinct_paths` counter is incremented the first time a path
+//! is seen on a route, so its total is the path cardinality of the route. At most
+//! `MAX_TRACKED_PATHS` paths are tracked per route, the route is logged once above that.
+use crate::metrics_defs::ROUTE_DISTINCT_PATHS;
+use std::collections::{HashMap, HashSet};
+use std::hash::{BuildHasher, RandomState};
+use std::sync::{Arc, Mutex};
+
+const MAX_TRACKED_PATHS: usize = 1000;
+
+#[derive(Default)]
+pub struct RoutePaths {
+    hasher: RandomState,
+    // Hashes of the paths seen on each route pattern
+    routes: Mutex<HashMap<Arc<str>, HashSet<u64>>>,
+}
+
+impl RoutePaths {
+    /// Records a request to `path` matched by the route with `pattern`. Returns whether
+    /// the path was not seen on the route before.
+    pub fn record(&self, pattern: &Arc<str>, path: &str) -> bool {
+        let hash = self.hasher.hash_one(path);
+        let mut routes = self.routes.lock().unwrap_or_else(|e| e.into_inner());
+        let paths = routes.entry(pattern.clone()).or_default();
+
+        if paths.len() >= MAX_TRACKED_PATHS || !paths.insert(hash) {
+            return false;
+        }
+        if paths.len() == MAX_TRACKED_PATHS {
+            tracing::warn!(
+                route = %pattern,
+                "Route matched {MAX_TRACKED_PATHS} distinct paths, its pattern may be too broad"
+            );
+        }
+
+        metrics::counter!(ROUTE_DISTINCT_PATHS.name, "route" => pattern.to_string()).increment(1);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record() {
+        let route_paths = RoutePaths::default();
+        let users: Arc<str> = Arc::from("/api/users/{user_id}");
+        let splat: Arc<str> = Arc::from("/api/*");
+
+        assert!(route_paths.record(&users, "/api/users/1"));
+        assert!(!route_paths.record(&users, "/api/users/1"));
+        assert!(route_paths.record(&users, "/api/users/2"));
+        // Paths are counted per route
+        assert!(route_paths.record(&splat, "/api/users/1"));
+
+        // Tracking stops at the limit
+        for i in 1..MAX_TRACKED_PATHS {
+            assert!(route_paths.record(&splat, &format!("/api/events/{i}")));
+        }
+        assert!(!route_paths.record(&splat, "/api/events/new"));
+        assert!(route_paths.record(&users, "/api/users/3"));
+    }
+}
@@ -0,0 +1,234 @@
+//! Tracing of requests that did not match any route.
+//!
+//! The most recent unmatched requests are kept in a ring buffer and exposed on the admin
+//! listener. They can be replayed against a candidate route table to preview which routes
+//! they would match before the table is deployed.
+use crate::config::{Action, Route as RouteConfig, RouteTracing as RouteTracingConfig};
+use crate::errors::ProxyError;
+use crate::route_actions::{RouteActions, RouteMatch};
+use http::header::HOST;
+use http::{HeaderValue, Request};
+use serde::Serialize;
+use shared::http::redacted_headers;
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct RecordedRequest {
+    /// Unix timestamp in seconds
+    pub timestamp: u64,
+    pub method: String,
+    pub host: Option<String>,
+    pub path: String,
+    pub headers: Vec<(String, String)>,
+}
+
+impl RecordedRequest {
+    fn from_request<B>(request: &Request<B>) -> Self {
+        // Same as route resolution, the host is taken from the URI or the Host header
+        let host = request
+            .uri()
+            .host()
+            .or_else(|| request.headers().get(HOST).and_then(|h| h.to_str().ok()));
+
+        Self {
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+            method: request.method().to_string(),
+            host: host.map(String::from),
+            path: request.uri().path().to_string(),
+            headers: redacted_headers(request.headers()),
+        }
+    }
+
+    /// Rebuilds the parts of the request used for route matching.
+    fn to_request(&self) -> Request<()> {
+        let mut request = Request::new(());
+        if let Ok(uri) = self.path.parse() {
+            *request.uri_mut() = uri;
+        }
+        if let Some(host) = self
+            .host
+            .as_deref()
+            .and_then(|h| HeaderValue::from_str(h).ok())
+        {
+            request.headers_mut().insert(HOST, host);
+        }
+        request
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct ReplayResult {
+    pub request: RecordedRequest,
+    /// Matched routes in order, see `RouteActions::resolve`. Flags are not evaluated.
+    pub matches: Vec<ReplayMatch>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ReplayMatch {
+    /// Path pattern of the matched route
+    pub pattern: String,
+    pub action: Action,
+    pub params: HashMap<String, String>,
+    pub flag: Option<String>,
+}
+
+impl From<RouteMatch> for ReplayMatch {
+    fn from(route_match: RouteMatch) -> Self {
+        Self {
+            pattern: route_match.pattern.to_string(),
+            action: route_match.action,
+            params: route_match.params,
+            flag: route_match.flag,
+        }
+    }
+}
+
+pub struct UnmatchedRequests {
+    capacity: usize,
+    // Oldest first
+    requests: Mutex<VecDeque<RecordedRequest>>,
+}
+
+impl From<RouteTracingConfig> for UnmatchedRequests {
+    fn from(config: RouteTracingConfig) -> Self {
+        Self::new(config.max_requests)
+    }
+}
+
+impl UnmatchedRequests {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            requests: Mutex::new(VecDeque::with_capacity(capacity)),
+        }
+    }
+
+    pub fn record<B>(&self, request: &Request<B>) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        let recorded = RecordedRequest::from_request(request);
+        let mut requests = self.requests.lock().unwrap_or_else(|e| e.into_inner());
+        if requests.len() >= self.capacity {
+            requests.pop_front();
+        }
+        requests.push_back(recorded);
+    }
+
+    /// Recorded requests, oldest first
+    pub fn recorded(&self) -> Vec<RecordedRequest> {
+        let requests = self.requests.lock().unwrap_or_else(|e| e.into_inner());
+        requests.iter().cloned().collect()
+    }
+
+    /// Matches the recorded requests against the routes in `routes_yaml`, a list of routes
+    /// in the same format as the `routes` of the proxy config.
+    pub fn replay(&self, routes_yaml: &str) -> Result<Vec<ReplayResult>, ProxyError> {
+        let routes: Vec<RouteConfig> = serde_yaml::from_str(routes_yaml)
+            .map_err(|e| ProxyError::InvalidRoute(e.to_string()))?;
+        let route_actions = RouteActions::try_new(routes)?;
+
+        let results = self
+            .recorded()
+            .into_iter()
+            .map(|request| {
+                let matches = route_actions
+                    .resolve(&request.to_request())
+                    .into_iter()
+                    .map(ReplayMatch::from)
+                    .collect();
+                ReplayResult { request, matches }
+            })
+            .collect();
+
+        Ok(results)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(path: &str) -> Request<()> {
+        Request::builder()
+            .uri(path)
+            .header("host", "us.sentry.io")
+            .header("authorization", "Bearer secret")
+            .header("x-custom", "value")
+            .body(())
+            .unwrap()
+    }
+
+    #[test]
+    fn test_record() {
+        let unmatched = UnmatchedRequests::new(2);
+        for path in ["/first/", "/second/", "/third/"] {
+            unmatched.record(&request(path));
+        }
+
+        // The oldest request was dropped
+        let recorded = unmatched.recorded();
+        assert_eq!(recorded.len(), 2);
+        assert_eq!(recorded[0].path, "/second/");
+        assert_eq!(recorded[1].path, "/third/");
+
+        let request = &recorded[0];
+        assert_eq!(request.method, "GET");
+        assert_eq!(request.host.as_deref(), Some("us.sentry.io"));
+        assert!(
+            request
+                .headers
+                .contains(&("authorization".into(), "[redacted]".into()))
+        );
+        assert!(
+            request
+                .headers
+                .contains(&("x-custom".into(), "value".into()))
+        );
+    }
+
+    #[test]
+    fn test_replay() {
+        let unmatched = UnmatchedRequests::new(10);
+        unmatched.record(&request("/organizations/sentry/issues/"));
+        unmatched.record(&request("/other/"));
+
+        let results = unmatched
+            .replay(
+                r#"
+- match:
+    host: us.sentry.io
+    path: /organizations/{organization}/*
+  action:
+    resolver: cell_from_organization
+    cell_to_upstream:
+      us1: us1-upstream
+"#,
+            )
+            .unwrap();
+
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0].matches.len(), 1);
+        assert_eq!(
+            results[0].matches[0].params,
+            HashMap::from([("organization".into(), "sentry".into())])
+        );
+        assert!(results[1].matches.is_empty());
+
+        // Invalid route tables are rejected
+        assert!(matches!(
+            unmatched.replay("- match:\n    path: /api/*/test\n  action:\n    to: upstream\n"),
+            Err(ProxyError::InvalidRoute(_))
+        ));
+        assert!(matches!(
+            unmatched.replay("not a route table"),
+            Err(ProxyError::InvalidRoute(_))
+        ));
+    }
+}
@@ -0,0 +1,41 @@
+//! Mock servers for end-to-end tests of the proxy, see `shared::testutils`.
+use crate::config;
+use locator::client::Locator;
+
+pub use shared::testutils::{Echoed, MockServer, read_head};
+
+/// Upstream configuration pointing to a mock server
+pub trait MockUpstream {
+    fn upstream(&self, name: &str) -> config::UpstreamConfig;
+}
+
+impl MockUpstream for MockServer {
+    fn upstream(&self, name: &str) -> config::UpstreamConfig {
+        config::UpstreamConfig {
+            name: name.to_string(),
+            url: self.url(),
+            http1: None,
+            pool: None,
+            egress: None,
+            tls: None,
+            health_check: None,
+        }
+    }
+}
+
+/// Client of a locator API
+pub async fn locator_client(url: String) -> Locator {
+    Locator::new(
+        config::Locator {
+            r#type: config::LocatorType::Url {
+                url,
+                api_key: None,
+                datagram_addr: None,
+                cache: None,
+            },
+        }
+        .to_client_config(),
+    )
+    .await
+    .unwrap()
+}
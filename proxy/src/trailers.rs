@@ -0,0 +1,136 @@
+//! HTTP trailers of upstream responses.
+//!
+//! Trailer frames pass through the proxied body as they are, so HTTP/2 clients such as
+//! gRPC receive them unchanged. HTTP/1.1 only carries trailers in chunked bodies, if the
+//! request said with `TE: trailers` that the client accepts them and the response announces
+//! their fields in the `Trailer` header. Both headers are removed with the hop-by-hop
+//! headers, so they are restored on the proxied request and response. Chunk extensions are
+//! not meaningful to the proxy and are dropped by hyper when it decodes the chunks.
+//!
+//! Routes with `strip_trailers` remove trailers for legacy clients that cannot parse them.
+use http::header::{TE, TRAILER};
+use http::{HeaderMap, HeaderValue};
+use hyper::body::{Body, Frame, SizeHint};
+use std::pin::Pin;
+use std::task::{Context, Poll, ready};
+
+/// Whether the request accepts trailers. Checked before hop-by-hop headers are removed.
+pub fn accepts_trailers(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(TE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|coding| {
+            // Codings may carry a weight, e.g. `trailers;q=1`
+            let name = coding.split(';').next().unwrap_or_default().trim();
+            name.eq_ignore_ascii_case("trailers")
+        })
+}
+
+/// Announces trailers to the upstream on behalf of a client that accepts them.
+pub fn restore_te(headers: &mut HeaderMap) {
+    headers.insert(TE, HeaderValue::from_static("trailers"));
+}
+
+/// The trailer fields announced by the response. Taken before hop-by-hop headers are
+/// removed, and restored with `restore_announced`.
+pub fn announced(headers: &HeaderMap) -> Vec<HeaderValue> {
+    headers.get_all(TRAILER).iter().cloned().collect()
+}
+
+pub fn restore_announced(headers: &mut HeaderMap, announced: Vec<HeaderValue>) {
+    for value in announced {
+        headers.append(TRAILER, value);
+    }
+}
+
+/// Response body without its trailers.
+pub struct StripTrailers<B>(pub B);
+
+impl<B> Body for StripTrailers<B>
+where
+    B: Body + Unpin,
+{
+    type Data = B::Data;
+    type Error = B::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        loop {
+            match ready!(Pin::new(&mut self.0).poll_frame(cx)) {
+                Some(Ok(frame)) if frame.is_trailers() => continue,
+                frame => return Poll::Ready(frame),
+            }
+        }
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.0.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.0.size_hint()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http_body_util::BodyExt;
+    use hyper::body::Bytes;
+    use std::collections::VecDeque;
+    use std::convert::Infallible;
+
+    struct Frames(VecDeque<Frame<Bytes>>);
+
+    impl Body for Frames {
+        type Data = Bytes;
+        type Error = Infallible;
+
+        fn poll_frame(
+            mut self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+        ) -> Poll<Option<Result<Frame<Bytes>, Infallible>>> {
+            Poll::Ready(self.0.pop_front().map(Ok))
+        }
+    }
+
+    fn headers(te: &[&'static str]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for value in te {
+            headers.append(TE, HeaderValue::from_static(value));
+        }
+        headers
+    }
+
+    #[test]
+    fn test_accepts_trailers() {
+        assert!(accepts_trailers(&headers(&["trailers"])));
+        assert!(accepts_trailers(&headers(&["gzip, Trailers"])));
+        assert!(accepts_trailers(&headers(&["gzip", "trailers;q=1"])));
+        assert!(!accepts_trailers(&headers(&["gzip"])));
+        assert!(!accepts_trailers(&headers(&[])));
+    }
+
+    #[tokio::test]
+    async fn test_strip_trailers() {
+        let mut trailers = HeaderMap::new();
+        trailers.insert("grpc-status", HeaderValue::from_static("0"));
+        let frames = || {
+            Frames(VecDeque::from([
+                Frame::data(Bytes::from_static(b"ok")),
+                Frame::trailers(trailers.clone()),
+            ]))
+        };
+
+        let collected = frames().collect().await.unwrap();
+        assert_eq!(collected.trailers(), Some(&trailers));
+
+        let collected = StripTrailers(frames()).collect().await.unwrap();
+        assert_eq!(collected.trailers(), None);
+        assert_eq!(collected.to_bytes(), "ok");
+    }
+}
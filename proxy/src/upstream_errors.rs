@@ -0,0 +1,294 @@
+//! Categories of failed upstream requests, for faster triage of 502s.
+//!
+//! Every upstream request that fails without a response is logged with the category of its
+//! error, counted per upstream and category in `upstream.error`, and answered with a 502
+//! whose body and `X-Synapse-Upstream-Error` header name the category. Requests whose body
+//! could not be read from the client are not upstream failures: they are counted in
+//! `request.body_error` and answered with a 400.
+use crate::connector::DnsError;
+use crate::errors::ProxyError;
+use crate::metrics_defs::{REQUEST_BODY_ERRORS, UPSTREAM_ERRORS};
+use http::{HeaderValue, Response, StatusCode};
+use http_body_util::combinators::BoxBody;
+use http_body_util::{BodyExt, Full};
+use hyper::body::Bytes;
+use std::error::Error;
+use std::io;
+
+pub const ERROR_HEADER: &str = "x-synapse-upstream-error";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ErrorCategory {
+    /// The upstream's host name could not be resolved
+    Dns,
+    /// The connection was not established within the connect timeout
+    ConnectTimeout,
+    ConnectionRefused,
+    /// Any other failure to connect
+    Connect,
+    /// The TLS handshake failed, e.g. on an invalid certificate
+    Tls,
+    /// The connection was reset or closed before the response was complete
+    Reset,
+    /// HTTP/2 protocol error or stream reset
+    Http2,
+    Other,
+}
+
+impl ErrorCategory {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ErrorCategory::Dns => "dns",
+            ErrorCategory::ConnectTimeout => "connect_timeout",
+            ErrorCategory::ConnectionRefused => "connection_refused",
+            ErrorCategory::Connect => "connect",
+            ErrorCategory::Tls => "tls",
+            ErrorCategory::Reset => "reset",
+            ErrorCategory::Http2 => "http2",
+            ErrorCategory::Other => "other",
+        }
+    }
+
+    /// Category of a failed upstream request, from the chain of its error sources
+    pub fn of(error: &ProxyError) -> Self {
+        let mut connect = false;
+        let mut source: Option<&(dyn Error + 'static)> = Some(error);
+        while let Some(error) = source {
+            source = error.source();
+            if let Some(error) = error.downcast_ref::<hyper_util::client::legacy::Error>() {
+                connect |= error.is_connect();
+            }
+            if error.is::<DnsError>() {
+                return ErrorCategory::Dns;
+            }
+            if error.is::<rustls::Error>() {
+                return ErrorCategory::Tls;
+            }
+            if let Some(error) = error.downcast_ref::<h2::Error>() {
+                // Connection errors are categorized by their I/O error
+                match error.get_io() {
+                    Some(io) => source = Some(io),
+                    None => return ErrorCategory::Http2,
+                }
+            }
+            if let Some(error) = error.downcast_ref::<io::Error>() {
+                // The source of an I/O error is the source of its inner error, which is
+                // skipped otherwise
+                if error
+                    .get_ref()
+                    .is_some_and(|inner| inner.is::<rustls::Error>())
+                {
+                    return ErrorCategory::Tls;
+                }
+                match error.kind() {
+                    io::ErrorKind::TimedOut if connect => return ErrorCategory::ConnectTimeout,
+                    io::ErrorKind::ConnectionRefused => return ErrorCategory::ConnectionRefused,
+                    io::ErrorKind::ConnectionReset
+                    | io::ErrorKind::ConnectionAborted
+                    | io::ErrorKind::BrokenPipe
+                    | io::ErrorKind::UnexpectedEof => return ErrorCategory::Reset,
+                    _ => {}
+                }
+            }
+            if let Some(error) = error.downcast_ref::<hyper::Error>()
+                && (error.is_incomplete_message() || error.is_closed())
+            {
+                return ErrorCategory::Reset;
+            }
+        }
+
+        if connect {
+            ErrorCategory::Connect
+        } else {
+            ErrorCategory::Other
+        }
+    }
+}
+
+/// Whether the request failed because its body could not be read from the client
+fn is_request_body(error: &ProxyError) -> bool {
+    let mut source: Option<&(dyn Error + 'static)> = Some(error);
+    while let Some(error) = source {
+        if let Some(ProxyError::RequestBody(_)) = error.downcast_ref::<ProxyError>() {
+            return true;
+        }
+        source = error.source();
+    }
+    false
+}
+
+/// Response to a request of the upstream that failed without a response: a 400 if the
+/// request body could not be read from the client, a 502 otherwise.
+pub fn error_response<E>(error: &ProxyError, upstream: Option<&str>) -> Response<BoxBody<Bytes, E>>
+where
+    E: 'static,
+{
+    if !is_request_body(error) {
+        return bad_gateway(error, upstream);
+    }
+    tracing::warn!(upstream, "Failed to read the request body: {error}");
+    metrics::counter!(
+        REQUEST_BODY_ERRORS.name,
+        "upstream" => upstream.unwrap_or_default().to_string(),
+    )
+    .increment(1);
+    let mut response = Response::new(
+        Full::from("Bad Request: request body")
+            .map_err(|e| match e {})
+            .boxed(),
+    );
+    *response.status_mut() = StatusCode::BAD_REQUEST;
+    response
+}
+
+/// Logs and counts the failed request of the upstream, and returns the 502 it is answered
+/// with.
+fn bad_gateway<E>(error: &ProxyError, upstream: Option<&str>) -> Response<BoxBody<Bytes, E>>
+where
+    E: 'static,
+{
+    let category = ErrorCategory::of(error);
+    tracing::error!(
+        upstream,
+        category = category.as_str(),
+        "Upstream request failed: {error}"
+    );
+    metrics::counter!(
+        UPSTREAM_ERRORS.name,
+        "upstream" => upstream.unwrap_or_default().to_string(),
+        "category" => category.as_str(),
+    )
+    .increment(1);
+
+    let body = format!("Bad Gateway: {}", category.as_str());
+    let mut response = Response::new(Full::from(body).map_err(|e| match e {}).boxed());
+    *response.status_mut() = StatusCode::BAD_GATEWAY;
+    response
+        .headers_mut()
+        .insert(ERROR_HEADER, HeaderValue::from_static(category.as_str()));
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connector::http_connector;
+    use crate::testutils::MockServer;
+    use hyper::Request;
+    use hyper::body::{Body, Frame};
+    use hyper_util::client::legacy::Client;
+    use hyper_util::client::legacy::connect::HttpConnector;
+    use hyper_util::client::legacy::connect::dns::Name;
+    use hyper_util::rt::TokioExecutor;
+    use std::future::Ready;
+    use std::net::SocketAddr;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+    use tokio::io::AsyncWriteExt;
+    use tower_service::Service;
+
+    async fn request_error(uri: &str) -> ProxyError {
+        let client =
+            Client::builder(TokioExecutor::new()).build::<_, Full<Bytes>>(http_connector());
+        client.get(uri.parse().unwrap()).await.unwrap_err().into()
+    }
+
+    /// Resolver of no host name
+    #[derive(Clone)]
+    struct Unresolvable;
+
+    impl Service<Name> for Unresolvable {
+        type Response = std::vec::IntoIter<SocketAddr>;
+        type Error = DnsError;
+        type Future = Ready<Result<Self::Response, DnsError>>;
+
+        fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), DnsError>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, _: Name) -> Self::Future {
+            std::future::ready(Err(io::Error::other("no such host").into()))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_categories() {
+        // Nothing listens on the port
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        drop(listener);
+        let error = request_error(&format!("http://{addr}/")).await;
+        assert_eq!(ErrorCategory::of(&error), ErrorCategory::ConnectionRefused);
+
+        let client = Client::builder(TokioExecutor::new())
+            .build::<_, Full<Bytes>>(HttpConnector::new_with_resolver(Unresolvable));
+        let error: ProxyError = client
+            .get("http://upstream.test/".parse().unwrap())
+            .await
+            .unwrap_err()
+            .into();
+        assert_eq!(ErrorCategory::of(&error), ErrorCategory::Dns);
+
+        // The connection is closed without a response
+        let upstream = MockServer::spawn_tcp(|stream, _| async move { drop(stream) }).await;
+        let error = request_error(&format!("{}/", upstream.url())).await;
+        assert_eq!(ErrorCategory::of(&error), ErrorCategory::Reset);
+
+        // An HTTP/1.1 response to an HTTP/2 request is an invalid frame
+        let upstream = MockServer::spawn_tcp(|mut stream, _| async move {
+            let _ = stream.write_all(b"HTTP/1.1 200 OK\r\n\r\n").await;
+            std::future::pending::<()>().await;
+        })
+        .await;
+        let client = Client::builder(TokioExecutor::new())
+            .http2_only(true)
+            .build::<_, Full<Bytes>>(http_connector());
+        let error: ProxyError = client
+            .get(format!("{}/", upstream.url()).parse().unwrap())
+            .await
+            .unwrap_err()
+            .into();
+        assert_eq!(ErrorCategory::of(&error), ErrorCategory::Http2);
+
+        let tls = io::Error::new(io::ErrorKind::InvalidData, rustls::Error::DecryptError);
+        assert_eq!(ErrorCategory::of(&ProxyError::Io(tls)), ErrorCategory::Tls);
+        assert_eq!(
+            ErrorCategory::of(&ProxyError::InvalidUpstream),
+            ErrorCategory::Other
+        );
+    }
+
+    /// Body failing like a request body that could not be read from the client
+    struct FailingBody;
+
+    impl Body for FailingBody {
+        type Data = Bytes;
+        type Error = ProxyError;
+
+        fn poll_frame(
+            self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+        ) -> Poll<Option<Result<Frame<Bytes>, ProxyError>>> {
+            Poll::Ready(Some(Err(ProxyError::RequestBody("aborted".into()))))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_error_response() {
+        let upstream = MockServer::echo("upstream").await;
+        let client =
+            Client::builder(TokioExecutor::new()).build::<_, FailingBody>(http_connector());
+        let request = Request::post(format!("{}/", upstream.url()))
+            .body(FailingBody)
+            .unwrap();
+        let error: ProxyError = client.request(request).await.unwrap_err().into();
+        let response = error_response::<ProxyError>(&error, Some("upstream"));
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert!(response.headers().get(ERROR_HEADER).is_none());
+
+        let error = ProxyError::InvalidUpstream;
+        let response = error_response::<ProxyError>(&error, Some("upstream"));
+        assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+        assert_eq!(response.headers()[ERROR_HEADER], "other");
+    }
+}
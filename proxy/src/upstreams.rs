@@ -1,12 +1,19 @@
 use crate::config::UpstreamConfig;
 use crate::errors::ProxyError;
+use crate::upstream_client::UpstreamClient;
 use http::uri::{Authority, Scheme, Uri};
 use std::collections::HashMap;
+use std::sync::{OnceLock, RwLock};
+use std::time::Duration;
 
 #[derive(Debug, Clone)]
 pub struct Upstream {
     pub scheme: Scheme,
     pub authority: Authority,
+    /// Options of upstreams with HTTP/1.1, pool, egress or TLS options
+    pub client: Option<UpstreamClient>,
+    /// Connections older than this are not reused
+    pub max_lifetime: Option<Duration>,
 }
 
 impl TryFrom<UpstreamConfig> for Upstream {
@@ -16,13 +23,40 @@ impl TryFrom<UpstreamConfig> for Upstream {
         let scheme = uri.scheme().ok_or(ProxyError::InvalidUpstream)?.clone();
         let authority = uri.authority().ok_or(ProxyError::InvalidUpstream)?.clone();
 
-        Ok(Self { scheme, authority })
+        let client = (config.http1.is_some()
+            || config.pool.is_some()
+            || config.egress.is_some()
+            || config.tls.is_some())
+        .then(|| {
+            UpstreamClient::new(
+                config.http1.as_ref(),
+                config.pool.as_ref(),
+                config.egress.as_ref(),
+                config.tls.as_ref(),
+            )
+        })
+        .transpose()?;
+        let max_lifetime = config
+            .pool
+            .as_ref()
+            .and_then(|pool| pool.max_lifetime_secs)
+            .map(Duration::from_secs);
+
+        Ok(Self {
+            scheme,
+            authority,
+            client,
+            max_lifetime,
+        })
     }
 }
 
-#[derive(Clone)]
 pub struct Upstreams {
-    map: HashMap<String, Upstream>,
+    // Upstreams of the config, which cannot be replaced or removed at runtime
+    configured: HashMap<String, Upstream>,
+    // Upstreams added at runtime, only set once one is added so that proxies without
+    // registration never take the lock
+    registered: OnceLock<RwLock<HashMap<String, Upstream>>>,
 }
 
 impl Upstreams {
@@ -35,11 +69,55 @@ impl Upstreams {
             })
             .collect::<Result<HashMap<_, _>, _>>()?;
 
-        Ok(Upstreams { map: upstreams })
+        Ok(Upstreams {
+            configured: upstreams,
+            registered: OnceLock::new(),
+        })
+    }
+
+    pub fn get(&self, upstream: &str) -> Option<Upstream> {
+        if let Some(upstream) = self.configured.get(upstream) {
+            return Some(upstream.clone());
+        }
+        self.registered
+            .get()?
+            .read()
+            .unwrap()
+            .get(upstream)
+            .cloned()
+    }
+
+    /// Whether the upstream is one of the config
+    pub fn is_configured(&self, upstream: &str) -> bool {
+        self.configured.contains_key(upstream)
+    }
+
+    /// Adds an upstream at runtime, or replaces one added at runtime.
+    pub fn insert(&self, name: String, upstream: Upstream) -> Result<(), ProxyError> {
+        if self.is_configured(&name) {
+            return Err(ProxyError::UpstreamRegistration(format!(
+                "{name} is configured in the config file"
+            )));
+        }
+        self.registered
+            .get_or_init(RwLock::default)
+            .write()
+            .unwrap()
+            .insert(name, upstream);
+        Ok(())
     }
 
-    pub fn get(&self, upstream: &str) -> Option<&Upstream> {
-        self.map.get(upstream)
+    /// Removes an upstream added at runtime. Returns false if there is none of that name.
+    pub fn remove(&self, name: &str) -> Result<bool, ProxyError> {
+        if self.is_configured(name) {
+            return Err(ProxyError::UpstreamRegistration(format!(
+                "{name} is configured in the config file"
+            )));
+        }
+        Ok(self
+            .registered
+            .get()
+            .is_some_and(|registered| registered.write().unwrap().remove(name).is_some()))
     }
 }
 
@@ -52,11 +130,21 @@ mod tests {
         let valid_config = UpstreamConfig {
             name: "getsentry-us".into(),
             url: "http://1.1.1.1:80".into(),
+            http1: None,
+            pool: None,
+            egress: None,
+            tls: None,
+            health_check: None,
         };
 
         let invalid_config = UpstreamConfig {
             name: "getsentry-de".into(),
             url: "1.1.1.1:80".into(),
+            http1: None,
+            pool: None,
+            egress: None,
+            tls: None,
+            health_check: None,
         };
 
         let upstream = Upstream::try_from(valid_config).expect("Valid upstream should parse");
@@ -64,4 +152,30 @@ mod tests {
         assert_eq!(upstream.authority, "1.1.1.1:80");
         assert!(Upstream::try_from(invalid_config).is_err());
     }
+
+    #[test]
+    fn test_runtime_upstreams() {
+        let config = |name: &str, url: &str| UpstreamConfig {
+            name: name.into(),
+            url: url.into(),
+            http1: None,
+            pool: None,
+            egress: None,
+            tls: None,
+            health_check: None,
+        };
+        let upstreams = Upstreams::try_new(vec![config("us1", "http://1.1.1.1:80")]).unwrap();
+        let us2 = Upstream::try_from(config("us2", "http://2.2.2.2:80")).unwrap();
+
+        upstreams.insert("us2".into(), us2.clone()).unwrap();
+        assert_eq!(upstreams.get("us2").unwrap().authority, "2.2.2.2:80");
+        assert!(upstreams.remove("us2").unwrap());
+        assert!(!upstreams.remove("us2").unwrap());
+        assert!(upstreams.get("us2").is_none());
+
+        // Upstreams of the config are left alone
+        assert!(upstreams.insert("us1".into(), us2).is_err());
+        assert!(upstreams.remove("us1").is_err());
+        assert_eq!(upstreams.get("us1").unwrap().authority, "1.1.1.1:80");
+    }
 }
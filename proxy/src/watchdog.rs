@@ -0,0 +1,252 @@
+//! Slow request watchdog.
+//!
+//! Flags proxied requests whose total latency exceeds a configured threshold and logs a
+//! structured event with the timing breakdown. Since response bodies are streamed, the
+//! check runs once the body has been fully sent (or dropped by the client).
+//!
+//! When `report_to_sentry` is enabled the event is logged at error level, which the
+//! Sentry tracing layer turns into a Sentry event with the breakdown attached as fields.
+use crate::config::SlowRequestWatchdog as SlowRequestWatchdogConfig;
+use crate::metrics_defs::SLOW_REQUESTS;
+use http_body_util::BodyExt;
+use http_body_util::combinators::BoxBody;
+use hyper::body::{Body, Bytes, Frame, SizeHint};
+use hyper::{Method, Response};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+/// Timestamps collected while a request is being proxied.
+#[derive(Debug, Clone)]
+pub struct RequestTimings {
+    start: Instant,
+    resolved_at: Option<Instant>,
+    connect: Duration,
+    headers_at: Option<Instant>,
+}
+
+impl RequestTimings {
+    pub fn new(start: Instant) -> Self {
+        Self {
+            start,
+            resolved_at: None,
+            connect: Duration::ZERO,
+            headers_at: None,
+        }
+    }
+
+    /// Route and upstream resolution finished
+    pub fn resolved(&mut self, at: Instant) {
+        self.resolved_at = Some(at);
+    }
+
+    /// Time spent opening a new upstream connection for this request
+    pub fn connected(&mut self, connect: Duration) {
+        self.connect = connect;
+    }
+
+    /// Upstream response headers were received
+    pub fn headers_received(&mut self, at: Instant) {
+        self.headers_at = Some(at);
+    }
+
+    fn breakdown(&self, finished_at: Instant) -> Breakdown {
+        let resolved_at = self.resolved_at.unwrap_or(finished_at);
+        let headers_at = self.headers_at.unwrap_or(finished_at);
+
+        Breakdown {
+            total: finished_at - self.start,
+            resolve: resolved_at - self.start,
+            connect: self.connect,
+            ttfb: headers_at
+                .saturating_duration_since(resolved_at)
+                .saturating_sub(self.connect),
+            body: finished_at.saturating_duration_since(headers_at),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+struct Breakdown {
+    total: Duration,
+    resolve: Duration,
+    connect: Duration,
+    ttfb: Duration,
+    body: Duration,
+}
+
+#[derive(Clone, Debug)]
+pub struct SlowRequestWatchdog {
+    threshold: Duration,
+    report_to_sentry: bool,
+}
+
+impl From<SlowRequestWatchdogConfig> for SlowRequestWatchdog {
+    fn from(config: SlowRequestWatchdogConfig) -> Self {
+        Self {
+            threshold: Duration::from_millis(config.threshold_ms),
+            report_to_sentry: config.report_to_sentry,
+        }
+    }
+}
+
+impl SlowRequestWatchdog {
+    /// Wraps the response body so the request is checked once the body completes.
+    pub fn watch<E>(
+        &self,
+        response: Response<BoxBody<Bytes, E>>,
+        timings: RequestTimings,
+        method: Method,
+        path: String,
+        upstream: &str,
+    ) -> Response<BoxBody<Bytes, E>>
+    where
+        E: 'static,
+    {
+        let guard = SlowRequestGuard {
+            watchdog: self.clone(),
+            timings,
+            method,
+            path,
+            upstream: upstream.to_string(),
+            status: response.status().as_u16(),
+        };
+
+        response.map(|body| {
+            WatchedBody {
+                inner: body,
+                guard: Some(guard),
+            }
+            .boxed()
+        })
+    }
+}
+
+struct SlowRequestGuard {
+    watchdog: SlowRequestWatchdog,
+    timings: RequestTimings,
+    method: Method,
+    path: String,
+    upstream: String,
+    status: u16,
+}
+
+impl SlowRequestGuard {
+    fn finish(self, finished_at: Instant) {
+        let breakdown = self.timings.breakdown(finished_at);
+        if breakdown.total < self.watchdog.threshold {
+            return;
+        }
+
+        metrics::counter!(SLOW_REQUESTS.name, "upstream" => self.upstream.clone()).increment(1);
+
+        macro_rules! slow_request_event {
+            ($level:ident) => {
+                tracing::$level!(
+                    method = %self.method,
+                    path = %self.path,
+                    upstream = %self.upstream,
+                    status = self.status,
+                    total_ms = breakdown.total.as_millis() as u64,
+                    resolve_ms = breakdown.resolve.as_millis() as u64,
+                    connect_ms = breakdown.connect.as_millis() as u64,
+                    ttfb_ms = breakdown.ttfb.as_millis() as u64,
+                    body_ms = breakdown.body.as_millis() as u64,
+                    threshold_ms = self.watchdog.threshold.as_millis() as u64,
+                    "Slow request"
+                )
+            };
+        }
+
+        if self.watchdog.report_to_sentry {
+            slow_request_event!(error);
+        } else {
+            slow_request_event!(warn);
+        }
+    }
+}
+
+/// Response body that runs the slow request check once it completes or is dropped.
+struct WatchedBody<B> {
+    inner: B,
+    guard: Option<SlowRequestGuard>,
+}
+
+impl<B> WatchedBody<B> {
+    fn finish(&mut self) {
+        if let Some(guard) = self.guard.take() {
+            guard.finish(Instant::now());
+        }
+    }
+}
+
+impl<B> Body for WatchedBody<B>
+where
+    B: Body<Data = Bytes> + Unpin,
+{
+    type Data = Bytes;
+    type Error = B::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let poll = Pin::new(&mut self.inner).poll_frame(cx);
+        if let Poll::Ready(None) = poll {
+            self.finish();
+        }
+        poll
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+impl<B> Drop for WatchedBody<B> {
+    fn drop(&mut self) {
+        self.finish();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_breakdown() {
+        let start = Instant::now();
+        let mut timings = RequestTimings::new(start);
+        timings.resolved(start + Duration::from_millis(10));
+        timings.connected(Duration::from_millis(20));
+        timings.headers_received(start + Duration::from_millis(100));
+
+        assert_eq!(
+            timings.breakdown(start + Duration::from_millis(150)),
+            Breakdown {
+                total: Duration::from_millis(150),
+                resolve: Duration::from_millis(10),
+                connect: Duration::from_millis(20),
+                ttfb: Duration::from_millis(70),
+                body: Duration::from_millis(50),
+            }
+        );
+    }
+
+    #[test]
+    fn test_breakdown_without_upstream() {
+        // No upstream was reached, everything is attributed to resolution
+        let start = Instant::now();
+        let timings = RequestTimings::new(start);
+
+        let breakdown = timings.breakdown(start + Duration::from_millis(5));
+        assert_eq!(breakdown.total, Duration::from_millis(5));
+        assert_eq!(breakdown.resolve, Duration::from_millis(5));
+        assert_eq!(breakdown.ttfb, Duration::ZERO);
+        assert_eq!(breakdown.body, Duration::ZERO);
+    }
+}
@@ -22,7 +22,9 @@ import threading
 
 DEFAULT_PAGE_SIZE = 10
 TOTAL_RESULTS = 15
-START_TIME = int(time.time())
+# Rows are updated a minute apart, ending now
+UPDATE_INTERVAL = 60
+START_TIME = int(time.time()) - TOTAL_RESULTS * UPDATE_INTERVAL
 
 Results = list[dict[str, str]]
 Cursor = Optional[str]
@@ -35,6 +37,10 @@ CELL_TO_LOCALITY = {
     "de1": "de",
 }
 
+CELL_LABELS = {
+    "us1": {"region": "us-east1"},
+}
+
 
 def org_cell(i: int) -> str:
     if i % 5 == 4:
@@ -47,7 +53,7 @@ ALL_ORG_RESULTS = [
         "id": str(i),
         "slug": f"sentry{i}",
         "cell": org_cell(i),
-        "updated_at": START_TIME + i
+        "updated_at": START_TIME + i * UPDATE_INTERVAL
     }
     for i in range(TOTAL_RESULTS)
 ]
@@ -58,7 +64,7 @@ ALL_PROJECTKEY_RESULTS = [
         "id": i,
         "publickey": d * 32,
         "cell": f"us{i % 2 + 1}",
-        "updated_at": START_TIME + i
+        "updated_at": START_TIME + i * UPDATE_INTERVAL
     }
     for i, d in zip(range(TOTAL_RESULTS), itertools.cycle("0123456789abcdef"))
 ]
@@ -70,7 +76,10 @@ class EntityType(Enum):
 
 
 def get_results(
-    entity: EntityType, cursor: Optional[str], requested_localities: Optional[list[str]] = None
+    entity: EntityType,
+    cursor: Optional[str],
+    requested_localities: Optional[list[str]] = None,
+    page_size: int = DEFAULT_PAGE_SIZE,
 ) -> tuple[Results, Cursor, HasMore]:
 
     all_results = (
@@ -111,7 +120,7 @@ def get_results(
     if total == 0:
         return [], None, False
 
-    to_idx = min(from_idx + DEFAULT_PAGE_SIZE - 1, total - 1)
+    to_idx = min(from_idx + page_size - 1, total - 1)
 
     has_more = to_idx < total - 1
 
@@ -148,7 +157,10 @@ class MockControlApi(BaseHTTPRequestHandler):
         if base_path == "/api/0/internal/org-cell-mappings/":
             cursor = query_params.get("cursor", [None])[0]
             localities = query_params.get("locality") or None
-            (data, next_cursor, has_more) = get_results(EntityType.ORG, cursor, localities)
+            page_size = int(query_params.get("per_page", [DEFAULT_PAGE_SIZE])[0])
+            (data, next_cursor, has_more) = get_results(
+                EntityType.ORG, cursor, localities, page_size
+            )
 
             response = {
                 "data": data,
@@ -156,6 +168,7 @@ class MockControlApi(BaseHTTPRequestHandler):
                     "cursor": next_cursor,
                     "has_more": has_more,
                     "cell_to_locality": CELL_TO_LOCALITY,
+                    "cell_labels": CELL_LABELS,
                 },
             }
 
@@ -167,7 +180,10 @@ class MockControlApi(BaseHTTPRequestHandler):
         elif base_path == "/api/0/internal/projectkey-cell-mappings/":
             cursor = query_params.get("cursor", [None])[0]
             localities = query_params.get("locality") or None
-            (data, next_cursor, has_more) = get_results(EntityType.PROJECT_KEY, cursor, localities)
+            page_size = int(query_params.get("per_page", [DEFAULT_PAGE_SIZE])[0])
+            (data, next_cursor, has_more) = get_results(
+                EntityType.PROJECT_KEY, cursor, localities, page_size
+            )
 
             response = {
                 "data": data,
@@ -175,6 +191,7 @@ class MockControlApi(BaseHTTPRequestHandler):
                     "cursor": next_cursor,
                     "has_more": has_more,
                     "cell_to_locality": CELL_TO_LOCALITY,
+                    "cell_labels": CELL_LABELS,
                 },
             }
 
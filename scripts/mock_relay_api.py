@@ -172,6 +172,15 @@ class MockRelayHandler(BaseHTTPRequestHandler):
                 self._send_error_response(400, "Invalid JSON")
             except Exception as e:
                 self._send_error_response(500, str(e))
+        # Envelope endpoint, answers with the event id of the envelope header
+        elif parsed_path.path.startswith("/api/") and parsed_path.path.endswith("/envelope/"):
+            try:
+                content_length = int(self.headers.get('Content-Length', 0))
+                body = self.rfile.read(content_length)
+                header = json.loads(body.split(b"\n", 1)[0])
+                self._send_json_response(200, {"id": header.get("event_id")})
+            except json.JSONDecodeError:
+                self._send_error_response(400, "Invalid envelope header")
         else:
             self._send_error_response(404, "Not Found")
     
@@ -1,16 +1,93 @@
+use crate::constant_time;
 use crate::http::make_boxed_error_response;
+use http::HeaderValue;
+use http::header::{AUTHORIZATION, CONTENT_TYPE};
 use http_body_util::combinators::BoxBody;
-use http_body_util::{BodyExt, Full};
+use http_body_util::{BodyExt, Full, Limited};
 use hyper::body::{Bytes, Incoming};
 use hyper::service::Service;
 use hyper::{Request, Response, StatusCode};
+use serde::Serialize;
 use std::convert::Infallible;
 use std::future::Future;
 use std::marker::PhantomData;
 use std::pin::Pin;
+use std::sync::Arc;
+
+// Admin requests are small, larger bodies are rejected
+const MAX_BODY_BYTES: usize = 1024 * 1024;
+
+/// Response of an `AdminEndpoints` request
+pub type AdminResponse = Pin<Box<dyn Future<Output = Response<Bytes>> + Send + 'static>>;
+
+/// Endpoints served by the admin service besides the health and readiness checks, e.g. for
+/// debugging. Crates that the admin service cannot depend on provide them this way.
+pub trait AdminEndpoints: Send + Sync {
+    /// The response to the request, None if it is not for one of the endpoints
+    fn call(&self, request: &Request<Bytes>) -> Option<AdminResponse>;
+}
+
+/// JSON response of an admin endpoint, a 500 if the value cannot be serialized
+pub fn json_response<T: Serialize>(status: StatusCode, value: &T) -> Response<Bytes> {
+    let (status, body, content_type) = match serde_json::to_vec(value) {
+        Ok(body) => (status, body, "application/json"),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("{e}\n").into_bytes(),
+            "text/plain",
+        ),
+    };
+    let mut response = Response::new(Bytes::from(body));
+    *response.status_mut() = status;
+    response
+        .headers_mut()
+        .insert(CONTENT_TYPE, HeaderValue::from_static(content_type));
+    response
+}
+
+/// Whether the request is a health or readiness probe. Probes cannot send credentials,
+/// so they are exempt from the checks of admin requests.
+pub fn is_probe<B>(request: &Request<B>) -> bool {
+    matches!(request.uri().path(), "/health" | "/ready")
+}
+
+/// Tokens of which admin requests must send one as `Authorization: Bearer <token>`.
+/// Several tokens allow rotating them.
+#[derive(Debug)]
+pub struct BearerTokens(Vec<String>);
+
+impl BearerTokens {
+    /// Fails if one of the tokens is empty
+    pub fn new(tokens: Vec<String>) -> Result<Self, String> {
+        if tokens.iter().any(|token| token.is_empty()) {
+            return Err("empty bearer token".to_string());
+        }
+        Ok(Self(tokens))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Whether the request sends one of the tokens, compared in constant time
+    pub fn allows<B>(&self, request: &Request<B>) -> bool {
+        let token = request
+            .headers()
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+        token.is_some_and(|token| {
+            self.0
+                .iter()
+                .any(|expected| constant_time::eq(expected.as_bytes(), token.as_bytes()))
+        })
+    }
+}
 
 pub struct AdminService<F, E> {
     is_ready: F,
+    endpoints: Vec<Arc<dyn AdminEndpoints>>,
+    bearer_tokens: Option<Arc<BearerTokens>>,
     _error: PhantomData<E>,
 }
 
@@ -21,9 +98,23 @@ where
     pub fn new(is_ready: F) -> Self {
         Self {
             is_ready,
+            endpoints: Vec::new(),
+            bearer_tokens: None,
             _error: PhantomData,
         }
     }
+
+    /// Serves the endpoints as well. Endpoints are tried in the order they are added.
+    pub fn with_endpoints(mut self, endpoints: Arc<dyn AdminEndpoints>) -> Self {
+        self.endpoints.push(endpoints);
+        self
+    }
+
+    /// Rejects requests other than probes with 401 unless they send one of the tokens.
+    pub fn with_bearer_tokens(mut self, bearer_tokens: BearerTokens) -> Self {
+        self.bearer_tokens = Some(Arc::new(bearer_tokens));
+        self
+    }
 }
 
 impl<F, E> Service<Request<Incoming>> for AdminService<F, E>
@@ -37,11 +128,33 @@ where
         Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send + 'static>>;
 
     fn call(&self, req: Request<Incoming>) -> Self::Future {
+        if let Some(bearer_tokens) = &self.bearer_tokens
+            && !is_probe(&req)
+            && !bearer_tokens.allows(&req)
+        {
+            tracing::info!(
+                method = %req.method(),
+                path = req.uri().path(),
+                "Rejected admin request without a valid bearer token"
+            );
+            return Box::pin(async { Ok(make_boxed_error_response(StatusCode::UNAUTHORIZED)) });
+        }
         let is_ready = (self.is_ready)();
+        let endpoints = self.endpoints.clone();
 
         Box::pin(async move {
             let ok_body = || Full::new(Bytes::from("ok\n")).boxed();
 
+            let (parts, body) = req.into_parts();
+            let body = match Limited::new(body, MAX_BODY_BYTES).collect().await {
+                Ok(body) => body.to_bytes(),
+                Err(_) => return Ok(make_boxed_error_response(StatusCode::BAD_REQUEST)),
+            };
+            let req = Request::from_parts(parts, body);
+            if let Some(response) = endpoints.iter().find_map(|endpoints| endpoints.call(&req)) {
+                return Ok(response.await.map(|body| Full::new(body).boxed()));
+            }
+
             let res = match req.uri().path() {
                 "/health" => Response::new(ok_body()),
                 "/ready" => match is_ready {
@@ -54,3 +167,60 @@ where
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::serve_http_service;
+    use http_body_util::Empty;
+    use hyper_util::client::legacy::Client;
+    use hyper_util::rt::TokioExecutor;
+
+    fn request(path: &str, authorization: Option<&str>) -> Request<Empty<Bytes>> {
+        let mut request = Request::builder().uri(path);
+        if let Some(authorization) = authorization {
+            request = request.header(AUTHORIZATION, authorization);
+        }
+        request.body(Empty::new()).unwrap()
+    }
+
+    #[test]
+    fn test_bearer_tokens() {
+        let tokens = BearerTokens::new(vec!["old".into(), "new".into()]).unwrap();
+        assert!(tokens.allows(&request("/", Some("Bearer old"))));
+        assert!(tokens.allows(&request("/", Some("Bearer new"))));
+        assert!(!tokens.allows(&request("/", Some("Bearer other"))));
+        assert!(!tokens.allows(&request("/", Some("Basic new"))));
+        assert!(!tokens.allows(&request("/", None)));
+
+        assert!(BearerTokens::new(vec!["".into()]).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_bearer_tokens_of_service() {
+        let service = AdminService::<_, std::io::Error>::new(|| true)
+            .with_bearer_tokens(BearerTokens::new(vec!["token".into()]).unwrap());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve_http_service(
+            listener,
+            service,
+            None,
+            std::future::pending(),
+        ));
+
+        let client = Client::builder(TokioExecutor::new()).build_http();
+        let cases = [
+            ("/health", None, StatusCode::OK),
+            ("/ready", None, StatusCode::OK),
+            ("/other", None, StatusCode::UNAUTHORIZED),
+            ("/other", Some("Bearer wrong"), StatusCode::UNAUTHORIZED),
+            ("/other", Some("Bearer token"), StatusCode::NOT_FOUND),
+        ];
+        for (path, authorization, expected) in cases {
+            let uri = format!("http://{addr}{path}");
+            let response = client.request(request(&uri, authorization)).await.unwrap();
+            assert_eq!(response.status(), expected, "{path} {authorization:?}");
+        }
+    }
+}
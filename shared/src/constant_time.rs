@@ -0,0 +1,34 @@
+//! Comparison of secrets such as API keys and bearer tokens.
+//!
+//! Comparing with `==` returns at the first differing byte, so that the response time of
+//! a rejected secret tells how much of it is right, and a secret can be guessed byte by
+//! byte. Only the length is revealed by [`eq`].
+use std::hint::black_box;
+
+/// Whether the byte strings are equal, in a time independent of where they differ.
+pub fn eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    // Kept from being turned into an early return by the optimizer
+    let diff = a
+        .iter()
+        .zip(b)
+        .fold(0u8, |diff, (x, y)| black_box(diff | (x ^ y)));
+    diff == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_eq() {
+        assert!(eq(b"", b""));
+        assert!(eq(b"token", b"token"));
+        assert!(!eq(b"token", b"tokem"));
+        assert!(!eq(b"token", b"Token"));
+        assert!(!eq(b"token", b"token2"));
+        assert!(!eq(b"token", b""));
+    }
+}
@@ -1,7 +1,8 @@
+use crate::tls::TlsAcceptor;
 use http::Version;
 use http::header::{
-    CONNECTION, HeaderMap, HeaderName, HeaderValue, PROXY_AUTHENTICATE, PROXY_AUTHORIZATION, TE,
-    TRAILER, TRANSFER_ENCODING, UPGRADE, VIA,
+    AUTHORIZATION, CONNECTION, COOKIE, HeaderMap, HeaderName, HeaderValue, PROXY_AUTHENTICATE,
+    PROXY_AUTHORIZATION, SET_COOKIE, TE, TRAILER, TRANSFER_ENCODING, UPGRADE, VIA,
 };
 use http_body_util::combinators::BoxBody;
 use http_body_util::{BodyExt, Full};
@@ -13,10 +14,66 @@ use hyper::{Request, Response};
 use hyper_util::rt::TokioExecutor;
 use hyper_util::rt::TokioIo;
 use hyper_util::server::conn::auto::Builder;
+use hyper_util::server::graceful::GracefulShutdown;
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+use std::net::SocketAddr;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use tokio::net::TcpListener;
 
-pub async fn run_http_service<S, B, E>(host: &str, port: u16, service: S) -> Result<(), E>
+// Connections that did not complete the TLS handshake by then are closed
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Header carrying the id of a request, see `RequestId`.
+pub static REQUEST_ID_HEADER: HeaderName = HeaderName::from_static("x-request-id");
+
+/// Headers carrying credentials, which are redacted wherever requests are recorded or
+/// exposed on an admin listener: the DSN key in `X-Sentry-Auth`, relay signatures and the
+/// proxy's admin token.
+pub static SENSITIVE_HEADERS: [HeaderName; 7] = [
+    AUTHORIZATION,
+    PROXY_AUTHORIZATION,
+    COOKIE,
+    SET_COOKIE,
+    HeaderName::from_static("x-sentry-auth"),
+    HeaderName::from_static("x-sentry-relay-signature"),
+    HeaderName::from_static("x-synapse-admin-token"),
+];
+
+/// Headers as name and value pairs, with the values of `SENSITIVE_HEADERS` replaced.
+pub fn redacted_headers(headers: &HeaderMap) -> Vec<(String, String)> {
+    headers
+        .iter()
+        .map(|(name, value)| {
+            let value = if SENSITIVE_HEADERS.contains(name) {
+                "[redacted]".to_string()
+            } else {
+                String::from_utf8_lossy(value.as_bytes()).into_owned()
+            };
+            (name.to_string(), value)
+        })
+        .collect()
+}
+
+// Longest request id accepted from clients
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+// Request ids generated by this process
+static GENERATED_REQUEST_IDS: AtomicU64 = AtomicU64::new(0);
+
+/// Serves connections on `host:port`, terminating TLS with `tls` if set, until the process
+/// is asked to stop. Open connections are then given up to `drain_timeout` to finish their
+/// requests.
+pub async fn run_http_service<S, B, E>(
+    host: &str,
+    port: u16,
+    service: S,
+    tls: Option<TlsAcceptor>,
+    drain_timeout: Duration,
+) -> Result<(), E>
 where
     S: Service<Request<Incoming>, Response = Response<B>, Error = E> + Send + Sync + 'static,
     S::Future: Send + 'static,
@@ -25,20 +82,247 @@ where
     E: From<std::io::Error> + std::error::Error + Send + Sync + 'static,
 {
     let listener = TcpListener::bind(format!("{host}:{port}")).await?;
+    serve_http_service(listener, service, tls, async move {
+        shutdown_signal().await;
+        drain_timeout
+    })
+    .await
+}
+
+/// Serves connections accepted on `listener` until `shutdown` completes. The listener is
+/// then closed, and the open connections are given the duration `shutdown` completed with
+/// to finish their requests. HTTP/2 clients are sent a GOAWAY and HTTP/1.1 connections are
+/// closed after their current request. Connections are TLS connections if `tls` is set.
+pub async fn serve_http_service<S, B, E>(
+    listener: TcpListener,
+    service: S,
+    tls: Option<TlsAcceptor>,
+    shutdown: impl Future<Output = Duration>,
+) -> Result<(), E>
+where
+    S: Service<Request<Incoming>, Response = Response<B>, Error = E> + Send + Sync + 'static,
+    S::Future: Send + 'static,
+    B: Body<Data = Bytes> + Send + 'static,
+    B::Error: std::error::Error + Send + Sync,
+    E: From<std::io::Error> + std::error::Error + Send + Sync + 'static,
+{
     let service_arc = Arc::new(service);
+    let graceful = GracefulShutdown::new();
+    tokio::pin!(shutdown);
 
-    loop {
-        let (stream, _peer_addr) = listener.accept().await?;
+    let drain_timeout = loop {
+        let (stream, peer_addr) = tokio::select! {
+            accepted = listener.accept() => accepted?,
+            drain_timeout = &mut shutdown => break drain_timeout,
+        };
         let _ = stream.set_nodelay(true);
-        let io = TokioIo::new(stream);
-        let svc = service_arc.clone();
+        let mut svc = WithConnectionInfo {
+            inner: service_arc.clone(),
+            peer_addr: PeerAddr(peer_addr),
+            tls: None,
+        };
+
+        let Some(tls) = &tls else {
+            // Hand the connection to hyper; auto-detect h1/h2 on this socket
+            let connection = Builder::new(TokioExecutor::new())
+                .serve_connection(TokioIo::new(stream), svc)
+                .into_owned();
+            let connection = graceful.watch(connection);
+            tokio::spawn(async move {
+                let _ = connection.await;
+            });
+            continue;
+        };
 
-        // Hand the connection to hyper; auto-detect h1/h2 on this socket
+        // The handshake happens on the connection task, so that slow clients do not hold
+        // up accepting others
+        let tls = tls.clone();
+        let watcher = graceful.watcher();
         tokio::spawn(async move {
-            let _ = Builder::new(TokioExecutor::new())
-                .serve_connection(io, svc)
-                .await;
+            let stream = match tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, tls.accept(stream)).await
+            {
+                Ok(Ok(stream)) => stream,
+                Ok(Err(error)) => {
+                    tracing::debug!(%error, %peer_addr, "TLS handshake failed");
+                    return;
+                }
+                Err(_) => {
+                    tracing::debug!(%peer_addr, "TLS handshake timed out");
+                    return;
+                }
+            };
+            svc.tls = Some(ClientTls::from_connection(stream.get_ref().1));
+            let connection = Builder::new(TokioExecutor::new())
+                .serve_connection(TokioIo::new(stream), svc)
+                .into_owned();
+            let _ = watcher.watch(connection).await;
         });
+    };
+
+    drop(listener);
+    tracing::info!("Listener closed, draining open connections");
+    if tokio::time::timeout(drain_timeout, graceful.shutdown())
+        .await
+        .is_err()
+    {
+        tracing::warn!("Connections still open after draining for {drain_timeout:?}");
+    }
+    Ok(())
+}
+
+/// Completes once the process is asked to stop, with Ctrl-C or SIGTERM. Every caller is
+/// notified.
+pub async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{SignalKind, signal};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => {}
+                    _ = terminate.recv() => {}
+                }
+                return;
+            }
+            Err(error) => tracing::warn!(%error, "Failed to listen for SIGTERM"),
+        }
+    }
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+/// Request header a route matches on, in the route config of the proxy and the
+/// ingest-router. The name is case-insensitive.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct HeaderMatch {
+    pub name: String,
+    /// Exact value of the header. If not set, the header only needs to be present.
+    pub value: Option<String>,
+    /// Route parameter the header value is stored in, for the dynamic resolvers of the
+    /// proxy
+    pub param: Option<String>,
+}
+
+/// Address of the client connection, added to the extensions of every request served by
+/// `run_http_service`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PeerAddr(pub SocketAddr);
+
+/// TLS parameters negotiated with the client, added to the extensions of every request
+/// served over TLS by `run_http_service`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ClientTls {
+    /// e.g. `TLSv1.3`
+    pub version: &'static str,
+    /// Protocol selected with ALPN, e.g. `h2`. None if the client did not offer ALPN.
+    pub alpn: Option<String>,
+    /// e.g. `TLS13_AES_128_GCM_SHA256`
+    pub cipher_suite: &'static str,
+}
+
+impl ClientTls {
+    fn from_connection(connection: &rustls::ServerConnection) -> Self {
+        Self {
+            version: match connection.protocol_version() {
+                Some(rustls::ProtocolVersion::TLSv1_2) => "TLSv1.2",
+                Some(rustls::ProtocolVersion::TLSv1_3) => "TLSv1.3",
+                _ => "unknown",
+            },
+            alpn: connection
+                .alpn_protocol()
+                .map(|protocol| String::from_utf8_lossy(protocol).into_owned()),
+            cipher_suite: connection
+                .negotiated_cipher_suite()
+                .and_then(|suite| suite.suite().as_str())
+                .unwrap_or("unknown"),
+        }
+    }
+}
+
+struct WithConnectionInfo<S> {
+    inner: Arc<S>,
+    peer_addr: PeerAddr,
+    tls: Option<ClientTls>,
+}
+
+impl<S, B> Service<Request<B>> for WithConnectionInfo<S>
+where
+    S: Service<Request<B>>,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = S::Future;
+
+    fn call(&self, mut request: Request<B>) -> Self::Future {
+        request.extensions_mut().insert(self.peer_addr);
+        if let Some(tls) = &self.tls {
+            request.extensions_mut().insert(tls.clone());
+        }
+        self.inner.call(request)
+    }
+}
+
+/// Id of a request, shared by the logs of every synapse component the request passes
+/// through.
+///
+/// The id is taken from the `X-Request-Id` header of the request if it has a valid one, so
+/// that an edge in front of synapse or a proxy in front of the ingest-router can set it, and
+/// generated otherwise. It is forwarded to upstreams and returned in the response header.
+/// Valid ids are up to 128 ASCII letters, digits, `-`, `_`, `.` or `:`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RequestId(HeaderValue);
+
+impl RequestId {
+    /// A new id of 32 hex digits.
+    pub fn generate() -> Self {
+        // Unique within the process by the counter, and across processes by the randomly
+        // keyed hasher and the time
+        let count = GENERATED_REQUEST_IDS.fetch_add(1, Ordering::Relaxed);
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos();
+        let state = RandomState::new();
+        let high = state.hash_one((count, nanos));
+        let low = state.hash_one((nanos, count, std::process::id()));
+        let id = format!("{high:016x}{low:016x}");
+        Self(HeaderValue::from_str(&id).expect("hex digits are a valid header value"))
+    }
+
+    /// The id in a header value, None if it is not a valid id.
+    pub fn parse(value: &HeaderValue) -> Option<Self> {
+        let bytes = value.as_bytes();
+        let valid = !bytes.is_empty()
+            && bytes.len() <= MAX_REQUEST_ID_LEN
+            && bytes
+                .iter()
+                .all(|&b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.' | b':'));
+        valid.then(|| Self(value.clone()))
+    }
+
+    /// The id of a request, generated if the request has none or an invalid one. The
+    /// request header is set to the returned id, so that upstreams receive it.
+    pub fn ensure(headers: &mut HeaderMap) -> Self {
+        if let Some(request_id) = headers.get(&REQUEST_ID_HEADER).and_then(Self::parse) {
+            return request_id;
+        }
+        let request_id = Self::generate();
+        request_id.set_header(headers);
+        request_id
+    }
+
+    pub fn as_str(&self) -> &str {
+        self.0.to_str().expect("request ids are ASCII")
+    }
+
+    /// Sets the id header, typically of the response.
+    pub fn set_header(&self, headers: &mut HeaderMap) {
+        headers.insert(REQUEST_ID_HEADER.clone(), self.0.clone());
+    }
+
+    /// Span recording the id with the events logged while handling the request.
+    pub fn span(&self) -> tracing::Span {
+        tracing::info_span!("request", request_id = self.as_str())
     }
 }
 
@@ -158,6 +442,31 @@ where
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_redacted_headers() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "x-sentry-auth",
+            HeaderValue::from_static("Sentry sentry_key=abc"),
+        );
+        headers.insert("x-sentry-relay-signature", HeaderValue::from_static("sig"));
+        headers.insert("x-synapse-admin-token", HeaderValue::from_static("token"));
+        headers.insert(AUTHORIZATION, HeaderValue::from_static("Bearer token"));
+        headers.insert("x-sentry-relay-id", HeaderValue::from_static("relay-1"));
+
+        let redacted: std::collections::HashMap<_, _> =
+            redacted_headers(&headers).into_iter().collect();
+        for name in [
+            "x-sentry-auth",
+            "x-sentry-relay-signature",
+            "x-synapse-admin-token",
+            "authorization",
+        ] {
+            assert_eq!(redacted[name], "[redacted]", "{name}");
+        }
+        assert_eq!(redacted["x-sentry-relay-id"], "relay-1");
+    }
+
     #[test]
     fn test_filter_headers() {
         use http::header::{CONNECTION, CONTENT_TYPE, HeaderMap, HeaderValue};
@@ -183,4 +492,153 @@ mod tests {
         // Case-insensitive match with "cusTOM"
         assert!(filtered.get("custom").is_none());
     }
+
+    #[tokio::test]
+    async fn test_serve_http_service_drains() {
+        use hyper::service::service_fn;
+        use hyper_util::client::legacy::Client;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let service = service_fn(|_: Request<Incoming>| async {
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            Ok::<_, std::io::Error>(Response::new(Full::new(Bytes::from("done"))))
+        });
+        let (shutdown_tx, shutdown) = tokio::sync::oneshot::channel::<()>();
+        let server = tokio::spawn(serve_http_service(listener, service, None, async {
+            let _ = shutdown.await;
+            Duration::from_secs(5)
+        }));
+
+        let client = Client::builder(TokioExecutor::new()).build_http::<Full<Bytes>>();
+        let request = client.get(format!("http://{addr}/").parse().unwrap());
+        let response = tokio::spawn(request);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        shutdown_tx.send(()).unwrap();
+
+        // The request in flight completes, then the server stops
+        let response = response.await.unwrap().unwrap();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(body, "done");
+        server.await.unwrap().unwrap();
+        assert!(tokio::net::TcpStream::connect(addr).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_serve_tls() {
+        use crate::tls::{TlsFiles, acceptor};
+        use hyper::service::service_fn;
+        use rustls::pki_types::pem::PemObject;
+        use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+        use std::path::Path;
+        use tokio_rustls::TlsConnector;
+
+        let testdata = Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata");
+        let tls = acceptor(TlsFiles {
+            cert_file: &testdata.join("server.pem"),
+            key_file: &testdata.join("server-key.pem"),
+            client_ca_file: Some(&testdata.join("ca.pem")),
+        })
+        .unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let service = service_fn(|request: Request<Incoming>| async move {
+            let tls = request.extensions().get::<ClientTls>().unwrap();
+            let body = format!("{} {}", tls.version, tls.alpn.as_deref().unwrap_or("none"));
+            Ok::<_, std::io::Error>(Response::new(Full::new(Bytes::from(body))))
+        });
+        tokio::spawn(serve_http_service(
+            listener,
+            service,
+            Some(tls),
+            std::future::pending(),
+        ));
+
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let mut roots = rustls::RootCertStore::empty();
+        for cert in CertificateDer::pem_file_iter(testdata.join("ca.pem")).unwrap() {
+            roots.add(cert.unwrap()).unwrap();
+        }
+        let builder = rustls::ClientConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots);
+        let client_certs = CertificateDer::pem_file_iter(testdata.join("client.pem"))
+            .unwrap()
+            .map(Result::unwrap)
+            .collect();
+        let client_key = PrivateKeyDer::from_pem_file(testdata.join("client-key.pem")).unwrap();
+
+        let get = |config: rustls::ClientConfig| async move {
+            let stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+            let stream = TlsConnector::from(Arc::new(config))
+                .connect(ServerName::try_from("localhost").unwrap(), stream)
+                .await?;
+            let (mut sender, connection) =
+                hyper::client::conn::http1::handshake(TokioIo::new(stream))
+                    .await
+                    .map_err(std::io::Error::other)?;
+            tokio::spawn(connection);
+            let request = Request::get("/").body(Full::new(Bytes::new())).unwrap();
+            let response = sender
+                .send_request(request)
+                .await
+                .map_err(std::io::Error::other)?;
+            let body = response.into_body().collect().await.unwrap().to_bytes();
+            Ok::<_, std::io::Error>(body)
+        };
+
+        let mut with_cert = builder
+            .clone()
+            .with_client_auth_cert(client_certs, client_key)
+            .unwrap();
+        assert_eq!(get(with_cert.clone()).await.unwrap(), "TLSv1.3 none");
+        with_cert.alpn_protocols = vec![b"http/1.1".to_vec()];
+        assert_eq!(get(with_cert).await.unwrap(), "TLSv1.3 http/1.1");
+
+        // Clients without a certificate issued by the client CA are rejected
+        assert!(get(builder.with_no_client_auth()).await.is_err());
+    }
+
+    #[test]
+    fn test_request_id() {
+        let generated = RequestId::generate();
+        assert_eq!(generated.as_str().len(), 32);
+        assert_ne!(generated, RequestId::generate());
+
+        // Valid ids of the request are kept
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            REQUEST_ID_HEADER.clone(),
+            HeaderValue::from_static("edge-1:a.b_c"),
+        );
+        assert_eq!(RequestId::ensure(&mut headers).as_str(), "edge-1:a.b_c");
+        assert_eq!(headers[&REQUEST_ID_HEADER], "edge-1:a.b_c");
+
+        // Missing and invalid ids are replaced
+        let too_long = "a".repeat(129);
+        for value in [
+            None,
+            Some(""),
+            Some("a b"),
+            Some("a\"b"),
+            Some(too_long.as_str()),
+        ] {
+            let mut headers = HeaderMap::new();
+            if let Some(value) = value {
+                headers.insert(
+                    REQUEST_ID_HEADER.clone(),
+                    HeaderValue::from_str(value).unwrap(),
+                );
+            }
+            let request_id = RequestId::ensure(&mut headers);
+            assert_eq!(request_id.as_str().len(), 32, "{value:?}");
+            assert_eq!(headers[&REQUEST_ID_HEADER], request_id.as_str());
+        }
+
+        let mut response_headers = HeaderMap::new();
+        generated.set_header(&mut response_headers);
+        assert_eq!(response_headers[&REQUEST_ID_HEADER], generated.as_str());
+    }
 }
@@ -1,3 +1,7 @@
 pub mod admin_service;
+pub mod constant_time;
 pub mod http;
 pub mod metrics_defs;
+#[cfg(feature = "testutils")]
+pub mod testutils;
+pub mod tls;
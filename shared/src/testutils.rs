@@ -0,0 +1,218 @@
+//! Mock servers for tests of the services, enabled by the `testutils` feature.
+//!
+//! Servers listen on a free local port and stop when dropped, closing their connections,
+//! so that tests can run in parallel without external scripts.
+use http_body_util::{BodyExt, Full};
+use hyper::body::{Bytes, Incoming};
+use hyper::service::service_fn;
+use hyper::{Request, Response, StatusCode};
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use hyper_util::server::conn::auto::Builder;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::{JoinHandle, JoinSet};
+
+/// Server answering requests with a handler, or handling raw connections
+pub struct MockServer {
+    addr: SocketAddr,
+    connections: Arc<AtomicUsize>,
+    handle: JoinHandle<()>,
+}
+
+impl MockServer {
+    /// HTTP server answering every request with `handler`
+    pub async fn spawn<F>(handler: F) -> Self
+    where
+        F: Fn(Request<Bytes>) -> Response<Full<Bytes>> + Send + Sync + 'static,
+    {
+        Self::spawn_async(move |request| std::future::ready(handler(request))).await
+    }
+
+    /// HTTP server answering every request with an async `handler`, such as one that
+    /// responds after a delay
+    pub async fn spawn_async<F, Fut>(handler: F) -> Self
+    where
+        F: Fn(Request<Bytes>) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Response<Full<Bytes>>> + Send + 'static,
+    {
+        let handler = Arc::new(handler);
+        Self::spawn_tcp(move |stream, _| {
+            let handler = handler.clone();
+            let service = service_fn(move |request: Request<Incoming>| {
+                let handler = handler.clone();
+                async move {
+                    let (parts, body) = request.into_parts();
+                    let body = body.collect().await?.to_bytes();
+                    Ok::<_, hyper::Error>(handler(Request::from_parts(parts, body)).await)
+                }
+            });
+            async move {
+                let _ = Builder::new(TokioExecutor::new())
+                    .serve_connection_with_upgrades(TokioIo::new(stream), service)
+                    .await;
+            }
+        })
+        .await
+    }
+
+    /// Server handling every accepted connection with `handler`, which is passed the
+    /// address of the peer
+    pub async fn spawn_tcp<F, Fut>(handler: F) -> Self
+    where
+        F: Fn(TcpStream, SocketAddr) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let connections = Arc::new(AtomicUsize::new(0));
+
+        let accepted = connections.clone();
+        let handle = tokio::spawn(async move {
+            // Dropped with the server, which aborts the open connections
+            let mut open = JoinSet::new();
+            while let Ok((stream, peer)) = listener.accept().await {
+                accepted.fetch_add(1, Ordering::SeqCst);
+                while open.try_join_next().is_some() {}
+                open.spawn(handler(stream, peer));
+            }
+        });
+
+        Self {
+            addr,
+            connections,
+            handle,
+        }
+    }
+
+    /// Upstream describing every request it receives in an `Echoed` JSON body. Responses
+    /// have an `x-upstream` header with `name`.
+    pub async fn echo(name: &str) -> Self {
+        let name = name.to_string();
+        Self::spawn(move |request| {
+            let echoed = serde_json::json!({
+                "method": request.method().as_str(),
+                "uri": request.uri().to_string(),
+                "headers": request
+                    .headers()
+                    .iter()
+                    .map(|(name, value)| {
+                        (name.to_string(), String::from_utf8_lossy(value.as_bytes()))
+                    })
+                    .collect::<HashMap<_, _>>(),
+                "body": String::from_utf8_lossy(request.body()),
+            });
+            Response::builder()
+                .header("content-type", "application/json")
+                .header("x-upstream", &name)
+                .body(Full::new(Bytes::from(echoed.to_string())))
+                .unwrap()
+        })
+        .await
+    }
+
+    /// Upstream answering every request with `body` and the given status
+    pub async fn status(status: StatusCode, body: &'static str) -> Self {
+        Self::spawn(move |_| {
+            let mut response = Response::new(Full::new(Bytes::from_static(body.as_bytes())));
+            *response.status_mut() = status;
+            response
+        })
+        .await
+    }
+
+    /// Locator API resolving the ids of `id_to_cell`. Other ids are not found.
+    pub async fn locator(id_to_cell: HashMap<String, String>) -> Self {
+        Self::spawn(move |request| {
+            let cell = request
+                .uri()
+                .query()
+                .unwrap_or_default()
+                .split('&')
+                .find_map(|param| param.strip_prefix("id="))
+                .and_then(|id| id_to_cell.get(id));
+            match cell {
+                Some(cell) => Response::new(Full::new(Bytes::from(
+                    serde_json::json!({ "cell": cell }).to_string(),
+                ))),
+                None => {
+                    let mut response = Response::new(Full::new(Bytes::new()));
+                    *response.status_mut() = StatusCode::NOT_FOUND;
+                    response
+                }
+            }
+        })
+        .await
+    }
+
+    /// Server accepting connections without ever answering
+    pub async fn stalled() -> Self {
+        Self::spawn_tcp(|stream, _| async move {
+            std::future::pending::<()>().await;
+            drop(stream);
+        })
+        .await
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub fn port(&self) -> u16 {
+        self.addr.port()
+    }
+
+    pub fn url(&self) -> String {
+        format!("http://{}", self.addr)
+    }
+
+    /// Number of connections accepted so far
+    pub fn connections(&self) -> usize {
+        self.connections.load(Ordering::SeqCst)
+    }
+}
+
+impl Drop for MockServer {
+    fn drop(&mut self) {
+        self.handle.abort();
+    }
+}
+
+/// Reads the head of the next HTTP message from a raw connection, None if the connection
+/// is closed first.
+pub async fn read_head<S: AsyncRead + Unpin>(stream: &mut S) -> Option<String> {
+    let mut received = Vec::new();
+    let mut buf = [0; 1];
+    while !received.ends_with(b"\r\n\r\n") {
+        match stream.read(&mut buf).await {
+            Ok(0) | Err(_) => return None,
+            Ok(_) => received.push(buf[0]),
+        }
+    }
+    Some(String::from_utf8_lossy(&received).into_owned())
+}
+
+/// Request as received by the echo upstream
+#[derive(Debug, Deserialize)]
+pub struct Echoed {
+    pub method: String,
+    pub uri: String,
+    pub headers: HashMap<String, String>,
+    pub body: String,
+}
+
+impl Echoed {
+    pub async fn from_response<B>(response: Response<B>) -> Self
+    where
+        B: hyper::body::Body,
+        B::Error: std::fmt::Debug,
+    {
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        serde_json::from_slice(&body).unwrap()
+    }
+}
@@ -0,0 +1,172 @@
+//! TLS termination of the listeners.
+//!
+//! A listener with TLS settings presents the configured certificate chain, and negotiates
+//! HTTP/2 or HTTP/1.1 with ALPN. With a client CA, clients must present a certificate
+//! issued by it. The files are loaded when the service starts, so that a missing or
+//! malformed file fails the startup rather than every handshake. Certificates are not
+//! reloaded, a hot upgrade picks up renewed ones.
+use rustls::ServerConfig;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::WebPkiClientVerifier;
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use thiserror::Error;
+pub use tokio_rustls::TlsAcceptor;
+
+#[derive(Error, Debug)]
+pub enum TlsError {
+    #[error("Failed to read {path}: {source}")]
+    ReadFile {
+        path: PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
+
+    #[error("Invalid PEM file {0}: {1}")]
+    InvalidPem(PathBuf, String),
+
+    #[error("No certificates found in {0}")]
+    NoCertificates(PathBuf),
+
+    #[error("Invalid TLS configuration: {0}")]
+    InvalidConfig(String),
+}
+
+/// Files of the TLS settings of a listener
+#[derive(Clone, Debug)]
+pub struct TlsFiles<'a> {
+    /// PEM certificate chain presented to clients
+    pub cert_file: &'a Path,
+    /// PEM private key of the certificate
+    pub key_file: &'a Path,
+    /// PEM bundle of the CAs issuing client certificates. Client certificates are not
+    /// requested if not set.
+    pub client_ca_file: Option<&'a Path>,
+}
+
+/// TLS settings of a listener in the config of a service
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct ListenerTls {
+    /// PEM certificate chain presented to clients
+    pub cert_file: PathBuf,
+    /// PEM private key of the certificate
+    pub key_file: PathBuf,
+    /// PEM bundle of the CAs issuing client certificates. Clients without one are
+    /// rejected if set.
+    pub client_ca_file: Option<PathBuf>,
+}
+
+impl ListenerTls {
+    pub fn acceptor(&self) -> Result<TlsAcceptor, TlsError> {
+        acceptor(TlsFiles {
+            cert_file: &self.cert_file,
+            key_file: &self.key_file,
+            client_ca_file: self.client_ca_file.as_deref(),
+        })
+    }
+}
+
+/// Builds the acceptor of the TLS connections of a listener.
+pub fn acceptor(files: TlsFiles<'_>) -> Result<TlsAcceptor, TlsError> {
+    let invalid = |e: rustls::Error| TlsError::InvalidConfig(e.to_string());
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let builder = ServerConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .map_err(invalid)?;
+
+    let builder = match files.client_ca_file {
+        Some(path) => {
+            let mut roots = rustls::RootCertStore::empty();
+            for cert in read_certs(path)? {
+                roots.add(cert).map_err(invalid)?;
+            }
+            let verifier = WebPkiClientVerifier::builder_with_provider(roots.into(), provider)
+                .build()
+                .map_err(|e| TlsError::InvalidConfig(e.to_string()))?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+
+    let certs = read_certs(files.cert_file)?;
+    let key = PrivateKeyDer::from_pem_slice(&read_file(files.key_file)?)
+        .map_err(|e| TlsError::InvalidPem(files.key_file.to_path_buf(), e.to_string()))?;
+    let mut config = builder.with_single_cert(certs, key).map_err(invalid)?;
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+fn read_file(path: &Path) -> Result<Vec<u8>, TlsError> {
+    std::fs::read(path).map_err(|source| TlsError::ReadFile {
+        path: path.to_path_buf(),
+        source,
+    })
+}
+
+fn read_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>, TlsError> {
+    let certs = CertificateDer::pem_slice_iter(&read_file(path)?)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| TlsError::InvalidPem(path.to_path_buf(), e.to_string()))?;
+    if certs.is_empty() {
+        return Err(TlsError::NoCertificates(path.to_path_buf()));
+    }
+    Ok(certs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn testdata(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("testdata")
+            .join(name)
+    }
+
+    #[test]
+    fn test_acceptor() {
+        let cert_file = testdata("server.pem");
+        let key_file = testdata("server-key.pem");
+        let ca_file = testdata("ca.pem");
+        let files = TlsFiles {
+            cert_file: &cert_file,
+            key_file: &key_file,
+            client_ca_file: None,
+        };
+        assert!(acceptor(files.clone()).is_ok());
+        assert!(
+            acceptor(TlsFiles {
+                client_ca_file: Some(&ca_file),
+                ..files.clone()
+            })
+            .is_ok()
+        );
+
+        // The key does not match the certificate
+        let client_key_file = testdata("client-key.pem");
+        assert!(matches!(
+            acceptor(TlsFiles {
+                key_file: &client_key_file,
+                ..files.clone()
+            }),
+            Err(TlsError::InvalidConfig(_))
+        ));
+        assert!(matches!(
+            acceptor(TlsFiles {
+                cert_file: Path::new("/nonexistent/cert.pem"),
+                ..files.clone()
+            }),
+            Err(TlsError::ReadFile { .. })
+        ));
+        assert!(matches!(
+            acceptor(TlsFiles {
+                client_ca_file: Some(&key_file),
+                ..files
+            }),
+            Err(TlsError::NoCertificates(_))
+        ));
+    }
+}
@@ -122,7 +122,10 @@ mod tests {
             &proxy_config.listener,
             &Listener {
                 host: "0.0.0.0".into(),
-                port: 8080
+                port: 8080,
+                path_normalization: None,
+                trusted_proxies: Vec::new(),
+                tls: None,
             }
         );
         assert_eq!(
@@ -131,8 +134,20 @@ mod tests {
                 r#match: proxy::config::Match {
                     host: None,
                     path: Some("test".into()),
+                    flag: None,
+                    active: None,
+                    headers: vec![],
                 },
-                action: proxy::config::Action::Static { to: "local".into() }
+                action: proxy::config::Action::Static { to: "local".into() },
+                response_headers: None,
+                client_ips: None,
+                strip_trailers: false,
+                headers: None,
+                capture: None,
+                timeout_ms: None,
+                retries: None,
+                methods: None,
+                priority: 0,
             }]
         );
     }
@@ -1,8 +1,9 @@
-use clap::{Args, Parser};
+use clap::{Args, Parser, Subcommand};
 use std::path::PathBuf;
 
 mod config;
 mod healthcheck;
+mod validate;
 use config::{Config, MetricsConfig};
 use metrics_exporter_statsd::StatsdBuilder;
 use std::future::Future;
@@ -19,6 +20,9 @@ enum CliCommand {
     GenerateRelayCredentials,
     /// Probe a URL and exit 0 on a 2xx response, non-zero otherwise.
     Healthcheck(HealthcheckArgs),
+    /// Check a config file without starting any component, and exit non-zero if it is
+    /// invalid. For CI and pre-deploy hooks.
+    Validate(ValidateArgs),
     /// Show all metrics definitions as markdown table
     ShowMetrics,
     /// Sync METRICS.md with current metric definitions
@@ -35,6 +39,10 @@ enum CliError {
     RuntimeError(#[from] std::io::Error),
     #[error("Healthcheck failed: {0}")]
     HealthcheckFailed(String),
+    #[error("Self-test failed for {0} dependencies")]
+    SelfTestFailed(usize),
+    #[error("Validation failed for {0} checks")]
+    ValidationFailed(usize),
 }
 
 fn main() {
@@ -50,8 +58,31 @@ fn cli() -> Result<(), CliError> {
     let cmd = CliCommand::parse();
 
     match &cmd {
-        CliCommand::Locator(locator_args) => {
-            let config = Config::from_file(&locator_args.base.config_file_path)?;
+        CliCommand::Locator(LocatorArgs {
+            command: Some(LocatorCommand::SelfTest(args)),
+            ..
+        }) => {
+            let config = Config::from_file(&args.config_file_path)?;
+            let locator_config = config
+                .locator
+                .ok_or(CliError::InvalidConfig("Missing locator config"))?;
+
+            let rt = tokio::runtime::Builder::new_multi_thread()
+                .enable_all()
+                .build()?;
+            let checks = rt.block_on(locator::self_test::run(locator_config));
+            for check in &checks {
+                println!("{check}");
+            }
+            match checks.iter().filter(|check| check.failed()).count() {
+                0 => Ok(()),
+                failed => Err(CliError::SelfTestFailed(failed)),
+            }
+        }
+        CliCommand::Locator(LocatorArgs {
+            base: Some(base), ..
+        }) => {
+            let config = Config::from_file(&base.config_file_path)?;
             let _sentry_guard = init_sentry(config.common.logging);
             init_statsd_recorder("synapse.locator", config.common.metrics);
 
@@ -62,6 +93,7 @@ fn cli() -> Result<(), CliError> {
             run_async(locator::run(locator_config))?;
             Ok(())
         }
+        CliCommand::Locator(_) => unreachable!("the config file path is required"),
         CliCommand::Proxy(proxy_args) => {
             let config = Config::from_file(&proxy_args.base.config_file_path)?;
             let _sentry_guard = init_sentry(config.common.logging);
@@ -96,6 +128,9 @@ fn cli() -> Result<(), CliError> {
             Ok(())
         }
         CliCommand::Healthcheck(args) => healthcheck::run(&args.base.config_file_path),
+        CliCommand::Validate(args) => {
+            validate::run(&args.base.config_file_path, args.deny_warnings, args.json)
+        }
         CliCommand::ShowMetrics => {
             println!("## Locator Metrics\n");
             println!(
@@ -240,9 +275,19 @@ struct BaseArgs {
 }
 
 #[derive(Args, Debug)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct LocatorArgs {
+    #[command(subcommand)]
+    command: Option<LocatorCommand>,
     #[command(flatten)]
-    base: BaseArgs,
+    base: Option<BaseArgs>,
+}
+
+#[derive(Subcommand, Debug)]
+enum LocatorCommand {
+    /// Check the control plane, backup route store and serialization, and exit non-zero if
+    /// one of them fails. For deployment preflight checks.
+    SelfTest(BaseArgs),
 }
 
 #[derive(Args, Debug)]
@@ -265,6 +310,18 @@ struct HealthcheckArgs {
     base: BaseArgs,
 }
 
+#[derive(Args, Debug)]
+struct ValidateArgs {
+    #[command(flatten)]
+    base: BaseArgs,
+    /// Also exit non-zero on warnings, such as upstream hosts that don't resolve
+    #[arg(long)]
+    deny_warnings: bool,
+    /// Print the report as JSON, for CI
+    #[arg(long)]
+    json: bool,
+}
+
 #[cfg(test)]
 mod tests {
     #[test]
@@ -0,0 +1,318 @@
+//! Checks a config file without starting any component, for CI and pre-deploy hooks.
+//!
+//! Every component in the file is validated like on startup, without binding its listeners
+//! or contacting its dependencies. The hosts of the upstream, cell and locator URLs are
+//! resolved, and routes are checked to only send requests to configured upstreams. The
+//! report has one line per check, e.g. `FAIL proxy: ...`, or is a JSON document with
+//! `--json`. Failures make the command exit non-zero, and so do warnings with
+//! `--deny-warnings`.
+use crate::CliError;
+use crate::config::Config;
+use locator::client::{LocatorConfig, LocatorType, ShardTopology};
+use proxy::config::{Action, Route, UpstreamConfig};
+use serde::Serialize;
+use std::collections::HashSet;
+use std::fmt;
+use std::net::ToSocketAddrs;
+use std::path::Path;
+
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(tag = "status", content = "detail", rename_all = "lowercase")]
+pub enum Outcome {
+    Passed(String),
+    /// Accepted on startup, but likely a mistake
+    Warning(String),
+    Failed(String),
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct Check {
+    pub subject: String,
+    #[serde(flatten)]
+    pub outcome: Outcome,
+}
+
+/// Report printed with `--json`
+#[derive(Serialize)]
+struct Report<'a> {
+    /// Whether the command exits successfully
+    passed: bool,
+    checks: &'a [Check],
+}
+
+impl Check {
+    fn new(subject: impl Into<String>, outcome: Outcome) -> Self {
+        Self {
+            subject: subject.into(),
+            outcome,
+        }
+    }
+
+    fn fails(&self, deny_warnings: bool) -> bool {
+        match self.outcome {
+            Outcome::Passed(_) => false,
+            Outcome::Warning(_) => deny_warnings,
+            Outcome::Failed(_) => true,
+        }
+    }
+}
+
+impl fmt::Display for Check {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (status, detail) = match &self.outcome {
+            Outcome::Passed(detail) => ("PASS", detail),
+            Outcome::Warning(detail) => ("WARN", detail),
+            Outcome::Failed(detail) => ("FAIL", detail),
+        };
+        write!(f, "{status} {}: {detail}", self.subject)
+    }
+}
+
+pub fn run(config_path: &Path, deny_warnings: bool, json: bool) -> Result<(), CliError> {
+    let checks = match Config::from_file(config_path) {
+        Ok(config) => check_config(&config),
+        Err(e) => vec![Check::new("config", Outcome::Failed(e.to_string()))],
+    };
+    let failed = checks
+        .iter()
+        .filter(|check| check.fails(deny_warnings))
+        .count();
+    if json {
+        let report = Report {
+            passed: failed == 0,
+            checks: &checks,
+        };
+        println!("{}", serde_json::to_string_pretty(&report).unwrap());
+    } else {
+        for check in &checks {
+            println!("{check}");
+        }
+    }
+    match failed {
+        0 => Ok(()),
+        failed => Err(CliError::ValidationFailed(failed)),
+    }
+}
+
+fn check_config(config: &Config) -> Vec<Check> {
+    let mut checks = Vec::new();
+
+    if let Some(proxy) = &config.proxy {
+        checks.push(component("proxy", proxy::validate(proxy)));
+        checks.extend(unknown_upstreams("proxy", &proxy.routes, &proxy.upstreams));
+        for additional in &proxy.additional_listeners {
+            let mut routes = additional.routes.clone();
+            let mut upstreams = additional.upstreams.clone();
+            if additional.inherit_routes {
+                routes.extend(proxy.routes.iter().cloned());
+                upstreams.extend(proxy.upstreams.iter().cloned());
+            }
+            checks.extend(unknown_upstreams(
+                &format!("proxy listener {}", additional.name),
+                &routes,
+                &upstreams,
+            ));
+        }
+        let upstreams = proxy
+            .upstreams
+            .iter()
+            .chain(proxy.additional_listeners.iter().flat_map(|a| &a.upstreams));
+        for upstream in upstreams {
+            checks.push(resolve(
+                &format!("proxy upstream {}", upstream.name),
+                &upstream.url,
+            ));
+        }
+        checks.extend(locator_urls(
+            "proxy",
+            proxy.locator.clone().to_client_config(),
+        ));
+    }
+
+    if let Some(locator) = &config.locator {
+        checks.push(component("locator", locator::validate(locator)));
+        checks.push(resolve("locator control plane", &locator.control_plane.url));
+    }
+
+    if let Some(ingest_router) = &config.ingest_router {
+        checks.push(component(
+            "ingest-router",
+            ingest_router::validate(ingest_router),
+        ));
+        let mut localities: Vec<_> = ingest_router.localities.iter().collect();
+        localities.sort_by_key(|(locality, _)| *locality);
+        for cell in localities.into_iter().flat_map(|(_, cells)| cells) {
+            let subject = format!("ingest-router cell {}", cell.id);
+            checks.push(resolve(&subject, cell.sentry_url.as_str()));
+            checks.push(resolve(&subject, cell.relay_url.as_str()));
+        }
+        checks.extend(locator_urls(
+            "ingest-router",
+            ingest_router.locator.clone().to_client_config(),
+        ));
+    }
+
+    if checks.is_empty() {
+        checks.push(Check::new(
+            "config",
+            Outcome::Failed("No proxy, locator or ingest-router config".to_string()),
+        ));
+    }
+    checks
+}
+
+fn component(name: &str, result: Result<(), impl std::error::Error>) -> Check {
+    match result {
+        Ok(()) => Check::new(name, Outcome::Passed("config is valid".to_string())),
+        Err(e) => Check::new(name, Outcome::Failed(e.to_string())),
+    }
+}
+
+/// Warns about routes sending requests to upstreams that are not configured, which are
+/// only noticed once a request matches them.
+fn unknown_upstreams(subject: &str, routes: &[Route], upstreams: &[UpstreamConfig]) -> Vec<Check> {
+    let names: HashSet<&str> = upstreams.iter().map(|u| u.name.as_str()).collect();
+    let mut unknown = Vec::new();
+    for route in routes {
+        let targets: Vec<&String> = match &route.action {
+            Action::Static { to } => vec![to],
+            Action::Dynamic {
+                cell_to_upstream,
+                default,
+                ..
+            } => cell_to_upstream.values().chain(default).collect(),
+        };
+        for target in targets {
+            if !names.contains(target.as_str()) && !unknown.contains(target) {
+                unknown.push(target.clone());
+            }
+        }
+    }
+    unknown.sort();
+    unknown
+        .into_iter()
+        .map(|upstream| {
+            Check::new(
+                subject,
+                Outcome::Warning(format!("Routes use the unknown upstream {upstream}")),
+            )
+        })
+        .collect()
+}
+
+/// Resolves the locator URLs of a remote locator, or the control plane URL of an
+/// in-process one.
+fn locator_urls(component: &str, config: LocatorConfig) -> Vec<Check> {
+    match config.locator_type {
+        LocatorType::InProcess { control_plane, .. } => vec![resolve(
+            &format!("{component} locator control plane"),
+            &control_plane.url,
+        )],
+        LocatorType::Url { url, .. } => vec![resolve(&format!("{component} locator"), &url)],
+        LocatorType::Sharded { shards, .. } => {
+            let urls = match shards {
+                ShardTopology::Urls(urls) => urls,
+                ShardTopology::DiscoveryUrl(url) => vec![url],
+            };
+            urls.iter()
+                .map(|url| resolve(&format!("{component} locator"), url))
+                .collect()
+        }
+    }
+}
+
+/// Resolves the host of the URL. Hosts that don't resolve are warnings, as they may only
+/// resolve where the component is deployed.
+fn resolve(subject: &str, url: &str) -> Check {
+    let parsed = match reqwest::Url::parse(url) {
+        Ok(parsed) => parsed,
+        Err(e) => return Check::new(subject, Outcome::Failed(format!("Invalid URL {url}: {e}"))),
+    };
+    let (Some(host), Some(port)) = (parsed.host_str(), parsed.port_or_known_default()) else {
+        return Check::new(subject, Outcome::Failed(format!("URL without host: {url}")));
+    };
+    // IPv6 hosts are bracketed in URLs
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    match (host, port).to_socket_addrs() {
+        Ok(addrs) if addrs.len() > 0 => {
+            Check::new(subject, Outcome::Passed(format!("{url} resolves")))
+        }
+        Ok(_) => Check::new(
+            subject,
+            Outcome::Warning(format!("{url} resolves to no address")),
+        ),
+        Err(e) => Check::new(
+            subject,
+            Outcome::Warning(format!("{url} does not resolve: {e}")),
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn example_config(name: &str) -> Config {
+        Config::from_file(&PathBuf::from("..").join(name)).unwrap()
+    }
+
+    #[test]
+    fn test_example_configs() {
+        for name in [
+            "example_config_proxy.yaml",
+            "example_config_locator.yaml",
+            "example_config_ingest_router.yaml",
+        ] {
+            let checks = check_config(&example_config(name));
+            assert!(!checks.is_empty());
+            let failed: Vec<_> = checks.iter().filter(|check| check.fails(false)).collect();
+            assert!(failed.is_empty(), "{name}: {failed:?}");
+        }
+    }
+
+    #[test]
+    fn test_checks() {
+        let mut config = example_config("example_config_proxy.yaml");
+        let proxy = config.proxy.as_mut().unwrap();
+        proxy
+            .upstreams
+            .retain(|upstream| upstream.name != "de-getsentry");
+        proxy.upstreams[0].url = "not a url".to_string();
+        proxy.upstreams[1].url = "http://127.0.0.1:8080".to_string();
+
+        let checks = check_config(&config);
+        assert!(checks.contains(&Check::new(
+            "proxy",
+            Outcome::Warning("Routes use the unknown upstream de-getsentry".to_string())
+        )));
+        let invalid = checks
+            .iter()
+            .find(|check| check.subject == "proxy upstream us1-getsentry")
+            .unwrap();
+        assert!(invalid.fails(false));
+        assert!(
+            invalid
+                .to_string()
+                .starts_with("FAIL proxy upstream us1-getsentry: ")
+        );
+        assert!(checks.contains(&Check::new(
+            "proxy upstream us2-getsentry",
+            Outcome::Passed("http://127.0.0.1:8080 resolves".to_string())
+        )));
+
+        let check = Check::new("proxy", Outcome::Warning("...".to_string()));
+        assert!(!check.fails(false));
+        assert!(check.fails(true));
+
+        assert_eq!(
+            serde_json::to_value(&check).unwrap(),
+            serde_json::json!({ "subject": "proxy", "status": "warning", "detail": "..." })
+        );
+
+        let empty: Config = serde_yaml::from_str("{}").unwrap();
+        let checks = check_config(&empty);
+        assert_eq!(checks.len(), 1);
+        assert!(checks[0].fails(false));
+    }
+}